@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::time::Duration;
 
 use futures::executor::ThreadPool;
@@ -21,10 +22,12 @@ use node::connect::{node_connect, NodeConnection};
 pub struct ConnectError;
 
 /// Connect to a remote offst-node.
+/// If `opt_socks5_proxy` is given, the connection is made through this SOCKS5 proxy.
 pub async fn connect<S>(
     node_public_key: PublicKey,
     node_net_address: NetAddress,
     app_identity_client: IdentityClient,
+    opt_socks5_proxy: Option<SocketAddr>,
     spawner: S,
 ) -> Result<NodeConnection, ConnectError>
 where
@@ -32,13 +35,20 @@ where
 {
     let resolve_thread_pool = ThreadPool::new().map_err(|_| ConnectError)?;
 
-    // A tcp connector, Used to connect to remote servers:
-    let net_connector = NetConnector::new(MAX_FRAME_LENGTH, resolve_thread_pool, spawner.clone());
-
     // Get a timer client:
     let dur = Duration::from_millis(usize_to_u64(TICK_MS).unwrap());
     let timer_client = create_timer(dur, spawner.clone()).map_err(|_| ConnectError)?;
 
+    // A tcp connector, Used to connect to remote servers:
+    let net_connector = NetConnector::new(
+        MAX_FRAME_LENGTH,
+        opt_socks5_proxy,
+        None,
+        timer_client.clone(),
+        resolve_thread_pool,
+        spawner.clone(),
+    );
+
     // Obtain secure cryptographic random:
     let rng = system_random();
 
@@ -3,7 +3,7 @@ use std::path::Path;
 
 use identity::{create_identity, IdentityClient};
 
-use proto::file::identity::load_identity_from_file;
+use bin::passphrase::load_identity_from_file;
 
 #[derive(Debug)]
 pub enum IdentityFromFileError {
@@ -11,6 +11,8 @@ pub enum IdentityFromFileError {
     CreateIdentityError,
 }
 
+/// Load an identity from a file, and spawn an identity service for it.
+/// If the file is encrypted, the passphrase is obtained using `bin::passphrase::get_passphrase`.
 pub fn identity_from_file<S>(
     idfile_path: &Path,
     mut spawner: S,
@@ -39,11 +39,12 @@ pub use self::identity::{identity_from_file, IdentityFromFileError};
 // TODO: Possibly reduce what we export from report in the future?
 pub mod report {
     pub use proto::report::messages::{
-        AddFriendReport, ChannelInconsistentReport, ChannelStatusReport, DirectionReport,
-        FriendLivenessReport, FriendReport, FriendReportMutation, FriendStatusReport, FunderReport,
+        AddFriendReport, ChannelInconsistentReport, ChannelStatusReport, ConnectionsReport,
+        ConnectionsReportMutation, DirectionReport, FriendConnectionReport, FriendLivenessReport,
+        FriendReport, FriendReportMutation, FriendStatusReport, FunderReport,
         FunderReportMutateError, FunderReportMutation, FunderReportMutations, McBalanceReport,
-        McRequestsStatusReport, MoveTokenHashedReport, RequestsStatusReport, ResetTermsReport,
-        SentLocalRelaysReport, TcReport,
+        McRequestsStatusReport, MoveTokenHashedReport, RelayHealthReport, RequestsStatusReport,
+        ResetTermsReport, SentLocalRelaysReport, TcReport,
     };
 
     pub use proto::app_server::messages::{NodeReport, NodeReportMutation};
@@ -58,7 +59,7 @@ pub mod invoice {
 
 pub mod route {
     pub use proto::funder::messages::FriendsRoute;
-    pub use proto::index_server::messages::RouteWithCapacity;
+    pub use proto::index_server::messages::{NodeWithCapacity, RouteWithCapacity};
 
 }
 
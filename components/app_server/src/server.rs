@@ -19,11 +19,12 @@ use proto::report::convert::funder_report_mutation_to_index_mutation;
 
 use proto::app_server::messages::{
     AppPermissions, AppRequest, AppServerToApp, AppToAppServer, NodeReport, NodeReportMutation,
-    ReportMutations,
+    RelayAddress, ReportMutations,
 };
 use proto::index_client::messages::{
     AppServerToIndexClient, IndexClientRequest, IndexClientToAppServer,
 };
+use proto::report::messages::{ConnectionsReport, ConnectionsReportMutation};
 
 pub type IncomingAppConnection<B> = (
     AppPermissions,
@@ -48,6 +49,7 @@ pub enum AppServerEvent<B: Clone> {
     FunderClosed,
     FromIndexClient(IndexClientToAppServer<B>),
     IndexClientClosed,
+    FromChanneler(ConnectionsReportMutation<RelayAddress<B>>),
     FromApp((u128, Option<AppToAppServer<B>>)), // None means that app was closed
 }
 
@@ -55,7 +57,10 @@ pub struct App<B: Clone> {
     permissions: AppPermissions,
     opt_sender: Option<mpsc::Sender<AppServerToApp<B>>>,
     open_route_requests: HashSet<Uid>,
+    open_reachability_requests: HashSet<Uid>,
     open_send_funds_requests: HashSet<Uid>,
+    /// Did the app request connection statistics reports?
+    connections_report: bool,
 }
 
 impl<B> App<B>
@@ -67,7 +72,9 @@ where
             permissions,
             opt_sender: Some(sender),
             open_route_requests: HashSet::new(),
+            open_reachability_requests: HashSet::new(),
             open_send_funds_requests: HashSet::new(),
+            connections_report: false,
         }
     }
 
@@ -114,6 +121,9 @@ fn check_permissions<B>(app_permissions: &AppPermissions, app_request: &AppReque
         AppRequest::RequestRoutes(_) => app_permissions.routes,
         AppRequest::AddIndexServer(_) => app_permissions.config,
         AppRequest::RemoveIndexServer(_) => app_permissions.config,
+        AppRequest::SetCapacityBucket(_) => app_permissions.config,
+        AppRequest::RequestReachability(_) => app_permissions.routes,
+        AppRequest::EnableConnectionsReport => true,
     }
 }
 
@@ -167,8 +177,11 @@ where
             .map_err(|_| AppServerError::SpawnError)?;
 
         let mut app = App::new(permissions, sender);
-        // Send the initial node report:
-        await!(app.send(AppServerToApp::Report(self.node_report.clone())));
+        // Send the initial node report.
+        // Connection statistics are sent only after the app requests them:
+        let mut node_report = self.node_report.clone();
+        node_report.connections_report = ConnectionsReport::new();
+        await!(app.send(AppServerToApp::Report(node_report)));
 
         self.apps.insert(self.app_counter, app);
         self.app_counter = self.app_counter.wrapping_add(1);
@@ -190,7 +203,28 @@ where
     pub async fn broadcast_node_report_mutations(&mut self, report_mutations: ReportMutations<B>) {
         // Send node report mutations to all connected apps
         for app in &mut self.apps.values_mut() {
-            await!(app.send(AppServerToApp::ReportMutations(report_mutations.clone())));
+            if app.connections_report {
+                await!(app.send(AppServerToApp::ReportMutations(report_mutations.clone())));
+                continue;
+            }
+
+            // Apps that did not request connection statistics might not be able to parse them:
+            let mutations = report_mutations
+                .mutations
+                .iter()
+                .filter(|mutation| match mutation {
+                    NodeReportMutation::Connections(_) => false,
+                    _ => true,
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            if mutations.is_empty() && report_mutations.opt_app_request_id.is_none() {
+                continue;
+            }
+            await!(app.send(AppServerToApp::ReportMutations(ReportMutations {
+                opt_app_request_id: report_mutations.opt_app_request_id,
+                mutations,
+            })));
         }
     }
 
@@ -253,6 +287,21 @@ where
         Ok(())
     }
 
+    pub async fn handle_from_channeler(
+        &mut self,
+        connections_report_mutation: ConnectionsReportMutation<RelayAddress<B>>,
+    ) {
+        let mutation = NodeReportMutation::Connections(connections_report_mutation);
+        // Mutate our node report:
+        self.node_report.mutate(&mutation).unwrap();
+
+        let report_mutations = ReportMutations {
+            opt_app_request_id: None,
+            mutations: vec![mutation],
+        };
+        await!(self.broadcast_node_report_mutations(report_mutations));
+    }
+
     pub async fn handle_from_index_client(
         &mut self,
         index_client_message: IndexClientToAppServer<B>,
@@ -286,6 +335,18 @@ where
                     }
                 }
             }
+            IndexClientToAppServer::ResponseReachability(client_response_reachability) => {
+                for app in self.apps.values_mut() {
+                    if app
+                        .open_reachability_requests
+                        .remove(&client_response_reachability.request_id)
+                    {
+                        await!(app.send(AppServerToApp::ResponseReachability(
+                            client_response_reachability.clone()
+                        )));
+                    }
+                }
+            }
         };
         Ok(())
     }
@@ -450,6 +511,47 @@ where
                     IndexClientRequest::RemoveIndexServer(index_server_address)
                 ))))
             .map_err(|_| AppServerError::SendToIndexClientError),
+            AppRequest::SetCapacityBucket(opt_capacity_bucket) => await!(self
+                .to_index_client
+                .send(AppServerToIndexClient::AppRequest((
+                    app_request_id,
+                    IndexClientRequest::SetCapacityBucket(opt_capacity_bucket)
+                ))))
+            .map_err(|_| AppServerError::SendToIndexClientError),
+            AppRequest::RequestReachability(request_reachability) => {
+                // Keep track of which application issued this request:
+                app.open_reachability_requests
+                    .insert(request_reachability.request_id);
+                await!(self
+                    .to_index_client
+                    .send(AppServerToIndexClient::AppRequest((
+                        app_request_id,
+                        IndexClientRequest::RequestReachability(request_reachability)
+                    ))))
+                .map_err(|_| AppServerError::SendToIndexClientError)
+            }
+            AppRequest::EnableConnectionsReport => {
+                // The app was sent an empty connections report so far. We send it the current
+                // connections report, and from now on all the connections report mutations:
+                app.connections_report = true;
+                let mutations = self
+                    .node_report
+                    .connections_report
+                    .friends
+                    .iter()
+                    .map(|(friend_public_key, friend_connection_report)| {
+                        NodeReportMutation::Connections(ConnectionsReportMutation::SetFriend((
+                            friend_public_key.clone(),
+                            friend_connection_report.clone(),
+                        )))
+                    })
+                    .collect();
+                await!(app.send(AppServerToApp::ReportMutations(ReportMutations {
+                    opt_app_request_id: Some(app_request_id),
+                    mutations,
+                })));
+                Ok(())
+            }
         }
     }
 
@@ -474,11 +576,12 @@ where
 }
 
 #[allow(unused)]
-pub async fn app_server_loop<B, FF, TF, FIC, TIC, IC, S>(
+pub async fn app_server_loop<B, FF, TF, FIC, TIC, FC, IC, S>(
     from_funder: FF,
     to_funder: TF,
     from_index_client: FIC,
     to_index_client: TIC,
+    from_channeler: FC,
     incoming_connections: IC,
     initial_node_report: NodeReport<B>,
     mut spawner: S,
@@ -489,6 +592,7 @@ where
     TF: Sink<SinkItem = FunderIncomingControl<B>> + Unpin + Sync + Send,
     FIC: Stream<Item = IndexClientToAppServer<B>> + Unpin + Send,
     TIC: Sink<SinkItem = AppServerToIndexClient<B>> + Unpin,
+    FC: Stream<Item = ConnectionsReportMutation<RelayAddress<B>>> + Unpin + Send,
     IC: Stream<Item = IncomingAppConnection<B>> + Unpin + Send,
     S: Spawn,
 {
@@ -511,6 +615,10 @@ where
             AppServerEvent::IndexClientClosed,
         )));
 
+    // Connection statistics are not essential. If the channeler stops sending them, we keep
+    // going:
+    let from_channeler = from_channeler.map(AppServerEvent::FromChanneler);
+
     let from_app_receiver = from_app_receiver.map(AppServerEvent::FromApp);
 
     let incoming_connections = incoming_connections
@@ -522,6 +630,7 @@ where
     let mut events = select_streams![
         from_funder,
         from_index_client,
+        from_channeler,
         from_app_receiver,
         incoming_connections
     ];
@@ -542,6 +651,9 @@ where
                 await!(app_server.handle_from_index_client(from_index_client))?
             }
             AppServerEvent::IndexClientClosed => return Err(AppServerError::IndexClientClosed),
+            AppServerEvent::FromChanneler(connections_report_mutation) => {
+                await!(app_server.handle_from_channeler(connections_report_mutation))
+            }
             AppServerEvent::FromApp((app_id, opt_app_message)) => {
                 await!(app_server.handle_from_app(app_id, opt_app_message))?
             }
@@ -22,6 +22,7 @@ where
         mut funder_receiver,
         mut index_client_sender,
         mut index_client_receiver,
+        _channeler_sender,
         mut connections_sender,
         initial_node_report,
     ) = spawn_dummy_app_server(spawner.clone());
@@ -0,0 +1,178 @@
+use futures::channel::mpsc;
+use futures::executor::ThreadPool;
+use futures::task::Spawn;
+use futures::{SinkExt, StreamExt};
+
+use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+use crypto::uid::{Uid, UID_LEN};
+
+use proto::app_server::messages::{
+    AppPermissions, AppRequest, AppServerToApp, AppToAppServer, NodeReportMutation, RelayAddress,
+};
+use proto::report::messages::{ConnectionsReportMutation, FriendConnectionReport};
+
+use super::utils::spawn_dummy_app_server;
+
+async fn task_app_server_loop_connections_report<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let (
+        _funder_sender,
+        _funder_receiver,
+        _index_client_sender,
+        _index_client_receiver,
+        mut channeler_sender,
+        mut connections_sender,
+        initial_node_report,
+    ) = spawn_dummy_app_server(spawner.clone());
+
+    let (mut app_sender, app_server_receiver) = mpsc::channel(0);
+    let (app_server_sender, mut app_receiver) = mpsc::channel(0);
+    let app_server_conn_pair = (app_server_sender, app_server_receiver);
+
+    let app_permissions = AppPermissions {
+        routes: false,
+        send_funds: false,
+        config: false,
+    };
+
+    await!(connections_sender.send((app_permissions, app_server_conn_pair))).unwrap();
+
+    // The app should receive the current node report as the first message:
+    let to_app_message = await!(app_receiver.next()).unwrap();
+    match to_app_message {
+        AppServerToApp::Report(report) => assert_eq!(report, initial_node_report),
+        _ => unreachable!(),
+    };
+
+    // The app requests connection statistics:
+    let to_app_server = AppToAppServer::new(
+        Uid::from(&[1; UID_LEN]),
+        AppRequest::EnableConnectionsReport,
+    );
+    await!(app_sender.send(to_app_server)).unwrap();
+
+    // No connection statistics are known yet:
+    let to_app_message = await!(app_receiver.next()).unwrap();
+    match to_app_message {
+        AppServerToApp::ReportMutations(report_mutations) => {
+            assert_eq!(
+                report_mutations.opt_app_request_id,
+                Some(Uid::from(&[1; UID_LEN]))
+            );
+            assert!(report_mutations.mutations.is_empty());
+        }
+        _ => unreachable!(),
+    };
+
+    // The channeler reports new connection statistics for a friend:
+    let mut friend_connection_report = FriendConnectionReport {
+        opt_connected_since: Some(1_500_000_000),
+        reconnect_count: 1,
+        bytes_sent: 100,
+        bytes_received: 200,
+        messages_sent: 1,
+        messages_received: 2,
+        opt_last_rtt_ms: Some(30),
+        opt_relay: Some(RelayAddress {
+            public_key: PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+            address: 0xcc_u32,
+        }),
+        relays_health: Vec::new(),
+    };
+    let connections_report_mutation = ConnectionsReportMutation::SetFriend((
+        PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+        friend_connection_report.clone(),
+    ));
+    await!(channeler_sender.send(connections_report_mutation.clone())).unwrap();
+
+    // The mutation should be forwarded to the app:
+    let to_app_message = await!(app_receiver.next()).unwrap();
+    match to_app_message {
+        AppServerToApp::ReportMutations(report_mutations) => {
+            assert!(report_mutations.opt_app_request_id.is_none());
+            assert_eq!(
+                report_mutations.mutations,
+                vec![NodeReportMutation::Connections(connections_report_mutation)]
+            );
+        }
+        _ => unreachable!(),
+    };
+
+    // Connect a second app, that does not request connection statistics:
+    let (mut app_sender2, app_server_receiver) = mpsc::channel(0);
+    let (app_server_sender, mut app_receiver2) = mpsc::channel(0);
+    let app_server_conn_pair = (app_server_sender, app_server_receiver);
+
+    let app_permissions = AppPermissions {
+        routes: false,
+        send_funds: false,
+        config: false,
+    };
+    await!(connections_sender.send((app_permissions, app_server_conn_pair))).unwrap();
+
+    // The second app gets a report without connection statistics:
+    let to_app_message = await!(app_receiver2.next()).unwrap();
+    match to_app_message {
+        AppServerToApp::Report(report) => {
+            assert!(report.connections_report.friends.is_empty());
+            assert_eq!(report.funder_report, initial_node_report.funder_report);
+        }
+        _ => unreachable!(),
+    };
+
+    // More connection statistics are reported. Only the first app receives them:
+    friend_connection_report.reconnect_count = 2;
+    let connections_report_mutation = ConnectionsReportMutation::SetFriend((
+        PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+        friend_connection_report.clone(),
+    ));
+    await!(channeler_sender.send(connections_report_mutation.clone())).unwrap();
+
+    let to_app_message = await!(app_receiver.next()).unwrap();
+    match to_app_message {
+        AppServerToApp::ReportMutations(report_mutations) => {
+            assert_eq!(
+                report_mutations.mutations,
+                vec![NodeReportMutation::Connections(
+                    connections_report_mutation.clone()
+                )]
+            );
+        }
+        _ => unreachable!(),
+    };
+
+    // Closing the channeler stream should not close the app server:
+    drop(channeler_sender);
+
+    // The second app requests connection statistics, and gets the current connections report:
+    let to_app_server = AppToAppServer::new(
+        Uid::from(&[2; UID_LEN]),
+        AppRequest::EnableConnectionsReport,
+    );
+    await!(app_sender2.send(to_app_server)).unwrap();
+
+    let to_app_message = await!(app_receiver2.next()).unwrap();
+    match to_app_message {
+        AppServerToApp::ReportMutations(report_mutations) => {
+            assert_eq!(
+                report_mutations.opt_app_request_id,
+                Some(Uid::from(&[2; UID_LEN]))
+            );
+            assert_eq!(
+                report_mutations.mutations,
+                vec![NodeReportMutation::Connections(connections_report_mutation)]
+            );
+        }
+        _ => unreachable!(),
+    };
+}
+
+#[test]
+fn test_app_server_loop_connections_report() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_app_server_loop_connections_report(
+        thread_pool.clone(),
+    ));
+}
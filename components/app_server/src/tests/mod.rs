@@ -1,6 +1,8 @@
 mod all_apps_closed;
+mod connections_report;
 mod funder_command;
 mod index_client_command;
+mod request_reachability;
 mod request_routes;
 mod request_send_funds;
 mod two_apps;
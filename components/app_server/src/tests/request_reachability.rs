@@ -0,0 +1,125 @@
+use futures::channel::mpsc;
+use futures::executor::ThreadPool;
+use futures::task::Spawn;
+use futures::{SinkExt, StreamExt};
+
+use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+use crypto::uid::Uid;
+use crypto::uid::UID_LEN;
+
+use proto::app_server::messages::{AppPermissions, AppRequest, AppServerToApp, AppToAppServer};
+use proto::index_client::messages::{
+    AppServerToIndexClient, ClientResponseReachability, IndexClientRequest, IndexClientToAppServer,
+    NodeWithCapacity, RequestReachability, ResponseReachabilityResult,
+};
+
+use super::utils::spawn_dummy_app_server;
+
+async fn task_app_server_loop_request_reachability<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let (
+        _funder_sender,
+        _funder_receiver,
+        mut index_client_sender,
+        mut index_client_receiver,
+        _channeler_sender,
+        mut connections_sender,
+        _initial_node_report,
+    ) = spawn_dummy_app_server(spawner.clone());
+
+    // Connect two apps:
+    let (mut app_sender0, app_server_receiver) = mpsc::channel(0);
+    let (app_server_sender, mut app_receiver0) = mpsc::channel(0);
+    let app_server_conn_pair = (app_server_sender, app_server_receiver);
+    let app_permissions = AppPermissions {
+        routes: true,
+        send_funds: false,
+        config: false,
+    };
+    await!(connections_sender.send((app_permissions, app_server_conn_pair))).unwrap();
+
+    let (_app_sender1, app_server_receiver) = mpsc::channel(0);
+    let (app_server_sender, mut app_receiver1) = mpsc::channel(0);
+    let app_server_conn_pair = (app_server_sender, app_server_receiver);
+    let app_permissions = AppPermissions {
+        routes: true,
+        send_funds: false,
+        config: false,
+    };
+    await!(connections_sender.send((app_permissions, app_server_conn_pair))).unwrap();
+
+    // The apps should receive the current node report as the first message:
+    let _to_app_message = await!(app_receiver0.next()).unwrap();
+    let _to_app_message = await!(app_receiver1.next()).unwrap();
+
+    // Send a request reachability message through app0:
+    let request_reachability = RequestReachability {
+        request_id: Uid::from(&[3; UID_LEN]),
+        capacity: 250,
+        destination: PublicKey::from(&[0xff; PUBLIC_KEY_LEN]),
+        opt_max_nodes: None,
+    };
+
+    let to_app_server = AppToAppServer::new(
+        Uid::from(&[22; UID_LEN]),
+        AppRequest::RequestReachability(request_reachability.clone()),
+    );
+    await!(app_sender0.send(to_app_server)).unwrap();
+
+    // RequestReachability command should be forwarded to IndexClient:
+    match await!(index_client_receiver.next()).unwrap() {
+        AppServerToIndexClient::AppRequest((
+            app_request_id,
+            IndexClientRequest::RequestReachability(received_request_reachability),
+        )) => {
+            assert_eq!(app_request_id, Uid::from(&[22; UID_LEN]));
+            assert_eq!(received_request_reachability, request_reachability);
+        }
+        _ => unreachable!(),
+    };
+
+    // IndexClient returns a response corresponding to the open request:
+    let client_response_reachability = ClientResponseReachability {
+        request_id: Uid::from(&[3; UID_LEN]),
+        result: ResponseReachabilityResult::Success(vec![NodeWithCapacity {
+            public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            capacity: 300,
+        }]),
+    };
+    await!(
+        index_client_sender.send(IndexClientToAppServer::ResponseReachability(
+            client_response_reachability.clone()
+        ))
+    )
+    .unwrap();
+
+    // Only the app that issued the request gets the response:
+    match await!(app_receiver0.next()).unwrap() {
+        AppServerToApp::ResponseReachability(response_reachability) => {
+            assert_eq!(response_reachability, client_response_reachability)
+        }
+        _ => unreachable!(),
+    }
+    assert!(app_receiver1.try_next().is_err());
+
+    // A repeated response does not correspond to any open request, and is discarded:
+    await!(
+        index_client_sender.send(IndexClientToAppServer::ResponseReachability(
+            client_response_reachability
+        ))
+    )
+    .unwrap();
+
+    assert!(app_receiver0.try_next().is_err());
+    assert!(app_receiver1.try_next().is_err());
+}
+
+#[test]
+fn test_app_server_loop_request_reachability() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_app_server_loop_request_reachability(
+        thread_pool.clone(),
+    ));
+}
@@ -24,6 +24,7 @@ where
         _funder_receiver,
         mut index_client_sender,
         mut index_client_receiver,
+        _channeler_sender,
         mut connections_sender,
         _initial_node_report,
     ) = spawn_dummy_app_server(spawner.clone());
@@ -86,6 +87,7 @@ where
     let client_response_routes = ClientResponseRoutes {
         request_id: Uid::from(&[2; UID_LEN]),
         result: ResponseRoutesResult::Failure,
+        opt_routes_signature: None,
     };
     await!(
         index_client_sender.send(IndexClientToAppServer::ResponseRoutes(
@@ -102,6 +104,7 @@ where
     let client_response_routes = ClientResponseRoutes {
         request_id: Uid::from(&[3; UID_LEN]),
         result: ResponseRoutesResult::Failure,
+        opt_routes_signature: None,
     };
     await!(
         index_client_sender.send(IndexClientToAppServer::ResponseRoutes(
@@ -127,6 +130,7 @@ where
     let client_response_routes = ClientResponseRoutes {
         request_id: Uid::from(&[3; UID_LEN]),
         result: ResponseRoutesResult::Failure,
+        opt_routes_signature: None,
     };
     await!(
         index_client_sender.send(IndexClientToAppServer::ResponseRoutes(
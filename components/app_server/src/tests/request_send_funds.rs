@@ -24,6 +24,7 @@ where
         mut funder_receiver,
         _index_client_sender,
         _index_client_receiver,
+        _channeler_sender,
         mut connections_sender,
         _initial_node_report,
     ) = spawn_dummy_app_server(spawner.clone());
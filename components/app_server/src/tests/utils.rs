@@ -6,13 +6,13 @@ use im::hashmap::HashMap as ImHashMap;
 
 use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
 
-use proto::app_server::messages::{NamedRelayAddress, NodeReport};
+use proto::app_server::messages::{NamedRelayAddress, NodeReport, RelayAddress};
 use proto::funder::messages::{FunderIncomingControl, FunderOutgoingControl};
 use proto::index_client::messages::{
     AppServerToIndexClient, IndexClientReport, IndexClientToAppServer,
 };
 use proto::index_server::messages::NamedIndexServerAddress;
-use proto::report::messages::FunderReport;
+use proto::report::messages::{ConnectionsReport, ConnectionsReportMutation, FunderReport};
 
 use crate::server::{app_server_loop, IncomingAppConnection};
 
@@ -42,6 +42,7 @@ pub fn spawn_dummy_app_server<S>(
     mpsc::Receiver<FunderIncomingControl<u32>>,
     mpsc::Sender<IndexClientToAppServer<u32>>,
     mpsc::Receiver<AppServerToIndexClient<u32>>,
+    mpsc::Sender<ConnectionsReportMutation<RelayAddress<u32>>>,
     mpsc::Sender<IncomingAppConnection<u32>>,
     NodeReport<u32>,
 )
@@ -54,6 +55,8 @@ where
     let (index_client_sender, from_index_client) = mpsc::channel(0);
     let (to_index_client, index_client_receiver) = mpsc::channel(0);
 
+    let (channeler_sender, from_channeler) = mpsc::channel(0);
+
     let (connections_sender, incoming_connections) = mpsc::channel(0);
 
     // Create a dummy initial_node_report:
@@ -86,6 +89,7 @@ where
     let initial_node_report = NodeReport {
         funder_report,
         index_client_report,
+        connections_report: ConnectionsReport::new(),
     };
 
     let fut_loop = app_server_loop(
@@ -93,6 +97,7 @@ where
         to_funder,
         from_index_client,
         to_index_client,
+        from_channeler,
         incoming_connections,
         initial_node_report.clone(),
         spawner.clone(),
@@ -107,6 +112,7 @@ where
         funder_receiver,
         index_client_sender,
         index_client_receiver,
+        channeler_sender,
         connections_sender,
         initial_node_report,
     )
@@ -14,15 +14,19 @@
 #[macro_use]
 extern crate log;
 
+use std::env;
+use std::io;
+
 use structopt::StructOpt;
 
-use bin::stindexlib::{stindex, IndexServerBinError, StIndexCmd};
+use bin::stindexlib::{stindex, with_default_subcommand, IndexServerBinError, StIndexCmd};
 
 fn run() -> Result<(), IndexServerBinError> {
     env_logger::init();
 
-    let st_index_cmd = StIndexCmd::from_args();
-    stindex(st_index_cmd)
+    let args = with_default_subcommand(env::args_os().collect());
+    let st_index_cmd = StIndexCmd::from_iter(args);
+    stindex(st_index_cmd, &mut io::stdout())
 }
 
 fn main() {
@@ -12,6 +12,9 @@
     clippy::new_without_default
 )]
 
+pub mod net_utils;
+pub mod passphrase;
+pub mod shutdown;
 pub mod stindexlib;
 pub mod stmgrlib;
 pub mod stnodelib;
@@ -0,0 +1,117 @@
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+
+use futures::channel::mpsc;
+use futures::task::Spawn;
+use futures::{stream, Stream, StreamExt};
+
+use common::conn::{ConnPairVec, Listener};
+use net::{TcpListener, TcpPeerListener, WsListener};
+use proto::net::messages::NetAddress;
+use timer::TimerClient;
+
+/// Amount of ticks to wait for a WebSocket handshake to complete.
+const WS_HANDSHAKE_TIMEOUT_TICKS: usize = 0x8;
+/// Maximum amount of WebSocket handshakes in progress.
+/// Connections beyond this amount are dropped.
+const MAX_CONCURRENT_WS_HANDSHAKES: usize = 0x40;
+
+/// Listen for raw connections on a TCP address, and optionally on a WebSocket address.
+/// Connections of both kinds are returned through a single stream.
+pub fn listen_raw_conns<S>(
+    laddr: SocketAddr,
+    opt_ws_laddr: Option<SocketAddr>,
+    max_frame_length: usize,
+    timer_client: TimerClient,
+    spawner: S,
+) -> impl Stream<Item = ConnPairVec> + Unpin + Send
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let tcp_listener = TcpListener::new(max_frame_length, spawner.clone());
+    let (_config_sender, incoming_tcp_conns) = tcp_listener.listen(laddr);
+
+    let incoming_ws_conns = listen_ws_conns(opt_ws_laddr, max_frame_length, timer_client, spawner);
+
+    stream::select(incoming_tcp_conns, incoming_ws_conns)
+}
+
+/// Listen for raw connections, like `listen_raw_conns`.
+/// Every connection is returned together with the address of the remote peer, as observed by us.
+/// The addresses of peers connecting through WebSocket are not known.
+pub fn listen_raw_peer_conns<S>(
+    laddr: SocketAddr,
+    opt_ws_laddr: Option<SocketAddr>,
+    max_frame_length: usize,
+    timer_client: TimerClient,
+    spawner: S,
+) -> impl Stream<Item = (Option<NetAddress>, ConnPairVec)> + Unpin + Send
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let tcp_listener = TcpPeerListener::new(max_frame_length, spawner.clone());
+    let (_config_sender, incoming_tcp_conns) = tcp_listener.listen(laddr);
+    let incoming_tcp_conns = incoming_tcp_conns.map(|(peer_addr, conn_pair)| {
+        let opt_address = NetAddress::try_from(peer_addr.to_string()).ok();
+        (opt_address, conn_pair)
+    });
+
+    let incoming_ws_conns = listen_ws_conns(opt_ws_laddr, max_frame_length, timer_client, spawner)
+        .map(|conn_pair| (None, conn_pair));
+
+    stream::select(incoming_tcp_conns, incoming_ws_conns)
+}
+
+/// Listen for raw WebSocket connections, if an address is provided.
+/// If no address is provided, the returned stream is closed.
+fn listen_ws_conns<S>(
+    opt_ws_laddr: Option<SocketAddr>,
+    max_frame_length: usize,
+    timer_client: TimerClient,
+    spawner: S,
+) -> mpsc::Receiver<ConnPairVec>
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    match opt_ws_laddr {
+        Some(ws_laddr) => {
+            let ws_listener = WsListener::new(
+                max_frame_length,
+                WS_HANDSHAKE_TIMEOUT_TICKS,
+                MAX_CONCURRENT_WS_HANDSHAKES,
+                timer_client,
+                spawner,
+            );
+            let (_config_sender, incoming_ws_conns) = ws_listener.listen(ws_laddr);
+            incoming_ws_conns
+        }
+        None => {
+            // A closed stream of connections:
+            let (_ws_conns_sender, incoming_ws_conns) = mpsc::channel(0);
+            incoming_ws_conns
+        }
+    }
+}
+
+/// Listen for direct connections from friends on a TCP address, if provided.
+/// If no address is provided, the returned stream is closed.
+pub fn listen_direct_raw_conns<S>(
+    opt_direct_laddr: Option<SocketAddr>,
+    max_frame_length: usize,
+    spawner: S,
+) -> mpsc::Receiver<ConnPairVec>
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    match opt_direct_laddr {
+        Some(direct_laddr) => {
+            let tcp_listener = TcpListener::new(max_frame_length, spawner);
+            let (_config_sender, incoming_direct_conns) = tcp_listener.listen(direct_laddr);
+            incoming_direct_conns
+        }
+        None => {
+            let (_direct_conns_sender, incoming_direct_conns) = mpsc::channel(0);
+            incoming_direct_conns
+        }
+    }
+}
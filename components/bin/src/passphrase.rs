@@ -0,0 +1,60 @@
+use std::path::Path;
+use std::{env, fs, io};
+
+use crypto::identity::Identity;
+
+use proto::file::identity::{
+    is_identity_file_encrypted, load_identity_from_file_with_passphrase,
+    load_raw_identity_from_file_with_passphrase, IdentityFileError,
+};
+
+/// Environment variable that may contain the passphrase of an encrypted identity file.
+/// The environment of a process may be visible to other processes of the same user, so
+/// `PASSPHRASE_FILE_ENV_VAR` should be preferred.
+pub const PASSPHRASE_ENV_VAR: &str = "OFFST_IDENT_PASSPHRASE";
+/// Environment variable that may contain the path of a file holding the passphrase of an
+/// encrypted identity file
+pub const PASSPHRASE_FILE_ENV_VAR: &str = "OFFST_IDENT_PASSPHRASE_FILE";
+
+/// Obtain the passphrase of an encrypted identity file.
+/// The passphrase is taken from the `PASSPHRASE_ENV_VAR` environment variable, or from the file
+/// pointed to by `PASSPHRASE_FILE_ENV_VAR`. If neither is set, the user is prompted on the
+/// terminal.
+/// `PASSPHRASE_ENV_VAR` is removed from the environment once read, so that it is not inherited
+/// by child processes.
+pub fn get_passphrase(prompt: &str) -> Result<String, io::Error> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV_VAR) {
+        env::remove_var(PASSPHRASE_ENV_VAR);
+        return Ok(passphrase);
+    }
+    if let Ok(passphrase_path) = env::var(PASSPHRASE_FILE_ENV_VAR) {
+        let passphrase = fs::read_to_string(passphrase_path)?;
+        return Ok(passphrase
+            .trim_end_matches(|c: char| c == '\n' || c == '\r')
+            .to_owned());
+    }
+    rpassword::read_password_from_tty(Some(prompt))
+}
+
+/// Obtain the passphrase for the identity file at `path`, if it is encrypted.
+fn get_opt_passphrase(path: &Path) -> Result<Option<String>, IdentityFileError> {
+    if !is_identity_file_encrypted(path)? {
+        return Ok(None);
+    }
+    let prompt = format!("Passphrase for {}: ", path.display());
+    Ok(Some(get_passphrase(&prompt)?))
+}
+
+/// Load Identity from a file
+/// If the file is encrypted, the passphrase is obtained using `get_passphrase`.
+pub fn load_raw_identity_from_file(path: &Path) -> Result<[u8; 85], IdentityFileError> {
+    let passphrase = get_opt_passphrase(path)?.unwrap_or_default();
+    load_raw_identity_from_file_with_passphrase(path, &passphrase)
+}
+
+/// Load an identity from a file
+/// If the file is encrypted, the passphrase is obtained using `get_passphrase`.
+pub fn load_identity_from_file(path: &Path) -> Result<impl Identity, IdentityFileError> {
+    let passphrase = get_opt_passphrase(path)?.unwrap_or_default();
+    load_identity_from_file_with_passphrase(path, &passphrase)
+}
@@ -0,0 +1,31 @@
+use std::sync::{Mutex, Once};
+
+use futures::channel::oneshot;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Senders waiting to be notified once the process is asked to terminate.
+    static ref SHUTDOWN_SENDERS: Mutex<Vec<oneshot::Sender<()>>> = Mutex::new(Vec::new());
+}
+
+static SET_HANDLER: Once = Once::new();
+
+/// Get a receiver that is notified once the process is asked to terminate (SIGINT or SIGTERM).
+///
+/// May be called multiple times (For example, when running a few servers in the same process).
+/// All the receivers are notified together.
+pub fn shutdown_signal() -> Result<oneshot::Receiver<()>, ctrlc::Error> {
+    let mut res = Ok(());
+    SET_HANDLER.call_once(|| {
+        res = ctrlc::set_handler(|| {
+            for shutdown_sender in SHUTDOWN_SENDERS.lock().unwrap().drain(..) {
+                let _ = shutdown_sender.send(());
+            }
+        });
+    });
+    res?;
+
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    SHUTDOWN_SENDERS.lock().unwrap().push(shutdown_sender);
+    Ok(shutdown_receiver)
+}
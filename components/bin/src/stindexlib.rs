@@ -1,29 +1,43 @@
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
 
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use futures::channel::mpsc;
 use futures::executor::ThreadPool;
 use futures::task::SpawnExt;
 
 use structopt::StructOpt;
 
-use common::conn::Listener;
+use common::conn::{FutTransform, Listener};
 use common::int_convert::usize_to_u64;
 
 use crypto::crypto_rand::system_random;
 
 use identity::{create_identity, IdentityClient};
 
-use index_server::{net_index_server, NetIndexServerError};
-use proto::consts::{MAX_FRAME_LENGTH, TICK_MS};
+use index_server::{
+    edges_to_dot, edges_to_graphml, net_index_server, request_admin, AdminError, AdminRequest,
+    AdminResponse, BanConfig, IndexServerStatus, NetIndexServerError, RoutesLimit,
+};
+use proto::consts::{BYTES_TO_REKEY, MAX_FRAME_LENGTH, MESSAGES_TO_REKEY, TICK_MS};
 use timer::create_timer;
 
-use net::{NetConnector, TcpListener};
+use net::{NetConnector, TcpConnector, TcpListener};
 
-use proto::file::identity::load_identity_from_file;
-use proto::file::index_server::{load_trusted_servers, IndexServerDirectoryError};
+use proto::file::index_server::{
+    load_index_server_from_file, load_trusted_servers, IndexServerDirectoryError,
+    IndexServerFileError,
+};
+use proto::file::ser_string::{public_key_to_string, string_to_public_key};
+
+use crate::net_utils::listen_raw_conns;
+use crate::passphrase::load_identity_from_file;
+use crate::shutdown::shutdown_signal;
 
 // TODO; Maybe take as a command line argument in the future?
 /// Maximum amount of concurrent encrypted channel set-ups.
@@ -31,25 +45,186 @@ use proto::file::index_server::{load_trusted_servers, IndexServerDirectoryError}
 pub const MAX_CONCURRENT_ENCRYPT: usize = 0x200;
 /// Amount of ticks we wait before attempting to reconnect to a remote index server.
 pub const BACKOFF_TICKS: usize = 0x8;
+/// Default amount of ticks between two consecutive snapshots of the index server state.
+pub const SNAPSHOT_TICKS: usize = 0x10;
+/// Default amount of route requests a client may send every tick.
+pub const CLIENT_ROUTES_PER_TICK: usize = 0x4;
+/// Default maximum amount of route requests a client may send in a burst.
+pub const CLIENT_ROUTES_BURST: usize = 0x20;
+/// Default maximum amount of graph queries in progress at the same time.
+pub const MAX_CONCURRENT_ROUTE_QUERIES: usize = 0x40;
+/// Default violation score at which a node is banned.
+/// The violation score of a node decreases by one every tick.
+pub const BAN_VIOLATION_THRESHOLD: usize = 0x10;
+/// Default amount of ticks a misbehaving node remains banned.
+pub const BAN_TICKS: usize = 10 * 60 * (1000 / TICK_MS); // 10 minutes
+/// Maximum frame length for admin connections.
+/// Larger than usual, because a whole graph might be sent in a single frame.
+pub const ADMIN_MAX_FRAME_LENGTH: usize = 1 << 26; // 64[MB]
+/// Default maximum amount of servers discovered through trusted servers we connect to.
+pub const MAX_DISCOVERED_SERVERS: usize = 0x10;
+/// Default maximum amount of ticks to wait for open route queries when shutting down.
+pub const DRAIN_TICKS: usize = 10 * (1000 / TICK_MS); // 10 seconds
 
-/// stindex: Offst Index Server
-/// A server used to index the Offst network. Collects topology information from nodes, and serves
-/// nodes requests for routes
 #[derive(Debug, StructOpt)]
-#[structopt(name = "stindex")]
-pub struct StIndexCmd {
+pub struct RunCmd {
     /// StCtrl app identity file path
     #[structopt(parse(from_os_str), short = "i", long = "idfile")]
     pub idfile: PathBuf,
     /// Listening address for clients
     #[structopt(short = "c", long = "lclient")]
     pub lclient: SocketAddr,
+    /// WebSocket address to listen on for incoming clients (Example: 0.0.0.0:8080)
+    #[structopt(long = "ws-lclient")]
+    pub ws_lclient: Option<SocketAddr>,
     /// Listening address for servers
     #[structopt(short = "s", long = "lserver")]
     pub lserver: SocketAddr,
+    /// WebSocket address to listen on for incoming servers
+    #[structopt(long = "ws-lserver")]
+    pub ws_lserver: Option<SocketAddr>,
     /// Directory path of trusted index servers
     #[structopt(parse(from_os_str), short = "t", long = "trusted")]
     pub trusted: PathBuf,
+    /// Snapshot file path. If provided, the index server state is restored from this file on
+    /// startup, and saved to this file periodically.
+    #[structopt(parse(from_os_str), long = "snapshot")]
+    pub snapshot: Option<PathBuf>,
+    /// Amount of ticks between two consecutive snapshots
+    #[structopt(long = "snapshot-ticks")]
+    pub snapshot_ticks: Option<usize>,
+    /// Listening address for local admin requests. Must be a loopback address, as admin requests
+    /// are not authenticated.
+    #[structopt(long = "ladmin")]
+    pub ladmin: Option<SocketAddr>,
+    /// Amount of route requests a client may send every tick
+    #[structopt(long = "routes-per-tick")]
+    pub routes_per_tick: Option<usize>,
+    /// Maximum amount of route requests a client may send in a burst
+    #[structopt(long = "routes-burst")]
+    pub routes_burst: Option<usize>,
+    /// Maximum amount of graph queries in progress at the same time
+    #[structopt(long = "max-route-queries")]
+    pub max_route_queries: Option<usize>,
+    /// Privacy mode: Answer route queries in multiples of this capacity
+    #[structopt(long = "capacity-bucket")]
+    pub capacity_bucket: Option<u128>,
+    /// Violation score at which a misbehaving node is banned
+    #[structopt(long = "ban-threshold")]
+    pub ban_threshold: Option<usize>,
+    /// Amount of ticks a misbehaving node remains banned
+    #[structopt(long = "ban-ticks")]
+    pub ban_ticks: Option<usize>,
+    /// Share trusted servers with trusted servers, and connect to servers they vouch for
+    #[structopt(long = "discovery")]
+    pub discovery: bool,
+    /// Maximum amount of discovered servers to connect to
+    #[structopt(long = "max-discovered-servers")]
+    pub max_discovered_servers: Option<usize>,
+    /// Maximum amount of ticks to wait for open route queries when shutting down
+    #[structopt(long = "drain-ticks")]
+    pub drain_ticks: Option<usize>,
+    /// Make outgoing connections through a SOCKS5 proxy (For example: 127.0.0.1:9050)
+    #[structopt(long = "proxy")]
+    pub proxy: Option<SocketAddr>,
+    /// Amount of bytes to encrypt using the same key until the next rekeying
+    #[structopt(long = "bytes-to-rekey")]
+    pub bytes_to_rekey: Option<u64>,
+    /// Amount of messages to encrypt using the same key until the next rekeying
+    #[structopt(long = "messages-to-rekey")]
+    pub messages_to_rekey: Option<u64>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StatusCmd {
+    /// Address of the index server admin endpoint
+    #[structopt(short = "a", long = "admin")]
+    pub admin: SocketAddr,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExportGraphCmd {
+    /// Address of the index server admin endpoint
+    #[structopt(short = "a", long = "admin")]
+    pub admin: SocketAddr,
+    /// Graph output file path
+    #[structopt(parse(from_os_str), short = "o", long = "output")]
+    pub output: PathBuf,
+    /// Export in GraphML format (Graphviz DOT format is used by default)
+    #[structopt(long = "graphml")]
+    pub graphml: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AddServerCmd {
+    /// Address of the index server admin endpoint
+    #[structopt(short = "a", long = "admin")]
+    pub admin: SocketAddr,
+    /// Path of the remote index server file
+    #[structopt(parse(from_os_str), short = "s", long = "server")]
+    pub server: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RemoveServerCmd {
+    /// Address of the index server admin endpoint
+    #[structopt(short = "a", long = "admin")]
+    pub admin: SocketAddr,
+    /// Public key of the remote index server
+    #[structopt(short = "p", long = "pubkey")]
+    pub public_key: String,
+}
+
+/// stindex: Offst Index Server
+/// A server used to index the Offst network. Collects topology information from nodes, and serves
+/// nodes requests for routes
+///
+/// Running stindex without a subcommand is the same as `stindex run`.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "stindex")]
+pub enum StIndexCmd {
+    /// Run an index server
+    #[structopt(name = "run")]
+    Run(RunCmd),
+    /// Show the status of a running index server
+    #[structopt(name = "status")]
+    Status(StatusCmd),
+    /// Export the capacity graph of a running index server
+    #[structopt(name = "export-graph")]
+    ExportGraph(ExportGraphCmd),
+    /// Trust a remote index server (Without restarting the index server).
+    /// The server is also saved to the trusted servers directory.
+    #[structopt(name = "add-server")]
+    AddServer(AddServerCmd),
+    /// Stop trusting a remote index server (Without restarting the index server).
+    /// The server is also removed from the trusted servers directory.
+    #[structopt(name = "remove-server")]
+    RemoveServer(RemoveServerCmd),
+}
+
+/// Names of the subcommands of stindex (Including the ones added by structopt)
+const SUBCOMMANDS: &[&str] = &[
+    "run",
+    "status",
+    "export-graph",
+    "add-server",
+    "remove-server",
+    "help",
+];
+
+/// Insert the `run` subcommand into the command line arguments if no subcommand was given.
+/// This keeps invocations from before stindex had subcommands working.
+pub fn with_default_subcommand(mut args: Vec<OsString>) -> Vec<OsString> {
+    let is_subcommand_given = match args.get(1).and_then(|arg| arg.to_str()) {
+        None => return args,
+        Some(arg) => {
+            SUBCOMMANDS.contains(&arg) || ["-h", "--help", "-V", "--version"].contains(&arg)
+        }
+    };
+    if !is_subcommand_given {
+        args.insert(1, OsString::from("run"));
+    }
+    args
 }
 
 #[allow(clippy::enum_variant_names)]
@@ -60,16 +235,63 @@ pub enum IndexServerBinError {
     NetIndexServerError(NetIndexServerError),
     LoadIdentityError,
     CreateIdentityError,
+    ShutdownSignalError,
+    AdminAddressNotLoopback,
     LoadTrustedServersError(IndexServerDirectoryError),
+    ConnectAdminError,
+    AdminRequestError(AdminError),
+    UnexpectedAdminResponse,
+    WriteError(io::Error),
+    OutputAlreadyExists,
+    CreateOutputError(io::Error),
+    LoadIndexServerError(IndexServerFileError),
+    ParsePublicKeyError,
+    ServerAlreadyTrusted,
+    ServerNotTrusted,
 }
 
-pub fn stindex(st_index_cmd: StIndexCmd) -> Result<(), IndexServerBinError> {
-    let StIndexCmd {
+fn run(run_cmd: RunCmd) -> Result<(), IndexServerBinError> {
+    let RunCmd {
         idfile,
         lclient,
+        ws_lclient,
         lserver,
+        ws_lserver,
         trusted,
-    } = st_index_cmd;
+        snapshot,
+        snapshot_ticks,
+        ladmin,
+        routes_per_tick,
+        routes_burst,
+        max_route_queries,
+        capacity_bucket,
+        ban_threshold,
+        ban_ticks,
+        discovery,
+        max_discovered_servers,
+        drain_ticks,
+        proxy,
+        bytes_to_rekey,
+        messages_to_rekey,
+    } = run_cmd;
+
+    // Admin requests are not authenticated. Only local users may send them:
+    if let Some(ladmin) = &ladmin {
+        if !ladmin.ip().is_loopback() {
+            return Err(IndexServerBinError::AdminAddressNotLoopback);
+        }
+    }
+
+    let routes_limit = RoutesLimit {
+        client_requests_per_tick: routes_per_tick.unwrap_or(CLIENT_ROUTES_PER_TICK),
+        client_max_burst: routes_burst.unwrap_or(CLIENT_ROUTES_BURST),
+        max_concurrent_queries: max_route_queries.unwrap_or(MAX_CONCURRENT_ROUTE_QUERIES),
+    };
+
+    let ban_config = BanConfig {
+        violation_threshold: ban_threshold.unwrap_or(BAN_VIOLATION_THRESHOLD),
+        ban_ticks: ban_ticks.unwrap_or(BAN_TICKS),
+    };
 
     let identity = load_identity_from_file(Path::new(&idfile))
         .map_err(|_| IndexServerBinError::LoadIdentityError)?;
@@ -97,6 +319,10 @@ pub fn stindex(st_index_cmd: StIndexCmd) -> Result<(), IndexServerBinError> {
     let graph_service_thread_pool =
         ThreadPool::new().map_err(|_| IndexServerBinError::CreateThreadPoolError)?;
 
+    // A thread pool for writing snapshots to disk:
+    let file_thread_pool =
+        ThreadPool::new().map_err(|_| IndexServerBinError::CreateThreadPoolError)?;
+
     // Spawn identity service:
     let (sender, identity_loop) = create_identity(identity);
     thread_pool
@@ -110,30 +336,79 @@ pub fn stindex(st_index_cmd: StIndexCmd) -> Result<(), IndexServerBinError> {
         .map_err(|_| IndexServerBinError::CreateTimerError)?;
 
     // Start listening to clients:
-    let client_tcp_listener = TcpListener::new(MAX_FRAME_LENGTH, thread_pool.clone());
-    let (_config_sender, incoming_client_raw_conns) = client_tcp_listener.listen(lclient);
+    let incoming_client_raw_conns = listen_raw_conns(
+        lclient,
+        ws_lclient,
+        MAX_FRAME_LENGTH,
+        timer_client.clone(),
+        thread_pool.clone(),
+    );
 
     // Start listening to servers:
-    let server_tcp_listener = TcpListener::new(MAX_FRAME_LENGTH, thread_pool.clone());
-    let (_config_sender, incoming_server_raw_conns) = server_tcp_listener.listen(lserver);
+    let incoming_server_raw_conns = listen_raw_conns(
+        lserver,
+        ws_lserver,
+        MAX_FRAME_LENGTH,
+        timer_client.clone(),
+        thread_pool.clone(),
+    );
+
+    // Start listening to admin requests (If enabled):
+    let incoming_admin_raw_conns = match ladmin {
+        Some(ladmin) => {
+            let admin_tcp_listener =
+                TcpListener::new(ADMIN_MAX_FRAME_LENGTH, thread_pool.clone());
+            let (_config_sender, incoming_admin_raw_conns) = admin_tcp_listener.listen(ladmin);
+            incoming_admin_raw_conns
+        }
+        None => {
+            // A closed stream of connections:
+            let (_admin_raw_conns_sender, incoming_admin_raw_conns) = mpsc::channel(0);
+            incoming_admin_raw_conns
+        }
+    };
 
     // A tcp connector, Used to connect to remote servers:
-    let raw_server_net_connector =
-        NetConnector::new(MAX_FRAME_LENGTH, resolve_thread_pool, thread_pool.clone());
+    let raw_server_net_connector = NetConnector::new(
+        MAX_FRAME_LENGTH,
+        proxy,
+        None,
+        timer_client.clone(),
+        resolve_thread_pool,
+        thread_pool.clone(),
+    );
 
     let rng = system_random();
 
+    // Start draining on SIGTERM or SIGINT:
+    let shutdown_receiver =
+        shutdown_signal().map_err(|_| IndexServerBinError::ShutdownSignalError)?;
+
     let index_server_fut = net_index_server(
         incoming_client_raw_conns,
         incoming_server_raw_conns,
+        incoming_admin_raw_conns,
         raw_server_net_connector,
         identity_client,
         timer_client,
         rng,
         trusted_servers,
+        Some(trusted),
         MAX_CONCURRENT_ENCRYPT,
+        bytes_to_rekey.unwrap_or(BYTES_TO_REKEY),
+        messages_to_rekey.unwrap_or(MESSAGES_TO_REKEY),
         BACKOFF_TICKS,
+        routes_limit,
+        capacity_bucket,
+        ban_config,
+        discovery,
+        max_discovered_servers.unwrap_or(MAX_DISCOVERED_SERVERS),
+        snapshot,
+        snapshot_ticks.unwrap_or(SNAPSHOT_TICKS),
+        shutdown_receiver,
+        drain_ticks.unwrap_or(DRAIN_TICKS),
         graph_service_thread_pool,
+        file_thread_pool,
         thread_pool.clone(),
     );
 
@@ -143,3 +418,144 @@ pub fn stindex(st_index_cmd: StIndexCmd) -> Result<(), IndexServerBinError> {
 
     Ok(())
 }
+
+fn write_status(
+    status: &IndexServerStatus,
+    writer: &mut impl io::Write,
+) -> Result<(), IndexServerBinError> {
+    let route_stats = &status.route_stats;
+    writeln!(
+        writer,
+        "Graph: {} nodes, {} edges",
+        status.num_nodes, status.num_edges
+    )
+    .map_err(IndexServerBinError::WriteError)?;
+    writeln!(writer, "Connected clients: {}", status.num_clients)
+        .map_err(IndexServerBinError::WriteError)?;
+    writeln!(writer, "Banned nodes: {}", status.num_banned)
+        .map_err(IndexServerBinError::WriteError)?;
+    writeln!(
+        writer,
+        "Route requests: {} total, {} during the last minute",
+        route_stats.total_requests, route_stats.recent_requests
+    )
+    .map_err(IndexServerBinError::WriteError)?;
+    writeln!(
+        writer,
+        "Route latency (last minute): average {}us, max {}us",
+        route_stats.recent_avg_latency_us, route_stats.recent_max_latency_us
+    )
+    .map_err(IndexServerBinError::WriteError)?;
+
+    writeln!(writer, "Remote index servers:").map_err(IndexServerBinError::WriteError)?;
+    for remote_server in &status.remote_servers {
+        write!(
+            writer,
+            "  {} {:?}",
+            public_key_to_string(&remote_server.public_key),
+            remote_server.status
+        )
+        .map_err(IndexServerBinError::WriteError)?;
+        if let Some(voucher) = &remote_server.opt_voucher {
+            write!(writer, " (Discovered through {})", public_key_to_string(voucher))
+                .map_err(IndexServerBinError::WriteError)?;
+        }
+        writeln!(writer).map_err(IndexServerBinError::WriteError)?;
+    }
+    Ok(())
+}
+
+/// Send a single request to the admin endpoint of an index server
+fn admin_request(
+    admin: SocketAddr,
+    request: AdminRequest,
+) -> Result<AdminResponse, IndexServerBinError> {
+    let mut thread_pool =
+        ThreadPool::new().map_err(|_| IndexServerBinError::CreateThreadPoolError)?;
+
+    let mut tcp_connector = TcpConnector::new(ADMIN_MAX_FRAME_LENGTH, thread_pool.clone());
+    thread_pool.run(
+        async move {
+            let conn_pair = await!(tcp_connector.transform(admin))
+                .ok_or(IndexServerBinError::ConnectAdminError)?;
+            await!(request_admin(conn_pair, request))
+                .map_err(IndexServerBinError::AdminRequestError)
+        },
+    )
+}
+
+fn status(status_cmd: StatusCmd, writer: &mut impl io::Write) -> Result<(), IndexServerBinError> {
+    let StatusCmd { admin } = status_cmd;
+
+    match admin_request(admin, AdminRequest::GetStatus)? {
+        AdminResponse::Status(index_server_status) => write_status(&index_server_status, writer),
+        _ => Err(IndexServerBinError::UnexpectedAdminResponse),
+    }
+}
+
+fn export_graph(export_graph_cmd: ExportGraphCmd) -> Result<(), IndexServerBinError> {
+    let ExportGraphCmd {
+        admin,
+        output,
+        graphml,
+    } = export_graph_cmd;
+
+    // Make sure that output does not exist:
+    if output.exists() {
+        return Err(IndexServerBinError::OutputAlreadyExists);
+    }
+
+    let edges = match admin_request(admin, AdminRequest::GetEdges)? {
+        AdminResponse::Edges(edges) => edges,
+        _ => return Err(IndexServerBinError::UnexpectedAdminResponse),
+    };
+
+    let data = if graphml {
+        edges_to_graphml(&edges)
+    } else {
+        edges_to_dot(&edges)
+    };
+
+    let mut file = File::create(&output).map_err(IndexServerBinError::CreateOutputError)?;
+    file.write_all(data.as_bytes())
+        .map_err(IndexServerBinError::WriteError)
+}
+
+fn add_server(add_server_cmd: AddServerCmd) -> Result<(), IndexServerBinError> {
+    let AddServerCmd { admin, server } = add_server_cmd;
+
+    let index_server_address = load_index_server_from_file(&server)
+        .map_err(IndexServerBinError::LoadIndexServerError)?;
+
+    match admin_request(admin, AdminRequest::AddServer(index_server_address))? {
+        AdminResponse::ServerAdded(true) => Ok(()),
+        AdminResponse::ServerAdded(false) => Err(IndexServerBinError::ServerAlreadyTrusted),
+        _ => Err(IndexServerBinError::UnexpectedAdminResponse),
+    }
+}
+
+fn remove_server(remove_server_cmd: RemoveServerCmd) -> Result<(), IndexServerBinError> {
+    let RemoveServerCmd { admin, public_key } = remove_server_cmd;
+
+    let public_key =
+        string_to_public_key(&public_key).map_err(|_| IndexServerBinError::ParsePublicKeyError)?;
+
+    match admin_request(admin, AdminRequest::RemoveServer(public_key))? {
+        AdminResponse::ServerRemoved(true) => Ok(()),
+        AdminResponse::ServerRemoved(false) => Err(IndexServerBinError::ServerNotTrusted),
+        _ => Err(IndexServerBinError::UnexpectedAdminResponse),
+    }
+}
+
+pub fn stindex(
+    st_index_cmd: StIndexCmd,
+    writer: &mut impl io::Write,
+) -> Result<(), IndexServerBinError> {
+    match st_index_cmd {
+        StIndexCmd::Run(run_cmd) => run(run_cmd),
+        StIndexCmd::Status(status_cmd) => status(status_cmd, writer),
+        StIndexCmd::ExportGraph(export_graph_cmd) => export_graph(export_graph_cmd),
+        StIndexCmd::AddServer(add_server_cmd) => add_server(add_server_cmd),
+        StIndexCmd::RemoveServer(remove_server_cmd) => remove_server(remove_server_cmd),
+    }
+}
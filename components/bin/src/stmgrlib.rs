@@ -1,10 +1,12 @@
 use std::convert::TryInto;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 use structopt::StructOpt;
 
 use crypto::crypto_rand::system_random;
 use crypto::identity::{generate_pkcs8_key_pair, Identity};
+use crypto::passphrase::KdfParams;
 
 use proto::app_server::messages::{AppPermissions, RelayAddress};
 use proto::index_server::messages::IndexServerAddress;
@@ -15,11 +17,16 @@ use database::file_db::FileDb;
 use node::NodeState;
 
 use proto::file::app::{store_trusted_app_to_file, TrustedApp};
-use proto::file::identity::{load_identity_from_file, store_raw_identity_to_file};
+use proto::file::identity::{
+    is_identity_file_encrypted, store_encrypted_raw_identity_to_file, store_raw_identity_to_file,
+    IdentityFileError,
+};
 use proto::file::index_server::store_index_server_to_file;
 use proto::file::node::store_node_to_file;
 use proto::file::relay::store_relay_to_file;
 
+use crate::passphrase::{load_identity_from_file, load_raw_identity_from_file};
+
 #[derive(Debug)]
 pub enum InitNodeDbError {
     OutputAlreadyExists,
@@ -35,6 +42,9 @@ pub struct InitNodeDbCmd {
     /// Database output file path
     #[structopt(parse(from_os_str), short = "o", long = "output")]
     pub output: PathBuf,
+    /// Privacy mode: Round down capacities reported to index servers to a multiple of this value
+    #[structopt(long = "capacity-bucket")]
+    pub capacity_bucket: Option<u128>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -42,6 +52,48 @@ pub struct GenIdentCmd {
     /// Identity file output file path
     #[structopt(parse(from_os_str), short = "o", long = "output")]
     pub output: PathBuf,
+    /// Protect the identity file with a passphrase
+    #[structopt(long = "encrypt")]
+    pub encrypt: bool,
+    /// Read the new passphrase from a file, instead of prompting for it
+    #[structopt(parse(from_os_str), long = "new-passphrase-file")]
+    pub new_passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EncryptIdentCmd {
+    /// Identity file path
+    #[structopt(parse(from_os_str), short = "i", long = "idfile")]
+    pub idfile: PathBuf,
+    /// Encrypted identity file output file path
+    #[structopt(parse(from_os_str), short = "o", long = "output")]
+    pub output: PathBuf,
+    /// Read the new passphrase from a file, instead of prompting for it
+    #[structopt(parse(from_os_str), long = "new-passphrase-file")]
+    pub new_passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DecryptIdentCmd {
+    /// Encrypted identity file path
+    #[structopt(parse(from_os_str), short = "i", long = "idfile")]
+    pub idfile: PathBuf,
+    /// Identity file output file path
+    #[structopt(parse(from_os_str), short = "o", long = "output")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ChangePassphraseCmd {
+    /// Encrypted identity file path
+    #[structopt(parse(from_os_str), short = "i", long = "idfile")]
+    pub idfile: PathBuf,
+    /// Encrypted identity file output file path
+    #[structopt(parse(from_os_str), short = "o", long = "output")]
+    pub output: PathBuf,
+    /// Read the new passphrase from a file, instead of prompting for it
+    #[structopt(parse(from_os_str), long = "new-passphrase-file")]
+    pub new_passphrase_file: Option<PathBuf>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -104,6 +156,10 @@ pub struct NodeTicketCmd {
 
 /// stmgr: offST ManaGeR
 /// A util for managing Offst entities and files
+///
+/// The passphrase of an encrypted identity file is read from the OFFST_IDENT_PASSPHRASE
+/// environment variable, or from the file pointed to by OFFST_IDENT_PASSPHRASE_FILE.
+/// If neither is set, the passphrase is prompted for.
 #[derive(Debug, StructOpt)]
 #[structopt(name = "stmgr")]
 pub enum StMgrCmd {
@@ -113,6 +169,15 @@ pub enum StMgrCmd {
     /// Randomly generate a new identity file
     #[structopt(name = "gen-ident")]
     GenIdent(GenIdentCmd),
+    /// Protect an identity file with a passphrase
+    #[structopt(name = "encrypt-ident")]
+    EncryptIdent(EncryptIdentCmd),
+    /// Remove the passphrase protection of an identity file
+    #[structopt(name = "decrypt-ident")]
+    DecryptIdent(DecryptIdentCmd),
+    /// Change the passphrase of an encrypted identity file
+    #[structopt(name = "change-passphrase")]
+    ChangePassphrase(ChangePassphraseCmd),
     /// Create an application ticket
     #[structopt(name = "app-ticket")]
     AppTicket(AppTicketCmd),
@@ -127,7 +192,13 @@ pub enum StMgrCmd {
     NodeTicket(NodeTicketCmd),
 }
 
-fn init_node_db(InitNodeDbCmd { idfile, output }: InitNodeDbCmd) -> Result<(), InitNodeDbError> {
+fn init_node_db(
+    InitNodeDbCmd {
+        idfile,
+        output,
+        capacity_bucket,
+    }: InitNodeDbCmd,
+) -> Result<(), InitNodeDbError> {
     // Make sure that output does not exist.
     // This program should never override any file!
     // (Otherwise users might erase their database by
@@ -142,29 +213,187 @@ fn init_node_db(InitNodeDbCmd { idfile, output }: InitNodeDbCmd) -> Result<(), I
     let local_public_key = identity.get_public_key();
 
     // Create a new database file:
-    let initial_state = NodeState::<NetAddress>::new(local_public_key);
+    let mut initial_state = NodeState::<NetAddress>::new(local_public_key);
+    initial_state.index_client_config.opt_capacity_bucket = capacity_bucket;
     let _ = FileDb::create(output, initial_state).map_err(|_| InitNodeDbError::FileDbError)?;
 
     Ok(())
 }
 
+#[derive(Debug)]
+pub enum NewPassphraseError {
+    ReadPassphraseError,
+    PassphraseMismatch,
+    EmptyPassphrase,
+}
+
+/// Obtain a new passphrase for an identity file.
+/// If `opt_passphrase_file` is not given, the user is prompted twice, to avoid typos.
+fn read_new_passphrase(opt_passphrase_file: Option<PathBuf>) -> Result<String, NewPassphraseError> {
+    let passphrase = if let Some(passphrase_file) = opt_passphrase_file {
+        let passphrase = fs::read_to_string(passphrase_file)
+            .map_err(|_| NewPassphraseError::ReadPassphraseError)?;
+        passphrase
+            .trim_end_matches(|c: char| c == '\n' || c == '\r')
+            .to_owned()
+    } else {
+        let passphrase = rpassword::read_password_from_tty(Some("New passphrase: "))
+            .map_err(|_| NewPassphraseError::ReadPassphraseError)?;
+        let passphrase2 = rpassword::read_password_from_tty(Some("Repeat new passphrase: "))
+            .map_err(|_| NewPassphraseError::ReadPassphraseError)?;
+        if passphrase != passphrase2 {
+            return Err(NewPassphraseError::PassphraseMismatch);
+        }
+        passphrase
+    };
+
+    if passphrase.is_empty() {
+        return Err(NewPassphraseError::EmptyPassphrase);
+    }
+    Ok(passphrase)
+}
+
 #[derive(Debug)]
 pub enum GenIdentityError {
     OutputAlreadyExists,
+    NewPassphraseError(NewPassphraseError),
     StoreToFileError,
 }
 
 /// Randomly generate an identity file (private-public key pair)
-fn gen_identity(GenIdentCmd { output }: GenIdentCmd) -> Result<(), GenIdentityError> {
+fn gen_identity(
+    GenIdentCmd {
+        output,
+        encrypt,
+        new_passphrase_file,
+    }: GenIdentCmd,
+) -> Result<(), GenIdentityError> {
     // Generate a new random keypair:
     let rng = system_random();
     let pkcs8 = generate_pkcs8_key_pair(&rng);
 
-    if output.exists() {
-        return Err(GenIdentityError::OutputAlreadyExists);
+    let store_res = if encrypt {
+        let passphrase = read_new_passphrase(new_passphrase_file)
+            .map_err(GenIdentityError::NewPassphraseError)?;
+        store_encrypted_raw_identity_to_file(
+            &pkcs8,
+            &passphrase,
+            &KdfParams::default(),
+            &rng,
+            &output,
+        )
+    } else {
+        store_raw_identity_to_file(&pkcs8, &output)
+    };
+    store_res.map_err(|e| match e {
+        IdentityFileError::FileAlreadyExists => GenIdentityError::OutputAlreadyExists,
+        _ => GenIdentityError::StoreToFileError,
+    })
+}
+
+#[derive(Debug)]
+pub enum EncryptIdentityError {
+    OutputAlreadyExists,
+    LoadIdentityError,
+    AlreadyEncrypted,
+    NewPassphraseError(NewPassphraseError),
+    StoreToFileError,
+}
+
+/// Protect an identity file with a passphrase.
+/// The encrypted identity is written to a new file. The original file is left untouched.
+fn encrypt_identity(
+    EncryptIdentCmd {
+        idfile,
+        output,
+        new_passphrase_file,
+    }: EncryptIdentCmd,
+) -> Result<(), EncryptIdentityError> {
+    if is_identity_file_encrypted(&idfile).map_err(|_| EncryptIdentityError::LoadIdentityError)? {
+        return Err(EncryptIdentityError::AlreadyEncrypted);
+    }
+    let raw_identity = load_raw_identity_from_file(&idfile)
+        .map_err(|_| EncryptIdentityError::LoadIdentityError)?;
+
+    let passphrase = read_new_passphrase(new_passphrase_file)
+        .map_err(EncryptIdentityError::NewPassphraseError)?;
+    let rng = system_random();
+    store_encrypted_raw_identity_to_file(
+        &raw_identity,
+        &passphrase,
+        &KdfParams::default(),
+        &rng,
+        &output,
+    )
+    .map_err(|e| match e {
+        IdentityFileError::FileAlreadyExists => EncryptIdentityError::OutputAlreadyExists,
+        _ => EncryptIdentityError::StoreToFileError,
+    })
+}
+
+#[derive(Debug)]
+pub enum DecryptIdentityError {
+    OutputAlreadyExists,
+    LoadIdentityError,
+    NotEncrypted,
+    StoreToFileError,
+}
+
+/// Remove the passphrase protection of an identity file.
+/// The decrypted identity is written to a new file. The original file is left untouched.
+fn decrypt_identity(
+    DecryptIdentCmd { idfile, output }: DecryptIdentCmd,
+) -> Result<(), DecryptIdentityError> {
+    if !is_identity_file_encrypted(&idfile).map_err(|_| DecryptIdentityError::LoadIdentityError)? {
+        return Err(DecryptIdentityError::NotEncrypted);
+    }
+    let raw_identity = load_raw_identity_from_file(&idfile)
+        .map_err(|_| DecryptIdentityError::LoadIdentityError)?;
+
+    store_raw_identity_to_file(&raw_identity, &output).map_err(|e| match e {
+        IdentityFileError::FileAlreadyExists => DecryptIdentityError::OutputAlreadyExists,
+        _ => DecryptIdentityError::StoreToFileError,
+    })
+}
+
+#[derive(Debug)]
+pub enum ChangePassphraseError {
+    OutputAlreadyExists,
+    LoadIdentityError,
+    NotEncrypted,
+    NewPassphraseError(NewPassphraseError),
+    StoreToFileError,
+}
+
+/// Change the passphrase of an encrypted identity file.
+/// The re-encrypted identity is written to a new file. The original file is left untouched.
+fn change_passphrase(
+    ChangePassphraseCmd {
+        idfile,
+        output,
+        new_passphrase_file,
+    }: ChangePassphraseCmd,
+) -> Result<(), ChangePassphraseError> {
+    if !is_identity_file_encrypted(&idfile).map_err(|_| ChangePassphraseError::LoadIdentityError)? {
+        return Err(ChangePassphraseError::NotEncrypted);
     }
+    let raw_identity = load_raw_identity_from_file(&idfile)
+        .map_err(|_| ChangePassphraseError::LoadIdentityError)?;
 
-    store_raw_identity_to_file(&pkcs8, &output).map_err(|_| GenIdentityError::StoreToFileError)
+    let passphrase = read_new_passphrase(new_passphrase_file)
+        .map_err(ChangePassphraseError::NewPassphraseError)?;
+    let rng = system_random();
+    store_encrypted_raw_identity_to_file(
+        &raw_identity,
+        &passphrase,
+        &KdfParams::default(),
+        &rng,
+        &output,
+    )
+    .map_err(|e| match e {
+        IdentityFileError::FileAlreadyExists => ChangePassphraseError::OutputAlreadyExists,
+        _ => ChangePassphraseError::StoreToFileError,
+    })
 }
 
 #[derive(Debug)]
@@ -344,6 +573,9 @@ fn node_ticket(
 pub enum StmError {
     InitNodeDbError(InitNodeDbError),
     GenIdentityError(GenIdentityError),
+    EncryptIdentityError(EncryptIdentityError),
+    DecryptIdentityError(DecryptIdentityError),
+    ChangePassphraseError(ChangePassphraseError),
     AppTicketError(AppTicketError),
     RelayTicketError(RelayTicketError),
     IndexTicketError(IndexTicketError),
@@ -362,6 +594,24 @@ impl From<GenIdentityError> for StmError {
     }
 }
 
+impl From<EncryptIdentityError> for StmError {
+    fn from(e: EncryptIdentityError) -> Self {
+        StmError::EncryptIdentityError(e)
+    }
+}
+
+impl From<DecryptIdentityError> for StmError {
+    fn from(e: DecryptIdentityError) -> Self {
+        StmError::DecryptIdentityError(e)
+    }
+}
+
+impl From<ChangePassphraseError> for StmError {
+    fn from(e: ChangePassphraseError) -> Self {
+        StmError::ChangePassphraseError(e)
+    }
+}
+
 impl From<AppTicketError> for StmError {
     fn from(e: AppTicketError) -> Self {
         StmError::AppTicketError(e)
@@ -390,6 +640,9 @@ pub fn stmgr(st_mgr_cmd: StMgrCmd) -> Result<(), StmError> {
     match st_mgr_cmd {
         StMgrCmd::InitNodeDb(i) => init_node_db(i)?,
         StMgrCmd::GenIdent(i) => gen_identity(i)?,
+        StMgrCmd::EncryptIdent(i) => encrypt_identity(i)?,
+        StMgrCmd::DecryptIdent(i) => decrypt_identity(i)?,
+        StMgrCmd::ChangePassphrase(i) => change_passphrase(i)?,
         StMgrCmd::AppTicket(i) => app_ticket(i)?,
         StMgrCmd::RelayTicket(i) => relay_ticket(i)?,
         StMgrCmd::IndexTicket(i) => index_ticket(i)?,
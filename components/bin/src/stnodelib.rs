@@ -9,7 +9,6 @@ use futures::task::SpawnExt;
 
 use structopt::StructOpt;
 
-use common::conn::Listener;
 use common::int_convert::usize_to_u64;
 
 use crypto::crypto_rand::system_random;
@@ -17,19 +16,21 @@ use crypto::crypto_rand::system_random;
 use identity::{create_identity, IdentityClient};
 use timer::create_timer;
 
-use node::{net_node, NetNodeError, NodeConfig, NodeState};
+use node::{net_node, NetNodeError, NodeConfig, NodeState, NodeStateV0};
 
 use database::file_db::FileDb;
 
-use net::{NetConnector, TcpListener};
+use net::{NetConnector, PunchConnector};
 use proto::consts::{
-    KEEPALIVE_TICKS, MAX_FRAME_LENGTH, MAX_NODE_RELAYS, MAX_OPERATIONS_IN_BATCH, TICKS_TO_REKEY,
-    TICK_MS,
+    BYTES_TO_REKEY, KEEPALIVE_TICKS, MAX_FRAME_LENGTH, MAX_NODE_RELAYS, MAX_OPERATIONS_IN_BATCH,
+    MESSAGES_TO_REKEY, TICKS_TO_REKEY, TICK_MS,
 };
 use proto::net::messages::NetAddress;
 
 use proto::file::app::load_trusted_apps;
-use proto::file::identity::load_identity_from_file;
+
+use crate::net_utils::{listen_direct_raw_conns, listen_raw_conns};
+use crate::passphrase::load_identity_from_file;
 
 /// Memory allocated to a channel in memory (Used to connect two components)
 const CHANNEL_LEN: usize = 0x20;
@@ -45,6 +46,8 @@ const MAX_OPEN_INDEX_CLIENT_REQUESTS: usize = 0x8;
 /// The amount of ticks we are willing to wait until a connection is established (Through
 /// the relay)
 const CONN_TIMEOUT_TICKS: usize = 0x8;
+/// The amount of ticks between reports of friends connection statistics
+const CONN_STATS_TICKS: usize = 0x4;
 /// Maximum amount of concurrent applications
 /// going through the incoming connection transform at the same time
 const MAX_CONCURRENT_INCOMING_APPS: usize = 0x8;
@@ -74,6 +77,34 @@ pub struct StNodeCmd {
     /// Listening address (Used for communication with apps)
     #[structopt(short = "l", long = "laddr")]
     pub laddr: SocketAddr,
+    /// WebSocket listening address (Used for communication with apps)
+    #[structopt(long = "ws-laddr")]
+    pub ws_laddr: Option<SocketAddr>,
+    /// Listening address for direct connections from friends (Without a relay)
+    #[structopt(long = "direct-laddr")]
+    pub direct_laddr: Option<SocketAddr>,
+    /// Make outgoing connections through a SOCKS5 proxy (For example: 127.0.0.1:9050)
+    #[structopt(long = "proxy")]
+    pub proxy: Option<SocketAddr>,
+    /// Punch direct connections to friends through NATs, from this local address (For example:
+    /// 0.0.0.0:5000). Connections to relays are made from the same local address.
+    /// Not used together with a SOCKS5 proxy
+    #[structopt(long = "punch-laddr")]
+    pub punch_laddr: Option<SocketAddr>,
+    /// Use a hybrid (post-quantum) handshake with relays, friends, index servers and apps that
+    /// support it
+    #[structopt(long = "hybrid-handshake")]
+    pub hybrid_handshake: bool,
+    /// Only use the hybrid (post-quantum) handshake. Relays, friends, index servers and apps that
+    /// do not support it can not be communicated with
+    #[structopt(long = "require-hybrid")]
+    pub require_hybrid: bool,
+    /// Amount of bytes to encrypt using the same key until the next rekeying
+    #[structopt(long = "bytes-to-rekey")]
+    pub bytes_to_rekey: Option<u64>,
+    /// Amount of messages to encrypt using the same key until the next rekeying
+    #[structopt(long = "messages-to-rekey")]
+    pub messages_to_rekey: Option<u64>,
     /// Database file path
     #[structopt(parse(from_os_str), short = "d", long = "database")]
     pub database: PathBuf,
@@ -86,6 +117,14 @@ pub fn stnode(st_node_cmd: StNodeCmd) -> Result<(), NodeBinError> {
     let StNodeCmd {
         idfile,
         laddr,
+        ws_laddr,
+        direct_laddr,
+        proxy,
+        punch_laddr,
+        hybrid_handshake,
+        require_hybrid,
+        bytes_to_rekey,
+        messages_to_rekey,
         database,
         trusted,
     } = st_node_cmd;
@@ -125,12 +164,22 @@ pub fn stnode(st_node_cmd: StNodeCmd) -> Result<(), NodeBinError> {
         keepalive_ticks: KEEPALIVE_TICKS,
         /// Amount of ticks to wait until the next rekeying (Channel encryption)
         ticks_to_rekey: TICKS_TO_REKEY,
+        /// Amount of bytes to encrypt using the same key until the next rekeying
+        bytes_to_rekey: bytes_to_rekey.unwrap_or(BYTES_TO_REKEY),
+        /// Amount of messages to encrypt using the same key until the next rekeying
+        messages_to_rekey: messages_to_rekey.unwrap_or(MESSAGES_TO_REKEY),
+        /// Use the hybrid (post-quantum) handshake with remote sides that support it
+        hybrid_handshake,
+        /// Only use the hybrid (post-quantum) handshake
+        require_hybrid,
         /// Maximum amount of encryption set ups (diffie hellman) that we allow to occur at the same
         /// time.
         max_concurrent_encrypt: MAX_CONCURRENT_ENCRYPT,
         /// The amount of ticks we are willing to wait until a connection is established (Through
         /// the relay)
         conn_timeout_ticks: CONN_TIMEOUT_TICKS,
+        /// The amount of ticks between reports of friends connection statistics
+        conn_stats_ticks: CONN_STATS_TICKS,
         /// Maximum amount of operations in one move token message
         max_operations_in_batch: MAX_OPERATIONS_IN_BATCH,
         /// The size we allocate for the user send funds requests queue.
@@ -143,20 +192,50 @@ pub fn stnode(st_node_cmd: StNodeCmd) -> Result<(), NodeBinError> {
         max_concurrent_incoming_apps: MAX_CONCURRENT_INCOMING_APPS,
     };
 
+    // Punching through NATs requires connecting directly, and not through a proxy:
+    let opt_punch_laddr = if proxy.is_some() { None } else { punch_laddr };
+
     // A tcp connector, Used to connect to remote servers:
-    let net_connector =
-        NetConnector::new(MAX_FRAME_LENGTH, resolve_thread_pool, thread_pool.clone());
+    let net_connector = NetConnector::new(
+        MAX_FRAME_LENGTH,
+        proxy,
+        opt_punch_laddr,
+        timer_client.clone(),
+        resolve_thread_pool,
+        thread_pool.clone(),
+    );
+
+    // Used to punch direct connections to friends, from the local address we use for connecting to
+    // relays:
+    let opt_punch_connector = opt_punch_laddr.map(|punch_laddr| {
+        PunchConnector::new(
+            punch_laddr,
+            MAX_FRAME_LENGTH,
+            timer_client.clone(),
+            thread_pool.clone(),
+        )
+    });
 
     // Obtain secure cryptographic random:
     let rng = system_random();
 
-    // Load database:
+    // Load database (Database files of older versions are migrated):
     let atomic_db =
-        FileDb::<NodeState<NetAddress>>::load(database).map_err(|_| NodeBinError::LoadDbError)?;
+        FileDb::<NodeState<NetAddress>>::load_or_migrate::<NodeStateV0<NetAddress>>(database)
+            .map_err(|_| NodeBinError::LoadDbError)?;
 
     // Start listening to apps:
-    let app_tcp_listener = TcpListener::new(MAX_FRAME_LENGTH, thread_pool.clone());
-    let (_config_sender, incoming_app_raw_conns) = app_tcp_listener.listen(laddr);
+    let incoming_app_raw_conns = listen_raw_conns(
+        laddr,
+        ws_laddr,
+        MAX_FRAME_LENGTH,
+        timer_client.clone(),
+        thread_pool.clone(),
+    );
+
+    // Start listening to direct connections from friends:
+    let incoming_direct_raw_conns =
+        listen_direct_raw_conns(direct_laddr, MAX_FRAME_LENGTH, thread_pool.clone());
 
     // Create a closure for loading trusted apps map:
     let get_trusted_apps = move || -> Option<_> {
@@ -171,7 +250,9 @@ pub fn stnode(st_node_cmd: StNodeCmd) -> Result<(), NodeBinError> {
 
     let node_fut = net_node(
         incoming_app_raw_conns,
+        incoming_direct_raw_conns,
         net_connector,
+        opt_punch_connector,
         timer_client,
         identity_client,
         rng,
@@ -1,5 +1,5 @@
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use futures::executor::ThreadPool;
@@ -7,25 +7,39 @@ use futures::task::SpawnExt;
 
 use structopt::StructOpt;
 
-use common::conn::Listener;
+use common::access_control::{AccessControl, AccessControlOp};
 
 use crypto::crypto_rand::system_random;
+use crypto::identity::PublicKey;
 use identity::{create_identity, IdentityClient};
 
-use proto::consts::{MAX_FRAME_LENGTH, TICK_MS};
+use proto::consts::{BYTES_TO_REKEY, MAX_FRAME_LENGTH, MESSAGES_TO_REKEY, TICK_MS};
 
 use common::int_convert::usize_to_u64;
 
-use net::TcpListener;
-use relay::{net_relay_server, NetRelayServerError};
+use relay::{net_relay_server, NetRelayServerError, RelayAcl, RelayQuotas};
 use timer::create_timer;
 
-use proto::file::identity::load_identity_from_file;
+use proto::file::friend::load_friends;
+
+use crate::net_utils::listen_raw_peer_conns;
+use crate::passphrase::load_identity_from_file;
+use crate::shutdown::shutdown_signal;
 
 // TODO; Maybe take as a command line argument in the future?
 /// Maximum amount of concurrent encrypted channel set-ups.
 /// We set this number to avoid DoS from half finished encrypted channel negotiations.
 pub const MAX_CONCURRENT_ENCRYPT: usize = 0x200;
+/// Default maximum amount of concurrent tunnels a single client may take part in.
+pub const MAX_CLIENT_TUNNELS: usize = 0x40;
+/// Default maximum amount of listen registrations a single client may perform every tick.
+pub const MAX_CLIENT_LISTENS_PER_TICK: usize = 0x2;
+/// Default maximum amount of bytes a single client may send through its tunnels every tick.
+pub const MAX_CLIENT_BYTES_PER_TICK: usize = 1 << 22; // 4[MB]
+/// Default amount of ticks between two consecutive relay statistics log lines.
+pub const STATS_TICKS: usize = 60 * (1000 / TICK_MS); // 1 minute
+/// Default maximum amount of ticks to wait for open tunnels to close when shutting down.
+pub const DRAIN_TICKS: usize = 30 * (1000 / TICK_MS); // 30 seconds
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
@@ -34,6 +48,8 @@ pub enum RelayServerBinError {
     LoadIdentityError,
     CreateIdentityError,
     CreateTimerError,
+    LoadAllowlistError,
+    ShutdownSignalError,
     NetRelayServerError(NetRelayServerError),
 }
 
@@ -48,10 +64,78 @@ pub struct StRelayCmd {
     /// Listening address (Example: 0.0.0.0:1337)
     #[structopt(short = "l", long = "laddr")]
     pub laddr: SocketAddr,
+    /// WebSocket listening address (Example: 0.0.0.0:8080)
+    #[structopt(long = "ws-laddr")]
+    pub ws_laddr: Option<SocketAddr>,
+    /// Maximum amount of concurrent tunnels a single client may take part in
+    #[structopt(long = "max-tunnels")]
+    pub max_tunnels: Option<usize>,
+    /// Maximum amount of listen registrations a single client may perform every tick
+    #[structopt(long = "listens-per-tick")]
+    pub listens_per_tick: Option<usize>,
+    /// Maximum amount of bytes a single client may send through its tunnels every tick
+    #[structopt(long = "bytes-per-tick")]
+    pub bytes_per_tick: Option<usize>,
+    /// Directory of friend tickets of nodes allowed to listen on the relay.
+    /// If not provided, any node may listen.
+    #[structopt(parse(from_os_str), long = "listen-allow")]
+    pub listen_allow: Option<PathBuf>,
+    /// Directory of friend tickets of nodes allowed to connect through the relay.
+    /// If not provided, any node may connect.
+    #[structopt(parse(from_os_str), long = "connect-allow")]
+    pub connect_allow: Option<PathBuf>,
+    /// Amount of ticks between two consecutive relay statistics log lines
+    #[structopt(long = "stats-ticks")]
+    pub stats_ticks: Option<usize>,
+    /// Maximum amount of ticks to wait for open tunnels to close when shutting down
+    #[structopt(long = "drain-ticks")]
+    pub drain_ticks: Option<usize>,
+    /// Amount of bytes to encrypt using the same key until the next rekeying
+    #[structopt(long = "bytes-to-rekey")]
+    pub bytes_to_rekey: Option<u64>,
+    /// Amount of messages to encrypt using the same key until the next rekeying
+    #[structopt(long = "messages-to-rekey")]
+    pub messages_to_rekey: Option<u64>,
+}
+
+/// Load an access control list from a directory of friend tickets
+fn load_access_control(dir_path: &Path) -> Result<AccessControl<PublicKey>, RelayServerBinError> {
+    let friends = load_friends(dir_path).map_err(|_| RelayServerBinError::LoadAllowlistError)?;
+
+    let mut access_control = AccessControl::new();
+    for friend in friends {
+        access_control.apply_op(AccessControlOp::Add(friend.public_key));
+    }
+    Ok(access_control)
 }
 
 pub fn strelay(st_relay_cmd: StRelayCmd) -> Result<(), RelayServerBinError> {
-    let StRelayCmd { idfile, laddr } = st_relay_cmd;
+    let StRelayCmd {
+        idfile,
+        laddr,
+        ws_laddr,
+        max_tunnels,
+        listens_per_tick,
+        bytes_per_tick,
+        listen_allow,
+        connect_allow,
+        stats_ticks,
+        drain_ticks,
+        bytes_to_rekey,
+        messages_to_rekey,
+    } = st_relay_cmd;
+
+    // Load access control lists:
+    let acl = RelayAcl {
+        opt_listen: match listen_allow {
+            Some(dir_path) => Some(load_access_control(&dir_path)?),
+            None => None,
+        },
+        opt_connect: match connect_allow {
+            Some(dir_path) => Some(load_access_control(&dir_path)?),
+            None => None,
+        },
+    };
 
     // Parse identity file:
     let identity =
@@ -74,8 +158,24 @@ pub fn strelay(st_relay_cmd: StRelayCmd) -> Result<(), RelayServerBinError> {
 
     let rng = system_random();
 
-    let tcp_listener = TcpListener::new(MAX_FRAME_LENGTH, thread_pool.clone());
-    let (_config_sender, incoming_raw_conns) = tcp_listener.listen(laddr);
+    let quotas = RelayQuotas {
+        max_tunnels: max_tunnels.unwrap_or(MAX_CLIENT_TUNNELS),
+        max_listens_per_tick: listens_per_tick.unwrap_or(MAX_CLIENT_LISTENS_PER_TICK),
+        max_bytes_per_tick: bytes_per_tick.unwrap_or(MAX_CLIENT_BYTES_PER_TICK),
+    };
+
+    // Start draining on SIGTERM or SIGINT:
+    let shutdown_receiver =
+        shutdown_signal().map_err(|_| RelayServerBinError::ShutdownSignalError)?;
+
+    // The addresses of remote peers are reported to clients that ask to punch through NATs:
+    let incoming_raw_conns = listen_raw_peer_conns(
+        laddr,
+        ws_laddr,
+        MAX_FRAME_LENGTH,
+        timer_client.clone(),
+        thread_pool.clone(),
+    );
 
     let relay_server_fut = net_relay_server(
         incoming_raw_conns,
@@ -83,6 +183,13 @@ pub fn strelay(st_relay_cmd: StRelayCmd) -> Result<(), RelayServerBinError> {
         timer_client,
         rng,
         MAX_CONCURRENT_ENCRYPT,
+        bytes_to_rekey.unwrap_or(BYTES_TO_REKEY),
+        messages_to_rekey.unwrap_or(MESSAGES_TO_REKEY),
+        quotas,
+        acl,
+        Some(stats_ticks.unwrap_or(STATS_TICKS)),
+        shutdown_receiver,
+        drain_ticks.unwrap_or(DRAIN_TICKS),
         thread_pool.clone(),
     );
 
@@ -11,17 +11,21 @@ use common::conn::{FutTransform, Listener};
 use common::select_streams::{select_streams, BoxStream};
 use crypto::identity::{compare_public_key, PublicKey};
 use proto::funder::messages::{ChannelerToFunder, ChannelerUpdateFriend, FunderToChanneler};
+use proto::report::messages::ConnectionsReportMutation;
 
+use crate::conn_stats::ConnStats;
 use crate::connect_pool::{ConnectPoolControl, CpConfigClient, CpConnectClient};
 use crate::listen_pool::LpConfig;
 use crate::overwrite_channel::overwrite_send_all;
-use crate::types::RawConn;
+use crate::relay_health::RelaysHealth;
+use crate::types::FriendConn;
 
 #[derive(Debug)]
 pub enum ChannelerEvent<RA> {
     FromFunder(FunderToChanneler<RA>),
-    Connection((PublicKey, RawConn)),
+    Connection((PublicKey, FriendConn<RA>)),
     FriendEvent(FriendEvent),
+    TimerTick,
     ListenerClosed,
     FunderClosed,
 }
@@ -43,6 +47,7 @@ pub enum ChannelerError {
     ListenerClosed,
     FunderClosed,
     ConnectorConfigError,
+    RequestTimerStreamError,
 }
 
 struct Connected<T> {
@@ -94,7 +99,7 @@ enum OutFriendStatus {
 
 struct OutFriend<RA> {
     config_client: CpConfigClient<RA>,
-    connect_client: CpConnectClient,
+    connect_client: CpConnectClient<RA>,
     status: OutFriendStatus,
 }
 
@@ -143,11 +148,19 @@ struct Channeler<RA, C, S, TF> {
     spawner: S,
     to_funder: TF,
     event_sender: mpsc::Sender<ChannelerEvent<RA>>,
+    /// Shared by the connect pools of all friends. Advanced by our timer ticks.
+    relays_health: RelaysHealth<RA>,
+    conn_stats: ConnStats<RA>,
+    /// Connection statistics are reported once every `stats_ticks` ticks:
+    stats_ticks: usize,
+    remaining_stats_ticks: usize,
+    /// Connection statistics are dropped if the receiver is too slow:
+    to_reports: mpsc::Sender<ConnectionsReportMutation<RA>>,
 }
 
 impl<RA, C, S, TF> Channeler<RA, C, S, TF>
 where
-    RA: Clone + Send + Sync + 'static,
+    RA: Clone + Eq + Send + Sync + 'static,
     C: FutTransform<Input = PublicKey, Output = ConnectPoolControl<RA>>
         + Clone
         + Send
@@ -163,6 +176,9 @@ where
         spawner: S,
         to_funder: TF,
         event_sender: mpsc::Sender<ChannelerEvent<RA>>,
+        relays_health: RelaysHealth<RA>,
+        stats_ticks: usize,
+        to_reports: mpsc::Sender<ConnectionsReportMutation<RA>>,
     ) -> Self {
         Channeler {
             local_public_key,
@@ -172,6 +188,11 @@ where
             spawner,
             to_funder,
             event_sender,
+            conn_stats: ConnStats::new(relays_health.clone()),
+            relays_health,
+            stats_ticks,
+            remaining_stats_ticks: stats_ticks,
+            to_reports,
         }
     }
 
@@ -192,8 +213,8 @@ where
         let mut c_event_sender = self.event_sender.clone();
         let connect_fut = async move {
             match await!(c_connect_client.connect()) {
-                Ok(raw_conn) => {
-                    let event = ChannelerEvent::Connection((c_friend_public_key, raw_conn));
+                Ok(friend_conn) => {
+                    let event = ChannelerEvent::Connection((c_friend_public_key, friend_conn));
                     let _ = await!(c_event_sender.send(event));
                 }
                 Err(e) => {
@@ -224,6 +245,8 @@ where
             return Ok(());
         }
 
+        self.conn_stats.add_friend(friend_public_key);
+
         // We should add a new friend:
         if self.is_listen_friend(friend_public_key) {
             self.friends
@@ -262,8 +285,11 @@ where
                     }
                 };
 
+                let message_len = message.len();
                 // TODO: Should we check errors here?
-                let _ = await!(friend_connected.send(message));
+                if await!(friend_connected.send(message)) {
+                    self.conn_stats.message_sent(&public_key, message_len);
+                }
                 Ok(())
             }
             FunderToChanneler::SetRelays(addresses) => {
@@ -293,6 +319,8 @@ where
                 } else if let Some(out_friend) =
                     self.friends.out_friends.get_mut(&friend_public_key)
                 {
+                    self.conn_stats
+                        .set_relays(&friend_public_key, friend_relays.clone());
                     await!(out_friend.config_client.config(friend_relays))
                         .map_err(|_| ChannelerError::ConnectorConfigError)?;
                 }
@@ -300,6 +328,8 @@ where
                 Ok(())
             }
             FunderToChanneler::RemoveFriend(friend_public_key) => {
+                self.conn_stats.remove_friend(&friend_public_key);
+
                 if self.friends.in_friends.remove(&friend_public_key).is_some() {
                     let lp_config = LpConfig::RemoveFriend(friend_public_key.clone());
                     await!(self.listen_config.send(lp_config))
@@ -318,9 +348,9 @@ where
     async fn handle_connection(
         &mut self,
         friend_public_key: PublicKey,
-        raw_conn: RawConn,
+        friend_conn: FriendConn<RA>,
     ) -> Result<(), ChannelerError> {
-        let (sender, receiver) = raw_conn;
+        let ((sender, receiver), conn_info) = friend_conn;
 
         // Close fut_recv whenever closer is closed.
         let (closer, close_receiver) = oneshot::channel::<()>();
@@ -371,6 +401,8 @@ where
             return Ok(());
         }
 
+        self.conn_stats.set_connected(&friend_public_key, conn_info);
+
         let mut c_event_sender = self.event_sender.clone();
         let c_friend_public_key = friend_public_key.clone();
         let mut receiver = receiver.map(move |data| {
@@ -409,11 +441,14 @@ where
     ) -> Result<(), ChannelerError> {
         match friend_event {
             FriendEvent::IncomingMessage((friend_public_key, data)) => {
+                self.conn_stats.message_received(&friend_public_key, data.len());
                 let message = ChannelerToFunder::Message((friend_public_key, data));
                 await!(self.to_funder.send(message))
                     .map_err(|_| ChannelerError::SendToFunderFailed)?
             }
             FriendEvent::ReceiverClosed(friend_public_key) => {
+                self.conn_stats.set_disconnected(&friend_public_key);
+
                 // Report Funder that the friend is offline:
                 let to_funder = ChannelerToFunder::Offline(friend_public_key.clone());
                 await!(self.to_funder.send(to_funder))
@@ -445,26 +480,58 @@ where
         }
         Ok(())
     }
+
+    fn handle_timer_tick(&mut self) {
+        self.relays_health.handle_timer_tick();
+        self.handle_stats_tick();
+    }
+
+    /// Send connection statistics changes, once every `stats_ticks` ticks.
+    /// Connection statistics are not essential for the operation of the Channeler, so we never
+    /// wait for the receiver. Changes that could not be sent are sent again on a later tick.
+    fn handle_stats_tick(&mut self) {
+        self.remaining_stats_ticks = self.remaining_stats_ticks.saturating_sub(1);
+        if self.remaining_stats_ticks > 0 {
+            return;
+        }
+        self.remaining_stats_ticks = self.stats_ticks;
+
+        for mutation in self.conn_stats.collect_mutations() {
+            match self.to_reports.try_send(mutation) {
+                Ok(()) => {}
+                Err(e) if e.is_full() => self.conn_stats.unsent_mutation(e.into_inner()),
+                Err(_) => {
+                    warn!("handle_stats_tick(): Failed to send connections report mutation");
+                    return;
+                }
+            }
+        }
+    }
 }
 
-pub async fn channeler_loop<FF, TF, RA, C, L, S>(
+pub async fn channeler_loop<FF, TF, RA, C, L, TS, S>(
     local_public_key: PublicKey,
     from_funder: FF,
     to_funder: TF,
+    to_reports: mpsc::Sender<ConnectionsReportMutation<RA>>,
     connector: C,
     listener: L,
+    relays_health: RelaysHealth<RA>,
+    timer_stream: TS,
+    stats_ticks: usize,
     spawner: S,
 ) -> Result<(), ChannelerError>
 where
     FF: Stream<Item = FunderToChanneler<RA>> + Send + Unpin,
     TF: Sink<SinkItem = ChannelerToFunder> + Send + Unpin,
-    RA: Clone + Send + Sync + Debug + 'static,
+    RA: Clone + Eq + Send + Sync + Debug + 'static,
     C: FutTransform<Input = PublicKey, Output = ConnectPoolControl<RA>>
         + Clone
         + Send
         + Sync
         + 'static,
-    L: Listener<Connection = (PublicKey, RawConn), Config = LpConfig<RA>, Arg = ()> + Clone + Send,
+    L: Listener<Connection = (PublicKey, FriendConn<RA>), Config = LpConfig<RA>, Arg = ()> + Send,
+    TS: Stream + Unpin + Send,
     S: Spawn + Clone + Send + Sync + 'static,
 {
     let (event_sender, event_receiver) = mpsc::channel(0);
@@ -478,6 +545,9 @@ where
         spawner,
         to_funder,
         event_sender,
+        relays_health,
+        stats_ticks,
+        to_reports,
     );
 
     // Forward incoming listen connections:
@@ -497,19 +567,22 @@ where
         .map(ChannelerEvent::FromFunder)
         .chain(stream::once(future::ready(ChannelerEvent::FunderClosed)));
 
-    let mut events = select_streams![event_receiver, from_funder];
+    let timer_stream = timer_stream.map(|_| ChannelerEvent::TimerTick);
+
+    let mut events = select_streams![event_receiver, from_funder, timer_stream];
 
     while let Some(event) = await!(events.next()) {
         match event {
             ChannelerEvent::FromFunder(funder_to_channeler) => {
                 await!(channeler.handle_from_funder(funder_to_channeler))?
             }
-            ChannelerEvent::Connection((public_key, raw_conn)) => {
-                await!(channeler.handle_connection(public_key, raw_conn))?
+            ChannelerEvent::Connection((public_key, friend_conn)) => {
+                await!(channeler.handle_connection(public_key, friend_conn))?
             }
             ChannelerEvent::FriendEvent(friend_event) => {
                 await!(channeler.handle_friend_event(friend_event))?
             }
+            ChannelerEvent::TimerTick => channeler.handle_timer_tick(),
             ChannelerEvent::ListenerClosed => return Err(ChannelerError::ListenerClosed),
             ChannelerEvent::FunderClosed => return Err(ChannelerError::FunderClosed),
         };
@@ -525,6 +598,17 @@ mod tests {
     use common::dummy_connector::DummyConnector;
     use common::dummy_listener::DummyListener;
     use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+    use keepalive::KeepAliveStats;
+    use timer::TimerTick;
+
+    use crate::types::ConnInfo;
+
+    fn dummy_conn_info(relay_address: u32) -> ConnInfo<u32> {
+        ConnInfo {
+            opt_relay_address: Some(relay_address),
+            keepalive_stats: KeepAliveStats::new(),
+        }
+    }
 
     /// Test the case of a friend the channeler initiates connection to.
     async fn task_channeler_loop_connect_friend<S>(mut spawner: S)
@@ -533,6 +617,8 @@ mod tests {
     {
         let (mut funder_sender, from_funder) = mpsc::channel(0);
         let (to_funder, mut funder_receiver) = mpsc::channel(0);
+        let (to_reports, _reports_receiver) = mpsc::channel(0);
+        let (_tick_sender, timer_stream) = mpsc::channel::<TimerTick>(0);
 
         // We sort the public keys ahead of time, so that we know how to break ties.
         // Our local public key will be pks[1]. pks[0] < pks[1] < pks[2]
@@ -556,8 +642,12 @@ mod tests {
                     pks[1].clone(),
                     from_funder,
                     to_funder,
+                    to_reports,
                     connector,
                     listener,
+                    RelaysHealth::new(),
+                    timer_stream,
+                    1, // stats_ticks
                     spawner.clone(),
                 )
                 .map_err(|e| error!("Error in channeler_loop(): {:?}", e))
@@ -622,7 +712,7 @@ mod tests {
         let (local_sender, mut pk0_receiver) = mpsc::channel(0);
         connect_req0
             .response_sender
-            .send((local_sender, local_receiver))
+            .send(((local_sender, local_receiver), dummy_conn_info(0x0u32)))
             .unwrap();
 
         // Friend should be reported as online:
@@ -668,7 +758,7 @@ mod tests {
         let (local_sender, pk0_receiver) = mpsc::channel(0);
         connect_req0
             .response_sender
-            .send((local_sender, local_receiver))
+            .send(((local_sender, local_receiver), dummy_conn_info(0x0u32)))
             .unwrap();
 
         // Online report:
@@ -700,7 +790,7 @@ mod tests {
         drop(
             connect_req0
                 .response_sender
-                .send((local_sender, local_receiver)),
+                .send(((local_sender, local_receiver), dummy_conn_info(0x0u32))),
         );
 
         // The connection requests receiver should be closed:
@@ -723,6 +813,8 @@ mod tests {
     {
         let (mut funder_sender, from_funder) = mpsc::channel(0);
         let (to_funder, mut funder_receiver) = mpsc::channel(0);
+        let (to_reports, _reports_receiver) = mpsc::channel(0);
+        let (_tick_sender, timer_stream) = mpsc::channel::<TimerTick>(0);
 
         // We sort the public keys ahead of time, so that we know how to break ties.
         // Our local public key will be pks[1]. pks[0] < pks[1] < pks[2]
@@ -746,8 +838,12 @@ mod tests {
                     pks[1].clone(),
                     from_funder,
                     to_funder,
+                    to_reports,
                     connector,
                     listener,
+                    RelaysHealth::new(),
+                    timer_stream,
+                    1, // stats_ticks
                     spawner.clone(),
                 )
                 .map_err(|e| error!("Error in channeler_loop(): {:?}", e))
@@ -785,7 +881,7 @@ mod tests {
             let (sender, mut pk2_receiver) = mpsc::channel(0);
             await!(listener_request
                 .conn_sender
-                .send((pks[2].clone(), (sender, receiver))))
+                .send((pks[2].clone(), ((sender, receiver), dummy_conn_info(0x2u32)))))
             .unwrap();
 
             // Friend should be reported as online:
@@ -847,6 +943,8 @@ mod tests {
     {
         let (mut funder_sender, from_funder) = mpsc::channel(0);
         let (to_funder, mut funder_receiver) = mpsc::channel(0);
+        let (to_reports, _reports_receiver) = mpsc::channel(0);
+        let (_tick_sender, timer_stream) = mpsc::channel::<TimerTick>(0);
 
         // We sort the public keys ahead of time, so that we know how to break ties.
         // Our local public key will be pks[1]. pks[0] < pks[1] < pks[2]
@@ -870,8 +968,12 @@ mod tests {
                     pks[1].clone(),
                     from_funder,
                     to_funder,
+                    to_reports,
                     connector,
                     listener,
+                    RelaysHealth::new(),
+                    timer_stream,
+                    1, // stats_ticks
                     spawner.clone(),
                 )
                 .map_err(|e| error!("Error in channeler_loop(): {:?}", e))
@@ -907,7 +1009,7 @@ mod tests {
             let (sender, _pk2_receiver) = mpsc::channel(0);
             await!(listener_request
                 .conn_sender
-                .send((pks[2].clone(), (sender, receiver))))
+                .send((pks[2].clone(), ((sender, receiver), dummy_conn_info(0x2u32)))))
             .unwrap();
 
             // Friend should be reported as online:
@@ -948,6 +1050,8 @@ mod tests {
     {
         let (mut funder_sender, from_funder) = mpsc::channel(0);
         let (to_funder, _funder_receiver) = mpsc::channel(0);
+        let (to_reports, _reports_receiver) = mpsc::channel(0);
+        let (_tick_sender, timer_stream) = mpsc::channel::<TimerTick>(0);
 
         // We sort the public keys ahead of time, so that we know how to break ties.
         // Our local public key will be pks[1]. pks[0] < pks[1] < pks[2]
@@ -971,8 +1075,12 @@ mod tests {
                     pks[1].clone(),
                     from_funder,
                     to_funder,
+                    to_reports,
                     connector,
                     listener,
+                    RelaysHealth::new(),
+                    timer_stream,
+                    1, // stats_ticks
                     spawner.clone(),
                 )
                 .map_err(|e| error!("Error in channeler_loop(): {:?}", e))
@@ -1040,6 +1148,118 @@ mod tests {
         ));
     }
 
+    /// Test the reporting of connection statistics.
+    async fn task_channeler_loop_conn_stats<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + Sync + 'static,
+    {
+        let (mut funder_sender, from_funder) = mpsc::channel(0);
+        let (to_funder, mut funder_receiver) = mpsc::channel(0);
+        let (to_reports, mut reports_receiver) = mpsc::channel(0);
+        let (mut tick_sender, timer_stream) = mpsc::channel::<TimerTick>(0);
+
+        // Our local public key will be pks[1]. pks[2] will be a listen friend.
+        let mut pks = (0..3)
+            .map(|i| PublicKey::from(&[i; PUBLIC_KEY_LEN]))
+            .collect::<Vec<PublicKey>>();
+        pks.sort_by(compare_public_key);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(conn_request_sender);
+
+        let (listener_req_sender, mut listener_req_receiver) = mpsc::channel(0);
+        let listener = DummyListener::new(listener_req_sender, spawner.clone());
+
+        spawner
+            .spawn(
+                channeler_loop(
+                    pks[1].clone(),
+                    from_funder,
+                    to_funder,
+                    to_reports,
+                    connector,
+                    listener,
+                    RelaysHealth::new(),
+                    timer_stream,
+                    2, // stats_ticks
+                    spawner.clone(),
+                )
+                .map_err(|e| error!("Error in channeler_loop(): {:?}", e))
+                .map(|_| ()),
+            )
+            .unwrap();
+
+        let mut listener_request = await!(listener_req_receiver.next()).unwrap();
+
+        // Add a friend:
+        let channeler_update_friend = ChannelerUpdateFriend {
+            friend_public_key: pks[2].clone(),
+            friend_relays: vec![0x0u32],
+            local_relays: vec![0x2u32],
+        };
+        await!(funder_sender.send(FunderToChanneler::UpdateFriend(channeler_update_friend)))
+            .unwrap();
+        let _lp_config = await!(listener_request.config_receiver.next()).unwrap();
+
+        // Set up a connection from pks[2], through the relay 0x2u32:
+        let (mut pk2_sender, receiver) = mpsc::channel(0);
+        let (sender, mut pk2_receiver) = mpsc::channel(0);
+        await!(listener_request
+            .conn_sender
+            .send((pks[2].clone(), ((sender, receiver), dummy_conn_info(0x2u32)))))
+        .unwrap();
+
+        let channeler_to_funder = await!(funder_receiver.next()).unwrap();
+        match channeler_to_funder {
+            ChannelerToFunder::Online(public_key) => assert_eq!(public_key, pks[2]),
+            _ => unreachable!(),
+        };
+
+        // Exchange messages:
+        await!(funder_sender.send(FunderToChanneler::Message((pks[2].clone(), vec![1, 2, 3]))))
+            .unwrap();
+        assert_eq!(await!(pk2_receiver.next()).unwrap(), vec![1, 2, 3]);
+
+        await!(pk2_sender.send(vec![3, 2])).unwrap();
+        let _channeler_to_funder = await!(funder_receiver.next()).unwrap();
+
+        // Statistics are reported only once every two ticks:
+        await!(tick_sender.send(TimerTick)).unwrap();
+        await!(tick_sender.send(TimerTick)).unwrap();
+
+        let mutation = await!(reports_receiver.next()).unwrap();
+        match mutation {
+            ConnectionsReportMutation::SetFriend((public_key, report)) => {
+                assert_eq!(public_key, pks[2]);
+                assert!(report.opt_connected_since.is_some());
+                assert_eq!(report.reconnect_count, 0);
+                assert_eq!(report.messages_sent, 1);
+                assert_eq!(report.bytes_sent, 3);
+                assert_eq!(report.messages_received, 1);
+                assert_eq!(report.bytes_received, 2);
+                assert_eq!(report.opt_relay, Some(0x2u32));
+            }
+            _ => unreachable!(),
+        };
+
+        // Remove the friend:
+        await!(funder_sender.send(FunderToChanneler::RemoveFriend(pks[2].clone()))).unwrap();
+        let _lp_config = await!(listener_request.config_receiver.next()).unwrap();
+        let _channeler_to_funder = await!(funder_receiver.next()).unwrap();
+
+        await!(tick_sender.send(TimerTick)).unwrap();
+        await!(tick_sender.send(TimerTick)).unwrap();
+
+        let mutation = await!(reports_receiver.next()).unwrap();
+        assert_eq!(mutation, ConnectionsReportMutation::RemoveFriend(pks[2].clone()));
+    }
+
+    #[test]
+    fn test_channeler_loop_conn_stats() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_channeler_loop_conn_stats(thread_pool.clone()));
+    }
+
     // TODO: Add tests to make sure access control works properly?
     // If a friend with a strange public key tries to connect, he should not be able to succeed?
 }
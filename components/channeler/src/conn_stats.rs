@@ -0,0 +1,348 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crypto::identity::PublicKey;
+use proto::report::messages::{ConnectionsReportMutation, FriendConnectionReport};
+
+use crate::relay_health::RelaysHealth;
+use crate::types::ConnInfo;
+
+/// Convert a duration to milliseconds, saturating on overflow.
+pub fn duration_to_millis(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(duration.subsec_millis()))
+}
+
+/// Current unix time, in seconds.
+fn unix_time_now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs())
+}
+
+/// Connection statistics of a single friend.
+struct FriendConnStats<RA> {
+    /// Amount of connections established with this friend so far
+    num_connections: u64,
+    opt_connected_since: Option<u64>,
+    /// Traffic over the current connection:
+    bytes_sent: u64,
+    bytes_received: u64,
+    messages_sent: u64,
+    messages_received: u64,
+    /// Information about the current connection (If connected)
+    opt_conn_info: Option<ConnInfo<RA>>,
+    /// The relays we connect to this friend through (Only if we connect to this friend)
+    relays: Vec<RA>,
+    /// The last report we have sent about this friend
+    opt_last_report: Option<FriendConnectionReport<RA>>,
+    /// The last report could not be sent, and should be sent again
+    resend_report: bool,
+}
+
+impl<RA> FriendConnStats<RA>
+where
+    RA: Clone + Eq,
+{
+    fn new() -> Self {
+        FriendConnStats {
+            num_connections: 0,
+            opt_connected_since: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            messages_sent: 0,
+            messages_received: 0,
+            opt_conn_info: None,
+            relays: Vec::new(),
+            opt_last_report: None,
+            resend_report: false,
+        }
+    }
+
+    fn report(&self, relays_health: &RelaysHealth<RA>) -> FriendConnectionReport<RA> {
+        FriendConnectionReport {
+            opt_connected_since: self.opt_connected_since,
+            reconnect_count: self.num_connections.saturating_sub(1),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            messages_sent: self.messages_sent,
+            messages_received: self.messages_received,
+            opt_last_rtt_ms: self
+                .opt_conn_info
+                .as_ref()
+                .and_then(|conn_info| conn_info.keepalive_stats.last_rtt())
+                .map(duration_to_millis),
+            opt_relay: self
+                .opt_conn_info
+                .as_ref()
+                .and_then(|conn_info| conn_info.opt_relay_address.clone()),
+            relays_health: relays_health.report(&self.relays),
+        }
+    }
+}
+
+/// Connection statistics of all the friends of the Channeler.
+/// Changes are reported periodically, using `collect_mutations()`.
+pub struct ConnStats<RA> {
+    friends: HashMap<PublicKey, FriendConnStats<RA>>,
+    /// Removed friends that were already reported
+    removed_friends: HashSet<PublicKey>,
+    /// Health of the relays of all the friends
+    relays_health: RelaysHealth<RA>,
+}
+
+impl<RA> ConnStats<RA>
+where
+    RA: Clone + Eq,
+{
+    pub fn new(relays_health: RelaysHealth<RA>) -> Self {
+        ConnStats {
+            friends: HashMap::new(),
+            removed_friends: HashSet::new(),
+            relays_health,
+        }
+    }
+
+    /// Forget the health of relays that none of the friends use anymore.
+    fn prune_relays_health(&self) {
+        let friends = &self.friends;
+        self.relays_health.retain_relays(|relay| {
+            friends
+                .values()
+                .any(|friend_conn_stats| friend_conn_stats.relays.contains(relay))
+        });
+    }
+
+    pub fn add_friend(&mut self, friend_public_key: &PublicKey) {
+        self.removed_friends.remove(friend_public_key);
+        self.friends
+            .entry(friend_public_key.clone())
+            .or_insert_with(FriendConnStats::new);
+    }
+
+    pub fn remove_friend(&mut self, friend_public_key: &PublicKey) {
+        if let Some(friend_conn_stats) = self.friends.remove(friend_public_key) {
+            if friend_conn_stats.opt_last_report.is_some() {
+                self.removed_friends.insert(friend_public_key.clone());
+            }
+        }
+        self.prune_relays_health();
+    }
+
+    /// Set the relays we use to connect to a friend.
+    /// The health of those relays is tracked, and reported together with the friend.
+    pub fn set_relays(&mut self, friend_public_key: &PublicKey, relays: Vec<RA>) {
+        if let Some(friend_conn_stats) = self.friends.get_mut(friend_public_key) {
+            for relay in &relays {
+                self.relays_health.add_relay(relay.clone());
+            }
+            friend_conn_stats.relays = relays;
+        }
+        self.prune_relays_health();
+    }
+
+    pub fn set_connected(&mut self, friend_public_key: &PublicKey, conn_info: ConnInfo<RA>) {
+        if let Some(friend_conn_stats) = self.friends.get_mut(friend_public_key) {
+            friend_conn_stats.num_connections = friend_conn_stats.num_connections.saturating_add(1);
+            friend_conn_stats.opt_connected_since = unix_time_now();
+            friend_conn_stats.bytes_sent = 0;
+            friend_conn_stats.bytes_received = 0;
+            friend_conn_stats.messages_sent = 0;
+            friend_conn_stats.messages_received = 0;
+            friend_conn_stats.opt_conn_info = Some(conn_info);
+        }
+    }
+
+    pub fn set_disconnected(&mut self, friend_public_key: &PublicKey) {
+        if let Some(friend_conn_stats) = self.friends.get_mut(friend_public_key) {
+            friend_conn_stats.opt_connected_since = None;
+            friend_conn_stats.opt_conn_info = None;
+        }
+    }
+
+    pub fn message_sent(&mut self, friend_public_key: &PublicKey, len: usize) {
+        if let Some(friend_conn_stats) = self.friends.get_mut(friend_public_key) {
+            friend_conn_stats.messages_sent = friend_conn_stats.messages_sent.saturating_add(1);
+            friend_conn_stats.bytes_sent = friend_conn_stats.bytes_sent.saturating_add(len as u64);
+        }
+    }
+
+    pub fn message_received(&mut self, friend_public_key: &PublicKey, len: usize) {
+        if let Some(friend_conn_stats) = self.friends.get_mut(friend_public_key) {
+            friend_conn_stats.messages_received =
+                friend_conn_stats.messages_received.saturating_add(1);
+            friend_conn_stats.bytes_received =
+                friend_conn_stats.bytes_received.saturating_add(len as u64);
+        }
+    }
+
+    /// Get report mutations for all the changes since the last call to this function.
+    pub fn collect_mutations(&mut self) -> Vec<ConnectionsReportMutation<RA>> {
+        let mut mutations = Vec::new();
+        for friend_public_key in self.removed_friends.drain() {
+            mutations.push(ConnectionsReportMutation::RemoveFriend(friend_public_key));
+        }
+
+        for (friend_public_key, friend_conn_stats) in &mut self.friends {
+            let report = friend_conn_stats.report(&self.relays_health);
+            if !friend_conn_stats.resend_report
+                && friend_conn_stats.opt_last_report.as_ref() == Some(&report)
+            {
+                continue;
+            }
+            friend_conn_stats.opt_last_report = Some(report.clone());
+            friend_conn_stats.resend_report = false;
+            mutations.push(ConnectionsReportMutation::SetFriend((
+                friend_public_key.clone(),
+                report,
+            )));
+        }
+        mutations
+    }
+
+    /// Return a mutation (Obtained from `collect_mutations`) that could not be sent.
+    /// The change will be included again in the next call to `collect_mutations`.
+    pub fn unsent_mutation(&mut self, mutation: ConnectionsReportMutation<RA>) {
+        match mutation {
+            ConnectionsReportMutation::RemoveFriend(friend_public_key) => {
+                if !self.friends.contains_key(&friend_public_key) {
+                    self.removed_friends.insert(friend_public_key);
+                }
+            }
+            ConnectionsReportMutation::SetFriend((friend_public_key, _report)) => {
+                if let Some(friend_conn_stats) = self.friends.get_mut(&friend_public_key) {
+                    friend_conn_stats.resend_report = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::identity::PUBLIC_KEY_LEN;
+    use keepalive::KeepAliveStats;
+
+    #[test]
+    fn test_conn_stats_collect_mutations() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let relays_health = RelaysHealth::new();
+        let mut conn_stats = ConnStats::<u32>::new(relays_health.clone());
+
+        conn_stats.add_friend(&pk_a);
+        let mutations = conn_stats.collect_mutations();
+        assert_eq!(mutations.len(), 1);
+        match &mutations[0] {
+            ConnectionsReportMutation::SetFriend((public_key, report)) => {
+                assert_eq!(public_key, &pk_a);
+                assert!(report.opt_connected_since.is_none());
+                assert!(report.opt_relay.is_none());
+            }
+            _ => unreachable!(),
+        };
+
+        // Nothing has changed:
+        assert!(conn_stats.collect_mutations().is_empty());
+
+        let conn_info = ConnInfo {
+            opt_relay_address: Some(5u32),
+            keepalive_stats: KeepAliveStats::new(),
+        };
+        conn_stats.set_connected(&pk_a, conn_info.clone());
+        conn_stats.message_sent(&pk_a, 10);
+        conn_stats.message_received(&pk_a, 20);
+        conn_stats.message_received(&pk_a, 30);
+
+        let mutations = conn_stats.collect_mutations();
+        assert_eq!(mutations.len(), 1);
+        match &mutations[0] {
+            ConnectionsReportMutation::SetFriend((_public_key, report)) => {
+                assert!(report.opt_connected_since.is_some());
+                assert_eq!(report.reconnect_count, 0);
+                assert_eq!(report.messages_sent, 1);
+                assert_eq!(report.bytes_sent, 10);
+                assert_eq!(report.messages_received, 2);
+                assert_eq!(report.bytes_received, 50);
+                assert_eq!(report.opt_relay, Some(5u32));
+            }
+            _ => unreachable!(),
+        };
+
+        // Reconnect. Traffic counters are reset:
+        conn_stats.set_disconnected(&pk_a);
+        conn_stats.set_connected(&pk_a, conn_info);
+        let mutations = conn_stats.collect_mutations();
+        match &mutations[0] {
+            ConnectionsReportMutation::SetFriend((_public_key, report)) => {
+                assert_eq!(report.reconnect_count, 1);
+                assert_eq!(report.messages_sent, 0);
+                assert_eq!(report.messages_received, 0);
+            }
+            _ => unreachable!(),
+        };
+
+        // Changes in relays health are reported:
+        conn_stats.set_relays(&pk_a, vec![5u32]);
+        let mutations = conn_stats.collect_mutations();
+        assert_eq!(mutations.len(), 1);
+
+        relays_health.attempt_failure(&5u32, 1);
+        let mutations = conn_stats.collect_mutations();
+        match &mutations[0] {
+            ConnectionsReportMutation::SetFriend((_public_key, report)) => {
+                assert_eq!(report.relays_health.len(), 1);
+                assert_eq!(report.relays_health[0].relay, 5u32);
+                assert_eq!(report.relays_health[0].failures, 1);
+            }
+            _ => unreachable!(),
+        };
+
+        // Relays that are no longer used by any friend are forgotten:
+        conn_stats.set_relays(&pk_a, vec![6u32]);
+        assert!(relays_health.report(&[5u32]).is_empty());
+        assert_eq!(relays_health.report(&[6u32]).len(), 1);
+
+        conn_stats.remove_friend(&pk_a);
+        assert!(relays_health.report(&[6u32]).is_empty());
+        let mutations = conn_stats.collect_mutations();
+        assert_eq!(mutations, vec![ConnectionsReportMutation::RemoveFriend(pk_a)]);
+        assert!(conn_stats.collect_mutations().is_empty());
+    }
+
+    #[test]
+    fn test_conn_stats_unsent_mutation() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let mut conn_stats = ConnStats::<u32>::new(RelaysHealth::new());
+
+        conn_stats.add_friend(&pk_a);
+        let mut mutations = conn_stats.collect_mutations();
+        assert_eq!(mutations.len(), 1);
+
+        // The mutation could not be sent. It is collected again:
+        conn_stats.unsent_mutation(mutations.pop().unwrap());
+        let mut mutations = conn_stats.collect_mutations();
+        assert_eq!(mutations.len(), 1);
+        assert!(conn_stats.collect_mutations().is_empty());
+
+        conn_stats.unsent_mutation(mutations.pop().unwrap());
+        conn_stats.remove_friend(&pk_a);
+        let mut mutations = conn_stats.collect_mutations();
+        assert_eq!(
+            mutations,
+            vec![ConnectionsReportMutation::RemoveFriend(pk_a.clone())]
+        );
+
+        // An unsent removal is collected again:
+        conn_stats.unsent_mutation(mutations.pop().unwrap());
+        let mutations = conn_stats.collect_mutations();
+        assert_eq!(
+            mutations,
+            vec![ConnectionsReportMutation::RemoveFriend(pk_a)]
+        );
+        assert!(conn_stats.collect_mutations().is_empty());
+    }
+}
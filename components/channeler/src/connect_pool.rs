@@ -1,8 +1,10 @@
-use std::collections::{HashSet, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::marker::{PhantomData, Unpin};
+use std::marker::Unpin;
 use std::mem;
+use std::time::Instant;
 
 use futures::channel::{mpsc, oneshot};
 use futures::task::{Spawn, SpawnExt};
@@ -10,22 +12,24 @@ use futures::{future, select, stream, FutureExt, SinkExt, Stream, StreamExt, Try
 
 use common::conn::{BoxFuture, FutTransform};
 use common::select_streams::{select_streams, BoxStream};
+use keepalive::{KeepAliveConn, KeepAliveStats};
 use timer::TimerClient;
 
-use crate::types::RawConn;
+use crate::relay_health::RelaysHealth;
+use crate::types::{ConnInfo, DirectAddress, FriendConn, RawConn};
 use crypto::identity::PublicKey;
 
 #[derive(Debug)]
 pub struct ConnectPoolClientError;
 
 #[derive(Debug)]
-pub struct CpConnectRequest {
-    pub response_sender: oneshot::Sender<RawConn>,
+pub struct CpConnectRequest<RA> {
+    pub response_sender: oneshot::Sender<FriendConn<RA>>,
 }
 
 #[derive(Clone)]
-pub struct CpConnectClient {
-    request_sender: mpsc::Sender<CpConnectRequest>,
+pub struct CpConnectClient<RA> {
+    request_sender: mpsc::Sender<CpConnectRequest<RA>>,
 }
 
 pub struct CpConfigClient<RA> {
@@ -43,12 +47,12 @@ impl<RA> CpConfigClient<RA> {
     }
 }
 
-impl CpConnectClient {
-    pub fn new(request_sender: mpsc::Sender<CpConnectRequest>) -> Self {
+impl<RA> CpConnectClient<RA> {
+    pub fn new(request_sender: mpsc::Sender<CpConnectRequest<RA>>) -> Self {
         CpConnectClient { request_sender }
     }
 
-    pub async fn connect(&mut self) -> Result<RawConn, ConnectPoolClientError> {
+    pub async fn connect(&mut self) -> Result<FriendConn<RA>, ConnectPoolClientError> {
         let (response_sender, response_receiver) = oneshot::channel();
         let connect_request = CpConnectRequest { response_sender };
         await!(self.request_sender.send(connect_request)).map_err(|_| ConnectPoolClientError)?;
@@ -63,28 +67,57 @@ pub enum ConnectPoolError {
     MultipleConnectRequests,
 }
 
+/// A failed attempt to connect to a friend through an address
+#[derive(Debug)]
+enum ConnAttemptError {
+    /// Could not connect to the address itself (For example: The relay is down)
+    AddressFailure,
+    /// Connected to the address, but could not establish a secure channel with the friend
+    /// (For example: The friend is not connected to the relay)
+    FriendFailure,
+    /// The address was removed during the connection attempt
+    Canceled,
+}
+
+type ConnAttemptResult = Result<(RawConn, KeepAliveStats), ConnAttemptError>;
+
 #[derive(Debug)]
 enum CpEvent<RA> {
-    ConnectRequest(CpConnectRequest),
+    ConnectRequest(CpConnectRequest<RA>),
     ConnectRequestClosed,
     ConfigRequest(Vec<RA>),
     ConfigRequestClosed,
-    ConnectAttemptDone(Option<RawConn>),
+    ConnectAttemptDone(ConnAttemptResult),
     TimerTick,
     TimerClosed,
 }
 
 enum CpStatus<RA> {
     NoRequest,
-    Waiting((usize, oneshot::Sender<RawConn>)),
-    Connecting((RA, oneshot::Sender<()>, oneshot::Sender<RawConn>)),
+    /// Waiting until there is an address we can attempt to connect to
+    Waiting(oneshot::Sender<FriendConn<RA>>),
+    Connecting(
+        (
+            RA,
+            Instant,
+            oneshot::Sender<()>,
+            oneshot::Sender<FriendConn<RA>>,
+        ),
+    ),
 }
 
 struct ConnectPool<RA, C, ET, S> {
     friend_public_key: PublicKey,
     addresses: VecDeque<RA>,
+    /// Shared with the connect pools of all the other friends
+    relays_health: RelaysHealth<RA>,
+    /// Addresses through which we could not reach the friend, with the remaining ticks until we
+    /// may attempt them again. These failures are not held against the relays.
+    friend_backoffs: HashMap<RA, usize>,
     status: CpStatus<RA>,
-    conn_done_sender: mpsc::Sender<Option<RawConn>>,
+    conn_done_sender: mpsc::Sender<ConnAttemptResult>,
+    /// Base backoff from a failing address. Grows exponentially with consecutive failures of a
+    /// relay.
     backoff_ticks: usize,
     client_connector: C,
     encrypt_transform: ET,
@@ -97,41 +130,50 @@ async fn conn_attempt<RA, C, ET>(
     mut client_connector: C,
     mut encrypt_transform: ET,
     canceler: oneshot::Receiver<()>,
-) -> Option<RawConn>
+) -> ConnAttemptResult
 where
     RA: Eq,
-    C: FutTransform<Input = (RA, PublicKey), Output = Option<RawConn>> + Clone,
+    C: FutTransform<Input = (RA, PublicKey), Output = Option<KeepAliveConn>> + Clone,
     ET: FutTransform<Input = (PublicKey, RawConn), Output = Option<RawConn>> + Clone,
 {
     // TODO; How to remove this Box::pin?
     let connect_fut = Box::pin(
         async move {
-            let raw_conn =
-                await!(client_connector.transform((address, friend_public_key.clone())))?;
-            await!(encrypt_transform.transform((friend_public_key.clone(), raw_conn)))
+            let keepalive_conn =
+                await!(client_connector.transform((address, friend_public_key.clone())))
+                    .ok_or(ConnAttemptError::AddressFailure)?;
+            let KeepAliveConn { conn_pair, stats } = keepalive_conn;
+            let enc_conn =
+                await!(encrypt_transform.transform((friend_public_key.clone(), conn_pair)))
+                    .ok_or(ConnAttemptError::FriendFailure)?;
+            Ok((enc_conn, stats))
         },
     );
 
     // We either finish connecting, or got canceled in the middle:
     select! {
         connect_fut = connect_fut.fuse() => connect_fut,
-        _ = canceler.fuse() => None,
+        _ = canceler.fuse() => Err(ConnAttemptError::Canceled),
     }
 }
 
 impl<RA, C, ET, S> ConnectPool<RA, C, ET, S>
 where
-    RA: Hash + Clone + Eq + Send + Debug + 'static,
+    RA: DirectAddress + Hash + Clone + Eq + Send + Debug + 'static,
     S: Spawn,
     ET: FutTransform<Input = (PublicKey, RawConn), Output = Option<RawConn>>
         + Clone
         + Send
         + 'static,
-    C: FutTransform<Input = (RA, PublicKey), Output = Option<RawConn>> + Clone + Send + 'static,
+    C: FutTransform<Input = (RA, PublicKey), Output = Option<KeepAliveConn>>
+        + Clone
+        + Send
+        + 'static,
 {
     pub fn new(
         friend_public_key: PublicKey,
-        conn_done_sender: mpsc::Sender<Option<RawConn>>,
+        relays_health: RelaysHealth<RA>,
+        conn_done_sender: mpsc::Sender<ConnAttemptResult>,
         backoff_ticks: usize,
         client_connector: C,
         encrypt_transform: ET,
@@ -140,6 +182,8 @@ where
         ConnectPool {
             friend_public_key,
             addresses: VecDeque::new(),
+            relays_health,
+            friend_backoffs: HashMap::new(),
             status: CpStatus::NoRequest,
             conn_done_sender,
             backoff_ticks,
@@ -162,14 +206,14 @@ where
 
         let mut c_conn_done_sender = self.conn_done_sender.clone();
         let conn_fut = async move {
-            let opt_conn = await!(conn_attempt(
+            let conn_attempt_result = await!(conn_attempt(
                 c_friend_public_key.clone(),
                 address,
                 c_client_connector.clone(),
                 c_encrypt_transform.clone(),
                 cancel_receiver
             ));
-            let _ = await!(c_conn_done_sender.send(opt_conn));
+            let _ = await!(c_conn_done_sender.send(conn_attempt_result));
         };
 
         self.spawner
@@ -179,68 +223,95 @@ where
         Ok(cancel_sender)
     }
 
-    pub fn handle_connect_request(
+    /// Take the next address to connect to out of the queue.
+    ///
+    /// Direct addresses of the friend come first. We fall back to relays if connecting directly
+    /// fails. Relays are attempted from the healthiest to the least healthy one, skipping
+    /// addresses we are backing off from. Equally healthy relays are attempted cyclically.
+    fn pop_next_address(&mut self) -> Option<RA> {
+        let relays_health = &self.relays_health;
+        let friend_backoffs = &self.friend_backoffs;
+        let index = self
+            .addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, address)| {
+                relays_health.is_available(address) && !friend_backoffs.contains_key(address)
+            })
+            .max_by_key(|(index, address)| {
+                (
+                    address.is_direct(),
+                    relays_health.score(address),
+                    Reverse(*index),
+                )
+            })
+            .map(|(index, _)| index)?;
+        self.addresses.remove(index)
+    }
+
+    /// Attempt to connect to the next address, if there is any.
+    /// Otherwise, wait until an address becomes available.
+    fn try_connect(
         &mut self,
-        connect_request: CpConnectRequest,
+        response_sender: oneshot::Sender<FriendConn<RA>>,
     ) -> Result<(), ConnectPoolError> {
-        if let CpStatus::NoRequest = self.status {
-        } else {
-            return Err(ConnectPoolError::MultipleConnectRequests);
-        }
-
-        let address = match self.addresses.pop_front() {
+        let address = match self.pop_next_address() {
+            Some(address) => address,
             None => {
-                // We can't connect yet, because we don't know of any address.
-                self.status = CpStatus::Waiting((0, connect_request.response_sender));
+                self.status = CpStatus::Waiting(response_sender);
                 return Ok(());
             }
-            Some(address) => address,
         };
 
         let canceler = self.create_conn_attempt(address.clone())?;
-        self.status = CpStatus::Connecting((address, canceler, connect_request.response_sender));
+        self.status = CpStatus::Connecting((address, Instant::now(), canceler, response_sender));
         Ok(())
     }
 
+    pub fn handle_connect_request(
+        &mut self,
+        connect_request: CpConnectRequest<RA>,
+    ) -> Result<(), ConnectPoolError> {
+        if let CpStatus::NoRequest = self.status {
+        } else {
+            return Err(ConnectPoolError::MultipleConnectRequests);
+        }
+
+        self.try_connect(connect_request.response_sender)
+    }
+
     fn add_address(&mut self, address: RA) -> Result<(), ConnectPoolError> {
-        let was_empty = self.addresses.is_empty();
         if !self.addresses.contains(&address) {
             self.addresses.push_back(address);
         }
 
-        let status = mem::replace(&mut self.status, CpStatus::NoRequest);
-        match (was_empty, status) {
-            (true, CpStatus::Waiting((_remaining_ticks, response_sender))) => {
-                let address = self.addresses.pop_front().unwrap();
-                let canceler = self.create_conn_attempt(address.clone())?;
-                self.status = CpStatus::Connecting((address, canceler, response_sender));
-            }
-            (_, status) => self.status = status,
+        match mem::replace(&mut self.status, CpStatus::NoRequest) {
+            CpStatus::Waiting(response_sender) => self.try_connect(response_sender)?,
+            status => self.status = status,
         };
         Ok(())
     }
 
     fn remove_address(&mut self, address: RA) -> Result<(), ConnectPoolError> {
         self.addresses.retain(|cur_address| cur_address != &address);
+        self.friend_backoffs.remove(&address);
         match mem::replace(&mut self.status, CpStatus::NoRequest) {
             CpStatus::NoRequest => {}
-            CpStatus::Waiting(waiting) => {
-                self.status = CpStatus::Waiting(waiting);
+            CpStatus::Waiting(response_sender) => {
+                self.status = CpStatus::Waiting(response_sender);
             }
-            CpStatus::Connecting((cur_address, canceler, response_sender)) => {
+            CpStatus::Connecting((cur_address, start_instant, canceler, response_sender)) => {
                 if address == cur_address {
                     // We were trying to connect to the address being removed:
                     let _ = canceler.send(());
-                    if let Some(address) = self.addresses.pop_front() {
-                        // There is another address we can use:
-                        let canceler = self.create_conn_attempt(address.clone())?;
-                        self.status = CpStatus::Connecting((address, canceler, response_sender));
-                    } else {
-                        // There is no other address:
-                        self.status = CpStatus::Waiting((0, response_sender));
-                    }
+                    self.try_connect(response_sender)?;
                 } else {
-                    self.status = CpStatus::Connecting((cur_address, canceler, response_sender));
+                    self.status = CpStatus::Connecting((
+                        cur_address,
+                        start_instant,
+                        canceler,
+                        response_sender,
+                    ));
                 }
             }
         };
@@ -262,67 +333,93 @@ where
         Ok(())
     }
 
+    /// The backoff from failing relays is advanced by the Channeler, because it is shared by all
+    /// the connect pools. Here we only advance the backoff from addresses that failed to reach
+    /// the friend.
     pub fn handle_timer_tick(&mut self) -> Result<(), ConnectPoolError> {
-        let waiting = match mem::replace(&mut self.status, CpStatus::NoRequest) {
-            CpStatus::Waiting(waiting) => waiting,
-            other_status => {
-                self.status = other_status;
-                return Ok(());
-            }
-        };
-
-        let (mut backoff_ticks, response_sender) = waiting;
-        backoff_ticks = backoff_ticks.saturating_sub(1);
-        if backoff_ticks == 0 {
-            if let Some(address) = self.addresses.pop_front() {
-                let canceler = self.create_conn_attempt(address.clone())?;
-                self.status = CpStatus::Connecting((address, canceler, response_sender));
-            } else {
-                self.status = CpStatus::Waiting((self.backoff_ticks, response_sender));
-            }
-        } else {
-            self.status = CpStatus::Waiting((backoff_ticks, response_sender));
+        for remaining_ticks in self.friend_backoffs.values_mut() {
+            *remaining_ticks = remaining_ticks.saturating_sub(1);
         }
+        self.friend_backoffs
+            .retain(|_, remaining_ticks| *remaining_ticks > 0);
+
+        match mem::replace(&mut self.status, CpStatus::NoRequest) {
+            // Some address might have become available:
+            CpStatus::Waiting(response_sender) => self.try_connect(response_sender)?,
+            status => self.status = status,
+        };
         Ok(())
     }
 
-    pub fn handle_connect_attempt_done(&mut self, opt_conn: Option<RawConn>) {
+    pub fn handle_connect_attempt_done(
+        &mut self,
+        conn_attempt_result: ConnAttemptResult,
+    ) -> Result<(), ConnectPoolError> {
+        if let Err(ConnAttemptError::Canceled) = conn_attempt_result {
+            // The address was removed, and we already moved on to the next address:
+            return Ok(());
+        }
+
         let connecting = match mem::replace(&mut self.status, CpStatus::NoRequest) {
             CpStatus::NoRequest | CpStatus::Waiting(_) => unreachable!(),
             CpStatus::Connecting(connecting) => connecting,
         };
 
-        let (address, _canceler, response_sender) = connecting;
-        self.addresses.push_back(address);
-
-        if let Some(conn) = opt_conn {
-            if let Err(e) = response_sender.send(conn) {
-                warn!(
-                    "handle_connect_attempt_done(): Failed to send connection response: {:?}",
-                    e
-                );
+        let (address, start_instant, _canceler, response_sender) = connecting;
+        self.addresses.push_back(address.clone());
+
+        match conn_attempt_result {
+            Ok((conn, keepalive_stats)) => {
+                self.relays_health
+                    .attempt_success(&address, start_instant.elapsed());
+                let conn_info = ConnInfo {
+                    opt_relay_address: Some(address),
+                    keepalive_stats,
+                };
+                if let Err(e) = response_sender.send((conn, conn_info)) {
+                    warn!(
+                        "handle_connect_attempt_done(): Failed to send connection response: {:?}",
+                        e
+                    );
+                }
+                self.status = CpStatus::NoRequest;
             }
-            self.status = CpStatus::NoRequest;
-        } else {
-            self.status = CpStatus::Waiting((self.backoff_ticks, response_sender));
+            // Try the next address right away. We only wait if all the addresses are failing:
+            Err(ConnAttemptError::AddressFailure) => {
+                self.relays_health
+                    .attempt_failure(&address, self.backoff_ticks);
+                self.try_connect(response_sender)?;
+            }
+            Err(ConnAttemptError::FriendFailure) => {
+                // The relay works, but the friend could not be reached through it (For example,
+                // because the friend is offline). This is not the fault of the relay:
+                self.friend_backoffs.insert(address, self.backoff_ticks);
+                self.try_connect(response_sender)?;
+            }
+            Err(ConnAttemptError::Canceled) => unreachable!(),
         }
+        Ok(())
     }
 }
 
 async fn connect_pool_loop<RA, ET, TS, C, S>(
-    incoming_requests: mpsc::Receiver<CpConnectRequest>,
+    incoming_requests: mpsc::Receiver<CpConnectRequest<RA>>,
     incoming_config: mpsc::Receiver<Vec<RA>>,
     timer_stream: TS,
     encrypt_transform: ET,
     friend_public_key: PublicKey,
+    relays_health: RelaysHealth<RA>,
     backoff_ticks: usize,
     client_connector: C,
     spawner: S,
     mut opt_event_sender: Option<mpsc::Sender<()>>,
 ) -> Result<(), ConnectPoolError>
 where
-    RA: Hash + Clone + Eq + Send + Debug + 'static,
-    C: FutTransform<Input = (RA, PublicKey), Output = Option<RawConn>> + Clone + Send + 'static,
+    RA: DirectAddress + Hash + Clone + Eq + Send + Debug + 'static,
+    C: FutTransform<Input = (RA, PublicKey), Output = Option<KeepAliveConn>>
+        + Clone
+        + Send
+        + 'static,
     TS: Stream + Unpin + Send,
     ET: FutTransform<Input = (PublicKey, RawConn), Output = Option<RawConn>>
         + Clone
@@ -333,6 +430,7 @@ where
     let (conn_done_sender, incoming_conn_done) = mpsc::channel(0);
     let mut connect_pool = ConnectPool::new(
         friend_public_key,
+        relays_health,
         conn_done_sender,
         backoff_ticks,
         client_connector,
@@ -381,7 +479,7 @@ where
                 break;
             }
             CpEvent::ConnectAttemptDone(opt_conn) => {
-                connect_pool.handle_connect_attempt_done(opt_conn)
+                connect_pool.handle_connect_attempt_done(opt_conn)?
             }
         }
         if let Some(ref mut event_sender) = opt_event_sender {
@@ -392,19 +490,23 @@ where
     Ok(())
 }
 
-pub type ConnectPoolControl<RA> = (CpConfigClient<RA>, CpConnectClient);
+pub type ConnectPoolControl<RA> = (CpConfigClient<RA>, CpConnectClient<RA>);
 
 pub fn create_connect_pool<RA, ET, TS, C, S>(
     timer_stream: TS,
     encrypt_transform: ET,
     friend_public_key: PublicKey,
+    relays_health: RelaysHealth<RA>,
     backoff_ticks: usize,
     client_connector: C,
     mut spawner: S,
 ) -> Result<ConnectPoolControl<RA>, ConnectPoolError>
 where
-    RA: Hash + Clone + Eq + Send + Debug + 'static,
-    C: FutTransform<Input = (RA, PublicKey), Output = Option<RawConn>> + Clone + Send + 'static,
+    RA: DirectAddress + Hash + Clone + Eq + Send + Debug + 'static,
+    C: FutTransform<Input = (RA, PublicKey), Output = Option<KeepAliveConn>>
+        + Clone
+        + Send
+        + 'static,
     TS: Stream + Unpin + Send + 'static,
     ET: FutTransform<Input = (PublicKey, RawConn), Output = Option<RawConn>>
         + Clone
@@ -421,6 +523,7 @@ where
         timer_stream,
         encrypt_transform,
         friend_public_key,
+        relays_health,
         backoff_ticks,
         client_connector,
         spawner.clone(),
@@ -444,15 +547,19 @@ pub struct PoolConnector<RA, C, ET, S> {
     timer_client: TimerClient,
     client_connector: C,
     encrypt_transform: ET,
+    /// Shared by the connect pools of all friends
+    relays_health: RelaysHealth<RA>,
     backoff_ticks: usize,
     spawner: S,
-    phantom_b: PhantomData<RA>,
 }
 
 impl<RA, C, ET, S> PoolConnector<RA, C, ET, S>
 where
     RA: Hash + Clone + Eq + Send + 'static,
-    C: FutTransform<Input = (RA, PublicKey), Output = Option<RawConn>> + Clone + Send + 'static,
+    C: FutTransform<Input = (RA, PublicKey), Output = Option<KeepAliveConn>>
+        + Clone
+        + Send
+        + 'static,
     ET: FutTransform<Input = (PublicKey, RawConn), Output = Option<RawConn>>
         + Clone
         + Send
@@ -463,6 +570,7 @@ where
         timer_client: TimerClient,
         client_connector: C,
         encrypt_transform: ET,
+        relays_health: RelaysHealth<RA>,
         backoff_ticks: usize,
         spawner: S,
     ) -> Self {
@@ -470,17 +578,20 @@ where
             timer_client,
             client_connector,
             encrypt_transform,
+            relays_health,
             backoff_ticks,
             spawner,
-            phantom_b: PhantomData,
         }
     }
 }
 
 impl<RA, C, ET, S> FutTransform for PoolConnector<RA, C, ET, S>
 where
-    RA: Hash + Clone + Eq + Send + Debug + 'static,
-    C: FutTransform<Input = (RA, PublicKey), Output = Option<RawConn>> + Clone + Send + 'static,
+    RA: DirectAddress + Hash + Clone + Eq + Send + Debug + 'static,
+    C: FutTransform<Input = (RA, PublicKey), Output = Option<KeepAliveConn>>
+        + Clone
+        + Send
+        + 'static,
     ET: FutTransform<Input = (PublicKey, RawConn), Output = Option<RawConn>>
         + Clone
         + Send
@@ -499,6 +610,7 @@ where
                     timer_stream,
                     self.encrypt_transform.clone(),
                     friend_public_key,
+                    self.relays_health.clone(),
                     self.backoff_ticks,
                     self.client_connector.clone(),
                     self.spawner.clone(),
@@ -516,11 +628,22 @@ mod tests {
 
     use common::conn::FuncFutTransform;
     use common::dummy_connector::DummyConnector;
+    use std::convert::TryFrom;
+
     use crypto::identity::PUBLIC_KEY_LEN;
+    use proto::app_server::messages::RelayAddress;
+    use proto::net::messages::NetAddress;
 
     use timer::{dummy_timer_multi_sender, TimerTick};
 
-    async fn task_pool_connector_cyclic_connect<S>(spawner: S)
+    /// Plain numbers are used as relay addresses in tests
+    impl DirectAddress for u32 {
+        fn is_direct(&self) -> bool {
+            false
+        }
+    }
+
+    async fn task_pool_connector_prefer_healthy<S>(spawner: S)
     where
         S: Spawn + Clone + Send + 'static,
     {
@@ -537,10 +660,12 @@ mod tests {
             Box::pin(future::ready(Some(conn_pair)))
         });
 
+        let relays_health = RelaysHealth::new();
         let mut pool_connector = PoolConnector::<u32, _, _, _>::new(
             timer_client,
             client_connector,
             encrypt_transform,
+            relays_health.clone(),
             backoff_ticks,
             spawner,
         );
@@ -550,7 +675,11 @@ mod tests {
             await!(pool_connector.transform(pk_b.clone()));
         let _tick_sender = await!(tick_sender_receiver.next()).unwrap();
 
+        // The relays are tracked by the Channeler:
         let addresses = vec![0x0u32, 0x1u32, 0x2u32];
+        for address in &addresses {
+            relays_health.add_relay(address.clone());
+        }
         await!(config_client.config(addresses.clone())).unwrap();
 
         // Addresses that we have seen an attempt to connect to:
@@ -567,49 +696,52 @@ mod tests {
             observed_addresses.push(address.clone());
             assert_eq!(pk, &pk_b);
 
-            conn_request.reply(Some((local_sender, local_receiver)));
+            conn_request.reply(Some(KeepAliveConn {
+                conn_pair: (local_sender, local_receiver),
+                stats: KeepAliveStats::new(),
+            }));
             (conn_request_receiver, (remote_sender, remote_receiver))
         };
         let (local_conn, (new_conn_request_receiver, _remote_conn)) =
             await!(connect_fut.join(handle_connect_fut));
         let mut conn_request_receiver = new_conn_request_receiver;
 
+        // The connection is reported to go through the relay we connected to:
+        let (conn_pair, conn_info) = local_conn.unwrap();
+        assert_eq!(
+            conn_info.opt_relay_address.as_ref(),
+            observed_addresses.last()
+        );
+
         // Drop the connection:
-        drop(local_conn);
+        drop(conn_pair);
 
         // Request a new connection:
         let connect_fut = connect_client.connect();
         let handle_connect_fut = async {
+            // The relay that worked before is preferred over relays that were not attempted yet:
             let conn_request = await!(conn_request_receiver.next()).unwrap();
-            let (local_sender, remote_receiver) = mpsc::channel(0);
-            let (remote_sender, local_receiver) = mpsc::channel(0);
-
             let (address, pk) = &conn_request.address;
-            observed_addresses.push(address.clone());
             assert_eq!(pk, &pk_b);
+            assert_eq!(address, &observed_addresses[0]);
 
-            conn_request.reply(Some((local_sender, local_receiver)));
-            (conn_request_receiver, (remote_sender, remote_receiver))
-        };
-        let (local_conn, (new_conn_request_receiver, _remote_conn)) =
-            await!(connect_fut.join(handle_connect_fut));
-        let mut conn_request_receiver = new_conn_request_receiver;
-
-        // Drop the connection:
-        drop(local_conn);
+            // This time the relay fails:
+            conn_request.reply(None);
 
-        // Request a new connection:
-        let connect_fut = connect_client.connect();
-        let handle_connect_fut = async {
+            // Another relay is attempted right away, without waiting for backoff ticks:
             let conn_request = await!(conn_request_receiver.next()).unwrap();
             let (local_sender, remote_receiver) = mpsc::channel(0);
             let (remote_sender, local_receiver) = mpsc::channel(0);
 
             let (address, pk) = &conn_request.address;
-            observed_addresses.push(address.clone());
             assert_eq!(pk, &pk_b);
+            assert_ne!(address, &observed_addresses[0]);
+            observed_addresses.push(address.clone());
 
-            conn_request.reply(Some((local_sender, local_receiver)));
+            conn_request.reply(Some(KeepAliveConn {
+                conn_pair: (local_sender, local_receiver),
+                stats: KeepAliveStats::new(),
+            }));
             (conn_request_receiver, (remote_sender, remote_receiver))
         };
         let (local_conn, (new_conn_request_receiver, _remote_conn)) =
@@ -619,10 +751,6 @@ mod tests {
         // Drop the connection:
         drop(local_conn);
 
-        // There should be exactly 3 observed addresses:
-        let unique_observed = observed_addresses.iter().cloned().collect::<HashSet<_>>();
-        assert_eq!(unique_observed.len(), 3);
-
         // Request a new connection:
         let connect_fut = connect_client.connect();
         let handle_connect_fut = async move {
@@ -630,24 +758,44 @@ mod tests {
             let (local_sender, remote_receiver) = mpsc::channel(0);
             let (remote_sender, local_receiver) = mpsc::channel(0);
 
-            // We expect cyclic attempts.
-            // This time the first observed_address should be attempted again:
+            // The relay that never failed is preferred:
             let (address, pk) = &conn_request.address;
             assert_eq!(pk, &pk_b);
-            assert_eq!(address, &observed_addresses[0]);
-
-            conn_request.reply(Some((local_sender, local_receiver)));
-            (conn_request_receiver, (remote_sender, remote_receiver))
+            assert_eq!(address, &observed_addresses[1]);
+
+            conn_request.reply(Some(KeepAliveConn {
+                conn_pair: (local_sender, local_receiver),
+                stats: KeepAliveStats::new(),
+            }));
+            (
+                conn_request_receiver,
+                observed_addresses,
+                (remote_sender, remote_receiver),
+            )
         };
-        let (_local_conn, (new_conn_request_receiver, _remote_conn)) =
+        let (_local_conn, (_conn_request_receiver, observed_addresses, _remote_conn)) =
             await!(connect_fut.join(handle_connect_fut));
-        let _conn_request_receiver = new_conn_request_receiver;
+
+        // The health of every relay is available for reporting:
+        let relays_health_report = relays_health.report(&addresses);
+        assert_eq!(relays_health_report.len(), addresses.len());
+        for relay_health_report in relays_health_report {
+            let (successes, failures) = if relay_health_report.relay == observed_addresses[0] {
+                (1, 1)
+            } else if relay_health_report.relay == observed_addresses[1] {
+                (2, 0)
+            } else {
+                (0, 0)
+            };
+            assert_eq!(relay_health_report.successes, successes);
+            assert_eq!(relay_health_report.failures, failures);
+        }
     }
 
     #[test]
-    fn test_pool_connector_cyclic_connect() {
+    fn test_pool_connector_prefer_healthy() {
         let mut thread_pool = ThreadPool::new().unwrap();
-        thread_pool.run(task_pool_connector_cyclic_connect(thread_pool.clone()));
+        thread_pool.run(task_pool_connector_prefer_healthy(thread_pool.clone()));
     }
 
     async fn task_pool_connector_backoff_ticks<S>(mut spawner: S)
@@ -678,6 +826,7 @@ mod tests {
         let (config_sender, incoming_config) = mpsc::channel(0);
 
         let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let relays_health = RelaysHealth::new();
 
         // We call connect_pool_loop directly instead of using the wrapper here.
         // This is done because we need the event_sender if we want precise tests for
@@ -691,6 +840,7 @@ mod tests {
             timer_stream,
             encrypt_transform,
             pk_b.clone(), // friend_public_key
+            relays_health.clone(),
             backoff_ticks,
             client_connector,
             spawner.clone(),
@@ -705,6 +855,9 @@ mod tests {
         let mut config_client = CpConfigClient::new(config_sender);
 
         let addresses = vec![0x0u32, 0x1u32, 0x2u32];
+        for address in &addresses {
+            relays_health.add_relay(address.clone());
+        }
         await!(config_client.config(addresses.clone())).unwrap();
         await!(event_receiver.next()).unwrap();
 
@@ -722,15 +875,17 @@ mod tests {
                 observed_addresses.push(address.clone());
                 assert_eq!(pk, &pk_b);
 
-                // Connection attempt failed:
+                // Connection attempt failed. The next address is attempted right away:
                 conn_request.reply(None);
                 await!(event_receiver.next()).unwrap(); // connection attempt done event
+            }
 
-                // Wait backoff_ticks:
-                for _ in 0..backoff_ticks {
-                    await!(tick_sender.send(TimerTick)).unwrap();
-                    await!(event_receiver.next()).unwrap(); // timer tick event
-                }
+            // All the addresses failed. Wait backoff_ticks.
+            // The backoff from failing relays is usually advanced by the Channeler:
+            for _ in 0..backoff_ticks {
+                relays_health.handle_timer_tick();
+                await!(tick_sender.send(TimerTick)).unwrap();
+                await!(event_receiver.next()).unwrap(); // timer tick event
             }
 
             // Finally, we let the connection request succeed:
@@ -745,7 +900,10 @@ mod tests {
             assert_eq!(pk, &pk_b);
             assert_eq!(address, &observed_addresses[0]);
 
-            conn_request.reply(Some((local_sender, local_receiver)));
+            conn_request.reply(Some(KeepAliveConn {
+                conn_pair: (local_sender, local_receiver),
+                stats: KeepAliveStats::new(),
+            }));
             await!(event_receiver.next()).unwrap(); // connection attempt done event
             (conn_request_receiver, (remote_sender, remote_receiver))
         };
@@ -762,4 +920,246 @@ mod tests {
         let mut thread_pool = ThreadPool::new().unwrap();
         thread_pool.run(task_pool_connector_backoff_ticks(thread_pool.clone()));
     }
+
+    async fn task_connect_pool_loop_friend_failure<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        // Create a mock time service:
+        let (mut tick_sender_receiver, mut timer_client) =
+            dummy_timer_multi_sender(spawner.clone());
+
+        let backoff_ticks = 2;
+
+        let (conn_request_sender, mut conn_request_receiver) = mpsc::channel(0);
+        let client_connector = DummyConnector::new(conn_request_sender);
+
+        // The relay works, but the friend can never be reached through it:
+        let encrypt_transform =
+            FuncFutTransform::new(|(_public_key, _conn_pair)| Box::pin(future::ready(None)));
+
+        let timer_stream = await!(timer_client.request_timer_stream()).unwrap();
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+
+        // Used for debugging the loop:
+        let (event_sender, mut event_receiver) = mpsc::channel(0);
+
+        let (request_sender, incoming_requests) = mpsc::channel(0);
+        let (config_sender, incoming_config) = mpsc::channel(0);
+
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let relays_health = RelaysHealth::new();
+
+        let loop_fut = connect_pool_loop(
+            incoming_requests,
+            incoming_config,
+            timer_stream,
+            encrypt_transform,
+            pk_b.clone(), // friend_public_key
+            relays_health.clone(),
+            backoff_ticks,
+            client_connector,
+            spawner.clone(),
+            Some(event_sender),
+        )
+        .map_err(|e| error!("connect_pool_loop() error: {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(loop_fut).unwrap();
+
+        let mut connect_client = CpConnectClient::new(request_sender);
+        let mut config_client = CpConfigClient::new(config_sender);
+
+        let addresses = vec![0x0u32];
+        relays_health.add_relay(0x0u32);
+        await!(config_client.config(addresses.clone())).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        // The connection request never completes, because the friend is unreachable:
+        spawner
+            .spawn(
+                async move {
+                    let _ = await!(connect_client.connect());
+                },
+            )
+            .unwrap();
+        await!(event_receiver.next()).unwrap(); // Connection request event
+
+        for iteration in 0..2 {
+            if iteration > 0 {
+                // We wait backoff_ticks before attempting to reach the friend again.
+                // The backoff of the relay (Advanced by the Channeler) is not involved:
+                for _ in 0..backoff_ticks {
+                    await!(tick_sender.send(TimerTick)).unwrap();
+                    await!(event_receiver.next()).unwrap(); // timer tick event
+                }
+            }
+
+            let conn_request = await!(conn_request_receiver.next()).unwrap();
+            assert_eq!(conn_request.address, (0x0u32, pk_b.clone()));
+
+            let (local_sender, _remote_receiver) = mpsc::channel(0);
+            let (_remote_sender, local_receiver) = mpsc::channel(0);
+            conn_request.reply(Some(KeepAliveConn {
+                conn_pair: (local_sender, local_receiver),
+                stats: KeepAliveStats::new(),
+            }));
+            await!(event_receiver.next()).unwrap(); // connection attempt done event
+
+            // The failure is not held against the relay:
+            assert!(relays_health.is_available(&0x0u32));
+            let relays_health_report = relays_health.report(&addresses);
+            assert_eq!(relays_health_report[0].failures, 0);
+        }
+    }
+
+    #[test]
+    fn test_connect_pool_loop_friend_failure() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_connect_pool_loop_friend_failure(thread_pool.clone()));
+    }
+
+    async fn task_connect_pool_loop_direct_first<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        // Create a mock time service:
+        let (mut tick_sender_receiver, mut timer_client) =
+            dummy_timer_multi_sender(spawner.clone());
+
+        let backoff_ticks = 2;
+
+        let (conn_request_sender, mut conn_request_receiver) = mpsc::channel(0);
+        let client_connector = DummyConnector::new(conn_request_sender);
+
+        // We don't need encryption for this test:
+        let encrypt_transform = FuncFutTransform::new(|(_public_key, conn_pair)| {
+            Box::pin(future::ready(Some(conn_pair)))
+        });
+
+        let timer_stream = await!(timer_client.request_timer_stream()).unwrap();
+        let mut tick_sender = await!(tick_sender_receiver.next()).unwrap();
+
+        // Used for debugging the loop:
+        let (event_sender, mut event_receiver) = mpsc::channel(0);
+
+        let (request_sender, incoming_requests) = mpsc::channel(0);
+        let (config_sender, incoming_config) = mpsc::channel(0);
+
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let pk_relay = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+        let relays_health = RelaysHealth::new();
+
+        let loop_fut = connect_pool_loop(
+            incoming_requests,
+            incoming_config,
+            timer_stream,
+            encrypt_transform,
+            pk_b.clone(), // friend_public_key
+            relays_health.clone(),
+            backoff_ticks,
+            client_connector,
+            spawner.clone(),
+            Some(event_sender),
+        )
+        .map_err(|e| error!("connect_pool_loop() error: {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(loop_fut).unwrap();
+
+        let mut connect_client = CpConnectClient::new(request_sender);
+        let mut config_client = CpConfigClient::new(config_sender);
+
+        let relay_address = RelayAddress {
+            public_key: pk_relay,
+            address: NetAddress::try_from("relay.example.com:1337".to_owned()).unwrap(),
+        };
+        // An address with the direct scheme points directly at the friend:
+        let direct_address = RelayAddress {
+            public_key: pk_b.clone(),
+            address: NetAddress::try_from("direct://friend.example.com:1337".to_owned()).unwrap(),
+        };
+        let addresses = vec![relay_address.clone(), direct_address.clone()];
+        for address in &addresses {
+            relays_health.add_relay(address.clone());
+        }
+        await!(config_client.config(addresses)).unwrap();
+        await!(event_receiver.next()).unwrap();
+
+        let connect_fut = connect_client.connect();
+        let handle_connect_fut = async {
+            await!(event_receiver.next()).unwrap(); // Connection request event
+
+            // The direct address is attempted first:
+            let conn_request = await!(conn_request_receiver.next()).unwrap();
+            assert_eq!(conn_request.address, (direct_address.clone(), pk_b.clone()));
+
+            // Connection attempt failed:
+            conn_request.reply(None);
+            await!(event_receiver.next()).unwrap(); // connection attempt done event
+
+            // We fall back to the relay right away:
+            let conn_request = await!(conn_request_receiver.next()).unwrap();
+            assert_eq!(conn_request.address, (relay_address.clone(), pk_b.clone()));
+
+            let (local_sender, remote_receiver) = mpsc::channel(0);
+            let (remote_sender, local_receiver) = mpsc::channel(0);
+            conn_request.reply(Some(KeepAliveConn {
+                conn_pair: (local_sender, local_receiver),
+                stats: KeepAliveStats::new(),
+            }));
+            await!(event_receiver.next()).unwrap(); // connection attempt done event
+            (
+                conn_request_receiver,
+                event_receiver,
+                (remote_sender, remote_receiver),
+            )
+        };
+        let (local_conn, (conn_request_receiver, event_receiver, _remote_conn)) =
+            await!(connect_fut.join(handle_connect_fut));
+        let mut conn_request_receiver = conn_request_receiver;
+        let mut event_receiver = event_receiver;
+
+        let (conn_pair, conn_info) = local_conn.unwrap();
+        assert_eq!(conn_info.opt_relay_address, Some(relay_address));
+
+        // Drop the connection:
+        drop(conn_pair);
+
+        // Wait until we may attempt the direct address again:
+        for _ in 0..backoff_ticks {
+            relays_health.handle_timer_tick();
+            await!(tick_sender.send(TimerTick)).unwrap();
+            await!(event_receiver.next()).unwrap(); // timer tick event
+        }
+
+        // Request a new connection. The direct address is attempted first again, although the
+        // relay is healthier:
+        let connect_fut = connect_client.connect();
+        let handle_connect_fut = async {
+            await!(event_receiver.next()).unwrap(); // Connection request event
+
+            let conn_request = await!(conn_request_receiver.next()).unwrap();
+            assert_eq!(conn_request.address, (direct_address.clone(), pk_b.clone()));
+
+            let (local_sender, remote_receiver) = mpsc::channel(0);
+            let (remote_sender, local_receiver) = mpsc::channel(0);
+            conn_request.reply(Some(KeepAliveConn {
+                conn_pair: (local_sender, local_receiver),
+                stats: KeepAliveStats::new(),
+            }));
+            await!(event_receiver.next()).unwrap(); // connection attempt done event
+            (remote_sender, remote_receiver)
+        };
+        let (local_conn, _remote_conn) = await!(connect_fut.join(handle_connect_fut));
+
+        let (_conn_pair, conn_info) = local_conn.unwrap();
+        assert_eq!(conn_info.opt_relay_address, Some(direct_address));
+    }
+
+    #[test]
+    fn test_connect_pool_loop_direct_first() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_connect_pool_loop_direct_first(thread_pool.clone()));
+    }
 }
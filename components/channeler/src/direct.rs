@@ -0,0 +1,316 @@
+use std::marker::PhantomData;
+
+use futures::channel::mpsc;
+use futures::task::{Spawn, SpawnExt};
+use futures::{future, stream, FutureExt, SinkExt, Stream, StreamExt, TryFutureExt};
+
+use common::conn::{BoxFuture, ConnPairVec, FutTransform, Listener};
+use common::select_streams::{select_streams, BoxStream};
+use common::transform_pool::transform_pool_loop;
+use crypto::identity::PublicKey;
+use keepalive::KeepAliveConn;
+use proto::funder::messages::{ChannelerUpdateFriend, FunderToChanneler};
+
+use crate::types::{ConnInfo, DirectAddress, FriendConn};
+
+/// Remove our own direct addresses from the addresses we listen on.
+/// Direct connections from friends are received by the `DirectListener`,
+/// so there is no relay to listen on for those addresses.
+pub fn remove_local_direct<RA>(funder_to_channeler: FunderToChanneler<RA>) -> FunderToChanneler<RA>
+where
+    RA: DirectAddress,
+{
+    match funder_to_channeler {
+        FunderToChanneler::SetRelays(addresses) => FunderToChanneler::SetRelays(
+            addresses
+                .into_iter()
+                .filter(|address| !address.is_direct())
+                .collect(),
+        ),
+        FunderToChanneler::UpdateFriend(channeler_update_friend) => {
+            let ChannelerUpdateFriend {
+                friend_public_key,
+                friend_relays,
+                local_relays,
+            } = channeler_update_friend;
+            FunderToChanneler::UpdateFriend(ChannelerUpdateFriend {
+                friend_public_key,
+                friend_relays,
+                local_relays: local_relays
+                    .into_iter()
+                    .filter(|address| !address.is_direct())
+                    .collect(),
+            })
+        }
+        funder_to_channeler => funder_to_channeler,
+    }
+}
+
+/// Connects to a friend using a given address.
+/// Direct addresses of the friend are connected to directly. Other addresses are used as relays.
+#[derive(Clone)]
+pub struct DirectConnector<C, DC, KT> {
+    client_connector: C,
+    direct_connector: DC,
+    keepalive_transform: KT,
+}
+
+impl<C, DC, KT> DirectConnector<C, DC, KT> {
+    pub fn new(client_connector: C, direct_connector: DC, keepalive_transform: KT) -> Self {
+        DirectConnector {
+            client_connector,
+            direct_connector,
+            keepalive_transform,
+        }
+    }
+}
+
+impl<RA, C, DC, KT> FutTransform for DirectConnector<C, DC, KT>
+where
+    RA: DirectAddress + Send + 'static,
+    C: FutTransform<Input = (RA, PublicKey), Output = Option<KeepAliveConn>> + Send,
+    DC: FutTransform<Input = RA, Output = Option<ConnPairVec>> + Send,
+    KT: FutTransform<Input = ConnPairVec, Output = KeepAliveConn> + Send,
+{
+    type Input = (RA, PublicKey);
+    type Output = Option<KeepAliveConn>;
+
+    fn transform(&mut self, input: Self::Input) -> BoxFuture<'_, Self::Output> {
+        let (address, friend_public_key) = input;
+
+        Box::pin(
+            async move {
+                if address.is_direct() {
+                    let conn_pair = await!(self.direct_connector.transform(address))?;
+                    Some(await!(self.keepalive_transform.transform(conn_pair)))
+                } else {
+                    await!(self
+                        .client_connector
+                        .transform((address, friend_public_key)))
+                }
+            },
+        )
+    }
+}
+
+/// Set up an incoming direct connection.
+/// The public key of the remote side is only known after the encryption handshake.
+#[derive(Clone)]
+struct DirectAcceptTransform<RA, ET, KT> {
+    encrypt_transform: ET,
+    keepalive_transform: KT,
+    phantom_ra: PhantomData<RA>,
+}
+
+impl<RA, ET, KT> DirectAcceptTransform<RA, ET, KT> {
+    fn new(encrypt_transform: ET, keepalive_transform: KT) -> Self {
+        DirectAcceptTransform {
+            encrypt_transform,
+            keepalive_transform,
+            phantom_ra: PhantomData,
+        }
+    }
+}
+
+impl<RA, ET, KT> FutTransform for DirectAcceptTransform<RA, ET, KT>
+where
+    RA: Send,
+    ET: FutTransform<
+            Input = (Option<PublicKey>, ConnPairVec),
+            Output = Option<(PublicKey, ConnPairVec)>,
+        > + Send,
+    KT: FutTransform<Input = ConnPairVec, Output = KeepAliveConn> + Send,
+{
+    type Input = ConnPairVec;
+    type Output = Option<(PublicKey, FriendConn<RA>)>;
+
+    fn transform(&mut self, conn_pair: Self::Input) -> BoxFuture<'_, Self::Output> {
+        Box::pin(
+            async move {
+                // Keepalive is applied below the encryption, as done for connections through
+                // relays:
+                let KeepAliveConn { conn_pair, stats } =
+                    await!(self.keepalive_transform.transform(conn_pair));
+                let (public_key, enc_conn) =
+                    await!(self.encrypt_transform.transform((None, conn_pair)))?;
+                let conn_info = ConnInfo {
+                    opt_relay_address: None,
+                    keepalive_stats: stats,
+                };
+                Some((public_key, (enc_conn, conn_info)))
+            },
+        )
+    }
+}
+
+/// A listener that accepts direct connections from friends,
+/// in addition to the connections received through relays by an inner listener.
+///
+/// Any remote side that completes the encryption handshake is passed on.
+/// Connections from remote sides that are not friends are discarded by the Channeler.
+pub struct DirectListener<L, IDC, ET, KT, S> {
+    listener: L,
+    incoming_direct_conns: IDC,
+    encrypt_transform: ET,
+    keepalive_transform: KT,
+    max_concurrent_encrypt: usize,
+    spawner: S,
+}
+
+impl<L, IDC, ET, KT, S> DirectListener<L, IDC, ET, KT, S> {
+    pub fn new(
+        listener: L,
+        incoming_direct_conns: IDC,
+        encrypt_transform: ET,
+        keepalive_transform: KT,
+        max_concurrent_encrypt: usize,
+        spawner: S,
+    ) -> Self {
+        DirectListener {
+            listener,
+            incoming_direct_conns,
+            encrypt_transform,
+            keepalive_transform,
+            max_concurrent_encrypt,
+            spawner,
+        }
+    }
+}
+
+impl<RA, L, IDC, ET, KT, S> Listener for DirectListener<L, IDC, ET, KT, S>
+where
+    RA: Clone + Send + Sync + 'static,
+    L: Listener<Connection = (PublicKey, FriendConn<RA>)>,
+    IDC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
+    ET: FutTransform<
+            Input = (Option<PublicKey>, ConnPairVec),
+            Output = Option<(PublicKey, ConnPairVec)>,
+        > + Clone
+        + Send
+        + 'static,
+    KT: FutTransform<Input = ConnPairVec, Output = KeepAliveConn> + Clone + Send + 'static,
+    S: Spawn + Clone + Send + 'static,
+{
+    type Connection = L::Connection;
+    type Config = L::Config;
+    type Arg = L::Arg;
+
+    fn listen(
+        self,
+        arg: Self::Arg,
+    ) -> (mpsc::Sender<Self::Config>, mpsc::Receiver<Self::Connection>) {
+        let DirectListener {
+            listener,
+            incoming_direct_conns,
+            encrypt_transform,
+            keepalive_transform,
+            max_concurrent_encrypt,
+            mut spawner,
+        } = self;
+
+        let (config_sender, relay_conns) = listener.listen(arg);
+        let (mut outgoing_conns, incoming_conns) = mpsc::channel(0);
+
+        // Set up incoming direct connections:
+        let (direct_conns_sender, direct_conns) = mpsc::channel(0);
+        let accept_transform =
+            DirectAcceptTransform::<RA, _, _>::new(encrypt_transform, keepalive_transform);
+        let accept_loop_fut = transform_pool_loop(
+            incoming_direct_conns,
+            direct_conns_sender,
+            accept_transform,
+            max_concurrent_encrypt,
+            spawner.clone(),
+        )
+        .map_err(|e| error!("transform_pool_loop: {:?}", e))
+        .map(|_| ());
+
+        if spawner.spawn(accept_loop_fut).is_err() {
+            return (config_sender, incoming_conns);
+        }
+
+        // We stop once the inner listener is closed.
+        // Closing of the direct connections stream is not an error: We might not be listening for
+        // direct connections at all.
+        let relay_conns = relay_conns
+            .map(Some)
+            .chain(stream::once(future::ready(None)));
+        let direct_conns = direct_conns.map(Some);
+        let mut conns = select_streams![relay_conns, direct_conns];
+
+        let forward_fut = async move {
+            while let Some(Some(conn)) = await!(conns.next()) {
+                if await!(outgoing_conns.send(conn)).is_err() {
+                    return;
+                }
+            }
+        };
+
+        // If the spawn didn't work, incoming_conns will be closed (because outgoing_conns is
+        // dropped) and the user of this listener will find out about it.
+        let _ = spawner.spawn(forward_fut);
+
+        (config_sender, incoming_conns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    use crypto::identity::PUBLIC_KEY_LEN;
+    use proto::app_server::messages::RelayAddress;
+    use proto::net::messages::NetAddress;
+
+    fn net_address(address: &str) -> NetAddress {
+        NetAddress::try_from(address.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn test_remove_local_direct() {
+        let local_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let friend_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let relay_public_key = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        let relay_address = RelayAddress {
+            public_key: relay_public_key,
+            address: net_address("relay.example.com:1337"),
+        };
+        let local_direct_address = RelayAddress {
+            public_key: local_public_key.clone(),
+            address: net_address("direct://local.example.com:1337"),
+        };
+        let friend_direct_address = RelayAddress {
+            public_key: friend_public_key.clone(),
+            address: net_address("direct://friend.example.com:1337"),
+        };
+
+        let funder_to_channeler =
+            FunderToChanneler::SetRelays(vec![relay_address.clone(), local_direct_address.clone()]);
+        match remove_local_direct(funder_to_channeler) {
+            FunderToChanneler::SetRelays(addresses) => {
+                assert_eq!(addresses, vec![relay_address.clone()])
+            }
+            _ => unreachable!(),
+        };
+
+        let funder_to_channeler = FunderToChanneler::UpdateFriend(ChannelerUpdateFriend {
+            friend_public_key: friend_public_key.clone(),
+            friend_relays: vec![relay_address.clone(), friend_direct_address.clone()],
+            local_relays: vec![local_direct_address, relay_address.clone()],
+        });
+        match remove_local_direct(funder_to_channeler) {
+            FunderToChanneler::UpdateFriend(channeler_update_friend) => {
+                assert_eq!(channeler_update_friend.friend_public_key, friend_public_key);
+                // Direct addresses of the friend are kept:
+                assert_eq!(
+                    channeler_update_friend.friend_relays,
+                    vec![relay_address.clone(), friend_direct_address]
+                );
+                assert_eq!(channeler_update_friend.local_relays, vec![relay_address]);
+            }
+            _ => unreachable!(),
+        };
+    }
+}
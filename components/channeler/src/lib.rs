@@ -19,11 +19,15 @@ extern crate log;
 extern crate common;
 
 mod channeler;
+mod conn_stats;
 mod connect_pool;
 mod connector_utils;
+mod direct;
 mod listen_pool;
 mod listen_pool_state;
 mod overwrite_channel;
+mod punch;
+mod relay_health;
 mod spawn;
 mod types;
 
@@ -8,14 +8,15 @@ use futures::task::{Spawn, SpawnExt};
 use futures::{future, stream, FutureExt, SinkExt, Stream, StreamExt, TryFutureExt};
 
 use common::access_control::AccessControlOp;
-use common::conn::{FutTransform, Listener};
+use common::conn::{FuncFutTransform, FutTransform, Listener};
 use common::select_streams::{select_streams, BoxStream};
 use common::transform_pool::transform_pool_loop;
 
+use keepalive::KeepAliveConn;
 use timer::TimerClient;
 
 use crate::listen_pool_state::{ListenPoolState, Relay};
-use crate::types::{AccessControlOpPk, AccessControlPk, RawConn};
+use crate::types::{AccessControlOpPk, AccessControlPk, ConnInfo, FriendConn, RawConn};
 use crypto::identity::PublicKey;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -72,7 +73,7 @@ enum RelayStatus {
 
 struct ListenPool<RA, L, S> {
     state: ListenPoolState<RA, PublicKey, RelayStatus>,
-    plain_conn_sender: mpsc::Sender<(PublicKey, RawConn)>,
+    plain_conn_sender: mpsc::Sender<(PublicKey, FriendConn<RA>)>,
     relay_closed_sender: mpsc::Sender<RA>,
     listener: L,
     backoff_ticks: usize,
@@ -83,7 +84,7 @@ impl<RA, L, S> ListenPool<RA, L, S>
 where
     RA: Hash + Eq + Clone + Send + Debug + 'static,
     L: Listener<
-            Connection = (PublicKey, RawConn),
+            Connection = (PublicKey, KeepAliveConn),
             Config = AccessControlOpPk,
             Arg = (RA, AccessControlPk),
         > + Clone
@@ -91,7 +92,7 @@ where
     S: Spawn + Clone,
 {
     pub fn new(
-        plain_conn_sender: mpsc::Sender<(PublicKey, RawConn)>,
+        plain_conn_sender: mpsc::Sender<(PublicKey, FriendConn<RA>)>,
         relay_closed_sender: mpsc::Sender<RA>,
         listener: L,
         backoff_ticks: usize,
@@ -119,13 +120,25 @@ where
             access_control.apply_op(AccessControlOp::Add(friend_public_key.clone()));
         }
 
-        let (access_control_sender, mut connections_receiver) = self
+        let (access_control_sender, connections_receiver) = self
             .listener
             .clone()
             .listen((address.clone(), access_control));
         // TODO: Do we need the listener.clone() here? Maybe Listen doesn't need to take ownership
         // over self?
 
+        // Attach the relay address to every incoming connection:
+        let c_address = address.clone();
+        let mut connections_receiver =
+            connections_receiver.map(move |(public_key, keepalive_conn)| {
+                let KeepAliveConn { conn_pair, stats } = keepalive_conn;
+                let conn_info = ConnInfo {
+                    opt_relay_address: Some(c_address.clone()),
+                    keepalive_stats: stats,
+                };
+                (public_key, (conn_pair, conn_info))
+            });
+
         let mut c_plain_conn_sender = self.plain_conn_sender.clone();
         let mut c_relay_closed_sender = self.relay_closed_sender.clone();
         let send_fut = async move {
@@ -248,7 +261,7 @@ where
 
 async fn listen_pool_loop<RA, L, TS, S>(
     incoming_config: mpsc::Receiver<LpConfig<RA>>,
-    outgoing_plain_conns: mpsc::Sender<(PublicKey, RawConn)>,
+    outgoing_plain_conns: mpsc::Sender<(PublicKey, FriendConn<RA>)>,
     listener: L,
     backoff_ticks: usize,
     timer_stream: TS,
@@ -258,7 +271,7 @@ async fn listen_pool_loop<RA, L, TS, S>(
 where
     RA: Clone + Eq + Hash + Send + Debug + 'static,
     L: Listener<
-            Connection = (PublicKey, RawConn),
+            Connection = (PublicKey, KeepAliveConn),
             Config = AccessControlOpPk,
             Arg = (RA, AccessControlPk),
         > + Clone
@@ -341,7 +354,7 @@ impl<RA, L, ET, S> Listener for PoolListener<RA, L, ET, S>
 where
     RA: Clone + Eq + Hash + Send + Sync + Debug + 'static,
     L: Listener<
-            Connection = (PublicKey, RawConn),
+            Connection = (PublicKey, KeepAliveConn),
             Config = AccessControlOpPk,
             Arg = (RA, AccessControlPk),
         > + Clone
@@ -353,7 +366,7 @@ where
         + 'static,
     S: Spawn + Clone + Send + 'static,
 {
-    type Connection = (PublicKey, RawConn);
+    type Connection = (PublicKey, FriendConn<RA>);
     type Config = LpConfig<RA>;
     type Arg = ();
 
@@ -366,7 +379,21 @@ where
 
         let mut c_timer_client = self.timer_client.clone();
         let c_listener = self.listener.clone();
-        let c_encrypt_transform = self.encrypt_transform.clone();
+        let encrypt_transform = self.encrypt_transform.clone();
+        // Encrypt connections, keeping the information about every connection:
+        let c_encrypt_transform = FuncFutTransform::new(
+            move |(public_key, friend_conn): (PublicKey, FriendConn<RA>)| {
+                let (raw_conn, conn_info) = friend_conn;
+                let mut c_encrypt_transform = encrypt_transform.clone();
+                Box::pin(
+                    async move {
+                        let (public_key, enc_conn) =
+                            await!(c_encrypt_transform.transform((public_key, raw_conn)))?;
+                        Some((public_key, (enc_conn, conn_info)))
+                    },
+                )
+            },
+        );
         let c_max_concurrent_encrypt = self.max_concurrent_encrypt;
         let c_backoff_ticks = self.backoff_ticks;
         let mut c_spawner = self.spawner.clone();
@@ -429,6 +456,7 @@ mod tests {
     use crypto::identity::PUBLIC_KEY_LEN;
 
     use common::dummy_listener::DummyListener;
+    use keepalive::KeepAliveStats;
     use timer::{dummy_timer_multi_sender, TimerTick};
 
     async fn task_listen_pool_loop_set_local_addresses<S>(mut spawner: S)
@@ -481,13 +509,19 @@ mod tests {
         for _ in 0..5usize {
             let (_local_sender, remote_receiver) = mpsc::channel(0);
             let (remote_sender, _local_receiver) = mpsc::channel(0);
+            let keepalive_conn = KeepAliveConn {
+                conn_pair: (remote_sender, remote_receiver),
+                stats: KeepAliveStats::new(),
+            };
             await!(listen_req0
                 .conn_sender
-                .send((pk_b.clone(), (remote_sender, remote_receiver))))
+                .send((pk_b.clone(), keepalive_conn)))
             .unwrap();
 
-            let (pk, _conn) = await!(incoming_plain_conns.next()).unwrap();
+            let (pk, (_conn, conn_info)) = await!(incoming_plain_conns.next()).unwrap();
             assert_eq!(pk, pk_b);
+            // The connection should be attributed to the relay it arrived from:
+            assert_eq!(conn_info.opt_relay_address, Some(*relay_address0));
         }
 
         let mut listen_req1 = await!(listen_req_receiver.next()).unwrap();
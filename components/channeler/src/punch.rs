@@ -0,0 +1,310 @@
+use futures::{SinkExt, StreamExt};
+
+use common::conn::{BoxFuture, ConnPairVec, FutTransform};
+use keepalive::KeepAliveConn;
+use timer::utils::future_timeout;
+use timer::TimerClient;
+
+use proto::net::messages::NetAddress;
+use proto::relay::serialize::deserialize_peer_endpoint;
+
+/// Attempts to replace a connection to a friend through a relay with a direct connection.
+///
+/// The first message received through the relay connection is the address of the friend, as
+/// observed by the relay. If an address was received, `punch_connector` is used to open a direct
+/// connection to it (Both sides attempt this at the same time, to punch through their NATs).
+///
+/// When the punching attempt is over (Successfully, or after `punch_timeout_ticks`), both sides
+/// tell each other through the relay connection whether they managed to punch. The direct
+/// connection is used only if both sides managed to punch. Otherwise we keep using the relay
+/// connection. If the result of the remote side does not arrive in time, the connection fails.
+///
+/// Both sides should use the same timeout.
+#[derive(Clone)]
+pub struct PunchTransform<PC, KT> {
+    punch_connector: PC,
+    keepalive_transform: KT,
+    timer_client: TimerClient,
+    punch_timeout_ticks: usize,
+}
+
+impl<PC, KT> PunchTransform<PC, KT> {
+    pub fn new(
+        punch_connector: PC,
+        keepalive_transform: KT,
+        timer_client: TimerClient,
+        punch_timeout_ticks: usize,
+    ) -> Self {
+        PunchTransform {
+            punch_connector,
+            keepalive_transform,
+            timer_client,
+            punch_timeout_ticks,
+        }
+    }
+}
+
+impl<PC, KT> PunchTransform<PC, KT>
+where
+    PC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>> + Send,
+    KT: FutTransform<Input = ConnPairVec, Output = KeepAliveConn> + Send,
+{
+    async fn punch(&mut self, relay_conn: KeepAliveConn) -> Option<KeepAliveConn> {
+        let KeepAliveConn {
+            conn_pair: (sender, mut receiver),
+            stats,
+        } = relay_conn;
+
+        let peer_endpoint = deserialize_peer_endpoint(&await!(receiver.next())?).ok()?;
+        let relay_conn = KeepAliveConn {
+            conn_pair: (sender, receiver),
+            stats,
+        };
+
+        let address = match peer_endpoint.opt_address {
+            Some(address) => address,
+            None => return Some(relay_conn),
+        };
+
+        let punch_timer_stream = await!(self.timer_client.request_timer_stream()).ok()?;
+        let punch_fut = self.punch_connector.transform(address);
+        let opt_direct_conn_pair = match await!(future_timeout(
+            punch_fut,
+            punch_timer_stream,
+            self.punch_timeout_ticks
+        )) {
+            Some(Some(direct_conn_pair)) => Some(direct_conn_pair),
+            _ => None,
+        };
+
+        // The remote side might finish punching up to `punch_timeout_ticks` after us:
+        let confirm_timer_stream = await!(self.timer_client.request_timer_stream()).ok()?;
+        let (relay_conn, remote_punched) = await!(future_timeout(
+            exchange_punch_results(relay_conn, opt_direct_conn_pair.is_some()),
+            confirm_timer_stream,
+            self.punch_timeout_ticks
+        ))??;
+
+        match opt_direct_conn_pair {
+            Some(direct_conn_pair) if remote_punched => {
+                // We don't need the relay connection anymore:
+                drop(relay_conn);
+                Some(await!(self.keepalive_transform.transform(direct_conn_pair)))
+            }
+            _ => Some(relay_conn),
+        }
+    }
+}
+
+/// Tell the remote side whether we managed to punch, and find out whether the remote side managed
+/// to punch.
+async fn exchange_punch_results(
+    relay_conn: KeepAliveConn,
+    punched: bool,
+) -> Option<(KeepAliveConn, bool)> {
+    let KeepAliveConn {
+        conn_pair: (mut sender, mut receiver),
+        stats,
+    } = relay_conn;
+
+    await!(sender.send(vec![punched as u8])).ok()?;
+    let remote_punched = match &await!(receiver.next())?[..] {
+        [0] => false,
+        [1] => true,
+        _ => return None,
+    };
+
+    let relay_conn = KeepAliveConn {
+        conn_pair: (sender, receiver),
+        stats,
+    };
+    Some((relay_conn, remote_punched))
+}
+
+impl<PC, KT> FutTransform for PunchTransform<PC, KT>
+where
+    PC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>> + Send,
+    KT: FutTransform<Input = ConnPairVec, Output = KeepAliveConn> + Send,
+{
+    type Input = KeepAliveConn;
+    type Output = Option<KeepAliveConn>;
+
+    fn transform(&mut self, relay_conn: Self::Input) -> BoxFuture<'_, Self::Output> {
+        Box::pin(self.punch(relay_conn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryInto;
+
+    use futures::channel::mpsc;
+    use futures::executor::ThreadPool;
+    use futures::task::{Spawn, SpawnExt};
+    use futures::{future, SinkExt};
+
+    use common::conn::FuncFutTransform;
+    use common::dummy_connector::DummyConnector;
+    use keepalive::KeepAliveStats;
+    use proto::relay::messages::PeerEndpoint;
+    use proto::relay::serialize::serialize_peer_endpoint;
+    use timer::create_timer_incoming;
+
+    async fn task_punch_transform(mut spawner: impl Spawn + Clone + Send + 'static) {
+        let (mut tick_sender, tick_receiver) = mpsc::channel(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let punch_connector = DummyConnector::new(req_sender);
+        // keepalive_transform does nothing:
+        let keepalive_transform = FuncFutTransform::new(|conn_pair| {
+            Box::pin(future::ready(KeepAliveConn {
+                conn_pair,
+                stats: KeepAliveStats::new(),
+            }))
+        });
+
+        let punch_timeout_ticks = 8;
+        let punch_transform = PunchTransform::new(
+            punch_connector,
+            keepalive_transform,
+            timer_client,
+            punch_timeout_ticks,
+        );
+
+        let address: NetAddress = "1.2.3.4:1337".to_owned().try_into().unwrap();
+
+        // The relay did not tell us the address of the friend. We keep using the relay connection:
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let relay_conn = KeepAliveConn {
+            conn_pair: (local_sender, local_receiver),
+            stats: KeepAliveStats::new(),
+        };
+        let mut c_punch_transform = punch_transform.clone();
+        let fut_conn = spawner
+            .spawn_with_handle(async move { await!(c_punch_transform.transform(relay_conn)) })
+            .unwrap();
+        await!(relay_sender.send(serialize_peer_endpoint(&PeerEndpoint { opt_address: None })))
+            .unwrap();
+        let KeepAliveConn { conn_pair, .. } = await!(fut_conn).unwrap();
+        let (mut sender, _receiver) = conn_pair;
+        await!(sender.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(relay_receiver.next()).unwrap(), vec![1, 2, 3]);
+
+        // Both sides manage to punch. We use the direct connection:
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let relay_conn = KeepAliveConn {
+            conn_pair: (local_sender, local_receiver),
+            stats: KeepAliveStats::new(),
+        };
+        let mut c_punch_transform = punch_transform.clone();
+        let fut_conn = spawner
+            .spawn_with_handle(async move { await!(c_punch_transform.transform(relay_conn)) })
+            .unwrap();
+        let peer_endpoint = PeerEndpoint {
+            opt_address: Some(address.clone()),
+        };
+        await!(relay_sender.send(serialize_peer_endpoint(&peer_endpoint))).unwrap();
+
+        let req = await!(req_receiver.next()).unwrap();
+        assert_eq!(req.address, address);
+        let (local_sender, mut direct_receiver) = mpsc::channel(0);
+        let (_direct_sender, local_receiver) = mpsc::channel(0);
+        req.reply(Some((local_sender, local_receiver)));
+
+        assert_eq!(await!(relay_receiver.next()).unwrap(), vec![1]);
+        await!(relay_sender.send(vec![1])).unwrap();
+
+        let KeepAliveConn { conn_pair, .. } = await!(fut_conn).unwrap();
+        let (mut sender, _receiver) = conn_pair;
+        await!(sender.send(vec![4, 5, 6])).unwrap();
+        assert_eq!(await!(direct_receiver.next()).unwrap(), vec![4, 5, 6]);
+
+        // We manage to punch, but the remote side does not. We keep using the relay connection:
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let relay_conn = KeepAliveConn {
+            conn_pair: (local_sender, local_receiver),
+            stats: KeepAliveStats::new(),
+        };
+        let mut c_punch_transform = punch_transform.clone();
+        let fut_conn = spawner
+            .spawn_with_handle(async move { await!(c_punch_transform.transform(relay_conn)) })
+            .unwrap();
+        await!(relay_sender.send(serialize_peer_endpoint(&peer_endpoint))).unwrap();
+
+        let req = await!(req_receiver.next()).unwrap();
+        let (local_sender, _direct_receiver) = mpsc::channel(0);
+        let (_direct_sender, local_receiver) = mpsc::channel(0);
+        req.reply(Some((local_sender, local_receiver)));
+
+        assert_eq!(await!(relay_receiver.next()).unwrap(), vec![1]);
+        await!(relay_sender.send(vec![0])).unwrap();
+
+        let KeepAliveConn { conn_pair, .. } = await!(fut_conn).unwrap();
+        let (mut sender, _receiver) = conn_pair;
+        await!(sender.send(vec![4, 5, 6])).unwrap();
+        assert_eq!(await!(relay_receiver.next()).unwrap(), vec![4, 5, 6]);
+
+        // Punching times out. We keep using the relay connection:
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let relay_conn = KeepAliveConn {
+            conn_pair: (local_sender, local_receiver),
+            stats: KeepAliveStats::new(),
+        };
+        let mut c_punch_transform = punch_transform.clone();
+        let fut_conn = spawner
+            .spawn_with_handle(async move { await!(c_punch_transform.transform(relay_conn)) })
+            .unwrap();
+        await!(relay_sender.send(serialize_peer_endpoint(&peer_endpoint))).unwrap();
+
+        // Keep the punch request pending:
+        let _req = await!(req_receiver.next()).unwrap();
+        for _ in 0..punch_timeout_ticks {
+            await!(tick_sender.send(())).unwrap();
+        }
+
+        assert_eq!(await!(relay_receiver.next()).unwrap(), vec![0]);
+        await!(relay_sender.send(vec![1])).unwrap();
+
+        let KeepAliveConn { conn_pair, .. } = await!(fut_conn).unwrap();
+        let (mut sender, _receiver) = conn_pair;
+        await!(sender.send(vec![7, 8, 9])).unwrap();
+        assert_eq!(await!(relay_receiver.next()).unwrap(), vec![7, 8, 9]);
+
+        // The result of the remote side never arrives. The connection fails:
+        let (local_sender, mut relay_receiver) = mpsc::channel(0);
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        let relay_conn = KeepAliveConn {
+            conn_pair: (local_sender, local_receiver),
+            stats: KeepAliveStats::new(),
+        };
+        let mut c_punch_transform = punch_transform.clone();
+        let fut_conn = spawner
+            .spawn_with_handle(async move { await!(c_punch_transform.transform(relay_conn)) })
+            .unwrap();
+        await!(relay_sender.send(serialize_peer_endpoint(&peer_endpoint))).unwrap();
+
+        let req = await!(req_receiver.next()).unwrap();
+        let (local_sender, _direct_receiver) = mpsc::channel(0);
+        let (_direct_sender, local_receiver) = mpsc::channel(0);
+        req.reply(Some((local_sender, local_receiver)));
+
+        assert_eq!(await!(relay_receiver.next()).unwrap(), vec![1]);
+        for _ in 0..punch_timeout_ticks {
+            await!(tick_sender.send(())).unwrap();
+        }
+        assert!(await!(fut_conn).is_none());
+    }
+
+    #[test]
+    fn test_punch_transform() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_punch_transform(thread_pool.clone()));
+    }
+}
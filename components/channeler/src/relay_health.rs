@@ -0,0 +1,233 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use proto::report::messages::RelayHealthReport;
+
+use crate::conn_stats::duration_to_millis;
+
+/// The backoff from a failing relay doubles with every consecutive failure,
+/// up to `2^MAX_BACKOFF_SHIFT` times the base backoff.
+const MAX_BACKOFF_SHIFT: u64 = 5;
+
+/// Health of a single relay, as observed by connection attempts through it.
+#[derive(Debug, Clone)]
+struct RelayHealth {
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u64,
+    opt_last_latency: Option<Duration>,
+    /// Remaining ticks until we may attempt this relay again
+    backoff_ticks: usize,
+}
+
+impl RelayHealth {
+    fn new() -> Self {
+        RelayHealth {
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            opt_last_latency: None,
+            backoff_ticks: 0,
+        }
+    }
+
+    fn attempt_success(&mut self, latency: Duration) {
+        self.successes = self.successes.saturating_add(1);
+        self.consecutive_failures = 0;
+        self.opt_last_latency = Some(latency);
+        self.backoff_ticks = 0;
+    }
+
+    fn attempt_failure(&mut self, base_backoff_ticks: usize) {
+        self.failures = self.failures.saturating_add(1);
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let shift = (self.consecutive_failures - 1).min(MAX_BACKOFF_SHIFT);
+        self.backoff_ticks = base_backoff_ticks.saturating_mul(1 << shift);
+    }
+
+    /// Ratio of successful attempts, in thousandths.
+    /// Relays that were never attempted are considered to be half healthy.
+    fn success_permille(&self) -> u64 {
+        let attempts = self.successes.saturating_add(self.failures);
+        self.successes.saturating_add(1).saturating_mul(1000) / attempts.saturating_add(2)
+    }
+}
+
+/// Health of all the relays we use to connect to friends.
+/// Shared by the connect pools of all friends: A relay that fails for one friend is likely to fail
+/// for the others too. The Channeler decides which relays are tracked, advances the backoff
+/// timers and reports the health of the relays of every friend.
+#[derive(Debug, Clone)]
+pub struct RelaysHealth<RA> {
+    // We use a vector (and not a HashMap) to keep the order of reports stable:
+    relays: Arc<Mutex<Vec<(RA, RelayHealth)>>>,
+}
+
+impl<RA> RelaysHealth<RA>
+where
+    RA: Clone + Eq,
+{
+    pub fn new() -> Self {
+        RelaysHealth {
+            relays: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn add_relay(&self, relay: RA) {
+        let mut relays = self.relays.lock().unwrap();
+        if relays.iter().all(|(cur_relay, _)| cur_relay != &relay) {
+            relays.push((relay, RelayHealth::new()));
+        }
+    }
+
+    /// Forget the health of all relays that do not satisfy the predicate `f`.
+    pub fn retain_relays(&self, mut f: impl FnMut(&RA) -> bool) {
+        self.relays
+            .lock()
+            .unwrap()
+            .retain(|(cur_relay, _)| f(cur_relay));
+    }
+
+    fn update_relay(&self, relay: &RA, f: impl FnOnce(&mut RelayHealth)) {
+        let mut relays = self.relays.lock().unwrap();
+        if let Some((_, relay_health)) = relays.iter_mut().find(|(cur_relay, _)| cur_relay == relay)
+        {
+            f(relay_health);
+        }
+    }
+
+    pub fn attempt_success(&self, relay: &RA, latency: Duration) {
+        self.update_relay(relay, |relay_health| relay_health.attempt_success(latency));
+    }
+
+    /// Record a failed connection attempt through a relay.
+    /// Only failures of the relay itself should be recorded here, and not failures to reach the
+    /// friend through the relay (For example, because the friend is offline).
+    /// The relay will not be attempted again for a while, starting from `base_backoff_ticks` and
+    /// growing exponentially with consecutive failures.
+    pub fn attempt_failure(&self, relay: &RA, base_backoff_ticks: usize) {
+        self.update_relay(relay, |relay_health| {
+            relay_health.attempt_failure(base_backoff_ticks)
+        });
+    }
+
+    pub fn handle_timer_tick(&self) {
+        for (_, relay_health) in self.relays.lock().unwrap().iter_mut() {
+            relay_health.backoff_ticks = relay_health.backoff_ticks.saturating_sub(1);
+        }
+    }
+
+    /// Are we allowed to attempt connecting through this relay?
+    pub fn is_available(&self, relay: &RA) -> bool {
+        self.relays
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(cur_relay, _)| cur_relay == relay)
+            .map(|(_, relay_health)| relay_health.backoff_ticks == 0)
+            .unwrap_or(true)
+    }
+
+    /// A score for the health of a relay. Healthier relays have larger scores.
+    /// Relays are compared by their ratio of successful attempts first, and then by the latency
+    /// of the last successful attempt.
+    pub fn score(&self, relay: &RA) -> (u64, u64) {
+        let relays = self.relays.lock().unwrap();
+        let relay_health = match relays.iter().find(|(cur_relay, _)| cur_relay == relay) {
+            Some((_, relay_health)) => relay_health,
+            None => return (RelayHealth::new().success_permille(), u64::max_value()),
+        };
+        let latency_score = match relay_health.opt_last_latency {
+            Some(last_latency) => u64::max_value() - duration_to_millis(last_latency),
+            None => u64::max_value(),
+        };
+        (relay_health.success_permille(), latency_score)
+    }
+
+    /// Report the health of the given relays, in the given order.
+    pub fn report(&self, relays: &[RA]) -> Vec<RelayHealthReport<RA>> {
+        let cur_relays = self.relays.lock().unwrap();
+        relays
+            .iter()
+            .filter_map(|relay| cur_relays.iter().find(|(cur_relay, _)| cur_relay == relay))
+            .map(|(relay, relay_health)| RelayHealthReport {
+                relay: relay.clone(),
+                successes: relay_health.successes,
+                failures: relay_health.failures,
+                consecutive_failures: relay_health.consecutive_failures,
+                opt_last_latency_ms: relay_health.opt_last_latency.map(duration_to_millis),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relays_health_exponential_backoff() {
+        let relays_health = RelaysHealth::new();
+        relays_health.add_relay(0u32);
+        assert!(relays_health.is_available(&0u32));
+
+        let base_backoff_ticks = 2;
+        for num_failures in 1..=8 {
+            relays_health.attempt_failure(&0u32, base_backoff_ticks);
+            let expected_ticks = base_backoff_ticks << (num_failures - 1).min(MAX_BACKOFF_SHIFT);
+            for _ in 0..expected_ticks {
+                assert!(!relays_health.is_available(&0u32));
+                relays_health.handle_timer_tick();
+            }
+            assert!(relays_health.is_available(&0u32));
+        }
+
+        // A successful attempt resets the backoff:
+        relays_health.attempt_success(&0u32, Duration::from_millis(10));
+        relays_health.attempt_failure(&0u32, base_backoff_ticks);
+        for _ in 0..base_backoff_ticks {
+            assert!(!relays_health.is_available(&0u32));
+            relays_health.handle_timer_tick();
+        }
+        assert!(relays_health.is_available(&0u32));
+
+        let report = relays_health.report(&[0u32]);
+        assert_eq!(
+            report,
+            vec![RelayHealthReport {
+                relay: 0u32,
+                successes: 1,
+                failures: 9,
+                consecutive_failures: 1,
+                opt_last_latency_ms: Some(10),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_relays_health_score() {
+        let relays_health = RelaysHealth::new();
+        for relay in 0..4u32 {
+            relays_health.add_relay(relay);
+        }
+
+        relays_health.attempt_failure(&0u32, 1);
+        relays_health.attempt_success(&1u32, Duration::from_millis(500));
+        relays_health.attempt_success(&2u32, Duration::from_millis(50));
+
+        // Successful relays come first, the faster one before the slower one.
+        // Relays that were never attempted come before failing relays:
+        assert!(relays_health.score(&2u32) > relays_health.score(&1u32));
+        assert!(relays_health.score(&1u32) > relays_health.score(&3u32));
+        assert!(relays_health.score(&3u32) > relays_health.score(&0u32));
+
+        // Relays are reported in the requested order. Forgotten relays are not reported:
+        relays_health.retain_relays(|relay| relay != &0u32);
+        let reported_relays = relays_health
+            .report(&[3u32, 0u32, 1u32, 2u32])
+            .into_iter()
+            .map(|relay_health_report| relay_health_report.relay)
+            .collect::<Vec<_>>();
+        assert_eq!(reported_relays, vec![3u32, 1u32, 2u32]);
+    }
+}
@@ -3,18 +3,26 @@ use std::hash::Hash;
 
 use futures::channel::mpsc;
 use futures::task::Spawn;
+use futures::{Stream, StreamExt};
 
 use common::conn::{BoxFuture, ConnPairVec, FutTransform};
+use keepalive::KeepAliveConn;
 use timer::TimerClient;
 
 use crypto::identity::PublicKey;
+use proto::net::messages::NetAddress;
 
 use relay::{ClientConnector, ClientListener};
 
 use crate::channeler::{channeler_loop, ChannelerError};
 use crate::connect_pool::PoolConnector;
+use crate::direct::{remove_local_direct, DirectConnector, DirectListener};
 use crate::listen_pool::PoolListener;
+use crate::punch::PunchTransform;
+use crate::relay_health::RelaysHealth;
+use crate::types::DirectAddress;
 use proto::funder::messages::{ChannelerToFunder, FunderToChanneler};
+use proto::report::messages::ConnectionsReportMutation;
 
 /// A connection style encrypt transform.
 /// Does not return the public key of the remote side, because we already know it.
@@ -96,22 +104,38 @@ pub enum SpawnChannelerError {
 
 // TODO: Possibly rename this function and module, as the channeler future
 // is not spawned here.
-pub async fn spawn_channeler<RA, C, ET, KT, S>(
+/// `direct_connector` is used to connect directly to friends that advertise a direct address.
+/// `incoming_direct_conns` are direct connections from friends (Not through a relay).
+/// If `opt_punch_connector` is provided, we ask relays for the addresses of friends, and attempt
+/// to punch direct connections to them through NATs. `punch_connector` should
+/// connect from the same local address we use for connecting to relays.
+pub async fn spawn_channeler<RA, C, DC, PC, ET, KT, IDC, S>(
     local_public_key: PublicKey,
-    timer_client: TimerClient,
+    mut timer_client: TimerClient,
     backoff_ticks: usize,
     conn_timeout_ticks: usize,
     max_concurrent_encrypt: usize,
+    stats_ticks: usize,
     enc_relay_connector: C,
+    direct_connector: DC,
+    opt_punch_connector: Option<PC>,
     encrypt_transform: ET,
     keepalive_transform: KT,
+    incoming_direct_conns: IDC,
     from_funder: mpsc::Receiver<FunderToChanneler<RA>>,
     to_funder: mpsc::Sender<ChannelerToFunder>,
+    to_reports: mpsc::Sender<ConnectionsReportMutation<RA>>,
     spawner: S,
 ) -> Result<(), ChannelerError>
 where
-    RA: Eq + Hash + Clone + Send + Sync + Debug + 'static,
+    RA: DirectAddress + Eq + Hash + Clone + Send + Sync + Debug + 'static,
     C: FutTransform<Input = RA, Output = Option<ConnPairVec>> + Clone + Send + Sync + 'static,
+    DC: FutTransform<Input = RA, Output = Option<ConnPairVec>> + Clone + Send + Sync + 'static,
+    PC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     ET: FutTransform<
             Input = (Option<PublicKey>, ConnPairVec),
             Output = Option<(PublicKey, ConnPairVec)>,
@@ -119,18 +143,43 @@ where
         + Send
         + Sync
         + 'static,
-    KT: FutTransform<Input = ConnPairVec, Output = ConnPairVec> + Clone + Send + Sync + 'static,
+    KT: FutTransform<Input = ConnPairVec, Output = KeepAliveConn> + Clone + Send + Sync + 'static,
+    IDC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
     S: Spawn + Clone + Send + Sync + 'static,
 {
-    let client_connector =
-        ClientConnector::new(enc_relay_connector.clone(), keepalive_transform.clone());
+    // Punching uses the same timeout on both sides of the connection:
+    let opt_punch_transform = opt_punch_connector.map(|punch_connector| {
+        PunchTransform::new(
+            punch_connector,
+            keepalive_transform.clone(),
+            timer_client.clone(),
+            conn_timeout_ticks,
+        )
+    });
+
+    let client_connector = ClientConnector::new(
+        enc_relay_connector.clone(),
+        keepalive_transform.clone(),
+        opt_punch_transform.clone(),
+    );
+
+    // Friends that advertise a direct address are connected without a relay:
+    let direct_client_connector = DirectConnector::new(
+        client_connector,
+        direct_connector,
+        keepalive_transform.clone(),
+    );
 
     let connect_encrypt_transform = ConnectEncryptTransform::new(encrypt_transform.clone());
 
+    // The health of relays is shared by the connect pools of all friends:
+    let relays_health = RelaysHealth::new();
+
     let pool_connector = PoolConnector::new(
         timer_client.clone(),
-        client_connector.clone(),
+        direct_client_connector,
         connect_encrypt_transform,
+        relays_health.clone(),
         backoff_ticks,
         spawner.clone(),
     );
@@ -138,6 +187,7 @@ where
     let client_listener = ClientListener::new(
         enc_relay_connector,
         keepalive_transform.clone(),
+        opt_punch_transform,
         conn_timeout_ticks,
         timer_client.clone(),
         spawner.clone(),
@@ -154,13 +204,33 @@ where
         spawner.clone(),
     );
 
+    // Accept direct connections from friends, in addition to connections through relays:
+    let direct_listener = DirectListener::new(
+        pool_listener,
+        incoming_direct_conns,
+        encrypt_transform,
+        keepalive_transform,
+        max_concurrent_encrypt,
+        spawner.clone(),
+    );
+
+    // Our own direct addresses are served by the direct listener:
+    let from_funder = from_funder.map(remove_local_direct);
+
+    let timer_stream = await!(timer_client.request_timer_stream())
+        .map_err(|_| ChannelerError::RequestTimerStreamError)?;
+
     // TODO: Maybe use await! instead of spawn_with_handle() here?
     await!(channeler_loop(
         local_public_key,
         from_funder,
         to_funder,
+        to_reports,
         pool_connector,
-        pool_listener,
+        direct_listener,
+        relays_health,
+        timer_stream,
+        stats_ticks,
         spawner.clone()
     ))
 }
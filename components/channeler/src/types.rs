@@ -1,8 +1,37 @@
 use common::access_control::{AccessControl, AccessControlOp};
 use common::conn::ConnPair;
 use crypto::identity::PublicKey;
+use keepalive::KeepAliveStats;
+use proto::app_server::messages::RelayAddress;
+use proto::net::messages::NetAddress;
 
 pub type RawConn = ConnPair<Vec<u8>, Vec<u8>>;
 
 pub type AccessControlPk = AccessControl<PublicKey>;
 pub type AccessControlOpPk = AccessControlOp<PublicKey>;
+
+/// Information about an established connection to a friend.
+/// Used for collecting connection statistics.
+#[derive(Debug, Clone)]
+pub struct ConnInfo<RA> {
+    /// The address used for this connection.
+    /// None for incoming direct connections.
+    pub opt_relay_address: Option<RA>,
+    pub keepalive_stats: KeepAliveStats,
+}
+
+/// An encrypted connection to a friend, together with information about the connection.
+pub type FriendConn<RA> = (RawConn, ConnInfo<RA>);
+
+/// An address that may point directly at a node, instead of at a relay.
+pub trait DirectAddress {
+    /// Does this address point directly at a node?
+    fn is_direct(&self) -> bool;
+}
+
+/// Direct addresses are marked explicitly by their network address (See `NetAddress::is_direct`).
+impl DirectAddress for RelayAddress<NetAddress> {
+    fn is_direct(&self) -> bool {
+        self.address.is_direct()
+    }
+}
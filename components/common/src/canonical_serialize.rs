@@ -51,6 +51,13 @@ impl CanonicalSerialize for String {
     }
 }
 
+// Used mostly for testing:
+impl CanonicalSerialize for u8 {
+    fn canonical_serialize(&self) -> Vec<u8> {
+        vec![*self]
+    }
+}
+
 // Used mostly for testing:
 impl CanonicalSerialize for u32 {
     fn canonical_serialize(&self) -> Vec<u8> {
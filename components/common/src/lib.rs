@@ -33,6 +33,7 @@ pub mod futures_compat;
 pub mod multi_consumer;
 pub mod mutable_state;
 pub mod select_streams;
+pub mod shutdown;
 pub mod state_service;
 pub mod transform_pool;
 // pub mod wait_spawner;
@@ -0,0 +1,94 @@
+use futures::channel::oneshot;
+use futures::{future, stream, FutureExt, Stream, StreamExt};
+use std::marker::Unpin;
+
+use crate::select_streams::{select_streams, BoxStream};
+
+/// Forward items from `stream` until a shutdown is requested through `shutdown_receiver`, or
+/// until `stream` ends.
+/// If the shutdown sender is dropped without requesting a shutdown, the stream is never stopped.
+pub fn until_shutdown<'a, T, S>(
+    stream: S,
+    shutdown_receiver: oneshot::Receiver<()>,
+) -> impl Stream<Item = T> + Unpin + Send + 'a
+where
+    T: Send + 'a,
+    S: Stream<Item = T> + Unpin + Send + 'a,
+{
+    let shutdown_stream = shutdown_receiver
+        .into_stream()
+        .filter_map(|res| future::ready(res.ok().map(|()| None)));
+
+    // The end of `stream` is marked with a None, to stop even if the shutdown sender is alive:
+    let stream = stream.map(Some).chain(stream::once(future::ready(None)));
+
+    let streams: Vec<BoxStream<'a, Option<T>>> = vec![Box::pin(stream), Box::pin(shutdown_stream)];
+    select_streams(streams)
+        .take_while(|opt_item| future::ready(opt_item.is_some()))
+        .map(Option::unwrap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::ThreadPool;
+    use futures::SinkExt;
+
+    async fn task_until_shutdown() {
+        let (mut sender, receiver) = mpsc::channel::<u32>(0);
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let mut stream = until_shutdown(receiver, shutdown_receiver);
+
+        await!(sender.send(1)).unwrap();
+        assert_eq!(await!(stream.next()), Some(1));
+
+        shutdown_sender.send(()).unwrap();
+        assert_eq!(await!(stream.next()), None);
+    }
+
+    #[test]
+    fn test_until_shutdown() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_until_shutdown());
+    }
+
+    async fn task_until_shutdown_canceled() {
+        let (mut sender, receiver) = mpsc::channel::<u32>(0);
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let mut stream = until_shutdown(receiver, shutdown_receiver);
+
+        // Dropping the shutdown sender does not stop the stream:
+        drop(shutdown_sender);
+        await!(sender.send(1)).unwrap();
+        assert_eq!(await!(stream.next()), Some(1));
+
+        drop(sender);
+        assert_eq!(await!(stream.next()), None);
+    }
+
+    #[test]
+    fn test_until_shutdown_canceled() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_until_shutdown_canceled());
+    }
+
+    async fn task_until_shutdown_stream_closed() {
+        let (mut sender, receiver) = mpsc::channel::<u32>(0);
+        let (_shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let mut stream = until_shutdown(receiver, shutdown_receiver);
+
+        await!(sender.send(1)).unwrap();
+        assert_eq!(await!(stream.next()), Some(1));
+
+        // The stream ends when the inner stream ends, although the shutdown sender is alive:
+        drop(sender);
+        assert_eq!(await!(stream.next()), None);
+    }
+
+    #[test]
+    fn test_until_shutdown_stream_closed() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_until_shutdown_stream_closed());
+    }
+}
@@ -25,6 +25,34 @@ pub async fn transform_pool_loop<IN, OUT, I, O, T, S>(
     outgoing: O,
     transform: T,
     max_concurrent: usize,
+    spawner: S,
+) -> Result<(), TransformPoolLoopError>
+where
+    IN: Send + 'static,
+    OUT: Send,
+    T: FutTransform<Input = IN, Output = Option<OUT>> + Clone + Send + 'static,
+    I: Stream<Item = IN> + Unpin,
+    O: Sink<SinkItem = OUT> + Clone + Send + Unpin + 'static,
+    S: Spawn,
+{
+    await!(report_transform_pool_loop(
+        incoming,
+        outgoing,
+        transform,
+        max_concurrent,
+        None,
+        spawner
+    ))
+}
+
+/// Like `transform_pool_loop`, but also sends a notification through `opt_dropped_sender` for
+/// every incoming item that is dropped because `max_concurrent` was exceeded.
+pub async fn report_transform_pool_loop<IN, OUT, I, O, T, S>(
+    incoming: I,
+    outgoing: O,
+    transform: T,
+    max_concurrent: usize,
+    mut opt_dropped_sender: Option<mpsc::Sender<()>>,
     mut spawner: S,
 ) -> Result<(), TransformPoolLoopError>
 where
@@ -53,6 +81,9 @@ where
                 if num_concurrent >= max_concurrent {
                     warn!("transform_pool_loop: Dropping connection: max_concurrent exceeded");
                     // We drop the input value because we don't have any room to process it.
+                    if let Some(dropped_sender) = &mut opt_dropped_sender {
+                        let _ = await!(dropped_sender.send(()));
+                    }
                     continue;
                 }
                 num_concurrent = num_concurrent.checked_add(1).unwrap();
@@ -4,6 +4,7 @@ use ring::hkdf::extract_and_expand;
 use ring::hmac::SigningKey;
 use ring::rand::SecureRandom;
 
+use super::kem::KemSharedSecret;
 use super::sym_encrypt::{SymmetricKey, SYMMETRIC_KEY_LEN};
 use super::CryptoError;
 
@@ -55,6 +56,40 @@ impl DhPrivateKey {
         remote_public_key: DhPublicKey,
         sent_salt: Salt,
         recv_salt: Salt,
+    ) -> Result<(SymmetricKey, SymmetricKey), CryptoError> {
+        self.derive_keys(remote_public_key, sent_salt, recv_salt, &[], &[])
+    }
+
+    /// Derive a symmetric key from our private key and remote's public key, mixed with the
+    /// shared secrets of a post-quantum KEM (Hybrid handshake).
+    /// `sent_kem_secret` is the secret we encapsulated for the remote side, and
+    /// `recv_kem_secret` is the secret the remote side encapsulated for us.
+    pub fn derive_symmetric_key_hybrid(
+        self,
+        remote_public_key: DhPublicKey,
+        sent_salt: Salt,
+        recv_salt: Salt,
+        sent_kem_secret: &KemSharedSecret,
+        recv_kem_secret: &KemSharedSecret,
+    ) -> Result<(SymmetricKey, SymmetricKey), CryptoError> {
+        self.derive_keys(
+            remote_public_key,
+            sent_salt,
+            recv_salt,
+            sent_kem_secret.as_ref(),
+            recv_kem_secret.as_ref(),
+        )
+    }
+
+    /// The key for every direction is derived from the Diffie-Hellman shared secret, followed by
+    /// the extra secret of the sending side and then the extra secret of the receiving side.
+    fn derive_keys(
+        self,
+        remote_public_key: DhPublicKey,
+        sent_salt: Salt,
+        recv_salt: Salt,
+        sent_extra: &[u8],
+        recv_extra: &[u8],
     ) -> Result<(SymmetricKey, SymmetricKey), CryptoError> {
         let u_remote_public_key = untrusted::Input::from(&remote_public_key);
 
@@ -65,10 +100,17 @@ impl DhPrivateKey {
                 let sent_sk = SigningKey::new(&digest::SHA512_256, &sent_salt);
                 let recv_sk = SigningKey::new(&digest::SHA512_256, &recv_salt);
 
+                let mut send_secret = shared_key.to_vec();
+                send_secret.extend_from_slice(sent_extra);
+                send_secret.extend_from_slice(recv_extra);
+                let mut recv_secret = shared_key.to_vec();
+                recv_secret.extend_from_slice(recv_extra);
+                recv_secret.extend_from_slice(sent_extra);
+
                 let mut send_key = [0x00u8; SYMMETRIC_KEY_LEN];
                 let mut recv_key = [0x00u8; SYMMETRIC_KEY_LEN];
-                extract_and_expand(&sent_sk, shared_key, &[], &mut send_key);
-                extract_and_expand(&recv_sk, shared_key, &[], &mut recv_key);
+                extract_and_expand(&sent_sk, &send_secret, &[], &mut send_key);
+                extract_and_expand(&recv_sk, &recv_secret, &[], &mut recv_key);
 
                 Ok((SymmetricKey::from(&send_key), SymmetricKey::from(&recv_key)))
             }
@@ -86,6 +128,7 @@ impl DhPrivateKey {
 
 #[cfg(test)]
 mod tests {
+    use super::super::kem::{kem_encapsulate, KemPrivateKey};
     use super::super::test_utils::DummyRandom;
     use super::*;
 
@@ -124,4 +167,49 @@ mod tests {
         assert_eq!(send_key_a, recv_key_b);
         assert_eq!(send_key_b, recv_key_a)
     }
+
+    #[test]
+    fn test_derive_symmetric_key_hybrid() {
+        let rng = DummyRandom::new(&[1, 2, 3, 4, 5]);
+        let dh_private_a = DhPrivateKey::new(&rng).unwrap();
+        let dh_private_b = DhPrivateKey::new(&rng).unwrap();
+
+        let public_key_a = dh_private_a.compute_public_key().unwrap();
+        let public_key_b = dh_private_b.compute_public_key().unwrap();
+
+        let salt_a = Salt::new(&rng).unwrap();
+        let salt_b = Salt::new(&rng).unwrap();
+
+        // Each side encapsulates a secret for the other side:
+        let (kem_private_a, kem_public_a) = KemPrivateKey::generate(&rng).unwrap();
+        let (kem_private_b, kem_public_b) = KemPrivateKey::generate(&rng).unwrap();
+        let (sent_secret_a, ciphertext_a) = kem_encapsulate(&kem_public_b, &rng).unwrap();
+        let (sent_secret_b, ciphertext_b) = kem_encapsulate(&kem_public_a, &rng).unwrap();
+        let recv_secret_a = kem_private_a.decapsulate(&ciphertext_b).unwrap();
+        let recv_secret_b = kem_private_b.decapsulate(&ciphertext_a).unwrap();
+
+        let (send_key_a, recv_key_a) = dh_private_a
+            .derive_symmetric_key_hybrid(
+                public_key_b,
+                salt_a.clone(),
+                salt_b.clone(),
+                &sent_secret_a,
+                &recv_secret_a,
+            )
+            .unwrap();
+
+        let (send_key_b, recv_key_b) = dh_private_b
+            .derive_symmetric_key_hybrid(
+                public_key_a,
+                salt_b,
+                salt_a,
+                &sent_secret_b,
+                &recv_secret_b,
+            )
+            .unwrap();
+
+        assert_eq!(send_key_a, recv_key_b);
+        assert_eq!(send_key_b, recv_key_a);
+        assert_ne!(send_key_a, send_key_b);
+    }
 }
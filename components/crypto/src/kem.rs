@@ -0,0 +1,182 @@
+use std::convert::{TryFrom, TryInto};
+
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use rand_core::{impls, CryptoRng, RngCore};
+use ring::rand::SecureRandom;
+
+use super::crypto_rand::CryptoRandom;
+use super::CryptoError;
+
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+// Sizes for ML-KEM-768 (FIPS 203):
+pub const KEM_PUBLIC_KEY_LEN: usize = 1184;
+pub const KEM_CIPHERTEXT_LEN: usize = 1088;
+pub const KEM_SHARED_SECRET_LEN: usize = 32;
+
+/// Allows the KEM implementation to draw randomness from a `CryptoRandom`.
+struct KemRng<'a, R>(&'a R);
+
+impl<'a, R: CryptoRandom> RngCore for KemRng<'a, R> {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill(dest).unwrap();
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl<'a, R: CryptoRandom> CryptoRng for KemRng<'a, R> {}
+
+/// Public key of the post-quantum KEM (ML-KEM-768)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KemPublicKey(Vec<u8>);
+
+/// Ciphertext of the post-quantum KEM (ML-KEM-768), encapsulating a shared secret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KemCiphertext(Vec<u8>);
+
+/// A shared secret obtained from the post-quantum KEM
+pub struct KemSharedSecret(Vec<u8>);
+
+impl AsRef<[u8]> for KemPublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for KemCiphertext {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for KemSharedSecret {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for KemPublicKey {
+    type Error = ();
+
+    fn try_from(src: &'a [u8]) -> Result<Self, ()> {
+        if src.len() != KEM_PUBLIC_KEY_LEN {
+            return Err(());
+        }
+        Ok(KemPublicKey(src.to_vec()))
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for KemCiphertext {
+    type Error = ();
+
+    fn try_from(src: &'a [u8]) -> Result<Self, ()> {
+        if src.len() != KEM_CIPHERTEXT_LEN {
+            return Err(());
+        }
+        Ok(KemCiphertext(src.to_vec()))
+    }
+}
+
+/// An ephemeral private key of the post-quantum KEM (ML-KEM-768)
+pub struct KemPrivateKey(DecapsulationKey);
+
+impl KemPrivateKey {
+    /// Generate a new ephemeral key pair.
+    pub fn generate<R: CryptoRandom>(
+        crypt_rng: &R,
+    ) -> Result<(KemPrivateKey, KemPublicKey), CryptoError> {
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut KemRng(crypt_rng));
+        Ok((
+            KemPrivateKey(decapsulation_key),
+            KemPublicKey(encapsulation_key.as_bytes().to_vec()),
+        ))
+    }
+
+    /// Obtain the shared secret encapsulated (by the remote side) inside `ciphertext`.
+    pub fn decapsulate(&self, ciphertext: &KemCiphertext) -> Result<KemSharedSecret, CryptoError> {
+        let ciphertext: Ciphertext<MlKem768> = ciphertext
+            .0
+            .as_slice()
+            .try_into()
+            .map_err(|_| CryptoError)?;
+        let shared_secret = self.0.decapsulate(&ciphertext).map_err(|_| CryptoError)?;
+        Ok(KemSharedSecret(shared_secret.to_vec()))
+    }
+}
+
+/// Create a new shared secret, encapsulated for the owner of `public_key`.
+pub fn kem_encapsulate<R: CryptoRandom>(
+    public_key: &KemPublicKey,
+    crypt_rng: &R,
+) -> Result<(KemSharedSecret, KemCiphertext), CryptoError> {
+    let encoded: Encoded<EncapsulationKey> = public_key
+        .0
+        .as_slice()
+        .try_into()
+        .map_err(|_| CryptoError)?;
+    let encapsulation_key = EncapsulationKey::from_bytes(&encoded);
+    let (ciphertext, shared_secret) = encapsulation_key
+        .encapsulate(&mut KemRng(crypt_rng))
+        .map_err(|_| CryptoError)?;
+    Ok((
+        KemSharedSecret(shared_secret.to_vec()),
+        KemCiphertext(ciphertext.to_vec()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::DummyRandom;
+
+    #[test]
+    fn test_kem_encapsulate_decapsulate() {
+        let rng = DummyRandom::new(&[1u8]);
+        let (private_key, public_key) = KemPrivateKey::generate(&rng).unwrap();
+        assert_eq!(public_key.as_ref().len(), KEM_PUBLIC_KEY_LEN);
+
+        let (shared_secret1, ciphertext) = kem_encapsulate(&public_key, &rng).unwrap();
+        assert_eq!(ciphertext.as_ref().len(), KEM_CIPHERTEXT_LEN);
+        assert_eq!(shared_secret1.as_ref().len(), KEM_SHARED_SECRET_LEN);
+
+        let shared_secret2 = private_key.decapsulate(&ciphertext).unwrap();
+        assert_eq!(shared_secret1.as_ref(), shared_secret2.as_ref());
+
+        // A different private key obtains a different secret:
+        let (other_private_key, _) = KemPrivateKey::generate(&rng).unwrap();
+        let shared_secret3 = other_private_key.decapsulate(&ciphertext).unwrap();
+        assert_ne!(shared_secret1.as_ref(), shared_secret3.as_ref());
+    }
+
+    #[test]
+    fn test_kem_invalid_lengths() {
+        assert!(KemPublicKey::try_from(&[0u8; KEM_PUBLIC_KEY_LEN - 1][..]).is_err());
+        assert!(KemCiphertext::try_from(&[0u8; KEM_CIPHERTEXT_LEN + 1][..]).is_err());
+    }
+
+    #[test]
+    fn test_kem_deterministic_rng() {
+        // Key generation and encapsulation only use the provided random generator:
+        let (_, public_key1) = KemPrivateKey::generate(&DummyRandom::new(&[2u8])).unwrap();
+        let (_, public_key2) = KemPrivateKey::generate(&DummyRandom::new(&[2u8])).unwrap();
+        assert_eq!(public_key1, public_key2);
+
+        let (_, ciphertext1) = kem_encapsulate(&public_key1, &DummyRandom::new(&[3u8])).unwrap();
+        let (_, ciphertext2) = kem_encapsulate(&public_key1, &DummyRandom::new(&[3u8])).unwrap();
+        assert_eq!(ciphertext1, ciphertext2);
+    }
+}
@@ -17,7 +17,9 @@ pub mod dh;
 pub mod hash;
 pub mod identity;
 pub mod invoice_id;
+pub mod kem;
 pub mod nonce_window;
+pub mod passphrase;
 pub mod sym_encrypt;
 pub mod test_utils;
 pub mod uid;
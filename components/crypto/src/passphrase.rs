@@ -0,0 +1,182 @@
+use scrypt::{scrypt, ScryptParams};
+
+use crate::crypto_rand::CryptoRandom;
+use crate::sym_encrypt::{Decryptor, Encryptor, SymmetricKey, SYMMETRIC_KEY_LEN};
+
+use super::CryptoError;
+
+pub const PASSPHRASE_SALT_LEN: usize = 16;
+/// Maximum amount of memory (In bytes) a single key derivation may use.
+pub const MAX_KDF_MEMORY: u64 = 1 << 30;
+/// Maximum parallelization parameter for key derivation.
+pub const MAX_KDF_P: u32 = 16;
+
+define_fixed_bytes!(PassphraseSalt, PASSPHRASE_SALT_LEN);
+
+impl PassphraseSalt {
+    pub fn new<R: CryptoRandom>(crypt_rng: &R) -> Self {
+        let mut salt = PassphraseSalt([0; PASSPHRASE_SALT_LEN]);
+        crypt_rng.fill(&mut salt.0).unwrap();
+        salt
+    }
+}
+
+/// Parameters of the scrypt key derivation function.
+/// Deriving a key costs about `128 * r * 2^log_n` bytes of memory, `p` times.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    /// Parameters recommended for interactive use
+    fn default() -> Self {
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Check that deriving a key takes at most `MAX_KDF_MEMORY` bytes of memory, and that the
+    /// parallelization parameter is at most `MAX_KDF_P`.
+    /// Should be checked for parameters that come from an untrusted source.
+    pub fn is_bounded(&self) -> bool {
+        if self.log_n == 0 || self.log_n >= 32 || self.r == 0 || self.p == 0 {
+            return false;
+        }
+        let opt_memory = (128 * u64::from(self.r)).checked_mul(1u64 << self.log_n);
+        match opt_memory {
+            Some(memory) => memory <= MAX_KDF_MEMORY && self.p <= MAX_KDF_P,
+            None => false,
+        }
+    }
+}
+
+/// Derive a symmetric key from a passphrase, using scrypt.
+pub fn derive_symmetric_key(
+    passphrase: &[u8],
+    salt: &PassphraseSalt,
+    kdf_params: &KdfParams,
+) -> Result<SymmetricKey, CryptoError> {
+    let scrypt_params =
+        ScryptParams::new(kdf_params.log_n, kdf_params.r, kdf_params.p).map_err(|_| CryptoError)?;
+    let mut key = [0u8; SYMMETRIC_KEY_LEN];
+    scrypt(passphrase, salt, &scrypt_params, &mut key).map_err(|_| CryptoError)?;
+    Ok(SymmetricKey::from(&key))
+}
+
+/// Encrypt a message using a key derived from a passphrase.
+/// A new salt must be used for every encrypted message, as the nonce counter of the encryptor
+/// always starts from zero.
+pub fn passphrase_encrypt(
+    passphrase: &[u8],
+    salt: &PassphraseSalt,
+    kdf_params: &KdfParams,
+    plain_msg: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let symmetric_key = derive_symmetric_key(passphrase, salt, kdf_params)?;
+    Encryptor::new(&symmetric_key)?.encrypt(plain_msg)
+}
+
+/// Decrypt and authenticate a message encrypted with `passphrase_encrypt`.
+/// Fails if the passphrase is wrong.
+pub fn passphrase_decrypt(
+    passphrase: &[u8],
+    salt: &PassphraseSalt,
+    kdf_params: &KdfParams,
+    cipher_msg: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let symmetric_key = derive_symmetric_key(passphrase, salt, kdf_params)?;
+    let mut decryptor = Decryptor::new(&symmetric_key)?;
+    decryptor.decrypt(cipher_msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::DummyRandom;
+
+    /// Cheap parameters, to keep the tests fast:
+    fn test_kdf_params() -> KdfParams {
+        KdfParams {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        }
+    }
+
+    #[test]
+    fn test_passphrase_encrypt_decrypt() {
+        let rng = DummyRandom::new(&[1u8]);
+        let salt = PassphraseSalt::new(&rng);
+        let kdf_params = test_kdf_params();
+
+        let plain_msg = b"Hello world!";
+        let cipher_msg = passphrase_encrypt(b"passphrase", &salt, &kdf_params, plain_msg).unwrap();
+        assert_ne!(&cipher_msg[..], &plain_msg[..]);
+
+        let decrypted = passphrase_decrypt(b"passphrase", &salt, &kdf_params, &cipher_msg).unwrap();
+        assert_eq!(&decrypted[..], &plain_msg[..]);
+
+        // Wrong passphrase:
+        assert!(passphrase_decrypt(b"wrong", &salt, &kdf_params, &cipher_msg).is_err());
+
+        // Wrong salt:
+        let salt2 = PassphraseSalt::new(&rng);
+        assert!(passphrase_decrypt(b"passphrase", &salt2, &kdf_params, &cipher_msg).is_err());
+    }
+
+    #[test]
+    fn test_kdf_params_is_bounded() {
+        assert!(KdfParams::default().is_bounded());
+        assert!(test_kdf_params().is_bounded());
+
+        // 1GB of memory:
+        let kdf_params = KdfParams {
+            log_n: 20,
+            r: 8,
+            p: 1,
+        };
+        assert!(kdf_params.is_bounded());
+
+        let kdf_params = KdfParams {
+            log_n: 21,
+            r: 8,
+            p: 1,
+        };
+        assert!(!kdf_params.is_bounded());
+
+        let kdf_params = KdfParams {
+            log_n: 255,
+            r: 8,
+            p: 1,
+        };
+        assert!(!kdf_params.is_bounded());
+
+        let kdf_params = KdfParams {
+            log_n: 4,
+            r: u32::max_value(),
+            p: 1,
+        };
+        assert!(!kdf_params.is_bounded());
+
+        let kdf_params = KdfParams {
+            log_n: 4,
+            r: 8,
+            p: MAX_KDF_P + 1,
+        };
+        assert!(!kdf_params.is_bounded());
+
+        let kdf_params = KdfParams {
+            log_n: 4,
+            r: 0,
+            p: 1,
+        };
+        assert!(!kdf_params.is_bounded());
+    }
+}
@@ -103,6 +103,9 @@ impl Decryptor {
 
     /// Decrypt and authenticate a message.
     pub fn decrypt(&mut self, cipher_msg: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if cipher_msg.len() < ENC_NONCE_LEN {
+            return Err(CryptoError);
+        }
         let enc_nonce = &cipher_msg[..ENC_NONCE_LEN];
         if enc_nonce != self.nonce_counter.as_ref() {
             // Nonce doesn't match!
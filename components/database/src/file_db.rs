@@ -78,6 +78,39 @@ where
 
         Ok(FileDb { path_buf, state })
     }
+
+    /// Load an existing database from file, possibly stored in an older format.
+    /// If the file can not be read as the current state, it is read as the older state `OS`
+    /// and converted. The converted state is then written back to the file, atomically.
+    pub fn load_or_migrate<OS>(path_buf: PathBuf) -> Result<Self, FileDbError<S::MutateError>>
+    where
+        OS: DeserializeOwned + Into<S>,
+    {
+        let mut f = File::open(&path_buf).map_err(FileDbError::OpenError)?;
+        // read the whole file
+        let mut serialized_buff = Vec::new();
+        f.read_to_end(&mut serialized_buff)
+            .map_err(FileDbError::ReadError)?;
+
+        let deser_err = match bincode::deserialize::<S>(&serialized_buff) {
+            Ok(state) => return Ok(FileDb { path_buf, state }),
+            Err(e) => e,
+        };
+
+        let old_state: OS = match bincode::deserialize(&serialized_buff) {
+            Ok(old_state) => old_state,
+            Err(_) => return Err(FileDbError::DeserializeError(deser_err)),
+        };
+        let state: S = old_state.into();
+
+        // Save the migrated state to file, atomically:
+        let serialized_buff = bincode::serialize(&state).map_err(FileDbError::SerializeError)?;
+        let af = atomicwrites::AtomicFile::new(&path_buf, atomicwrites::AllowOverwrite);
+        af.write(|fw| fw.write_all(&serialized_buff))
+            .map_err(FileDbError::WriteError)?;
+
+        Ok(FileDb { path_buf, state })
+    }
 }
 
 impl<S> AtomicDb for FileDb<S>
@@ -203,4 +236,49 @@ mod tests {
         // Remove temporary directory:
         dir.close().unwrap();
     }
+
+    /// An older version of DummyState
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    struct DummyStateV0 {
+        pub x: u16,
+    }
+
+    impl From<DummyStateV0> for DummyState {
+        fn from(old_state: DummyStateV0) -> Self {
+            DummyState::new(u32::from(old_state.x))
+        }
+    }
+
+    #[test]
+    fn test_file_db_migrate() {
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("database_file");
+
+        // Write a database file in the old format:
+        let serialized_buff = bincode::serialize(&DummyStateV0 { x: 5 }).unwrap();
+        let af = atomicwrites::AtomicFile::new(&file_path, atomicwrites::AllowOverwrite);
+        af.write(|fw| fw.write_all(&serialized_buff)).unwrap();
+
+        // The old format can not be loaded directly:
+        assert!(FileDb::<DummyState>::load(file_path.clone()).is_err());
+
+        let mut file_db =
+            FileDb::<DummyState>::load_or_migrate::<DummyStateV0>(file_path.clone()).unwrap();
+        assert_eq!(file_db.get_state().x, 5);
+        file_db.mutate_db(&[DummyMutation::Inc]).unwrap();
+        drop(file_db);
+
+        // The file was rewritten in the new format:
+        let file_db = FileDb::<DummyState>::load(file_path.clone()).unwrap();
+        assert_eq!(file_db.get_state().x, 6);
+
+        // Migrating a database that is already in the new format does nothing:
+        let file_db =
+            FileDb::<DummyState>::load_or_migrate::<DummyStateV0>(file_path.clone()).unwrap();
+        assert_eq!(file_db.get_state().x, 6);
+
+        // Remove temporary directory:
+        dir.close().unwrap();
+    }
 }
@@ -16,10 +16,12 @@ use crypto::uid::Uid;
 use database::DatabaseClient;
 
 use proto::index_client::messages::{
-    AppServerToIndexClient, ClientResponseRoutes, IndexClientReportMutation,
-    IndexClientReportMutations, IndexClientRequest, IndexClientToAppServer, IndexMutation,
-    RequestRoutes, ResponseRoutesResult,
+    AppServerToIndexClient, ClientResponseReachability, ClientResponseRoutes,
+    IndexClientReportMutation, IndexClientReportMutations, IndexClientRequest,
+    IndexClientToAppServer, IndexMutation, RequestReachability, RequestRoutes,
+    ResponseReachabilityResult, ResponseRoutesResult, RoutesSignature,
 };
+use proto::index_server::capacity_bucket::bucket_mutation;
 use proto::index_server::messages::{IndexServerAddress, NamedIndexServerAddress};
 
 use crate::client_session::{ControlSender, SessionHandle};
@@ -29,12 +31,16 @@ use crate::single_client::SingleClientControl;
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct IndexClientConfig<ISA> {
     pub index_servers: Vec<NamedIndexServerAddress<ISA>>,
+    /// Privacy mode: If set, capacities reported to the index servers are rounded down to a
+    /// multiple of this value, so that the exact balances with our friends are not revealed.
+    pub opt_capacity_bucket: Option<u128>,
 }
 
 impl<ISA> IndexClientConfig<ISA> {
     pub fn new() -> Self {
         IndexClientConfig {
             index_servers: Vec::new(),
+            opt_capacity_bucket: None,
         }
     }
 }
@@ -43,6 +49,7 @@ impl<ISA> IndexClientConfig<ISA> {
 pub enum IndexClientConfigMutation<ISA> {
     AddIndexServer(NamedIndexServerAddress<ISA>),
     RemoveIndexServer(PublicKey),
+    SetCapacityBucket(Option<u128>),
 }
 
 impl<ISA> MutableState for IndexClientConfig<ISA>
@@ -66,6 +73,9 @@ where
                 self.index_servers
                     .retain(|named_index_server| &named_index_server.public_key != public_key);
             }
+            IndexClientConfigMutation::SetCapacityBucket(opt_capacity_bucket) => {
+                self.opt_capacity_bucket = *opt_capacity_bucket;
+            }
         };
         Ok(())
     }
@@ -113,7 +123,8 @@ enum IndexClientEvent<ISA> {
     AppServerClosed,
     IndexServerConnected(ControlSender),
     IndexServerClosed,
-    ResponseRoutes((Uid, ResponseRoutesResult)),
+    ResponseRoutes(ClientResponseRoutes),
+    ResponseReachability(ClientResponseReachability),
     TimerTick,
 }
 
@@ -129,6 +140,8 @@ struct IndexClient<ISA, TAS, ICS, S> {
     index_client_session: ICS,
     max_open_requests: usize,
     num_open_requests: usize,
+    /// Capacities reported to the index servers are rounded down to a multiple of this value
+    opt_capacity_bucket: Option<u128>,
     keepalive_ticks: usize,
     backoff_ticks: usize,
     conn_status: ConnStatus<ISA>,
@@ -141,6 +154,7 @@ struct IndexClient<ISA, TAS, ICS, S> {
 async fn send_full_state(
     mut seq_friends_client: SeqFriendsClient,
     mut control_sender: ControlSender,
+    opt_capacity_bucket: Option<u128>,
 ) -> Result<(), IndexClientError> {
     await!(seq_friends_client.reset_countdown()).map_err(|_| IndexClientError::SeqFriendsError)?;
 
@@ -155,7 +169,10 @@ async fn send_full_state(
 
         // TODO: Maybe send mutations in batches in the future:
         // However, we need to be careful to not send too many mutations in one batch.
-        let mutations = vec![IndexMutation::UpdateFriend(update_friend)];
+        let mutations = vec![bucket_mutation(
+            IndexMutation::UpdateFriend(update_friend),
+            opt_capacity_bucket,
+        )];
         if await!(control_sender.send(SingleClientControl::SendMutations(mutations))).is_err() {
             break;
         }
@@ -199,6 +216,7 @@ where
                 address: named_index_server.address,
             })
             .collect::<VecDeque<_>>();
+        let opt_capacity_bucket = index_client_config.opt_capacity_bucket;
 
         IndexClient {
             event_sender,
@@ -208,6 +226,7 @@ where
             index_client_session,
             max_open_requests,
             num_open_requests: 0,
+            opt_capacity_bucket,
             keepalive_ticks,
             backoff_ticks,
             conn_status: ConnStatus::Empty(backoff_ticks),
@@ -247,6 +266,7 @@ where
         self.conn_status = ConnStatus::Connecting(server_connecting);
 
         let c_seq_friends_client = self.seq_friends_client.clone();
+        let c_opt_capacity_bucket = self.opt_capacity_bucket;
         let mut c_spawner = self.spawner.clone();
 
         // Canceller for the send_full_state() task:
@@ -262,11 +282,15 @@ where
                 let c_control_sender = control_sender.clone();
                 let send_full_state_cancellable_fut = async move {
                     let send_full_state_fut = Box::pin(
-                        send_full_state(c_seq_friends_client, c_control_sender)
-                            .map_err(|e| warn!("Error in send_full_state(): {:?}", e))
-                            .map(|_| {
-                                let _ = sfs_done_sender.send(());
-                            }),
+                        send_full_state(
+                            c_seq_friends_client,
+                            c_control_sender,
+                            c_opt_capacity_bucket,
+                        )
+                        .map_err(|e| warn!("Error in send_full_state(): {:?}", e))
+                        .map(|_| {
+                            let _ = sfs_done_sender.send(());
+                        }),
                     );
 
                     select! {
@@ -315,6 +339,7 @@ where
         let client_response_routes = ClientResponseRoutes {
             request_id,
             result: ResponseRoutesResult::Failure,
+            opt_routes_signature: None,
         };
         await!(self
             .to_app_server
@@ -324,6 +349,22 @@ where
         .map_err(|_| IndexClientError::SendToAppServerFailed)
     }
 
+    pub async fn return_response_reachability_failure(
+        &mut self,
+        request_id: Uid,
+    ) -> Result<(), IndexClientError> {
+        let client_response_reachability = ClientResponseReachability {
+            request_id,
+            result: ResponseReachabilityResult::Failure,
+        };
+        await!(self
+            .to_app_server
+            .send(IndexClientToAppServer::ResponseReachability(
+                client_response_reachability
+            )))
+        .map_err(|_| IndexClientError::SendToAppServerFailed)
+    }
+
     pub async fn handle_from_app_server_add_index_server(
         &mut self,
         app_request_id: Uid,
@@ -420,6 +461,56 @@ where
         Ok(())
     }
 
+    pub async fn handle_from_app_server_set_capacity_bucket(
+        &mut self,
+        app_request_id: Uid,
+        opt_capacity_bucket: Option<u128>,
+    ) -> Result<(), IndexClientError> {
+        // Update database:
+        await!(self
+            .db_client
+            .mutate(vec![IndexClientConfigMutation::SetCapacityBucket(
+                opt_capacity_bucket
+            )]))
+        .map_err(|_| IndexClientError::DatabaseError)?;
+
+        let old_opt_capacity_bucket = self.opt_capacity_bucket;
+        self.opt_capacity_bucket = opt_capacity_bucket;
+
+        // Send empty report (Indicates that we received the request):
+        let index_client_report_mutations = IndexClientReportMutations {
+            opt_app_request_id: Some(app_request_id),
+            mutations: Vec::new(),
+        };
+        await!(self
+            .to_app_server
+            .send(IndexClientToAppServer::ReportMutations(
+                index_client_report_mutations
+            )))
+        .map_err(|_| IndexClientError::SendToAppServerFailed)?;
+
+        if old_opt_capacity_bucket == opt_capacity_bucket {
+            return Ok(());
+        }
+
+        // The index server still holds capacities reported using the old bucket.
+        // We disconnect, so that our full state is sent again using the new bucket when we
+        // reconnect:
+        match &mut self.conn_status {
+            ConnStatus::Empty(_) => {} // Nothing to do here
+            ConnStatus::Connecting(server_connecting) => {
+                if let Some(cancel_sender) = server_connecting.opt_cancel_sender.take() {
+                    let _ = cancel_sender.send(());
+                }
+            }
+            ConnStatus::Connected(server_connected) => {
+                server_connected.opt_control_sender.take();
+                server_connected.opt_cancel_sender.take();
+            }
+        }
+        Ok(())
+    }
+
     pub async fn handle_from_app_server_request_routes(
         &mut self,
         app_request_id: Uid,
@@ -455,6 +546,8 @@ where
         };
 
         let c_request_id = request_routes.request_id;
+        let c_request_routes = request_routes.clone();
+        let server_public_key = server_connected.index_server.public_key.clone();
         let (response_sender, response_receiver) = oneshot::channel();
         let single_client_control =
             SingleClientControl::RequestRoutes((request_routes, response_sender));
@@ -466,15 +559,107 @@ where
 
         let mut c_event_sender = self.event_sender.clone();
         let request_fut = async move {
-            let response_routes_result = match await!(response_receiver) {
-                Ok(routes) => ResponseRoutesResult::Success(routes),
-                Err(_) => ResponseRoutesResult::Failure,
+            let (result, opt_routes_signature) = match await!(response_receiver) {
+                // Older index servers do not sign their responses:
+                Ok(response_routes) => match response_routes.opt_signature.clone() {
+                    None => (ResponseRoutesResult::Success(response_routes.routes), None),
+                    Some(response_routes_signature) => {
+                        if response_routes.verify_signature(&c_request_routes, &server_public_key) {
+                            let routes_signature = RoutesSignature {
+                                server_public_key,
+                                time_hash: response_routes_signature.time_hash,
+                                signature: response_routes_signature.signature,
+                            };
+                            (
+                                ResponseRoutesResult::Success(response_routes.routes),
+                                Some(routes_signature),
+                            )
+                        } else {
+                            warn!(
+                                "Invalid signature for routes response from index server {:?}",
+                                server_public_key
+                            );
+                            (ResponseRoutesResult::Failure, None)
+                        }
+                    }
+                },
+                Err(_) => (ResponseRoutesResult::Failure, None),
+            };
+            let client_response_routes = ClientResponseRoutes {
+                request_id: c_request_id,
+                result,
+                opt_routes_signature,
             };
             // TODO: Should report error here if failure occurs?
-            let _ = await!(c_event_sender.send(IndexClientEvent::ResponseRoutes((
-                c_request_id,
-                response_routes_result
-            ))));
+            let _ = await!(c_event_sender.send(IndexClientEvent::ResponseRoutes(
+                client_response_routes
+            )));
+        };
+
+        self.num_open_requests = self.num_open_requests.saturating_add(1);
+        self.spawner
+            .spawn(request_fut)
+            .map_err(|_| IndexClientError::SpawnError)
+    }
+
+    pub async fn handle_from_app_server_request_reachability(
+        &mut self,
+        app_request_id: Uid,
+        request_reachability: RequestReachability,
+    ) -> Result<(), IndexClientError> {
+        // Send empty report (Indicates that we received the request):
+        let index_client_report_mutations = IndexClientReportMutations {
+            opt_app_request_id: Some(app_request_id),
+            mutations: Vec::new(),
+        };
+        await!(self
+            .to_app_server
+            .send(IndexClientToAppServer::ReportMutations(
+                index_client_report_mutations
+            )))
+        .map_err(|_| IndexClientError::SendToAppServerFailed)?;
+
+        let request_id = request_reachability.request_id;
+
+        // Reachability requests share the open requests limit with routes requests:
+        if self.num_open_requests >= self.max_open_requests {
+            return await!(self.return_response_reachability_failure(request_id));
+        }
+
+        // Check server connection status:
+        let server_connected = match &mut self.conn_status {
+            ConnStatus::Empty(_) | ConnStatus::Connecting(_) => {
+                return await!(self.return_response_reachability_failure(request_id))
+            }
+            ConnStatus::Connected(server_connected) => server_connected,
+        };
+
+        let mut control_sender = match server_connected.opt_control_sender.take() {
+            Some(control_sender) => control_sender,
+            None => return await!(self.return_response_reachability_failure(request_id)),
+        };
+
+        let (response_sender, response_receiver) = oneshot::channel();
+        let single_client_control =
+            SingleClientControl::RequestReachability((request_reachability, response_sender));
+
+        match await!(control_sender.send(single_client_control)) {
+            Ok(()) => server_connected.opt_control_sender = Some(control_sender),
+            Err(_) => return await!(self.return_response_reachability_failure(request_id)),
+        };
+
+        let mut c_event_sender = self.event_sender.clone();
+        let request_fut = async move {
+            let result = match await!(response_receiver) {
+                Ok(response_reachability) => {
+                    ResponseReachabilityResult::Success(response_reachability.nodes)
+                }
+                Err(_) => ResponseReachabilityResult::Failure,
+            };
+            let client_response_reachability = ClientResponseReachability { request_id, result };
+            let _ = await!(c_event_sender.send(IndexClientEvent::ResponseReachability(
+                client_response_reachability
+            )));
         };
 
         self.num_open_requests = self.num_open_requests.saturating_add(1);
@@ -514,6 +699,11 @@ where
         if let Some((_cycle_countdown, update_friend)) = next_update_res {
             mutations.push(IndexMutation::UpdateFriend(update_friend));
         }
+        let opt_capacity_bucket = self.opt_capacity_bucket;
+        let mutations = mutations
+            .into_iter()
+            .map(|mutation| bucket_mutation(mutation, opt_capacity_bucket))
+            .collect();
 
         if let Ok(()) = await!(control_sender.send(SingleClientControl::SendMutations(mutations))) {
             server_connected.opt_control_sender = Some(control_sender);
@@ -541,6 +731,16 @@ where
                         await!(self
                             .handle_from_app_server_request_routes(app_request_id, request_routes))
                     }
+                    IndexClientRequest::SetCapacityBucket(opt_capacity_bucket) => await!(self
+                        .handle_from_app_server_set_capacity_bucket(
+                            app_request_id,
+                            opt_capacity_bucket
+                        )),
+                    IndexClientRequest::RequestReachability(request_reachability) => await!(self
+                        .handle_from_app_server_request_reachability(
+                            app_request_id,
+                            request_reachability
+                        )),
                 }
             }
             AppServerToIndexClient::ApplyMutations(mutations) => {
@@ -613,16 +813,10 @@ where
 
     pub async fn handle_response_routes(
         &mut self,
-        request_id: Uid,
-        response_routes_result: ResponseRoutesResult,
+        client_response_routes: ClientResponseRoutes,
     ) -> Result<(), IndexClientError> {
         self.num_open_requests = self.num_open_requests.checked_sub(1).unwrap();
 
-        let client_response_routes = ClientResponseRoutes {
-            request_id,
-            result: response_routes_result,
-        };
-
         await!(self
             .to_app_server
             .send(IndexClientToAppServer::ResponseRoutes(
@@ -631,6 +825,20 @@ where
         .map_err(|_| IndexClientError::SendToAppServerFailed)
     }
 
+    pub async fn handle_response_reachability(
+        &mut self,
+        client_response_reachability: ClientResponseReachability,
+    ) -> Result<(), IndexClientError> {
+        self.num_open_requests = self.num_open_requests.checked_sub(1).unwrap();
+
+        await!(self
+            .to_app_server
+            .send(IndexClientToAppServer::ResponseReachability(
+                client_response_reachability
+            )))
+        .map_err(|_| IndexClientError::SendToAppServerFailed)
+    }
+
     pub async fn handle_timer_tick(&mut self) -> Result<(), IndexClientError> {
         // Make sure that we are connected to any server:
         let server_connected: &mut ServerConnected<ISA> = match self.conn_status {
@@ -674,7 +882,10 @@ where
             .map_err(|_| IndexClientError::SeqFriendsError)?;
 
         if let Some((_cycle_countdown, update_friend)) = next_update_res {
-            mutations.push(IndexMutation::UpdateFriend(update_friend));
+            mutations.push(bucket_mutation(
+                IndexMutation::UpdateFriend(update_friend),
+                self.opt_capacity_bucket,
+            ));
         }
 
         if let Ok(()) = await!(control_sender.send(SingleClientControl::SendMutations(mutations))) {
@@ -750,8 +961,11 @@ where
             IndexClientEvent::IndexServerClosed => {
                 await!(index_client.handle_index_server_closed())?
             }
-            IndexClientEvent::ResponseRoutes((request_id, response_routes_result)) => {
-                await!(index_client.handle_response_routes(request_id, response_routes_result))?
+            IndexClientEvent::ResponseRoutes(client_response_routes) => {
+                await!(index_client.handle_response_routes(client_response_routes))?
+            }
+            IndexClientEvent::ResponseReachability(client_response_reachability) => {
+                await!(index_client.handle_response_reachability(client_response_reachability))?
             }
             IndexClientEvent::TimerTick => await!(index_client.handle_timer_tick())?,
         };
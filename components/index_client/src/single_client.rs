@@ -14,15 +14,16 @@ use crypto::uid::Uid;
 use identity::IdentityClient;
 
 use proto::index_server::messages::{
-    IndexClientToServer, IndexMutation, IndexServerToClient, MutationsUpdate, RequestRoutes,
-    ResponseRoutes, RouteWithCapacity,
+    IndexClientToServer, IndexMutation, IndexServerToClient, MutationsUpdate, RequestReachability,
+    RequestRoutes, ResponseReachability, ResponseRoutes,
 };
 
 pub type ServerConn = ConnPair<IndexClientToServer, IndexServerToClient>;
 
 #[derive(Debug)]
 pub enum SingleClientControl {
-    RequestRoutes((RequestRoutes, oneshot::Sender<Vec<RouteWithCapacity>>)),
+    RequestRoutes((RequestRoutes, oneshot::Sender<ResponseRoutes>)),
+    RequestReachability((RequestReachability, oneshot::Sender<ResponseReachability>)),
     SendMutations(Vec<IndexMutation>),
 }
 
@@ -54,7 +55,9 @@ struct SingleClient<TS, R> {
     /// We use this value to prove that our signatures are recent
     server_time_hash: HashResult,
     /// Unanswered requests, waiting for a response from the server
-    open_requests: HashMap<Uid, oneshot::Sender<Vec<RouteWithCapacity>>>,
+    open_requests: HashMap<Uid, oneshot::Sender<ResponseRoutes>>,
+    /// Unanswered reachability requests, waiting for a response from the server
+    open_reachability_requests: HashMap<Uid, oneshot::Sender<ResponseReachability>>,
 }
 
 impl<TS, R> SingleClient<TS, R>
@@ -79,6 +82,7 @@ where
             counter: 0,
             server_time_hash,
             open_requests: HashMap::new(),
+            open_reachability_requests: HashMap::new(),
         }
     }
 
@@ -90,7 +94,7 @@ where
         match index_server_to_client {
             IndexServerToClient::TimeHash(time_hash) => self.server_time_hash = time_hash,
             IndexServerToClient::ResponseRoutes(response_routes) => {
-                let ResponseRoutes { request_id, routes } = response_routes;
+                let request_id = response_routes.request_id;
                 let request_sender = match self.open_requests.remove(&request_id) {
                     Some(request_sender) => request_sender,
                     None => {
@@ -101,13 +105,37 @@ where
                         return Ok(());
                     }
                 };
-                if request_sender.send(routes).is_err() {
+                // Note that the signature of the server is verified by the IndexClient:
+                if request_sender.send(response_routes).is_err() {
                     warn!(
                         "Failed to return response for request_id: {:?} ",
                         &request_id
                     );
                 }
             }
+            IndexServerToClient::GraphSnapshot(_) | IndexServerToClient::GraphUpdate(_) => {
+                // We never subscribe to graph changes:
+                warn!("Received unexpected graph changes from server");
+            }
+            IndexServerToClient::ResponseReachability(response_reachability) => {
+                let request_id = response_reachability.request_id;
+                let request_sender = match self.open_reachability_requests.remove(&request_id) {
+                    Some(request_sender) => request_sender,
+                    None => {
+                        warn!(
+                            "Received a reachability response for unrecognized request_id: {:?}",
+                            &request_id
+                        );
+                        return Ok(());
+                    }
+                };
+                if request_sender.send(response_reachability).is_err() {
+                    warn!(
+                        "Failed to return reachability response for request_id: {:?} ",
+                        &request_id
+                    );
+                }
+            }
         }
         Ok(())
     }
@@ -128,6 +156,15 @@ where
                 await!(self.to_server.send(to_server_message))
                     .map_err(|_| SingleClientError::SendToServerError)?;
             }
+            SingleClientControl::RequestReachability((request_reachability, response_sender)) => {
+                self.open_reachability_requests
+                    .insert(request_reachability.request_id, response_sender);
+
+                let to_server_message =
+                    IndexClientToServer::RequestReachability(request_reachability);
+                await!(self.to_server.send(to_server_message))
+                    .map_err(|_| SingleClientError::SendToServerError)?;
+            }
             SingleClientControl::SendMutations(index_mutations) => {
                 let mut mutations_update = MutationsUpdate {
                     node_public_key: self.local_public_key.clone(),
@@ -246,6 +283,8 @@ mod tests {
 
     use identity::create_identity;
 
+    use proto::index_server::messages::NodeWithCapacity;
+
     async fn task_first_server_time_hash() {
         let (mut to_server, mut from_server) = mpsc::channel(0);
         let time_hash = HashResult::from(&[1; HASH_RESULT_LEN]);
@@ -348,14 +387,53 @@ mod tests {
         let response_routes = ResponseRoutes {
             request_id: Uid::from(&[3; UID_LEN]),
             routes: vec![], // No suitable routes were found
+            opt_signature: None,
         };
 
         await!(server_sender.send(IndexServerToClient::ResponseRoutes(response_routes.clone())))
             .unwrap();
 
         // Client receives response routes:
-        let routes = await!(response_receiver).unwrap();
-        assert_eq!(routes, vec![]);
+        let received_response_routes = await!(response_receiver).unwrap();
+        assert_eq!(received_response_routes.request_id, Uid::from(&[3; UID_LEN]));
+        assert_eq!(received_response_routes.routes, vec![]);
+
+        // Request reachability:
+        let request_reachability = RequestReachability {
+            request_id: Uid::from(&[4; UID_LEN]),
+            capacity: 20,
+            destination: PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]),
+            opt_max_nodes: Some(5),
+        };
+
+        let (response_sender, response_receiver) = oneshot::channel();
+        let single_client_control = SingleClientControl::RequestReachability((
+            request_reachability.clone(),
+            response_sender,
+        ));
+        await!(control_sender.send(single_client_control)).unwrap();
+
+        match await!(server_receiver.next()).unwrap() {
+            IndexClientToServer::RequestReachability(sent_request_reachability) => {
+                assert_eq!(request_reachability, sent_request_reachability);
+            }
+            _ => unreachable!(),
+        };
+
+        let response_reachability = ResponseReachability {
+            request_id: Uid::from(&[4; UID_LEN]),
+            nodes: vec![NodeWithCapacity {
+                public_key: PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+                capacity: 30,
+            }],
+        };
+        await!(server_sender.send(IndexServerToClient::ResponseReachability(
+            response_reachability.clone()
+        )))
+        .unwrap();
+
+        let received_response_reachability = await!(response_receiver).unwrap();
+        assert_eq!(received_response_reachability, response_reachability);
 
         for iter in 0..3 {
             // Counter should increment every time
@@ -5,13 +5,22 @@ use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt};
 
 use common::dummy_connector::{ConnRequest, DummyConnector};
 
-use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+use crypto::hash::{HashResult, HASH_RESULT_LEN};
+use crypto::identity::{
+    generate_pkcs8_key_pair, Identity, PublicKey, SoftwareEd25519Identity, PUBLIC_KEY_LEN,
+};
+use crypto::test_utils::DummyRandom;
 use crypto::uid::{Uid, UID_LEN};
 use proto::index_client::messages::{
     AppServerToIndexClient, IndexClientReportMutation, IndexClientRequest, IndexClientToAppServer,
-    IndexMutation, RequestRoutes, ResponseRoutesResult, UpdateFriend,
+    IndexMutation, NodeWithCapacity, RequestReachability, RequestRoutes,
+    ResponseReachabilityResult, ResponseRoutesResult, UpdateFriend,
+};
+use proto::index_server::messages::{
+    IndexServerAddress, NamedIndexServerAddress, ResponseReachability, ResponseRoutes,
+    ResponseRoutesSignature, RouteWithCapacity,
 };
-use proto::index_server::messages::{IndexServerAddress, NamedIndexServerAddress};
+use proto::index_server::signature_buff::response_routes_signature_buff;
 
 use database::{DatabaseClient, DatabaseRequest};
 
@@ -20,6 +29,34 @@ use crate::index_client::{index_client_loop, IndexClientConfig, IndexClientConfi
 use crate::seq_friends::{SeqFriendsClient, SeqFriendsRequest};
 use crate::single_client::{SingleClientControl, SingleClientError};
 
+/// Identity of the index server the IndexClient connects to
+fn index_server_identity() -> SoftwareEd25519Identity {
+    let rng = DummyRandom::new(&[0x37]);
+    let pkcs8 = generate_pkcs8_key_pair(&rng);
+    SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap()
+}
+
+fn index_server_public_key() -> PublicKey {
+    index_server_identity().get_public_key()
+}
+
+/// Create a routes response, signed by the index server
+fn signed_response_routes(
+    request_routes: &RequestRoutes,
+    routes: Vec<RouteWithCapacity>,
+) -> ResponseRoutes {
+    let time_hash = HashResult::from(&[0x11; HASH_RESULT_LEN]);
+    let signature_buff = response_routes_signature_buff(request_routes, &routes, &time_hash);
+    ResponseRoutes {
+        request_id: request_routes.request_id,
+        routes,
+        opt_signature: Some(ResponseRoutesSignature {
+            time_hash,
+            signature: index_server_identity().sign(&signature_buff),
+        }),
+    }
+}
+
 /// A test util struct
 /// Holds sender/receiver interface for an IndexClient.
 struct IndexClientControl<ISA> {
@@ -37,7 +74,10 @@ struct IndexClientControl<ISA> {
 }
 
 /// Create a basic IndexClientControl, used for testing
-fn basic_index_client<S>(mut spawner: S) -> IndexClientControl<u32>
+fn basic_index_client<S>(
+    mut spawner: S,
+    opt_capacity_bucket: Option<u128>,
+) -> IndexClientControl<u32>
 where
     S: Spawn + Clone + Send + 'static,
 {
@@ -45,12 +85,13 @@ where
     let (to_app_server, app_server_receiver) = mpsc::channel(0);
 
     let index_server37 = NamedIndexServerAddress {
-        public_key: PublicKey::from(&[0x37; PUBLIC_KEY_LEN]),
+        public_key: index_server_public_key(),
         address: 0x1337u32,
         name: "0x1337".to_owned(),
     };
     let index_client_config = IndexClientConfig {
         index_servers: vec![index_server37],
+        opt_capacity_bucket,
     };
 
     let (seq_friends_sender, seq_friends_receiver) = mpsc::channel(0);
@@ -244,10 +285,10 @@ async fn task_index_client_loop_add_remove_index_server<S>(spawner: S)
 where
     S: Spawn + Clone + Send + 'static,
 {
-    let mut icc = basic_index_client(spawner.clone());
+    let mut icc = basic_index_client(spawner.clone(), None);
 
     let index_server = IndexServerAddress {
-        public_key: PublicKey::from(&[0x37; PUBLIC_KEY_LEN]),
+        public_key: index_server_public_key(),
         address: 0x1337,
     };
     let (mut control_receiver, close_sender) = await!(icc.expect_server_connection(index_server));
@@ -264,7 +305,7 @@ where
     }));
     await!(icc.remove_index_server(PublicKey::from(&[0x38; PUBLIC_KEY_LEN])));
     // Remove index server in use (0x1337):
-    await!(icc.remove_index_server(PublicKey::from(&[0x37; PUBLIC_KEY_LEN])));
+    await!(icc.remove_index_server(index_server_public_key()));
 
     // We expect that control_receiver will be closed eventually:
     while let Some(_control_message) = await!(control_receiver.next()) {}
@@ -309,9 +350,9 @@ async fn task_index_client_loop_apply_mutations<S>(spawner: S)
 where
     S: Spawn + Clone + Send + 'static,
 {
-    let mut icc = basic_index_client(spawner.clone());
+    let mut icc = basic_index_client(spawner.clone(), None);
     let index_server = IndexServerAddress {
-        public_key: PublicKey::from(&[0x37; PUBLIC_KEY_LEN]),
+        public_key: index_server_public_key(),
         address: 0x1337,
     };
     let (mut control_receiver, _close_sender) = await!(icc.expect_server_connection(index_server));
@@ -370,13 +411,182 @@ fn test_index_client_loop_apply_mutations() {
     thread_pool.run(task_index_client_loop_apply_mutations(thread_pool.clone()));
 }
 
-async fn task_index_client_loop_request_routes_basic<S>(spawner: S)
+async fn task_index_client_loop_apply_mutations_capacity_bucket<S>(spawner: S)
 where
     S: Spawn + Clone + Send + 'static,
 {
-    let mut icc = basic_index_client(spawner.clone());
+    let mut icc = basic_index_client(spawner.clone(), Some(50));
     let index_server = IndexServerAddress {
-        public_key: PublicKey::from(&[0x37; PUBLIC_KEY_LEN]),
+        public_key: index_server_public_key(),
+        address: 0x1337,
+    };
+    let (mut control_receiver, _close_sender) = await!(icc.expect_server_connection(index_server));
+
+    let update_friend = UpdateFriend {
+        public_key: PublicKey::from(PublicKey::from(&[0xbb; PUBLIC_KEY_LEN])),
+        send_capacity: 230,
+        recv_capacity: 120,
+    };
+    let index_mutation = IndexMutation::UpdateFriend(update_friend);
+    let mutations = vec![index_mutation.clone()];
+    await!(icc
+        .app_server_sender
+        .send(AppServerToIndexClient::ApplyMutations(mutations)))
+    .unwrap();
+
+    // seq_friends keeps the exact capacities:
+    match await!(icc.seq_friends_receiver.next()).unwrap() {
+        SeqFriendsRequest::Mutate(index_mutation0, response_sender) => {
+            assert_eq!(index_mutation0, index_mutation);
+            response_sender.send(()).unwrap();
+        }
+        _ => unreachable!(),
+    };
+
+    match await!(icc.seq_friends_receiver.next()).unwrap() {
+        SeqFriendsRequest::NextUpdate(response_sender) => {
+            let next_update_friend = UpdateFriend {
+                public_key: PublicKey::from(PublicKey::from(&[0xcc; PUBLIC_KEY_LEN])),
+                send_capacity: 20,
+                recv_capacity: 80,
+            };
+            response_sender.send(Some((0, next_update_friend))).unwrap();
+        }
+        _ => unreachable!(),
+    };
+
+    // The index server only gets rounded down capacities:
+    match await!(control_receiver.next()).unwrap() {
+        SingleClientControl::SendMutations(mutations0) => {
+            let expected_mutations = vec![
+                IndexMutation::UpdateFriend(UpdateFriend {
+                    public_key: PublicKey::from(PublicKey::from(&[0xbb; PUBLIC_KEY_LEN])),
+                    send_capacity: 200,
+                    recv_capacity: 100,
+                }),
+                IndexMutation::UpdateFriend(UpdateFriend {
+                    public_key: PublicKey::from(PublicKey::from(&[0xcc; PUBLIC_KEY_LEN])),
+                    send_capacity: 0,
+                    recv_capacity: 50,
+                }),
+            ];
+            assert_eq!(mutations0, expected_mutations);
+        }
+        _ => unreachable!(),
+    };
+}
+
+#[test]
+fn test_index_client_loop_apply_mutations_capacity_bucket() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_index_client_loop_apply_mutations_capacity_bucket(
+        thread_pool.clone(),
+    ));
+}
+
+async fn task_index_client_loop_set_capacity_bucket<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let mut icc = basic_index_client(spawner.clone(), None);
+    let index_server = IndexServerAddress {
+        public_key: index_server_public_key(),
+        address: 0x1337,
+    };
+    let (mut control_receiver, close_sender) =
+        await!(icc.expect_server_connection(index_server.clone()));
+
+    // Turn on privacy mode (From AppServer):
+    let app_server_to_index_client = AppServerToIndexClient::AppRequest((
+        Uid::from(&[54; UID_LEN]),
+        IndexClientRequest::SetCapacityBucket(Some(40)),
+    ));
+    await!(icc.app_server_sender.send(app_server_to_index_client)).unwrap();
+
+    let db_request = await!(icc.database_req_receiver.next()).unwrap();
+    assert_eq!(
+        db_request.mutations,
+        vec![IndexClientConfigMutation::SetCapacityBucket(Some(40))]
+    );
+    db_request.response_sender.send(()).unwrap();
+
+    // Expect empty report mutations:
+    match await!(icc.app_server_receiver.next()).unwrap() {
+        IndexClientToAppServer::ReportMutations(ic_report_mutations) => {
+            assert_eq!(
+                ic_report_mutations.opt_app_request_id,
+                Some(Uid::from(&[54; UID_LEN]))
+            );
+            assert!(ic_report_mutations.mutations.is_empty());
+        }
+        _ => unreachable!(),
+    };
+
+    // The current connection is closed, so that the full state will be sent again:
+    while let Some(_control_message) = await!(control_receiver.next()) {}
+    let _ = close_sender.send(Ok(()));
+    await!(icc.expect_set_connected_server(None));
+
+    for _ in 0..icc.backoff_ticks {
+        await!(icc.tick_sender.send(())).unwrap();
+    }
+
+    let session_conn_request = await!(icc.session_receiver.next()).unwrap();
+    assert_eq!(session_conn_request.address, index_server);
+    let (control_sender, mut control_receiver) = mpsc::channel(0);
+    let (_close_sender, close_receiver) = oneshot::channel();
+    session_conn_request.reply(Some((control_sender, close_receiver)));
+    await!(icc.expect_set_connected_server(Some(index_server_public_key())));
+
+    match await!(icc.seq_friends_receiver.next()).unwrap() {
+        SeqFriendsRequest::ResetCountdown(response_sender) => {
+            response_sender.send(()).unwrap();
+        }
+        _ => unreachable!(),
+    };
+    match await!(icc.seq_friends_receiver.next()).unwrap() {
+        SeqFriendsRequest::NextUpdate(response_sender) => {
+            let update_friend = UpdateFriend {
+                public_key: PublicKey::from(PublicKey::from(&[0xaa; PUBLIC_KEY_LEN])),
+                send_capacity: 100,
+                recv_capacity: 50,
+            };
+            response_sender.send(Some((0, update_friend))).unwrap();
+        }
+        _ => unreachable!(),
+    };
+
+    // The full state is sent again, using the new bucket:
+    match await!(control_receiver.next()).unwrap() {
+        SingleClientControl::SendMutations(mutations0) => {
+            let expected_mutations = vec![IndexMutation::UpdateFriend(UpdateFriend {
+                public_key: PublicKey::from(PublicKey::from(&[0xaa; PUBLIC_KEY_LEN])),
+                send_capacity: 80,
+                recv_capacity: 40,
+            })];
+            assert_eq!(mutations0, expected_mutations);
+        }
+        _ => unreachable!(),
+    };
+}
+
+#[test]
+fn test_index_client_loop_set_capacity_bucket() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_index_client_loop_set_capacity_bucket(
+        thread_pool.clone(),
+    ));
+}
+
+/// Request routes through the IndexClient.
+/// If `signed` is false, the index server does not sign its response (Like older index servers).
+async fn task_index_client_loop_request_routes_basic<S>(spawner: S, signed: bool)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let mut icc = basic_index_client(spawner.clone(), None);
+    let index_server = IndexServerAddress {
+        public_key: index_server_public_key(),
         address: 0x1337,
     };
     let (mut control_receiver, _close_sender) = await!(icc.expect_server_connection(index_server));
@@ -401,7 +611,11 @@ where
         SingleClientControl::RequestRoutes((request_routes0, response_sender)) => {
             assert_eq!(request_routes0, request_routes);
             // Server returns: no routes found:
-            response_sender.send(vec![]).unwrap();
+            let mut response_routes = signed_response_routes(&request_routes, vec![]);
+            if !signed {
+                response_routes.opt_signature = None;
+            }
+            response_sender.send(response_routes).unwrap();
         }
         _ => unreachable!(),
     };
@@ -427,6 +641,17 @@ where
                 _ => unreachable!(),
             };
             assert!(routes.is_empty());
+            if signed {
+                // The signature of the index server is forwarded to the AppServer:
+                let routes_signature = client_response_routes.opt_routes_signature.unwrap();
+                assert_eq!(
+                    routes_signature.server_public_key,
+                    index_server_public_key()
+                );
+                assert!(routes_signature.verify(&request_routes, &routes));
+            } else {
+                assert_eq!(client_response_routes.opt_routes_signature, None);
+            }
         }
         _ => unreachable!(),
     };
@@ -437,6 +662,165 @@ fn test_index_client_loop_request_routes_basic() {
     let mut thread_pool = ThreadPool::new().unwrap();
     thread_pool.run(task_index_client_loop_request_routes_basic(
         thread_pool.clone(),
+        true,
+    ));
+}
+
+#[test]
+fn test_index_client_loop_request_routes_unsigned() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_index_client_loop_request_routes_basic(
+        thread_pool.clone(),
+        false,
+    ));
+}
+
+async fn task_index_client_loop_request_reachability<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let mut icc = basic_index_client(spawner.clone(), None);
+    let index_server = IndexServerAddress {
+        public_key: index_server_public_key(),
+        address: 0x1337,
+    };
+    let (mut control_receiver, _close_sender) = await!(icc.expect_server_connection(index_server));
+
+    let request_reachability = RequestReachability {
+        request_id: Uid::from(&[3; UID_LEN]),
+        capacity: 250,
+        destination: PublicKey::from(&[0xff; PUBLIC_KEY_LEN]),
+        opt_max_nodes: Some(2),
+    };
+
+    // Request reachability from IndexClient (From AppServer):
+    let app_server_to_index_client = AppServerToIndexClient::AppRequest((
+        Uid::from(&[50; UID_LEN]),
+        IndexClientRequest::RequestReachability(request_reachability.clone()),
+    ));
+    await!(icc.app_server_sender.send(app_server_to_index_client)).unwrap();
+
+    let nodes = vec![
+        NodeWithCapacity {
+            public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            capacity: 400,
+        },
+        NodeWithCapacity {
+            public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            capacity: 300,
+        },
+    ];
+
+    // IndexClient forwards the reachability request to the server:
+    match await!(control_receiver.next()).unwrap() {
+        SingleClientControl::RequestReachability((request_reachability0, response_sender)) => {
+            assert_eq!(request_reachability0, request_reachability);
+            let response_reachability = ResponseReachability {
+                request_id: request_reachability.request_id,
+                nodes: nodes.clone(),
+            };
+            response_sender.send(response_reachability).unwrap();
+        }
+        _ => unreachable!(),
+    };
+
+    // Expect empty report mutations:
+    match await!(icc.app_server_receiver.next()).unwrap() {
+        IndexClientToAppServer::ReportMutations(ic_report_mutations) => {
+            assert_eq!(
+                ic_report_mutations.opt_app_request_id,
+                Some(Uid::from(&[50; UID_LEN]))
+            );
+            assert!(ic_report_mutations.mutations.is_empty());
+        }
+        _ => unreachable!(),
+    };
+
+    // IndexClient returns the result back to AppServer:
+    match await!(icc.app_server_receiver.next()).unwrap() {
+        IndexClientToAppServer::ResponseReachability(client_response_reachability) => {
+            assert_eq!(
+                client_response_reachability.request_id,
+                Uid::from(&[3; UID_LEN])
+            );
+            assert_eq!(
+                client_response_reachability.result,
+                ResponseReachabilityResult::Success(nodes)
+            );
+        }
+        _ => unreachable!(),
+    };
+}
+
+#[test]
+fn test_index_client_loop_request_reachability() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_index_client_loop_request_reachability(
+        thread_pool.clone(),
+    ));
+}
+
+async fn task_index_client_loop_request_routes_invalid_signature<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let mut icc = basic_index_client(spawner.clone(), None);
+    let index_server = IndexServerAddress {
+        public_key: index_server_public_key(),
+        address: 0x1337,
+    };
+    let (mut control_receiver, _close_sender) = await!(icc.expect_server_connection(index_server));
+
+    let request_routes = RequestRoutes {
+        request_id: Uid::from(&[3; UID_LEN]),
+        capacity: 250,
+        source: PublicKey::from(PublicKey::from(&[0xee; PUBLIC_KEY_LEN])),
+        destination: PublicKey::from(PublicKey::from(&[0xff; PUBLIC_KEY_LEN])),
+        opt_exclude: None,
+    };
+
+    let app_server_to_index_client = AppServerToIndexClient::AppRequest((
+        Uid::from(&[50; UID_LEN]),
+        IndexClientRequest::RequestRoutes(request_routes.clone()),
+    ));
+    await!(icc.app_server_sender.send(app_server_to_index_client)).unwrap();
+
+    match await!(control_receiver.next()).unwrap() {
+        SingleClientControl::RequestRoutes((request_routes0, response_sender)) => {
+            assert_eq!(request_routes0, request_routes);
+            // Server returns a response with a signature that does not match the routes:
+            let mut response_routes = signed_response_routes(&request_routes, vec![]);
+            response_routes.opt_signature.as_mut().unwrap().time_hash =
+                HashResult::from(&[0x22; HASH_RESULT_LEN]);
+            response_sender.send(response_routes).unwrap();
+        }
+        _ => unreachable!(),
+    };
+
+    // Expect empty report mutations:
+    match await!(icc.app_server_receiver.next()).unwrap() {
+        IndexClientToAppServer::ReportMutations(ic_report_mutations) => {
+            assert!(ic_report_mutations.mutations.is_empty());
+        }
+        _ => unreachable!(),
+    };
+
+    // The response is not forwarded to the AppServer:
+    match await!(icc.app_server_receiver.next()).unwrap() {
+        IndexClientToAppServer::ResponseRoutes(client_response_routes) => {
+            assert_eq!(client_response_routes.request_id, Uid::from(&[3; UID_LEN]));
+            assert_eq!(client_response_routes.result, ResponseRoutesResult::Failure);
+            assert_eq!(client_response_routes.opt_routes_signature, None);
+        }
+        _ => unreachable!(),
+    };
+}
+
+#[test]
+fn test_index_client_loop_request_routes_invalid_signature() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_index_client_loop_request_routes_invalid_signature(
+        thread_pool.clone(),
     ));
 }
 
@@ -444,12 +828,12 @@ async fn task_index_client_loop_connecting_state<S>(spawner: S)
 where
     S: Spawn + Clone + Send + 'static,
 {
-    let mut icc = basic_index_client(spawner.clone());
+    let mut icc = basic_index_client(spawner.clone(), None);
 
     // Wait for a connection request:
     let session_conn_request = await!(icc.session_receiver.next()).unwrap();
     let index_server = IndexServerAddress {
-        public_key: PublicKey::from(&[0x37; PUBLIC_KEY_LEN]),
+        public_key: index_server_public_key(),
         address: 0x1337,
     };
     assert_eq!(session_conn_request.address, index_server);
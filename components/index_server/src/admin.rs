@@ -0,0 +1,233 @@
+use futures::channel::{mpsc, oneshot};
+use futures::task::{Spawn, SpawnExt};
+use futures::{FutureExt, SinkExt, Stream, StreamExt, TryFutureExt};
+
+use bincode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use common::conn::ConnPairVec;
+
+use crypto::identity::PublicKey;
+
+use proto::index_server::messages::IndexServerAddress;
+use proto::net::messages::NetAddress;
+
+use crate::graph::capacity_graph::AgedEdge;
+use crate::graph::route_stats::RouteStats;
+
+/// State of the connection to a trusted remote index server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RemoteServerStatus {
+    /// Connection is established
+    Connected,
+    /// We are trying to connect to the remote server
+    Initiating,
+    /// We are waiting for the remote server to connect to us
+    Listening,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteServerInfo {
+    pub public_key: PublicKey,
+    pub status: RemoteServerStatus,
+    /// The trusted server that vouched for this server (If this server was discovered through
+    /// peer lists)
+    pub opt_voucher: Option<PublicKey>,
+}
+
+/// Current state of a running index server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexServerStatus {
+    /// Amount of nodes in the graph that have at least one outgoing edge
+    pub num_nodes: usize,
+    /// Amount of directed edges in the graph
+    pub num_edges: usize,
+    /// Amount of connected clients
+    pub num_clients: usize,
+    /// Amount of nodes currently banned due to misbehaviour
+    pub num_banned: usize,
+    /// All trusted remote index servers, sorted by public key
+    pub remote_servers: Vec<RemoteServerInfo>,
+    pub route_stats: RouteStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminRequest<A = NetAddress> {
+    GetStatus,
+    /// Get all the directed edges of the capacity graph
+    GetEdges,
+    /// Start trusting a remote index server (Or update its address)
+    AddServer(IndexServerAddress<A>),
+    /// Stop trusting a remote index server
+    RemoveServer(PublicKey),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Status(IndexServerStatus),
+    Edges(Vec<AgedEdge<PublicKey, u128>>),
+    /// false if the server was already trusted
+    ServerAdded(bool),
+    /// false if the server was not trusted
+    ServerRemoved(bool),
+}
+
+/// An admin request waiting to be handled by the index server
+#[derive(Debug)]
+pub struct IncomingAdminRequest<A = NetAddress> {
+    pub request: AdminRequest<A>,
+    pub response_sender: oneshot::Sender<AdminResponse>,
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    SpawnError,
+    ConnectionClosed,
+    SendError,
+    SerializeError(bincode::Error),
+    DeserializeError(bincode::Error),
+    RequestSenderClosed,
+    ResponseCanceled,
+}
+
+/// Handle a single admin connection.
+/// Every admin connection carries exactly one request and one response.
+async fn handle_admin_conn<A>(
+    conn_pair: ConnPairVec,
+    mut admin_request_sender: mpsc::Sender<IncomingAdminRequest<A>>,
+) -> Result<(), AdminError>
+where
+    A: DeserializeOwned,
+{
+    let (mut sender, mut receiver) = conn_pair;
+
+    let data = await!(receiver.next()).ok_or(AdminError::ConnectionClosed)?;
+    let request = bincode::deserialize(&data).map_err(AdminError::DeserializeError)?;
+
+    let (response_sender, response_receiver) = oneshot::channel();
+    let incoming_admin_request = IncomingAdminRequest {
+        request,
+        response_sender,
+    };
+    await!(admin_request_sender.send(incoming_admin_request))
+        .map_err(|_| AdminError::RequestSenderClosed)?;
+    let response = await!(response_receiver).map_err(|_| AdminError::ResponseCanceled)?;
+
+    let data = bincode::serialize(&response).map_err(AdminError::SerializeError)?;
+    await!(sender.send(data)).map_err(|_| AdminError::SendError)?;
+    Ok(())
+}
+
+/// Serve admin requests arriving from local raw connections.
+/// Requests are forwarded to the index server through `admin_request_sender`.
+///
+/// Note that admin connections are not encrypted or authenticated. The admin endpoint should only
+/// be exposed locally.
+pub async fn admin_loop<A, IAC, S>(
+    mut incoming_admin_raw_conns: IAC,
+    admin_request_sender: mpsc::Sender<IncomingAdminRequest<A>>,
+    mut spawner: S,
+) -> Result<(), AdminError>
+where
+    A: DeserializeOwned + Send + 'static,
+    IAC: Stream<Item = ConnPairVec> + Unpin,
+    S: Spawn,
+{
+    while let Some(conn_pair) = await!(incoming_admin_raw_conns.next()) {
+        let conn_fut = handle_admin_conn(conn_pair, admin_request_sender.clone())
+            .map_err(|e| warn!("handle_admin_conn() error: {:?}", e))
+            .map(|_| ());
+        spawner
+            .spawn(conn_fut)
+            .map_err(|_| AdminError::SpawnError)?;
+    }
+    Ok(())
+}
+
+/// Send an admin request to an index server over a raw connection, and wait for the response.
+pub async fn request_admin<A>(
+    conn_pair: ConnPairVec,
+    request: AdminRequest<A>,
+) -> Result<AdminResponse, AdminError>
+where
+    A: Serialize,
+{
+    let (mut sender, mut receiver) = conn_pair;
+
+    let data = bincode::serialize(&request).map_err(AdminError::SerializeError)?;
+    await!(sender.send(data)).map_err(|_| AdminError::SendError)?;
+
+    let data = await!(receiver.next()).ok_or(AdminError::ConnectionClosed)?;
+    bincode::deserialize(&data).map_err(AdminError::DeserializeError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::ThreadPool;
+
+    use crypto::identity::PUBLIC_KEY_LEN;
+
+    fn example_status() -> IndexServerStatus {
+        IndexServerStatus {
+            num_nodes: 3,
+            num_edges: 5,
+            num_clients: 2,
+            num_banned: 1,
+            remote_servers: vec![RemoteServerInfo {
+                public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+                status: RemoteServerStatus::Connected,
+                opt_voucher: None,
+            }],
+            route_stats: RouteStats {
+                total_requests: 10,
+                recent_requests: 4,
+                recent_avg_latency_us: 150,
+                recent_max_latency_us: 400,
+            },
+        }
+    }
+
+    async fn task_admin_loop_basic<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (mut admin_raw_conns_sender, incoming_admin_raw_conns) = mpsc::channel(0);
+        let (admin_request_sender, mut incoming_admin_requests) =
+            mpsc::channel::<IncomingAdminRequest>(0);
+
+        let loop_fut = admin_loop(
+            incoming_admin_raw_conns,
+            admin_request_sender,
+            spawner.clone(),
+        )
+        .map_err(|e| error!("admin_loop() error: {:?}", e))
+        .map(|_| ());
+        spawner.spawn(loop_fut).unwrap();
+
+        let (client_sender, server_receiver) = mpsc::channel(0);
+        let (server_sender, client_receiver) = mpsc::channel(0);
+        await!(admin_raw_conns_sender.send((server_sender, server_receiver))).unwrap();
+
+        let client_conn = (client_sender, client_receiver);
+        let response_fut = request_admin(client_conn, AdminRequest::<NetAddress>::GetStatus);
+        let response_handle = spawner.spawn_with_handle(response_fut).unwrap();
+
+        let incoming_admin_request = await!(incoming_admin_requests.next()).unwrap();
+        assert_eq!(incoming_admin_request.request, AdminRequest::GetStatus);
+        incoming_admin_request
+            .response_sender
+            .send(AdminResponse::Status(example_status()))
+            .unwrap();
+
+        let response = await!(response_handle).unwrap();
+        assert_eq!(response, AdminResponse::Status(example_status()));
+    }
+
+    #[test]
+    fn test_admin_loop_basic() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_admin_loop_basic(thread_pool.clone()));
+    }
+}
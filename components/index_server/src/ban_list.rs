@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Configuration for automatic banning of misbehaving nodes
+#[derive(Debug, Clone)]
+pub struct BanConfig {
+    /// A node is banned when its violation score reaches this value
+    pub violation_threshold: usize,
+    /// Amount of ticks a node remains banned
+    pub ban_ticks: usize,
+}
+
+/// Keeps track of violations done by nodes, and temporarily bans nodes with too many violations.
+/// Violation scores decay by one every tick, so that occasional violations are forgiven.
+#[derive(Debug)]
+pub struct BanList<N> {
+    ban_config: BanConfig,
+    /// Current violation score of every node
+    scores: HashMap<N, usize>,
+    /// Banned nodes, together with the amount of ticks left until the ban is lifted.
+    banned: HashMap<N, usize>,
+}
+
+impl<N> BanList<N>
+where
+    N: Eq + Hash + Clone,
+{
+    pub fn new(ban_config: BanConfig) -> Self {
+        BanList {
+            ban_config,
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    /// Record a violation done by a node.
+    /// Returns true if the node was banned due to this violation.
+    pub fn add_violation(&mut self, node: &N) -> bool {
+        if self.is_banned(node) {
+            return false;
+        }
+
+        let score = self.scores.entry(node.clone()).or_insert(0);
+        *score = score.saturating_add(1);
+        if *score < self.ban_config.violation_threshold {
+            return false;
+        }
+
+        self.ban(node.clone())
+    }
+
+    /// Ban a node (Or extend the current ban of the node).
+    /// Returns true if the node was not banned before.
+    pub fn ban(&mut self, node: N) -> bool {
+        self.scores.remove(&node);
+        self.banned.insert(node, self.ban_config.ban_ticks).is_none()
+    }
+
+    pub fn is_banned(&self, node: &N) -> bool {
+        self.banned.contains_key(node)
+    }
+
+    pub fn num_banned(&self) -> usize {
+        self.banned.len()
+    }
+
+    /// Advance time by one tick: Decay violation scores and lift expired bans.
+    /// Returns the nodes that are no longer banned.
+    pub fn tick(&mut self) -> Vec<N> {
+        self.scores.retain(|_node, score| {
+            *score = score.saturating_sub(1);
+            *score > 0
+        });
+
+        let mut unbanned = Vec::new();
+        self.banned.retain(|node, ticks_left| {
+            *ticks_left = ticks_left.saturating_sub(1);
+            if *ticks_left == 0 {
+                unbanned.push(node.clone());
+                false
+            } else {
+                true
+            }
+        });
+        unbanned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_ban_list() -> BanList<u32> {
+        BanList::new(BanConfig {
+            violation_threshold: 3,
+            ban_ticks: 2,
+        })
+    }
+
+    #[test]
+    fn test_ban_list_violations() {
+        let mut ban_list = example_ban_list();
+
+        assert!(!ban_list.add_violation(&1));
+        assert!(!ban_list.add_violation(&1));
+        assert!(!ban_list.is_banned(&1));
+        assert!(ban_list.add_violation(&1));
+        assert!(ban_list.is_banned(&1));
+        assert_eq!(ban_list.num_banned(), 1);
+
+        // Violations of a banned node are ignored:
+        assert!(!ban_list.add_violation(&1));
+
+        assert!(ban_list.tick().is_empty());
+        assert!(ban_list.is_banned(&1));
+        assert_eq!(ban_list.tick(), vec![1]);
+        assert!(!ban_list.is_banned(&1));
+        assert_eq!(ban_list.num_banned(), 0);
+
+        // The violation score was reset by the ban:
+        assert!(!ban_list.add_violation(&1));
+        assert!(!ban_list.add_violation(&1));
+        assert!(ban_list.add_violation(&1));
+    }
+
+    #[test]
+    fn test_ban_list_score_decay() {
+        let mut ban_list = example_ban_list();
+
+        assert!(!ban_list.add_violation(&1));
+        assert!(!ban_list.add_violation(&1));
+        ban_list.tick();
+        assert!(!ban_list.add_violation(&1));
+        ban_list.tick();
+        ban_list.tick();
+        assert!(!ban_list.add_violation(&1));
+        assert!(!ban_list.add_violation(&1));
+        assert!(!ban_list.is_banned(&1));
+    }
+
+    #[test]
+    fn test_ban_list_ban() {
+        let mut ban_list = example_ban_list();
+
+        assert!(ban_list.ban(2));
+        assert!(ban_list.is_banned(&2));
+        ban_list.tick();
+        // Banning again extends the ban:
+        assert!(!ban_list.ban(2));
+        ban_list.tick();
+        assert!(ban_list.is_banned(&2));
+        assert_eq!(ban_list.tick(), vec![2]);
+        assert!(!ban_list.is_banned(&2));
+    }
+}
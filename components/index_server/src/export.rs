@@ -0,0 +1,166 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crypto::identity::PublicKey;
+
+use proto::file::ser_string::public_key_to_string;
+
+use crate::graph::capacity_graph::AgedEdge;
+
+// Note: Public keys are encoded using url safe base64, therefore they never need to be escaped
+// inside DOT quoted strings or XML attributes.
+
+/// Sort edges and collect all the nodes that appear in the edges, to get deterministic output.
+fn sorted_nodes_edges(
+    edges: &[AgedEdge<PublicKey, u128>],
+) -> (BTreeSet<&PublicKey>, Vec<&AgedEdge<PublicKey, u128>>) {
+    let mut nodes = BTreeSet::new();
+    for (a, b, _capacity, _age) in edges {
+        nodes.insert(a);
+        nodes.insert(b);
+    }
+    let mut sorted_edges = edges.iter().collect::<Vec<_>>();
+    sorted_edges.sort();
+    (nodes, sorted_edges)
+}
+
+/// Export a capacity graph to Graphviz DOT format
+pub fn edges_to_dot(edges: &[AgedEdge<PublicKey, u128>]) -> String {
+    let (nodes, sorted_edges) = sorted_nodes_edges(edges);
+
+    // Writing into a String never fails:
+    let mut output = String::new();
+    writeln!(output, "digraph index {{").unwrap();
+    for node in nodes {
+        writeln!(output, "    \"{}\";", public_key_to_string(node)).unwrap();
+    }
+    for (a, b, (send_capacity, recv_capacity), age) in sorted_edges {
+        writeln!(
+            output,
+            "    \"{}\" -> \"{}\" [label=\"{}/{}\", send_capacity=\"{}\", \
+             recv_capacity=\"{}\", age=\"{}\"];",
+            public_key_to_string(a),
+            public_key_to_string(b),
+            send_capacity,
+            recv_capacity,
+            send_capacity,
+            recv_capacity,
+            age
+        )
+        .unwrap();
+    }
+    writeln!(output, "}}").unwrap();
+    output
+}
+
+/// Export a capacity graph to GraphML format
+pub fn edges_to_graphml(edges: &[AgedEdge<PublicKey, u128>]) -> String {
+    let (nodes, sorted_edges) = sorted_nodes_edges(edges);
+
+    // Writing into a String never fails:
+    let mut output = String::new();
+    writeln!(output, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(
+        output,
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">"
+    )
+    .unwrap();
+    // Capacities are 128 bits long, so we encode them as strings:
+    for key in &["send_capacity", "recv_capacity", "age"] {
+        writeln!(
+            output,
+            "  <key id=\"{}\" for=\"edge\" attr.name=\"{}\" attr.type=\"string\"/>",
+            key, key
+        )
+        .unwrap();
+    }
+    writeln!(output, "  <graph id=\"index\" edgedefault=\"directed\">").unwrap();
+    for node in nodes {
+        writeln!(output, "    <node id=\"{}\"/>", public_key_to_string(node)).unwrap();
+    }
+    for (a, b, (send_capacity, recv_capacity), age) in sorted_edges {
+        writeln!(
+            output,
+            "    <edge source=\"{}\" target=\"{}\">",
+            public_key_to_string(a),
+            public_key_to_string(b)
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "      <data key=\"send_capacity\">{}</data>",
+            send_capacity
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "      <data key=\"recv_capacity\">{}</data>",
+            recv_capacity
+        )
+        .unwrap();
+        writeln!(output, "      <data key=\"age\">{}</data>", age).unwrap();
+        writeln!(output, "    </edge>").unwrap();
+    }
+    writeln!(output, "  </graph>").unwrap();
+    writeln!(output, "</graphml>").unwrap();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::identity::PUBLIC_KEY_LEN;
+
+    fn example_edges() -> Vec<AgedEdge<PublicKey, u128>> {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        vec![
+            (pk_b.clone(), pk_a.clone(), (20, 10), 0),
+            (pk_a.clone(), pk_b.clone(), (10, 20), 3),
+        ]
+    }
+
+    #[test]
+    fn test_edges_to_dot() {
+        let pk_a_str = public_key_to_string(&PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]));
+        let pk_b_str = public_key_to_string(&PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]));
+
+        let expected = format!(
+            "digraph index {{\n    \"{a}\";\n    \"{b}\";\n    \
+             \"{a}\" -> \"{b}\" [label=\"10/20\", send_capacity=\"10\", recv_capacity=\"20\", \
+             age=\"3\"];\n    \
+             \"{b}\" -> \"{a}\" [label=\"20/10\", send_capacity=\"20\", recv_capacity=\"10\", \
+             age=\"0\"];\n}}\n",
+            a = pk_a_str,
+            b = pk_b_str
+        );
+        assert_eq!(edges_to_dot(&example_edges()), expected);
+    }
+
+    #[test]
+    fn test_edges_to_graphml() {
+        let pk_a_str = public_key_to_string(&PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]));
+        let pk_b_str = public_key_to_string(&PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]));
+
+        let graphml = edges_to_graphml(&example_edges());
+        assert!(graphml.starts_with("<?xml"));
+        assert!(graphml.contains(&format!("<node id=\"{}\"/>", pk_a_str)));
+        assert!(graphml.contains(&format!("<node id=\"{}\"/>", pk_b_str)));
+
+        let edge_a_b = format!(
+            "<edge source=\"{}\" target=\"{}\">\n      \
+             <data key=\"send_capacity\">10</data>\n      \
+             <data key=\"recv_capacity\">20</data>\n      \
+             <data key=\"age\">3</data>\n    </edge>",
+            pk_a_str, pk_b_str
+        );
+        assert!(graphml.contains(&edge_a_b));
+        assert!(graphml.ends_with("</graphml>\n"));
+    }
+
+    #[test]
+    fn test_export_empty_graph() {
+        assert_eq!(edges_to_dot(&[]), "digraph index {\n}\n");
+        assert!(!edges_to_graphml(&[]).contains("<node"));
+    }
+}
@@ -1,5 +1,7 @@
 pub type CapacityEdge<C> = (C, C);
 pub type CapacityRoute<N, C> = (Vec<N>, C);
+/// A directed edge (from, to), its capacity and its age (in ticks)
+pub type AgedEdge<N, C> = (N, N, CapacityEdge<C>, u128);
 
 pub trait CapacityGraph {
     type Node; // Node type
@@ -22,8 +24,8 @@ pub trait CapacityGraph {
 
     /// Remove a node and all related edges known from him.
     /// Note: This method will not remove an edge from another node b pointing to a.
-    /// Returns true if the node `a` was present, false otherwise
-    fn remove_node(&mut self, a: &Self::Node) -> bool;
+    /// Returns the nodes b of the removed edges a -> b.
+    fn remove_node(&mut self, a: &Self::Node) -> Vec<Self::Node>;
 
     /// Get a route with capacity at least `capacity`.
     /// Returns the route together with the capacity it is possible to send through the route.
@@ -38,6 +40,28 @@ pub trait CapacityGraph {
         opt_exclude: Option<(&Self::Node, &Self::Node)>,
     ) -> Vec<CapacityRoute<Self::Node, Self::Capacity>>;
 
+    /// Get all the nodes that have a route to `b` with capacity at least `capacity`.
+    /// Returns every such node together with the maximum capacity it can send to `b` through a
+    /// single route, sorted by capacity (Largest first).
+    ///
+    /// If `opt_max_nodes` is provided, only the first `max_nodes` nodes are returned.
+    fn get_reachability(
+        &self,
+        b: &Self::Node,
+        capacity: Self::Capacity,
+        opt_max_nodes: Option<usize>,
+    ) -> Vec<(Self::Node, Self::Capacity)>;
+
     /// Simulate advancement of time. Used to remove old edges.
-    fn tick(&mut self, a: &Self::Node);
+    /// Returns the nodes b of the expired edges a -> b.
+    fn tick(&mut self, a: &Self::Node) -> Vec<Self::Node>;
+
+    /// Get all the directed edges in the graph, together with their age.
+    fn get_edges(&self) -> Vec<AgedEdge<Self::Node, Self::Capacity>>;
+
+    /// Amount of nodes that have at least one outgoing edge.
+    fn num_nodes(&self) -> usize;
+
+    /// Amount of directed edges in the graph.
+    fn num_edges(&self) -> usize;
 }
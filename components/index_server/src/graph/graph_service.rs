@@ -1,8 +1,24 @@
+use std::time::{Duration, Instant};
+
 use futures::channel::{mpsc, oneshot};
 use futures::task::{Spawn, SpawnError, SpawnExt};
 use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt};
 
-use super::capacity_graph::{CapacityEdge, CapacityGraph, CapacityRoute};
+use super::capacity_graph::{AgedEdge, CapacityEdge, CapacityGraph, CapacityRoute};
+use super::route_stats::{RouteStats, RouteStatsCollector};
+
+/// Time window used for recent route requests statistics.
+const ROUTE_STATS_WINDOW: Duration = Duration::from_secs(60);
+
+/// Statistics about the graph and the route requests it served.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphStats {
+    /// Amount of nodes that have at least one outgoing edge
+    pub num_nodes: usize,
+    /// Amount of directed edges
+    pub num_edges: usize,
+    pub route_stats: RouteStats,
+}
 
 pub enum GraphRequest<N, C> {
     /// Change capacities on a directed edge:
@@ -16,7 +32,8 @@ pub enum GraphRequest<N, C> {
     RemoveEdge(N, N, oneshot::Sender<Option<CapacityEdge<C>>>),
     /// Remove a node and all edges starting from this node.
     /// Note: This will not remove edges going to this node.
-    RemoveNode(N, oneshot::Sender<bool>),
+    /// Returns the destinations of the removed edges.
+    RemoveNode(N, oneshot::Sender<Vec<N>>),
     /// Get some routes from one node to another of at least certain capacity.
     /// If an exclude directed edge is provided, the routes must not contain this directed edge.
     GetRoutes(
@@ -26,8 +43,17 @@ pub enum GraphRequest<N, C> {
         Option<(N, N)>,
         oneshot::Sender<Vec<CapacityRoute<N, C>>>,
     ), // (from, to, capacity, opt_exclude)
-    /// Expire old outgoing edges for the specified node
-    Tick(N, oneshot::Sender<()>),
+    /// Get all the nodes that can send at least a certain capacity to a node, together with the
+    /// maximum capacity they can send. At most opt_max_nodes nodes are returned.
+    /// (to, capacity, opt_max_nodes)
+    GetReachability(N, C, Option<usize>, oneshot::Sender<Vec<(N, C)>>),
+    /// Expire old outgoing edges for the specified node.
+    /// Returns the destinations of the expired edges.
+    Tick(N, oneshot::Sender<Vec<N>>),
+    /// Get all the directed edges in the graph, together with their age.
+    GetEdges(oneshot::Sender<Vec<AgedEdge<N, C>>>),
+    /// Get statistics about the graph and about processed route requests.
+    GetStats(oneshot::Sender<GraphStats>),
 }
 
 #[derive(Debug)]
@@ -37,11 +63,21 @@ pub enum GraphServiceError {
 }
 
 /// Process one GraphRequest, and send the response through the provided sender.
+/// The latency of route requests is recorded in `route_stats_collector`.
 /// This function might perform a long computation and take a long time to complete.
-fn process_request<N, C, CG>(capacity_graph: &mut CG, graph_request: GraphRequest<N, C>)
-where
+fn process_request<N, C, CG>(
+    capacity_graph: &mut CG,
+    route_stats_collector: &mut RouteStatsCollector,
+    graph_request: GraphRequest<N, C>,
+) where
     CG: CapacityGraph<Node = N, Capacity = C>,
 {
+    let start = Instant::now();
+    let is_get_routes = match graph_request {
+        GraphRequest::GetRoutes(..) | GraphRequest::GetReachability(..) => true,
+        _ => false,
+    };
+
     match graph_request {
         GraphRequest::UpdateEdge(a, b, capacity_edge, sender) => {
             let _ = sender.send(capacity_graph.update_edge(a, b, capacity_edge));
@@ -59,11 +95,29 @@ where
             };
             let _ = sender.send(routes);
         }
+        GraphRequest::GetReachability(b, capacity, opt_max_nodes, sender) => {
+            let _ = sender.send(capacity_graph.get_reachability(&b, capacity, opt_max_nodes));
+        }
         GraphRequest::Tick(a, sender) => {
-            capacity_graph.tick(&a);
-            let _ = sender.send(());
+            let _ = sender.send(capacity_graph.tick(&a));
+        }
+        GraphRequest::GetEdges(sender) => {
+            let _ = sender.send(capacity_graph.get_edges());
+        }
+        GraphRequest::GetStats(sender) => {
+            let graph_stats = GraphStats {
+                num_nodes: capacity_graph.num_nodes(),
+                num_edges: capacity_graph.num_edges(),
+                route_stats: route_stats_collector.stats(start),
+            };
+            let _ = sender.send(graph_stats);
         }
     }
+
+    if is_get_routes {
+        let now = Instant::now();
+        route_stats_collector.record(now, now.duration_since(start));
+    }
 }
 
 async fn graph_service_loop<N, C, CG, GS>(
@@ -80,19 +134,27 @@ where
     // We use a separate spawner to be used for long graph computations.
     // We don't want to block the external shared thread pool.
 
+    let mut route_stats_collector = RouteStatsCollector::new(ROUTE_STATS_WINDOW);
+
     while let Some(graph_request) = await!(incoming_requests.next()) {
         // Run the graph computation over own pool:
         let process_request_handle = graph_service_spawner
             .spawn_with_handle(
                 async move {
-                    process_request(&mut capacity_graph, graph_request);
-                    capacity_graph
+                    process_request(
+                        &mut capacity_graph,
+                        &mut route_stats_collector,
+                        graph_request,
+                    );
+                    (capacity_graph, route_stats_collector)
                 },
             )
             .map_err(|_| GraphServiceError::LocalSpawnError)?;
 
         // Wait for completion of the computation on the external pool:
-        capacity_graph = await!(process_request_handle);
+        let (new_capacity_graph, new_route_stats_collector) = await!(process_request_handle);
+        capacity_graph = new_capacity_graph;
+        route_stats_collector = new_route_stats_collector;
     }
     Ok(())
 }
@@ -154,8 +216,8 @@ impl<N, C> GraphClient<N, C> {
 
     /// Remove a node and all related edges known from him.
     /// Note: This method will not remove an edge from another node b pointing to a.
-    /// Returns true if the node `a` was present, false otherwise
-    pub async fn remove_node(&mut self, a: N) -> Result<bool, GraphClientError> {
+    /// Returns the nodes b of the removed edges a -> b.
+    pub async fn remove_node(&mut self, a: N) -> Result<Vec<N>, GraphClientError> {
         let (sender, receiver) = oneshot::channel();
         await!(self
             .requests_sender
@@ -186,12 +248,47 @@ impl<N, C> GraphClient<N, C> {
         Ok(await!(receiver)?)
     }
 
-    /// Remove an edge from the graph
-    pub async fn tick(&mut self, a: N) -> Result<(), GraphClientError> {
+    /// Obtain all the nodes that can send at least `capacity` to `b`.
+    /// Returns each node together with the maximum capacity it can send to `b`, ordered from the
+    /// largest capacity to the smallest. If `opt_max_nodes` is provided, only the top nodes are
+    /// returned.
+    pub async fn get_reachability(
+        &mut self,
+        b: N,
+        capacity: C,
+        opt_max_nodes: Option<usize>,
+    ) -> Result<Vec<(N, C)>, GraphClientError> {
+        let (sender, receiver) = oneshot::channel();
+        await!(self.requests_sender.send(GraphRequest::GetReachability(
+            b,
+            capacity,
+            opt_max_nodes,
+            sender
+        )))?;
+        Ok(await!(receiver)?)
+    }
+
+    /// Expire old outgoing edges for the node `a`.
+    /// Returns the nodes b of the expired edges a -> b.
+    pub async fn tick(&mut self, a: N) -> Result<Vec<N>, GraphClientError> {
         let (sender, receiver) = oneshot::channel();
         await!(self.requests_sender.send(GraphRequest::Tick(a, sender)))?;
         Ok(await!(receiver)?)
     }
+
+    /// Get all the directed edges in the graph, together with their age.
+    pub async fn get_edges(&mut self) -> Result<Vec<AgedEdge<N, C>>, GraphClientError> {
+        let (sender, receiver) = oneshot::channel();
+        await!(self.requests_sender.send(GraphRequest::GetEdges(sender)))?;
+        Ok(await!(receiver)?)
+    }
+
+    /// Get statistics about the graph and about processed route requests.
+    pub async fn get_stats(&mut self) -> Result<GraphStats, GraphClientError> {
+        let (sender, receiver) = oneshot::channel();
+        await!(self.requests_sender.send(GraphRequest::GetStats(sender)))?;
+        Ok(await!(receiver)?)
+    }
 }
 
 /// Spawn a graph service, returning a GraphClient on success.
@@ -250,14 +347,33 @@ mod tests {
             vec![]
         );
 
-        await!(graph_client.tick(2)).unwrap();
+        assert_eq!(
+            await!(graph_client.get_reachability(5, 30, None)).unwrap(),
+            vec![(2, 30)]
+        );
+        assert_eq!(
+            await!(graph_client.get_reachability(5, 31, None)).unwrap(),
+            vec![]
+        );
+
+        assert!(await!(graph_client.tick(2)).unwrap().is_empty());
+
+        let mut edges = await!(graph_client.get_edges()).unwrap();
+        edges.sort();
+        assert_eq!(edges, vec![(2, 5, (30, 5), 1), (5, 2, (5, 30), 0)]);
+
+        let graph_stats = await!(graph_client.get_stats()).unwrap();
+        assert_eq!(graph_stats.num_nodes, 2);
+        assert_eq!(graph_stats.num_edges, 2);
+        assert_eq!(graph_stats.route_stats.total_requests, 5);
+        assert_eq!(graph_stats.route_stats.recent_requests, 5);
 
         assert_eq!(
             await!(graph_client.remove_edge(2, 5)).unwrap(),
             Some((30, 5))
         );
-        assert_eq!(await!(graph_client.remove_node(2)).unwrap(), false);
-        assert_eq!(await!(graph_client.remove_node(5)).unwrap(), true);
+        assert!(await!(graph_client.remove_node(2)).unwrap().is_empty());
+        assert_eq!(await!(graph_client.remove_node(5)).unwrap(), vec![2]);
     }
 
     #[test]
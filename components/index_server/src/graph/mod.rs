@@ -1,5 +1,6 @@
 mod bfs;
-mod capacity_graph;
+pub mod capacity_graph;
 pub mod graph_service;
+pub mod route_stats;
 pub mod simple_capacity_graph;
 mod utils;
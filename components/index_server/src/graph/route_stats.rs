@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use common::int_convert::usize_to_u64;
+
+/// Statistics about route requests processed by the graph service.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RouteStats {
+    /// Total amount of route requests processed
+    pub total_requests: u64,
+    /// Amount of route requests processed during the last time window
+    pub recent_requests: u64,
+    /// Average time it took to compute routes during the last time window (In microseconds)
+    pub recent_avg_latency_us: u64,
+    /// Maximum time it took to compute routes during the last time window (In microseconds)
+    pub recent_max_latency_us: u64,
+}
+
+/// Collects route requests latencies, and keeps only the ones inside a recent time window.
+pub struct RouteStatsCollector {
+    window: Duration,
+    total_requests: u64,
+    /// (Completion time, latency) of recent requests, oldest first.
+    recent: VecDeque<(Instant, Duration)>,
+}
+
+fn duration_to_micros(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1_000_000)
+        .saturating_add(u64::from(duration.subsec_micros()))
+}
+
+impl RouteStatsCollector {
+    pub fn new(window: Duration) -> Self {
+        RouteStatsCollector {
+            window,
+            total_requests: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Forget requests that are older than the time window.
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(completed, _)) = self.recent.front() {
+            if now.duration_since(completed) < self.window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    /// Record a route request that was completed at `now`, and took `latency` to compute.
+    pub fn record(&mut self, now: Instant, latency: Duration) {
+        self.total_requests = self.total_requests.saturating_add(1);
+        self.recent.push_back((now, latency));
+        self.prune(now);
+    }
+
+    pub fn stats(&mut self, now: Instant) -> RouteStats {
+        self.prune(now);
+
+        let recent_requests = usize_to_u64(self.recent.len()).unwrap();
+        let latencies = self
+            .recent
+            .iter()
+            .map(|&(_, latency)| duration_to_micros(latency));
+        let total_latency_us = latencies
+            .clone()
+            .fold(0u64, |acc, latency_us| acc.saturating_add(latency_us));
+
+        RouteStats {
+            total_requests: self.total_requests,
+            recent_requests,
+            recent_avg_latency_us: total_latency_us.checked_div(recent_requests).unwrap_or(0),
+            recent_max_latency_us: latencies.max().unwrap_or(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_stats_collector_basic() {
+        let window = Duration::from_secs(60);
+        let mut collector = RouteStatsCollector::new(window);
+        let start = Instant::now();
+
+        assert_eq!(collector.stats(start), RouteStats::default());
+
+        collector.record(start, Duration::from_micros(100));
+        collector.record(start + Duration::from_secs(10), Duration::from_micros(300));
+
+        let stats = collector.stats(start + Duration::from_secs(20));
+        assert_eq!(stats.total_requests, 2);
+        assert_eq!(stats.recent_requests, 2);
+        assert_eq!(stats.recent_avg_latency_us, 200);
+        assert_eq!(stats.recent_max_latency_us, 300);
+
+        // The first request leaves the time window:
+        let stats = collector.stats(start + Duration::from_secs(65));
+        assert_eq!(stats.total_requests, 2);
+        assert_eq!(stats.recent_requests, 1);
+        assert_eq!(stats.recent_avg_latency_us, 300);
+        assert_eq!(stats.recent_max_latency_us, 300);
+
+        // All requests leave the time window:
+        let stats = collector.stats(start + Duration::from_secs(100));
+        assert_eq!(stats.total_requests, 2);
+        assert_eq!(stats.recent_requests, 0);
+        assert_eq!(stats.recent_avg_latency_us, 0);
+        assert_eq!(stats.recent_max_latency_us, 0);
+    }
+}
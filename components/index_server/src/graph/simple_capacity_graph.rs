@@ -1,8 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap};
 use std::{cmp, hash};
 
 use super::bfs::bfs;
-use super::capacity_graph::{CapacityEdge, CapacityGraph};
+use super::capacity_graph::{AgedEdge, CapacityEdge, CapacityGraph};
 use super::utils::{option_to_vec, OptionIterator};
 
 /// Amount of ticks an edge could live regardless of coupon collector's approximation.
@@ -18,6 +18,10 @@ impl Edge {
     fn new(capacity: CapacityEdge<u128>) -> Self {
         Edge { capacity, age: 0 }
     }
+
+    fn with_age(capacity: CapacityEdge<u128>, age: u128) -> Self {
+        Edge { capacity, age }
+    }
 }
 
 struct NodeEdges<N> {
@@ -49,13 +53,20 @@ impl<N> NodeEdges<N>
 where
     N: cmp::Eq + hash::Hash + Clone + std::fmt::Debug,
 {
-    pub fn tick(&mut self) {
+    /// Returns the remote nodes of the expired edges.
+    pub fn tick(&mut self) -> Vec<N> {
         let max_edge_age = max_edge_age(self.edges.len());
 
-        self.edges.retain(|_remote_node, edge| {
+        let mut expired = Vec::new();
+        self.edges.retain(|remote_node, edge| {
             edge.age = edge.age.saturating_add(1);
-            edge.age < max_edge_age
+            if edge.age >= max_edge_age {
+                expired.push(remote_node.clone());
+                return false;
+            }
+            true
         });
+        expired
     }
 }
 
@@ -63,6 +74,33 @@ pub struct SimpleCapacityGraph<N> {
     nodes: HashMap<N, NodeEdges<N>>,
 }
 
+/// A node waiting to be visited during a reachability search.
+/// Entries are ordered by capacity only, so that the widest routes are visited first.
+struct ReachEntry<N> {
+    capacity: u128,
+    node: N,
+}
+
+impl<N> PartialEq for ReachEntry<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.capacity == other.capacity
+    }
+}
+
+impl<N> Eq for ReachEntry<N> {}
+
+impl<N> PartialOrd for ReachEntry<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<N> Ord for ReachEntry<N> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.capacity.cmp(&other.capacity)
+    }
+}
+
 impl<N> SimpleCapacityGraph<N>
 where
     N: cmp::Eq + hash::Hash + Clone + std::fmt::Debug,
@@ -73,6 +111,24 @@ where
         }
     }
 
+    /// Rebuild a graph from a list of directed edges (For example, edges previously obtained
+    /// using `get_edges()`). Edges keep their age, so that they will expire as usual.
+    pub fn from_edges<I>(edges: I) -> SimpleCapacityGraph<N>
+    where
+        I: IntoIterator<Item = AgedEdge<N, u128>>,
+    {
+        let mut capacity_graph = SimpleCapacityGraph::new();
+        for (a, b, capacity, age) in edges {
+            capacity_graph
+                .nodes
+                .entry(a)
+                .or_insert_with(NodeEdges::new)
+                .edges
+                .insert(b, Edge::with_age(capacity, age));
+        }
+        capacity_graph
+    }
+
     /// Get a directed edge (if exists)
     fn get_edge(&self, a: &N, b: &N) -> Option<CapacityEdge<u128>> {
         match self.nodes.get(a) {
@@ -130,6 +186,58 @@ where
             .min()
     }
 
+    /// Calculate, for every node with a route to `b` of capacity at least `capacity`, the maximum
+    /// capacity it can send to `b` through a single route.
+    ///
+    /// This is a BFS over reversed edges, where nodes are visited in the order of the widest route
+    /// found so far (Similar to Dijkstra's algorithm).
+    fn get_max_capacities_to(&self, b: &N, capacity: u128) -> HashMap<N, u128> {
+        let mut max_capacities: HashMap<N, u128> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        queue.push(ReachEntry {
+            capacity: u128::max_value(),
+            node: b.clone(),
+        });
+
+        while let Some(ReachEntry {
+            capacity: node_capacity,
+            node,
+        }) = queue.pop()
+        {
+            if &node != b && max_capacities.get(&node) != Some(&node_capacity) {
+                // We have already found a wider route from this node:
+                continue;
+            }
+            // A node `prev` can send to `node` only if both nodes report an edge between them.
+            // Therefore it is enough to check the neighbors reported by `node`:
+            let node_edges = match self.nodes.get(&node) {
+                Some(node_edges) => node_edges,
+                None => continue,
+            };
+            for prev in node_edges.edges.keys() {
+                if prev == b {
+                    continue;
+                }
+                let prev_capacity = cmp::min(node_capacity, self.get_send_capacity(prev, &node));
+                if prev_capacity < capacity {
+                    continue;
+                }
+                let is_wider = match max_capacities.get(prev) {
+                    Some(&cur_capacity) => prev_capacity > cur_capacity,
+                    None => true,
+                };
+                if is_wider {
+                    max_capacities.insert(prev.clone(), prev_capacity);
+                    queue.push(ReachEntry {
+                        capacity: prev_capacity,
+                        node: prev.clone(),
+                    });
+                }
+            }
+        }
+        max_capacities
+    }
+
     /// Get a route with capacity at least `capacity`.
     /// Returns the route together with the capacity it is possible to send through the route.
     ///
@@ -196,8 +304,11 @@ where
 
     /// Remove a node and all related edges known from him.
     /// Note: This method will not remove an edge from another node b pointing to a.
-    fn remove_node(&mut self, a: &N) -> bool {
-        self.nodes.remove(a).is_some()
+    fn remove_node(&mut self, a: &N) -> Vec<N> {
+        match self.nodes.remove(a) {
+            Some(a_edges) => a_edges.edges.into_iter().map(|(b, _edge)| b).collect(),
+            None => Vec::new(),
+        }
     }
 
     fn get_routes(
@@ -210,11 +321,50 @@ where
         option_to_vec(self.get_route(a, b, capacity, opt_exclude))
     }
 
-    fn tick(&mut self, a: &N) {
-        if let Some(node_edges) = self.nodes.get_mut(a) {
-            node_edges.tick();
+    fn get_reachability(
+        &self,
+        b: &N,
+        capacity: u128,
+        opt_max_nodes: Option<usize>,
+    ) -> Vec<(N, u128)> {
+        let mut reachability = self
+            .get_max_capacities_to(b, capacity)
+            .into_iter()
+            .collect::<Vec<_>>();
+        reachability.sort_by(|(_, capacity_a), (_, capacity_b)| capacity_b.cmp(capacity_a));
+        if let Some(max_nodes) = opt_max_nodes {
+            reachability.truncate(max_nodes);
+        }
+        reachability
+    }
+
+    fn tick(&mut self, a: &N) -> Vec<N> {
+        match self.nodes.get_mut(a) {
+            Some(node_edges) => node_edges.tick(),
+            None => Vec::new(),
         }
     }
+
+    fn get_edges(&self) -> Vec<AgedEdge<N, u128>> {
+        let mut edges = Vec::new();
+        for (a, a_edges) in &self.nodes {
+            for (b, edge) in &a_edges.edges {
+                edges.push((a.clone(), b.clone(), edge.capacity, edge.age));
+            }
+        }
+        edges
+    }
+
+    fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn num_edges(&self) -> usize {
+        self.nodes
+            .values()
+            .map(|node_edges| node_edges.edges.len())
+            .sum()
+    }
 }
 
 #[cfg(test)]
@@ -246,14 +396,19 @@ mod tests {
         assert_eq!(cg.remove_edge(&0, &1), None);
         cg.update_edge(0, 1, (10, 20));
         assert_eq!(cg.nodes.len(), 1);
+        assert_eq!(cg.num_nodes(), 1);
+        assert_eq!(cg.num_edges(), 1);
 
         assert_eq!(cg.remove_edge(&0, &1), Some((10, 20)));
         assert_eq!(cg.nodes.len(), 0);
+        assert_eq!(cg.num_edges(), 0);
 
         cg.update_edge(0, 1, (10, 20));
         assert_eq!(cg.nodes.len(), 1);
-        cg.remove_node(&1);
+        assert!(cg.remove_node(&1).is_empty());
         assert_eq!(cg.nodes.len(), 1);
+        assert_eq!(cg.remove_node(&0), vec![1]);
+        assert_eq!(cg.nodes.len(), 0);
     }
 
     fn example_capacity_graph() -> SimpleCapacityGraph<u32> {
@@ -334,6 +489,52 @@ mod tests {
         assert_eq!(cg.get_route(&2, &1, 7, Some((&2, &1))), None);
     }
 
+    #[test]
+    fn test_get_reachability() {
+        let cg = example_capacity_graph();
+
+        // Maximum capacities to 5:
+        // 0 -> 1 -> 3 -> 4 -> 2 -> 5: 30
+        // 1 -> 3 -> 4 -> 2 -> 5: 30
+        // 3 -> 4 -> 2 -> 5: 30
+        // 4 -> 2 -> 5: 30
+        // 2 -> 5: 30
+        let mut reachability = cg.get_reachability(&5, 0, None);
+        reachability.sort();
+        assert_eq!(
+            reachability,
+            vec![(0, 30), (1, 30), (2, 30), (3, 30), (4, 30)]
+        );
+        assert!(cg.get_reachability(&5, 31, None).is_empty());
+
+        // Maximum capacities to 1:
+        // 0 -> 1: 30
+        // 2 -> 1: 10
+        // 4 -> 2 -> 1: 10
+        // 3 -> 4 -> 2 -> 1: 10 (Wider than 3 -> 1: 8)
+        // 5 -> 2 -> 1: 5
+        let mut reachability = cg.get_reachability(&1, 0, None);
+        assert_eq!(reachability[0], (0, 30));
+        assert_eq!(reachability[4], (5, 5));
+        reachability.sort();
+        assert_eq!(
+            reachability,
+            vec![(0, 30), (2, 10), (3, 10), (4, 10), (5, 5)]
+        );
+
+        let mut reachability = cg.get_reachability(&1, 6, None);
+        reachability.sort();
+        assert_eq!(reachability, vec![(0, 30), (2, 10), (3, 10), (4, 10)]);
+        assert_eq!(cg.get_reachability(&1, 11, None), vec![(0, 30)]);
+
+        // Top nodes by capacity:
+        assert_eq!(cg.get_reachability(&1, 0, Some(1)), vec![(0, 30)]);
+        assert_eq!(cg.get_reachability(&1, 0, Some(0)), vec![]);
+
+        // Unknown destination:
+        assert!(cg.get_reachability(&7, 0, None).is_empty());
+    }
+
     #[test]
     fn test_simple_capacity_graph_tick() {
         let mut cg = SimpleCapacityGraph::<u32>::new();
@@ -349,14 +550,48 @@ mod tests {
 
         let max_edge_age = max_edge_age(1);
         for _ in 0..max_edge_age - 1 {
-            cg.tick(&0);
+            assert!(cg.tick(&0).is_empty());
             assert_eq!(cg.get_route(&0, &1, 30, None), Some((vec![0, 1], 30)));
             assert_eq!(cg.get_route(&2, &3, 30, None), Some((vec![2, 3], 30)));
         }
 
         // At this point 0->1 and 1->0 should expire, but 2->3 and 3->2 don't expire:
-        cg.tick(&0);
+        assert_eq!(cg.tick(&0), vec![1]);
         assert_eq!(cg.get_route(&0, &1, 30, None), None);
         assert_eq!(cg.get_route(&2, &3, 30, None), Some((vec![2, 3], 30)));
     }
+
+    #[test]
+    fn test_simple_capacity_graph_from_edges() {
+        let cg = example_capacity_graph();
+        let mut edges = cg.get_edges();
+        edges.sort();
+        assert_eq!(edges.len(), 12);
+        assert_eq!(edges[0], (0, 1, (30, 10), 0));
+
+        let mut cg2 = SimpleCapacityGraph::from_edges(edges.clone());
+        let mut edges2 = cg2.get_edges();
+        edges2.sort();
+        assert_eq!(edges, edges2);
+        assert_eq!(cg2.num_edges(), 12);
+        assert_eq!(cg2.num_nodes(), cg.num_nodes());
+
+        assert_eq!(
+            cg2.get_route(&0, &5, 30, None),
+            Some((vec![0, 1, 3, 4, 2, 5], 30))
+        );
+
+        // Edges keep their age when loaded:
+        let max_edge_age = max_edge_age(1);
+        let mut cg3 = SimpleCapacityGraph::from_edges(vec![
+            (0, 1, (30, 10), max_edge_age - 1),
+            (1, 0, (10, 30), 0),
+        ]);
+        assert_eq!(cg3.get_route(&0, &1, 30, None), Some((vec![0, 1], 30)));
+        cg3.tick(&0);
+        assert_eq!(cg3.get_route(&0, &1, 30, None), None);
+
+        cg2.tick(&0);
+        assert!(cg2.get_edges().contains(&(0, 1, (30, 10), 1)));
+    }
 }
@@ -19,10 +19,27 @@ extern crate log;
 #[macro_use]
 extern crate common;
 
+#[macro_use]
+extern crate serde_derive;
+
+mod admin;
 mod backoff_connector;
+mod ban_list;
+mod export;
 mod graph;
 mod net_server;
 mod server;
+mod snapshot;
+mod token_bucket;
+mod trusted_dir;
 mod verifier;
 
-pub use net_server::{net_index_server, NetIndexServerError};
+pub use admin::{
+    request_admin, AdminError, AdminRequest, AdminResponse, IndexServerStatus, RemoteServerInfo,
+    RemoteServerStatus,
+};
+pub use ban_list::BanConfig;
+pub use export::{edges_to_dot, edges_to_graphml};
+pub use graph::capacity_graph::AgedEdge;
+pub use graph::route_stats::RouteStats;
+pub use net_server::{net_index_server, NetIndexServerError, RoutesLimit};
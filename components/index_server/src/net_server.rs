@@ -1,18 +1,23 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::marker::Unpin;
+use std::path::PathBuf;
 
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::task::{Spawn, SpawnExt};
 use futures::{FutureExt, SinkExt, Stream, StreamExt, TryFutureExt};
 
+use common::canonical_serialize::CanonicalSerialize;
 use common::conn::{BoxFuture, ConnPair, ConnPairVec, FuncFutTransform, FutTransform};
+use common::shutdown::until_shutdown;
 use common::transform_pool::transform_pool_loop;
 
 use proto::consts::{INDEX_NODE_TIMEOUT_TICKS, KEEPALIVE_TICKS, PROTOCOL_VERSION, TICKS_TO_REKEY};
 use proto::index_server::messages::{
     IndexClientToServer, IndexServerToClient, IndexServerToServer,
 };
+use proto::net::messages::NetAddress;
+
 use proto::index_server::serialize::{
     deserialize_index_client_to_server, deserialize_index_server_to_server,
     serialize_index_server_to_client, serialize_index_server_to_server,
@@ -25,54 +30,115 @@ use crypto::identity::{compare_public_key, PublicKey};
 
 use identity::IdentityClient;
 use keepalive::KeepAliveChannel;
-use secure_channel::SecureChannel;
+use secure_channel::{HandshakeMode, SecureChannel};
 use version::VersionPrefix;
 
 use crate::server::{server_loop, ServerLoopError};
-pub use crate::server::{ClientConn, ServerConn};
+pub use crate::server::{ClientConn, RoutesLimit, ServerConn};
 
+use crate::admin::{admin_loop, IncomingAdminRequest};
 use crate::backoff_connector::BackoffConnector;
+use crate::ban_list::BanConfig;
 use crate::graph::graph_service::create_graph_service;
 use crate::graph::simple_capacity_graph::SimpleCapacityGraph;
+use crate::snapshot::{load_snapshot_from_file, snapshot_loop, SnapshotError};
+use crate::trusted_dir::trusted_dir_loop;
 use crate::verifier::simple_verifier::SimpleVerifier;
 
 #[derive(Debug)]
 pub enum IndexServerError {
     RequestTimerStreamError,
     CreateGraphServiceError,
+    LoadSnapshotError(SnapshotError),
+    SpawnError,
     ServerLoopError(ServerLoopError),
 }
 
 /// Run an index server
 /// Will keep running until an error occurs.
-async fn index_server<A, IS, IC, SC, R, GS, S>(
+///
+/// If `opt_snapshot_path` is provided, the state of the index server is restored from this file
+/// (If it exists), and saved to this file every `snapshot_ticks` ticks.
+///
+/// When the incoming connections are closed, we wait up to `drain_ticks` ticks for open queries
+/// to complete, and save a final snapshot before returning.
+async fn index_server<A, IS, IC, IA, SC, R, GS, FS, S>(
     local_public_key: PublicKey,
+    identity_client: IdentityClient,
     trusted_servers: HashMap<PublicKey, A>,
     incoming_server_connections: IS,
     incoming_client_connections: IC,
+    incoming_admin_requests: IA,
     server_connector: SC,
     mut timer_client: TimerClient,
     ticks_to_live: usize,
     backoff_ticks: usize,
+    routes_limit: RoutesLimit,
+    opt_capacity_bucket: Option<u128>,
+    ban_config: BanConfig,
+    discovery: bool,
+    max_discovered_servers: usize,
     rng: R,
+    opt_snapshot_path: Option<PathBuf>,
+    snapshot_ticks: usize,
+    drain_ticks: usize,
     graph_service_spawner: GS,
-    spawner: S,
+    file_spawner: FS,
+    mut spawner: S,
 ) -> Result<(), IndexServerError>
 where
-    A: Debug + Send + Clone + 'static,
-    IS: Stream<Item = (PublicKey, ServerConn)> + Unpin + Send,
+    A: CanonicalSerialize + Debug + Send + Clone + Eq + 'static,
+    IS: Stream<Item = (PublicKey, ServerConn<A>)> + Unpin + Send,
     IC: Stream<Item = (PublicKey, ClientConn)> + Unpin + Send,
-    SC: FutTransform<Input = (PublicKey, A), Output = Option<ServerConn>> + Clone + Send + 'static,
+    IA: Stream<Item = IncomingAdminRequest<A>> + Unpin + Send,
+    SC: FutTransform<Input = (PublicKey, A), Output = Option<ServerConn<A>>>
+        + Clone
+        + Send
+        + 'static,
     R: CryptoRandom,
     S: Spawn + Clone + Send,
     GS: Spawn + Send + 'static,
+    FS: Spawn + Send + 'static,
 {
-    let verifier = SimpleVerifier::new(ticks_to_live, rng);
+    // Load a previously saved snapshot, if exists:
+    let opt_snapshot = match &opt_snapshot_path {
+        Some(snapshot_path) if snapshot_path.exists() => Some(
+            load_snapshot_from_file(snapshot_path).map_err(IndexServerError::LoadSnapshotError)?,
+        ),
+        _ => None,
+    };
+
+    let (capacity_graph, verifier) = match opt_snapshot {
+        Some(snapshot) => {
+            info!("index_server(): Loaded snapshot with {} edges", snapshot.edges.len());
+            (
+                SimpleCapacityGraph::from_edges(snapshot.edges),
+                SimpleVerifier::from_snapshot(ticks_to_live, rng, snapshot.verifier),
+            )
+        }
+        None => (
+            SimpleCapacityGraph::new(),
+            SimpleVerifier::new(ticks_to_live, rng),
+        ),
+    };
 
-    let capacity_graph = SimpleCapacityGraph::new();
     let graph_client = create_graph_service(capacity_graph, graph_service_spawner, spawner.clone())
         .map_err(|_| IndexServerError::CreateGraphServiceError)?;
 
+    let (opt_snapshot_sender, opt_snapshot_loop_handle) = match opt_snapshot_path {
+        Some(snapshot_path) => {
+            let (snapshot_sender, incoming_snapshots) = mpsc::channel(0);
+            let snapshot_loop_fut = snapshot_loop(snapshot_path, incoming_snapshots, file_spawner)
+                .map_err(|e| error!("snapshot_loop() error: {:?}", e))
+                .map(|_| ());
+            let snapshot_loop_handle = spawner
+                .spawn_with_handle(snapshot_loop_fut)
+                .map_err(|_| IndexServerError::SpawnError)?;
+            (Some(snapshot_sender), Some(snapshot_loop_handle))
+        }
+        None => (None, None),
+    };
+
     let timer_stream = await!(timer_client.request_timer_stream())
         .map_err(|_| IndexServerError::RequestTimerStreamError)?;
 
@@ -80,18 +146,34 @@ where
 
     await!(server_loop(
         local_public_key,
+        identity_client,
         trusted_servers,
         incoming_server_connections,
         incoming_client_connections,
+        incoming_admin_requests,
         backoff_connector,
         graph_client,
         compare_public_key,
         verifier,
         timer_stream,
+        routes_limit,
+        opt_capacity_bucket,
+        ban_config,
+        discovery,
+        max_discovered_servers,
+        opt_snapshot_sender,
+        snapshot_ticks,
+        drain_ticks,
         spawner,
         None
     ))
-    .map_err(IndexServerError::ServerLoopError)
+    .map_err(IndexServerError::ServerLoopError)?;
+
+    // Wait for the last snapshot to be written:
+    if let Some(snapshot_loop_handle) = opt_snapshot_loop_handle {
+        await!(snapshot_loop_handle);
+    }
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -316,37 +398,94 @@ pub enum NetIndexServerError {
     SpawnError,
 }
 
-pub async fn net_index_server<A, ICC, ISC, SC, R, GS, S>(
+/// Run an index server over raw network connections.
+///
+/// `incoming_admin_raw_conns` are local connections used to query the state of the index server.
+/// These connections are not encrypted, and should only be accepted from the local machine.
+///
+/// Secure channels are rekeyed after `bytes_to_rekey` bytes or `messages_to_rekey` messages were
+/// encrypted using the same key, whichever comes first.
+///
+/// If `opt_capacity_bucket` is provided, route queries are answered in multiples of this capacity.
+///
+/// Clients that keep sending invalid mutations are temporarily banned according to `ban_config`.
+/// Bans are shared with the trusted index servers.
+///
+/// Trusted index servers can be added and removed at runtime using admin requests. If
+/// `opt_trusted_dir` is provided, these changes are also saved to the trusted servers directory.
+/// If `discovery` is enabled, trusted index servers share their trusted servers with each other,
+/// and we also connect to up to `max_discovered_servers` servers vouched for by our trusted
+/// servers.
+///
+/// Once a shutdown is requested through `shutdown_receiver`, we stop accepting new connections,
+/// wait up to `drain_ticks` ticks for open queries to complete and save a final snapshot.
+pub async fn net_index_server<ICC, ISC, IAC, SC, R, GS, FS, S>(
     incoming_client_raw_conns: ICC,
     incoming_server_raw_conns: ISC,
+    incoming_admin_raw_conns: IAC,
     raw_server_net_connector: SC,
     identity_client: IdentityClient,
     timer_client: TimerClient,
     rng: R,
-    trusted_servers: HashMap<PublicKey, A>,
+    trusted_servers: HashMap<PublicKey, NetAddress>,
+    opt_trusted_dir: Option<PathBuf>,
     max_concurrent_encrypt: usize,
+    bytes_to_rekey: u64,
+    messages_to_rekey: u64,
     backoff_ticks: usize,
+    routes_limit: RoutesLimit,
+    opt_capacity_bucket: Option<u128>,
+    ban_config: BanConfig,
+    discovery: bool,
+    max_discovered_servers: usize,
+    opt_snapshot_path: Option<PathBuf>,
+    snapshot_ticks: usize,
+    shutdown_receiver: oneshot::Receiver<()>,
+    drain_ticks: usize,
     graph_service_spawner: GS,
+    file_spawner: FS,
     mut spawner: S,
 ) -> Result<(), NetIndexServerError>
 where
-    A: Clone + Send + Debug + 'static,
-    SC: FutTransform<Input = A, Output = Option<ConnPairVec>> + Clone + Send + 'static,
+    SC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>> + Clone + Send + 'static,
     ICC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
     ISC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
+    IAC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
     R: CryptoRandom + Clone + 'static,
     GS: Spawn + Send + 'static,
+    FS: Spawn + Clone + Send + 'static,
     S: Spawn + Clone + Send + Sync + 'static,
 {
     let local_public_key = await!(identity_client.request_public_key())
         .map_err(|_| NetIndexServerError::RequestPublicKeyError)?;
 
+    // Stop accepting new client and server connections once a shutdown is requested:
+    let (client_shutdown_sender, client_shutdown_receiver) = oneshot::channel();
+    let (server_shutdown_sender, server_shutdown_receiver) = oneshot::channel();
+    let shutdown_fut = shutdown_receiver.map(|res| {
+        if res.is_ok() {
+            let _ = client_shutdown_sender.send(());
+            let _ = server_shutdown_sender.send(());
+        }
+    });
+    spawner
+        .spawn(shutdown_fut)
+        .map_err(|_| NetIndexServerError::SpawnError)?;
+    let incoming_client_raw_conns =
+        until_shutdown(incoming_client_raw_conns, client_shutdown_receiver);
+    let incoming_server_raw_conns =
+        until_shutdown(incoming_server_raw_conns, server_shutdown_receiver);
+
     let version_transform = VersionPrefix::new(PROTOCOL_VERSION, spawner.clone());
+    // The hybrid (post-quantum) handshake is used with clients and servers that offer it:
     let encrypt_transform = SecureChannel::new(
-        identity_client,
+        identity_client.clone(),
         rng.clone(),
         timer_client.clone(),
         TICKS_TO_REKEY,
+        bytes_to_rekey,
+        messages_to_rekey,
+        HandshakeMode::Negotiate,
         spawner.clone(),
     );
 
@@ -404,6 +543,40 @@ where
         .spawn(pool_fut)
         .map_err(|_| NetIndexServerError::SpawnError)?;
 
+    // Serve local admin requests:
+    let (admin_request_sender, incoming_admin_requests) = mpsc::channel(0);
+    let admin_loop_fut = admin_loop(
+        incoming_admin_raw_conns,
+        admin_request_sender,
+        spawner.clone(),
+    )
+    .map_err(|e| error!("admin_loop() error: {:?}", e))
+    .map(|_| ());
+    spawner
+        .spawn(admin_loop_fut)
+        .map_err(|_| NetIndexServerError::SpawnError)?;
+
+    // Save changes of the trusted servers made through admin requests:
+    let incoming_admin_requests = match opt_trusted_dir {
+        Some(trusted_dir) => {
+            let (admin_request_sender, trusted_admin_requests) = mpsc::channel(0);
+            let trusted_dir_loop_fut = trusted_dir_loop(
+                trusted_dir,
+                local_public_key.clone(),
+                incoming_admin_requests,
+                admin_request_sender,
+                file_spawner.clone(),
+            )
+            .map_err(|e| error!("trusted_dir_loop() error: {:?}", e))
+            .map(|_| ());
+            spawner
+                .spawn(trusted_dir_loop_fut)
+                .map_err(|_| NetIndexServerError::SpawnError)?;
+            trusted_admin_requests
+        }
+        None => incoming_admin_requests,
+    };
+
     // Apply transform to create server connector:
     let c_conn_transformer = conn_transformer.clone();
     let server_connector = FuncFutTransform::new(move |(public_key, net_address)| {
@@ -419,15 +592,26 @@ where
 
     await!(index_server(
         local_public_key,
+        identity_client,
         trusted_servers,
         incoming_server_conns,
         incoming_client_conns,
+        incoming_admin_requests,
         server_connector,
         timer_client,
         INDEX_NODE_TIMEOUT_TICKS,
         backoff_ticks,
+        routes_limit,
+        opt_capacity_bucket,
+        ban_config,
+        discovery,
+        max_discovered_servers,
         rng,
+        opt_snapshot_path,
+        snapshot_ticks,
+        drain_ticks,
         graph_service_spawner,
+        file_spawner,
         spawner.clone()
     ))
     .map_err(NetIndexServerError::IndexServerError)
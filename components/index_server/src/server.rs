@@ -1,30 +1,52 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::marker::Unpin;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use futures::channel::{mpsc, oneshot};
 use futures::task::{Spawn, SpawnExt};
 use futures::{future, select, stream, FutureExt, SinkExt, Stream, StreamExt, TryFutureExt};
 
+use common::canonical_serialize::CanonicalSerialize;
 use common::conn::{ConnPair, FutTransform};
+use common::int_convert::u32_to_usize;
 use common::select_streams::{select_streams, BoxStream};
 
-use crypto::identity::PublicKey;
+use crypto::hash::{HashResult, HASH_RESULT_LEN};
+use crypto::identity::{PublicKey, Signature};
 use crypto::uid::Uid;
 
+use identity::IdentityClient;
+
+use proto::index_server::capacity_bucket::{bucket_ceil, bucket_floor, bucket_mutation};
 use proto::index_server::messages::{
     ForwardMutationsUpdate, IndexClientToServer, IndexMutation, IndexServerToClient,
-    IndexServerToServer, MutationsUpdate, ResponseRoutes, RouteWithCapacity, TimeProofLink,
+    IndexServerAddress, IndexServerToServer, MutationsUpdate, NodeMutations, NodeWithCapacity,
+    ResponseReachability, ResponseRoutes, ResponseRoutesSignature, RouteWithCapacity,
+    SignedIndexServerAddress, TimeProofLink, UpdateFriend,
 };
+use proto::index_server::signature_buff::response_routes_signature_buff;
 
 use proto::funder::messages::FriendsRoute;
 
+use crate::admin::{
+    AdminRequest, AdminResponse, IncomingAdminRequest, IndexServerStatus, RemoteServerInfo,
+    RemoteServerStatus,
+};
+use crate::ban_list::{BanConfig, BanList};
+use crate::graph::capacity_graph::AgedEdge;
 use crate::graph::graph_service::{GraphClient, GraphClientError};
+use crate::snapshot::IndexServerSnapshot;
+use crate::token_bucket::TokenBucket;
 use crate::verifier::Verifier;
 
-pub type ServerConn = ConnPair<IndexServerToServer, IndexServerToServer>;
+pub type ServerConn<A> = ConnPair<IndexServerToServer<A>, IndexServerToServer<A>>;
 pub type ClientConn = ConnPair<IndexServerToClient, IndexClientToServer>;
 
+/// Maximum amount of graph messages waiting to be sent to a subscribed client.
+/// A subscribed client that falls further behind is unsubscribed.
+const SUBSCRIPTION_BUFFER: usize = 0x100;
+
 #[derive(Debug)]
 pub enum ServerLoopError {
     SpawnError,
@@ -33,6 +55,19 @@ pub enum ServerLoopError {
     ClientEventSenderError,
     ClientSenderError,
     RemoteSendError,
+    PermitCanceled,
+    RequestSignatureError,
+}
+
+/// Limits on the route requests served by the index server
+#[derive(Debug, Clone)]
+pub struct RoutesLimit {
+    /// Amount of route requests a client may send every tick
+    pub client_requests_per_tick: usize,
+    /// Maximum amount of route requests a client may send in a burst
+    pub client_max_burst: usize,
+    /// Maximum amount of graph queries in progress at the same time (For all clients)
+    pub max_concurrent_queries: usize,
 }
 
 /// A connected remote entity
@@ -65,6 +100,11 @@ impl<T> Connected<T> {
         }
         Ok(())
     }
+
+    /// Get another sender to the remote entity (If no send failure occurred)
+    pub fn clone_sender(&self) -> Option<mpsc::Sender<T>> {
+        self.opt_sender.clone()
+    }
 }
 
 #[derive(Debug)]
@@ -74,8 +114,8 @@ struct ServerInitiating {
 }
 
 #[derive(Debug)]
-enum RemoteServerState {
-    Connected(Connected<IndexServerToServer>),
+enum RemoteServerState<A> {
+    Connected(Connected<IndexServerToServer<A>>),
     Initiating(ServerInitiating),
     Listening,
 }
@@ -83,18 +123,53 @@ enum RemoteServerState {
 #[derive(Debug)]
 struct RemoteServer<A> {
     address: A,
-    state: RemoteServerState,
+    state: RemoteServerState<A>,
+    /// The trusted server that vouched for this server (If this server was discovered through
+    /// peer lists).
+    /// Discovered servers are trusted less than directly trusted servers: We only accept time
+    /// hashes from them. Their mutations and bans are ignored.
+    opt_voucher: Option<PublicKey>,
 }
 
 struct IndexServer<A, S, SC, V, CMP> {
     local_public_key: PublicKey,
+    identity_client: IdentityClient,
     server_connector: SC,
     graph_client: GraphClient<PublicKey, u128>,
     verifier: V,
     compare_public_key: CMP,
     remote_servers: HashMap<PublicKey, RemoteServer<A>>,
     clients: HashMap<PublicKey, Connected<IndexServerToClient>>,
-    event_sender: mpsc::Sender<IndexServerEvent>,
+    /// Clients subscribed to graph changes, together with a queue of messages to send them
+    subscribers: HashMap<PublicKey, mpsc::Sender<IndexServerToClient>>,
+    event_sender: mpsc::Sender<IndexServerEvent<A>>,
+    /// Last time hash sent to clients. Included in signed routes responses.
+    time_hash: HashResult,
+    routes_limit: RoutesLimit,
+    /// Privacy mode: If set, route queries are answered in multiples of this capacity
+    opt_capacity_bucket: Option<u128>,
+    /// Violation scores and temporary bans of misbehaving nodes
+    ban_list: BanList<PublicKey>,
+    /// Share our trusted servers with our trusted servers, and connect to servers they vouch for
+    discovery: bool,
+    /// Maximum amount of discovered servers we keep at the same time
+    max_discovered_servers: usize,
+    /// Sequence number of the last peer list we have sent
+    peer_list_seq: u64,
+    /// Sequence number of the last peer list received from every trusted server.
+    /// Used to reject replayed peer lists. Kept across reconnections.
+    peer_list_seqs: HashMap<PublicKey, u64>,
+    /// Route requests budget for every client.
+    /// Kept for disconnected clients until refilled, to avoid resets by reconnecting.
+    client_buckets: HashMap<PublicKey, TokenBucket>,
+    /// Clients with a graph query in progress
+    open_queries: HashSet<PublicKey>,
+    /// Used to save snapshots of our state (If persistence is enabled)
+    opt_snapshot_sender: Option<mpsc::Sender<IndexServerSnapshot>>,
+    /// Amount of ticks between two consecutive snapshots
+    snapshot_ticks: usize,
+    /// Amount of ticks left until the next snapshot
+    ticks_to_snapshot: usize,
     spawner: S,
 }
 
@@ -106,12 +181,21 @@ impl From<GraphClientError> for ServerLoopError {
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
-enum IndexServerEvent {
-    ServerConnection((PublicKey, ServerConn)),
-    FromServer((PublicKey, Option<IndexServerToServer>)),
+enum IndexServerEvent<A> {
+    ServerConnection((PublicKey, ServerConn<A>)),
+    FromServer((PublicKey, Option<IndexServerToServer<A>>)),
     ClientConnection((PublicKey, ClientConn)),
     ClientClosed(PublicKey),
-    ClientMutationsUpdate(MutationsUpdate),
+    /// Mutations sent by a connected client (Identified by its public key)
+    ClientMutationsUpdate((PublicKey, MutationsUpdate)),
+    /// A client subscribes to graph changes
+    ClientSubscribe(PublicKey),
+    /// A client asks for a permission to query the graph for routes.
+    /// The response contains the permission, and the current time hash.
+    RoutesPermit((PublicKey, oneshot::Sender<(bool, HashResult)>)),
+    /// A client finished a graph query
+    RoutesQueryDone(PublicKey),
+    AdminRequest(IncomingAdminRequest<A>),
     TimerTick,
     ClientListenerClosed,
     ServerListenerClosed,
@@ -133,39 +217,63 @@ fn is_listen_server(local_public_key: &PublicKey, friend_public_key: &PublicKey)
 
 impl<A, S, SC, V, CMP> IndexServer<A, S, SC, V, CMP>
 where
-    A: Clone + Send + std::fmt::Debug + 'static,
+    A: CanonicalSerialize + Clone + Eq + Send + std::fmt::Debug + 'static,
     S: Spawn + Send,
-    SC: FutTransform<Input = (PublicKey, A), Output = Option<ServerConn>> + Clone + Send + 'static,
+    SC: FutTransform<Input = (PublicKey, A), Output = Option<ServerConn<A>>>
+        + Clone
+        + Send
+        + 'static,
     V: Verifier<Node = PublicKey, Neighbor = PublicKey, SessionId = Uid>,
     CMP: Clone + Fn(&PublicKey, &PublicKey) -> Ordering,
 {
     pub fn new(
         local_public_key: PublicKey,
+        identity_client: IdentityClient,
         trusted_servers: HashMap<PublicKey, A>,
         server_connector: SC,
         graph_client: GraphClient<PublicKey, u128>,
         compare_public_key: CMP,
         verifier: V,
-        event_sender: mpsc::Sender<IndexServerEvent>,
+        event_sender: mpsc::Sender<IndexServerEvent<A>>,
+        routes_limit: RoutesLimit,
+        opt_capacity_bucket: Option<u128>,
+        ban_config: BanConfig,
+        discovery: bool,
+        max_discovered_servers: usize,
+        opt_snapshot_sender: Option<mpsc::Sender<IndexServerSnapshot>>,
+        snapshot_ticks: usize,
         spawner: S,
     ) -> Result<Self, ServerLoopError> {
         let mut index_server = IndexServer {
             local_public_key,
+            identity_client,
             server_connector,
             graph_client,
             verifier,
             compare_public_key,
             remote_servers: HashMap::new(),
             clients: HashMap::new(),
+            subscribers: HashMap::new(),
             event_sender,
+            // No time hash was sent to clients yet:
+            time_hash: HashResult::from(&[0; HASH_RESULT_LEN]),
+            routes_limit,
+            opt_capacity_bucket,
+            ban_list: BanList::new(ban_config),
+            discovery,
+            max_discovered_servers,
+            peer_list_seq: 0,
+            peer_list_seqs: HashMap::new(),
+            client_buckets: HashMap::new(),
+            open_queries: HashSet::new(),
+            opt_snapshot_sender,
+            snapshot_ticks,
+            ticks_to_snapshot: snapshot_ticks,
             spawner,
         };
 
         for (public_key, address) in trusted_servers.into_iter() {
-            let remote_server = index_server.spawn_server(public_key.clone(), address)?;
-            index_server
-                .remote_servers
-                .insert(public_key, remote_server);
+            index_server.add_remote_server(public_key, address, None)?;
         }
         Ok(index_server)
     }
@@ -173,7 +281,7 @@ where
     /// Iterate over all connected servers
     fn iter_connected_servers(
         &mut self,
-    ) -> impl Iterator<Item = (&PublicKey, &mut Connected<IndexServerToServer>)> {
+    ) -> impl Iterator<Item = (&PublicKey, &mut Connected<IndexServerToServer<A>>)> {
         self.remote_servers
             .iter_mut()
             .filter_map(
@@ -190,11 +298,13 @@ where
         &mut self,
         public_key: PublicKey,
         address: A,
+        opt_voucher: Option<PublicKey>,
     ) -> Result<RemoteServer<A>, ServerLoopError> {
         if (self.compare_public_key)(&self.local_public_key, &public_key) == Ordering::Less {
             return Ok(RemoteServer {
                 address,
                 state: RemoteServerState::Listening,
+                opt_voucher,
             });
         }
 
@@ -229,21 +339,317 @@ where
 
         let state = RemoteServerState::Initiating(ServerInitiating { close_sender });
 
-        Ok(RemoteServer { address, state })
+        Ok(RemoteServer {
+            address,
+            state,
+            opt_voucher,
+        })
+    }
+
+    /// Start trusting a remote server. Assumes that the server is not already trusted.
+    fn add_remote_server(
+        &mut self,
+        public_key: PublicKey,
+        address: A,
+        opt_voucher: Option<PublicKey>,
+    ) -> Result<(), ServerLoopError> {
+        let remote_server = self.spawn_server(public_key.clone(), address, opt_voucher)?;
+        self.remote_servers.insert(public_key, remote_server);
+        Ok(())
+    }
+
+    /// Stop trusting a remote server.
+    /// Dropping the remote server closes the connection (Or the connection attempt) to it.
+    fn remove_remote_server(&mut self, public_key: &PublicKey) -> Option<RemoteServer<A>> {
+        let opt_remote_server = self.remote_servers.remove(public_key);
+        if opt_remote_server.is_some() {
+            let _ = self.verifier.remove_neighbor(public_key);
+        }
+        opt_remote_server
+    }
+
+    /// Start trusting a remote server (Requested by the admin).
+    /// Returns false if the server was already trusted.
+    async fn handle_add_server(
+        &mut self,
+        index_server_address: IndexServerAddress<A>,
+    ) -> Result<bool, ServerLoopError> {
+        let IndexServerAddress {
+            public_key,
+            address,
+        } = index_server_address;
+
+        if public_key == self.local_public_key {
+            warn!("handle_add_server(): Attempt to add the local server. Ignoring.");
+            return Ok(false);
+        }
+
+        let is_new = match self.remote_servers.get_mut(&public_key) {
+            Some(remote_server) => {
+                if remote_server.address == address {
+                    // A discovered server becomes a directly trusted server:
+                    remote_server.opt_voucher = None;
+                } else {
+                    // Reconnect using the new address:
+                    self.remove_remote_server(&public_key);
+                    self.add_remote_server(public_key, address, None)?;
+                }
+                false
+            }
+            None => {
+                self.add_remote_server(public_key, address, None)?;
+                true
+            }
+        };
+
+        await!(self.broadcast_peer_list())?;
+        Ok(is_new)
+    }
+
+    /// Stop trusting a remote server (Requested by the admin).
+    /// Servers discovered through the removed server are removed too.
+    /// Returns false if the server was not trusted.
+    async fn handle_remove_server(
+        &mut self,
+        public_key: PublicKey,
+    ) -> Result<bool, ServerLoopError> {
+        if self.remove_remote_server(&public_key).is_none() {
+            return Ok(false);
+        }
+
+        let discovered_servers = self
+            .remote_servers
+            .iter()
+            .filter(|(_, remote_server)| remote_server.opt_voucher.as_ref() == Some(&public_key))
+            .map(|(server_public_key, _)| server_public_key.clone())
+            .collect::<Vec<_>>();
+        for server_public_key in discovered_servers {
+            self.remove_remote_server(&server_public_key);
+        }
+
+        await!(self.broadcast_peer_list())?;
+        Ok(true)
+    }
+
+    /// Get a new sequence number for a peer list.
+    /// Sequence numbers are based on the current time, so that they keep increasing after a
+    /// restart.
+    fn next_peer_list_seq(&mut self) -> u64 {
+        let now_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| {
+                duration
+                    .as_secs()
+                    .saturating_mul(1_000)
+                    .saturating_add(u64::from(duration.subsec_millis()))
+            })
+            .unwrap_or(0);
+        self.peer_list_seq = std::cmp::max(self.peer_list_seq.saturating_add(1), now_millis);
+        self.peer_list_seq
+    }
+
+    /// Sign the addresses of all our directly trusted servers.
+    async fn create_peer_list(
+        &mut self,
+    ) -> Result<Vec<SignedIndexServerAddress<A>>, ServerLoopError> {
+        let mut trusted_servers = self
+            .remote_servers
+            .iter()
+            .filter(|(_, remote_server)| remote_server.opt_voucher.is_none())
+            .map(|(public_key, remote_server)| (public_key.clone(), remote_server.address.clone()))
+            .collect::<Vec<_>>();
+        trusted_servers.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let seq = self.next_peer_list_seq();
+        let mut peer_list = Vec::new();
+        for (public_key, address) in trusted_servers {
+            let mut signed_index_server_address = SignedIndexServerAddress {
+                public_key,
+                address,
+                seq,
+                signature: Signature::zero(),
+            };
+            signed_index_server_address.signature = await!(self
+                .identity_client
+                .request_signature(signed_index_server_address.signature_buff()))
+            .map_err(|_| ServerLoopError::RequestSignatureError)?;
+            peer_list.push(signed_index_server_address);
+        }
+        Ok(peer_list)
+    }
+
+    /// Send our peer list to connected servers (If discovery is enabled).
+    /// If `opt_server_public_key` is provided, the peer list is sent only to this server.
+    ///
+    /// Peer lists are sent only to directly trusted servers. A server does not receive its own
+    /// address.
+    async fn send_peer_list(
+        &mut self,
+        opt_server_public_key: Option<PublicKey>,
+    ) -> Result<(), ServerLoopError> {
+        if !self.discovery {
+            return Ok(());
+        }
+
+        let peer_list = await!(self.create_peer_list())?;
+        for (server_public_key, remote_server) in &mut self.remote_servers {
+            if remote_server.opt_voucher.is_some() {
+                continue;
+            }
+            if let Some(wanted_public_key) = &opt_server_public_key {
+                if wanted_public_key != server_public_key {
+                    continue;
+                }
+            }
+            if let RemoteServerState::Connected(server_connected) = &mut remote_server.state {
+                let server_peer_list = peer_list
+                    .iter()
+                    .filter(|signed_index_server_address| {
+                        &signed_index_server_address.public_key != server_public_key
+                    })
+                    .cloned()
+                    .collect();
+                let _ = server_connected.try_send(IndexServerToServer::PeerList(server_peer_list));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send our peer list to all connected servers (If discovery is enabled).
+    async fn broadcast_peer_list(&mut self) -> Result<(), ServerLoopError> {
+        await!(self.send_peer_list(None))
+    }
+
+    /// Handle a list of servers vouched for by a trusted server.
+    /// The list replaces the previous list sent by the same server.
+    /// Entries that are not newer than the last list received from the server are skipped. A list
+    /// with no newer entries at all is ignored.
+    /// We keep at most `max_discovered_servers` discovered servers.
+    fn handle_peer_list(
+        &mut self,
+        server_public_key: PublicKey,
+        peer_list: Vec<SignedIndexServerAddress<A>>,
+    ) -> Result<(), ServerLoopError> {
+        if !self.discovery {
+            return Ok(());
+        }
+
+        // Only directly trusted servers may vouch for other servers:
+        match self.remote_servers.get(&server_public_key) {
+            Some(remote_server) if remote_server.opt_voucher.is_none() => {}
+            _ => {
+                warn!(
+                    "handle_peer_list(): Server {:?} is not directly trusted. Ignoring peer list.",
+                    server_public_key
+                );
+                return Ok(());
+            }
+        }
+
+        let last_seq = self
+            .peer_list_seqs
+            .get(&server_public_key)
+            .cloned()
+            .unwrap_or(0);
+        let mut max_seq = last_seq;
+        let mut vouched_servers = HashMap::new();
+        let is_empty = peer_list.is_empty();
+        for signed_index_server_address in peer_list {
+            if !signed_index_server_address.verify_signature(&server_public_key) {
+                warn!(
+                    "handle_peer_list(): Invalid signature for server {:?} (Vouched by {:?})",
+                    signed_index_server_address.public_key, server_public_key
+                );
+                continue;
+            }
+            if signed_index_server_address.seq <= last_seq {
+                warn!(
+                    "handle_peer_list(): Outdated entry for {:?} (Vouched by {:?})",
+                    signed_index_server_address.public_key, server_public_key
+                );
+                continue;
+            }
+            max_seq = std::cmp::max(max_seq, signed_index_server_address.seq);
+            if signed_index_server_address.public_key == self.local_public_key {
+                continue;
+            }
+            vouched_servers.insert(
+                signed_index_server_address.public_key,
+                signed_index_server_address.address,
+            );
+        }
+        if !is_empty && max_seq == last_seq {
+            warn!(
+                "handle_peer_list(): Outdated peer list from server {:?}. Ignoring.",
+                server_public_key
+            );
+            return Ok(());
+        }
+        self.peer_list_seqs.insert(server_public_key.clone(), max_seq);
+
+        // Forget servers this server does not vouch for anymore (Or whose address has changed):
+        let stale_servers = self
+            .remote_servers
+            .iter()
+            .filter(|(public_key, remote_server)| {
+                remote_server.opt_voucher.as_ref() == Some(&server_public_key)
+                    && vouched_servers.get(*public_key) != Some(&remote_server.address)
+            })
+            .map(|(public_key, _)| public_key.clone())
+            .collect::<Vec<_>>();
+        for public_key in stale_servers {
+            info!("handle_peer_list(): Removing discovered server {:?}", public_key);
+            self.remove_remote_server(&public_key);
+        }
+
+        let mut num_discovered = self
+            .remote_servers
+            .values()
+            .filter(|remote_server| remote_server.opt_voucher.is_some())
+            .count();
+        for (public_key, address) in vouched_servers {
+            if self.remote_servers.contains_key(&public_key) {
+                continue;
+            }
+            if num_discovered >= self.max_discovered_servers {
+                warn!(
+                    "handle_peer_list(): Too many discovered servers. Ignoring server {:?}",
+                    public_key
+                );
+                continue;
+            }
+            num_discovered += 1;
+            info!(
+                "handle_peer_list(): Discovered server {:?} (Vouched by {:?})",
+                public_key, server_public_key
+            );
+            self.add_remote_server(public_key, address, Some(server_public_key.clone()))?;
+        }
+        Ok(())
     }
 
+    /// Verify, apply and forward a MutationsUpdate message.
+    /// Returns false if the message failed verification.
     pub async fn handle_forward_mutations_update(
         &mut self,
         opt_server_public_key: Option<PublicKey>,
         mut forward_mutations_update: ForwardMutationsUpdate,
-    ) -> Result<(), ServerLoopError> {
+    ) -> Result<bool, ServerLoopError> {
+        // Mutations of banned nodes are ignored.
+        // This is not a violation of the sender, as the message might have been sent before the
+        // ban took place:
+        let node_public_key = &forward_mutations_update.mutations_update.node_public_key;
+        if self.ban_list.is_banned(node_public_key) {
+            return Ok(true);
+        }
+
         // Check the signature:
         if !forward_mutations_update.mutations_update.verify_signature() {
             warn!(
                 "{}: handle_forward_mutations_update: Failed verifying signature from server {:?}",
                 self.local_public_key[0], opt_server_public_key
             );
-            return Ok(());
+            return Ok(false);
         }
 
         // Make sure that the signature is fresh, and that the message is not out of order:
@@ -268,19 +674,20 @@ where
                 warn!("{}: handle_forward_mutations_update: Failed verifying message from server {:?}",
                       self.local_public_key[0],
                       opt_server_public_key);
-                return Ok(());
+                return Ok(false);
             }
         };
 
         // The message is valid and fresh.
 
-        // Expire old edges for `node_public_key`:
+        // Expire old edges for `node_public_key`, and let subscribed clients know about them:
         // Note: This tick happens every time a message is received from this `node_public_key`,
         // and not every constant amount of time.
-        await!(self
+        let expired_friends = await!(self
             .graph_client
             .tick(mutations_update.node_public_key.clone()))
         .map_err(|_| ServerLoopError::GraphClientError)?;
+        self.send_removed_friends(mutations_update.node_public_key.clone(), expired_friends);
 
         // Add a link to the time proof:
         forward_mutations_update
@@ -315,6 +722,21 @@ where
             }
         }
 
+        // Let subscribed clients know about the change:
+        if !self.subscribers.is_empty() {
+            let opt_capacity_bucket = self.opt_capacity_bucket;
+            let node_mutations = NodeMutations {
+                node_public_key: mutations_update.node_public_key.clone(),
+                index_mutations: mutations_update
+                    .index_mutations
+                    .iter()
+                    .cloned()
+                    .map(|index_mutation| bucket_mutation(index_mutation, opt_capacity_bucket))
+                    .collect(),
+            };
+            self.send_graph_update(node_mutations);
+        }
+
         // Try to forward to all connected servers:
         for (server_public_key, connected_server) in self.iter_connected_servers() {
             if Some(server_public_key) == opt_server_public_key.as_ref() {
@@ -325,28 +747,204 @@ where
                 forward_mutations_update.clone(),
             ));
         }
+        Ok(true)
+    }
+
+    pub async fn handle_client_mutations_update(
+        &mut self,
+        client_public_key: PublicKey,
+        mutations_update: MutationsUpdate,
+    ) -> Result<(), ServerLoopError> {
+        // Banned clients may not submit mutations:
+        if self.ban_list.is_banned(&client_public_key) {
+            return Ok(());
+        }
+
+        let forward_mutations_update = ForwardMutationsUpdate {
+            mutations_update,
+            time_proof_chain: Vec::new(),
+        };
+        // Note that we blame the client that sent an invalid message, and not the node that
+        // supposedly signed it. Otherwise anyone could get a node banned by replaying its old
+        // messages.
+        if !await!(self.handle_forward_mutations_update(None, forward_mutations_update))? {
+            await!(self.add_violation(client_public_key))?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe a client to graph changes.
+    /// The client first receives a snapshot of the graph, and then all the verified changes.
+    /// Subscribing again is ignored, as every snapshot costs a full pass over the graph.
+    pub async fn handle_client_subscribe(
+        &mut self,
+        public_key: PublicKey,
+    ) -> Result<(), ServerLoopError> {
+        if self.subscribers.contains_key(&public_key) {
+            return Ok(());
+        }
+
+        let opt_client_sender = self
+            .clients
+            .get(&public_key)
+            .and_then(|connected_client| connected_client.clone_sender());
+        let mut client_sender = match opt_client_sender {
+            Some(client_sender) => client_sender,
+            None => {
+                warn!(
+                    "handle_client_subscribe(): Client {:?} is not connected",
+                    public_key
+                );
+                return Ok(());
+            }
+        };
+
+        // Graph messages are queued and sent to the client by a separate task, so that a slow
+        // client will not block the server:
+        let (subscription_sender, mut subscription_receiver) = mpsc::channel(SUBSCRIPTION_BUFFER);
+        self.spawner
+            .spawn(
+                async move {
+                    let _ = await!(client_sender.send_all(&mut subscription_receiver));
+                },
+            )
+            .map_err(|_| ServerLoopError::SpawnError)?;
+        self.subscribers
+            .insert(public_key.clone(), subscription_sender);
+
+        let edges = await!(self.graph_client.get_edges())?;
+        let graph_snapshot = edges_to_nodes_mutations(edges, self.opt_capacity_bucket);
+
+        if let Some(subscription_sender) = self.subscribers.get_mut(&public_key) {
+            if subscription_sender
+                .try_send(IndexServerToClient::GraphSnapshot(graph_snapshot))
+                .is_err()
+            {
+                warn!(
+                    "handle_client_subscribe(): Client {:?} fell behind. Unsubscribing.",
+                    public_key
+                );
+                self.subscribers.remove(&public_key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue a graph change to be sent to all subscribed clients.
+    /// Clients that fell behind are unsubscribed.
+    fn send_graph_update(&mut self, node_mutations: NodeMutations) {
+        self.subscribers.retain(|public_key, subscription_sender| {
+            let message = IndexServerToClient::GraphUpdate(node_mutations.clone());
+            if subscription_sender.try_send(message).is_err() {
+                warn!(
+                    "send_graph_update(): Client {:?} fell behind. Unsubscribing.",
+                    public_key
+                );
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Remove a node (and all the edges known from it) from the graph.
+    /// Subscribed clients are told about the removed edges.
+    async fn remove_graph_node(&mut self, public_key: PublicKey) -> Result<(), ServerLoopError> {
+        let removed_friends = await!(self.graph_client.remove_node(public_key.clone()))?;
+        self.send_removed_friends(public_key, removed_friends);
+        Ok(())
+    }
+
+    /// Tell subscribed clients about edges that were removed from the graph.
+    fn send_removed_friends(&mut self, public_key: PublicKey, removed_friends: Vec<PublicKey>) {
+        if self.subscribers.is_empty() || removed_friends.is_empty() {
+            return;
+        }
+        let node_mutations = NodeMutations {
+            node_public_key: public_key,
+            index_mutations: removed_friends
+                .into_iter()
+                .map(IndexMutation::RemoveFriend)
+                .collect(),
+        };
+        self.send_graph_update(node_mutations);
+    }
+
+    /// Increase the violation score of a node, possibly banning it.
+    async fn add_violation(&mut self, public_key: PublicKey) -> Result<(), ServerLoopError> {
+        if self.ban_list.add_violation(&public_key) {
+            warn!("add_violation(): Banning node {:?}", public_key);
+            await!(self.apply_ban(None, public_key))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a newly banned node from the graph, and let all trusted servers know about the ban.
+    /// Bans are not sent back to the server that told us about the ban.
+    async fn apply_ban(
+        &mut self,
+        opt_server_public_key: Option<PublicKey>,
+        public_key: PublicKey,
+    ) -> Result<(), ServerLoopError> {
+        await!(self.remove_graph_node(public_key.clone()))?;
+
+        for (server_public_key, connected_server) in self.iter_connected_servers() {
+            if Some(server_public_key) == opt_server_public_key.as_ref() {
+                continue;
+            }
+            let _ = connected_server.try_send(IndexServerToServer::BanNode(public_key.clone()));
+        }
         Ok(())
     }
 
     pub async fn handle_from_server(
         &mut self,
         public_key: PublicKey,
-        server_msg: IndexServerToServer,
+        server_msg: IndexServerToServer<A>,
     ) -> Result<(), ServerLoopError> {
+        // The server might have been removed from the trusted servers:
+        let is_discovered = match self.remote_servers.get(&public_key) {
+            Some(remote_server) => remote_server.opt_voucher.is_some(),
+            None => return Ok(()),
+        };
+
         match server_msg {
             IndexServerToServer::TimeHash(time_hash) => {
                 let _ = self.verifier.neighbor_tick(public_key, time_hash);
             }
+            IndexServerToServer::ForwardMutationsUpdate(_) | IndexServerToServer::BanNode(_)
+                if is_discovered =>
+            {
+                // Only directly trusted servers may change our graph or ban nodes:
+                warn!(
+                    "handle_from_server(): Ignoring message from discovered server {:?}",
+                    public_key
+                );
+            }
             IndexServerToServer::ForwardMutationsUpdate(forward_mutations_update) => {
                 await!(self
                     .handle_forward_mutations_update(Some(public_key), forward_mutations_update))?;
             }
+            IndexServerToServer::BanNode(node_public_key) => {
+                // Trusted servers' bans are accepted without further checks.
+                // We only forward bans that are new to us, to avoid forwarding loops:
+                if self.ban_list.ban(node_public_key.clone()) {
+                    warn!(
+                        "handle_from_server(): Banning node {:?} (Reported by server {:?})",
+                        node_public_key, public_key
+                    );
+                    await!(self.apply_ban(Some(public_key), node_public_key))?;
+                }
+            }
+            IndexServerToServer::PeerList(peer_list) => {
+                self.handle_peer_list(public_key, peer_list)?;
+            }
         };
         Ok(())
     }
 
     pub async fn handle_timer_tick(&mut self) -> Result<(), ServerLoopError> {
         let (time_hash, removed_nodes) = self.verifier.tick();
+        self.time_hash = time_hash.clone();
 
         // Try to send the time tick to all servers. Sending to some of them might fail:
         for (_server_public_key, connected_server) in self.iter_connected_servers() {
@@ -360,18 +958,192 @@ where
 
         // Update the graph service about removed nodes:
         for node_public_key in removed_nodes {
-            await!(self.graph_client.remove_node(node_public_key))?;
+            await!(self.remove_graph_node(node_public_key))?;
+        }
+
+        for node_public_key in self.ban_list.tick() {
+            info!("handle_timer_tick(): Ban of node {:?} expired", node_public_key);
+        }
+
+        // Refill route requests budgets.
+        // Full budgets of disconnected clients are not needed anymore:
+        let clients = &self.clients;
+        self.client_buckets.retain(|public_key, token_bucket| {
+            token_bucket.tick();
+            !token_bucket.is_full() || clients.contains_key(public_key)
+        });
+
+        // Periodically save a snapshot of our state:
+        if self.opt_snapshot_sender.is_some() {
+            self.ticks_to_snapshot = self.ticks_to_snapshot.saturating_sub(1);
+            if self.ticks_to_snapshot == 0 {
+                self.ticks_to_snapshot = self.snapshot_ticks;
+                await!(self.send_snapshot())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decide whether a client may query the graph for routes.
+    /// A permitted client must report completion of the query using a `RoutesQueryDone` event.
+    fn routes_permit(&mut self, public_key: &PublicKey) -> bool {
+        if self.open_queries.len() >= self.routes_limit.max_concurrent_queries {
+            warn!("routes_permit(): Too many concurrent queries. Rejecting request.");
+            return false;
+        }
+
+        let routes_limit = &self.routes_limit;
+        let token_bucket = self
+            .client_buckets
+            .entry(public_key.clone())
+            .or_insert_with(|| {
+                TokenBucket::new(
+                    routes_limit.client_max_burst,
+                    routes_limit.client_requests_per_tick,
+                )
+            });
+
+        if !token_bucket.try_take() {
+            warn!(
+                "routes_permit(): Client {:?} exceeded route requests budget. Rejecting request.",
+                public_key
+            );
+            return false;
+        }
+
+        self.open_queries.insert(public_key.clone());
+        true
+    }
+
+    /// Collect statistics about the current state of the index server.
+    async fn get_status(&mut self) -> Result<IndexServerStatus, ServerLoopError> {
+        let graph_stats = await!(self.graph_client.get_stats())?;
+
+        let mut remote_servers = self
+            .remote_servers
+            .iter()
+            .map(|(public_key, remote_server)| RemoteServerInfo {
+                public_key: public_key.clone(),
+                status: match remote_server.state {
+                    RemoteServerState::Connected(_) => RemoteServerStatus::Connected,
+                    RemoteServerState::Initiating(_) => RemoteServerStatus::Initiating,
+                    RemoteServerState::Listening => RemoteServerStatus::Listening,
+                },
+                opt_voucher: remote_server.opt_voucher.clone(),
+            })
+            .collect::<Vec<_>>();
+        remote_servers.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+
+        Ok(IndexServerStatus {
+            num_nodes: graph_stats.num_nodes,
+            num_edges: graph_stats.num_edges,
+            num_clients: self.clients.len(),
+            num_banned: self.ban_list.num_banned(),
+            remote_servers,
+            route_stats: graph_stats.route_stats,
+        })
+    }
+
+    pub async fn handle_admin_request(
+        &mut self,
+        incoming_admin_request: IncomingAdminRequest<A>,
+    ) -> Result<(), ServerLoopError> {
+        let IncomingAdminRequest {
+            request,
+            response_sender,
+        } = incoming_admin_request;
+
+        let response = match request {
+            AdminRequest::GetStatus => AdminResponse::Status(await!(self.get_status())?),
+            AdminRequest::GetEdges => AdminResponse::Edges(await!(self.graph_client.get_edges())?),
+            AdminRequest::AddServer(index_server_address) => {
+                AdminResponse::ServerAdded(await!(self.handle_add_server(index_server_address))?)
+            }
+            AdminRequest::RemoveServer(public_key) => {
+                AdminResponse::ServerRemoved(await!(self.handle_remove_server(public_key))?)
+            }
+        };
+        // The admin connection might have been closed already:
+        let _ = response_sender.send(response);
+        Ok(())
+    }
+
+    /// Collect the current state of the graph and the verifier.
+    async fn collect_snapshot(&mut self) -> Result<IndexServerSnapshot, ServerLoopError> {
+        let edges = await!(self.graph_client.get_edges())?;
+        Ok(IndexServerSnapshot {
+            edges,
+            verifier: self.verifier.snapshot(),
+        })
+    }
+
+    /// Collect the current state of the graph and the verifier, and send it to be saved.
+    async fn send_snapshot(&mut self) -> Result<(), ServerLoopError> {
+        let snapshot = await!(self.collect_snapshot())?;
+
+        if let Some(snapshot_sender) = &mut self.opt_snapshot_sender {
+            // If the previous snapshot is still being written, we skip this snapshot:
+            if snapshot_sender.try_send(snapshot).is_err() {
+                warn!("send_snapshot(): Failed to queue snapshot. Skipping.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a snapshot to be saved before shutting down.
+    /// Unlike `send_snapshot()`, we wait for the previous snapshot to be written.
+    async fn send_final_snapshot(&mut self) -> Result<(), ServerLoopError> {
+        if self.opt_snapshot_sender.is_none() {
+            return Ok(());
         }
+        let snapshot = await!(self.collect_snapshot())?;
 
+        if let Some(snapshot_sender) = &mut self.opt_snapshot_sender {
+            if await!(snapshot_sender.send(snapshot)).is_err() {
+                warn!("send_final_snapshot(): Failed to queue snapshot.");
+            }
+        }
         Ok(())
     }
 }
 
-async fn client_handler(
+/// Convert the edges of the graph into mutations, grouped by the source node.
+/// Capacities are rounded down in privacy mode.
+fn edges_to_nodes_mutations(
+    edges: Vec<AgedEdge<PublicKey, u128>>,
+    opt_capacity_bucket: Option<u128>,
+) -> Vec<NodeMutations> {
+    // We use a BTreeMap to get a deterministic order of nodes:
+    let mut nodes_mutations = BTreeMap::new();
+    for (a, b, (send_capacity, recv_capacity), _age) in edges {
+        let index_mutation = IndexMutation::UpdateFriend(UpdateFriend {
+            public_key: b,
+            send_capacity,
+            recv_capacity,
+        });
+        nodes_mutations
+            .entry(a)
+            .or_insert_with(Vec::new)
+            .push(bucket_mutation(index_mutation, opt_capacity_bucket));
+    }
+
+    nodes_mutations
+        .into_iter()
+        .map(|(node_public_key, index_mutations)| NodeMutations {
+            node_public_key,
+            index_mutations,
+        })
+        .collect()
+}
+
+async fn client_handler<A>(
     mut graph_client: GraphClient<PublicKey, u128>,
-    _public_key: PublicKey, // TODO: unused?
+    identity_client: IdentityClient,
+    public_key: PublicKey,
     client_conn: ClientConn,
-    mut event_sender: mpsc::Sender<IndexServerEvent>,
+    opt_capacity_bucket: Option<u128>,
+    mut event_sender: mpsc::Sender<IndexServerEvent<A>>,
 ) -> Result<(), ServerLoopError> {
     let (mut sender, mut receiver) = client_conn;
 
@@ -379,54 +1151,179 @@ async fn client_handler(
         match client_msg {
             IndexClientToServer::MutationsUpdate(mutations_update) => {
                 // Forward to main server future to process:
-                await!(event_sender.send(IndexServerEvent::ClientMutationsUpdate(mutations_update)))
+                await!(event_sender.send(IndexServerEvent::ClientMutationsUpdate((
+                    public_key.clone(),
+                    mutations_update
+                ))))
+                .map_err(|_| ServerLoopError::ClientEventSenderError)?;
+            }
+            IndexClientToServer::Subscribe => {
+                await!(event_sender.send(IndexServerEvent::ClientSubscribe(public_key.clone())))
                     .map_err(|_| ServerLoopError::ClientEventSenderError)?;
             }
             IndexClientToServer::RequestRoutes(request_routes) => {
-                let route_tuples = await!(graph_client.get_routes(
-                    request_routes.source.clone(),
-                    request_routes.destination.clone(),
-                    request_routes.capacity,
-                    request_routes.opt_exclude.clone()
-                ))?;
+                // Ask the main server future for a permission to query the graph:
+                let (permit_sender, permit_receiver) = oneshot::channel();
+                await!(event_sender.send(IndexServerEvent::RoutesPermit((
+                    public_key.clone(),
+                    permit_sender
+                ))))
+                .map_err(|_| ServerLoopError::ClientEventSenderError)?;
+                let (permitted, time_hash) =
+                    await!(permit_receiver).map_err(|_| ServerLoopError::PermitCanceled)?;
+
+                // In privacy mode we answer in buckets: The wanted capacity is rounded up, and the
+                // capacities of the found routes are rounded down. This way a client can not probe
+                // for exact capacities by repeatedly changing the wanted capacity.
+                let capacity = match opt_capacity_bucket {
+                    Some(capacity_bucket) => bucket_ceil(request_routes.capacity, capacity_bucket),
+                    None => request_routes.capacity,
+                };
+
+                // Over quota requests are answered with no routes:
+                let route_tuples = if permitted {
+                    let route_tuples_res = await!(graph_client.get_routes(
+                        request_routes.source.clone(),
+                        request_routes.destination.clone(),
+                        capacity,
+                        request_routes.opt_exclude.clone()
+                    ));
+                    await!(event_sender.send(IndexServerEvent::RoutesQueryDone(public_key.clone())))
+                        .map_err(|_| ServerLoopError::ClientEventSenderError)?;
+                    route_tuples_res?
+                } else {
+                    Vec::new()
+                };
+
                 let routes = route_tuples
                     .into_iter()
                     .map(|(route, capacity)| RouteWithCapacity {
                         route: FriendsRoute { public_keys: route },
-                        capacity,
+                        capacity: match opt_capacity_bucket {
+                            Some(capacity_bucket) => bucket_floor(capacity, capacity_bucket),
+                            None => capacity,
+                        },
                     })
                     .collect::<Vec<_>>();
 
+                // Sign the query together with the response, so that the client can later prove
+                // which routes we advertised. Refused requests are not signed: An empty signed
+                // response would claim that no route exists.
+                let opt_signature = if permitted {
+                    let signature_buff =
+                        response_routes_signature_buff(&request_routes, &routes, &time_hash);
+                    let signature = await!(identity_client.request_signature(signature_buff))
+                        .map_err(|_| ServerLoopError::RequestSignatureError)?;
+                    Some(ResponseRoutesSignature {
+                        time_hash,
+                        signature,
+                    })
+                } else {
+                    None
+                };
+
                 let response_routes = ResponseRoutes {
                     request_id: request_routes.request_id,
                     routes,
+                    opt_signature,
                 };
+
                 let message = IndexServerToClient::ResponseRoutes(response_routes);
                 await!(sender.send(message)).map_err(|_| ServerLoopError::ClientSenderError)?;
             }
-        }
-    }
-    Ok(())
-}
+            IndexClientToServer::RequestReachability(request_reachability) => {
+                // Reachability queries are as expensive as route queries, and are limited by the
+                // same quota:
+                let (permit_sender, permit_receiver) = oneshot::channel();
+                await!(event_sender.send(IndexServerEvent::RoutesPermit((
+                    public_key.clone(),
+                    permit_sender
+                ))))
+                .map_err(|_| ServerLoopError::ClientEventSenderError)?;
+                let (permitted, _time_hash) =
+                    await!(permit_receiver).map_err(|_| ServerLoopError::PermitCanceled)?;
+
+                let capacity = match opt_capacity_bucket {
+                    Some(capacity_bucket) => {
+                        bucket_ceil(request_reachability.capacity, capacity_bucket)
+                    }
+                    None => request_reachability.capacity,
+                };
 
-pub async fn server_loop<A, IS, IC, SC, CMP, V, TS, S>(
-    local_public_key: PublicKey,
-    trusted_servers: HashMap<PublicKey, A>,
-    incoming_server_connections: IS,
-    incoming_client_connections: IC,
-    server_connector: SC,
+                // Over quota requests are answered with no nodes:
+                let node_tuples = if permitted {
+                    let node_tuples_res = await!(graph_client.get_reachability(
+                        request_reachability.destination.clone(),
+                        capacity,
+                        request_reachability.opt_max_nodes.and_then(u32_to_usize)
+                    ));
+                    await!(event_sender.send(IndexServerEvent::RoutesQueryDone(public_key.clone())))
+                        .map_err(|_| ServerLoopError::ClientEventSenderError)?;
+                    node_tuples_res?
+                } else {
+                    Vec::new()
+                };
+
+                let nodes = node_tuples
+                    .into_iter()
+                    .map(|(public_key, capacity)| NodeWithCapacity {
+                        public_key,
+                        capacity: match opt_capacity_bucket {
+                            Some(capacity_bucket) => bucket_floor(capacity, capacity_bucket),
+                            None => capacity,
+                        },
+                    })
+                    .collect::<Vec<_>>();
+
+                let response_reachability = ResponseReachability {
+                    request_id: request_reachability.request_id,
+                    nodes,
+                };
+                let message = IndexServerToClient::ResponseReachability(response_reachability);
+                await!(sender.send(message)).map_err(|_| ServerLoopError::ClientSenderError)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the main loop of the index server.
+///
+/// When one of the incoming connections streams is closed, the server starts draining: New
+/// connections are ignored, no new route queries are permitted and a final snapshot is saved.
+/// The loop exits once all open route queries are done, or after `drain_ticks` ticks.
+pub async fn server_loop<A, IS, IC, IA, SC, CMP, V, TS, S>(
+    local_public_key: PublicKey,
+    identity_client: IdentityClient,
+    trusted_servers: HashMap<PublicKey, A>,
+    incoming_server_connections: IS,
+    incoming_client_connections: IC,
+    incoming_admin_requests: IA,
+    server_connector: SC,
     graph_client: GraphClient<PublicKey, u128>,
     compare_public_key: CMP,
     verifier: V,
     timer_stream: TS,
+    routes_limit: RoutesLimit,
+    opt_capacity_bucket: Option<u128>,
+    ban_config: BanConfig,
+    discovery: bool,
+    max_discovered_servers: usize,
+    opt_snapshot_sender: Option<mpsc::Sender<IndexServerSnapshot>>,
+    snapshot_ticks: usize,
+    drain_ticks: usize,
     spawner: S,
     mut opt_debug_event_sender: Option<mpsc::Sender<()>>,
 ) -> Result<(), ServerLoopError>
 where
-    A: Clone + Send + std::fmt::Debug + 'static,
-    IS: Stream<Item = (PublicKey, ServerConn)> + Unpin + Send,
+    A: CanonicalSerialize + Clone + Eq + Send + std::fmt::Debug + 'static,
+    IS: Stream<Item = (PublicKey, ServerConn<A>)> + Unpin + Send,
     IC: Stream<Item = (PublicKey, ClientConn)> + Unpin + Send,
-    SC: FutTransform<Input = (PublicKey, A), Output = Option<ServerConn>> + Clone + Send + 'static,
+    IA: Stream<Item = IncomingAdminRequest<A>> + Unpin + Send,
+    SC: FutTransform<Input = (PublicKey, A), Output = Option<ServerConn<A>>>
+        + Clone
+        + Send
+        + 'static,
     V: Verifier<Node = PublicKey, Neighbor = PublicKey, SessionId = Uid>,
     CMP: Clone + Fn(&PublicKey, &PublicKey) -> Ordering + Sync,
     TS: Stream + Unpin + Send,
@@ -441,12 +1338,20 @@ where
 
     let mut index_server = IndexServer::new(
         local_public_key,
+        identity_client,
         trusted_servers,
         server_connector,
         graph_client,
         compare_public_key,
         verifier,
         event_sender,
+        routes_limit,
+        opt_capacity_bucket,
+        ban_config,
+        discovery,
+        max_discovered_servers,
+        opt_snapshot_sender,
+        snapshot_ticks,
         spawner,
     )?;
 
@@ -471,17 +1376,29 @@ where
             IndexServerEvent::ClientListenerClosed,
         )));
 
+    // Note: The admin requests stream may be closed without closing the server loop.
+    let incoming_admin_requests = incoming_admin_requests.map(IndexServerEvent::AdminRequest);
+
     let timer_stream = timer_stream.map(|_| IndexServerEvent::TimerTick);
 
     let mut events = select_streams![
         event_receiver,
         incoming_server_connections,
         incoming_client_connections,
+        incoming_admin_requests,
         timer_stream
     ];
 
+    // Amount of ticks left until we stop draining (If we are draining)
+    let mut opt_drain_ticks_left: Option<usize> = None;
+
     while let Some(event) = await!(events.next()) {
         match event {
+            IndexServerEvent::ServerConnection(_) | IndexServerEvent::ClientConnection(_)
+                if opt_drain_ticks_left.is_some() =>
+            {
+                warn!("server_loop(): Draining. Ignoring new connection.");
+            }
             IndexServerEvent::ServerConnection((public_key, server_conn)) => {
                 let mut remote_server = match index_server.remote_servers.remove(&public_key) {
                     None => {
@@ -530,7 +1447,10 @@ where
 
                 index_server
                     .remote_servers
-                    .insert(public_key, remote_server);
+                    .insert(public_key.clone(), remote_server);
+
+                // Share our trusted servers with the newly connected server:
+                await!(index_server.send_peer_list(Some(public_key)))?;
             }
             IndexServerEvent::FromServer((public_key, Some(index_server_to_server))) => {
                 await!(index_server.handle_from_server(public_key, index_server_to_server))?
@@ -539,17 +1459,18 @@ where
                 // Server connection closed
                 let old_server = match index_server.remote_servers.remove(&public_key) {
                     None => {
-                        error!(
-                            "A non existent server {:?} was closed. Aborting.",
-                            public_key
-                        );
+                        // The server might have been removed from the trusted servers:
+                        info!("A non trusted server {:?} was closed.", public_key);
                         continue;
                     }
                     Some(old_server) => old_server,
                 };
                 let _ = index_server.verifier.remove_neighbor(&public_key);
-                let server = index_server.spawn_server(public_key.clone(), old_server.address)?;
-                index_server.remote_servers.insert(public_key, server);
+                index_server.add_remote_server(
+                    public_key,
+                    old_server.address,
+                    old_server.opt_voucher,
+                )?;
             }
             IndexServerEvent::ClientConnection((public_key, client_conn)) => {
                 if index_server.clients.contains_key(&public_key) {
@@ -565,8 +1486,10 @@ where
                 let c_public_key = public_key.clone();
                 let client_handler_fut = client_handler(
                     index_server.graph_client.clone(),
+                    index_server.identity_client.clone(),
                     public_key.clone(),
                     client_conn,
+                    index_server.opt_capacity_bucket,
                     index_server.event_sender.clone(),
                 )
                 .map_err(|e| error!("client_handler() error: {:?}", e))
@@ -586,29 +1509,50 @@ where
                     .clients
                     .insert(public_key, Connected::new(c_sender));
             }
-            IndexServerEvent::ClientMutationsUpdate(mutations_update) => {
-                let forward_mutations_update = ForwardMutationsUpdate {
-                    mutations_update,
-                    time_proof_chain: Vec::new(),
-                };
-                await!(
-                    index_server.handle_forward_mutations_update(None, forward_mutations_update)
-                )?;
+            IndexServerEvent::ClientMutationsUpdate((public_key, mutations_update)) => {
+                await!(index_server.handle_client_mutations_update(public_key, mutations_update))?
+            }
+            IndexServerEvent::ClientSubscribe(public_key) => {
+                await!(index_server.handle_client_subscribe(public_key))?
             }
             IndexServerEvent::ClientClosed(public_key) => {
                 // Client connection closed
                 if index_server.clients.remove(&public_key).is_none() {
                     error!("A non existent client {:?} was closed.", public_key);
                 }
+                // A closed client might have been closed in the middle of a query:
+                index_server.open_queries.remove(&public_key);
+                index_server.subscribers.remove(&public_key);
+            }
+            IndexServerEvent::RoutesPermit((public_key, permit_sender)) => {
+                // No new queries are permitted while draining:
+                let permit =
+                    opt_drain_ticks_left.is_none() && index_server.routes_permit(&public_key);
+                let _ = permit_sender.send((permit, index_server.time_hash.clone()));
+            }
+            IndexServerEvent::RoutesQueryDone(public_key) => {
+                index_server.open_queries.remove(&public_key);
             }
-            IndexServerEvent::TimerTick => await!(index_server.handle_timer_tick())?,
-            IndexServerEvent::ClientListenerClosed => {
-                warn!("server_loop() client listener closed!");
-                break;
+            IndexServerEvent::AdminRequest(incoming_admin_request) => {
+                await!(index_server.handle_admin_request(incoming_admin_request))?
             }
-            IndexServerEvent::ServerListenerClosed => {
-                warn!("server_loop() server listener closed!");
-                break;
+            IndexServerEvent::TimerTick => {
+                await!(index_server.handle_timer_tick())?;
+                if let Some(drain_ticks_left) = opt_drain_ticks_left.as_mut() {
+                    *drain_ticks_left = drain_ticks_left.saturating_sub(1);
+                    if *drain_ticks_left == 0 {
+                        warn!("server_loop(): Drain timeout. Closing open queries.");
+                        break;
+                    }
+                }
+            }
+            IndexServerEvent::ClientListenerClosed | IndexServerEvent::ServerListenerClosed => {
+                if opt_drain_ticks_left.is_none() {
+                    info!("server_loop(): Listener closed. Draining...");
+                    opt_drain_ticks_left = Some(drain_ticks);
+                    // Save our state before shutting down:
+                    await!(index_server.send_final_snapshot())?;
+                }
             }
         }
         // debug_event_sender is used to control the order of events during testing.
@@ -617,6 +1561,10 @@ where
         if let Some(ref mut debug_event_sender) = &mut opt_debug_event_sender {
             let _ = await!(debug_event_sender.send(()));
         }
+
+        if opt_drain_ticks_left.is_some() && index_server.open_queries.is_empty() {
+            break;
+        }
     }
     Ok(())
 }
@@ -636,10 +1584,11 @@ mod tests {
     use crypto::uid::UID_LEN;
 
     use common::dummy_connector::{ConnRequest, DummyConnector};
-    use identity::{create_identity, IdentityClient};
-    use proto::index_server::messages::RequestRoutes;
+    use identity::create_identity;
+    use proto::index_server::messages::{RequestReachability, RequestRoutes};
 
-    use crate::graph::graph_service::GraphRequest;
+    use crate::graph::graph_service::{GraphRequest, GraphStats};
+    use crate::graph::route_stats::RouteStats;
     use crate::verifier::simple_verifier::SimpleVerifier;
 
     /// Size of channel used for channels between servers, or channels between a server and a
@@ -647,6 +1596,12 @@ mod tests {
     /// forwarding or when sending time hash ticks.
     const CHANNEL_SIZE: usize = 16;
 
+    /// Maximum amount of ticks to wait for open queries when shutting down
+    const DRAIN_TICKS: usize = 8;
+
+    /// Maximum amount of discovered servers
+    const MAX_DISCOVERED_SERVERS: usize = 8;
+
     fn create_identity_client<S>(mut spawner: S, seed: &[u8]) -> IdentityClient
     where
         S: Spawn,
@@ -662,17 +1617,35 @@ mod tests {
         identity_client
     }
 
+    /// Route requests limits that are not reached during tests
+    fn test_routes_limit() -> RoutesLimit {
+        RoutesLimit {
+            client_requests_per_tick: 0x100,
+            client_max_burst: 0x100,
+            max_concurrent_queries: 0x100,
+        }
+    }
+
+    fn test_ban_config() -> BanConfig {
+        BanConfig {
+            violation_threshold: 0x10,
+            ban_ticks: 0x100,
+        }
+    }
+
     async fn task_index_server_loop_single_server<S>(mut spawner: S)
     where
         S: Spawn + Clone + Send + 'static,
     {
-        let server_pk = PublicKey::from(&[0; PUBLIC_KEY_LEN]);
+        let server_identity_client = create_identity_client(spawner.clone(), &[0, 0]);
+        let server_pk = await!(server_identity_client.request_public_key()).unwrap();
 
         let local_public_key = server_pk.clone();
         let trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
 
         let (_server_connections_sender, incoming_server_connections) = mpsc::channel(0);
         let (mut client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
 
         let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
         let server_connector = DummyConnector::new(conn_request_sender);
@@ -691,14 +1664,24 @@ mod tests {
 
         let server_loop_fut = server_loop(
             local_public_key,
+            server_identity_client,
             trusted_servers,
             incoming_server_connections,
             incoming_client_connections,
+            incoming_admin_requests,
             server_connector,
             graph_client,
             compare_public_key,
             verifier,
             timer_stream,
+            test_routes_limit(),
+            None,
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            None,
+            0,
+            DRAIN_TICKS,
             spawner.clone(),
             None,
         )
@@ -725,7 +1708,8 @@ mod tests {
             destination: PublicKey::from(&[9; PUBLIC_KEY_LEN]),
             opt_exclude: None,
         };
-        await!(client_sender.send(IndexClientToServer::RequestRoutes(request_routes))).unwrap();
+        await!(client_sender.send(IndexClientToServer::RequestRoutes(request_routes.clone())))
+            .unwrap();
 
         // Handle the graph request:
         match await!(graph_requests_receiver.next()).unwrap() {
@@ -743,6 +1727,17 @@ mod tests {
             IndexServerToClient::ResponseRoutes(response_routes) => {
                 assert_eq!(response_routes.request_id, request_id);
                 assert!(response_routes.routes.is_empty());
+                // No time hash was sent yet:
+                assert_eq!(
+                    response_routes.opt_signature.as_ref().unwrap().time_hash,
+                    HashResult::from(&[0; HASH_RESULT_LEN])
+                );
+                assert!(response_routes.verify_signature(&request_routes, &server_pk));
+
+                // The signature covers the query:
+                let mut other_request_routes = request_routes.clone();
+                other_request_routes.capacity = 200;
+                assert!(!response_routes.verify_signature(&other_request_routes, &server_pk));
             }
             _ => unreachable!(),
         };
@@ -781,7 +1776,7 @@ mod tests {
         match await!(graph_requests_receiver.next()).unwrap() {
             GraphRequest::Tick(node, response_sender) => {
                 assert_eq!(node, client_public_key);
-                response_sender.send(()).unwrap();
+                response_sender.send(Vec::new()).unwrap();
             }
             _ => unreachable!(),
         }
@@ -809,11 +1804,11 @@ mod tests {
     struct TestServer {
         public_key: PublicKey,
         tick_sender: mpsc::Sender<()>,
-        server_connections_sender: mpsc::Sender<(PublicKey, ServerConn)>,
+        server_connections_sender: mpsc::Sender<(PublicKey, ServerConn<u8>)>,
         client_connections_sender: mpsc::Sender<(PublicKey, ClientConn)>,
         graph_requests_receiver: mpsc::Receiver<GraphRequest<PublicKey, u128>>,
         server_conn_request_receiver:
-            mpsc::Receiver<ConnRequest<(PublicKey, u8), Option<ServerConn>>>,
+            mpsc::Receiver<ConnRequest<(PublicKey, u8), Option<ServerConn<u8>>>>,
         debug_event_receiver: mpsc::Receiver<()>,
     }
 
@@ -831,6 +1826,7 @@ mod tests {
 
         let (server_connections_sender, incoming_server_connections) = mpsc::channel(0);
         let (client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
 
         let (server_conn_request_sender, server_conn_request_receiver) = mpsc::channel(0);
         let server_connector = DummyConnector::new(server_conn_request_sender);
@@ -851,14 +1847,24 @@ mod tests {
 
         let server_loop_fut = server_loop(
             local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
             trusted_servers,
             incoming_server_connections,
             incoming_client_connections,
+            incoming_admin_requests,
             server_connector,
             graph_client,
             compare_public_key,
             verifier,
             timer_stream,
+            test_routes_limit(),
+            None,
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            None,
+            0,
+            DRAIN_TICKS,
             spawner.clone(),
             Some(debug_event_sender),
         )
@@ -1011,6 +2017,9 @@ mod tests {
             }
             _ => unreachable!(),
         };
+        // Routes permit and query completion events:
+        await!(test_servers[0].debug_event_receiver.next()).unwrap();
+        await!(test_servers[0].debug_event_receiver.next()).unwrap();
 
         // One time iteration for server 0:
         await!(test_servers[0].tick_sender.send(())).unwrap();
@@ -1053,7 +2062,7 @@ mod tests {
                 match await!(test_servers[$index].graph_requests_receiver.next()).unwrap() {
                     GraphRequest::Tick(node, response_sender) => {
                         assert_eq!(node, client_public_key);
-                        response_sender.send(()).unwrap();
+                        response_sender.send(Vec::new()).unwrap();
                     }
                     _ => unreachable!(),
                 }
@@ -1094,5 +2103,1320 @@ mod tests {
         thread_pool.run(task_index_server_loop_multi_server(thread_pool.clone()));
     }
 
+    async fn task_index_server_loop_snapshot<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_public_key = PublicKey::from(&[0; PUBLIC_KEY_LEN]);
+        let trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
+
+        let (_server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (_client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (mut tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        let compare_public_key = |pk_a: &PublicKey, pk_b: &PublicKey| pk_a.cmp(pk_b);
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        let (snapshot_sender, mut snapshot_receiver) = mpsc::channel(0);
+        let snapshot_ticks = 3;
+
+        let (debug_event_sender, mut debug_event_receiver) = mpsc::channel(0);
+
+        let server_loop_fut = server_loop(
+            local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            test_routes_limit(),
+            None,
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            Some(snapshot_sender),
+            snapshot_ticks,
+            DRAIN_TICKS,
+            spawner.clone(),
+            Some(debug_event_sender),
+        )
+        .map_err(|e| error!("Error in server_loop(): {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(server_loop_fut).unwrap();
+
+        // No snapshot is taken during the first ticks:
+        for _ in 0..snapshot_ticks - 1 {
+            await!(tick_sender.send(())).unwrap();
+            await!(debug_event_receiver.next()).unwrap();
+        }
+
+        // A snapshot is taken every `snapshot_ticks` ticks:
+        await!(tick_sender.send(())).unwrap();
+
+        let edge = (
+            PublicKey::from(&[1; PUBLIC_KEY_LEN]),
+            PublicKey::from(&[2; PUBLIC_KEY_LEN]),
+            (10, 20),
+            5,
+        );
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetEdges(response_sender) => {
+                response_sender.send(vec![edge.clone()]).unwrap();
+            }
+            _ => unreachable!(),
+        }
+        await!(debug_event_receiver.next()).unwrap();
+
+        let snapshot = await!(snapshot_receiver.next()).unwrap();
+        assert_eq!(snapshot.edges, vec![edge]);
+        assert_eq!(snapshot.verifier.last_ticks.len(), snapshot_ticks);
+        assert!(snapshot.verifier.ratchets.is_empty());
+    }
+
+    #[test]
+    fn test_index_server_loop_snapshot() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_snapshot(thread_pool.clone()));
+    }
+
+    async fn task_index_server_loop_drain<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_public_key = PublicKey::from(&[0; PUBLIC_KEY_LEN]);
+        let trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
+
+        let (_server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (_tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        let compare_public_key = |pk_a: &PublicKey, pk_b: &PublicKey| pk_a.cmp(pk_b);
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        let (snapshot_sender, mut snapshot_receiver) = mpsc::channel(0);
+
+        let server_loop_fut = server_loop(
+            local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            test_routes_limit(),
+            None,
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            Some(snapshot_sender),
+            16,
+            DRAIN_TICKS,
+            spawner.clone(),
+            None,
+        );
+
+        let server_loop_handle = spawner.spawn_with_handle(server_loop_fut).unwrap();
+
+        // Closing the incoming client connections starts draining:
+        drop(client_connections_sender);
+
+        // A final snapshot is saved:
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetEdges(response_sender) => {
+                response_sender.send(Vec::new()).unwrap();
+            }
+            _ => unreachable!(),
+        }
+        let snapshot = await!(snapshot_receiver.next()).unwrap();
+        assert!(snapshot.edges.is_empty());
+
+        // There are no open queries, so the server loop exits:
+        await!(server_loop_handle).unwrap();
+        assert!(await!(snapshot_receiver.next()).is_none());
+    }
+
+    #[test]
+    fn test_index_server_loop_drain() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_drain(thread_pool.clone()));
+    }
+
+    async fn task_index_server_loop_admin<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_public_key = PublicKey::from(&[5; PUBLIC_KEY_LEN]);
+        // We have the responsibility to connect to server 1, and server 9 should connect to us:
+        let trusted_servers: HashMap<PublicKey, u8> = vec![
+            (PublicKey::from(&[1; PUBLIC_KEY_LEN]), 1),
+            (PublicKey::from(&[9; PUBLIC_KEY_LEN]), 9),
+        ]
+        .into_iter()
+        .collect();
+
+        let (_server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (mut client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (mut admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (_tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        let compare_public_key = |pk_a: &PublicKey, pk_b: &PublicKey| pk_a.cmp(pk_b);
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        let (debug_event_sender, mut debug_event_receiver) = mpsc::channel(0);
+
+        let server_loop_fut = server_loop(
+            local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            test_routes_limit(),
+            None,
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            None,
+            0,
+            DRAIN_TICKS,
+            spawner.clone(),
+            Some(debug_event_sender),
+        )
+        .map_err(|e| error!("Error in server_loop(): {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(server_loop_fut).unwrap();
+
+        // Connect a client:
+        let client_public_key = PublicKey::from(&[3; PUBLIC_KEY_LEN]);
+        let (_client_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (server_sender, _client_receiver) = mpsc::channel(CHANNEL_SIZE);
+        await!(client_connections_sender
+            .send((client_public_key, (server_sender, server_receiver))))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // Request status:
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_requests_sender.send(IncomingAdminRequest {
+            request: AdminRequest::GetStatus,
+            response_sender,
+        }))
+        .unwrap();
+
+        let route_stats = RouteStats {
+            total_requests: 7,
+            recent_requests: 2,
+            recent_avg_latency_us: 30,
+            recent_max_latency_us: 50,
+        };
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetStats(response_sender) => {
+                response_sender
+                    .send(GraphStats {
+                        num_nodes: 4,
+                        num_edges: 6,
+                        route_stats: route_stats.clone(),
+                    })
+                    .unwrap();
+            }
+            _ => unreachable!(),
+        }
+        await!(debug_event_receiver.next()).unwrap();
+
+        let status = match await!(response_receiver).unwrap() {
+            AdminResponse::Status(status) => status,
+            _ => unreachable!(),
+        };
+        assert_eq!(status.num_nodes, 4);
+        assert_eq!(status.num_edges, 6);
+        assert_eq!(status.num_clients, 1);
+        assert_eq!(status.num_banned, 0);
+        assert_eq!(status.route_stats, route_stats);
+        assert_eq!(
+            status.remote_servers,
+            vec![
+                RemoteServerInfo {
+                    public_key: PublicKey::from(&[1; PUBLIC_KEY_LEN]),
+                    status: RemoteServerStatus::Initiating,
+                    opt_voucher: None,
+                },
+                RemoteServerInfo {
+                    public_key: PublicKey::from(&[9; PUBLIC_KEY_LEN]),
+                    status: RemoteServerStatus::Listening,
+                    opt_voucher: None,
+                },
+            ]
+        );
+
+        // Request graph edges:
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_requests_sender.send(IncomingAdminRequest {
+            request: AdminRequest::GetEdges,
+            response_sender,
+        }))
+        .unwrap();
+
+        let edge = (
+            PublicKey::from(&[1; PUBLIC_KEY_LEN]),
+            PublicKey::from(&[2; PUBLIC_KEY_LEN]),
+            (10, 20),
+            5,
+        );
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetEdges(response_sender) => {
+                response_sender.send(vec![edge.clone()]).unwrap();
+            }
+            _ => unreachable!(),
+        }
+        await!(debug_event_receiver.next()).unwrap();
+
+        match await!(response_receiver).unwrap() {
+            AdminResponse::Edges(edges) => assert_eq!(edges, vec![edge]),
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_index_server_loop_admin() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_admin(thread_pool.clone()));
+    }
+
+    fn create_request_routes(request_id_byte: u8) -> RequestRoutes {
+        RequestRoutes {
+            request_id: Uid::from(&[request_id_byte; UID_LEN]),
+            capacity: 100,
+            source: PublicKey::from(&[8; PUBLIC_KEY_LEN]),
+            destination: PublicKey::from(&[9; PUBLIC_KEY_LEN]),
+            opt_exclude: None,
+        }
+    }
+
+    async fn task_index_server_loop_routes_limit<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_public_key = PublicKey::from(&[0; PUBLIC_KEY_LEN]);
+        let trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
+
+        let (_server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (mut client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (mut tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        let compare_public_key = |pk_a: &PublicKey, pk_b: &PublicKey| pk_a.cmp(pk_b);
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        let routes_limit = RoutesLimit {
+            client_requests_per_tick: 1,
+            client_max_burst: 1,
+            max_concurrent_queries: 1,
+        };
+
+        let (debug_event_sender, mut debug_event_receiver) = mpsc::channel(0);
+
+        let server_loop_fut = server_loop(
+            local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            routes_limit,
+            None,
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            None,
+            0,
+            DRAIN_TICKS,
+            spawner.clone(),
+            Some(debug_event_sender),
+        )
+        .map_err(|e| error!("Error in server_loop(): {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(server_loop_fut).unwrap();
+
+        // Connect two clients:
+        let mut clients = Vec::new();
+        for &i in &[1u8, 2] {
+            let client_public_key = PublicKey::from(&[i; PUBLIC_KEY_LEN]);
+            let (client_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+            let (server_sender, client_receiver) = mpsc::channel(CHANNEL_SIZE);
+            await!(client_connections_sender
+                .send((client_public_key, (server_sender, server_receiver))))
+            .unwrap();
+            await!(debug_event_receiver.next()).unwrap();
+            clients.push((client_sender, client_receiver));
+        }
+
+        // Client 1 requests routes:
+        let request_routes = create_request_routes(0);
+        await!(clients[0]
+            .0
+            .send(IndexClientToServer::RequestRoutes(request_routes)))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The graph query of client 1 is in progress:
+        let response_sender = match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetRoutes(_src, _dest, _capacity, _opt_exclude, response_sender) => {
+                response_sender
+            }
+            _ => unreachable!(),
+        };
+
+        // Client 2 requests routes, but there are too many concurrent queries:
+        let request_routes = create_request_routes(1);
+        await!(clients[1]
+            .0
+            .send(IndexClientToServer::RequestRoutes(request_routes)))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        match await!(clients[1].1.next()).unwrap() {
+            IndexServerToClient::ResponseRoutes(response_routes) => {
+                assert_eq!(response_routes.request_id, Uid::from(&[1; UID_LEN]));
+                assert!(response_routes.routes.is_empty());
+                // Refused requests are not signed:
+                assert!(response_routes.opt_signature.is_none());
+            }
+            _ => unreachable!(),
+        };
+
+        // Client 1 query completes:
+        let route = (
+            vec![
+                PublicKey::from(&[8; PUBLIC_KEY_LEN]),
+                PublicKey::from(&[9; PUBLIC_KEY_LEN]),
+            ],
+            100,
+        );
+        response_sender.send(vec![route]).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        match await!(clients[0].1.next()).unwrap() {
+            IndexServerToClient::ResponseRoutes(response_routes) => {
+                assert_eq!(response_routes.request_id, Uid::from(&[0; UID_LEN]));
+                assert_eq!(response_routes.routes.len(), 1);
+                assert!(response_routes.opt_signature.is_some());
+            }
+            _ => unreachable!(),
+        };
+
+        // Client 1 used its budget for this tick:
+        let request_routes = create_request_routes(2);
+        await!(clients[0]
+            .0
+            .send(IndexClientToServer::RequestRoutes(request_routes)))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        match await!(clients[0].1.next()).unwrap() {
+            IndexServerToClient::ResponseRoutes(response_routes) => {
+                assert_eq!(response_routes.request_id, Uid::from(&[2; UID_LEN]));
+                assert!(response_routes.routes.is_empty());
+                assert!(response_routes.opt_signature.is_none());
+            }
+            _ => unreachable!(),
+        };
+
+        // Budget is refilled after a tick:
+        await!(tick_sender.send(())).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+        for (_client_sender, client_receiver) in &mut clients {
+            match await!(client_receiver.next()).unwrap() {
+                IndexServerToClient::TimeHash(_) => {}
+                _ => unreachable!(),
+            };
+        }
+
+        let request_routes = create_request_routes(3);
+        await!(clients[0]
+            .0
+            .send(IndexClientToServer::RequestRoutes(request_routes)))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetRoutes(_src, _dest, _capacity, _opt_exclude, response_sender) => {
+                response_sender.send(Vec::new()).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        match await!(clients[0].1.next()).unwrap() {
+            IndexServerToClient::ResponseRoutes(response_routes) => {
+                assert_eq!(response_routes.request_id, Uid::from(&[3; UID_LEN]));
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_index_server_loop_routes_limit() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_routes_limit(thread_pool.clone()));
+    }
+
+    async fn task_index_server_loop_capacity_bucket<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_public_key = PublicKey::from(&[0; PUBLIC_KEY_LEN]);
+        let trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
+
+        let (_server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (mut client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (_tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        let compare_public_key = |pk_a: &PublicKey, pk_b: &PublicKey| pk_a.cmp(pk_b);
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        let (debug_event_sender, mut debug_event_receiver) = mpsc::channel(0);
+
+        let server_loop_fut = server_loop(
+            local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            test_routes_limit(),
+            Some(64),
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            None,
+            0,
+            DRAIN_TICKS,
+            spawner.clone(),
+            Some(debug_event_sender),
+        )
+        .map_err(|e| error!("Error in server_loop(): {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(server_loop_fut).unwrap();
+
+        // Connect a client:
+        let client_public_key = PublicKey::from(&[1; PUBLIC_KEY_LEN]);
+        let (mut client_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (server_sender, mut client_receiver) = mpsc::channel(CHANNEL_SIZE);
+        await!(client_connections_sender
+            .send((client_public_key, (server_sender, server_receiver))))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // Wanted capacity is 100:
+        let request_routes = create_request_routes(0);
+        await!(client_sender.send(IndexClientToServer::RequestRoutes(request_routes))).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The graph is queried with the wanted capacity rounded up:
+        let route = (
+            vec![
+                PublicKey::from(&[8; PUBLIC_KEY_LEN]),
+                PublicKey::from(&[9; PUBLIC_KEY_LEN]),
+            ],
+            150,
+        );
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetRoutes(_src, _dest, capacity, _opt_exclude, response_sender) => {
+                assert_eq!(capacity, 128);
+                response_sender.send(vec![route]).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The capacity of the route is rounded down:
+        match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::ResponseRoutes(response_routes) => {
+                assert_eq!(response_routes.request_id, Uid::from(&[0; UID_LEN]));
+                assert_eq!(response_routes.routes.len(), 1);
+                assert_eq!(response_routes.routes[0].capacity, 128);
+            }
+            _ => unreachable!(),
+        };
+
+        // Reachability queries are bucketed in the same way:
+        let request_reachability = RequestReachability {
+            request_id: Uid::from(&[1; UID_LEN]),
+            capacity: 100,
+            destination: PublicKey::from(&[9; PUBLIC_KEY_LEN]),
+            opt_max_nodes: Some(2),
+        };
+        await!(client_sender.send(IndexClientToServer::RequestReachability(
+            request_reachability
+        )))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        let node_tuples = vec![
+            (PublicKey::from(&[7; PUBLIC_KEY_LEN]), 200),
+            (PublicKey::from(&[8; PUBLIC_KEY_LEN]), 150),
+        ];
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetReachability(dest, capacity, opt_max_nodes, response_sender) => {
+                assert_eq!(dest, PublicKey::from(&[9; PUBLIC_KEY_LEN]));
+                assert_eq!(capacity, 128);
+                assert_eq!(opt_max_nodes, Some(2));
+                response_sender.send(node_tuples).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::ResponseReachability(response_reachability) => {
+                assert_eq!(response_reachability.request_id, Uid::from(&[1; UID_LEN]));
+                let capacities = response_reachability
+                    .nodes
+                    .iter()
+                    .map(|node_with_capacity| node_with_capacity.capacity)
+                    .collect::<Vec<_>>();
+                assert_eq!(capacities, vec![192, 128]);
+            }
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_index_server_loop_capacity_bucket() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_capacity_bucket(thread_pool.clone()));
+    }
+
+    async fn task_index_server_loop_ban<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_public_key = PublicKey::from(&[0; PUBLIC_KEY_LEN]);
+        let remote_server_public_key = PublicKey::from(&[1; PUBLIC_KEY_LEN]);
+        let mut trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
+        trusted_servers.insert(remote_server_public_key.clone(), 1);
+
+        let (mut server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (mut client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (_tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        let compare_public_key = |pk_a: &PublicKey, pk_b: &PublicKey| pk_a.cmp(pk_b);
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        // Ban after two violations:
+        let ban_config = BanConfig {
+            violation_threshold: 2,
+            ban_ticks: 0x100,
+        };
+
+        let (debug_event_sender, mut debug_event_receiver) = mpsc::channel(0);
+
+        let server_loop_fut = server_loop(
+            local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            test_routes_limit(),
+            None,
+            ban_config,
+            false,
+            MAX_DISCOVERED_SERVERS,
+            None,
+            0,
+            DRAIN_TICKS,
+            spawner.clone(),
+            Some(debug_event_sender),
+        )
+        .map_err(|e| error!("Error in server_loop(): {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(server_loop_fut).unwrap();
+
+        // The remote server connects to us:
+        let (mut remote_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (server_sender, mut remote_receiver) = mpsc::channel(CHANNEL_SIZE);
+        await!(server_connections_sender.send((
+            remote_server_public_key.clone(),
+            (server_sender, server_receiver)
+        )))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // A client connects:
+        let identity_client = create_identity_client(spawner.clone(), &[1, 1]);
+        let client_public_key = await!(identity_client.request_public_key()).unwrap();
+
+        let (mut client_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (server_sender, _client_receiver) = mpsc::channel(CHANNEL_SIZE);
+        await!(client_connections_sender
+            .send((client_public_key.clone(), (server_sender, server_receiver))))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        let mut mutations_update = MutationsUpdate {
+            node_public_key: client_public_key.clone(),
+            index_mutations: vec![IndexMutation::RemoveFriend(PublicKey::from(
+                &[11; PUBLIC_KEY_LEN],
+            ))],
+            time_hash: HashResult::from(&[0; HASH_RESULT_LEN]),
+            session_id: Uid::from(&[0; UID_LEN]),
+            counter: 0,
+            rand_nonce: RandValue::from(&[0; RAND_VALUE_LEN]),
+            signature: Signature::from(&[0; SIGNATURE_LEN]),
+        };
+
+        // First invalid message (Bad signature):
+        await!(client_sender.send(IndexClientToServer::MutationsUpdate(
+            mutations_update.clone()
+        )))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // Second invalid message. The client is banned:
+        await!(client_sender.send(IndexClientToServer::MutationsUpdate(
+            mutations_update.clone()
+        )))
+        .unwrap();
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::RemoveNode(node, response_sender) => {
+                assert_eq!(node, client_public_key);
+                response_sender.send(Vec::new()).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The ban is propagated to the remote server:
+        match await!(remote_receiver.next()).unwrap() {
+            IndexServerToServer::BanNode(node) => assert_eq!(node, client_public_key),
+            _ => unreachable!(),
+        };
+
+        // Valid messages from the banned client are ignored (No graph requests are made):
+        mutations_update.signature =
+            await!(identity_client.request_signature(mutations_update.signature_buff())).unwrap();
+        await!(client_sender.send(IndexClientToServer::MutationsUpdate(mutations_update))).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The remote server reports a ban:
+        let banned_public_key = PublicKey::from(&[5; PUBLIC_KEY_LEN]);
+        await!(remote_sender.send(IndexServerToServer::BanNode(
+            banned_public_key.clone()
+        )))
+        .unwrap();
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::RemoveNode(node, response_sender) => {
+                assert_eq!(node, banned_public_key);
+                response_sender.send(Vec::new()).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        // A repeated ban report is not applied again:
+        await!(remote_sender.send(IndexServerToServer::BanNode(banned_public_key))).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // Bans are not sent back to the server that reported them:
+        assert!(remote_receiver.try_next().is_err());
+    }
+
+    #[test]
+    fn test_index_server_loop_ban() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_ban(thread_pool.clone()));
+    }
+
+    async fn task_index_server_loop_subscribe<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_public_key = PublicKey::from(&[0; PUBLIC_KEY_LEN]);
+        let trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
+
+        let (_server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (mut client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (_admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (mut tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        let compare_public_key = |pk_a: &PublicKey, pk_b: &PublicKey| pk_a.cmp(pk_b);
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        let (debug_event_sender, mut debug_event_receiver) = mpsc::channel(0);
+
+        // Graph changes are sent in privacy mode:
+        let server_loop_fut = server_loop(
+            local_public_key,
+            create_identity_client(spawner.clone(), &[0x13, 0x37]),
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            test_routes_limit(),
+            Some(64),
+            test_ban_config(),
+            false,
+            MAX_DISCOVERED_SERVERS,
+            None,
+            0,
+            DRAIN_TICKS,
+            spawner.clone(),
+            Some(debug_event_sender),
+        )
+        .map_err(|e| error!("Error in server_loop(): {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(server_loop_fut).unwrap();
+
+        // Connect a client:
+        let identity_client = create_identity_client(spawner.clone(), &[1, 1]);
+        let client_public_key = await!(identity_client.request_public_key()).unwrap();
+
+        let (mut client_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (server_sender, mut client_receiver) = mpsc::channel(CHANNEL_SIZE);
+        await!(client_connections_sender
+            .send((client_public_key.clone(), (server_sender, server_receiver))))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // Client subscribes to graph changes:
+        await!(client_sender.send(IndexClientToServer::Subscribe)).unwrap();
+
+        let pk_a = PublicKey::from(&[0xa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xb; PUBLIC_KEY_LEN]);
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetEdges(response_sender) => {
+                let edges = vec![
+                    (pk_a.clone(), pk_b.clone(), (100, 30), 0),
+                    (pk_b.clone(), pk_a.clone(), (30, 100), 1),
+                ];
+                response_sender.send(edges).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The client receives a snapshot of the graph, with rounded capacities:
+        match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::GraphSnapshot(nodes_mutations) => assert_eq!(
+                nodes_mutations,
+                vec![
+                    NodeMutations {
+                        node_public_key: pk_a.clone(),
+                        index_mutations: vec![IndexMutation::UpdateFriend(UpdateFriend {
+                            public_key: pk_b.clone(),
+                            send_capacity: 64,
+                            recv_capacity: 0,
+                        })],
+                    },
+                    NodeMutations {
+                        node_public_key: pk_b.clone(),
+                        index_mutations: vec![IndexMutation::UpdateFriend(UpdateFriend {
+                            public_key: pk_a.clone(),
+                            send_capacity: 0,
+                            recv_capacity: 64,
+                        })],
+                    },
+                ]
+            ),
+            _ => unreachable!(),
+        };
+
+        // Subscribing again is ignored (No snapshot is taken):
+        await!(client_sender.send(IndexClientToServer::Subscribe)).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // Get a time hash from the server:
+        await!(tick_sender.send(())).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+        let time_hash = match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::TimeHash(time_hash) => time_hash,
+            _ => unreachable!(),
+        };
+
+        // Client sends mutations:
+        let update_friend = UpdateFriend {
+            public_key: pk_a.clone(),
+            send_capacity: 150,
+            recv_capacity: 200,
+        };
+        let mut mutations_update = MutationsUpdate {
+            node_public_key: client_public_key.clone(),
+            index_mutations: vec![IndexMutation::UpdateFriend(update_friend)],
+            time_hash,
+            session_id: Uid::from(&[0; UID_LEN]),
+            counter: 0,
+            rand_nonce: RandValue::from(&[0; RAND_VALUE_LEN]),
+            signature: Signature::from(&[0; SIGNATURE_LEN]),
+        };
+        mutations_update.signature =
+            await!(identity_client.request_signature(mutations_update.signature_buff())).unwrap();
+        await!(client_sender.send(IndexClientToServer::MutationsUpdate(mutations_update))).unwrap();
+
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::Tick(node, response_sender) => {
+                assert_eq!(node, client_public_key);
+                // An old edge to pk_b expires:
+                response_sender.send(vec![pk_b.clone()]).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::UpdateEdge(node, friend, capacity_edge, response_sender) => {
+                assert_eq!(node, client_public_key);
+                assert_eq!(friend, pk_a);
+                assert_eq!(capacity_edge, (150, 200));
+                response_sender.send(None).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The subscribed client is told about the expired edge:
+        match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::GraphUpdate(node_mutations) => assert_eq!(
+                node_mutations,
+                NodeMutations {
+                    node_public_key: client_public_key.clone(),
+                    index_mutations: vec![IndexMutation::RemoveFriend(pk_b.clone())],
+                }
+            ),
+            _ => unreachable!(),
+        };
+
+        // The verified change is sent to the subscribed client, with rounded capacities:
+        match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::GraphUpdate(node_mutations) => assert_eq!(
+                node_mutations,
+                NodeMutations {
+                    node_public_key: client_public_key.clone(),
+                    index_mutations: vec![IndexMutation::UpdateFriend(UpdateFriend {
+                        public_key: pk_a.clone(),
+                        send_capacity: 128,
+                        recv_capacity: 192,
+                    })],
+                }
+            ),
+            _ => unreachable!(),
+        };
+
+        // The client stops sending updates, and eventually expires:
+        for _ in 0..7 {
+            await!(tick_sender.send(())).unwrap();
+            await!(debug_event_receiver.next()).unwrap();
+            match await!(client_receiver.next()).unwrap() {
+                IndexServerToClient::TimeHash(_) => {}
+                _ => unreachable!(),
+            };
+        }
+        await!(tick_sender.send(())).unwrap();
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::RemoveNode(node, response_sender) => {
+                assert_eq!(node, client_public_key);
+                response_sender.send(vec![pk_a.clone()]).unwrap();
+            }
+            _ => unreachable!(),
+        };
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The subscribed client is told about the removed edges:
+        match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::TimeHash(_) => {}
+            _ => unreachable!(),
+        };
+        match await!(client_receiver.next()).unwrap() {
+            IndexServerToClient::GraphUpdate(node_mutations) => assert_eq!(
+                node_mutations,
+                NodeMutations {
+                    node_public_key: client_public_key.clone(),
+                    index_mutations: vec![IndexMutation::RemoveFriend(pk_a.clone())],
+                }
+            ),
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_index_server_loop_subscribe() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_subscribe(thread_pool.clone()));
+    }
+
+    async fn task_index_server_loop_discovery<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let local_identity_client = create_identity_client(spawner.clone(), &[0x13, 0x37]);
+        let local_public_key = await!(local_identity_client.request_public_key()).unwrap();
+
+        let remote_identity_client = create_identity_client(spawner.clone(), &[2, 2]);
+        let remote_server_public_key = await!(remote_identity_client.request_public_key()).unwrap();
+
+        let mut trusted_servers: HashMap<PublicKey, u8> = HashMap::new();
+        trusted_servers.insert(remote_server_public_key.clone(), 2);
+
+        let (mut server_connections_sender, incoming_server_connections) = mpsc::channel(0);
+        let (_client_connections_sender, incoming_client_connections) = mpsc::channel(0);
+        let (mut admin_requests_sender, incoming_admin_requests) = mpsc::channel(0);
+
+        let (conn_request_sender, _conn_request_receiver) = mpsc::channel(0);
+        let server_connector = DummyConnector::new(conn_request_sender);
+
+        let (_tick_sender, timer_stream) = mpsc::channel::<()>(0);
+
+        let (graph_requests_sender, mut graph_requests_receiver) = mpsc::channel(0);
+        let graph_client = GraphClient::new(graph_requests_sender);
+
+        // All remote servers are responsible for connecting to us:
+        let compare_public_key = |_pk_a: &PublicKey, _pk_b: &PublicKey| Ordering::Less;
+
+        let rng = DummyRandom::new(&[0u8]);
+        let verifier = SimpleVerifier::new(8, rng);
+
+        let (debug_event_sender, mut debug_event_receiver) = mpsc::channel(0);
+
+        let server_loop_fut = server_loop(
+            local_public_key.clone(),
+            local_identity_client,
+            trusted_servers,
+            incoming_server_connections,
+            incoming_client_connections,
+            incoming_admin_requests,
+            server_connector,
+            graph_client,
+            compare_public_key,
+            verifier,
+            timer_stream,
+            test_routes_limit(),
+            None,
+            test_ban_config(),
+            true,
+            1,
+            None,
+            0,
+            DRAIN_TICKS,
+            spawner.clone(),
+            Some(debug_event_sender),
+        )
+        .map_err(|e| error!("Error in server_loop(): {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(server_loop_fut).unwrap();
+
+        // The remote server connects to us:
+        let (mut remote_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (server_sender, mut remote_receiver) = mpsc::channel(CHANNEL_SIZE);
+        await!(server_connections_sender.send((
+            remote_server_public_key.clone(),
+            (server_sender, server_receiver)
+        )))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // We have no other trusted servers to share:
+        match await!(remote_receiver.next()).unwrap() {
+            IndexServerToServer::PeerList(peer_list) => assert!(peer_list.is_empty()),
+            _ => unreachable!(),
+        };
+
+        // The remote server vouches for some servers:
+        let mut vouched_server = SignedIndexServerAddress {
+            public_key: PublicKey::from(&[5; PUBLIC_KEY_LEN]),
+            address: 5u8,
+            seq: 1,
+            signature: Signature::from(&[0; SIGNATURE_LEN]),
+        };
+        vouched_server.signature =
+            await!(remote_identity_client.request_signature(vouched_server.signature_buff()))
+                .unwrap();
+        let invalid_vouched_server = SignedIndexServerAddress {
+            public_key: PublicKey::from(&[6; PUBLIC_KEY_LEN]),
+            address: 6u8,
+            seq: 1,
+            signature: Signature::from(&[0; SIGNATURE_LEN]),
+        };
+        await!(remote_sender.send(IndexServerToServer::PeerList(vec![
+            vouched_server,
+            invalid_vouched_server
+        ])))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // A peer list that is not newer than the last one is ignored:
+        let mut replayed_server = SignedIndexServerAddress {
+            public_key: PublicKey::from(&[8; PUBLIC_KEY_LEN]),
+            address: 8u8,
+            seq: 1,
+            signature: Signature::from(&[0; SIGNATURE_LEN]),
+        };
+        replayed_server.signature =
+            await!(remote_identity_client.request_signature(replayed_server.signature_buff()))
+                .unwrap();
+        let replayed_peer_list = vec![replayed_server.clone()];
+        await!(remote_sender.send(IndexServerToServer::PeerList(replayed_peer_list))).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // A newer peer list. The outdated entry is skipped, and we don't discover more than one
+        // server:
+        let mut peer_list = vec![replayed_server];
+        for &i in &[5u8, 10] {
+            let mut signed_index_server_address = SignedIndexServerAddress {
+                public_key: PublicKey::from(&[i; PUBLIC_KEY_LEN]),
+                address: i,
+                seq: 2,
+                signature: Signature::from(&[0; SIGNATURE_LEN]),
+            };
+            signed_index_server_address.signature = await!(remote_identity_client
+                .request_signature(signed_index_server_address.signature_buff()))
+            .unwrap();
+            peer_list.push(signed_index_server_address);
+        }
+        await!(remote_sender.send(IndexServerToServer::PeerList(peer_list))).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // The discovered server connects to us:
+        let (mut discovered_sender, server_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (server_sender, _discovered_receiver) = mpsc::channel(CHANNEL_SIZE);
+        await!(server_connections_sender.send((
+            PublicKey::from(&[5; PUBLIC_KEY_LEN]),
+            (server_sender, server_receiver)
+        )))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        // Bans sent by discovered servers are ignored:
+        let banned_public_key = PublicKey::from(&[9; PUBLIC_KEY_LEN]);
+        await!(discovered_sender.send(IndexServerToServer::BanNode(banned_public_key))).unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_requests_sender.send(IncomingAdminRequest {
+            request: AdminRequest::GetStatus,
+            response_sender,
+        }))
+        .unwrap();
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetStats(response_sender) => {
+                response_sender
+                    .send(GraphStats {
+                        num_nodes: 0,
+                        num_edges: 0,
+                        route_stats: RouteStats::default(),
+                    })
+                    .unwrap();
+            }
+            _ => unreachable!(),
+        }
+        await!(debug_event_receiver.next()).unwrap();
+        let remote_servers = match await!(response_receiver).unwrap() {
+            AdminResponse::Status(status) => {
+                assert_eq!(status.num_banned, 0);
+                status.remote_servers
+            }
+            _ => unreachable!(),
+        };
+        let mut expected_remote_servers = vec![
+            RemoteServerInfo {
+                public_key: remote_server_public_key.clone(),
+                status: RemoteServerStatus::Connected,
+                opt_voucher: None,
+            },
+            RemoteServerInfo {
+                public_key: PublicKey::from(&[5; PUBLIC_KEY_LEN]),
+                status: RemoteServerStatus::Connected,
+                opt_voucher: Some(remote_server_public_key.clone()),
+            },
+        ];
+        expected_remote_servers.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+        assert_eq!(remote_servers, expected_remote_servers);
+
+        // Add a trusted server:
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_requests_sender.send(IncomingAdminRequest {
+            request: AdminRequest::AddServer(IndexServerAddress {
+                public_key: PublicKey::from(&[7; PUBLIC_KEY_LEN]),
+                address: 7u8,
+            }),
+            response_sender,
+        }))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+        assert_eq!(
+            await!(response_receiver).unwrap(),
+            AdminResponse::ServerAdded(true)
+        );
+
+        // The new server is shared with the remote server.
+        // Discovered servers are not shared:
+        match await!(remote_receiver.next()).unwrap() {
+            IndexServerToServer::PeerList(peer_list) => {
+                assert_eq!(peer_list.len(), 1);
+                assert_eq!(peer_list[0].public_key, PublicKey::from(&[7; PUBLIC_KEY_LEN]));
+                assert_eq!(peer_list[0].address, 7);
+                assert!(peer_list[0].seq > 0);
+                assert!(peer_list[0].verify_signature(&local_public_key));
+            }
+            _ => unreachable!(),
+        };
+
+        // Remove the remote server. Servers discovered through it are removed too:
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_requests_sender.send(IncomingAdminRequest {
+            request: AdminRequest::RemoveServer(remote_server_public_key.clone()),
+            response_sender,
+        }))
+        .unwrap();
+        await!(debug_event_receiver.next()).unwrap();
+        assert_eq!(
+            await!(response_receiver).unwrap(),
+            AdminResponse::ServerRemoved(true)
+        );
+
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_requests_sender.send(IncomingAdminRequest {
+            request: AdminRequest::GetStatus,
+            response_sender,
+        }))
+        .unwrap();
+        match await!(graph_requests_receiver.next()).unwrap() {
+            GraphRequest::GetStats(response_sender) => {
+                response_sender
+                    .send(GraphStats {
+                        num_nodes: 0,
+                        num_edges: 0,
+                        route_stats: RouteStats::default(),
+                    })
+                    .unwrap();
+            }
+            _ => unreachable!(),
+        }
+        await!(debug_event_receiver.next()).unwrap();
+        let remote_servers = match await!(response_receiver).unwrap() {
+            AdminResponse::Status(status) => status.remote_servers,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            remote_servers,
+            vec![RemoteServerInfo {
+                public_key: PublicKey::from(&[7; PUBLIC_KEY_LEN]),
+                status: RemoteServerStatus::Listening,
+                opt_voucher: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_index_server_loop_discovery() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_index_server_loop_discovery(thread_pool.clone()));
+    }
+
     // TODO: Add tests.
 }
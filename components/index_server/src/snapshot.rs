@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use futures::channel::mpsc;
+use futures::task::{Spawn, SpawnExt};
+use futures::{future, StreamExt};
+
+use atomicwrites;
+use bincode;
+
+use crypto::identity::PublicKey;
+use crypto::uid::Uid;
+
+use crate::graph::capacity_graph::AgedEdge;
+use crate::verifier::VerifierSnapshot;
+
+/// State of the index server that is saved to disk, allowing the index server to serve routes
+/// immediately after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexServerSnapshot {
+    /// All the directed edges of the capacity graph, together with their age.
+    pub edges: Vec<AgedEdge<PublicKey, u128>>,
+    /// State of the verifier (Time hashes and ratchets).
+    pub verifier: VerifierSnapshot<PublicKey, Uid>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    OpenError(io::Error),
+    ReadError(io::Error),
+    WriteError(atomicwrites::Error<io::Error>),
+    DeserializeError(bincode::Error),
+    SerializeError(bincode::Error),
+    SpawnError,
+}
+
+/// Load an index server snapshot from a file
+pub fn load_snapshot_from_file(path: &Path) -> Result<IndexServerSnapshot, SnapshotError> {
+    let mut f = File::open(path).map_err(SnapshotError::OpenError)?;
+    let mut serialized_buff = Vec::new();
+    f.read_to_end(&mut serialized_buff)
+        .map_err(SnapshotError::ReadError)?;
+
+    bincode::deserialize(&serialized_buff).map_err(SnapshotError::DeserializeError)
+}
+
+/// Store an index server snapshot to a file, atomically.
+/// An existing file will be overwritten.
+pub fn store_snapshot_to_file(
+    snapshot: &IndexServerSnapshot,
+    path: &Path,
+) -> Result<(), SnapshotError> {
+    let serialized_buff = bincode::serialize(snapshot).map_err(SnapshotError::SerializeError)?;
+
+    let af = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+    af.write(|fw| fw.write_all(&serialized_buff))
+        .map_err(SnapshotError::WriteError)?;
+
+    Ok(())
+}
+
+/// Write every incoming snapshot to `path_buf`.
+/// Writing is done using `file_spawner`, to make sure that we don't block the shared thread pool.
+pub async fn snapshot_loop<FS>(
+    path_buf: PathBuf,
+    mut incoming_snapshots: mpsc::Receiver<IndexServerSnapshot>,
+    mut file_spawner: FS,
+) -> Result<(), SnapshotError>
+where
+    FS: Spawn,
+{
+    while let Some(snapshot) = await!(incoming_snapshots.next()) {
+        let c_path_buf = path_buf.clone();
+        let store_fut = future::lazy(move |_| store_snapshot_to_file(&snapshot, &c_path_buf));
+        let handle = file_spawner
+            .spawn_with_handle(store_fut)
+            .map_err(|_| SnapshotError::SpawnError)?;
+
+        if let Err(e) = await!(handle) {
+            // Failing to write a snapshot is not fatal. We will try again with the next snapshot.
+            error!("snapshot_loop(): Failed to store snapshot: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::ThreadPool;
+    use futures::SinkExt;
+    use tempfile::tempdir;
+
+    use crypto::hash::{HashResult, HASH_RESULT_LEN};
+    use crypto::identity::PUBLIC_KEY_LEN;
+    use crypto::uid::UID_LEN;
+
+    fn example_snapshot() -> IndexServerSnapshot {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        IndexServerSnapshot {
+            edges: vec![
+                (pk_a.clone(), pk_b.clone(), (10, 20), 3),
+                (pk_b.clone(), pk_a.clone(), (20, 10), 0),
+            ],
+            verifier: VerifierSnapshot {
+                last_ticks: vec![(
+                    HashResult::from(&[1; HASH_RESULT_LEN]),
+                    vec![HashResult::from(&[2; HASH_RESULT_LEN])],
+                )],
+                ratchets: vec![(pk_a, Uid::from(&[3; UID_LEN]), 7, 5)],
+            },
+        }
+    }
+
+    #[test]
+    fn test_store_load_snapshot() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("snapshot_file");
+
+        // We can not load a nonexistent snapshot:
+        assert!(load_snapshot_from_file(&file_path).is_err());
+
+        let snapshot = example_snapshot();
+        store_snapshot_to_file(&snapshot, &file_path).unwrap();
+        assert_eq!(load_snapshot_from_file(&file_path).unwrap(), snapshot);
+
+        // Storing again overwrites the previous snapshot:
+        let mut snapshot2 = snapshot.clone();
+        snapshot2.edges.pop();
+        store_snapshot_to_file(&snapshot2, &file_path).unwrap();
+        assert_eq!(load_snapshot_from_file(&file_path).unwrap(), snapshot2);
+    }
+
+    async fn task_snapshot_loop_basic<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("snapshot_file");
+
+        let (mut snapshot_sender, incoming_snapshots) = mpsc::channel(0);
+        let loop_fut = snapshot_loop(file_path.clone(), incoming_snapshots, spawner.clone());
+        let loop_res_fut = spawner.spawn_with_handle(loop_fut).unwrap();
+
+        let snapshot = example_snapshot();
+        await!(snapshot_sender.send(snapshot.clone())).unwrap();
+
+        // Closing the sender should close the loop:
+        drop(snapshot_sender);
+        await!(loop_res_fut).unwrap();
+
+        assert_eq!(load_snapshot_from_file(&file_path).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_loop_basic() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_snapshot_loop_basic(thread_pool.clone()));
+    }
+}
@@ -0,0 +1,83 @@
+use std::cmp;
+
+/// A token bucket, refilled every tick.
+/// Used to limit the rate of requests from a single entity.
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: usize,
+    max_tokens: usize,
+    tokens_per_tick: usize,
+}
+
+impl TokenBucket {
+    /// Create a new full token bucket
+    pub fn new(max_tokens: usize, tokens_per_tick: usize) -> Self {
+        TokenBucket {
+            tokens: max_tokens,
+            max_tokens,
+            tokens_per_tick,
+        }
+    }
+
+    /// Try to take one token from the bucket.
+    /// Returns false if the bucket is empty.
+    pub fn try_take(&mut self) -> bool {
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// Refill the bucket (Should be called every tick)
+    pub fn tick(&mut self) {
+        self.tokens = cmp::min(
+            self.tokens.saturating_add(self.tokens_per_tick),
+            self.max_tokens,
+        );
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.tokens >= self.max_tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_basic() {
+        let mut token_bucket = TokenBucket::new(3, 2);
+        assert!(token_bucket.is_full());
+
+        assert!(token_bucket.try_take());
+        assert!(token_bucket.try_take());
+        assert!(token_bucket.try_take());
+        assert!(!token_bucket.try_take());
+        assert!(!token_bucket.is_full());
+
+        token_bucket.tick();
+        assert!(token_bucket.try_take());
+        assert!(token_bucket.try_take());
+        assert!(!token_bucket.try_take());
+
+        // The bucket never holds more than `max_tokens`:
+        token_bucket.tick();
+        token_bucket.tick();
+        assert!(token_bucket.is_full());
+        assert!(token_bucket.try_take());
+        assert!(token_bucket.try_take());
+        assert!(token_bucket.try_take());
+        assert!(!token_bucket.try_take());
+    }
+
+    #[test]
+    fn test_token_bucket_empty() {
+        let mut token_bucket = TokenBucket::new(0, 1);
+        assert!(token_bucket.is_full());
+        assert!(!token_bucket.try_take());
+        token_bucket.tick();
+        assert!(!token_bucket.try_take());
+    }
+}
@@ -0,0 +1,255 @@
+use std::fs;
+use std::io;
+use std::marker::Unpin;
+use std::path::{Path, PathBuf};
+
+use futures::channel::{mpsc, oneshot};
+use futures::task::{Spawn, SpawnExt};
+use futures::{future, SinkExt, Stream, StreamExt};
+
+use crypto::identity::PublicKey;
+
+use proto::file::index_server::{
+    load_index_server_from_file, store_index_server_to_file, IndexServerFileError,
+};
+use proto::file::ser_string::public_key_to_string;
+use proto::index_server::messages::IndexServerAddress;
+use proto::net::messages::NetAddress;
+
+use crate::admin::{AdminRequest, AdminResponse, IncomingAdminRequest};
+
+#[derive(Debug)]
+pub enum TrustedDirError {
+    ReadDirError(io::Error),
+    RemoveFileError(io::Error),
+    StoreFileError(IndexServerFileError),
+    SpawnError,
+}
+
+/// Remove all the files of a trusted server from the trusted servers directory.
+/// Files that can not be loaded are left untouched.
+pub fn remove_trusted_server(
+    dir_path: &Path,
+    public_key: &PublicKey,
+) -> Result<(), TrustedDirError> {
+    for entry in fs::read_dir(dir_path).map_err(TrustedDirError::ReadDirError)? {
+        let path = entry.map_err(TrustedDirError::ReadDirError)?.path();
+        if path.is_dir() {
+            continue;
+        }
+        match load_index_server_from_file(&path) {
+            Ok(index_server_address) if &index_server_address.public_key == public_key => {
+                fs::remove_file(&path).map_err(TrustedDirError::RemoveFileError)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Store a trusted server in the trusted servers directory.
+/// Previous files of the same server are replaced.
+pub fn store_trusted_server(
+    dir_path: &Path,
+    index_server_address: &IndexServerAddress<NetAddress>,
+) -> Result<(), TrustedDirError> {
+    remove_trusted_server(dir_path, &index_server_address.public_key)?;
+    let path = dir_path.join(public_key_to_string(&index_server_address.public_key));
+    store_index_server_to_file(index_server_address, &path).map_err(TrustedDirError::StoreFileError)
+}
+
+/// A change of the trusted servers, applied by the index server
+enum TrustedServersChange {
+    Store(IndexServerAddress<NetAddress>),
+    Remove(PublicKey),
+}
+
+fn apply_change(dir_path: &Path, change: TrustedServersChange) -> Result<(), TrustedDirError> {
+    match change {
+        TrustedServersChange::Store(index_server_address) => {
+            store_trusted_server(dir_path, &index_server_address)
+        }
+        TrustedServersChange::Remove(public_key) => remove_trusted_server(dir_path, &public_key),
+    }
+}
+
+/// Forward admin requests to the index server.
+/// Changes of the trusted servers are applied to the trusted servers directory at `dir_path`,
+/// so that they survive a restart.
+/// Writing is done using `file_spawner`, to make sure that we don't block the shared thread pool.
+pub async fn trusted_dir_loop<IA, FS>(
+    dir_path: PathBuf,
+    local_public_key: PublicKey,
+    mut incoming_admin_requests: IA,
+    mut admin_request_sender: mpsc::Sender<IncomingAdminRequest<NetAddress>>,
+    mut file_spawner: FS,
+) -> Result<(), TrustedDirError>
+where
+    IA: Stream<Item = IncomingAdminRequest<NetAddress>> + Unpin,
+    FS: Spawn,
+{
+    while let Some(incoming_admin_request) = await!(incoming_admin_requests.next()) {
+        let IncomingAdminRequest {
+            request,
+            response_sender,
+        } = incoming_admin_request;
+
+        // We wait for the response of the index server, to know if the change was applied:
+        let c_request = request.clone();
+        let (inner_response_sender, inner_response_receiver) = oneshot::channel();
+        if await!(admin_request_sender.send(IncomingAdminRequest {
+            request,
+            response_sender: inner_response_sender,
+        }))
+        .is_err()
+        {
+            // The index server was closed:
+            return Ok(());
+        }
+        let response = match await!(inner_response_receiver) {
+            Ok(response) => response,
+            Err(_) => continue,
+        };
+
+        let opt_change = match (c_request, &response) {
+            (AdminRequest::AddServer(index_server_address), AdminResponse::ServerAdded(_))
+                if index_server_address.public_key != local_public_key =>
+            {
+                Some(TrustedServersChange::Store(index_server_address))
+            }
+            (AdminRequest::RemoveServer(public_key), AdminResponse::ServerRemoved(true)) => {
+                Some(TrustedServersChange::Remove(public_key))
+            }
+            _ => None,
+        };
+
+        if let Some(change) = opt_change {
+            let c_dir_path = dir_path.clone();
+            let store_fut = future::lazy(move |_| apply_change(&c_dir_path, change));
+            let handle = file_spawner
+                .spawn_with_handle(store_fut)
+                .map_err(|_| TrustedDirError::SpawnError)?;
+            if let Err(e) = await!(handle) {
+                // The change was applied by the index server. It will be lost after a restart.
+                error!(
+                    "trusted_dir_loop(): Failed to update the trusted servers directory: {:?}",
+                    e
+                );
+            }
+        }
+
+        let _ = response_sender.send(response);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    use futures::executor::ThreadPool;
+    use tempfile::tempdir;
+
+    use crypto::identity::PUBLIC_KEY_LEN;
+
+    use proto::file::index_server::load_trusted_servers;
+
+    async fn task_trusted_dir_loop_basic<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let dir = tempdir().unwrap();
+
+        let (mut admin_sender, incoming_admin_requests) = mpsc::channel(0);
+        let (admin_request_sender, mut admin_receiver) = mpsc::channel(0);
+        let loop_fut = trusted_dir_loop(
+            dir.path().to_path_buf(),
+            PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            incoming_admin_requests,
+            admin_request_sender,
+            spawner.clone(),
+        );
+        let loop_res_fut = spawner.spawn_with_handle(loop_fut).unwrap();
+
+        let index_server_address = IndexServerAddress {
+            public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            address: "127.0.0.1:1337".to_owned().try_into().unwrap(),
+        };
+
+        // Add a server:
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_sender.send(IncomingAdminRequest {
+            request: AdminRequest::AddServer(index_server_address.clone()),
+            response_sender,
+        }))
+        .unwrap();
+        let incoming_admin_request = await!(admin_receiver.next()).unwrap();
+        assert_eq!(
+            incoming_admin_request.request,
+            AdminRequest::AddServer(index_server_address.clone())
+        );
+        incoming_admin_request
+            .response_sender
+            .send(AdminResponse::ServerAdded(true))
+            .unwrap();
+        assert_eq!(
+            await!(response_receiver).unwrap(),
+            AdminResponse::ServerAdded(true)
+        );
+        assert_eq!(
+            load_trusted_servers(dir.path()).unwrap(),
+            vec![index_server_address.clone()]
+        );
+
+        // Change the address of the server. The old file is replaced:
+        let index_server_address = IndexServerAddress {
+            public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            address: "127.0.0.1:1338".to_owned().try_into().unwrap(),
+        };
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_sender.send(IncomingAdminRequest {
+            request: AdminRequest::AddServer(index_server_address.clone()),
+            response_sender,
+        }))
+        .unwrap();
+        let incoming_admin_request = await!(admin_receiver.next()).unwrap();
+        incoming_admin_request
+            .response_sender
+            .send(AdminResponse::ServerAdded(false))
+            .unwrap();
+        await!(response_receiver).unwrap();
+        assert_eq!(
+            load_trusted_servers(dir.path()).unwrap(),
+            vec![index_server_address.clone()]
+        );
+
+        // Remove the server:
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(admin_sender.send(IncomingAdminRequest {
+            request: AdminRequest::RemoveServer(PublicKey::from(&[0xbb; PUBLIC_KEY_LEN])),
+            response_sender,
+        }))
+        .unwrap();
+        let incoming_admin_request = await!(admin_receiver.next()).unwrap();
+        incoming_admin_request
+            .response_sender
+            .send(AdminResponse::ServerRemoved(true))
+            .unwrap();
+        assert_eq!(
+            await!(response_receiver).unwrap(),
+            AdminResponse::ServerRemoved(true)
+        );
+        assert!(load_trusted_servers(dir.path()).unwrap().is_empty());
+
+        // Closing the sender should close the loop:
+        drop(admin_sender);
+        await!(loop_res_fut).unwrap();
+    }
+
+    #[test]
+    fn test_trusted_dir_loop_basic() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_trusted_dir_loop_basic(thread_pool.clone()));
+    }
+}
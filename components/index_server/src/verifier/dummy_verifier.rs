@@ -1,6 +1,6 @@
 use std::marker::PhantomData;
 
-use super::verifier::Verifier;
+use super::verifier::{Verifier, VerifierSnapshot};
 use crypto::hash::{HashResult, HASH_RESULT_LEN};
 
 pub struct DummyVerifier<N, B, U> {
@@ -59,4 +59,12 @@ impl<N, B, U> Verifier for DummyVerifier<N, B, U> {
         // Nothing happens
         None
     }
+
+    fn snapshot(&self) -> VerifierSnapshot<N, U> {
+        // Nothing to remember
+        VerifierSnapshot {
+            last_ticks: Vec::new(),
+            ratchets: Vec::new(),
+        }
+    }
 }
@@ -59,6 +59,26 @@ where
         self.last_ticks_map.insert(tick_hash.clone(), expansion);
     }
 
+    /// Get all the tick hashes we remember (Oldest first), together with their expansions.
+    pub fn get_last_ticks(&self) -> Vec<(HashResult, Vec<HashResult>)> {
+        self.last_ticks
+            .iter()
+            .filter_map(|tick_hash| {
+                self.last_ticks_map
+                    .get(tick_hash)
+                    .map(|expansion| (tick_hash.clone(), expansion.clone()))
+            })
+            .collect()
+    }
+
+    /// Restore tick hashes (Previously obtained using `get_last_ticks()`).
+    /// Tick hashes should be ordered from the oldest to the newest.
+    pub fn load_last_ticks(&mut self, last_ticks: Vec<(HashResult, Vec<HashResult>)>) {
+        for (tick_hash, expansion) in last_ticks {
+            self.insert_tick_hash(tick_hash, expansion);
+        }
+    }
+
     /// Should be called when a new hash is received from a neighbor.
     pub fn neighbor_tick(&mut self, neighbor: N, tick_hash: HashResult) -> Option<HashResult> {
         self.neighbor_hashes.insert(neighbor, tick_hash)
@@ -205,4 +225,31 @@ mod tests {
             .verify_expansion_chain(&origin_tick_hash, &[&expansion1, &expansion2, &expansion3])
             .is_none());
     }
+
+    #[test]
+    fn test_hash_clock_get_load_last_ticks() {
+        let last_ticks_max_len = 4;
+        let mut hash_clock = HashClock::<u32>::new(last_ticks_max_len);
+
+        let mut tick_hashes = Vec::new();
+        for iter in 0..6 {
+            let rand_value = RandValue::from(&[iter as u8; RAND_VALUE_LEN]);
+            tick_hashes.push(hash_clock.tick(rand_value));
+        }
+
+        let last_ticks = hash_clock.get_last_ticks();
+        assert_eq!(last_ticks.len(), last_ticks_max_len);
+        assert_eq!(last_ticks.last().unwrap().0, tick_hashes[5]);
+
+        let mut hash_clock2 = HashClock::<u32>::new(last_ticks_max_len);
+        hash_clock2.load_last_ticks(last_ticks);
+
+        // Tick hashes created before the restore are still recognized:
+        for tick_hash in &tick_hashes[2..] {
+            assert!(hash_clock2.verify_expansion_chain(tick_hash, &[]).is_some());
+        }
+        assert!(hash_clock2
+            .verify_expansion_chain(&tick_hashes[1], &[])
+            .is_none());
+    }
 }
@@ -5,4 +5,4 @@ mod ratchet;
 pub mod simple_verifier;
 mod verifier;
 
-pub use self::verifier::Verifier;
+pub use self::verifier::{Verifier, VerifierSnapshot};
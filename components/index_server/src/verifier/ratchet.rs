@@ -87,6 +87,28 @@ where
         removed_nodes
     }
 
+    /// Get the state of all ratchets, as (node, session_id, counter, cur_ticks_to_live) tuples.
+    pub fn get_ratchets(&self) -> Vec<(N, U, u64, usize)> {
+        self.ratchets
+            .iter()
+            .map(|(node, ratchet)| {
+                (
+                    node.clone(),
+                    ratchet.session_id.clone(),
+                    ratchet.counter,
+                    ratchet.cur_ticks_to_live,
+                )
+            })
+            .collect()
+    }
+
+    /// Restore the state of a ratchet (Previously obtained using `get_ratchets()`).
+    pub fn load_ratchet(&mut self, node: N, session_id: U, counter: u64, cur_ticks_to_live: usize) {
+        let mut ratchet = Ratchet::new(session_id, counter, self.ratchet_ticks_to_live);
+        ratchet.cur_ticks_to_live = cur_ticks_to_live;
+        self.ratchets.insert(node, ratchet);
+    }
+
     /// Try to update a certain ratchet.
     /// Returns true if ratchet moved forward (Or created)
     pub fn update(&mut self, node: &N, session_id: &U, counter: u64) -> bool {
@@ -234,4 +256,36 @@ mod tests {
         // A proof that node 1u128 was not removed:
         assert!(!ratchet_pool.update(&1u128, &5u128, 101));
     }
+
+    #[test]
+    fn test_ratchet_pool_get_load() {
+        let ratchet_ticks_to_live = 8;
+        let mut ratchet_pool = RatchetPool::new(ratchet_ticks_to_live);
+
+        assert!(ratchet_pool.update(&0u128, &0u128, 3));
+        assert!(ratchet_pool.update(&1u128, &5u128, 100));
+        for _ in 0..4 {
+            assert_eq!(ratchet_pool.tick(), vec![]);
+        }
+        assert!(ratchet_pool.update(&1u128, &5u128, 101));
+
+        let mut ratchets = ratchet_pool.get_ratchets();
+        ratchets.sort();
+        assert_eq!(ratchets, vec![(0u128, 0u128, 3, 4), (1u128, 5u128, 101, 8)]);
+
+        let mut ratchet_pool2 = RatchetPool::new(ratchet_ticks_to_live);
+        for (node, session_id, counter, cur_ticks_to_live) in ratchets {
+            ratchet_pool2.load_ratchet(node, session_id, counter, cur_ticks_to_live);
+        }
+
+        // Old messages are still rejected:
+        assert!(!ratchet_pool2.update(&0u128, &0u128, 3));
+        assert!(!ratchet_pool2.update(&1u128, &5u128, 101));
+
+        // Loaded ratchets keep their remaining ticks to live:
+        for _ in 0..3 {
+            assert_eq!(ratchet_pool2.tick(), vec![]);
+        }
+        assert_eq!(ratchet_pool2.tick(), vec![0u128]);
+    }
 }
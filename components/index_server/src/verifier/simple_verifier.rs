@@ -3,7 +3,7 @@ use crypto::hash::HashResult;
 
 use super::hash_clock::HashClock;
 use super::ratchet::RatchetPool;
-use super::verifier::Verifier;
+use super::verifier::{Verifier, VerifierSnapshot};
 
 pub struct SimpleVerifier<N, B, U, R> {
     hash_clock: HashClock<B>,
@@ -31,6 +31,20 @@ where
             rng,
         }
     }
+
+    /// Create a SimpleVerifier from a previously saved snapshot.
+    pub fn from_snapshot(ticks_to_live: usize, rng: R, snapshot: VerifierSnapshot<N, U>) -> Self {
+        let mut simple_verifier = SimpleVerifier::new(ticks_to_live, rng);
+        simple_verifier
+            .hash_clock
+            .load_last_ticks(snapshot.last_ticks);
+        for (node, session_id, counter, cur_ticks_to_live) in snapshot.ratchets {
+            simple_verifier
+                .ratchet_pool
+                .load_ratchet(node, session_id, counter, cur_ticks_to_live);
+        }
+        simple_verifier
+    }
 }
 
 impl<N, B, U, R> Verifier for SimpleVerifier<N, B, U, R>
@@ -79,6 +93,13 @@ where
     fn remove_neighbor(&mut self, neighbor: &B) -> Option<HashResult> {
         self.hash_clock.remove_neighbor(neighbor)
     }
+
+    fn snapshot(&self) -> VerifierSnapshot<N, U> {
+        VerifierSnapshot {
+            last_ticks: self.hash_clock.get_last_ticks(),
+            ratchets: self.ratchet_pool.get_ratchets(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -137,5 +158,44 @@ mod tests {
             .to_vec();
     }
 
+    #[test]
+    fn test_simple_verifier_snapshot() {
+        let ticks_to_live = 8;
+
+        let rng = DummyRandom::new(&[0u8]);
+        let mut sv = SimpleVerifier::<u128, u128, u128, _>::new(ticks_to_live, rng);
+        let (tick_hash, _removed) = sv.tick();
+        assert!(sv
+            .verify(&tick_hash, &[], &1234u128, &0u128, 5u64)
+            .is_some());
+
+        let snapshot = sv.snapshot();
+        assert_eq!(
+            snapshot.ratchets,
+            vec![(1234u128, 0u128, 5u64, ticks_to_live)]
+        );
+
+        let rng = DummyRandom::new(&[1u8]);
+        let mut sv2 =
+            SimpleVerifier::<u128, u128, u128, _>::from_snapshot(ticks_to_live, rng, snapshot);
+
+        // Replay is rejected after restore:
+        assert!(sv2
+            .verify(&tick_hash, &[], &1234u128, &0u128, 5u64)
+            .is_none());
+        // New messages signed over an old tick hash are accepted:
+        assert!(sv2
+            .verify(&tick_hash, &[], &1234u128, &0u128, 6u64)
+            .is_some());
+
+        // The restored node eventually times out:
+        for _ in 0..ticks_to_live - 1 {
+            let (_tick_hash, removed) = sv2.tick();
+            assert!(removed.is_empty());
+        }
+        let (_tick_hash, removed) = sv2.tick();
+        assert_eq!(removed, vec![1234u128]);
+    }
+
     // TODO: Add more tests?
 }
@@ -1,5 +1,14 @@
 use crypto::hash::HashResult;
 
+/// State of a verifier that can be saved and later used to restore the verifier.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifierSnapshot<N, U> {
+    /// Last tick hashes created locally (Oldest first), together with their expansions.
+    pub last_ticks: Vec<(HashResult, Vec<HashResult>)>,
+    /// (node, session_id, counter, cur_ticks_to_live) for every node we track.
+    pub ratchets: Vec<(N, U, u64, usize)>,
+}
+
 pub trait Verifier {
     type Node;
     type Neighbor;
@@ -32,4 +41,8 @@ pub trait Verifier {
     /// Remove a neighbor. This method should be invoked when a neighbor disconnects.
     /// If not called, the time proofs (list of hashes) will be larger than needed.
     fn remove_neighbor(&mut self, neighbor: &Self::Neighbor) -> Option<HashResult>;
+
+    /// Export the current state of the verifier.
+    /// Neighbors' hashes are not included, as neighbors resend them after reconnecting.
+    fn snapshot(&self) -> VerifierSnapshot<Self::Node, Self::SessionId>;
 }
@@ -2,9 +2,11 @@ use futures::channel::mpsc;
 use futures::task::{Spawn, SpawnExt};
 use futures::{future, stream, FutureExt, Sink, SinkExt, Stream, StreamExt, TryFutureExt};
 use std::marker::Unpin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use timer::{TimerClient, TimerTick};
 
-use common::conn::{BoxFuture, ConnPair, FutTransform};
+use common::conn::{BoxFuture, ConnPair, ConnPairVec, FutTransform};
 use common::select_streams::{select_streams, BoxStream};
 
 use proto::keepalive::messages::KaMessage;
@@ -17,6 +19,41 @@ pub enum KeepAliveError {
     DeserializeError,
 }
 
+/// Statistics collected by the keepalive layer about a single connection.
+#[derive(Debug, Clone, Default)]
+pub struct KeepAliveStats {
+    last_rtt: Arc<Mutex<Option<Duration>>>,
+}
+
+impl KeepAliveStats {
+    pub fn new() -> Self {
+        KeepAliveStats::default()
+    }
+
+    /// Round trip time of the last answered keepalive.
+    /// `None` if no keepalive was answered yet. This is always the case if the remote side does
+    /// not support keepalive probes.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        *self.last_rtt.lock().unwrap()
+    }
+
+    fn set_last_rtt(&self, rtt: Duration) {
+        *self.last_rtt.lock().unwrap() = Some(rtt);
+    }
+}
+
+/// A connection that performs keepalives, together with its keepalive statistics.
+pub struct KeepAliveConn {
+    pub conn_pair: ConnPairVec,
+    pub stats: KeepAliveStats,
+}
+
+impl From<KeepAliveConn> for ConnPairVec {
+    fn from(keepalive_conn: KeepAliveConn) -> Self {
+        keepalive_conn.conn_pair
+    }
+}
+
 #[derive(Debug, Clone)]
 enum KeepAliveEvent {
     TimerTick,
@@ -56,6 +93,7 @@ async fn inner_keepalive_loop<TR, FR, TU, FU, TS>(
     from_user: FU,
     timer_stream: TS,
     keepalive_ticks: usize,
+    stats: KeepAliveStats,
     mut opt_event_sender: Option<mpsc::Sender<KeepAliveEvent>>,
 ) -> Result<(), KeepAliveError>
 where
@@ -88,6 +126,10 @@ where
     // Amount of ticks remaining until we need to send a new keepalive (To make sure remote side
     // knows we are alive).
     let mut ticks_to_send_keepalive = keepalive_ticks / 2;
+    // Every keepalive we send is a ping. We remember the last ping we sent, to be able to measure
+    // the round trip time once the remote side answers.
+    let mut next_ping: u64 = 0;
+    let mut opt_pending_ping: Option<(u64, Instant)> = None;
 
     while let Some(event) = await!(events.next()) {
         if let Some(ref mut event_sender) = opt_event_sender {
@@ -98,10 +140,29 @@ where
                 let ka_message = deserialize_ka_message(&ser_ka_message)
                     .map_err(|_| KeepAliveError::DeserializeError)?;
                 ticks_to_close = keepalive_ticks;
-                if let KaMessage::Message(message) = ka_message {
-                    if await!(to_user.send(message)).is_err() {
-                        warn!("keepalive_loop(): Can not send to local side");
-                        break;
+                match ka_message {
+                    KaMessage::KeepAlive => {}
+                    KaMessage::Message(message) => {
+                        if await!(to_user.send(message)).is_err() {
+                            warn!("keepalive_loop(): Can not send to local side");
+                            break;
+                        }
+                    }
+                    KaMessage::Ping(ping) => {
+                        let ser_ka_message = serialize_ka_message(&KaMessage::Pong(ping));
+                        if await!(to_remote.send(ser_ka_message)).is_err() {
+                            warn!("keepalive_loop(): Can not send to remote side");
+                            break;
+                        }
+                    }
+                    KaMessage::Pong(pong) => {
+                        // We only measure answers to our last ping:
+                        if let Some((ping, sent_instant)) = opt_pending_ping {
+                            if ping == pong {
+                                stats.set_last_rtt(sent_instant.elapsed());
+                                opt_pending_ping = None;
+                            }
+                        }
                     }
                 }
             }
@@ -121,7 +182,9 @@ where
                     return Err(KeepAliveError::RemoteTimeout);
                 }
                 if ticks_to_send_keepalive == 0 {
-                    let ka_message = KaMessage::KeepAlive;
+                    let ka_message = KaMessage::Ping(next_ping);
+                    opt_pending_ping = Some((next_ping, Instant::now()));
+                    next_ping = next_ping.wrapping_add(1);
                     let ser_ka_message = serialize_ka_message(&ka_message);
                     if await!(to_remote.send(ser_ka_message)).is_err() {
                         warn!("Keepalive_loop(): Can not send to remote side");
@@ -167,11 +230,12 @@ where
     fn transform_keepalive(
         &mut self,
         conn_pair: ConnPair<Vec<u8>, Vec<u8>>,
-    ) -> BoxFuture<'_, ConnPair<Vec<u8>, Vec<u8>>> {
+    ) -> BoxFuture<'_, KeepAliveConn> {
         let (to_remote, from_remote) = conn_pair;
 
         let (to_user, user_receiver) = mpsc::channel::<Vec<u8>>(0);
         let (user_sender, from_user) = mpsc::channel::<Vec<u8>>(0);
+        let stats = KeepAliveStats::new();
 
         Box::pin(
             async move {
@@ -183,6 +247,7 @@ where
                         from_user,
                         timer_stream,
                         self.keepalive_ticks,
+                        stats.clone(),
                         None,
                     )
                     .map_err(|e| {
@@ -200,7 +265,10 @@ where
                     warn!("transform_keepalive(): Error requesting timer stream");
                 }
 
-                (user_sender, user_receiver)
+                KeepAliveConn {
+                    conn_pair: (user_sender, user_receiver),
+                    stats,
+                }
             },
         )
     }
@@ -214,7 +282,34 @@ where
     type Output = ConnPair<Vec<u8>, Vec<u8>>;
 
     fn transform(&mut self, input: Self::Input) -> BoxFuture<'_, Self::Output> {
-        self.transform_keepalive(input)
+        Box::pin(
+            self.transform_keepalive(input)
+                .map(|keepalive_conn| keepalive_conn.conn_pair),
+        )
+    }
+}
+
+/// Same as `KeepAliveChannel`, but also outputs the keepalive statistics of every connection.
+#[derive(Clone)]
+pub struct KeepAliveStatsChannel<S> {
+    keepalive_channel: KeepAliveChannel<S>,
+}
+
+impl<S> KeepAliveStatsChannel<S> {
+    pub fn new(keepalive_channel: KeepAliveChannel<S>) -> Self {
+        KeepAliveStatsChannel { keepalive_channel }
+    }
+}
+
+impl<S> FutTransform for KeepAliveStatsChannel<S>
+where
+    S: Spawn + Send,
+{
+    type Input = ConnPairVec;
+    type Output = KeepAliveConn;
+
+    fn transform(&mut self, input: Self::Input) -> BoxFuture<'_, Self::Output> {
+        self.keepalive_channel.transform_keepalive(input)
     }
 }
 
@@ -251,6 +346,7 @@ mod tests {
             from_user,
             timer_stream,
             keepalive_ticks,
+            KeepAliveStats::new(),
             None,
         )
         .map_err(|e| error!("[KeepAlive] inner_keepalive_loop() error: {:?}", e))
@@ -276,6 +372,7 @@ mod tests {
 
         let timer_stream = await!(timer_client.request_timer_stream()).unwrap();
         let keepalive_ticks = 16;
+        let stats = KeepAliveStats::new();
         let fut_keepalive_loop = inner_keepalive_loop(
             to_remote,
             from_remote,
@@ -283,6 +380,7 @@ mod tests {
             from_user,
             timer_stream,
             keepalive_ticks,
+            stats.clone(),
             Some(event_sender),
         )
         // .map_err(|e| println!("client_tunnel error: {:?}", e))
@@ -319,13 +417,23 @@ mod tests {
 
         // We expect to see a keepalive being sent:
         let vec = await!(remote_receiver.next()).unwrap();
-        assert_eq!(vec, serialize_ka_message(&KaMessage::KeepAlive));
+        assert_eq!(vec, serialize_ka_message(&KaMessage::Ping(0)));
+        assert!(stats.last_rtt().is_none());
 
-        // Remote sends a keepalive:
-        let vec = serialize_ka_message(&KaMessage::KeepAlive);
+        // Remote answers the keepalive:
+        let vec = serialize_ka_message(&KaMessage::Pong(0));
         await!(remote_sender.send(vec)).unwrap();
         await!(event_receiver.next()).unwrap();
 
+        // Remote sends a keepalive, expecting an answer:
+        let vec = serialize_ka_message(&KaMessage::Ping(7));
+        await!(remote_sender.send(vec)).unwrap();
+        await!(event_receiver.next()).unwrap();
+        let vec = await!(remote_receiver.next()).unwrap();
+        assert_eq!(vec, serialize_ka_message(&KaMessage::Pong(7)));
+        // Our ping was answered before:
+        assert!(stats.last_rtt().is_some());
+
         // Move time forward
         for _ in 0..16usize {
             await!(tick_sender.send(())).unwrap();
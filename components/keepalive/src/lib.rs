@@ -21,4 +21,4 @@ extern crate common;
 
 mod keepalive;
 
-pub use self::keepalive::KeepAliveChannel;
+pub use self::keepalive::{KeepAliveChannel, KeepAliveConn, KeepAliveStats, KeepAliveStatsChannel};
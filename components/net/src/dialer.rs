@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+use futures::channel::mpsc;
+use futures::task::{Spawn, SpawnExt};
+use futures::{stream, SinkExt, StreamExt};
+
+use tokio::net::TcpStream;
+
+use common::conn::FutTransform;
+use proto::consts::TICK_MS;
+use proto::net::messages::NetAddress;
+use timer::utils::future_timeout;
+use timer::TimerClient;
+
+use crate::resolver::Resolver;
+use crate::socks5::{socks5_connect, split_host_port};
+use crate::utils::tcp_connect;
+
+/// Amount of ticks to wait for a connection attempt before starting a parallel attempt to the
+/// next address.
+const ATTEMPT_DELAY_TICKS: usize = 1;
+
+/// Amount of ticks before a single connection attempt is abandoned.
+const ATTEMPT_TIMEOUT_TICKS: usize = 8;
+
+/// Amount of ticks before a connection through a SOCKS5 proxy is abandoned.
+/// This includes the handshake with the proxy, and the proxy's connection to the destination,
+/// which may be slow for proxies like Tor.
+const SOCKS5_TIMEOUT_TICKS: usize = 0x20;
+
+/// Addresses that failed to connect are tried last during this amount of ticks.
+const FAILED_ADDRESS_TICKS: usize = 10 * 60 * (1000 / TICK_MS); // 10 minutes
+
+#[derive(Debug)]
+enum DialEvent {
+    TimerTick,
+    AttemptDone((SocketAddr, Option<TcpStream>)),
+}
+
+/// Order addresses for connection attempts.
+/// Address families are interleaved (Starting with the family of the first address), and
+/// addresses that failed recently are moved to the end.
+fn sort_addrs(
+    socket_addrs: Vec<SocketAddr>,
+    failed_addrs: &HashMap<SocketAddr, usize>,
+) -> Vec<SocketAddr> {
+    let (good_addrs, bad_addrs): (Vec<_>, Vec<_>) = socket_addrs
+        .into_iter()
+        .partition(|socket_addr| !failed_addrs.contains_key(socket_addr));
+
+    let mut sorted_addrs = interleave_families(good_addrs);
+    sorted_addrs.extend(interleave_families(bad_addrs));
+    sorted_addrs
+}
+
+fn interleave_families(socket_addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match socket_addrs.first() {
+        Some(socket_addr) => socket_addr.is_ipv6(),
+        None => return Vec::new(),
+    };
+    let (first_family, second_family): (Vec<_>, Vec<_>) = socket_addrs
+        .into_iter()
+        .partition(|socket_addr| socket_addr.is_ipv6() == first_is_ipv6);
+
+    let mut first_iter = first_family.into_iter();
+    let mut second_iter = second_family.into_iter();
+    let mut interleaved = Vec::new();
+    loop {
+        let opt_first = first_iter.next();
+        let opt_second = second_iter.next();
+        if opt_first.is_none() && opt_second.is_none() {
+            return interleaved;
+        }
+        interleaved.extend(opt_first);
+        interleaved.extend(opt_second);
+    }
+}
+
+/// Forget failed addresses once their amount of ticks is over.
+/// Returns when the dialer (And all of its clones) is dropped.
+async fn expire_failed_addrs(
+    weak_failed_addrs: Weak<Mutex<HashMap<SocketAddr, usize>>>,
+    mut timer_client: TimerClient,
+) {
+    let mut timer_stream = match await!(timer_client.request_timer_stream()) {
+        Ok(timer_stream) => timer_stream,
+        Err(e) => {
+            error!(
+                "expire_failed_addrs(): request_timer_stream() error: {:?}",
+                e
+            );
+            return;
+        }
+    };
+    while let Some(_) = await!(timer_stream.next()) {
+        let failed_addrs = match weak_failed_addrs.upgrade() {
+            Some(failed_addrs) => failed_addrs,
+            None => return,
+        };
+        failed_addrs.lock().unwrap().retain(|_, ticks_left| {
+            *ticks_left = ticks_left.saturating_sub(1);
+            *ticks_left > 0
+        });
+    }
+}
+
+/// Open TCP connections to `host:port` addresses.
+///
+/// All the resolved addresses are tried, using staggered parallel attempts (Happy Eyeballs):
+/// A new attempt is started whenever the previous attempt fails or takes too long, and the first
+/// successful connection is used.
+///
+/// If a SOCKS5 proxy is configured, all connections go through the proxy, and host names are
+/// resolved by the proxy. Otherwise, if a local address is configured, connections are made from
+/// this local address (See `tcp_connect`).
+#[derive(Clone)]
+pub struct TcpDialer<S, RS> {
+    resolver: Resolver<RS>,
+    opt_socks5_proxy: Option<SocketAddr>,
+    opt_local_addr: Option<SocketAddr>,
+    timer_client: TimerClient,
+    /// Addresses that failed to connect recently, and the amount of ticks left until they are
+    /// forgotten
+    failed_addrs: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+    spawner: S,
+}
+
+impl<S, RS> TcpDialer<S, RS>
+where
+    S: Spawn + Send,
+    RS: Spawn + Send,
+{
+    pub fn new(
+        opt_socks5_proxy: Option<SocketAddr>,
+        opt_local_addr: Option<SocketAddr>,
+        timer_client: TimerClient,
+        resolve_spawner: RS,
+        mut spawner: S,
+    ) -> Self {
+        let failed_addrs = Arc::new(Mutex::new(HashMap::new()));
+        let expire_fut = expire_failed_addrs(Arc::downgrade(&failed_addrs), timer_client.clone());
+        if spawner.spawn(expire_fut).is_err() {
+            error!("TcpDialer::new(): Failed to spawn expire_failed_addrs()");
+        }
+
+        TcpDialer {
+            resolver: Resolver::new(resolve_spawner),
+            opt_socks5_proxy,
+            opt_local_addr,
+            timer_client,
+            failed_addrs,
+            spawner,
+        }
+    }
+
+    pub async fn dial(&mut self, host_port: String) -> Option<TcpStream> {
+        if let Some(proxy_addr) = self.opt_socks5_proxy {
+            let (host, port) = split_host_port(&host_port)?;
+            let timer_stream = await!(self.timer_client.request_timer_stream()).ok()?;
+            let connect_fut = Box::pin(socks5_connect(proxy_addr, host, port));
+            return match await!(future_timeout(
+                connect_fut,
+                timer_stream,
+                SOCKS5_TIMEOUT_TICKS
+            )) {
+                Some(Ok(tcp_stream)) => Some(tcp_stream),
+                Some(Err(e)) => {
+                    warn!("TcpDialer: SOCKS5 connect to {} failed: {:?}", host_port, e);
+                    None
+                }
+                None => {
+                    warn!("TcpDialer: SOCKS5 connect to {} timed out", host_port);
+                    None
+                }
+            };
+        }
+
+        let socket_addrs = await!(self
+            .resolver
+            .transform(NetAddress::try_from(host_port).ok()?));
+        await!(self.dial_addrs(socket_addrs))
+    }
+
+    /// Start a connection attempt to `socket_addr`.
+    /// The result is sent through `attempt_sender`.
+    fn start_attempt(
+        &mut self,
+        socket_addr: SocketAddr,
+        mut attempt_sender: mpsc::Sender<(SocketAddr, Option<TcpStream>)>,
+    ) -> Option<()> {
+        let mut timer_client = self.timer_client.clone();
+        let opt_local_addr = self.opt_local_addr;
+        let attempt_fut = async move {
+            let opt_tcp_stream = match await!(timer_client.request_timer_stream()) {
+                Ok(timer_stream) => {
+                    let connect_fut = Box::pin(tcp_connect(opt_local_addr, socket_addr));
+                    await!(future_timeout(
+                        connect_fut,
+                        timer_stream,
+                        ATTEMPT_TIMEOUT_TICKS
+                    ))
+                    .and_then(Result::ok)
+                }
+                Err(_) => None,
+            };
+            // The dial might be already over, in which case this connection is dropped:
+            let _ = await!(attempt_sender.send((socket_addr, opt_tcp_stream)));
+        };
+        self.spawner.spawn(attempt_fut).ok()
+    }
+
+    async fn dial_addrs(&mut self, socket_addrs: Vec<SocketAddr>) -> Option<TcpStream> {
+        let socket_addrs = sort_addrs(socket_addrs, &self.failed_addrs.lock().unwrap());
+        let mut pending_addrs = socket_addrs.into_iter();
+
+        let timer_stream = await!(self.timer_client.request_timer_stream()).ok()?;
+        let (attempt_sender, attempt_receiver) = mpsc::channel(0);
+        let mut events = stream::select(
+            timer_stream.map(|_| DialEvent::TimerTick),
+            attempt_receiver.map(DialEvent::AttemptDone),
+        );
+
+        self.start_attempt(pending_addrs.next()?, attempt_sender.clone())?;
+        let mut attempts_in_progress: usize = 1;
+        let mut ticks_since_attempt: usize = 0;
+
+        while let Some(event) = await!(events.next()) {
+            let start_next = match event {
+                DialEvent::TimerTick => {
+                    ticks_since_attempt = ticks_since_attempt.saturating_add(1);
+                    ticks_since_attempt >= ATTEMPT_DELAY_TICKS
+                }
+                DialEvent::AttemptDone((socket_addr, Some(tcp_stream))) => {
+                    self.failed_addrs.lock().unwrap().remove(&socket_addr);
+                    return Some(tcp_stream);
+                }
+                DialEvent::AttemptDone((socket_addr, None)) => {
+                    debug!("TcpDialer: Failed connecting to {:?}", socket_addr);
+                    self.failed_addrs
+                        .lock()
+                        .unwrap()
+                        .insert(socket_addr, FAILED_ADDRESS_TICKS);
+                    attempts_in_progress -= 1;
+                    true
+                }
+            };
+
+            if start_next {
+                if let Some(socket_addr) = pending_addrs.next() {
+                    self.start_attempt(socket_addr, attempt_sender.clone())?;
+                    attempts_in_progress += 1;
+                    ticks_since_attempt = 0;
+                } else if attempts_in_progress == 0 {
+                    return None;
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::ThreadPool;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener};
+
+    use timer::create_timer_incoming;
+
+    fn v4(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+    }
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), port)
+    }
+
+    #[test]
+    fn test_sort_addrs() {
+        let socket_addrs = vec![v6(1), v6(2), v6(3), v4(4), v4(5)];
+        let failed_addrs = HashMap::new();
+        assert_eq!(
+            sort_addrs(socket_addrs.clone(), &failed_addrs),
+            vec![v6(1), v4(4), v6(2), v4(5), v6(3)]
+        );
+
+        let mut failed_addrs = HashMap::new();
+        failed_addrs.insert(v6(1), FAILED_ADDRESS_TICKS);
+        failed_addrs.insert(v4(5), FAILED_ADDRESS_TICKS);
+        assert_eq!(
+            sort_addrs(socket_addrs, &failed_addrs),
+            vec![v6(2), v4(4), v6(3), v6(1), v4(5)]
+        );
+    }
+
+    async fn task_dial_addrs_fallback<S>(spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        // An address nobody listens on:
+        let dead_addr = v4(TcpListener::bind(v4(0)).unwrap().local_addr().unwrap().port());
+        // A listening address. The kernel completes the handshake even if we never accept:
+        let listener = TcpListener::bind(v4(0)).unwrap();
+        let live_addr = listener.local_addr().unwrap();
+
+        // Time never advances:
+        let (_tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let mut dialer = TcpDialer::new(None, None, timer_client, spawner.clone(), spawner.clone());
+        let tcp_stream = await!(dialer.dial_addrs(vec![dead_addr, live_addr])).unwrap();
+        assert_eq!(tcp_stream.peer_addr().unwrap(), live_addr);
+
+        // The dead address is remembered, and will be tried last next time:
+        assert!(dialer.failed_addrs.lock().unwrap().contains_key(&dead_addr));
+        assert!(!dialer.failed_addrs.lock().unwrap().contains_key(&live_addr));
+
+        assert!(await!(dialer.dial_addrs(vec![dead_addr])).is_none());
+        assert!(await!(dialer.dial_addrs(vec![])).is_none());
+    }
+
+    #[test]
+    fn test_dial_addrs_fallback() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_dial_addrs_fallback(thread_pool.clone()));
+    }
+
+    async fn task_dial_addrs_failed_expire<S>(spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let dead_addr = v4(TcpListener::bind(v4(0)).unwrap().local_addr().unwrap().port());
+
+        let (mut tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let mut dialer = TcpDialer::new(None, None, timer_client, spawner.clone(), spawner.clone());
+        assert!(await!(dialer.dial_addrs(vec![dead_addr])).is_none());
+        assert!(dialer.failed_addrs.lock().unwrap().contains_key(&dead_addr));
+
+        // The failed address is remembered for FAILED_ADDRESS_TICKS:
+        let mut num_ticks: usize = 0;
+        while num_ticks < FAILED_ADDRESS_TICKS / 2 {
+            await!(tick_sender.send(())).unwrap();
+            num_ticks += 1;
+        }
+        assert!(dialer.failed_addrs.lock().unwrap().contains_key(&dead_addr));
+
+        // The failed address is eventually forgotten:
+        while dialer.failed_addrs.lock().unwrap().contains_key(&dead_addr) {
+            await!(tick_sender.send(())).unwrap();
+            num_ticks += 1;
+        }
+        assert!(num_ticks >= FAILED_ADDRESS_TICKS);
+    }
+
+    #[test]
+    fn test_dial_addrs_failed_expire() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_dial_addrs_failed_expire(thread_pool.clone()));
+    }
+}
@@ -16,14 +16,23 @@
 #[macro_use]
 extern crate log;
 
+mod dialer;
 mod net_connector;
+mod punch_connector;
 mod resolver;
+mod socks5;
 mod tcp_connector;
 mod tcp_listener;
 #[cfg(test)]
 mod tests;
 mod types;
 mod utils;
+mod ws_connector;
+mod ws_listener;
 
 pub use self::net_connector::NetConnector;
-pub use self::tcp_listener::TcpListener;
+pub use self::punch_connector::PunchConnector;
+pub use self::tcp_connector::TcpConnector;
+pub use self::tcp_listener::{TcpListener, TcpPeerListener};
+pub use self::ws_connector::WsConnector;
+pub use self::ws_listener::WsListener;
@@ -1,22 +1,57 @@
+use std::net::SocketAddr;
+
 use common::conn::{BoxFuture, ConnPairVec, FutTransform};
 use futures::task::Spawn;
 
-use proto::net::messages::NetAddress;
+use proto::net::messages::{NetAddress, NetScheme};
+use timer::TimerClient;
 
-use crate::resolver::Resolver;
-use crate::tcp_connector::TcpConnector;
+use crate::dialer::TcpDialer;
+use crate::utils::tcp_stream_to_conn_pair;
+use crate::ws_connector::WsConnector;
 
+/// Connect to a `NetAddress`, using the transport determined by the address scheme.
+/// If `opt_socks5_proxy` is given, all outgoing connections are made through this SOCKS5 proxy.
+/// Otherwise, if `opt_local_addr` is given, TCP connections are made from this local address,
+/// which may be shared with a `PunchConnector`.
 #[derive(Clone)]
 pub struct NetConnector<S, RS> {
-    resolver: Resolver<RS>,
-    tcp_connector: TcpConnector<S>,
+    dialer: TcpDialer<S, RS>,
+    ws_connector: WsConnector<S, RS>,
+    max_frame_length: usize,
+    spawner: S,
 }
 
-impl<S, RS> NetConnector<S, RS> {
-    pub fn new(max_frame_length: usize, resolve_spawner: RS, spawner: S) -> Self {
+impl<S, RS> NetConnector<S, RS>
+where
+    S: Spawn + Send + Clone,
+    RS: Spawn + Send + Clone,
+{
+    pub fn new(
+        max_frame_length: usize,
+        opt_socks5_proxy: Option<SocketAddr>,
+        opt_local_addr: Option<SocketAddr>,
+        timer_client: TimerClient,
+        resolve_spawner: RS,
+        spawner: S,
+    ) -> Self {
         NetConnector {
-            resolver: Resolver::new(resolve_spawner),
-            tcp_connector: TcpConnector::new(max_frame_length, spawner),
+            dialer: TcpDialer::new(
+                opt_socks5_proxy,
+                opt_local_addr,
+                timer_client.clone(),
+                resolve_spawner.clone(),
+                spawner.clone(),
+            ),
+            ws_connector: WsConnector::new(
+                max_frame_length,
+                opt_socks5_proxy,
+                timer_client,
+                resolve_spawner,
+                spawner.clone(),
+            ),
+            max_frame_length,
+            spawner,
         }
     }
 }
@@ -33,11 +68,25 @@ where
         debug!("Connecting to {:?}", net_address);
         Box::pin(
             async move {
-                let socket_addr_vec = await!(self.resolver.transform(net_address));
-                // A trivial implementation: We try to connect to the first address on the list.
-                // TODO: Maybe choose a random address in the future?
-                let socket_addr = socket_addr_vec.get(0)?;
-                await!(self.tcp_connector.transform(*socket_addr))
+                let split_res = net_address
+                    .split_scheme()
+                    .map(|(scheme, host_port)| (scheme, host_port.to_owned()));
+                let host_port = match split_res {
+                    Ok((NetScheme::Tcp, host_port)) => host_port,
+                    Ok((NetScheme::Ws, _)) => {
+                        return await!(self.ws_connector.transform(net_address));
+                    }
+                    Err(e) => {
+                        warn!("Invalid address {:?}: {:?}", net_address, e);
+                        return None;
+                    }
+                };
+                let tcp_stream = await!(self.dialer.dial(host_port))?;
+                Some(tcp_stream_to_conn_pair(
+                    tcp_stream,
+                    self.max_frame_length,
+                    &mut self.spawner,
+                ))
             },
         )
     }
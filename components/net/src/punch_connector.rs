@@ -0,0 +1,89 @@
+use std::net::SocketAddr;
+
+use common::conn::{BoxFuture, ConnPairVec, FutTransform};
+
+use futures::task::Spawn;
+use futures::StreamExt;
+
+use proto::net::messages::{NetAddress, NetScheme};
+use timer::TimerClient;
+
+use crate::utils::{tcp_connect, tcp_stream_to_conn_pair};
+
+/// Punch TCP connections through NATs.
+///
+/// Connections are made from `local_addr`, which should be the local address used for connecting
+/// to relays (See `NetConnector`), so that the address of the remote side observed by the relay
+/// is the one we connect to. Both sides connect to each other at the same time.
+///
+/// A failed connection attempt is repeated every tick, until a connection is opened. Therefore
+/// this connector should be used with a timeout.
+#[derive(Clone)]
+pub struct PunchConnector<S> {
+    local_addr: SocketAddr,
+    max_frame_length: usize,
+    timer_client: TimerClient,
+    spawner: S,
+}
+
+impl<S> PunchConnector<S> {
+    pub fn new(
+        local_addr: SocketAddr,
+        max_frame_length: usize,
+        timer_client: TimerClient,
+        spawner: S,
+    ) -> Self {
+        PunchConnector {
+            local_addr,
+            max_frame_length,
+            timer_client,
+            spawner,
+        }
+    }
+}
+
+impl<S> FutTransform for PunchConnector<S>
+where
+    S: Spawn + Send,
+{
+    type Input = NetAddress;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, net_address: Self::Input) -> BoxFuture<'_, Self::Output> {
+        Box::pin(
+            async move {
+                // Addresses observed by relays are of the form `ip:port`:
+                let socket_addr: SocketAddr = match net_address.split_scheme() {
+                    Ok((NetScheme::Tcp, host_port)) => host_port.parse().ok()?,
+                    _ => {
+                        warn!("PunchConnector: Invalid address {:?}", net_address);
+                        return None;
+                    }
+                };
+
+                loop {
+                    match await!(tcp_connect(Some(self.local_addr), socket_addr)) {
+                        Ok(tcp_stream) => {
+                            return Some(tcp_stream_to_conn_pair(
+                                tcp_stream,
+                                self.max_frame_length,
+                                &mut self.spawner,
+                            ));
+                        }
+                        Err(e) => {
+                            debug!(
+                                "PunchConnector: Failed connecting to {:?}: {:?}",
+                                socket_addr, e
+                            );
+                        }
+                    }
+                    // Wait one tick before the next attempt.
+                    // (A timer stream that is not polled would hold back the timer):
+                    let mut timer_stream =
+                        await!(self.timer_client.request_timer_stream()).ok()?;
+                    await!(timer_stream.next())?;
+                }
+            },
+        )
+    }
+}
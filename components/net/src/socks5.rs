@@ -0,0 +1,155 @@
+use std::net::{IpAddr, SocketAddr};
+
+use futures::compat::Future01CompatExt;
+
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_METHOD_NONE: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN_NAME: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+#[derive(Debug)]
+pub enum Socks5Error {
+    ConnectProxyError,
+    IoError,
+    HostTooLong,
+    InvalidVersion,
+    AuthMethodRejected,
+    /// The proxy failed to connect to the requested destination, with the given reply code
+    ConnectRejected(u8),
+    InvalidAddressType,
+}
+
+/// Serialize a SOCKS5 CONNECT request to `host:port`.
+/// Host names are sent unresolved, and resolved by the proxy.
+fn serialize_connect_request(host: &str, port: u16) -> Result<Vec<u8>, Socks5Error> {
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ipv4_addr)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ipv4_addr.octets());
+        }
+        Ok(IpAddr::V6(ipv6_addr)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ipv6_addr.octets());
+        }
+        Err(_) => {
+            if host.len() > usize::from(u8::max_value()) {
+                return Err(Socks5Error::HostTooLong);
+            }
+            request.push(ATYP_DOMAIN_NAME);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+/// Open a TCP connection to `host:port` through the SOCKS5 proxy at `proxy_addr`.
+/// Only proxies that require no authentication are supported.
+pub async fn socks5_connect(
+    proxy_addr: SocketAddr,
+    host: String,
+    port: u16,
+) -> Result<TcpStream, Socks5Error> {
+    let request = serialize_connect_request(&host, port)?;
+
+    let tcp_stream = await!(TcpStream::connect(&proxy_addr).compat())
+        .map_err(|_| Socks5Error::ConnectProxyError)?;
+
+    // Greeting: We only offer to not authenticate:
+    let greeting = vec![SOCKS_VERSION, 0x01, AUTH_METHOD_NONE];
+    let (tcp_stream, _) =
+        await!(write_all(tcp_stream, greeting).compat()).map_err(|_| Socks5Error::IoError)?;
+    let (tcp_stream, choice) =
+        await!(read_exact(tcp_stream, [0u8; 2]).compat()).map_err(|_| Socks5Error::IoError)?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(Socks5Error::InvalidVersion);
+    }
+    if choice[1] != AUTH_METHOD_NONE {
+        return Err(Socks5Error::AuthMethodRejected);
+    }
+
+    let (tcp_stream, _) =
+        await!(write_all(tcp_stream, request).compat()).map_err(|_| Socks5Error::IoError)?;
+
+    // Reply: version, reply code, reserved, address type:
+    let (tcp_stream, reply) =
+        await!(read_exact(tcp_stream, [0u8; 4]).compat()).map_err(|_| Socks5Error::IoError)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(Socks5Error::InvalidVersion);
+    }
+    if reply[1] != REPLY_SUCCEEDED {
+        return Err(Socks5Error::ConnectRejected(reply[1]));
+    }
+
+    // Skip the bound address and port, which we do not need:
+    let (tcp_stream, bound_len) = match reply[3] {
+        ATYP_IPV4 => (tcp_stream, 4),
+        ATYP_IPV6 => (tcp_stream, 16),
+        ATYP_DOMAIN_NAME => {
+            let (tcp_stream, len) = await!(read_exact(tcp_stream, [0u8; 1]).compat())
+                .map_err(|_| Socks5Error::IoError)?;
+            (tcp_stream, usize::from(len[0]))
+        }
+        _ => return Err(Socks5Error::InvalidAddressType),
+    };
+    let (tcp_stream, _) = await!(read_exact(tcp_stream, vec![0u8; bound_len + 2]).compat())
+        .map_err(|_| Socks5Error::IoError)?;
+
+    Ok(tcp_stream)
+}
+
+/// Split a `host:port` string into its host and port.
+/// IPv6 hosts may be enclosed in brackets (For example: `[::1]:1337`).
+pub fn split_host_port(host_port: &str) -> Option<(String, u16)> {
+    let index = host_port.rfind(':')?;
+    let host = &host_port[..index];
+    let port = host_port[index + 1..].parse::<u16>().ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_connect_request() {
+        assert_eq!(
+            serialize_connect_request("127.0.0.1", 0x1337).unwrap(),
+            vec![5, 1, 0, ATYP_IPV4, 127, 0, 0, 1, 0x13, 0x37]
+        );
+        assert_eq!(
+            serialize_connect_request("ab.c", 80).unwrap(),
+            vec![5, 1, 0, ATYP_DOMAIN_NAME, 4, b'a', b'b', b'.', b'c', 0, 80]
+        );
+        let request = serialize_connect_request("::1", 80).unwrap();
+        assert_eq!(request[3], ATYP_IPV6);
+        assert_eq!(request.len(), 4 + 16 + 2);
+
+        let long_host = "a".repeat(0x100);
+        assert!(serialize_connect_request(&long_host, 80).is_err());
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("example.com:1337"),
+            Some(("example.com".to_owned(), 1337))
+        );
+        assert_eq!(split_host_port("[::1]:80"), Some(("::1".to_owned(), 80)));
+        assert_eq!(split_host_port("example.com"), None);
+        assert_eq!(split_host_port(":80"), None);
+        assert_eq!(split_host_port("example.com:abc"), None);
+    }
+}
@@ -26,6 +26,73 @@ impl<S> TcpListener<S> {
     }
 }
 
+/// Listen for incoming TCP connections.
+/// Every incoming connection is returned together with the address of the remote peer.
+/// Useful for servers that report to their clients the addresses they are observed from.
+pub struct TcpPeerListener<S> {
+    max_frame_length: usize,
+    spawner: S,
+}
+
+impl<S> TcpPeerListener<S> {
+    pub fn new(max_frame_length: usize, spawner: S) -> Self {
+        TcpPeerListener {
+            max_frame_length,
+            spawner,
+        }
+    }
+}
+
+/// Listen for incoming TCP connections on `socket_addr`.
+/// Every connection is converted to a conn pair, and then passed through `map_conn`.
+fn listen_tcp<S, T, F>(
+    socket_addr: SocketAddr,
+    max_frame_length: usize,
+    mut map_conn: F,
+    mut spawner: S,
+) -> (mpsc::Sender<()>, mpsc::Receiver<T>)
+where
+    S: Spawn + Send + Clone + 'static,
+    T: Send + 'static,
+    F: FnMut(SocketAddr, ConnPairVec) -> T + Send + 'static,
+{
+    let (config_sender, _config_sender_receiver) = mpsc::channel(0);
+    let (mut conn_receiver_sender, conn_receiver) = mpsc::channel(0);
+
+    let listener = match TokioTcpListener::bind(&socket_addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed listening on {:?}: {:?}", socket_addr, e);
+            // Return empty channels:
+            return (config_sender, conn_receiver);
+        }
+    };
+
+    let mut incoming_conns = listener.incoming().compat();
+    let mut c_spawner = spawner.clone();
+    let _ = spawner.spawn(
+        async move {
+            while let Some(Ok(tcp_stream)) = await!(incoming_conns.next()) {
+                let peer_addr = match tcp_stream.peer_addr() {
+                    Ok(peer_addr) => peer_addr,
+                    Err(e) => {
+                        warn!("listen_tcp(): Failed obtaining peer address: {:?}", e);
+                        continue;
+                    }
+                };
+                let conn_pair =
+                    tcp_stream_to_conn_pair(tcp_stream, max_frame_length, &mut c_spawner);
+                if let Err(e) = await!(conn_receiver_sender.send(map_conn(peer_addr, conn_pair))) {
+                    warn!("listen_tcp(): Send error: {:?}", e);
+                    return;
+                }
+            }
+        },
+    );
+
+    (config_sender, conn_receiver)
+}
+
 impl<S> Listener for TcpListener<S>
 where
     S: Spawn + Send + Clone + 'static,
@@ -35,37 +102,35 @@ where
     type Arg = SocketAddr;
 
     fn listen(
-        mut self,
+        self,
         socket_addr: Self::Arg,
     ) -> (mpsc::Sender<Self::Config>, mpsc::Receiver<Self::Connection>) {
-        let (config_sender, _config_sender_receiver) = mpsc::channel(0);
-        let (mut conn_receiver_sender, conn_receiver) = mpsc::channel(0);
-
-        let listener = match TokioTcpListener::bind(&socket_addr) {
-            Ok(listener) => listener,
-            Err(e) => {
-                warn!("Failed listening on {:?}: {:?}", socket_addr, e);
-                // Return empty channels:
-                return (config_sender, conn_receiver);
-            }
-        };
-
-        let mut incoming_conns = listener.incoming().compat();
-        let mut c_spawner = self.spawner.clone();
-        let c_max_frame_length = self.max_frame_length;
-        let _ = self.spawner.spawn(
-            async move {
-                while let Some(Ok(tcp_stream)) = await!(incoming_conns.next()) {
-                    let conn_pair =
-                        tcp_stream_to_conn_pair(tcp_stream, c_max_frame_length, &mut c_spawner);
-                    if let Err(e) = await!(conn_receiver_sender.send(conn_pair)) {
-                        warn!("TcpListener::listen(): Send error: {:?}", e);
-                        return;
-                    }
-                }
-            },
-        );
+        listen_tcp(
+            socket_addr,
+            self.max_frame_length,
+            |_peer_addr, conn_pair| conn_pair,
+            self.spawner,
+        )
+    }
+}
 
-        (config_sender, conn_receiver)
+impl<S> Listener for TcpPeerListener<S>
+where
+    S: Spawn + Send + Clone + 'static,
+{
+    type Connection = (SocketAddr, ConnPairVec);
+    type Config = ();
+    type Arg = SocketAddr;
+
+    fn listen(
+        self,
+        socket_addr: Self::Arg,
+    ) -> (mpsc::Sender<Self::Config>, mpsc::Receiver<Self::Connection>) {
+        listen_tcp(
+            socket_addr,
+            self.max_frame_length,
+            |peer_addr, conn_pair| (peer_addr, conn_pair),
+            self.spawner,
+        )
     }
 }
@@ -1,5 +1,8 @@
 use std::convert::TryInto;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::io::{self, Read, Write};
+use std::net::{self, IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
 
 use env_logger;
 
@@ -9,10 +12,13 @@ use futures::{SinkExt, StreamExt};
 
 use common::conn::{FutTransform, Listener};
 use proto::net::messages::NetAddress;
+use timer::create_timer;
 
 use crate::net_connector::NetConnector;
 use crate::tcp_connector::TcpConnector;
-use crate::tcp_listener::TcpListener;
+use crate::tcp_listener::{TcpListener, TcpPeerListener};
+use crate::utils::tcp_connect;
+use crate::ws_listener::WsListener;
 
 use tokio::net::TcpListener as TokioTcpListener;
 
@@ -28,6 +34,9 @@ fn get_available_port_v4() -> u16 {
 }
 
 const TEST_MAX_FRAME_LEN: usize = 0x100;
+const TEST_TICK: Duration = Duration::from_millis(100);
+const TEST_HANDSHAKE_TIMEOUT_TICKS: usize = 4;
+const TEST_MAX_CONCURRENT_HANDSHAKES: usize = 2;
 
 async fn task_tcp_client_server_v4<S>(spawner: S)
 where
@@ -73,6 +82,61 @@ fn test_tcp_client_server_v4() {
     thread_pool.run(task_tcp_client_server_v4(thread_pool.clone()));
 }
 
+async fn task_tcp_peer_listener_v4<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let available_port = get_available_port_v4();
+    let loopback = Ipv4Addr::new(127, 0, 0, 1);
+    let socket_addr = SocketAddr::new(IpAddr::V4(loopback), available_port);
+
+    let tcp_listener = TcpPeerListener::new(TEST_MAX_FRAME_LEN, spawner.clone());
+    let mut tcp_connector = TcpConnector::new(TEST_MAX_FRAME_LEN, spawner.clone());
+
+    let (_config_sender, mut incoming_connections) = tcp_listener.listen(socket_addr.clone());
+
+    let (mut client_sender, _client_receiver) =
+        await!(tcp_connector.transform(socket_addr.clone())).unwrap();
+    let (peer_addr, (_server_sender, mut server_receiver)) =
+        await!(incoming_connections.next()).unwrap();
+
+    // The client connects from the loopback address:
+    assert_eq!(peer_addr.ip(), IpAddr::V4(loopback));
+    assert_ne!(peer_addr.port(), available_port);
+
+    await!(client_sender.send(vec![1, 2, 3])).unwrap();
+    assert_eq!(await!(server_receiver.next()).unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_tcp_peer_listener_v4() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_tcp_peer_listener_v4(thread_pool.clone()));
+}
+
+async fn task_tcp_connect_shared_local_addr_v4() {
+    let loopback = Ipv4Addr::new(127, 0, 0, 1);
+    let local_addr = SocketAddr::new(IpAddr::V4(loopback), get_available_port_v4());
+
+    // A listening address. The kernel completes the handshake even if we never accept:
+    let listener_a = net::TcpListener::bind(SocketAddr::new(IpAddr::V4(loopback), 0)).unwrap();
+    let listener_b = net::TcpListener::bind(SocketAddr::new(IpAddr::V4(loopback), 0)).unwrap();
+    let addr_a = listener_a.local_addr().unwrap();
+    let addr_b = listener_b.local_addr().unwrap();
+
+    // Two connections are made from the same local address:
+    let tcp_stream_a = await!(tcp_connect(Some(local_addr), addr_a)).unwrap();
+    let tcp_stream_b = await!(tcp_connect(Some(local_addr), addr_b)).unwrap();
+    assert_eq!(tcp_stream_a.local_addr().unwrap(), local_addr);
+    assert_eq!(tcp_stream_b.local_addr().unwrap(), local_addr);
+}
+
+#[test]
+fn test_tcp_connect_shared_local_addr_v4() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_tcp_connect_shared_local_addr_v4());
+}
+
 async fn task_net_connector_v4_basic<S>(spawner: S)
 where
     S: Spawn + Clone + Send + 'static,
@@ -82,7 +146,15 @@ where
     let socket_addr = SocketAddr::new(IpAddr::V4(loopback), available_port);
 
     let tcp_listener = TcpListener::new(TEST_MAX_FRAME_LEN, spawner.clone());
-    let mut net_connector = NetConnector::new(TEST_MAX_FRAME_LEN, spawner.clone(), spawner.clone());
+    let timer_client = create_timer(TEST_TICK, spawner.clone()).unwrap();
+    let mut net_connector = NetConnector::new(
+        TEST_MAX_FRAME_LEN,
+        None,
+        None,
+        timer_client,
+        spawner.clone(),
+        spawner.clone(),
+    );
 
     let (_config_sender, mut incoming_connections) = tcp_listener.listen(socket_addr.clone());
 
@@ -107,6 +179,121 @@ fn test_net_connector_v4_basic() {
     thread_pool.run(task_net_connector_v4_basic(thread_pool.clone()));
 }
 
+async fn task_net_connector_ws_v4_basic<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let available_port = get_available_port_v4();
+    let loopback = Ipv4Addr::new(127, 0, 0, 1);
+    let socket_addr = SocketAddr::new(IpAddr::V4(loopback), available_port);
+
+    let timer_client = create_timer(TEST_TICK, spawner.clone()).unwrap();
+    let ws_listener = WsListener::new(
+        TEST_MAX_FRAME_LEN,
+        TEST_HANDSHAKE_TIMEOUT_TICKS,
+        TEST_MAX_CONCURRENT_HANDSHAKES,
+        timer_client.clone(),
+        spawner.clone(),
+    );
+    let mut net_connector = NetConnector::new(
+        TEST_MAX_FRAME_LEN,
+        None,
+        None,
+        timer_client,
+        spawner.clone(),
+        spawner.clone(),
+    );
+
+    let (_config_sender, mut incoming_connections) = ws_listener.listen(socket_addr.clone());
+
+    let net_address: NetAddress = format!("ws://127.0.0.1:{}/offst", available_port)
+        .try_into()
+        .unwrap();
+
+    for _ in 0..5 {
+        let (mut client_sender, mut client_receiver) =
+            await!(net_connector.transform(net_address.clone())).unwrap();
+        let (mut server_sender, mut server_receiver) = await!(incoming_connections.next()).unwrap();
+
+        await!(client_sender.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(server_receiver.next()).unwrap(), vec![1, 2, 3]);
+
+        await!(server_sender.send(vec![3, 2, 1])).unwrap();
+        assert_eq!(await!(client_receiver.next()).unwrap(), vec![3, 2, 1]);
+
+        // A message that is too long closes the connection:
+        await!(client_sender.send(vec![0; TEST_MAX_FRAME_LEN + 1])).unwrap();
+        assert!(await!(server_receiver.next()).is_none());
+    }
+}
+
+#[test]
+fn test_net_connector_ws_v4_basic() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_net_connector_ws_v4_basic(thread_pool.clone()));
+}
+
+async fn task_ws_listener_handshake_timeout<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let available_port = get_available_port_v4();
+    let loopback = Ipv4Addr::new(127, 0, 0, 1);
+    let socket_addr = SocketAddr::new(IpAddr::V4(loopback), available_port);
+
+    let timer_client = create_timer(TEST_TICK, spawner.clone()).unwrap();
+    let ws_listener = WsListener::new(
+        TEST_MAX_FRAME_LEN,
+        TEST_HANDSHAKE_TIMEOUT_TICKS,
+        TEST_MAX_CONCURRENT_HANDSHAKES,
+        timer_client.clone(),
+        spawner.clone(),
+    );
+    let mut net_connector = NetConnector::new(
+        TEST_MAX_FRAME_LEN,
+        None,
+        None,
+        timer_client,
+        spawner.clone(),
+        spawner.clone(),
+    );
+
+    let (_config_sender, mut incoming_connections) = ws_listener.listen(socket_addr.clone());
+
+    // Raw TCP connections that never perform the WebSocket handshake:
+    let mut idle_streams = Vec::new();
+    for _ in 0..TEST_MAX_CONCURRENT_HANDSHAKES + 1 {
+        let idle_stream = net::TcpStream::connect(socket_addr).unwrap();
+        idle_stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        idle_streams.push(idle_stream);
+    }
+
+    // All the idle connections are closed by the listener, either because there was no room
+    // for another handshake, or because the handshake timed out:
+    for idle_stream in &mut idle_streams {
+        let mut buff = [0u8; 1];
+        assert_eq!(idle_stream.read(&mut buff).unwrap(), 0);
+    }
+
+    // The listener still accepts valid connections:
+    let net_address: NetAddress = format!("ws://127.0.0.1:{}/offst", available_port)
+        .try_into()
+        .unwrap();
+    let (mut client_sender, _client_receiver) =
+        await!(net_connector.transform(net_address)).unwrap();
+    let (_server_sender, mut server_receiver) = await!(incoming_connections.next()).unwrap();
+    await!(client_sender.send(vec![1, 2, 3])).unwrap();
+    assert_eq!(await!(server_receiver.next()).unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_ws_listener_handshake_timeout() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_ws_listener_handshake_timeout(thread_pool.clone()));
+}
+
 async fn task_net_connector_v4_drop_sender<S>(spawner: S)
 where
     S: Spawn + Clone + Send + 'static,
@@ -116,7 +303,15 @@ where
     let socket_addr = SocketAddr::new(IpAddr::V4(loopback), available_port);
 
     let tcp_listener = TcpListener::new(TEST_MAX_FRAME_LEN, spawner.clone());
-    let mut net_connector = NetConnector::new(TEST_MAX_FRAME_LEN, spawner.clone(), spawner.clone());
+    let timer_client = create_timer(TEST_TICK, spawner.clone()).unwrap();
+    let mut net_connector = NetConnector::new(
+        TEST_MAX_FRAME_LEN,
+        None,
+        None,
+        timer_client,
+        spawner.clone(),
+        spawner.clone(),
+    );
 
     let (_config_sender, mut incoming_connections) = tcp_listener.listen(socket_addr.clone());
 
@@ -140,3 +335,123 @@ fn test_net_connector_v4_drop_sender() {
     let mut thread_pool = ThreadPool::new().unwrap();
     thread_pool.run(task_net_connector_v4_drop_sender(thread_pool.clone()));
 }
+
+/// A minimal blocking SOCKS5 proxy that serves a single CONNECT request to a domain name.
+/// Returns the address of the proxy, and a receiver for the requested host name.
+fn spawn_socks5_proxy() -> (SocketAddr, std::sync::mpsc::Receiver<String>) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let (host_sender, host_receiver) = std::sync::mpsc::channel();
+
+    thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 3];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        client.write_all(&[5, 0]).unwrap();
+
+        let mut header = [0u8; 5];
+        client.read_exact(&mut header).unwrap();
+        // Expecting a domain name address type:
+        assert_eq!(header[..4], [5, 1, 0, 3]);
+        let mut host = vec![0u8; usize::from(header[4])];
+        client.read_exact(&mut host).unwrap();
+        let mut port = [0u8; 2];
+        client.read_exact(&mut port).unwrap();
+        let host = String::from_utf8(host).unwrap();
+        let port = u16::from_be_bytes(port);
+
+        let target_addr = (host.as_str(), port)
+            .to_socket_addrs()
+            .unwrap()
+            .find(|socket_addr| socket_addr.is_ipv4())
+            .unwrap();
+        let mut target = net::TcpStream::connect(target_addr).unwrap();
+        client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        host_sender.send(host).unwrap();
+
+        let mut c_client = client.try_clone().unwrap();
+        let mut c_target = target.try_clone().unwrap();
+        thread::spawn(move || io::copy(&mut c_target, &mut c_client));
+        let _ = io::copy(&mut client, &mut target);
+    });
+
+    (proxy_addr, host_receiver)
+}
+
+async fn task_net_connector_socks5_v4<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    let available_port = get_available_port_v4();
+    let loopback = Ipv4Addr::new(127, 0, 0, 1);
+    let socket_addr = SocketAddr::new(IpAddr::V4(loopback), available_port);
+
+    let (proxy_addr, host_receiver) = spawn_socks5_proxy();
+
+    let tcp_listener = TcpListener::new(TEST_MAX_FRAME_LEN, spawner.clone());
+    let timer_client = create_timer(TEST_TICK, spawner.clone()).unwrap();
+    let mut net_connector = NetConnector::new(
+        TEST_MAX_FRAME_LEN,
+        Some(proxy_addr),
+        None,
+        timer_client,
+        spawner.clone(),
+        spawner.clone(),
+    );
+
+    let (_config_sender, mut incoming_connections) = tcp_listener.listen(socket_addr.clone());
+
+    let net_address: NetAddress = format!("localhost:{}", available_port).try_into().unwrap();
+
+    let (mut client_sender, mut client_receiver) =
+        await!(net_connector.transform(net_address.clone())).unwrap();
+    let (mut server_sender, mut server_receiver) = await!(incoming_connections.next()).unwrap();
+
+    // The host name was sent to the proxy unresolved:
+    assert_eq!(host_receiver.recv().unwrap(), "localhost");
+
+    await!(client_sender.send(vec![1, 2, 3])).unwrap();
+    assert_eq!(await!(server_receiver.next()).unwrap(), vec![1, 2, 3]);
+
+    await!(server_sender.send(vec![3, 2, 1])).unwrap();
+    assert_eq!(await!(client_receiver.next()).unwrap(), vec![3, 2, 1]);
+}
+
+#[test]
+fn test_net_connector_socks5_v4() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_net_connector_socks5_v4(thread_pool.clone()));
+}
+
+async fn task_net_connector_socks5_timeout<S>(spawner: S)
+where
+    S: Spawn + Clone + Send + 'static,
+{
+    // A proxy that accepts connections, but never answers:
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (_client, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(30));
+    });
+
+    let timer_client = create_timer(TEST_TICK, spawner.clone()).unwrap();
+    let mut net_connector = NetConnector::new(
+        TEST_MAX_FRAME_LEN,
+        Some(proxy_addr),
+        None,
+        timer_client,
+        spawner.clone(),
+        spawner.clone(),
+    );
+
+    let net_address: NetAddress = "localhost:1337".to_owned().try_into().unwrap();
+    assert!(await!(net_connector.transform(net_address)).is_none());
+}
+
+#[test]
+fn test_net_connector_socks5_timeout() {
+    let mut thread_pool = ThreadPool::new().unwrap();
+    thread_pool.run(task_net_connector_socks5_timeout(thread_pool.clone()));
+}
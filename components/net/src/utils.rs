@@ -1,3 +1,6 @@
+use std::io;
+use std::net::SocketAddr;
+
 use bytes::Bytes;
 
 use futures::channel::mpsc;
@@ -9,7 +12,16 @@ use futures_01::sink::Sink as Sink01;
 use futures_01::stream::Stream as Stream01;
 
 use tokio::codec::{Framed, LengthDelimitedCodec};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::reactor::Handle;
+
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+use net2::TcpBuilder;
+
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
 
 use common::conn::ConnPairVec;
 
@@ -98,6 +110,34 @@ where
     (user_sender, user_receiver)
 }
 
+/// Open a TCP connection to `socket_addr`.
+/// If `opt_local_addr` is provided (And it belongs to the same address family as `socket_addr`),
+/// the connection is made from this local address. The local address may be shared by many
+/// connections (SO_REUSEADDR, SO_REUSEPORT), which allows punching through NATs from the same
+/// address used for connecting to relays.
+pub async fn tcp_connect(
+    opt_local_addr: Option<SocketAddr>,
+    socket_addr: SocketAddr,
+) -> io::Result<TcpStream> {
+    let local_addr = match opt_local_addr {
+        Some(local_addr) if local_addr.is_ipv4() == socket_addr.is_ipv4() => local_addr,
+        _ => return await!(TcpStream::connect(&socket_addr).compat()),
+    };
+
+    let tcp_builder = if local_addr.is_ipv4() {
+        TcpBuilder::new_v4()?
+    } else {
+        TcpBuilder::new_v6()?
+    };
+    tcp_builder.reuse_address(true)?;
+    #[cfg(unix)]
+    tcp_builder.reuse_port(true)?;
+    tcp_builder.bind(local_addr)?;
+
+    let std_tcp_stream = tcp_builder.to_tcp_stream()?;
+    await!(TcpStream::connect_std(std_tcp_stream, &socket_addr, &Handle::default()).compat())
+}
+
 pub fn tcp_stream_to_conn_pair<S>(
     tcp_stream: TcpStream,
     max_frame_length: usize,
@@ -120,6 +160,35 @@ where
     conn_pair_01_to_03((sender_01, receiver_01), spawner)
 }
 
+/// Convert a WebSocket stream to a connection pair.
+/// Every sent message is carried by a single binary WebSocket message.
+pub fn ws_stream_to_conn_pair<T, S>(
+    ws_stream: WebSocketStream<T>,
+    max_frame_length: usize,
+    spawner: &mut S,
+) -> ConnPairVec
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+    S: Spawn + Send,
+{
+    let (sender_01, receiver_01) = ws_stream.split();
+
+    let sender_01 = sender_01
+        .sink_map_err(|_| ())
+        .with(|vec: Vec<u8>| -> Result<Message, ()> { Ok(Message::Binary(vec)) });
+
+    // Control messages are handled by the WebSocket layer, and text messages are not used.
+    // A message that is too long closes the connection.
+    let receiver_01 = receiver_01
+        .filter_map(|message| match message {
+            Message::Binary(vec) => Some(vec),
+            _ => None,
+        })
+        .take_while(move |vec| Ok(vec.len() <= max_frame_length));
+
+    conn_pair_01_to_03((sender_01, receiver_01), spawner)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
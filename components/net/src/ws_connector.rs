@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+
+use common::conn::{BoxFuture, ConnPairVec, FutTransform};
+
+use futures::compat::Future01CompatExt;
+use futures::task::Spawn;
+
+use tokio_tungstenite::client_async_with_config;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use url::Url;
+
+use proto::net::messages::{NetAddress, NetScheme};
+use timer::utils::future_timeout;
+use timer::TimerClient;
+
+use crate::dialer::TcpDialer;
+use crate::utils::ws_stream_to_conn_pair;
+
+/// Amount of ticks before a WebSocket handshake is abandoned.
+const HANDSHAKE_TIMEOUT_TICKS: usize = 8;
+
+/// Connect to a WebSocket address (For example: `ws://example.com:8080/offst`)
+#[derive(Clone)]
+pub struct WsConnector<S, RS> {
+    dialer: TcpDialer<S, RS>,
+    max_frame_length: usize,
+    timer_client: TimerClient,
+    spawner: S,
+}
+
+impl<S, RS> WsConnector<S, RS>
+where
+    S: Spawn + Send + Clone,
+    RS: Spawn + Send,
+{
+    pub fn new(
+        max_frame_length: usize,
+        opt_socks5_proxy: Option<SocketAddr>,
+        timer_client: TimerClient,
+        resolve_spawner: RS,
+        spawner: S,
+    ) -> Self {
+        WsConnector {
+            dialer: TcpDialer::new(
+                opt_socks5_proxy,
+                None,
+                timer_client.clone(),
+                resolve_spawner,
+                spawner.clone(),
+            ),
+            max_frame_length,
+            timer_client,
+            spawner,
+        }
+    }
+}
+
+impl<S, RS> FutTransform for WsConnector<S, RS>
+where
+    S: Spawn + Send,
+    RS: Spawn + Send,
+{
+    type Input = NetAddress;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, net_address: Self::Input) -> BoxFuture<'_, Self::Output> {
+        Box::pin(
+            async move {
+                match net_address.split_scheme() {
+                    Ok((NetScheme::Ws, _)) => {}
+                    _ => {
+                        warn!("WsConnector: Not a WebSocket address: {:?}", net_address);
+                        return None;
+                    }
+                };
+                let url = Url::parse(net_address.as_str()).ok()?;
+
+                // The port may be omitted from the url:
+                let host_port = format!("{}:{}", url.host_str()?, url.port_or_known_default()?);
+                let tcp_stream = await!(self.dialer.dial(host_port))?;
+
+                // Messages and frames larger than max_frame_length are rejected during parsing,
+                // so that the remote side can not make us allocate large buffers:
+                let ws_config = WebSocketConfig {
+                    max_send_queue: None,
+                    max_message_size: Some(self.max_frame_length),
+                    max_frame_size: Some(self.max_frame_length),
+                };
+                let timer_stream = await!(self.timer_client.request_timer_stream()).ok()?;
+                let handshake_fut = client_async_with_config(url, tcp_stream, Some(ws_config));
+                let ws_stream = match await!(future_timeout(
+                    handshake_fut.compat(),
+                    timer_stream,
+                    HANDSHAKE_TIMEOUT_TICKS
+                )) {
+                    Some(Ok((ws_stream, _response))) => ws_stream,
+                    Some(Err(e)) => {
+                        warn!("WsConnector: Handshake error: {:?}", e);
+                        return None;
+                    }
+                    None => {
+                        warn!("WsConnector: Handshake timed out");
+                        return None;
+                    }
+                };
+
+                Some(ws_stream_to_conn_pair(
+                    ws_stream,
+                    self.max_frame_length,
+                    &mut self.spawner,
+                ))
+            },
+        )
+    }
+}
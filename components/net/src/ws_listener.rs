@@ -0,0 +1,142 @@
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener as TokioTcpListener;
+use tokio_tungstenite::accept_async_with_config;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+use futures::channel::mpsc;
+use futures::compat::{Future01CompatExt, Stream01CompatExt};
+use futures::task::{Spawn, SpawnExt};
+use futures::{future, StreamExt};
+
+use common::conn::{ConnPairVec, FuncFutTransform, Listener};
+use common::transform_pool::transform_pool_loop;
+use timer::utils::future_timeout;
+use timer::TimerClient;
+
+use crate::utils::ws_stream_to_conn_pair;
+
+/// Listen for incoming WebSocket connections.
+/// Connections are accepted on any path.
+///
+/// Only plain WebSocket (ws://) is supported. Secure WebSocket (wss://, TLS) is not supported,
+/// and should be handled by a reverse proxy if required.
+pub struct WsListener<S> {
+    max_frame_length: usize,
+    handshake_timeout_ticks: usize,
+    max_concurrent_handshakes: usize,
+    timer_client: TimerClient,
+    spawner: S,
+}
+
+impl<S> WsListener<S> {
+    pub fn new(
+        max_frame_length: usize,
+        handshake_timeout_ticks: usize,
+        max_concurrent_handshakes: usize,
+        timer_client: TimerClient,
+        spawner: S,
+    ) -> Self {
+        WsListener {
+            max_frame_length,
+            handshake_timeout_ticks,
+            max_concurrent_handshakes,
+            timer_client,
+            spawner,
+        }
+    }
+}
+
+impl<S> Listener for WsListener<S>
+where
+    S: Spawn + Send + Clone + 'static,
+{
+    type Connection = ConnPairVec;
+    type Config = ();
+    type Arg = SocketAddr;
+
+    fn listen(
+        mut self,
+        socket_addr: Self::Arg,
+    ) -> (mpsc::Sender<Self::Config>, mpsc::Receiver<Self::Connection>) {
+        let (config_sender, _config_sender_receiver) = mpsc::channel(0);
+        let (conn_receiver_sender, conn_receiver) = mpsc::channel(0);
+
+        let listener = match TokioTcpListener::bind(&socket_addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Failed listening on {:?}: {:?}", socket_addr, e);
+                // Return empty channels:
+                return (config_sender, conn_receiver);
+            }
+        };
+
+        let incoming_conns = listener
+            .incoming()
+            .compat()
+            .take_while(|res| future::ready(res.is_ok()))
+            .map(Result::unwrap);
+
+        // Messages and frames larger than max_frame_length are rejected during parsing, so that
+        // a remote side can not make us allocate large buffers:
+        let ws_config = WebSocketConfig {
+            max_send_queue: None,
+            max_message_size: Some(self.max_frame_length),
+            max_frame_size: Some(self.max_frame_length),
+        };
+
+        let c_timer_client = self.timer_client.clone();
+        let c_spawner = self.spawner.clone();
+        let max_frame_length = self.max_frame_length;
+        let handshake_timeout_ticks = self.handshake_timeout_ticks;
+        let handshake_transform = FuncFutTransform::new(move |tcp_stream| {
+            let mut c_timer_client = c_timer_client.clone();
+            let mut c_spawner = c_spawner.clone();
+            Box::pin(
+                async move {
+                    let timer_stream = await!(c_timer_client.request_timer_stream()).ok()?;
+                    let handshake_fut = accept_async_with_config(tcp_stream, Some(ws_config));
+                    let ws_stream = match await!(future_timeout(
+                        handshake_fut.compat(),
+                        timer_stream,
+                        handshake_timeout_ticks
+                    )) {
+                        Some(Ok(ws_stream)) => ws_stream,
+                        Some(Err(e)) => {
+                            warn!("WsListener::listen(): Handshake error: {:?}", e);
+                            return None;
+                        }
+                        None => {
+                            warn!("WsListener::listen(): Handshake timed out");
+                            return None;
+                        }
+                    };
+                    Some(ws_stream_to_conn_pair(
+                        ws_stream,
+                        max_frame_length,
+                        &mut c_spawner,
+                    ))
+                },
+            )
+        });
+
+        // Handshakes are performed concurrently, so that a slow handshake does not block other
+        // incoming connections. Connections beyond max_concurrent_handshakes are dropped.
+        let loop_fut = transform_pool_loop(
+            incoming_conns,
+            conn_receiver_sender,
+            handshake_transform,
+            self.max_concurrent_handshakes,
+            self.spawner.clone(),
+        );
+        let _ = self.spawner.spawn(
+            async move {
+                if let Err(e) = await!(loop_fut) {
+                    warn!("WsListener::listen(): transform_pool_loop error: {:?}", e);
+                }
+            },
+        );
+
+        (config_sender, conn_receiver)
+    }
+}
@@ -48,6 +48,86 @@ where
     }
 }
 
+#[derive(Clone)]
+/// Open connections to relays using the hybrid (post-quantum) handshake when possible.
+///
+/// If `opt_hybrid_connector` is provided, it is tried first. If it fails (For example, because the
+/// relay does not support the hybrid handshake), `opt_classic_connector` is used instead, if
+/// provided.
+pub struct HybridFallbackConnector<HC, CC> {
+    opt_hybrid_connector: Option<HC>,
+    opt_classic_connector: Option<CC>,
+}
+
+impl<HC, CC> HybridFallbackConnector<HC, CC> {
+    pub fn new(opt_hybrid_connector: Option<HC>, opt_classic_connector: Option<CC>) -> Self {
+        HybridFallbackConnector {
+            opt_hybrid_connector,
+            opt_classic_connector,
+        }
+    }
+}
+
+impl<HC, CC> FutTransform for HybridFallbackConnector<HC, CC>
+where
+    HC: FutTransform<Input = RelayAddress, Output = Option<ConnPairVec>> + Send,
+    CC: FutTransform<Input = RelayAddress, Output = Option<ConnPairVec>> + Send,
+{
+    type Input = RelayAddress;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, relay_address: Self::Input) -> BoxFuture<'_, Self::Output> {
+        Box::pin(
+            async move {
+                if let Some(hybrid_connector) = &mut self.opt_hybrid_connector {
+                    if let Some(conn_pair) =
+                        await!(hybrid_connector.transform(relay_address.clone()))
+                    {
+                        return Some(conn_pair);
+                    }
+                    if self.opt_classic_connector.is_some() {
+                        warn!(
+                            "HybridFallbackConnector: Hybrid handshake with relay {:?} failed. \
+                             Falling back to the classic handshake",
+                            relay_address.address
+                        );
+                    }
+                }
+                await!(self
+                    .opt_classic_connector
+                    .as_mut()?
+                    .transform(relay_address))
+            },
+        )
+    }
+}
+
+#[derive(Clone)]
+/// Open a plain connection to the network address of a relay address.
+/// Used for direct connections to friends: The encryption is done later, against the friend's
+/// public key.
+pub struct DirectAddressConnector<C> {
+    net_connector: C,
+}
+
+impl<C> DirectAddressConnector<C> {
+    pub fn new(net_connector: C) -> Self {
+        DirectAddressConnector { net_connector }
+    }
+}
+
+impl<C> FutTransform for DirectAddressConnector<C>
+where
+    C: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>> + Send,
+{
+    type Input = RelayAddress;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, relay_address: Self::Input) -> BoxFuture<'_, Self::Output> {
+        self.net_connector.transform(relay_address.address)
+    }
+}
+
 #[derive(Clone)]
 pub struct EncKeepaliveConnector<ET, KT, C, S> {
     encrypt_transform: ET,
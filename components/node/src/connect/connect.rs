@@ -8,7 +8,9 @@ use proto::app_server::messages::{AppPermissions, AppServerToApp, AppToAppServer
 use proto::app_server::serialize::{
     deserialize_app_permissions, deserialize_app_server_to_app, serialize_app_to_app_server,
 };
-use proto::consts::{KEEPALIVE_TICKS, PROTOCOL_VERSION, TICKS_TO_REKEY};
+use proto::consts::{
+    BYTES_TO_REKEY, KEEPALIVE_TICKS, MESSAGES_TO_REKEY, PROTOCOL_VERSION, TICKS_TO_REKEY,
+};
 use proto::net::messages::NetAddress;
 
 use timer::TimerClient;
@@ -20,7 +22,7 @@ use identity::IdentityClient;
 pub use super::node_connection::NodeConnection;
 
 use keepalive::KeepAliveChannel;
-use secure_channel::SecureChannel;
+use secure_channel::{HandshakeMode, SecureChannel};
 use version::VersionPrefix;
 
 pub type NodeConnectionTuple = (
@@ -59,6 +61,9 @@ where
         rng.clone(),
         timer_client.clone(),
         TICKS_TO_REKEY,
+        BYTES_TO_REKEY,
+        MESSAGES_TO_REKEY,
+        HandshakeMode::Negotiate,
         spawner.clone(),
     );
 
@@ -180,4 +180,13 @@ where
     ) -> Result<(), AppConfigError> {
         await!(self.send_request(AppRequest::RemoveIndexServer(index_public_key)))
     }
+
+    /// Set the capacity bucket used when reporting capacities to the index servers.
+    /// `None` means that exact capacities are reported.
+    pub async fn set_capacity_bucket(
+        &mut self,
+        opt_capacity_bucket: Option<u128>,
+    ) -> Result<(), AppConfigError> {
+        await!(self.send_request(AppRequest::SetCapacityBucket(opt_capacity_bucket)))
+    }
 }
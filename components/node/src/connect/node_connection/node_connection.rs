@@ -2,9 +2,10 @@ use futures::channel::mpsc;
 use futures::task::{Spawn, SpawnExt};
 use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt};
 
-use proto::app_server::messages::AppServerToApp;
+use proto::app_server::messages::{AppRequest, AppServerToApp, AppToAppServer};
 
 use crypto::crypto_rand::{CryptoRandom, OffstSystemRandom};
+use crypto::uid::Uid;
 
 use common::multi_consumer::{multi_consumer_service, MultiConsumerClient};
 use common::mutable_state::BatchMutable;
@@ -20,6 +21,7 @@ use crate::connect::connect::NodeConnectionTuple;
 #[derive(Debug)]
 pub enum NodeConnectionError {
     SpawnError,
+    EnableConnectionsReportError,
 }
 
 // TODO: Do we need a way to close this connection?
@@ -30,6 +32,8 @@ pub struct NodeConnection<R = OffstSystemRandom> {
     opt_config: Option<AppConfig<R>>,
     opt_routes: Option<AppRoutes<R>>,
     opt_send_funds: Option<AppSendFunds<R>>,
+    sender: mpsc::Sender<AppToAppServer>,
+    done_app_requests_mc: MultiConsumerClient<Uid>,
     rng: R,
 }
 
@@ -71,6 +75,16 @@ where
             .spawn(routes_fut)
             .map_err(|_| NodeConnectionError::SpawnError)?;
 
+        let (mut incoming_reachability_sender, incoming_reachability) = mpsc::channel(0);
+        let (requests_sender, incoming_requests) = mpsc::channel(0);
+        let reachability_mc = MultiConsumerClient::new(requests_sender);
+        let reachability_fut = multi_consumer_service(incoming_reachability, incoming_requests)
+            .map_err(|e| error!("Reachability multi_consumer_service() error: {:?}", e))
+            .map(|_| ());
+        spawner
+            .spawn(reachability_fut)
+            .map_err(|_| NodeConnectionError::SpawnError)?;
+
         let (mut incoming_send_funds_sender, incoming_send_funds) = mpsc::channel(0);
         let (requests_sender, incoming_requests) = mpsc::channel(0);
         let send_funds_mc = MultiConsumerClient::new(requests_sender);
@@ -123,6 +137,10 @@ where
                             AppServerToApp::ResponseRoutes(client_response_routes) => {
                                 let _ = await!(incoming_routes_sender.send(client_response_routes));
                             }
+                            AppServerToApp::ResponseReachability(client_response_reachability) => {
+                                let _ = await!(incoming_reachability_sender
+                                    .send(client_response_reachability));
+                            }
                         }
                     }
                 },
@@ -143,6 +161,7 @@ where
             Some(AppRoutes::new(
                 sender.clone(),
                 routes_mc.clone(),
+                reachability_mc.clone(),
                 rng.clone(),
             ))
         } else {
@@ -165,6 +184,8 @@ where
             opt_config,
             opt_routes,
             opt_send_funds,
+            sender,
+            done_app_requests_mc,
             rng,
         })
     }
@@ -184,4 +205,28 @@ where
     pub fn send_funds(&mut self) -> Option<&mut AppSendFunds<R>> {
         self.opt_send_funds.as_mut()
     }
+
+    /// Request connection statistics from the node.
+    /// The connections report is empty until this request is done.
+    pub async fn enable_connections_report(&mut self) -> Result<(), NodeConnectionError> {
+        // Randomly generate a new app_request_id:
+        let app_request_id = Uid::new(&self.rng);
+        let to_app_server =
+            AppToAppServer::new(app_request_id, AppRequest::EnableConnectionsReport);
+
+        // Start listening to done requests:
+        let mut incoming_done_requests = await!(self.done_app_requests_mc.request_stream())
+            .map_err(|_| NodeConnectionError::EnableConnectionsReportError)?;
+
+        await!(self.sender.send(to_app_server))
+            .map_err(|_| NodeConnectionError::EnableConnectionsReportError)?;
+
+        // Wait until the current connections report was received:
+        while let Some(done_request_id) = await!(incoming_done_requests.next()) {
+            if app_request_id == done_request_id {
+                return Ok(());
+            }
+        }
+        Err(NodeConnectionError::EnableConnectionsReportError)
+    }
 }
@@ -8,8 +8,13 @@ use crypto::identity::PublicKey;
 use crypto::uid::Uid;
 
 use proto::app_server::messages::{AppRequest, AppToAppServer};
-use proto::index_client::messages::{ClientResponseRoutes, ResponseRoutesResult};
-use proto::index_server::messages::{RequestRoutes, RouteWithCapacity};
+use proto::index_client::messages::{
+    ClientResponseReachability, ClientResponseRoutes, ResponseReachabilityResult,
+    ResponseRoutesResult, RoutesSignature, SignedRoutes,
+};
+use proto::index_server::messages::{
+    NodeWithCapacity, RequestReachability, RequestRoutes, RouteWithCapacity,
+};
 
 #[derive(Debug)]
 pub struct AppRoutesError;
@@ -18,6 +23,7 @@ pub struct AppRoutesError;
 pub struct AppRoutes<R = OffstSystemRandom> {
     sender: mpsc::Sender<AppToAppServer>,
     routes_mc: MultiConsumerClient<ClientResponseRoutes>,
+    reachability_mc: MultiConsumerClient<ClientResponseReachability>,
     rng: R,
 }
 
@@ -55,22 +61,34 @@ where
     pub(super) fn new(
         sender: mpsc::Sender<AppToAppServer>,
         routes_mc: MultiConsumerClient<ClientResponseRoutes>,
+        reachability_mc: MultiConsumerClient<ClientResponseReachability>,
         rng: R,
     ) -> Self {
         AppRoutes {
             sender,
             routes_mc,
+            reachability_mc,
             rng,
         }
     }
 
-    pub async fn request_routes(
+    /// Request routes from the index server.
+    /// Returns the sent request, the returned routes and the signature of the index server over
+    /// the returned routes (If available).
+    async fn request_routes_inner(
         &mut self,
         capacity: u128,
         source: PublicKey,
         destination: PublicKey,
         opt_exclude: Option<(PublicKey, PublicKey)>,
-    ) -> Result<Vec<RouteWithCapacity>, AppRoutesError> {
+    ) -> Result<
+        (
+            RequestRoutes,
+            Vec<RouteWithCapacity>,
+            Option<RoutesSignature>,
+        ),
+        AppRoutesError,
+    > {
         let request_routes_id = Uid::new(&self.rng);
         let request_routes = RequestRoutes {
             request_id: request_routes_id,
@@ -80,7 +98,7 @@ where
             opt_exclude,
         };
 
-        let app_request = AppRequest::RequestRoutes(request_routes);
+        let app_request = AppRequest::RequestRoutes(request_routes.clone());
         let to_app_server = AppToAppServer::new(Uid::new(&self.rng), app_request);
 
         // Start listening for incoming response routes messages:
@@ -96,12 +114,91 @@ where
                 continue;
             }
             match client_response_routes.result {
-                ResponseRoutesResult::Success(routes_with_capacity) => {
-                    return Ok(routes_with_capacity)
+                ResponseRoutesResult::Success(routes) => {
+                    return Ok((
+                        request_routes,
+                        routes,
+                        client_response_routes.opt_routes_signature,
+                    ))
                 }
                 ResponseRoutesResult::Failure => return Err(AppRoutesError),
             }
         }
         Err(AppRoutesError)
     }
+
+    /// Request routes from the index server, together with the signature of the index server over
+    /// the request and the returned routes.
+    /// Returns the sent request, required to verify the signature using
+    /// `SignedRoutes::verify_signature()`.
+    /// Fails if the routes are not signed (The node or the index server are too old).
+    pub async fn request_signed_routes(
+        &mut self,
+        capacity: u128,
+        source: PublicKey,
+        destination: PublicKey,
+        opt_exclude: Option<(PublicKey, PublicKey)>,
+    ) -> Result<(RequestRoutes, SignedRoutes), AppRoutesError> {
+        let (request_routes, routes, opt_routes_signature) =
+            await!(self.request_routes_inner(capacity, source, destination, opt_exclude))?;
+        let routes_signature = opt_routes_signature.ok_or(AppRoutesError)?;
+        Ok((
+            request_routes,
+            SignedRoutes {
+                routes,
+                routes_signature,
+            },
+        ))
+    }
+
+    pub async fn request_routes(
+        &mut self,
+        capacity: u128,
+        source: PublicKey,
+        destination: PublicKey,
+        opt_exclude: Option<(PublicKey, PublicKey)>,
+    ) -> Result<Vec<RouteWithCapacity>, AppRoutesError> {
+        let (_request_routes, routes, _opt_routes_signature) =
+            await!(self.request_routes_inner(capacity, source, destination, opt_exclude))?;
+        Ok(routes)
+    }
+
+    /// Find the nodes that can send at least `capacity` to `destination`.
+    /// Returns at most `opt_max_nodes` nodes, ordered from the largest capacity to the smallest.
+    pub async fn request_reachability(
+        &mut self,
+        capacity: u128,
+        destination: PublicKey,
+        opt_max_nodes: Option<u32>,
+    ) -> Result<Vec<NodeWithCapacity>, AppRoutesError> {
+        let request_id = Uid::new(&self.rng);
+        let request_reachability = RequestReachability {
+            request_id,
+            capacity,
+            destination,
+            opt_max_nodes,
+        };
+
+        let app_request = AppRequest::RequestReachability(request_reachability);
+        let to_app_server = AppToAppServer::new(Uid::new(&self.rng), app_request);
+
+        // Start listening for incoming response reachability messages:
+        let mut incoming_reachability =
+            await!(self.reachability_mc.request_stream()).map_err(|_| AppRoutesError)?;
+
+        // Send our request to offst node:
+        await!(self.sender.send(to_app_server)).map_err(|_| AppRoutesError)?;
+
+        while let Some(client_response_reachability) = await!(incoming_reachability.next()) {
+            if client_response_reachability.request_id != request_id {
+                // This is not our request
+                continue;
+            }
+            match client_response_reachability.result {
+                ResponseReachabilityResult::Success(nodes) => return Ok(nodes),
+                ResponseReachabilityResult::Failure => return Err(AppRoutesError),
+            }
+        }
+        Err(AppRoutesError)
+    }
 }
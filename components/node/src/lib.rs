@@ -25,5 +25,5 @@ mod node;
 mod types;
 
 pub use self::net_node::{net_node, NetNodeError};
-pub use self::types::{NodeConfig, NodeState};
+pub use self::types::{NodeConfig, NodeState, NodeStateV0};
 pub use app_server::IncomingAppConnection;
@@ -15,7 +15,9 @@ use proto::app_server::messages::AppPermissions;
 use proto::app_server::serialize::{
     deserialize_app_to_app_server, serialize_app_permissions, serialize_app_server_to_app,
 };
-use proto::consts::{KEEPALIVE_TICKS, PROTOCOL_VERSION, TICKS_TO_REKEY};
+use proto::consts::{
+    HYBRID_VERSION_FLAG, KEEPALIVE_TICKS, PROTOCOL_VERSION, RELAY_MUX_PROTOCOL_VERSION,
+};
 use proto::net::messages::NetAddress;
 
 use database::{database_loop, AtomicDb, DatabaseClient};
@@ -154,9 +156,46 @@ where
     }
 }
 
-pub async fn net_node<IAC, C, R, GT, AD, DS, TS, S>(
+/// Wrap `net_connector` with a version prefix.
+fn create_version_connector<C, S>(
+    net_connector: C,
+    version: u32,
+    spawner: S,
+) -> impl FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+       + Clone
+       + Send
+       + Sync
+       + 'static
+where
+    C: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S: Spawn + Clone + Send + Sync + 'static,
+{
+    let version_transform = VersionPrefix::new(version, spawner);
+    FuncFutTransform::new(move |address| {
+        let mut c_net_connector = net_connector.clone();
+        let mut c_version_transform = version_transform.clone();
+        Box::pin(
+            async move {
+                let conn_pair = await!(c_net_connector.transform(address))?;
+                Some(await!(c_version_transform.transform(conn_pair)))
+            },
+        )
+    })
+}
+
+/// `incoming_direct_raw_conns` are connections from friends that connect to us directly, without a
+/// relay.
+/// `opt_punch_connector`, if provided, is used to punch direct connections to friends through
+/// NATs. It should connect from the same local address `net_connector` uses.
+pub async fn net_node<IAC, IDC, C, PC, R, GT, AD, DS, TS, S>(
     incoming_app_raw_conns: IAC,
+    incoming_direct_raw_conns: IDC,
     net_connector: C,
+    opt_punch_connector: Option<PC>,
     timer_client: TimerClient,
     identity_client: IdentityClient,
     rng: R,
@@ -169,11 +208,17 @@ pub async fn net_node<IAC, C, R, GT, AD, DS, TS, S>(
 ) -> Result<(), NetNodeError>
 where
     IAC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
+    IDC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
     C: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
         + Clone
         + Send
         + Sync
         + 'static,
+    PC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     R: CryptoRandom + Clone + 'static,
     GT: Fn() -> Option<HashMap<PublicKey, AppPermissions>> + Clone + Send + 'static,
     AD: AtomicDb<State = NodeState<NetAddress>, Mutation = NodeMutation<NetAddress>>
@@ -184,18 +229,38 @@ where
     TS: Spawn + Clone + Send + Sync + 'static,
     S: Spawn + Clone + Send + Sync + 'static,
 {
+    // Relays that support multiplexing are connected using a different version prefix.
+    // The hybrid flag is declared if we use the hybrid handshake. Relays that do not support it
+    // reject this version, and we fall back to the classic multiplexed version:
+    let mux_version_connector = create_version_connector(
+        net_connector.clone(),
+        RELAY_MUX_PROTOCOL_VERSION,
+        spawner.clone(),
+    );
+    let hybrid_mux_version_connector = create_version_connector(
+        net_connector.clone(),
+        RELAY_MUX_PROTOCOL_VERSION | HYBRID_VERSION_FLAG,
+        spawner.clone(),
+    );
+
     // Wrap net connector with a version prefix:
     let version_transform = VersionPrefix::new(PROTOCOL_VERSION, spawner.clone());
+    let version_connector =
+        create_version_connector(net_connector, PROTOCOL_VERSION, spawner.clone());
+
+    // Punched connections to friends are prefixed with a version, like direct connections:
     let c_version_transform = version_transform.clone();
-    let version_connector = FuncFutTransform::new(move |address| {
-        let mut c_net_connector = net_connector.clone();
-        let mut c_version_transform = c_version_transform.clone();
-        Box::pin(
-            async move {
-                let conn_pair = await!(c_net_connector.transform(address))?;
-                Some(await!(c_version_transform.transform(conn_pair)))
-            },
-        )
+    let opt_punch_version_connector = opt_punch_connector.map(move |punch_connector| {
+        FuncFutTransform::new(move |address| {
+            let mut c_punch_connector = punch_connector.clone();
+            let mut c_version_transform = c_version_transform.clone();
+            Box::pin(
+                async move {
+                    let conn_pair = await!(c_punch_connector.transform(address))?;
+                    Some(await!(c_version_transform.transform(conn_pair)))
+                },
+            )
+        })
     });
 
     let local_public_key = await!(identity_client.request_public_key())
@@ -226,13 +291,43 @@ where
         identity_client.clone(),
         rng.clone(),
         timer_client.clone(),
-        TICKS_TO_REKEY,
+        node_config.ticks_to_rekey,
+        node_config.bytes_to_rekey,
+        node_config.messages_to_rekey,
+        node_config.handshake_mode(),
         spawner.clone(),
     );
 
     let keepalive_transform =
         KeepAliveChannel::new(timer_client.clone(), KEEPALIVE_TICKS, spawner.clone());
 
+    // Direct connections from friends are prefixed with a version, like connections to relays:
+    let c_version_transform = version_transform.clone();
+    let direct_version_transform = FuncFutTransform::new(move |conn_pair| {
+        let mut c_version_transform = c_version_transform.clone();
+        Box::pin(
+            async move {
+                let conn_pair = await!(c_version_transform.transform(conn_pair));
+                Some(conn_pair)
+            },
+        )
+    });
+
+    let (incoming_direct_conns_sender, incoming_direct_conns) = mpsc::channel(0);
+    let direct_pool_fut = transform_pool_loop(
+        incoming_direct_raw_conns,
+        incoming_direct_conns_sender,
+        direct_version_transform,
+        node_config.max_concurrent_encrypt,
+        spawner.clone(),
+    )
+    .map_err(|e| error!("transform_pool_loop() error: {:?}", e))
+    .map(|_| ());
+
+    let _direct_pool_handle = spawner
+        .spawn_with_handle(direct_pool_fut)
+        .map_err(|_| NetNodeError::SpawnError)?;
+
     let app_conn_transform = AppConnTransform::new(
         version_transform,
         encrypt_transform,
@@ -268,7 +363,11 @@ where
         node_state,
         database_client,
         version_connector,
+        mux_version_connector,
+        hybrid_mux_version_connector,
+        opt_punch_version_connector,
         incoming_apps,
+        incoming_direct_conns,
         rng,
         spawner.clone()
     ))
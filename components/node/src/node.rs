@@ -18,8 +18,9 @@ use funder::types::{
     ChannelerConfig, FunderIncomingComm, FunderOutgoingComm, IncomingLivenessMessage,
 };
 use funder::{funder_loop, FunderError, FunderState};
-use keepalive::KeepAliveChannel;
-use secure_channel::SecureChannel;
+use keepalive::{KeepAliveChannel, KeepAliveStatsChannel};
+use relay::MuxConnector;
+use secure_channel::{HandshakeMode, SecureChannel};
 
 use index_client::{spawn_index_client, IndexClientError};
 
@@ -31,8 +32,11 @@ use proto::funder::serialize::{deserialize_friend_message, serialize_friend_mess
 use proto::index_client::messages::{AppServerToIndexClient, IndexClientToAppServer};
 use proto::net::messages::NetAddress;
 use proto::report::convert::funder_report_to_index_client_state;
+use proto::report::messages::ConnectionsReportMutation;
 
-use crate::adapters::{EncKeepaliveConnector, EncRelayConnector};
+use crate::adapters::{
+    DirectAddressConnector, EncKeepaliveConnector, EncRelayConnector, HybridFallbackConnector,
+};
 use crate::types::{create_node_report, NodeConfig, NodeMutation, NodeState};
 
 #[derive(Debug, From)]
@@ -45,15 +49,30 @@ pub enum NodeError {
     AppServerError(AppServerError),
 }
 
-fn node_spawn_channeler<C, R, S>(
+/// `mux_version_connector` is used to open multiplexed sessions to relays. If a relay does not
+/// support multiplexing, we fall back to `version_connector`.
+/// If `node_config.hybrid_handshake` is set, multiplexed sessions are first attempted using the
+/// hybrid (post-quantum) handshake over `hybrid_mux_version_connector`, which should declare a
+/// version with `HYBRID_VERSION_FLAG`. If the relay rejects it, we fall back to the classic
+/// multiplexed session. If `node_config.require_hybrid` is set, there is no fallback at all.
+/// `incoming_direct_conns` are version prefixed connections from friends that connect to us
+/// directly.
+/// `opt_punch_version_connector` is used to punch version prefixed direct connections to friends
+/// through NATs, if available.
+fn node_spawn_channeler<C, MC, HC, PC, IDC, R, S>(
     node_config: &NodeConfig,
     local_public_key: PublicKey,
     identity_client: IdentityClient,
     timer_client: TimerClient,
     version_connector: C,
+    mux_version_connector: MC,
+    hybrid_mux_version_connector: HC,
+    opt_punch_version_connector: Option<PC>,
+    incoming_direct_conns: IDC,
     rng: R,
     from_funder: mpsc::Receiver<FunderToChanneler<RelayAddress>>,
     to_funder: mpsc::Sender<ChannelerToFunder>,
+    to_reports: mpsc::Sender<ConnectionsReportMutation<RelayAddress>>,
     mut spawner: S,
 ) -> Result<impl Future<Output = Result<(), ChannelerError>>, NodeError>
 where
@@ -62,6 +81,22 @@ where
         + Send
         + Sync
         + 'static,
+    MC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    HC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    PC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    IDC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
     R: CryptoRandom + Clone + 'static,
     S: Spawn + Clone + Send + Sync + 'static,
 {
@@ -70,6 +105,32 @@ where
         rng.clone(),
         timer_client.clone(),
         node_config.ticks_to_rekey,
+        node_config.bytes_to_rekey,
+        node_config.messages_to_rekey,
+        HandshakeMode::Classic,
+        spawner.clone(),
+    );
+
+    let hybrid_encrypt_transform = SecureChannel::new(
+        identity_client.clone(),
+        rng.clone(),
+        timer_client.clone(),
+        node_config.ticks_to_rekey,
+        node_config.bytes_to_rekey,
+        node_config.messages_to_rekey,
+        HandshakeMode::Hybrid,
+        spawner.clone(),
+    );
+
+    // Friends are offered the hybrid handshake, according to the configuration:
+    let friend_encrypt_transform = SecureChannel::new(
+        identity_client.clone(),
+        rng.clone(),
+        timer_client.clone(),
+        node_config.ticks_to_rekey,
+        node_config.bytes_to_rekey,
+        node_config.messages_to_rekey,
+        node_config.handshake_mode(),
         spawner.clone(),
     );
 
@@ -79,7 +140,42 @@ where
         spawner.clone(),
     );
 
-    let enc_relay_connector = EncRelayConnector::new(encrypt_transform.clone(), version_connector);
+    // Friends that advertise a direct address are connected to without a relay:
+    let direct_connector = DirectAddressConnector::new(version_connector.clone());
+
+    let legacy_relay_connector =
+        EncRelayConnector::new(encrypt_transform.clone(), version_connector);
+    let mux_relay_connector = EncRelayConnector::new(encrypt_transform, mux_version_connector);
+    let hybrid_mux_relay_connector =
+        EncRelayConnector::new(hybrid_encrypt_transform, hybrid_mux_version_connector);
+
+    // Relays that reject the hybrid handshake are connected using the classic multiplexed
+    // session, unless the hybrid handshake is required:
+    let (opt_hybrid_relay_connector, opt_classic_relay_connector, opt_legacy_relay_connector) =
+        if node_config.require_hybrid {
+            (Some(hybrid_mux_relay_connector), None, None)
+        } else if node_config.hybrid_handshake {
+            (
+                Some(hybrid_mux_relay_connector),
+                Some(mux_relay_connector),
+                Some(legacy_relay_connector),
+            )
+        } else {
+            (
+                None,
+                Some(mux_relay_connector),
+                Some(legacy_relay_connector),
+            )
+        };
+    let enc_relay_connector = MuxConnector::new(
+        HybridFallbackConnector::new(opt_hybrid_relay_connector, opt_classic_relay_connector),
+        opt_legacy_relay_connector,
+        keepalive_transform.clone(),
+        spawner.clone(),
+    );
+
+    // Connections to friends also report keepalive round trip times:
+    let keepalive_stats_transform = KeepAliveStatsChannel::new(keepalive_transform);
 
     spawner
         .spawn_with_handle(spawn_channeler(
@@ -88,11 +184,16 @@ where
             node_config.backoff_ticks,
             node_config.conn_timeout_ticks,
             node_config.max_concurrent_encrypt,
+            node_config.conn_stats_ticks,
             enc_relay_connector,
-            encrypt_transform,
-            keepalive_transform,
+            direct_connector,
+            opt_punch_version_connector,
+            friend_encrypt_transform,
+            keepalive_stats_transform,
+            incoming_direct_conns,
             from_funder,
             to_funder,
+            to_reports,
             spawner.clone(),
         ))
         .map_err(|_| NodeError::SpawnError)
@@ -281,6 +382,9 @@ where
         rng.clone(),
         timer_client.clone(),
         node_config.ticks_to_rekey,
+        node_config.bytes_to_rekey,
+        node_config.messages_to_rekey,
+        node_config.handshake_mode(),
         spawner.clone(),
     );
 
@@ -316,14 +420,18 @@ where
     .map_err(|_| NodeError::SpawnError)
 }
 
-pub async fn node<C, IA, R, S>(
+pub async fn node<C, MC, HC, PC, IA, IDC, R, S>(
     node_config: NodeConfig,
     identity_client: IdentityClient,
     timer_client: TimerClient,
     node_state: NodeState<NetAddress>,
     database_client: DatabaseClient<NodeMutation<NetAddress>>,
     version_connector: C,
+    mux_version_connector: MC,
+    hybrid_mux_version_connector: HC,
+    opt_punch_version_connector: Option<PC>,
     incoming_apps: IA,
+    incoming_direct_conns: IDC,
     rng: R,
     mut spawner: S,
 ) -> Result<(), NodeError>
@@ -333,7 +441,23 @@ where
         + Send
         + Sync
         + 'static,
+    MC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    HC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    PC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     IA: Stream<Item = IncomingAppConnection<NetAddress>> + Unpin + Send + 'static,
+    IDC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
     R: CryptoRandom + Clone + 'static,
     S: Spawn + Clone + Send + Sync + 'static,
 {
@@ -349,15 +473,24 @@ where
     let (funder_to_channeler_sender, funder_to_channeler_receiver) =
         mpsc::channel(node_config.channel_len);
 
+    // Channeler --> AppServer (Connection statistics)
+    let (channeler_to_app_server_sender, channeler_to_app_server_receiver) =
+        mpsc::channel(node_config.channel_len);
+
     let channeler_handle = node_spawn_channeler(
         &node_config,
         local_public_key.clone(),
         identity_client.clone(),
         timer_client.clone(),
         version_connector.clone(),
+        mux_version_connector,
+        hybrid_mux_version_connector,
+        opt_punch_version_connector,
+        incoming_direct_conns,
         rng.clone(),
         funder_to_channeler_receiver,
         channeler_to_funder_sender,
+        channeler_to_app_server_sender,
         spawner.clone(),
     )?;
 
@@ -391,6 +524,7 @@ where
         app_server_to_funder_sender,
         index_client_to_app_server_receiver,
         app_server_to_index_client_sender,
+        channeler_to_app_server_receiver,
         incoming_apps,
         initial_node_report.clone(),
         spawner.clone(),
@@ -8,6 +8,10 @@ use index_client::{IndexClientConfig, IndexClientConfigMutation};
 
 use proto::app_server::messages::NodeReport;
 use proto::index_client::messages::IndexClientReport;
+use proto::index_server::messages::NamedIndexServerAddress;
+use proto::report::messages::ConnectionsReport;
+
+use secure_channel::HandshakeMode;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NodeMutation<B: Clone> {
@@ -21,6 +25,29 @@ pub struct NodeState<B: Clone> {
     pub index_client_config: IndexClientConfig<B>,
 }
 
+/// Node state as stored by versions that did not support capacity buckets.
+/// Used to migrate older database files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeStateV0<B: Clone> {
+    pub funder_state: FunderState<B>,
+    pub index_servers: Vec<NamedIndexServerAddress<B>>,
+}
+
+impl<B> From<NodeStateV0<B>> for NodeState<B>
+where
+    B: Clone,
+{
+    fn from(node_state_v0: NodeStateV0<B>) -> Self {
+        NodeState {
+            funder_state: node_state_v0.funder_state,
+            index_client_config: IndexClientConfig {
+                index_servers: node_state_v0.index_servers,
+                opt_capacity_bucket: None,
+            },
+        }
+    }
+}
+
 impl<B> NodeState<B>
 where
     B: Clone + CanonicalSerialize,
@@ -77,6 +104,8 @@ where
     NodeReport {
         funder_report: create_initial_report(&node_state.funder_state),
         index_client_report: create_index_client_report(&node_state.index_client_config),
+        // Connection statistics are collected only while the node is running:
+        connections_report: ConnectionsReport::new(),
     }
 }
 
@@ -90,11 +119,25 @@ pub struct NodeConfig {
     pub keepalive_ticks: usize,
     /// Amount of ticks to wait until the next rekeying (Channel encryption)
     pub ticks_to_rekey: usize,
+    /// Amount of bytes to encrypt using the same key until the next rekeying
+    pub bytes_to_rekey: u64,
+    /// Amount of messages to encrypt using the same key until the next rekeying
+    pub messages_to_rekey: u64,
+    /// Use the hybrid (post-quantum) handshake for multiplexed connections to relays.
+    /// Relays that do not support it are connected using the classic handshake.
+    /// The hybrid handshake is also offered to friends, index servers and apps, and used if they
+    /// support it.
+    pub hybrid_handshake: bool,
+    /// Only use the hybrid (post-quantum) handshake. Relays, friends, index servers and apps that
+    /// do not support it can not be communicated with.
+    pub require_hybrid: bool,
     /// Maximum amount of encryption set ups (diffie hellman) that we allow to occur at the same
     /// time from external communications (Channeler side)
     pub max_concurrent_encrypt: usize,
     /// The amount of ticks we are willing to wait until a connection is established.
     pub conn_timeout_ticks: usize,
+    /// The amount of ticks between reports of friends connection statistics
+    pub conn_stats_ticks: usize,
     /// Maximum amount of operations in one move token message
     pub max_operations_in_batch: usize,
     /// The size we allocate for the user send funds requests queue.
@@ -107,3 +150,16 @@ pub struct NodeConfig {
     /// for incoming app connections
     pub max_concurrent_incoming_apps: usize,
 }
+
+impl NodeConfig {
+    /// The handshake mode used for secure channels with friends, index servers and apps.
+    pub fn handshake_mode(&self) -> HandshakeMode {
+        if self.require_hybrid {
+            HandshakeMode::Hybrid
+        } else if self.hybrid_handshake {
+            HandshakeMode::Negotiate
+        } else {
+            HandshakeMode::Classic
+        }
+    }
+}
@@ -8,11 +8,13 @@ use crate::funder::messages::{
     SetFriendRemoteMaxDebt, UserRequestSendFunds,
 };
 use crate::index_client::messages::{
-    ClientResponseRoutes, IndexClientReport, IndexClientReportMutation,
+    ClientResponseReachability, ClientResponseRoutes, IndexClientReport, IndexClientReportMutation,
 };
-use crate::index_server::messages::{NamedIndexServerAddress, RequestRoutes};
+use crate::index_server::messages::{NamedIndexServerAddress, RequestReachability, RequestRoutes};
 use crate::net::messages::NetAddress;
-use crate::report::messages::{FunderReport, FunderReportMutation};
+use crate::report::messages::{
+    ConnectionsReport, ConnectionsReportMutation, FunderReport, FunderReportMutation,
+};
 
 // TODO: Move NamedRelayAddress and RelayAddress to another place in offst-proto?
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -56,6 +58,7 @@ where
 {
     pub funder_report: FunderReport<B>,
     pub index_client_report: IndexClientReport<B>,
+    pub connections_report: ConnectionsReport<RelayAddress<B>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -65,6 +68,7 @@ where
 {
     Funder(FunderReportMutation<B>),
     IndexClient(IndexClientReportMutation<B>),
+    Connections(ConnectionsReportMutation<RelayAddress<B>>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -87,6 +91,7 @@ where
     Report(NodeReport<B>),
     ReportMutations(ReportMutations<B>),
     ResponseRoutes(ClientResponseRoutes),
+    ResponseReachability(ClientResponseReachability),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -119,6 +124,13 @@ pub enum AppRequest<B = NetAddress> {
     /// Manage index servers:
     AddIndexServer(NamedIndexServerAddress<B>),
     RemoveIndexServer(PublicKey),
+    /// Round down capacities reported to the index servers to a multiple of this value:
+    SetCapacityBucket(Option<u128>),
+    /// Find the nodes that can send funds to a node:
+    RequestReachability(RequestReachability),
+    /// Receive connection statistics reports.
+    /// Apps that do not send this request do not receive them:
+    EnableConnectionsReport,
 }
 #[derive(Debug, PartialEq, Eq)]
 pub struct AppToAppServer<B = NetAddress> {
@@ -152,6 +164,7 @@ where
                 .mutate(mutation)
                 .map_err(|_| NodeReportMutateError)?,
             NodeReportMutation::IndexClient(mutation) => self.index_client_report.mutate(mutation),
+            NodeReportMutation::Connections(mutation) => self.connections_report.mutate(mutation),
         };
         Ok(())
     }
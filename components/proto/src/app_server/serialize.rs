@@ -1,11 +1,11 @@
 use std::io;
 
 use crate::capnp_common::{
-    read_custom_int128, read_custom_u_int128, read_invoice_id, read_named_index_server_address,
-    read_named_relay_address, read_public_key, read_receipt, read_relay_address, read_signature,
-    read_uid, write_custom_int128, write_custom_u_int128, write_invoice_id,
-    write_named_index_server_address, write_named_relay_address, write_public_key, write_receipt,
-    write_relay_address, write_signature, write_uid,
+    read_custom_int128, read_custom_u_int128, read_hash, read_invoice_id,
+    read_named_index_server_address, read_named_relay_address, read_public_key, read_receipt,
+    read_relay_address, read_signature, read_uid, write_custom_int128, write_custom_u_int128,
+    write_hash, write_invoice_id, write_named_index_server_address, write_named_relay_address,
+    write_public_key, write_receipt, write_relay_address, write_signature, write_uid,
 };
 use capnp;
 use capnp::serialize_packed;
@@ -14,13 +14,18 @@ use common::int_convert::usize_to_u32;
 use crate::serialize::SerializeError;
 use app_server_capnp;
 
-use crate::index_client::messages::{ClientResponseRoutes, ResponseRoutesResult};
+use crate::index_client::messages::{
+    ClientResponseReachability, ClientResponseRoutes, ResponseReachabilityResult,
+    ResponseRoutesResult, RoutesSignature,
+};
 
 use crate::report::serialize::{
     deser_node_report, deser_node_report_mutation, ser_node_report, ser_node_report_mutation,
 };
 use index_server::serialize::{
-    deser_request_routes, deser_route_with_capacity, ser_request_routes, ser_route_with_capacity,
+    deser_node_with_capacity, deser_request_reachability, deser_request_routes,
+    deser_route_with_capacity, ser_node_with_capacity, ser_request_reachability,
+    ser_request_routes, ser_route_with_capacity,
 };
 
 use crate::funder::messages::{
@@ -286,7 +291,63 @@ fn deser_reset_friend_channel(
     })
 }
 
+fn ser_set_capacity_bucket(
+    opt_capacity_bucket: Option<u128>,
+    set_capacity_bucket_builder: &mut app_server_capnp::set_capacity_bucket::Builder,
+) {
+    let mut opt_capacity_bucket_builder = set_capacity_bucket_builder
+        .reborrow()
+        .init_opt_capacity_bucket();
+    match opt_capacity_bucket {
+        Some(capacity_bucket) => write_custom_u_int128(
+            capacity_bucket,
+            &mut opt_capacity_bucket_builder.init_capacity_bucket(),
+        ),
+        None => opt_capacity_bucket_builder.set_empty(()),
+    };
+}
+
+fn deser_set_capacity_bucket(
+    set_capacity_bucket_reader: &app_server_capnp::set_capacity_bucket::Reader,
+) -> Result<Option<u128>, SerializeError> {
+    let opt_capacity_bucket_reader = set_capacity_bucket_reader.get_opt_capacity_bucket();
+    Ok(match opt_capacity_bucket_reader.which()? {
+        app_server_capnp::set_capacity_bucket::opt_capacity_bucket::CapacityBucket(
+            capacity_bucket_reader,
+        ) => Some(read_custom_u_int128(&capacity_bucket_reader?)?),
+        app_server_capnp::set_capacity_bucket::opt_capacity_bucket::Empty(()) => None,
+    })
+}
+
 // TODO: Add serialization code for ResponseRoutesResult, ClientResponseRoutes
+fn ser_routes_signature(
+    routes_signature: &RoutesSignature,
+    routes_signature_builder: &mut app_server_capnp::routes_signature::Builder,
+) {
+    write_public_key(
+        &routes_signature.server_public_key,
+        &mut routes_signature_builder.reborrow().init_server_public_key(),
+    );
+    write_hash(
+        &routes_signature.time_hash,
+        &mut routes_signature_builder.reborrow().init_time_hash(),
+    );
+    write_signature(
+        &routes_signature.signature,
+        &mut routes_signature_builder.reborrow().init_signature(),
+    );
+}
+
+fn deser_routes_signature(
+    routes_signature_reader: &app_server_capnp::routes_signature::Reader,
+) -> Result<RoutesSignature, SerializeError> {
+    Ok(RoutesSignature {
+        server_public_key: read_public_key(&routes_signature_reader.get_server_public_key()?)?,
+        time_hash: read_hash(&routes_signature_reader.get_time_hash()?)?,
+        signature: read_signature(&routes_signature_reader.get_signature()?)?,
+    })
+}
+
 fn ser_response_routes_result(
     response_routes_result: &ResponseRoutesResult,
     response_routes_result_builder: &mut app_server_capnp::response_routes_result::Builder,
@@ -335,14 +396,95 @@ fn ser_client_response_routes(
         &client_response_routes.result,
         &mut client_response_routes_builder.reborrow().init_result(),
     );
+
+    let mut opt_routes_signature_builder = client_response_routes_builder
+        .reborrow()
+        .init_opt_routes_signature();
+    match &client_response_routes.opt_routes_signature {
+        Some(routes_signature) => ser_routes_signature(
+            routes_signature,
+            &mut opt_routes_signature_builder.init_routes_signature(),
+        ),
+        None => opt_routes_signature_builder.set_empty(()),
+    };
 }
 
 fn deser_client_response_routes(
     client_response_routes_reader: &app_server_capnp::client_response_routes::Reader,
 ) -> Result<ClientResponseRoutes, SerializeError> {
+    let opt_routes_signature = match client_response_routes_reader
+        .get_opt_routes_signature()
+        .which()?
+    {
+        app_server_capnp::client_response_routes::opt_routes_signature::Empty(()) => None,
+        app_server_capnp::client_response_routes::opt_routes_signature::RoutesSignature(
+            routes_signature_reader,
+        ) => Some(deser_routes_signature(&routes_signature_reader?)?),
+    };
+
     Ok(ClientResponseRoutes {
         request_id: read_uid(&client_response_routes_reader.get_request_id()?)?,
         result: deser_response_routes_result(&client_response_routes_reader.get_result()?)?,
+        opt_routes_signature,
+    })
+}
+
+fn ser_response_reachability_result(
+    response_reachability_result: &ResponseReachabilityResult,
+    result_builder: &mut app_server_capnp::response_reachability_result::Builder,
+) {
+    match response_reachability_result {
+        ResponseReachabilityResult::Success(nodes_with_capacity) => {
+            let nodes_len = usize_to_u32(nodes_with_capacity.len()).unwrap();
+            let mut nodes_with_capacity_builder = result_builder.reborrow().init_success(nodes_len);
+            for (index, node_with_capacity) in nodes_with_capacity.iter().enumerate() {
+                let mut node_with_capacity_builder = nodes_with_capacity_builder
+                    .reborrow()
+                    .get(usize_to_u32(index).unwrap());
+                ser_node_with_capacity(node_with_capacity, &mut node_with_capacity_builder);
+            }
+        }
+        ResponseReachabilityResult::Failure => result_builder.reborrow().set_failure(()),
+    }
+}
+
+fn deser_response_reachability_result(
+    result_reader: &app_server_capnp::response_reachability_result::Reader,
+) -> Result<ResponseReachabilityResult, SerializeError> {
+    Ok(match result_reader.which()? {
+        app_server_capnp::response_reachability_result::Success(nodes_with_capacity_reader) => {
+            let mut nodes_with_capacity = Vec::new();
+            for node_with_capacity in nodes_with_capacity_reader? {
+                nodes_with_capacity.push(deser_node_with_capacity(&node_with_capacity)?);
+            }
+            ResponseReachabilityResult::Success(nodes_with_capacity)
+        }
+        app_server_capnp::response_reachability_result::Failure(()) => {
+            ResponseReachabilityResult::Failure
+        }
+    })
+}
+
+fn ser_client_response_reachability(
+    client_response_reachability: &ClientResponseReachability,
+    reachability_builder: &mut app_server_capnp::client_response_reachability::Builder,
+) {
+    write_uid(
+        &client_response_reachability.request_id,
+        &mut reachability_builder.reborrow().init_request_id(),
+    );
+    ser_response_reachability_result(
+        &client_response_reachability.result,
+        &mut reachability_builder.reborrow().init_result(),
+    );
+}
+
+fn deser_client_response_reachability(
+    reachability_reader: &app_server_capnp::client_response_reachability::Reader,
+) -> Result<ClientResponseReachability, SerializeError> {
+    Ok(ClientResponseReachability {
+        request_id: read_uid(&reachability_reader.get_request_id()?)?,
+        result: deser_response_reachability_result(&reachability_reader.get_result()?)?,
     })
 }
 
@@ -464,6 +606,14 @@ fn ser_app_server_to_app(
             response_routes,
             &mut app_server_to_app_builder.reborrow().init_response_routes(),
         ),
+        AppServerToApp::ResponseReachability(response_reachability) => {
+            ser_client_response_reachability(
+                response_reachability,
+                &mut app_server_to_app_builder
+                    .reborrow()
+                    .init_response_reachability(),
+            )
+        }
     }
 }
 
@@ -485,6 +635,11 @@ fn deser_app_server_to_app(
                 &client_response_routes_reader?,
             )?)
         }
+        app_server_capnp::app_server_to_app::ResponseReachability(
+            client_response_reachability_reader,
+        ) => AppServerToApp::ResponseReachability(deser_client_response_reachability(
+            &client_response_reachability_reader?,
+        )?),
     })
 }
 
@@ -567,6 +722,17 @@ fn ser_app_request(
             public_key,
             &mut app_request_builder.reborrow().init_remove_index_server(),
         ),
+        AppRequest::SetCapacityBucket(opt_capacity_bucket) => ser_set_capacity_bucket(
+            *opt_capacity_bucket,
+            &mut app_request_builder.reborrow().init_set_capacity_bucket(),
+        ),
+        AppRequest::RequestReachability(request_reachability) => ser_request_reachability(
+            request_reachability,
+            &mut app_request_builder.reborrow().init_request_reachability(),
+        ),
+        AppRequest::EnableConnectionsReport => {
+            app_request_builder.set_enable_connections_report(())
+        }
     }
 }
 
@@ -631,6 +797,17 @@ fn deser_app_request(
         app_server_capnp::app_request::RemoveIndexServer(public_key_reader) => {
             AppRequest::RemoveIndexServer(read_public_key(&public_key_reader?)?)
         }
+        app_server_capnp::app_request::SetCapacityBucket(set_capacity_bucket_reader) => {
+            AppRequest::SetCapacityBucket(deser_set_capacity_bucket(&set_capacity_bucket_reader?)?)
+        }
+        app_server_capnp::app_request::RequestReachability(request_reachability_reader) => {
+            AppRequest::RequestReachability(deser_request_reachability(
+                &request_reachability_reader?,
+            )?)
+        }
+        app_server_capnp::app_request::EnableConnectionsReport(()) => {
+            AppRequest::EnableConnectionsReport
+        }
     })
 }
 
@@ -724,9 +901,14 @@ pub fn deserialize_app_to_app_server(data: &[u8]) -> Result<AppToAppServer, Seri
 mod tests {
     use super::*;
     use crate::app_server::messages::{NodeReportMutation, RelayAddress};
+    use crate::funder::messages::FriendsRoute;
     use crate::index_client::messages::IndexClientReportMutation;
-    use crate::report::messages::FunderReportMutation;
-    use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+    use crate::index_server::messages::{NodeWithCapacity, RequestReachability, RouteWithCapacity};
+    use crate::report::messages::{
+        ConnectionsReportMutation, FriendConnectionReport, FunderReportMutation, RelayHealthReport,
+    };
+    use crypto::hash::{HashResult, HASH_RESULT_LEN};
+    use crypto::identity::{PublicKey, Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
     use crypto::uid::{Uid, UID_LEN};
     use std::convert::TryInto;
 
@@ -758,6 +940,51 @@ mod tests {
         mutations.push(NodeReportMutation::IndexClient(
             index_client_report_mutation,
         ));
+
+        let friend_connection_report = FriendConnectionReport {
+            opt_connected_since: Some(1_500_000_000),
+            reconnect_count: 3,
+            bytes_sent: 1000,
+            bytes_received: 2000,
+            messages_sent: 10,
+            messages_received: 20,
+            opt_last_rtt_ms: Some(45),
+            opt_relay: Some(RelayAddress {
+                public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+                address: "MyAddress:1337".to_owned().try_into().unwrap(),
+            }),
+            relays_health: vec![
+                RelayHealthReport {
+                    relay: RelayAddress {
+                        public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+                        address: "MyAddress:1337".to_owned().try_into().unwrap(),
+                    },
+                    successes: 4,
+                    failures: 1,
+                    consecutive_failures: 0,
+                    opt_last_latency_ms: Some(120),
+                },
+                RelayHealthReport {
+                    relay: RelayAddress {
+                        public_key: PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]),
+                        address: "MyAddress:1338".to_owned().try_into().unwrap(),
+                    },
+                    successes: 0,
+                    failures: 3,
+                    consecutive_failures: 3,
+                    opt_last_latency_ms: None,
+                },
+            ],
+        };
+        mutations.push(NodeReportMutation::Connections(
+            ConnectionsReportMutation::SetFriend((
+                PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+                friend_connection_report,
+            )),
+        ));
+        mutations.push(NodeReportMutation::Connections(
+            ConnectionsReportMutation::RemoveFriend(PublicKey::from(&[0xee; PUBLIC_KEY_LEN])),
+        ));
         let report_mutations = ReportMutations {
             opt_app_request_id: Some(Uid::from(&[0; UID_LEN])),
             mutations,
@@ -769,6 +996,67 @@ mod tests {
         assert_eq!(app_server_to_app, app_server_to_app2);
     }
 
+    #[test]
+    fn test_serialize_app_server_to_app_response_routes() {
+        let routes = vec![RouteWithCapacity {
+            route: FriendsRoute {
+                public_keys: vec![
+                    PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+                    PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+                ],
+            },
+            capacity: 100,
+        }];
+        let routes_signature = RoutesSignature {
+            server_public_key: PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]),
+            time_hash: HashResult::from(&[0xdd; HASH_RESULT_LEN]),
+            signature: Signature::from(&[0xee; SIGNATURE_LEN]),
+        };
+
+        for opt_routes_signature in vec![Some(routes_signature), None] {
+            let client_response_routes = ClientResponseRoutes {
+                request_id: Uid::from(&[1; UID_LEN]),
+                result: ResponseRoutesResult::Success(routes.clone()),
+                opt_routes_signature,
+            };
+            let app_server_to_app = AppServerToApp::ResponseRoutes(client_response_routes);
+
+            let data = serialize_app_server_to_app(&app_server_to_app);
+            let app_server_to_app2 = deserialize_app_server_to_app(&data).unwrap();
+            assert_eq!(app_server_to_app, app_server_to_app2);
+        }
+    }
+
+    #[test]
+    fn test_serialize_app_server_to_app_response_reachability() {
+        let nodes = vec![
+            NodeWithCapacity {
+                public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+                capacity: 200,
+            },
+            NodeWithCapacity {
+                public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+                capacity: 100,
+            },
+        ];
+
+        for result in vec![
+            ResponseReachabilityResult::Success(nodes),
+            ResponseReachabilityResult::Failure,
+        ] {
+            let client_response_reachability = ClientResponseReachability {
+                request_id: Uid::from(&[1; UID_LEN]),
+                result,
+            };
+            let app_server_to_app =
+                AppServerToApp::ResponseReachability(client_response_reachability);
+
+            let data = serialize_app_server_to_app(&app_server_to_app);
+            let app_server_to_app2 = deserialize_app_server_to_app(&data).unwrap();
+            assert_eq!(app_server_to_app, app_server_to_app2);
+        }
+    }
+
     #[test]
     fn test_serialize_app_to_app_server() {
         let mut relays = Vec::new();
@@ -795,6 +1083,43 @@ mod tests {
         let data = serialize_app_to_app_server(&app_to_app_server);
         let app_to_app_server2 = deserialize_app_to_app_server(&data).unwrap();
         assert_eq!(app_to_app_server, app_to_app_server2);
+
+        for opt_capacity_bucket in vec![Some(1000), None] {
+            let app_to_app_server = AppToAppServer {
+                app_request_id: Uid::from(&[2; UID_LEN]),
+                app_request: AppRequest::SetCapacityBucket(opt_capacity_bucket),
+            };
+
+            let data = serialize_app_to_app_server(&app_to_app_server);
+            let app_to_app_server2 = deserialize_app_to_app_server(&data).unwrap();
+            assert_eq!(app_to_app_server, app_to_app_server2);
+        }
+
+        for opt_max_nodes in vec![Some(10), None] {
+            let request_reachability = RequestReachability {
+                request_id: Uid::from(&[3; UID_LEN]),
+                capacity: 50,
+                destination: PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]),
+                opt_max_nodes,
+            };
+            let app_to_app_server = AppToAppServer {
+                app_request_id: Uid::from(&[4; UID_LEN]),
+                app_request: AppRequest::RequestReachability(request_reachability),
+            };
+
+            let data = serialize_app_to_app_server(&app_to_app_server);
+            let app_to_app_server2 = deserialize_app_to_app_server(&data).unwrap();
+            assert_eq!(app_to_app_server, app_to_app_server2);
+        }
+
+        let app_to_app_server = AppToAppServer {
+            app_request_id: Uid::from(&[5; UID_LEN]),
+            app_request: AppRequest::EnableConnectionsReport,
+        };
+
+        let data = serialize_app_to_app_server(&app_to_app_server);
+        let app_to_app_server2 = deserialize_app_to_app_server(&data).unwrap();
+        assert_eq!(app_to_app_server, app_to_app_server2);
     }
 
     // TODO: More tests are required here
@@ -1,6 +1,16 @@
 /// The current protocol version
 pub const PROTOCOL_VERSION: u32 = 0;
 
+/// Protocol version declared by relay clients that multiplex many tunnels over a single
+/// connection to the relay. Relay servers accept both this version and `PROTOCOL_VERSION`.
+pub const RELAY_MUX_PROTOCOL_VERSION: u32 = 1;
+
+/// Protocol versions with this flag set use the hybrid (post-quantum) secure channel handshake.
+/// For example, `RELAY_MUX_PROTOCOL_VERSION | HYBRID_VERSION_FLAG`.
+/// Servers that do not know this flag reject such versions, allowing clients to fall back to the
+/// classic handshake.
+pub const HYBRID_VERSION_FLAG: u32 = 1 << 16;
+
 /// Maximum amount of friend operations sent in one move token message.
 pub const MAX_OPERATIONS_IN_BATCH: usize = 16;
 
@@ -13,6 +23,12 @@ pub const TICK_MS: usize = 1000;
 /// Amount of ticks to wait before rekeying a secure channel.
 pub const TICKS_TO_REKEY: usize = 60 * 60 * (1000 / TICK_MS); // 1 hour
 
+/// Amount of bytes to encrypt using the same key before rekeying a secure channel.
+pub const BYTES_TO_REKEY: u64 = 1 << 30; // 1[GB]
+
+/// Amount of messages to encrypt using the same key before rekeying a secure channel.
+pub const MESSAGES_TO_REKEY: u64 = 1 << 20;
+
 /// If no message was sent for this amount of ticks, the connection will be closed
 pub const KEEPALIVE_TICKS: usize = 0x20;
 
@@ -20,6 +36,10 @@ pub const KEEPALIVE_TICKS: usize = 0x20;
 /// sends identification of which type of connection it is.
 pub const CONN_TIMEOUT_TICKS: usize = 4;
 
+/// Multiplexed relay connections: The amount of messages a side may send through a tunnel before
+/// receiving more credit from the remote side.
+pub const RELAY_MUX_WINDOW: usize = 0x10;
+
 /// The stream TCP connection is split into prefix length frames. This is the maximum allowed
 /// length for such frame, measured in bytes.
 pub const MAX_FRAME_LENGTH: usize = 1 << 20; // 1[MB]
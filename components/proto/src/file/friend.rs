@@ -1,7 +1,7 @@
 use std::convert::TryInto;
 use std::fs::{self, File};
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use toml;
 
@@ -102,6 +102,35 @@ pub fn store_friend_to_file(
     Ok(())
 }
 
+#[derive(Debug)]
+pub enum FriendDirectoryError {
+    IoError(io::Error),
+    InvalidDirectory(io::Error),
+    InvalidFile(PathBuf, FriendFileError),
+}
+
+impl From<io::Error> for FriendDirectoryError {
+    fn from(e: io::Error) -> Self {
+        FriendDirectoryError::IoError(e)
+    }
+}
+
+/// Load a directory of friend address files (Also known as friend tickets)
+pub fn load_friends(dir_path: &Path) -> Result<Vec<FriendAddress>, FriendDirectoryError> {
+    let mut res_friends = Vec::new();
+    for entry in fs::read_dir(dir_path).map_err(FriendDirectoryError::InvalidDirectory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            continue;
+        }
+        res_friends.push(
+            load_friend_from_file(&path).map_err(|e| FriendDirectoryError::InvalidFile(path, e))?,
+        );
+    }
+    Ok(res_friends)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,4 +200,26 @@ mod tests {
 
         assert_eq!(friend_address, friend_address2);
     }
+
+    #[test]
+    fn test_load_friends() {
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+
+        let friend_address0 = FriendAddress {
+            public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            relays: Vec::new(),
+        };
+        let friend_address1 = FriendAddress {
+            public_key: PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]),
+            relays: Vec::new(),
+        };
+
+        store_friend_to_file(&friend_address0, &dir.path().join("friend0")).unwrap();
+        store_friend_to_file(&friend_address1, &dir.path().join("friend1")).unwrap();
+
+        let mut friends = load_friends(dir.path()).unwrap();
+        friends.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+        assert_eq!(friends, vec![friend_address0, friend_address1]);
+    }
 }
@@ -1,12 +1,20 @@
-use std::fs::{self, File};
+use std::fs::{self, OpenOptions};
 use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 
+use base64::{self, URL_SAFE_NO_PAD};
 use toml;
 
+use crypto::crypto_rand::CryptoRandom;
 use crypto::identity::{Identity, SoftwareEd25519Identity};
+use crypto::passphrase::{passphrase_decrypt, passphrase_encrypt, KdfParams, PassphraseSalt};
 
-use crate::file::ser_string::{private_key_to_string, string_to_private_key, SerStringError};
+use crate::file::ser_string::{
+    passphrase_salt_to_string, private_key_to_string, string_to_passphrase_salt,
+    string_to_private_key, SerStringError,
+};
 use crate::net::messages::NetAddressError;
 
 #[derive(Debug, From)]
@@ -19,6 +27,15 @@ pub enum IdentityFileError {
     InvalidPublicKey,
     NetAddressError(NetAddressError),
     Pkcs8ParseError,
+    EncryptError,
+    /// Wrong passphrase, or a corrupt encrypted identity file
+    DecryptError,
+    /// The identity file is encrypted, and no passphrase was given
+    PassphraseRequired,
+    /// The key derivation parameters of an encrypted identity file are out of bounds
+    InvalidKdfParams,
+    /// Identity files are never overwritten
+    FileAlreadyExists,
 }
 
 /// A helper structure for serialize and deserializing IdentityAddress.
@@ -27,22 +44,124 @@ pub struct IdentityFile {
     pub private_key: String,
 }
 
+/// A helper structure for serializing and deserializing an identity file protected by a
+/// passphrase.
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedIdentityFile {
+    pub encrypted_private_key: String,
+    pub salt: String,
+    pub kdf_params: KdfParams,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum AnyIdentityFile {
+    Plain(IdentityFile),
+    Encrypted(EncryptedIdentityFile),
+}
+
 impl From<SerStringError> for IdentityFileError {
     fn from(_e: SerStringError) -> Self {
         IdentityFileError::SerStringError
     }
 }
 
-/// Load Identity from a file
-pub fn load_raw_identity_from_file(path: &Path) -> Result<[u8; 85], IdentityFileError> {
+fn read_identity_file(path: &Path) -> Result<AnyIdentityFile, IdentityFileError> {
     let data = fs::read_to_string(&path)?;
-    let identity_file: IdentityFile = toml::from_str(&data)?;
+    Ok(toml::from_str(&data)?)
+}
+
+fn write_identity_file(
+    any_identity_file: &AnyIdentityFile,
+    path: &Path,
+) -> Result<(), IdentityFileError> {
+    let data = toml::to_string(any_identity_file)?;
 
-    // Decode public key:
-    let private_key = string_to_private_key(&identity_file.private_key)?;
+    // Only the owner may read the private key.
+    // An existing file is never replaced, as it might contain the only copy of another identity:
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).create_new(true);
+    #[cfg(unix)]
+    open_options.mode(0o600);
+
+    let mut file = open_options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => IdentityFileError::FileAlreadyExists,
+        _ => IdentityFileError::IoError(e),
+    })?;
+    file.write_all(&data.as_bytes())?;
+
+    Ok(())
+}
+
+/// Check if an identity file is protected by a passphrase
+pub fn is_identity_file_encrypted(path: &Path) -> Result<bool, IdentityFileError> {
+    Ok(match read_identity_file(path)? {
+        AnyIdentityFile::Plain(_) => false,
+        AnyIdentityFile::Encrypted(_) => true,
+    })
+}
+
+/// Load Identity from a file, using the given passphrase if the file is encrypted.
+/// The passphrase is ignored if the file is not encrypted.
+pub fn load_raw_identity_from_file_with_passphrase(
+    path: &Path,
+    passphrase: &str,
+) -> Result<[u8; 85], IdentityFileError> {
+    match read_identity_file(path)? {
+        AnyIdentityFile::Plain(identity_file) => {
+            Ok(string_to_private_key(&identity_file.private_key)?)
+        }
+        AnyIdentityFile::Encrypted(encrypted_identity_file) => {
+            decrypt_identity_file(&encrypted_identity_file, passphrase)
+        }
+    }
+}
+
+fn decrypt_identity_file(
+    encrypted_identity_file: &EncryptedIdentityFile,
+    passphrase: &str,
+) -> Result<[u8; 85], IdentityFileError> {
+    // The parameters are read from the file, and could make key derivation take arbitrary
+    // amounts of memory and time:
+    if !encrypted_identity_file.kdf_params.is_bounded() {
+        return Err(IdentityFileError::InvalidKdfParams);
+    }
+    let salt = string_to_passphrase_salt(&encrypted_identity_file.salt)?;
+    let cipher_msg = base64::decode_config(
+        &encrypted_identity_file.encrypted_private_key,
+        URL_SAFE_NO_PAD,
+    )
+    .map_err(|_| IdentityFileError::SerStringError)?;
+
+    let private_key_vec = passphrase_decrypt(
+        passphrase.as_bytes(),
+        &salt,
+        &encrypted_identity_file.kdf_params,
+        &cipher_msg,
+    )
+    .map_err(|_| IdentityFileError::DecryptError)?;
+
+    if private_key_vec.len() != 85 {
+        return Err(IdentityFileError::DecryptError);
+    }
+    let mut private_key = [0u8; 85];
+    private_key.copy_from_slice(&private_key_vec[0..85]);
     Ok(private_key)
 }
 
+/// Load Identity from a file
+/// Fails with `PassphraseRequired` if the file is encrypted.
+pub fn load_raw_identity_from_file(path: &Path) -> Result<[u8; 85], IdentityFileError> {
+    match read_identity_file(path)? {
+        AnyIdentityFile::Plain(identity_file) => {
+            // Decode public key:
+            let private_key = string_to_private_key(&identity_file.private_key)?;
+            Ok(private_key)
+        }
+        AnyIdentityFile::Encrypted(_) => Err(IdentityFileError::PassphraseRequired),
+    }
+}
+
 /// Store Identity to file
 pub fn store_raw_identity_to_file(
     identity: &[u8; 85],
@@ -51,13 +170,31 @@ pub fn store_raw_identity_to_file(
     let identity_file = IdentityFile {
         private_key: private_key_to_string(&identity),
     };
+    write_identity_file(&AnyIdentityFile::Plain(identity_file), path)
+}
 
-    let data = toml::to_string(&identity_file)?;
-
-    let mut file = File::create(path)?;
-    file.write_all(&data.as_bytes())?;
+/// Store Identity to file, encrypted with a key derived from `passphrase`.
+/// A new random salt is generated on every call.
+pub fn store_encrypted_raw_identity_to_file<R>(
+    identity: &[u8; 85],
+    passphrase: &str,
+    kdf_params: &KdfParams,
+    rng: &R,
+    path: &Path,
+) -> Result<(), IdentityFileError>
+where
+    R: CryptoRandom,
+{
+    let salt = PassphraseSalt::new(rng);
+    let cipher_msg = passphrase_encrypt(passphrase.as_bytes(), &salt, kdf_params, &identity[..])
+        .map_err(|_| IdentityFileError::EncryptError)?;
 
-    Ok(())
+    let encrypted_identity_file = EncryptedIdentityFile {
+        encrypted_private_key: base64::encode_config(&cipher_msg, URL_SAFE_NO_PAD),
+        salt: passphrase_salt_to_string(&salt),
+        kdf_params: kdf_params.clone(),
+    };
+    write_identity_file(&AnyIdentityFile::Encrypted(encrypted_identity_file), path)
 }
 
 /// Load an identity from a file
@@ -68,9 +205,20 @@ pub fn load_identity_from_file(path: &Path) -> Result<impl Identity, IdentityFil
         .map_err(|_| IdentityFileError::Pkcs8ParseError)
 }
 
+/// Load an identity from a file, using the given passphrase if the file is encrypted.
+pub fn load_identity_from_file_with_passphrase(
+    path: &Path,
+    passphrase: &str,
+) -> Result<impl Identity, IdentityFileError> {
+    let raw_identity = load_raw_identity_from_file_with_passphrase(path, passphrase)?;
+    SoftwareEd25519Identity::from_pkcs8(&raw_identity)
+        .map_err(|_| IdentityFileError::Pkcs8ParseError)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crypto::test_utils::DummyRandom;
     use tempfile::tempdir;
 
     #[test]
@@ -94,9 +242,121 @@ mod tests {
         let identity = [33u8; 85];
 
         store_raw_identity_to_file(&identity, &file_path).unwrap();
+        assert!(!is_identity_file_encrypted(&file_path).unwrap());
         let identity2 = load_raw_identity_from_file(&file_path).unwrap();
 
         // We convert to vec here because [u8; 85] doesn't implement PartialEq
         assert_eq!(identity.to_vec(), identity2.to_vec());
     }
+
+    #[test]
+    fn test_store_load_encrypted_identity() {
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        let identity = [33u8; 85];
+        let rng = DummyRandom::new(&[1u8]);
+        // Cheap parameters, to keep the test fast:
+        let kdf_params = KdfParams {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        };
+
+        store_encrypted_raw_identity_to_file(
+            &identity,
+            "passphrase",
+            &kdf_params,
+            &rng,
+            &file_path,
+        )
+        .unwrap();
+        assert!(is_identity_file_encrypted(&file_path).unwrap());
+
+        // The private key is not stored in plaintext:
+        let data = fs::read_to_string(&file_path).unwrap();
+        assert!(!data.contains(&private_key_to_string(&identity)));
+
+        let identity2 =
+            load_raw_identity_from_file_with_passphrase(&file_path, "passphrase").unwrap();
+        assert_eq!(identity.to_vec(), identity2.to_vec());
+
+        match load_raw_identity_from_file_with_passphrase(&file_path, "wrong") {
+            Err(IdentityFileError::DecryptError) => {}
+            _ => unreachable!(),
+        }
+
+        match load_raw_identity_from_file(&file_path) {
+            Err(IdentityFileError::PassphraseRequired) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_identity_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        store_raw_identity_to_file(&[33u8; 85], &file_path).unwrap();
+        let mode = fs::metadata(&file_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_identity_file_no_overwrite() {
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        store_raw_identity_to_file(&[33u8; 85], &file_path).unwrap();
+        match store_raw_identity_to_file(&[34u8; 85], &file_path) {
+            Err(IdentityFileError::FileAlreadyExists) => {}
+            _ => unreachable!(),
+        }
+        // The original identity is kept:
+        let raw_identity = load_raw_identity_from_file(&file_path).unwrap();
+        assert_eq!(&raw_identity[..], &[33u8; 85][..]);
+    }
+
+    #[test]
+    fn test_load_encrypted_identity_unbounded_kdf_params() {
+        // Create a temporary directory:
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("identity_file");
+
+        let rng = DummyRandom::new(&[1u8]);
+        let kdf_params = KdfParams {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        };
+        store_encrypted_raw_identity_to_file(
+            &[33u8; 85],
+            "passphrase",
+            &kdf_params,
+            &rng,
+            &file_path,
+        )
+        .unwrap();
+
+        // Tamper with the key derivation parameters:
+        let data = fs::read_to_string(&file_path).unwrap();
+        let mut encrypted_identity_file: EncryptedIdentityFile = toml::from_str(&data).unwrap();
+        encrypted_identity_file.kdf_params.log_n = 63;
+        fs::write(
+            &file_path,
+            toml::to_string(&encrypted_identity_file).unwrap(),
+        )
+        .unwrap();
+
+        match load_raw_identity_from_file_with_passphrase(&file_path, "passphrase") {
+            Err(IdentityFileError::InvalidKdfParams) => {}
+            _ => unreachable!(),
+        }
+    }
 }
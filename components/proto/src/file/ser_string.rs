@@ -3,6 +3,7 @@ use crypto::crypto_rand::{RandValue, RAND_VALUE_LEN};
 use crypto::hash::{HashResult, HASH_RESULT_LEN};
 use crypto::identity::{PublicKey, Signature, PUBLIC_KEY_LEN, SIGNATURE_LEN};
 use crypto::invoice_id::{InvoiceId, INVOICE_ID_LEN};
+use crypto::passphrase::{PassphraseSalt, PASSPHRASE_SALT_LEN};
 
 #[derive(Debug)]
 pub struct SerStringError;
@@ -102,6 +103,22 @@ pub fn string_to_rand_value(rand_value_str: &str) -> Result<RandValue, SerString
     Ok(RandValue::from(&rand_value_array))
 }
 
+/// Convert a PassphraseSalt into a string
+pub fn passphrase_salt_to_string(salt: &PassphraseSalt) -> String {
+    base64::encode_config(&salt, URL_SAFE_NO_PAD)
+}
+
+/// Convert a string into a PassphraseSalt
+pub fn string_to_passphrase_salt(salt_str: &str) -> Result<PassphraseSalt, SerStringError> {
+    let salt_vec = base64::decode_config(salt_str, URL_SAFE_NO_PAD).map_err(|_| SerStringError)?;
+    if salt_vec.len() != PASSPHRASE_SALT_LEN {
+        return Err(SerStringError);
+    }
+    let mut salt_array = [0u8; PASSPHRASE_SALT_LEN];
+    salt_array.copy_from_slice(&salt_vec[0..PASSPHRASE_SALT_LEN]);
+    Ok(PassphraseSalt::from(&salt_array))
+}
+
 // TODO: Find a better way to represent private key.
 // We currently use [u8; 85] directly because of ring limitations.
 
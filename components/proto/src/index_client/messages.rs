@@ -1,10 +1,14 @@
 use std::collections::HashMap;
 
-use crypto::identity::PublicKey;
+use crypto::hash::HashResult;
+use crypto::identity::{verify_signature, PublicKey, Signature};
 use crypto::uid::Uid;
 
-pub use crate::index_server::messages::{IndexMutation, RequestRoutes, UpdateFriend};
+pub use crate::index_server::messages::{
+    IndexMutation, NodeWithCapacity, RequestReachability, RequestRoutes, UpdateFriend,
+};
 use crate::index_server::messages::{NamedIndexServerAddress, RouteWithCapacity};
+use crate::index_server::signature_buff::response_routes_signature_buff;
 
 #[derive(Debug, Clone)]
 pub struct IndexClientState {
@@ -38,6 +42,41 @@ pub enum IndexClientReportMutation<ISA> {
     SetConnectedServer(Option<PublicKey>),
 }
 
+/// Signature of an index server over the routes it returned for a routes request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutesSignature {
+    /// The index server that returned the routes
+    pub server_public_key: PublicKey,
+    /// The last time hash sent by the index server before creating the response
+    pub time_hash: HashResult,
+    /// Signature over the query and the returned routes (See `ResponseRoutes`)
+    pub signature: Signature,
+}
+
+impl RoutesSignature {
+    /// Verify the signature of the index server over `routes`, returned for `request_routes`.
+    pub fn verify(&self, request_routes: &RequestRoutes, routes: &[RouteWithCapacity]) -> bool {
+        let signature_buff =
+            response_routes_signature_buff(request_routes, routes, &self.time_hash);
+        verify_signature(&signature_buff, &self.server_public_key, &self.signature)
+    }
+}
+
+/// Routes returned by an index server, together with the signature of the server.
+/// Can be used to prove which routes were advertised by the index server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedRoutes {
+    pub routes: Vec<RouteWithCapacity>,
+    pub routes_signature: RoutesSignature,
+}
+
+impl SignedRoutes {
+    /// Verify the signature of the index server, for the routes request `request_routes`.
+    pub fn verify_signature(&self, request_routes: &RequestRoutes) -> bool {
+        self.routes_signature.verify(request_routes, &self.routes)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ResponseRoutesResult {
     Success(Vec<RouteWithCapacity>),
@@ -48,6 +87,22 @@ pub enum ResponseRoutesResult {
 pub struct ClientResponseRoutes {
     pub request_id: Uid,
     pub result: ResponseRoutesResult,
+    /// Signature of the index server over the returned routes.
+    /// Not available if the index server (or the node) does not support signed routes.
+    pub opt_routes_signature: Option<RoutesSignature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseReachabilityResult {
+    /// Ordered from the largest capacity to the smallest.
+    Success(Vec<NodeWithCapacity>),
+    Failure,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientResponseReachability {
+    pub request_id: Uid,
+    pub result: ResponseReachabilityResult,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -60,6 +115,7 @@ pub struct IndexClientReportMutations<ISA> {
 pub enum IndexClientToAppServer<ISA> {
     ReportMutations(IndexClientReportMutations<ISA>),
     ResponseRoutes(ClientResponseRoutes),
+    ResponseReachability(ClientResponseReachability),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -67,6 +123,8 @@ pub enum IndexClientRequest<ISA> {
     AddIndexServer(NamedIndexServerAddress<ISA>),
     RemoveIndexServer(PublicKey),
     RequestRoutes(RequestRoutes),
+    SetCapacityBucket(Option<u128>),
+    RequestReachability(RequestReachability),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -0,0 +1,95 @@
+use super::messages::IndexMutation;
+
+/// Round a capacity down to a multiple of `bucket`.
+/// A bucket of size 0 leaves the capacity unchanged.
+pub fn bucket_floor(capacity: u128, bucket: u128) -> u128 {
+    match capacity.checked_rem(bucket) {
+        Some(remainder) => capacity - remainder,
+        None => capacity,
+    }
+}
+
+/// Round a capacity up to a multiple of `bucket`.
+/// Saturates to the largest multiple of `bucket` if rounding up overflows.
+/// A bucket of size 0 leaves the capacity unchanged.
+pub fn bucket_ceil(capacity: u128, bucket: u128) -> u128 {
+    let floor = bucket_floor(capacity, bucket);
+    if floor == capacity {
+        return capacity;
+    }
+    floor
+        .checked_add(bucket)
+        .unwrap_or_else(|| bucket_floor(u128::max_value(), bucket))
+}
+
+/// Round down the capacities of UpdateFriend mutations, if privacy mode is enabled.
+pub fn bucket_mutation(
+    mutation: IndexMutation,
+    opt_capacity_bucket: Option<u128>,
+) -> IndexMutation {
+    let capacity_bucket = match opt_capacity_bucket {
+        Some(capacity_bucket) => capacity_bucket,
+        None => return mutation,
+    };
+
+    match mutation {
+        IndexMutation::UpdateFriend(mut update_friend) => {
+            update_friend.send_capacity =
+                bucket_floor(update_friend.send_capacity, capacity_bucket);
+            update_friend.recv_capacity =
+                bucket_floor(update_friend.recv_capacity, capacity_bucket);
+            IndexMutation::UpdateFriend(update_friend)
+        }
+        IndexMutation::RemoveFriend(public_key) => IndexMutation::RemoveFriend(public_key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index_server::messages::UpdateFriend;
+    use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
+
+    #[test]
+    fn test_bucket_floor() {
+        assert_eq!(bucket_floor(0, 100), 0);
+        assert_eq!(bucket_floor(99, 100), 0);
+        assert_eq!(bucket_floor(100, 100), 100);
+        assert_eq!(bucket_floor(250, 100), 200);
+        assert_eq!(bucket_floor(250, 0), 250);
+        assert_eq!(bucket_floor(250, 1), 250);
+    }
+
+    #[test]
+    fn test_bucket_ceil() {
+        assert_eq!(bucket_ceil(0, 100), 0);
+        assert_eq!(bucket_ceil(1, 100), 100);
+        assert_eq!(bucket_ceil(100, 100), 100);
+        assert_eq!(bucket_ceil(250, 100), 300);
+        assert_eq!(bucket_ceil(250, 0), 250);
+        assert_eq!(
+            bucket_ceil(u128::max_value() - 1, 1 << 64),
+            bucket_floor(u128::max_value(), 1 << 64)
+        );
+    }
+
+    #[test]
+    fn test_bucket_mutation() {
+        let update_friend = UpdateFriend {
+            public_key: PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]),
+            send_capacity: 250,
+            recv_capacity: 99,
+        };
+        let mutation = IndexMutation::UpdateFriend(update_friend.clone());
+        assert_eq!(bucket_mutation(mutation.clone(), None), mutation);
+
+        match bucket_mutation(mutation, Some(100)) {
+            IndexMutation::UpdateFriend(bucketed) => {
+                assert_eq!(bucketed.public_key, update_friend.public_key);
+                assert_eq!(bucketed.send_capacity, 200);
+                assert_eq!(bucketed.recv_capacity, 0);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
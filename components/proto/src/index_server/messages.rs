@@ -26,11 +26,57 @@ pub struct RouteWithCapacity {
     pub capacity: u128,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseRoutesSignature {
+    /// The last time hash sent by the server.
+    /// This proves that the response was created after this time hash was published.
+    pub time_hash: HashResult,
+    /// signature(sha_512_256("RESPONSE_ROUTES") ||
+    ///           requestId ||
+    ///           capacity ||
+    ///           source ||
+    ///           destination ||
+    ///           optExclude ||
+    ///           routes ||
+    ///           timeHash)
+    /// Signed by the index server.
+    pub signature: Signature,
+}
+
 /// IndexServer -> IndexClient
 #[derive(Debug, Clone)]
 pub struct ResponseRoutes {
     pub request_id: Uid,
     pub routes: Vec<RouteWithCapacity>,
+    /// Older index servers do not sign their responses
+    pub opt_signature: Option<ResponseRoutesSignature>,
+}
+
+/// IndexClient -> IndexServer
+/// Find all the nodes that can send at least a certain capacity to a destination node.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RequestReachability {
+    pub request_id: Uid,
+    /// Minimal capacity the nodes should be able to send to the destination.
+    pub capacity: u128,
+    pub destination: PublicKey,
+    /// Return only the top nodes (By maximum capacity).
+    pub opt_max_nodes: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NodeWithCapacity {
+    pub public_key: PublicKey,
+    /// Maximum capacity the node can send to the destination.
+    pub capacity: u128,
+}
+
+/// IndexServer -> IndexClient
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ResponseReachability {
+    pub request_id: Uid,
+    /// Ordered from the largest capacity to the smallest.
+    pub nodes: Vec<NodeWithCapacity>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -94,22 +140,62 @@ pub struct ForwardMutationsUpdate {
     pub time_proof_chain: Vec<TimeProofLink>,
 }
 
+/// Mutations applied to the graph of an index server, done by a single node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeMutations {
+    /// Public key of the node that did the mutations.
+    pub node_public_key: PublicKey,
+    /// Mutations to relationships with direct friends.
+    pub index_mutations: Vec<IndexMutation>,
+}
+
 #[derive(Debug)]
 pub enum IndexServerToClient {
     TimeHash(HashResult),
     ResponseRoutes(ResponseRoutes),
+    /// Full state of the graph. Sent after a subscription request.
+    /// The outgoing edges of every node are given as `UpdateFriend` mutations.
+    GraphSnapshot(Vec<NodeMutations>),
+    /// A verified change to the graph. Sent to subscribed clients.
+    GraphUpdate(NodeMutations),
+    ResponseReachability(ResponseReachability),
 }
 
 #[derive(Debug)]
 pub enum IndexClientToServer {
     MutationsUpdate(MutationsUpdate),
     RequestRoutes(RequestRoutes),
+    /// Subscribe to changes of the graph.
+    Subscribe,
+    RequestReachability(RequestReachability),
+}
+
+/// Address of an index server, vouched for by another index server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedIndexServerAddress<ISA = NetAddress> {
+    pub public_key: PublicKey,
+    pub address: ISA,
+    /// Sequence number of the peer list this address was sent in.
+    /// Increases with every peer list sent by the vouching server. Older lists are ignored.
+    pub seq: u64,
+    /// signature(sha_512_256("INDEX_SERVER_ADDRESS") ||
+    ///           publicKey ||
+    ///           address ||
+    ///           seq)
+    /// Signed by the vouching index server.
+    pub signature: Signature,
 }
 
 #[derive(Debug)]
-pub enum IndexServerToServer {
+pub enum IndexServerToServer<ISA = NetAddress> {
     TimeHash(HashResult),
     ForwardMutationsUpdate(ForwardMutationsUpdate),
+    /// A node was banned due to misbehaviour.
+    /// The receiving server should also ban this node.
+    BanNode(PublicKey),
+    /// The servers trusted by the sending server.
+    /// Replaces any previously sent list.
+    PeerList(Vec<SignedIndexServerAddress<ISA>>),
 }
 
 // ----------------------------------------------
@@ -1,3 +1,4 @@
+pub mod capacity_bucket;
 pub mod messages;
 pub mod serialize;
 pub mod signature_buff;
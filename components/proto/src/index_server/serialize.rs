@@ -2,17 +2,18 @@ use capnp::serialize_packed;
 use std::io;
 
 use crate::capnp_common::{
-    read_custom_u_int128, read_hash, read_public_key, read_rand_nonce, read_signature, read_uid,
-    write_custom_u_int128, write_hash, write_public_key, write_rand_nonce, write_signature,
-    write_uid,
+    read_custom_u_int128, read_hash, read_net_address, read_public_key, read_rand_nonce,
+    read_signature, read_uid, write_custom_u_int128, write_hash, write_net_address,
+    write_public_key, write_rand_nonce, write_signature, write_uid,
 };
 use common::int_convert::usize_to_u32;
 use index_capnp;
 
 use super::messages::{
     ForwardMutationsUpdate, IndexClientToServer, IndexMutation, IndexServerToClient,
-    IndexServerToServer, MutationsUpdate, RequestRoutes, ResponseRoutes, RouteWithCapacity,
-    TimeProofLink, UpdateFriend,
+    IndexServerToServer, MutationsUpdate, NodeMutations, NodeWithCapacity, RequestReachability,
+    RequestRoutes, ResponseReachability, ResponseRoutes, ResponseRoutesSignature,
+    RouteWithCapacity, SignedIndexServerAddress, TimeProofLink, UpdateFriend,
 };
 
 use crate::funder::serialize::{deser_friends_route, ser_friends_route};
@@ -120,6 +121,22 @@ fn ser_response_routes(
             routes_builder.reborrow().get(usize_to_u32(index).unwrap());
         ser_route_with_capacity(&route, &mut route_with_capacity_builder);
     }
+
+    let mut opt_signature_builder = response_routes_builder.reborrow().init_opt_signature();
+    match &response_routes.opt_signature {
+        Some(response_routes_signature) => {
+            let mut signature_builder = opt_signature_builder.init_signature();
+            write_hash(
+                &response_routes_signature.time_hash,
+                &mut signature_builder.reborrow().init_time_hash(),
+            );
+            write_signature(
+                &response_routes_signature.signature,
+                &mut signature_builder.reborrow().init_signature(),
+            );
+        }
+        None => opt_signature_builder.set_empty(()),
+    };
 }
 
 fn deser_response_routes(
@@ -130,9 +147,118 @@ fn deser_response_routes(
         routes.push(deser_route_with_capacity(&route_with_capacity)?);
     }
 
+    let opt_signature = match response_routes_reader.get_opt_signature().which()? {
+        index_capnp::response_routes::opt_signature::Empty(()) => None,
+        index_capnp::response_routes::opt_signature::Signature(signature_reader) => {
+            let signature_reader = signature_reader?;
+            Some(ResponseRoutesSignature {
+                time_hash: read_hash(&signature_reader.get_time_hash()?)?,
+                signature: read_signature(&signature_reader.get_signature()?)?,
+            })
+        }
+    };
+
     Ok(ResponseRoutes {
         request_id: read_uid(&response_routes_reader.get_request_id()?)?,
         routes,
+        opt_signature,
+    })
+}
+
+pub fn ser_request_reachability(
+    request_reachability: &RequestReachability,
+    request_reachability_builder: &mut index_capnp::request_reachability::Builder,
+) {
+    write_uid(
+        &request_reachability.request_id,
+        &mut request_reachability_builder.reborrow().init_request_id(),
+    );
+    write_custom_u_int128(
+        request_reachability.capacity,
+        &mut request_reachability_builder.reborrow().init_capacity(),
+    );
+    write_public_key(
+        &request_reachability.destination,
+        &mut request_reachability_builder.reborrow().init_destination(),
+    );
+
+    let mut opt_max_nodes_builder = request_reachability_builder.reborrow().init_opt_max_nodes();
+    match request_reachability.opt_max_nodes {
+        Some(max_nodes) => opt_max_nodes_builder.set_max_nodes(max_nodes),
+        None => opt_max_nodes_builder.set_empty(()),
+    }
+}
+
+pub fn deser_request_reachability(
+    request_reachability_reader: &index_capnp::request_reachability::Reader,
+) -> Result<RequestReachability, SerializeError> {
+    let opt_max_nodes = match request_reachability_reader.get_opt_max_nodes().which()? {
+        index_capnp::request_reachability::opt_max_nodes::MaxNodes(max_nodes) => Some(max_nodes),
+        index_capnp::request_reachability::opt_max_nodes::Empty(()) => None,
+    };
+
+    Ok(RequestReachability {
+        request_id: read_uid(&request_reachability_reader.get_request_id()?)?,
+        capacity: read_custom_u_int128(&request_reachability_reader.get_capacity()?)?,
+        destination: read_public_key(&request_reachability_reader.get_destination()?)?,
+        opt_max_nodes,
+    })
+}
+
+pub fn ser_node_with_capacity(
+    node_with_capacity: &NodeWithCapacity,
+    node_with_capacity_builder: &mut index_capnp::node_with_capacity::Builder,
+) {
+    write_public_key(
+        &node_with_capacity.public_key,
+        &mut node_with_capacity_builder.reborrow().init_public_key(),
+    );
+    write_custom_u_int128(
+        node_with_capacity.capacity,
+        &mut node_with_capacity_builder.reborrow().init_capacity(),
+    );
+}
+
+pub fn deser_node_with_capacity(
+    node_with_capacity_reader: &index_capnp::node_with_capacity::Reader,
+) -> Result<NodeWithCapacity, SerializeError> {
+    Ok(NodeWithCapacity {
+        public_key: read_public_key(&node_with_capacity_reader.get_public_key()?)?,
+        capacity: read_custom_u_int128(&node_with_capacity_reader.get_capacity()?)?,
+    })
+}
+
+fn ser_response_reachability(
+    response_reachability: &ResponseReachability,
+    response_reachability_builder: &mut index_capnp::response_reachability::Builder,
+) {
+    write_uid(
+        &response_reachability.request_id,
+        &mut response_reachability_builder.reborrow().init_request_id(),
+    );
+    let nodes_len = usize_to_u32(response_reachability.nodes.len()).unwrap();
+    let mut nodes_builder = response_reachability_builder
+        .reborrow()
+        .init_nodes(nodes_len);
+
+    for (index, node_with_capacity) in response_reachability.nodes.iter().enumerate() {
+        let mut node_with_capacity_builder =
+            nodes_builder.reborrow().get(usize_to_u32(index).unwrap());
+        ser_node_with_capacity(node_with_capacity, &mut node_with_capacity_builder);
+    }
+}
+
+fn deser_response_reachability(
+    response_reachability_reader: &index_capnp::response_reachability::Reader,
+) -> Result<ResponseReachability, SerializeError> {
+    let mut nodes = Vec::new();
+    for node_with_capacity_reader in response_reachability_reader.get_nodes()? {
+        nodes.push(deser_node_with_capacity(&node_with_capacity_reader)?);
+    }
+
+    Ok(ResponseReachability {
+        request_id: read_uid(&response_reachability_reader.get_request_id()?)?,
+        nodes,
     })
 }
 
@@ -323,6 +449,42 @@ fn deser_forward_mutations_update(
     })
 }
 
+fn ser_node_mutations(
+    node_mutations: &NodeMutations,
+    node_mutations_builder: &mut index_capnp::node_mutations::Builder,
+) {
+    write_public_key(
+        &node_mutations.node_public_key,
+        &mut node_mutations_builder.reborrow().init_node_public_key(),
+    );
+
+    let mutations_len = usize_to_u32(node_mutations.index_mutations.len()).unwrap();
+    let mut mutations_builder = node_mutations_builder
+        .reborrow()
+        .init_index_mutations(mutations_len);
+
+    for (index, index_mutation) in node_mutations.index_mutations.iter().enumerate() {
+        let mut index_mutation_builder = mutations_builder
+            .reborrow()
+            .get(usize_to_u32(index).unwrap());
+        ser_index_mutation(index_mutation, &mut index_mutation_builder);
+    }
+}
+
+fn deser_node_mutations(
+    node_mutations_reader: &index_capnp::node_mutations::Reader,
+) -> Result<NodeMutations, SerializeError> {
+    let mut index_mutations = Vec::new();
+    for index_mutation_reader in node_mutations_reader.get_index_mutations()? {
+        index_mutations.push(deser_index_mutation(&index_mutation_reader)?);
+    }
+
+    Ok(NodeMutations {
+        node_public_key: read_public_key(&node_mutations_reader.get_node_public_key()?)?,
+        index_mutations,
+    })
+}
+
 fn ser_index_server_to_client(
     index_server_to_client: &IndexServerToClient,
     index_server_to_client_builder: &mut index_capnp::index_server_to_client::Builder,
@@ -338,6 +500,31 @@ fn ser_index_server_to_client(
                 .init_response_routes();
             ser_response_routes(response_routes, &mut response_routes_builder);
         }
+        IndexServerToClient::GraphSnapshot(nodes_mutations) => {
+            let nodes_len = usize_to_u32(nodes_mutations.len()).unwrap();
+            let mut graph_snapshot_builder = index_server_to_client_builder
+                .reborrow()
+                .init_graph_snapshot(nodes_len);
+
+            for (index, node_mutations) in nodes_mutations.iter().enumerate() {
+                let mut node_mutations_builder = graph_snapshot_builder
+                    .reborrow()
+                    .get(usize_to_u32(index).unwrap());
+                ser_node_mutations(node_mutations, &mut node_mutations_builder);
+            }
+        }
+        IndexServerToClient::GraphUpdate(node_mutations) => {
+            let mut node_mutations_builder = index_server_to_client_builder
+                .reborrow()
+                .init_graph_update();
+            ser_node_mutations(node_mutations, &mut node_mutations_builder);
+        }
+        IndexServerToClient::ResponseReachability(response_reachability) => {
+            let mut response_reachability_builder = index_server_to_client_builder
+                .reborrow()
+                .init_response_reachability();
+            ser_response_reachability(response_reachability, &mut response_reachability_builder);
+        }
     }
 }
 
@@ -351,6 +538,21 @@ fn deser_index_server_to_client(
         index_capnp::index_server_to_client::ResponseRoutes(response_routes_reader) => {
             IndexServerToClient::ResponseRoutes(deser_response_routes(&response_routes_reader?)?)
         }
+        index_capnp::index_server_to_client::GraphSnapshot(graph_snapshot_reader) => {
+            let mut nodes_mutations = Vec::new();
+            for node_mutations_reader in graph_snapshot_reader? {
+                nodes_mutations.push(deser_node_mutations(&node_mutations_reader)?);
+            }
+            IndexServerToClient::GraphSnapshot(nodes_mutations)
+        }
+        index_capnp::index_server_to_client::GraphUpdate(node_mutations_reader) => {
+            IndexServerToClient::GraphUpdate(deser_node_mutations(&node_mutations_reader?)?)
+        }
+        index_capnp::index_server_to_client::ResponseReachability(response_reachability_reader) => {
+            IndexServerToClient::ResponseReachability(deser_response_reachability(
+                &response_reachability_reader?,
+            )?)
+        }
     })
 }
 
@@ -371,6 +573,13 @@ fn ser_index_client_to_server(
                 .init_request_routes();
             ser_request_routes(request_routes, &mut request_routes_builder);
         }
+        IndexClientToServer::Subscribe => index_client_to_server_builder.set_subscribe(()),
+        IndexClientToServer::RequestReachability(request_reachability) => {
+            let mut request_reachability_builder = index_client_to_server_builder
+                .reborrow()
+                .init_request_reachability();
+            ser_request_reachability(request_reachability, &mut request_reachability_builder);
+        }
     }
 }
 
@@ -384,6 +593,48 @@ fn deser_index_client_to_server(
         index_capnp::index_client_to_server::RequestRoutes(request_routes_reader) => {
             IndexClientToServer::RequestRoutes(deser_request_routes(&request_routes_reader?)?)
         }
+        index_capnp::index_client_to_server::Subscribe(()) => IndexClientToServer::Subscribe,
+        index_capnp::index_client_to_server::RequestReachability(request_reachability_reader) => {
+            IndexClientToServer::RequestReachability(deser_request_reachability(
+                &request_reachability_reader?,
+            )?)
+        }
+    })
+}
+
+fn ser_signed_index_server_address(
+    signed_index_server_address: &SignedIndexServerAddress,
+    signed_index_server_address_builder: &mut index_capnp::signed_index_server_address::Builder,
+) {
+    write_public_key(
+        &signed_index_server_address.public_key,
+        &mut signed_index_server_address_builder
+            .reborrow()
+            .init_public_key(),
+    );
+    write_net_address(
+        &signed_index_server_address.address,
+        &mut signed_index_server_address_builder
+            .reborrow()
+            .init_address(),
+    );
+    signed_index_server_address_builder.set_seq(signed_index_server_address.seq);
+    write_signature(
+        &signed_index_server_address.signature,
+        &mut signed_index_server_address_builder
+            .reborrow()
+            .init_signature(),
+    );
+}
+
+fn deser_signed_index_server_address(
+    signed_index_server_address_reader: &index_capnp::signed_index_server_address::Reader,
+) -> Result<SignedIndexServerAddress, SerializeError> {
+    Ok(SignedIndexServerAddress {
+        public_key: read_public_key(&signed_index_server_address_reader.get_public_key()?)?,
+        address: read_net_address(&signed_index_server_address_reader.get_address()?)?,
+        seq: signed_index_server_address_reader.get_seq(),
+        signature: read_signature(&signed_index_server_address_reader.get_signature()?)?,
     })
 }
 
@@ -405,6 +656,26 @@ fn ser_index_server_to_server(
                 &mut forward_mutations_update_builder,
             );
         }
+        IndexServerToServer::BanNode(public_key) => {
+            let mut ban_node_builder = index_server_to_server_builder.reborrow().init_ban_node();
+            write_public_key(public_key, &mut ban_node_builder);
+        }
+        IndexServerToServer::PeerList(peer_list) => {
+            let peer_list_len = usize_to_u32(peer_list.len()).unwrap();
+            let mut peer_list_builder = index_server_to_server_builder
+                .reborrow()
+                .init_peer_list(peer_list_len);
+
+            for (index, signed_index_server_address) in peer_list.iter().enumerate() {
+                let mut signed_index_server_address_builder = peer_list_builder
+                    .reborrow()
+                    .get(usize_to_u32(index).unwrap());
+                ser_signed_index_server_address(
+                    signed_index_server_address,
+                    &mut signed_index_server_address_builder,
+                );
+            }
+        }
     }
 }
 
@@ -420,6 +691,18 @@ fn deser_index_server_to_server(
         ) => IndexServerToServer::ForwardMutationsUpdate(deser_forward_mutations_update(
             &forward_mutations_update_reader?,
         )?),
+        index_capnp::index_server_to_server::BanNode(ban_node_reader) => {
+            IndexServerToServer::BanNode(read_public_key(&ban_node_reader?)?)
+        }
+        index_capnp::index_server_to_server::PeerList(peer_list_reader) => {
+            let mut peer_list = Vec::new();
+            for signed_index_server_address_reader in peer_list_reader? {
+                peer_list.push(deser_signed_index_server_address(
+                    &signed_index_server_address_reader,
+                )?);
+            }
+            IndexServerToServer::PeerList(peer_list)
+        }
     })
 }
 
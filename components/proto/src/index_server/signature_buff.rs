@@ -1,10 +1,13 @@
 use byteorder::{BigEndian, WriteBytesExt};
 use common::canonical_serialize::CanonicalSerialize;
 use common::int_convert::usize_to_u64;
-use crypto::hash;
-use crypto::identity::verify_signature;
+use crypto::hash::{self, HashResult};
+use crypto::identity::{verify_signature, PublicKey};
 
-use super::messages::{IndexMutation, MutationsUpdate, UpdateFriend};
+use super::messages::{
+    IndexMutation, MutationsUpdate, RequestRoutes, ResponseRoutes, RouteWithCapacity,
+    SignedIndexServerAddress, UpdateFriend,
+};
 
 // Canonical Serialization (To be used for signatures):
 // ----------------------------------------------------
@@ -23,6 +26,15 @@ impl CanonicalSerialize for UpdateFriend {
     }
 }
 
+impl CanonicalSerialize for RouteWithCapacity {
+    fn canonical_serialize(&self) -> Vec<u8> {
+        let mut res_bytes = Vec::new();
+        res_bytes.extend(self.route.canonical_serialize());
+        res_bytes.write_u128::<BigEndian>(self.capacity).unwrap();
+        res_bytes
+    }
+}
+
 impl CanonicalSerialize for IndexMutation {
     fn canonical_serialize(&self) -> Vec<u8> {
         let mut res_bytes = Vec::new();
@@ -71,3 +83,93 @@ impl MutationsUpdate {
         verify_signature(&signature_buff, &self.node_public_key, &self.signature)
     }
 }
+
+pub const RESPONSE_ROUTES_PREFIX: &[u8] = b"RESPONSE_ROUTES";
+
+/// The buffer signed by an index server when responding to a routes request.
+/// The signature covers both the query and the returned routes.
+/// Exposed as a function so that the signature can be verified also after the routes were
+/// separated from the original `ResponseRoutes` message.
+pub fn response_routes_signature_buff(
+    request_routes: &RequestRoutes,
+    routes: &[RouteWithCapacity],
+    time_hash: &HashResult,
+) -> Vec<u8> {
+    let mut res_bytes = Vec::new();
+    res_bytes.extend_from_slice(&hash::sha_512_256(RESPONSE_ROUTES_PREFIX));
+    res_bytes.extend_from_slice(&request_routes.request_id);
+    res_bytes
+        .write_u128::<BigEndian>(request_routes.capacity)
+        .unwrap();
+    res_bytes.extend_from_slice(&request_routes.source);
+    res_bytes.extend_from_slice(&request_routes.destination);
+    match &request_routes.opt_exclude {
+        None => res_bytes.push(0),
+        Some((from_public_key, to_public_key)) => {
+            res_bytes.push(1);
+            res_bytes.extend_from_slice(from_public_key);
+            res_bytes.extend_from_slice(to_public_key);
+        }
+    }
+
+    res_bytes
+        .write_u64::<BigEndian>(usize_to_u64(routes.len()).unwrap())
+        .unwrap();
+    for route in routes {
+        res_bytes.extend(route.canonical_serialize());
+    }
+
+    res_bytes.extend_from_slice(time_hash);
+    res_bytes
+}
+
+impl ResponseRoutes {
+    /// Verify the signature of the index server over the ResponseRoutes structure, given the
+    /// query it answers.
+    /// Returns false if the response is not signed.
+    pub fn verify_signature(
+        &self,
+        request_routes: &RequestRoutes,
+        server_public_key: &PublicKey,
+    ) -> bool {
+        let response_routes_signature = match &self.opt_signature {
+            Some(response_routes_signature) => response_routes_signature,
+            None => return false,
+        };
+        if self.request_id != request_routes.request_id {
+            return false;
+        }
+        let signature_buff = response_routes_signature_buff(
+            request_routes,
+            &self.routes,
+            &response_routes_signature.time_hash,
+        );
+        verify_signature(
+            &signature_buff,
+            server_public_key,
+            &response_routes_signature.signature,
+        )
+    }
+}
+
+pub const INDEX_SERVER_ADDRESS_PREFIX: &[u8] = b"INDEX_SERVER_ADDRESS";
+
+impl<ISA> SignedIndexServerAddress<ISA>
+where
+    ISA: CanonicalSerialize,
+{
+    pub fn signature_buff(&self) -> Vec<u8> {
+        let mut res_bytes = Vec::new();
+        res_bytes.extend_from_slice(&hash::sha_512_256(INDEX_SERVER_ADDRESS_PREFIX));
+        res_bytes.extend_from_slice(&self.public_key);
+        res_bytes.extend(self.address.canonical_serialize());
+        res_bytes.write_u64::<BigEndian>(self.seq).unwrap();
+        res_bytes
+    }
+
+    /// Verify the signature of the vouching index server over the address.
+    pub fn verify_signature(&self, voucher_public_key: &PublicKey) -> bool {
+        let signature_buff = self.signature_buff();
+        verify_signature(&signature_buff, voucher_public_key, &self.signature)
+    }
+}
@@ -2,4 +2,8 @@
 pub enum KaMessage {
     KeepAlive,
     Message(Vec<u8>),
+    /// A keepalive that should be answered with a `Pong` carrying the same value.
+    Ping(u64),
+    /// A keepalive answering a `Ping`.
+    Pong(u64),
 }
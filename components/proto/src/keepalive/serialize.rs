@@ -13,6 +13,14 @@ pub fn serialize_ka_message(ka_message: &KaMessage) -> Vec<u8> {
     match ka_message {
         KaMessage::KeepAlive => msg.set_keep_alive(()),
         KaMessage::Message(message) => msg.set_message(message),
+        KaMessage::Ping(ping) => {
+            msg.set_keep_alive(());
+            msg.reborrow().init_probe().set_ping(*ping);
+        }
+        KaMessage::Pong(pong) => {
+            msg.set_keep_alive(());
+            msg.reborrow().init_probe().set_pong(*pong);
+        }
     };
 
     let mut serialized_msg = Vec::new();
@@ -27,7 +35,12 @@ pub fn deserialize_ka_message(data: &[u8]) -> Result<KaMessage, SerializeError>
     let msg = reader.get_root::<keepalive_capnp::ka_message::Reader>()?;
 
     match msg.which() {
-        Ok(keepalive_capnp::ka_message::KeepAlive(())) => Ok(KaMessage::KeepAlive),
+        Ok(keepalive_capnp::ka_message::KeepAlive(())) => match msg.get_probe().which() {
+            Ok(keepalive_capnp::ka_message::probe::Empty(())) => Ok(KaMessage::KeepAlive),
+            Ok(keepalive_capnp::ka_message::probe::Ping(ping)) => Ok(KaMessage::Ping(ping)),
+            Ok(keepalive_capnp::ka_message::probe::Pong(pong)) => Ok(KaMessage::Pong(pong)),
+            Err(e) => Err(SerializeError::NotInSchema(e)),
+        },
         Ok(keepalive_capnp::ka_message::Message(opt_message_reader)) => {
             Ok(KaMessage::Message(Vec::from(opt_message_reader?)))
         }
@@ -55,4 +68,13 @@ mod tests {
         assert_eq!(ka_message, ka_message2);
     }
 
+    #[test]
+    fn test_basic_serialize_ka_message_probes() {
+        for ka_message in &[KaMessage::Ping(0x1234), KaMessage::Pong(0x4321)] {
+            let ser_data = serialize_ka_message(ka_message);
+            let ka_message2 = deserialize_ka_message(&ser_data).unwrap();
+            assert_eq!(ka_message, &ka_message2);
+        }
+    }
+
 }
@@ -7,10 +7,48 @@ use common::canonical_serialize::CanonicalSerialize;
 #[display(fmt = "{}", _0)]
 pub struct NetAddress(String);
 
+/// The transport used to reach a `NetAddress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetScheme {
+    /// Length prefixed frames over TCP
+    Tcp,
+    /// Binary messages over WebSocket
+    Ws,
+}
+
 impl NetAddress {
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    /// Is this a direct address of a node, instead of the address of a relay?
+    /// Direct addresses are declared using the `direct` scheme (For example: `direct://host:port`).
+    pub fn is_direct(&self) -> bool {
+        self.0.starts_with("direct://")
+    }
+
+    /// Split the address into its scheme and its `host:port` part.
+    /// Addresses without a scheme (For example: `host:port`) are TCP addresses.
+    /// Direct addresses are reached using TCP.
+    /// A WebSocket address may contain a path (For example: `ws://host:port/path`).
+    pub fn split_scheme(&self) -> Result<(NetScheme, &str), NetAddressError> {
+        let (scheme, rest) = match self.0.find("://") {
+            Some(index) => (&self.0[..index], &self.0[index + 3..]),
+            None => return Ok((NetScheme::Tcp, &self.0)),
+        };
+        match scheme {
+            "tcp" | "direct" => Ok((NetScheme::Tcp, rest)),
+            "ws" => {
+                // Remove the path:
+                let host_port = match rest.find('/') {
+                    Some(index) => &rest[..index],
+                    None => rest,
+                };
+                Ok((NetScheme::Ws, host_port))
+            }
+            _ => Err(NetAddressError::UnknownScheme),
+        }
+    }
 }
 
 impl CanonicalSerialize for NetAddress {
@@ -22,6 +60,7 @@ impl CanonicalSerialize for NetAddress {
 #[derive(Debug)]
 pub enum NetAddressError {
     AddressTooLong,
+    UnknownScheme,
 }
 
 impl TryFrom<String> for NetAddress {
@@ -33,3 +72,52 @@ impl TryFrom<String> for NetAddress {
         Ok(NetAddress(address))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(address: &str) -> Option<(NetScheme, String)> {
+        let net_address = NetAddress::try_from(address.to_owned()).unwrap();
+        let (scheme, host_port) = net_address.split_scheme().ok()?;
+        Some((scheme, host_port.to_owned()))
+    }
+
+    #[test]
+    fn test_net_address_split_scheme() {
+        assert_eq!(
+            split("127.0.0.1:1337"),
+            Some((NetScheme::Tcp, "127.0.0.1:1337".to_owned()))
+        );
+        assert_eq!(
+            split("tcp://example.com:1337"),
+            Some((NetScheme::Tcp, "example.com:1337".to_owned()))
+        );
+        assert_eq!(
+            split("ws://example.com:80/offst"),
+            Some((NetScheme::Ws, "example.com:80".to_owned()))
+        );
+        assert_eq!(
+            split("ws://[::1]:8080"),
+            Some((NetScheme::Ws, "[::1]:8080".to_owned()))
+        );
+        assert_eq!(
+            split("direct://example.com:1337"),
+            Some((NetScheme::Tcp, "example.com:1337".to_owned()))
+        );
+        assert_eq!(split("http://example.com:80"), None);
+    }
+
+    #[test]
+    fn test_net_address_is_direct() {
+        let is_direct = |address: &str| {
+            NetAddress::try_from(address.to_owned())
+                .unwrap()
+                .is_direct()
+        };
+        assert!(is_direct("direct://example.com:1337"));
+        assert!(!is_direct("example.com:1337"));
+        assert!(!is_direct("tcp://example.com:1337"));
+        assert!(!is_direct("ws://example.com:80/offst"));
+    }
+}
@@ -1,5 +1,7 @@
 use crypto::identity::PublicKey;
 
+use crate::net::messages::NetAddress;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum InitConnection {
     Listen,
@@ -7,6 +9,18 @@ pub enum InitConnection {
     Accept(PublicKey),
     // remote side wants to connect to public_key
     Connect(PublicKey),
+    // Like Accept, but also asks for the address of the connecting side
+    AcceptPunch(PublicKey),
+    // Like Connect, but also asks for the address of the accepting side
+    ConnectPunch(PublicKey),
+}
+
+/// Address of the remote side of a relay connection, as observed by the relay.
+/// Sent by the relay as the first message of connections opened with `AcceptPunch` or
+/// `ConnectPunch`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PeerEndpoint {
+    pub opt_address: Option<NetAddress>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -18,3 +32,27 @@ pub struct RejectConnection {
 pub struct IncomingConnection {
     pub public_key: PublicKey,
 }
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MuxData {
+    pub tunnel_id: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MuxCredit {
+    pub tunnel_id: u32,
+    /// Amount of additional messages the remote side may send through the tunnel
+    pub amount: u32,
+}
+
+/// A message sent over a multiplexed relay connection
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MuxMessage {
+    /// Open a new tunnel (Client -> Relay)
+    Open(u32),
+    Data(MuxData),
+    Credit(MuxCredit),
+    /// Close a tunnel
+    Close(u32),
+}
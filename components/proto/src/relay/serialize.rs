@@ -1,11 +1,14 @@
-use crate::capnp_common::{read_public_key, write_public_key};
+use crate::capnp_common::{read_net_address, read_public_key, write_net_address, write_public_key};
 use capnp;
 use capnp::serialize_packed;
 use std::io;
 
 use relay_capnp;
 
-use super::messages::{IncomingConnection, InitConnection, RejectConnection};
+use super::messages::{
+    IncomingConnection, InitConnection, MuxCredit, MuxData, MuxMessage, PeerEndpoint,
+    RejectConnection,
+};
 
 use crate::serialize::SerializeError;
 
@@ -23,6 +26,14 @@ pub fn serialize_init_connection(init_connection: &InitConnection) -> Vec<u8> {
             let mut connect = msg.init_connect();
             write_public_key(&public_key, &mut connect);
         }
+        InitConnection::AcceptPunch(public_key) => {
+            let mut accept_punch = msg.init_accept_punch();
+            write_public_key(&public_key, &mut accept_punch);
+        }
+        InitConnection::ConnectPunch(public_key) => {
+            let mut connect_punch = msg.init_connect_punch();
+            write_public_key(&public_key, &mut connect_punch);
+        }
     }
 
     let mut serialized_msg = Vec::new();
@@ -46,10 +57,48 @@ pub fn deserialize_init_connection(data: &[u8]) -> Result<InitConnection, Serial
             let public_key = read_public_key(&(public_key?))?;
             Ok(InitConnection::Connect(public_key))
         }
+        Ok(relay_capnp::init_connection::AcceptPunch(public_key)) => {
+            let public_key = read_public_key(&(public_key?))?;
+            Ok(InitConnection::AcceptPunch(public_key))
+        }
+        Ok(relay_capnp::init_connection::ConnectPunch(public_key)) => {
+            let public_key = read_public_key(&(public_key?))?;
+            Ok(InitConnection::ConnectPunch(public_key))
+        }
         Err(e) => Err(SerializeError::NotInSchema(e)),
     }
 }
 
+pub fn serialize_peer_endpoint(peer_endpoint: &PeerEndpoint) -> Vec<u8> {
+    let mut builder = capnp::message::Builder::new_default();
+    let msg = builder.init_root::<relay_capnp::peer_endpoint::Builder>();
+
+    let mut opt_address = msg.init_opt_address();
+    match &peer_endpoint.opt_address {
+        Some(address) => write_net_address(address, &mut opt_address.init_address()),
+        None => opt_address.set_empty(()),
+    }
+
+    let mut serialized_msg = Vec::new();
+    serialize_packed::write_message(&mut serialized_msg, &builder).unwrap();
+    serialized_msg
+}
+
+pub fn deserialize_peer_endpoint(data: &[u8]) -> Result<PeerEndpoint, SerializeError> {
+    let mut cursor = io::Cursor::new(data);
+    let reader =
+        serialize_packed::read_message(&mut cursor, ::capnp::message::ReaderOptions::new())?;
+    let msg = reader.get_root::<relay_capnp::peer_endpoint::Reader>()?;
+
+    let opt_address = match msg.get_opt_address().which()? {
+        relay_capnp::peer_endpoint::opt_address::Address(address) => {
+            Some(read_net_address(&address?)?)
+        }
+        relay_capnp::peer_endpoint::opt_address::Empty(()) => None,
+    };
+    Ok(PeerEndpoint { opt_address })
+}
+
 pub fn serialize_reject_connection(reject_connection: &RejectConnection) -> Vec<u8> {
     let mut builder = capnp::message::Builder::new_default();
     let msg = builder.init_root::<relay_capnp::reject_connection::Builder>();
@@ -92,12 +141,63 @@ pub fn deserialize_incoming_connection(data: &[u8]) -> Result<IncomingConnection
     Ok(IncomingConnection { public_key })
 }
 
+pub fn serialize_mux_message(mux_message: &MuxMessage) -> Vec<u8> {
+    let mut builder = capnp::message::Builder::new_default();
+    let mut msg = builder.init_root::<relay_capnp::mux_message::Builder>();
+
+    match mux_message {
+        MuxMessage::Open(tunnel_id) => msg.set_open(*tunnel_id),
+        MuxMessage::Data(mux_data) => {
+            let mut data = msg.init_data();
+            data.set_tunnel_id(mux_data.tunnel_id);
+            data.set_data(&mux_data.data);
+        }
+        MuxMessage::Credit(mux_credit) => {
+            let mut credit = msg.init_credit();
+            credit.set_tunnel_id(mux_credit.tunnel_id);
+            credit.set_amount(mux_credit.amount);
+        }
+        MuxMessage::Close(tunnel_id) => msg.set_close(*tunnel_id),
+    }
+
+    let mut serialized_msg = Vec::new();
+    serialize_packed::write_message(&mut serialized_msg, &builder).unwrap();
+    serialized_msg
+}
+
+pub fn deserialize_mux_message(data: &[u8]) -> Result<MuxMessage, SerializeError> {
+    let mut cursor = io::Cursor::new(data);
+    let reader =
+        serialize_packed::read_message(&mut cursor, ::capnp::message::ReaderOptions::new())?;
+    let msg = reader.get_root::<relay_capnp::mux_message::Reader>()?;
+
+    match msg.which() {
+        Ok(relay_capnp::mux_message::Open(tunnel_id)) => Ok(MuxMessage::Open(tunnel_id)),
+        Ok(relay_capnp::mux_message::Data(data)) => {
+            let data = data?;
+            Ok(MuxMessage::Data(MuxData {
+                tunnel_id: data.get_tunnel_id(),
+                data: data.get_data()?.to_vec(),
+            }))
+        }
+        Ok(relay_capnp::mux_message::Credit(credit)) => {
+            let credit = credit?;
+            Ok(MuxMessage::Credit(MuxCredit {
+                tunnel_id: credit.get_tunnel_id(),
+                amount: credit.get_amount(),
+            }))
+        }
+        Ok(relay_capnp::mux_message::Close(tunnel_id)) => Ok(MuxMessage::Close(tunnel_id)),
+        Err(e) => Err(SerializeError::NotInSchema(e)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crypto::identity::PublicKey;
     use crypto::identity::PUBLIC_KEY_LEN;
-    use std::convert::TryFrom;
+    use std::convert::{TryFrom, TryInto};
 
     #[test]
     fn test_serialize_init_connection() {
@@ -117,6 +217,33 @@ mod tests {
         let serialized = serialize_init_connection(&msg);
         let msg2 = deserialize_init_connection(&serialized[..]).unwrap();
         assert_eq!(msg, msg2);
+
+        let public_key = PublicKey::try_from(&[0x03u8; PUBLIC_KEY_LEN][..]).unwrap();
+        let msg = InitConnection::AcceptPunch(public_key);
+        let serialized = serialize_init_connection(&msg);
+        let msg2 = deserialize_init_connection(&serialized[..]).unwrap();
+        assert_eq!(msg, msg2);
+
+        let public_key = PublicKey::try_from(&[0x04u8; PUBLIC_KEY_LEN][..]).unwrap();
+        let msg = InitConnection::ConnectPunch(public_key);
+        let serialized = serialize_init_connection(&msg);
+        let msg2 = deserialize_init_connection(&serialized[..]).unwrap();
+        assert_eq!(msg, msg2);
+    }
+
+    #[test]
+    fn test_serialize_peer_endpoint() {
+        let msg = PeerEndpoint {
+            opt_address: Some("1.2.3.4:1337".to_owned().try_into().unwrap()),
+        };
+        let serialized = serialize_peer_endpoint(&msg);
+        let msg2 = deserialize_peer_endpoint(&serialized[..]).unwrap();
+        assert_eq!(msg, msg2);
+
+        let msg = PeerEndpoint { opt_address: None };
+        let serialized = serialize_peer_endpoint(&msg);
+        let msg2 = deserialize_peer_endpoint(&serialized[..]).unwrap();
+        assert_eq!(msg, msg2);
     }
 
     #[test]
@@ -136,4 +263,25 @@ mod tests {
         let msg2 = deserialize_incoming_connection(&serialized[..]).unwrap();
         assert_eq!(msg, msg2);
     }
+
+    #[test]
+    fn test_serialize_mux_message() {
+        let msgs = vec![
+            MuxMessage::Open(3),
+            MuxMessage::Data(MuxData {
+                tunnel_id: 3,
+                data: vec![1, 2, 3],
+            }),
+            MuxMessage::Credit(MuxCredit {
+                tunnel_id: 3,
+                amount: 8,
+            }),
+            MuxMessage::Close(3),
+        ];
+        for msg in msgs {
+            let serialized = serialize_mux_message(&msg);
+            let msg2 = deserialize_mux_message(&serialized[..]).unwrap();
+            assert_eq!(msg, msg2);
+        }
+    }
 }
@@ -380,3 +380,77 @@ where
         }
     }
 }
+
+/// Health of one of the relays of a friend, as observed by our connection attempts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayHealthReport<RA = RelayAddress> {
+    pub relay: RA,
+    /// Amount of successful connection attempts through this relay
+    pub successes: u64,
+    /// Amount of failed connection attempts through this relay
+    pub failures: u64,
+    /// Amount of failed connection attempts since the last successful attempt
+    pub consecutive_failures: u64,
+    /// Time it took to establish the last successful connection, in milliseconds
+    pub opt_last_latency_ms: Option<u64>,
+}
+
+/// Statistics about the connection to a remote friend.
+/// Traffic counters are kept for the current connection only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FriendConnectionReport<RA = RelayAddress> {
+    /// Unix time (in seconds) when the current connection was established
+    pub opt_connected_since: Option<u64>,
+    /// Amount of times the connection to this friend was re-established
+    pub reconnect_count: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Round trip time of the last keepalive probe, in milliseconds
+    pub opt_last_rtt_ms: Option<u64>,
+    /// The relay the current connection goes through.
+    /// None for incoming direct connections.
+    pub opt_relay: Option<RA>,
+    /// Health of the relays we connect to the friend through.
+    /// Empty if the friend connects to us.
+    pub relays_health: Vec<RelayHealthReport<RA>>,
+}
+
+/// Connection statistics for all the friends, as collected by the Channeler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionsReport<RA = RelayAddress>
+where
+    RA: Clone,
+{
+    pub friends: ImHashMap<PublicKey, FriendConnectionReport<RA>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionsReportMutation<RA = RelayAddress> {
+    SetFriend((PublicKey, FriendConnectionReport<RA>)),
+    RemoveFriend(PublicKey),
+}
+
+impl<RA> ConnectionsReport<RA>
+where
+    RA: Clone,
+{
+    pub fn new() -> Self {
+        ConnectionsReport {
+            friends: ImHashMap::new(),
+        }
+    }
+
+    pub fn mutate(&mut self, mutation: &ConnectionsReportMutation<RA>) {
+        match mutation {
+            ConnectionsReportMutation::SetFriend((friend_public_key, friend_connection_report)) => {
+                self.friends
+                    .insert(friend_public_key.clone(), friend_connection_report.clone());
+            }
+            ConnectionsReportMutation::RemoveFriend(friend_public_key) => {
+                let _ = self.friends.remove(friend_public_key);
+            }
+        }
+    }
+}
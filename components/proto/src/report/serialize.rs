@@ -12,9 +12,10 @@ use common::int_convert::usize_to_u32;
 use crypto::identity::PublicKey;
 
 use crate::report::messages::{
-    AddFriendReport, ChannelInconsistentReport, ChannelStatusReport, DirectionReport,
-    FriendLivenessReport, FriendReport, FriendReportMutation, FriendStatusReport, FunderReport,
-    FunderReportMutation, McBalanceReport, McRequestsStatusReport, MoveTokenHashedReport,
+    AddFriendReport, ChannelInconsistentReport, ChannelStatusReport, ConnectionsReport,
+    ConnectionsReportMutation, DirectionReport, FriendConnectionReport, FriendLivenessReport,
+    FriendReport, FriendReportMutation, FriendStatusReport, FunderReport, FunderReportMutation,
+    McBalanceReport, McRequestsStatusReport, MoveTokenHashedReport, RelayHealthReport,
     RequestsStatusReport, ResetTermsReport, SentLocalRelaysReport, TcReport,
 };
 use crate::serialize::SerializeError;
@@ -1139,6 +1140,231 @@ fn deser_index_client_report_mutation(
     })
 }
 
+fn ser_relay_health_report(
+    relay_health_report: &RelayHealthReport,
+    relay_health_report_builder: &mut report_capnp::relay_health_report::Builder,
+) {
+    write_relay_address(
+        &relay_health_report.relay,
+        &mut relay_health_report_builder.reborrow().init_relay(),
+    );
+    relay_health_report_builder.set_successes(relay_health_report.successes);
+    relay_health_report_builder.set_failures(relay_health_report.failures);
+    relay_health_report_builder.set_consecutive_failures(relay_health_report.consecutive_failures);
+
+    let mut opt_last_latency_builder = relay_health_report_builder
+        .reborrow()
+        .init_opt_last_latency();
+    match relay_health_report.opt_last_latency_ms {
+        Some(last_latency_ms) => opt_last_latency_builder.set_last_latency_ms(last_latency_ms),
+        None => opt_last_latency_builder.set_empty(()),
+    }
+}
+
+fn deser_relay_health_report(
+    relay_health_report_reader: &report_capnp::relay_health_report::Reader,
+) -> Result<RelayHealthReport, SerializeError> {
+    let opt_last_latency_ms = match relay_health_report_reader.get_opt_last_latency().which()? {
+        report_capnp::relay_health_report::opt_last_latency::LastLatencyMs(last_latency_ms) => {
+            Some(last_latency_ms)
+        }
+        report_capnp::relay_health_report::opt_last_latency::Empty(()) => None,
+    };
+
+    Ok(RelayHealthReport {
+        relay: read_relay_address(&relay_health_report_reader.get_relay()?)?,
+        successes: relay_health_report_reader.get_successes(),
+        failures: relay_health_report_reader.get_failures(),
+        consecutive_failures: relay_health_report_reader.get_consecutive_failures(),
+        opt_last_latency_ms,
+    })
+}
+
+fn ser_friend_connection_report(
+    friend_connection_report: &FriendConnectionReport,
+    friend_connection_report_builder: &mut report_capnp::friend_connection_report::Builder,
+) {
+    let mut opt_connected_since_builder = friend_connection_report_builder
+        .reborrow()
+        .init_opt_connected_since();
+    match friend_connection_report.opt_connected_since {
+        Some(connected_since) => opt_connected_since_builder.set_connected_since(connected_since),
+        None => opt_connected_since_builder.set_empty(()),
+    }
+
+    friend_connection_report_builder.set_reconnect_count(friend_connection_report.reconnect_count);
+    friend_connection_report_builder.set_bytes_sent(friend_connection_report.bytes_sent);
+    friend_connection_report_builder.set_bytes_received(friend_connection_report.bytes_received);
+    friend_connection_report_builder.set_messages_sent(friend_connection_report.messages_sent);
+    friend_connection_report_builder
+        .set_messages_received(friend_connection_report.messages_received);
+
+    let mut opt_last_rtt_builder = friend_connection_report_builder
+        .reborrow()
+        .init_opt_last_rtt();
+    match friend_connection_report.opt_last_rtt_ms {
+        Some(last_rtt_ms) => opt_last_rtt_builder.set_last_rtt_ms(last_rtt_ms),
+        None => opt_last_rtt_builder.set_empty(()),
+    }
+
+    let mut opt_relay_builder = friend_connection_report_builder.reborrow().init_opt_relay();
+    match &friend_connection_report.opt_relay {
+        Some(relay_address) => {
+            write_relay_address(relay_address, &mut opt_relay_builder.init_relay())
+        }
+        None => opt_relay_builder.set_empty(()),
+    }
+
+    let relays_health_len = usize_to_u32(friend_connection_report.relays_health.len()).unwrap();
+    let mut relays_health_builder = friend_connection_report_builder
+        .reborrow()
+        .init_relays_health(relays_health_len);
+    for (index, relay_health_report) in friend_connection_report.relays_health.iter().enumerate() {
+        let mut relay_health_report_builder = relays_health_builder
+            .reborrow()
+            .get(usize_to_u32(index).unwrap());
+        ser_relay_health_report(relay_health_report, &mut relay_health_report_builder);
+    }
+}
+
+fn deser_friend_connection_report(
+    friend_connection_report_reader: &report_capnp::friend_connection_report::Reader,
+) -> Result<FriendConnectionReport, SerializeError> {
+    let opt_connected_since = match friend_connection_report_reader
+        .get_opt_connected_since()
+        .which()?
+    {
+        report_capnp::friend_connection_report::opt_connected_since::ConnectedSince(
+            connected_since,
+        ) => Some(connected_since),
+        report_capnp::friend_connection_report::opt_connected_since::Empty(()) => None,
+    };
+
+    let opt_last_rtt_ms = match friend_connection_report_reader.get_opt_last_rtt().which()? {
+        report_capnp::friend_connection_report::opt_last_rtt::LastRttMs(last_rtt_ms) => {
+            Some(last_rtt_ms)
+        }
+        report_capnp::friend_connection_report::opt_last_rtt::Empty(()) => None,
+    };
+
+    let opt_relay = match friend_connection_report_reader.get_opt_relay().which()? {
+        report_capnp::friend_connection_report::opt_relay::Relay(relay_address_reader) => {
+            Some(read_relay_address(&relay_address_reader?)?)
+        }
+        report_capnp::friend_connection_report::opt_relay::Empty(()) => None,
+    };
+
+    let mut relays_health = Vec::new();
+    for relay_health_report in friend_connection_report_reader.get_relays_health()? {
+        relays_health.push(deser_relay_health_report(&relay_health_report)?);
+    }
+
+    Ok(FriendConnectionReport {
+        opt_connected_since,
+        reconnect_count: friend_connection_report_reader.get_reconnect_count(),
+        bytes_sent: friend_connection_report_reader.get_bytes_sent(),
+        bytes_received: friend_connection_report_reader.get_bytes_received(),
+        messages_sent: friend_connection_report_reader.get_messages_sent(),
+        messages_received: friend_connection_report_reader.get_messages_received(),
+        opt_last_rtt_ms,
+        opt_relay,
+        relays_health,
+    })
+}
+
+fn ser_pk_friend_connection_report(
+    pk_friend_connection_report: &(PublicKey, FriendConnectionReport),
+    pk_friend_connection_report_builder: &mut report_capnp::pk_friend_connection_report::Builder,
+) {
+    let (friend_public_key, friend_connection_report) = pk_friend_connection_report;
+    write_public_key(
+        friend_public_key,
+        &mut pk_friend_connection_report_builder
+            .reborrow()
+            .init_friend_public_key(),
+    );
+    ser_friend_connection_report(
+        friend_connection_report,
+        &mut pk_friend_connection_report_builder
+            .reborrow()
+            .init_friend_connection_report(),
+    );
+}
+
+fn deser_pk_friend_connection_report(
+    pk_friend_connection_report_reader: &report_capnp::pk_friend_connection_report::Reader,
+) -> Result<(PublicKey, FriendConnectionReport), SerializeError> {
+    Ok((
+        read_public_key(&pk_friend_connection_report_reader.get_friend_public_key()?)?,
+        deser_friend_connection_report(
+            &pk_friend_connection_report_reader.get_friend_connection_report()?,
+        )?,
+    ))
+}
+
+fn ser_connections_report(
+    connections_report: &ConnectionsReport,
+    connections_report_builder: &mut report_capnp::connections_report::Builder,
+) {
+    let friends_len = usize_to_u32(connections_report.friends.len()).unwrap();
+    let mut friends_builder = connections_report_builder
+        .reborrow()
+        .init_friends(friends_len);
+    for (index, pk_friend) in connections_report.friends.iter().enumerate() {
+        let mut pk_friend_builder = friends_builder.reborrow().get(usize_to_u32(index).unwrap());
+        ser_pk_friend_connection_report(pk_friend, &mut pk_friend_builder);
+    }
+}
+
+fn deser_connections_report(
+    connections_report_reader: &report_capnp::connections_report::Reader,
+) -> Result<ConnectionsReport, SerializeError> {
+    let mut friends = ImHashMap::new();
+    for pk_friend in connections_report_reader.get_friends()? {
+        let (friend_public_key, friend_connection_report) =
+            deser_pk_friend_connection_report(&pk_friend)?;
+        friends.insert(friend_public_key, friend_connection_report);
+    }
+    Ok(ConnectionsReport { friends })
+}
+
+fn ser_connections_report_mutation(
+    connections_report_mutation: &ConnectionsReportMutation,
+    connections_report_mutation_builder: &mut report_capnp::connections_report_mutation::Builder,
+) {
+    match connections_report_mutation {
+        ConnectionsReportMutation::SetFriend(pk_friend_connection_report) => {
+            ser_pk_friend_connection_report(
+                pk_friend_connection_report,
+                &mut connections_report_mutation_builder
+                    .reborrow()
+                    .init_set_friend(),
+            )
+        }
+        ConnectionsReportMutation::RemoveFriend(friend_public_key) => write_public_key(
+            friend_public_key,
+            &mut connections_report_mutation_builder
+                .reborrow()
+                .init_remove_friend(),
+        ),
+    }
+}
+
+fn deser_connections_report_mutation(
+    connections_report_mutation_reader: &report_capnp::connections_report_mutation::Reader,
+) -> Result<ConnectionsReportMutation, SerializeError> {
+    Ok(match connections_report_mutation_reader.which()? {
+        report_capnp::connections_report_mutation::SetFriend(
+            pk_friend_connection_report_reader,
+        ) => ConnectionsReportMutation::SetFriend(deser_pk_friend_connection_report(
+            &pk_friend_connection_report_reader?,
+        )?),
+        report_capnp::connections_report_mutation::RemoveFriend(public_key_reader) => {
+            ConnectionsReportMutation::RemoveFriend(read_public_key(&public_key_reader?)?)
+        }
+    })
+}
+
 pub fn ser_node_report(
     node_report: &NodeReport,
     node_report_builder: &mut report_capnp::node_report::Builder,
@@ -1151,6 +1377,10 @@ pub fn ser_node_report(
         &node_report.index_client_report,
         &mut node_report_builder.reborrow().init_index_client_report(),
     );
+    ser_connections_report(
+        &node_report.connections_report,
+        &mut node_report_builder.reborrow().init_connections_report(),
+    );
 }
 
 pub fn deser_node_report(
@@ -1161,6 +1391,9 @@ pub fn deser_node_report(
         index_client_report: deser_index_client_report(
             &node_report_reader.get_index_client_report()?,
         )?,
+        connections_report: deser_connections_report(
+            &node_report_reader.get_connections_report()?,
+        )?,
     })
 }
 
@@ -1179,6 +1412,12 @@ pub fn ser_node_report_mutation(
                 &mut node_report_mutation_builder.reborrow().init_index_client(),
             )
         }
+        NodeReportMutation::Connections(connections_report_mutation) => {
+            ser_connections_report_mutation(
+                &connections_report_mutation,
+                &mut node_report_mutation_builder.reborrow().init_connections(),
+            )
+        }
     }
 }
 
@@ -1196,5 +1435,10 @@ pub fn deser_node_report_mutation(
                 &index_client_report_mutation_reader?,
             )?)
         }
+        report_capnp::node_report_mutation::Connections(connections_report_mutation_reader) => {
+            NodeReportMutation::Connections(deser_connections_report_mutation(
+                &connections_report_mutation_reader?,
+            )?)
+        }
     })
 }
@@ -1,6 +1,7 @@
 use crypto::crypto_rand::RandValue;
 use crypto::dh::{DhPublicKey, Salt};
 use crypto::identity::{PublicKey, Signature};
+use crypto::kem::{KemCiphertext, KemPublicKey};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct EncryptedData(pub Vec<u8>);
@@ -12,6 +13,8 @@ pub struct PlainData(pub Vec<u8>);
 pub struct ExchangeRandNonce {
     pub rand_nonce: RandValue,
     pub public_key: PublicKey,
+    /// Only used in the hybrid handshake
+    pub opt_kem_public_key: Option<KemPublicKey>,
 }
 
 /// Second Diffie-Hellman message:
@@ -21,14 +24,40 @@ pub struct ExchangeDh {
     pub rand_nonce: RandValue,
     pub key_salt: Salt,
     pub signature: Signature,
+    /// Only used in the hybrid handshake
+    pub opt_kem_ciphertext: Option<KemCiphertext>,
 }
 
 impl ExchangeDh {
-    pub fn signature_buffer(&self) -> Vec<u8> {
+    /// The signature also covers the hybrid handshake offers of both sides:
+    /// `opt_recv_kem_public_key` is the KEM public key the signing side received from the remote
+    /// side, and `sent_kem_offer` tells whether the signing side offered the hybrid handshake
+    /// itself. This makes sure that an attacker can not strip the offers to force the classic
+    /// handshake.
+    pub fn signature_buffer(
+        &self,
+        opt_recv_kem_public_key: Option<&KemPublicKey>,
+        sent_kem_offer: bool,
+    ) -> Vec<u8> {
         let mut sbuffer = Vec::new();
         sbuffer.extend_from_slice(&self.dh_public_key);
         sbuffer.extend_from_slice(&self.rand_nonce);
         sbuffer.extend_from_slice(&self.key_salt);
+        if let Some(kem_ciphertext) = &self.opt_kem_ciphertext {
+            sbuffer.extend_from_slice(kem_ciphertext.as_ref());
+        }
+        // Nothing is added if no side offered the hybrid handshake, to keep the classic
+        // handshake unchanged:
+        if sent_kem_offer || opt_recv_kem_public_key.is_some() {
+            sbuffer.push(sent_kem_offer as u8);
+            match opt_recv_kem_public_key {
+                Some(recv_kem_public_key) => {
+                    sbuffer.push(1);
+                    sbuffer.extend_from_slice(recv_kem_public_key.as_ref());
+                }
+                None => sbuffer.push(0),
+            }
+        }
         sbuffer
     }
 }
@@ -1,8 +1,11 @@
 use capnp;
 use capnp::serialize_packed;
 use dh_capnp;
+use std::convert::TryFrom;
 use std::io;
 
+use crypto::kem::{KemCiphertext, KemPublicKey};
+
 use crate::capnp_common::{
     read_dh_public_key, read_public_key, read_rand_nonce, read_salt, read_signature,
     write_dh_public_key, write_public_key, write_rand_nonce, write_salt, write_signature,
@@ -26,6 +29,9 @@ pub fn serialize_exchange_rand_nonce(exchange_rand_nonce: &ExchangeRandNonce) ->
         &exchange_rand_nonce.public_key,
         &mut msg.reborrow().get_public_key().unwrap(),
     );
+    if let Some(kem_public_key) = &exchange_rand_nonce.opt_kem_public_key {
+        msg.reborrow().set_kem_public_key(kem_public_key.as_ref());
+    }
 
     let mut serialized_msg = Vec::new();
     serialize_packed::write_message(&mut serialized_msg, &builder).unwrap();
@@ -40,10 +46,19 @@ pub fn deserialize_exchange_rand_nonce(data: &[u8]) -> Result<ExchangeRandNonce,
 
     let rand_nonce = read_rand_nonce(&msg.get_rand_nonce()?)?;
     let public_key = read_public_key(&msg.get_public_key()?)?;
+    let kem_public_key = msg.get_kem_public_key()?;
+    let opt_kem_public_key = if kem_public_key.is_empty() {
+        None
+    } else {
+        Some(KemPublicKey::try_from(kem_public_key).map_err(|_| {
+            capnp::Error::failed("Invalid KEM public key length".to_owned())
+        })?)
+    };
 
     Ok(ExchangeRandNonce {
         rand_nonce,
         public_key,
+        opt_kem_public_key,
     })
 }
 
@@ -67,6 +82,9 @@ pub fn serialize_exchange_dh(exchange_dh: &ExchangeDh) -> Vec<u8> {
         &exchange_dh.signature,
         &mut msg.reborrow().get_signature().unwrap(),
     );
+    if let Some(kem_ciphertext) = &exchange_dh.opt_kem_ciphertext {
+        msg.reborrow().set_kem_ciphertext(kem_ciphertext.as_ref());
+    }
 
     let mut serialized_msg = Vec::new();
     serialize_packed::write_message(&mut serialized_msg, &builder).unwrap();
@@ -83,12 +101,21 @@ pub fn deserialize_exchange_dh(data: &[u8]) -> Result<ExchangeDh, SerializeError
     let rand_nonce = read_rand_nonce(&msg.get_rand_nonce()?)?;
     let key_salt = read_salt(&msg.get_key_salt()?)?;
     let signature = read_signature(&msg.get_signature()?)?;
+    let kem_ciphertext = msg.get_kem_ciphertext()?;
+    let opt_kem_ciphertext = if kem_ciphertext.is_empty() {
+        None
+    } else {
+        Some(KemCiphertext::try_from(kem_ciphertext).map_err(|_| {
+            capnp::Error::failed("Invalid KEM ciphertext length".to_owned())
+        })?)
+    };
 
     Ok(ExchangeDh {
         dh_public_key,
         rand_nonce,
         key_salt,
         signature,
+        opt_kem_ciphertext,
     })
 }
 
@@ -160,13 +187,24 @@ mod tests {
     use crypto::dh::{DH_PUBLIC_KEY_LEN, SALT_LEN};
     use crypto::identity::{PublicKey, Signature};
     use crypto::identity::{PUBLIC_KEY_LEN, SIGNATURE_LEN};
-    use std::convert::TryFrom;
+    use crypto::kem::{KEM_CIPHERTEXT_LEN, KEM_PUBLIC_KEY_LEN};
 
     #[test]
     fn test_serialize_exchange_rand_nonce() {
         let msg = ExchangeRandNonce {
             rand_nonce: RandValue::try_from(&[0x01u8; RAND_VALUE_LEN][..]).unwrap(),
             public_key: PublicKey::try_from(&[0x02u8; PUBLIC_KEY_LEN][..]).unwrap(),
+            opt_kem_public_key: None,
+        };
+        let serialized = serialize_exchange_rand_nonce(&msg);
+        let msg2 = deserialize_exchange_rand_nonce(&serialized[..]).unwrap();
+        assert_eq!(msg, msg2);
+
+        let msg = ExchangeRandNonce {
+            opt_kem_public_key: Some(
+                KemPublicKey::try_from(&[0x03u8; KEM_PUBLIC_KEY_LEN][..]).unwrap(),
+            ),
+            ..msg
         };
         let serialized = serialize_exchange_rand_nonce(&msg);
         let msg2 = deserialize_exchange_rand_nonce(&serialized[..]).unwrap();
@@ -180,6 +218,17 @@ mod tests {
             rand_nonce: RandValue::try_from(&[0x02u8; RAND_VALUE_LEN][..]).unwrap(),
             key_salt: Salt::try_from(&[0x03u8; SALT_LEN][..]).unwrap(),
             signature: Signature::try_from(&[0x03u8; SIGNATURE_LEN][..]).unwrap(),
+            opt_kem_ciphertext: None,
+        };
+        let serialized = serialize_exchange_dh(&msg);
+        let msg2 = deserialize_exchange_dh(&serialized[..]).unwrap();
+        assert_eq!(msg, msg2);
+
+        let msg = ExchangeDh {
+            opt_kem_ciphertext: Some(
+                KemCiphertext::try_from(&[0x04u8; KEM_CIPHERTEXT_LEN][..]).unwrap(),
+            ),
+            ..msg
         };
         let serialized = serialize_exchange_dh(&msg);
         let msg2 = deserialize_exchange_dh(&serialized[..]).unwrap();
@@ -1,5 +1,5 @@
 use crypto::identity::PublicKey;
-use futures::{FutureExt, SinkExt};
+use futures::SinkExt;
 
 use common::conn::{BoxFuture, ConnPairVec, FutTransform};
 
@@ -10,39 +10,59 @@ use proto::relay::serialize::serialize_init_connection;
 pub enum ClientConnectorError {
     InnerConnectorError,
     SendInitConnectionError,
+    PunchError,
 }
 
 /// ClientConnector is an end-to-end connector to a remote node.
 /// It relies on a given connector C to a relay.
+///
+/// If `opt_punch_transform` is provided, we ask the relay for the address of the remote side.
+/// The first message received through the connection will then be a `PeerEndpoint`, and it is
+/// left for the punch transform to consume it (and possibly replace the relay connection with a
+/// direct one).
 #[derive(Clone)]
-pub struct ClientConnector<C, FT> {
+pub struct ClientConnector<C, FT, PT> {
     connector: C,
     keepalive_transform: FT,
+    opt_punch_transform: Option<PT>,
 }
 
-impl<A, C, FT> ClientConnector<C, FT>
+impl<A, C, FT, PT> ClientConnector<C, FT, PT>
 where
     A: 'static,
     C: FutTransform<Input = A, Output = Option<ConnPairVec>>,
-    FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec>,
+    FT: FutTransform<Input = ConnPairVec>,
+    PT: FutTransform<Input = FT::Output, Output = Option<FT::Output>>,
 {
-    pub fn new(connector: C, keepalive_transform: FT) -> ClientConnector<C, FT> {
+    pub fn new(
+        connector: C,
+        keepalive_transform: FT,
+        opt_punch_transform: Option<PT>,
+    ) -> ClientConnector<C, FT, PT> {
         ClientConnector {
             connector,
             keepalive_transform,
+            opt_punch_transform,
         }
     }
 
+    /// If `punch` is set, we ask the relay for the address of the remote side, and let the punch
+    /// transform consume it.
     async fn relay_connect(
         &mut self,
         relay_address: A,
         remote_public_key: PublicKey,
-    ) -> Result<ConnPairVec, ClientConnectorError> {
+        punch: bool,
+    ) -> Result<FT::Output, ClientConnectorError> {
         let (mut sender, receiver) = await!(self.connector.transform(relay_address))
             .ok_or(ClientConnectorError::InnerConnectorError)?;
 
         // Send an InitConnection::Connect(PublicKey) message to remote side:
-        let init_connection = InitConnection::Connect(remote_public_key);
+        let init_connection = if punch {
+            InitConnection::ConnectPunch(remote_public_key)
+        } else {
+            InitConnection::Connect(remote_public_key)
+        };
         let ser_init_connection = serialize_init_connection(&init_connection);
         await!(sender.send(ser_init_connection))
             .map_err(|_| ClientConnectorError::SendInitConnectionError)?;
@@ -52,29 +72,49 @@ where
 
         // TODO; Do something about the unwrap here:
         // Maybe change ConnTransform trait to allow force returning something that is not None?
-        let (user_to_tunnel, user_from_tunnel) = await!(self
+        let conn = await!(self
             .keepalive_transform
             .transform((to_tunnel_sender, from_tunnel_receiver)));
 
-        Ok((user_to_tunnel, user_from_tunnel))
+        match &mut self.opt_punch_transform {
+            Some(punch_transform) if punch => {
+                await!(punch_transform.transform(conn)).ok_or(ClientConnectorError::PunchError)
+            }
+            _ => Ok(conn),
+        }
     }
 }
 
-impl<A, C, FT> FutTransform for ClientConnector<C, FT>
+impl<A, C, FT, PT> FutTransform for ClientConnector<C, FT, PT>
 where
-    A: Sync + Send + 'static,
+    A: Clone + Sync + Send + 'static,
     C: FutTransform<Input = A, Output = Option<ConnPairVec>> + Send + Sync,
-    FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec> + Send,
+    FT: FutTransform<Input = ConnPairVec> + Send,
+    FT::Output: Send,
+    PT: FutTransform<Input = FT::Output, Output = Option<FT::Output>> + Send,
 {
     type Input = (A, PublicKey);
-    type Output = Option<ConnPairVec>;
+    type Output = Option<FT::Output>;
 
     fn transform(&mut self, input: (A, PublicKey)) -> BoxFuture<'_, Self::Output> {
         let (relay_address, remote_public_key) = input;
-        let relay_connect = self
-            .relay_connect(relay_address, remote_public_key)
-            .map(Result::ok);
-        Box::pin(relay_connect)
+        Box::pin(
+            async move {
+                if self.opt_punch_transform.is_some() {
+                    match await!(self.relay_connect(
+                        relay_address.clone(),
+                        remote_public_key.clone(),
+                        true
+                    )) {
+                        // Relays that do not support punching close connections that ask for it.
+                        // We connect again, without punching:
+                        Err(ClientConnectorError::PunchError) => {}
+                        res => return res.ok(),
+                    }
+                }
+                await!(self.relay_connect(relay_address, remote_public_key, false)).ok()
+            },
+        )
     }
 }
 
@@ -87,12 +127,18 @@ mod tests {
     use futures::{future, StreamExt};
 
     use crypto::identity::PUBLIC_KEY_LEN;
-    use proto::relay::serialize::deserialize_init_connection;
+    use proto::relay::messages::PeerEndpoint;
+    use proto::relay::serialize::{
+        deserialize_init_connection, deserialize_peer_endpoint, serialize_peer_endpoint,
+    };
 
     use common::conn::FuncFutTransform;
     use common::dummy_connector::DummyConnector;
 
-    async fn task_client_connector_basic(mut spawner: impl Spawn + Clone + Sync + Send + 'static) {
+    async fn task_client_connector_basic(
+        mut spawner: impl Spawn + Clone + Sync + Send + 'static,
+        punch: bool,
+    ) {
         let (local_sender, mut relay_receiver) = mpsc::channel::<Vec<u8>>(0);
         let (mut relay_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
 
@@ -104,7 +150,21 @@ mod tests {
         // keepalive_transform does nothing:
         let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
 
-        let mut client_connector = ClientConnector::new(connector, keepalive_transform);
+        // punch_transform consumes the PeerEndpoint message, and keeps using the relay connection:
+        let punch_transform = FuncFutTransform::new(|conn_pair: ConnPairVec| {
+            Box::pin(
+                async move {
+                    let (sender, mut receiver) = conn_pair;
+                    let peer_endpoint = deserialize_peer_endpoint(&await!(receiver.next())?).ok()?;
+                    assert_eq!(peer_endpoint, PeerEndpoint { opt_address: None });
+                    Some((sender, receiver))
+                },
+            )
+        });
+        let opt_punch_transform = if punch { Some(punch_transform) } else { None };
+
+        let mut client_connector =
+            ClientConnector::new(connector, keepalive_transform, opt_punch_transform);
 
         let address: u32 = 15;
         let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
@@ -119,15 +179,23 @@ mod tests {
         let req = await!(req_receiver.next()).unwrap();
         // Reply with a connection:
         req.reply(Some(conn_pair));
-        let mut conn_pair = await!(fut_conn_pair);
 
         let vec = await!(relay_receiver.next()).unwrap();
         let init_connection = deserialize_init_connection(&vec).unwrap();
-        match init_connection {
-            InitConnection::Connect(conn_public_key) => assert_eq!(conn_public_key, public_key),
+        match (init_connection, punch) {
+            (InitConnection::Connect(conn_public_key), false)
+            | (InitConnection::ConnectPunch(conn_public_key), true) => {
+                assert_eq!(conn_public_key, public_key)
+            }
             _ => unreachable!(),
         };
 
+        if punch {
+            let peer_endpoint = PeerEndpoint { opt_address: None };
+            await!(relay_sender.send(serialize_peer_endpoint(&peer_endpoint))).unwrap();
+        }
+        let mut conn_pair = await!(fut_conn_pair);
+
         await!(relay_sender.send(vec![1, 2, 3])).unwrap();
         let (ref _sender, ref mut receiver) = conn_pair;
         let vec = await!(receiver.next()).unwrap();
@@ -137,6 +205,82 @@ mod tests {
     #[test]
     fn test_client_connector_basic() {
         let mut thread_pool = ThreadPool::new().unwrap();
-        thread_pool.run(task_client_connector_basic(thread_pool.clone()));
+        thread_pool.run(task_client_connector_basic(thread_pool.clone(), false));
+    }
+
+    #[test]
+    fn test_client_connector_punch() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_connector_basic(thread_pool.clone(), true));
+    }
+
+    async fn task_client_connector_punch_fallback(
+        mut spawner: impl Spawn + Clone + Sync + Send + 'static,
+    ) {
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+
+        // keepalive_transform does nothing:
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        // punch_transform expects a PeerEndpoint message:
+        let punch_transform = FuncFutTransform::new(|conn_pair: ConnPairVec| {
+            Box::pin(
+                async move {
+                    let (sender, mut receiver) = conn_pair;
+                    deserialize_peer_endpoint(&await!(receiver.next())?).ok()?;
+                    Some((sender, receiver))
+                },
+            )
+        });
+
+        let mut client_connector =
+            ClientConnector::new(connector, keepalive_transform, Some(punch_transform));
+
+        let address: u32 = 15;
+        let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
+        let c_public_key = public_key.clone();
+        let fut_conn_pair = spawner
+            .spawn_with_handle(
+                async move { await!(client_connector.transform((address, c_public_key))).unwrap() },
+            )
+            .unwrap();
+
+        // A relay that does not support punching closes the connection:
+        let (local_sender, mut relay_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (relay_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+        let vec = await!(relay_receiver.next()).unwrap();
+        match deserialize_init_connection(&vec).unwrap() {
+            InitConnection::ConnectPunch(conn_public_key) => {
+                assert_eq!(conn_public_key, public_key)
+            }
+            _ => unreachable!(),
+        };
+        drop((relay_sender, relay_receiver));
+
+        // We connect again, without punching:
+        let (local_sender, mut relay_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (mut relay_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+        let vec = await!(relay_receiver.next()).unwrap();
+        match deserialize_init_connection(&vec).unwrap() {
+            InitConnection::Connect(conn_public_key) => assert_eq!(conn_public_key, public_key),
+            _ => unreachable!(),
+        };
+        let mut conn_pair = await!(fut_conn_pair);
+
+        await!(relay_sender.send(vec![1, 2, 3])).unwrap();
+        let (ref _sender, ref mut receiver) = conn_pair;
+        let vec = await!(receiver.next()).unwrap();
+        assert_eq!(vec, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_client_connector_punch_fallback() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_client_connector_punch_fallback(thread_pool.clone()));
     }
 }
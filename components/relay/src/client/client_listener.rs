@@ -45,6 +45,7 @@ enum AcceptConnectionError {
     SendInitConnectionError,
     SendConnPairError,
     RequestTimerStreamError,
+    PunchError,
 }
 
 /*
@@ -104,68 +105,115 @@ where
     }
 }
 
-async fn accept_connection<C, CS, CSE, FT>(
+/// Open a connection to the relay, and accept the connection from `public_key` through it.
+/// If `opt_punch_transform` is provided, we also ask the relay for the address of the remote side,
+/// and let the punch transform consume it.
+async fn accept_relay_conn<'a, C, FT, PT>(
     public_key: PublicKey,
     connector: C,
-    mut pending_reject_sender: mpsc::Sender<PublicKey>,
-    mut connections_sender: CS,
-    mut keepalive_transform: FT,
+    keepalive_transform: &'a mut FT,
+    opt_punch_transform: Option<&'a mut PT>,
     conn_timeout_ticks: usize,
-    mut timer_client: TimerClient,
-) -> Result<(), AcceptConnectionError>
+    timer_client: &'a mut TimerClient,
+) -> Result<FT::Output, AcceptConnectionError>
 where
     C: FutTransform<Input = (), Output = Option<ConnPairVec>> + Send,
-    CS: Sink<SinkItem = (PublicKey, ConnPairVec), SinkError = CSE> + Unpin + 'static,
-    FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec>,
+    FT: FutTransform<Input = ConnPairVec>,
+    PT: FutTransform<Input = FT::Output, Output = Option<FT::Output>>,
 {
     let timer_stream = await!(timer_client.request_timer_stream())
         .map_err(|_| AcceptConnectionError::RequestTimerStreamError)?;
-    let opt_conn_pair = await!(connect_with_timeout(
+    let (mut sender, receiver) = await!(connect_with_timeout(
         connector,
         conn_timeout_ticks,
         timer_stream
-    ));
-    let conn_pair = match opt_conn_pair {
-        Some(conn_pair) => Ok(conn_pair),
-        None => {
-            await!(pending_reject_sender.send(public_key.clone()))
-                .map_err(|_| AcceptConnectionError::PendingRejectSenderError)?;
-            Err(AcceptConnectionError::ConnectionFailed)
-        }
-    }?;
-
-    let (mut sender, receiver) = conn_pair;
+    ))
+    .ok_or(AcceptConnectionError::ConnectionFailed)?;
 
     // Send first message:
-    let ser_init_connection =
-        serialize_init_connection(&InitConnection::Accept(public_key.clone()));
-    let send_res = await!(sender.send(ser_init_connection));
-    if send_res.is_err() {
-        await!(pending_reject_sender.send(public_key))
-            .map_err(|_| AcceptConnectionError::PendingRejectSenderError)?;
-        return Err(AcceptConnectionError::SendInitConnectionError);
-    }
+    let init_connection = if opt_punch_transform.is_some() {
+        InitConnection::AcceptPunch(public_key.clone())
+    } else {
+        InitConnection::Accept(public_key.clone())
+    };
+    let ser_init_connection = serialize_init_connection(&init_connection);
+    await!(sender.send(ser_init_connection))
+        .map_err(|_| AcceptConnectionError::SendInitConnectionError)?;
 
     let to_tunnel_sender = sender;
     let from_tunnel_receiver = receiver;
 
-    let (user_to_tunnel_sender, user_from_tunnel_receiver) =
+    let user_conn =
         await!(keepalive_transform.transform((to_tunnel_sender, from_tunnel_receiver)));
 
-    await!(connections_sender.send((
-        public_key,
-        (user_to_tunnel_sender, user_from_tunnel_receiver)
-    )))
-    .map_err(|_| AcceptConnectionError::SendConnPairError)?;
+    match opt_punch_transform {
+        Some(punch_transform) => {
+            await!(punch_transform.transform(user_conn)).ok_or(AcceptConnectionError::PunchError)
+        }
+        None => Ok(user_conn),
+    }
+}
+
+async fn accept_connection<C, CS, CSE, FT, PT>(
+    public_key: PublicKey,
+    connector: C,
+    mut pending_reject_sender: mpsc::Sender<PublicKey>,
+    mut connections_sender: CS,
+    mut keepalive_transform: FT,
+    mut opt_punch_transform: Option<PT>,
+    conn_timeout_ticks: usize,
+    mut timer_client: TimerClient,
+) -> Result<(), AcceptConnectionError>
+where
+    C: FutTransform<Input = (), Output = Option<ConnPairVec>> + Clone + Send,
+    CS: Sink<SinkItem = (PublicKey, FT::Output), SinkError = CSE> + Unpin + 'static,
+    FT: FutTransform<Input = ConnPairVec>,
+    PT: FutTransform<Input = FT::Output, Output = Option<FT::Output>>,
+{
+    let mut res_user_conn = await!(accept_relay_conn(
+        public_key.clone(),
+        connector.clone(),
+        &mut keepalive_transform,
+        opt_punch_transform.as_mut(),
+        conn_timeout_ticks,
+        &mut timer_client
+    ));
+    if let Err(AcceptConnectionError::PunchError) = res_user_conn {
+        // Relays that do not support punching close connections that ask for it.
+        // We accept again, without punching:
+        res_user_conn = await!(accept_relay_conn(
+            public_key.clone(),
+            connector,
+            &mut keepalive_transform,
+            None::<&mut PT>,
+            conn_timeout_ticks,
+            &mut timer_client
+        ));
+    }
+
+    let user_conn = match res_user_conn {
+        Ok(user_conn) => user_conn,
+        Err(e @ AcceptConnectionError::ConnectionFailed)
+        | Err(e @ AcceptConnectionError::SendInitConnectionError) => {
+            await!(pending_reject_sender.send(public_key))
+                .map_err(|_| AcceptConnectionError::PendingRejectSenderError)?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    await!(connections_sender.send((public_key, user_conn)))
+        .map_err(|_| AcceptConnectionError::SendConnPairError)?;
     Ok(())
 }
 
-async fn inner_client_listener<'a, C, IAC, CS, CSE, FT>(
+async fn inner_client_listener<'a, C, IAC, CS, CSE, FT, PT>(
     mut connector: C,
     access_control: &'a mut AccessControlPk,
     incoming_access_control: &'a mut IAC,
     connections_sender: CS,
     mut keepalive_transform: FT,
+    opt_punch_transform: Option<PT>,
     conn_timeout_ticks: usize,
     timer_client: TimerClient,
     mut spawner: impl Spawn + Clone + Send + 'static,
@@ -174,9 +222,11 @@ async fn inner_client_listener<'a, C, IAC, CS, CSE, FT>(
 where
     C: FutTransform<Input = (), Output = Option<ConnPairVec>> + Send + Sync + Clone + 'static,
     IAC: Stream<Item = AccessControlOp<PublicKey>> + Unpin + Send + 'static,
-    CS: Sink<SinkItem = (PublicKey, ConnPairVec), SinkError = CSE> + Unpin + Clone + Send + 'static,
+    CS: Sink<SinkItem = (PublicKey, FT::Output), SinkError = CSE> + Unpin + Clone + Send + 'static,
     CSE: 'static,
-    FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec> + Clone + Send + 'static,
+    FT: FutTransform<Input = ConnPairVec> + Clone + Send + 'static,
+    FT::Output: Into<ConnPairVec>,
+    PT: FutTransform<Input = FT::Output, Output = Option<FT::Output>> + Clone + Send + 'static,
 {
     let conn_pair = match await!(connector.transform(())) {
         Some(conn_pair) => conn_pair,
@@ -195,7 +245,7 @@ where
         .map_err(|_| ClientListenerError::SendInitConnectionError)?;
 
     let conn_pair = (sender, receiver);
-    let (sender, receiver) = await!(keepalive_transform.transform(conn_pair));
+    let (sender, receiver) = await!(keepalive_transform.transform(conn_pair)).into();
 
     // Add serialization for sender:
     let mut sender = sender
@@ -259,6 +309,7 @@ where
                         pending_reject_sender.clone(),
                         connections_sender.clone(),
                         keepalive_transform.clone(),
+                        opt_punch_transform.clone(),
                         conn_timeout_ticks,
                         timer_client.clone(),
                     )
@@ -282,26 +333,35 @@ where
     Ok(())
 }
 
+/// Listens for connections through a relay.
+///
+/// If `opt_punch_transform` is provided, we ask the relay for the address of every connecting
+/// side. The first message received through an accepted connection will then be a
+/// `PeerEndpoint`, and it is left for the punch transform to consume it (and possibly replace the
+/// relay connection with a direct one).
 #[derive(Clone)]
-pub struct ClientListener<C, FT, S> {
+pub struct ClientListener<C, FT, PT, S> {
     connector: C,
     keepalive_transform: FT,
+    opt_punch_transform: Option<PT>,
     conn_timeout_ticks: usize,
     timer_client: TimerClient,
     spawner: S,
 }
 
-impl<C, FT, S> ClientListener<C, FT, S> {
+impl<C, FT, PT, S> ClientListener<C, FT, PT, S> {
     pub fn new(
         connector: C,
         keepalive_transform: FT,
+        opt_punch_transform: Option<PT>,
         conn_timeout_ticks: usize,
         timer_client: TimerClient,
         spawner: S,
-    ) -> ClientListener<C, FT, S> {
+    ) -> ClientListener<C, FT, PT, S> {
         ClientListener {
             connector,
             keepalive_transform,
+            opt_punch_transform,
             conn_timeout_ticks,
             timer_client,
             spawner,
@@ -309,14 +369,16 @@ impl<C, FT, S> ClientListener<C, FT, S> {
     }
 }
 
-impl<A, C, FT, S> Listener for ClientListener<C, FT, S>
+impl<A, C, FT, PT, S> Listener for ClientListener<C, FT, PT, S>
 where
     A: Clone + Send + Sync + 'static,
     C: FutTransform<Input = A, Output = Option<ConnPairVec>> + Clone + Send + Sync + 'static,
     S: Spawn + Clone + Send + 'static,
-    FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec> + Clone + Send + 'static,
+    FT: FutTransform<Input = ConnPairVec> + Clone + Send + 'static,
+    FT::Output: Into<ConnPairVec> + Send + 'static,
+    PT: FutTransform<Input = FT::Output, Output = Option<FT::Output>> + Clone + Send + 'static,
 {
-    type Connection = (PublicKey, ConnPairVec);
+    type Connection = (PublicKey, FT::Output);
     type Config = AccessControlOpPk;
     type Arg = (A, AccessControlPk);
 
@@ -325,7 +387,7 @@ where
         arg: (A, AccessControlPk),
     ) -> (
         mpsc::Sender<AccessControlOp<PublicKey>>,
-        mpsc::Receiver<(PublicKey, ConnPairVec)>,
+        mpsc::Receiver<(PublicKey, FT::Output)>,
     ) {
         let (relay_address, mut access_control) = arg;
 
@@ -342,6 +404,7 @@ where
                 &mut access_control_receiver,
                 connections_sender,
                 self.keepalive_transform,
+                self.opt_punch_transform,
                 self.conn_timeout_ticks,
                 self.timer_client,
                 self.spawner,
@@ -366,11 +429,37 @@ mod tests {
     use proto::relay::serialize::deserialize_init_connection;
     use timer::create_timer_incoming;
 
-    use proto::relay::serialize::{deserialize_reject_connection, serialize_incoming_connection};
+    use proto::relay::messages::PeerEndpoint;
+    use proto::relay::serialize::{
+        deserialize_peer_endpoint, deserialize_reject_connection, serialize_incoming_connection,
+        serialize_peer_endpoint,
+    };
 
     use common::conn::FuncFutTransform;
     use common::dummy_connector::DummyConnector;
 
+    /// If `punch` is set, returns a punch transform that consumes the `PeerEndpoint` message and
+    /// keeps using the relay connection.
+    fn opt_relay_punch_transform(
+        punch: bool,
+    ) -> Option<
+        impl FutTransform<Input = ConnPairVec, Output = Option<ConnPairVec>> + Clone + Send + 'static,
+    > {
+        if !punch {
+            return None;
+        }
+        Some(FuncFutTransform::new(|conn_pair: ConnPairVec| {
+            Box::pin(
+                async move {
+                    let (sender, mut receiver) = conn_pair;
+                    let peer_endpoint = deserialize_peer_endpoint(&await!(receiver.next())?).ok()?;
+                    assert_eq!(peer_endpoint, PeerEndpoint { opt_address: None });
+                    Some((sender, receiver))
+                },
+            )
+        }))
+    }
+
     async fn task_connect_with_timeout_basic(mut spawner: impl Spawn) {
         let conn_timeout_ticks = 8;
         let (_timer_sender, timer_stream) = mpsc::channel::<TimerTick>(0);
@@ -431,7 +520,10 @@ mod tests {
         thread_pool.run(task_connect_with_timeout_timeout(thread_pool.clone()));
     }
 
-    async fn task_accept_connection_basic(mut spawner: impl Spawn + Clone + Send + 'static) {
+    async fn task_accept_connection_basic(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+        punch: bool,
+    ) {
         let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
         let (req_sender, mut req_receiver) = mpsc::channel(0);
         let connector = DummyConnector::new(req_sender);
@@ -450,6 +542,7 @@ mod tests {
             pending_reject_sender,
             connections_sender,
             keepalive_transform,
+            opt_relay_punch_transform(punch),
             conn_timeout_ticks,
             timer_client,
         )
@@ -469,15 +562,22 @@ mod tests {
 
         let vec_init_connection = await!(remote_receiver.next()).unwrap();
         let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
-        if let InitConnection::Accept(accept_public_key) = init_connection {
-            assert_eq!(accept_public_key, public_key);
-        } else {
-            unreachable!();
+        match (init_connection, punch) {
+            (InitConnection::Accept(accept_public_key), false)
+            | (InitConnection::AcceptPunch(accept_public_key), true) => {
+                assert_eq!(accept_public_key, public_key)
+            }
+            _ => unreachable!(),
         }
 
         let mut ser_remote_sender = remote_sender;
         let mut ser_remote_receiver = remote_receiver;
 
+        if punch {
+            let peer_endpoint = PeerEndpoint { opt_address: None };
+            await!(ser_remote_sender.send(serialize_peer_endpoint(&peer_endpoint))).unwrap();
+        }
+
         let (accepted_public_key, conn_pair) = await!(connections_receiver.next()).unwrap();
         assert_eq!(accepted_public_key, public_key);
 
@@ -495,7 +595,92 @@ mod tests {
     #[test]
     fn test_accept_connection_basic() {
         let mut thread_pool = ThreadPool::new().unwrap();
-        thread_pool.run(task_accept_connection_basic(thread_pool.clone()));
+        thread_pool.run(task_accept_connection_basic(thread_pool.clone(), false));
+    }
+
+    #[test]
+    fn test_accept_connection_punch() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_accept_connection_basic(thread_pool.clone(), true));
+    }
+
+    async fn task_accept_connection_punch_fallback(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) {
+        let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
+        let (req_sender, mut req_receiver) = mpsc::channel(0);
+        let connector = DummyConnector::new(req_sender);
+        let (pending_reject_sender, _pending_reject_receiver) = mpsc::channel(0);
+        let (connections_sender, mut connections_receiver) = mpsc::channel(0);
+        let conn_timeout_ticks = 8;
+        let (_tick_sender, tick_receiver) = mpsc::channel(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        // We don't need a real keepalive transform for this test:
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let fut_accept = accept_connection(
+            public_key.clone(),
+            connector,
+            pending_reject_sender,
+            connections_sender,
+            keepalive_transform,
+            opt_relay_punch_transform(true),
+            conn_timeout_ticks,
+            timer_client,
+        )
+        .map_err(|e| error!("accept_connection error: {:?}", e))
+        .map(|_| ());
+
+        spawner.spawn(fut_accept).unwrap();
+
+        // The relay does not support punching, and closes the connection:
+        let (local_sender, mut remote_receiver) = mpsc::channel(0);
+        let (remote_sender, local_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+
+        let vec_init_connection = await!(remote_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        match init_connection {
+            InitConnection::AcceptPunch(accept_public_key) => {
+                assert_eq!(accept_public_key, public_key)
+            }
+            _ => unreachable!(),
+        }
+        drop((remote_sender, remote_receiver));
+
+        // accept_connection() should try again, without punching:
+        let (local_sender, mut remote_receiver) = mpsc::channel(0);
+        let (mut remote_sender, local_receiver) = mpsc::channel(0);
+        let req = await!(req_receiver.next()).unwrap();
+        req.reply(Some((local_sender, local_receiver)));
+
+        let vec_init_connection = await!(remote_receiver.next()).unwrap();
+        let init_connection = deserialize_init_connection(&vec_init_connection).unwrap();
+        match init_connection {
+            InitConnection::Accept(accept_public_key) => assert_eq!(accept_public_key, public_key),
+            _ => unreachable!(),
+        }
+
+        let (accepted_public_key, conn_pair) = await!(connections_receiver.next()).unwrap();
+        assert_eq!(accepted_public_key, public_key);
+
+        let (mut sender, mut receiver) = conn_pair;
+
+        await!(sender.send(vec![1, 2, 3])).unwrap();
+        let res = await!(remote_receiver.next()).unwrap();
+        assert_eq!(res, vec![1, 2, 3]);
+
+        await!(remote_sender.send(vec![3, 2, 1])).unwrap();
+        let res = await!(receiver.next()).unwrap();
+        assert_eq!(res, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_accept_connection_punch_fallback() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_accept_connection_punch_fallback(thread_pool.clone()));
     }
 
     async fn task_client_listener_basic(mut spawner: impl Spawn + Clone + Send + 'static) {
@@ -519,6 +704,7 @@ mod tests {
                 &mut incoming_access_control,
                 connections_sender,
                 keepalive_transform,
+                opt_relay_punch_transform(false),
                 conn_timeout_ticks,
                 timer_client,
                 c_spawner,
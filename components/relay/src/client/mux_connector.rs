@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::{mpsc, oneshot};
+use futures::task::{Spawn, SpawnExt};
+use futures::{FutureExt, SinkExt, TryFutureExt};
+
+use common::conn::{BoxFuture, ConnPairVec, FutTransform};
+
+use crate::mux::mux_client_loop;
+
+type OpenSender = mpsc::Sender<oneshot::Sender<ConnPairVec>>;
+
+/// Amount of connections opened to a relay using the legacy connector before attempting to open a
+/// multiplexed session again. Failing to open a multiplexed session might be transient, so relays
+/// are not remembered as legacy relays forever.
+const LEGACY_CONNS_BEFORE_RETRY: usize = 16;
+
+/// Request a new tunnel from a running multiplexed session.
+async fn open_tunnel(mut open_sender: OpenSender) -> Option<ConnPairVec> {
+    let (conn_sender, conn_receiver) = oneshot::channel();
+    await!(open_sender.send(conn_sender)).ok()?;
+    await!(conn_receiver).ok()
+}
+
+/// A connector to relays that multiplexes all the connections to the same relay over a single
+/// (multiplexed) connection.
+///
+/// `mux_connector` should open a connection to the relay declaring the multiplexing protocol
+/// version. If the relay does not support multiplexing, `opt_legacy_connector` is used instead.
+/// If `opt_legacy_connector` is not provided, relays that do not support multiplexing are not
+/// used. Relays that are reachable using the legacy connector after failing to open a multiplexed
+/// session are remembered, and are connected using the legacy connector for the next
+/// `LEGACY_CONNS_BEFORE_RETRY` connections.
+/// Every returned connection behaves like a fresh connection to the relay.
+#[derive(Clone)]
+pub struct MuxConnector<A, MC, LC, KT, S> {
+    mux_connector: MC,
+    opt_legacy_connector: Option<LC>,
+    keepalive_transform: KT,
+    /// Running multiplexed sessions, by relay address
+    sessions: Arc<Mutex<HashMap<A, OpenSender>>>,
+    /// Addresses of relays that do not support multiplexing, with the amount of legacy
+    /// connections left before attempting a multiplexed session again
+    legacy_relays: Arc<Mutex<HashMap<A, usize>>>,
+    spawner: S,
+}
+
+impl<A, MC, LC, KT, S> MuxConnector<A, MC, LC, KT, S>
+where
+    A: Hash + Eq + Clone,
+    MC: FutTransform<Input = A, Output = Option<ConnPairVec>>,
+    LC: FutTransform<Input = A, Output = Option<ConnPairVec>>,
+    KT: FutTransform<Input = ConnPairVec, Output = ConnPairVec>,
+    S: Spawn + Clone + Send + 'static,
+{
+    pub fn new(
+        mux_connector: MC,
+        opt_legacy_connector: Option<LC>,
+        keepalive_transform: KT,
+        spawner: S,
+    ) -> Self {
+        MuxConnector {
+            mux_connector,
+            opt_legacy_connector,
+            keepalive_transform,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            legacy_relays: Arc::new(Mutex::new(HashMap::new())),
+            spawner,
+        }
+    }
+
+    /// Open a new multiplexed session to a relay, and return its first tunnel.
+    async fn mux_connect(&mut self, address: A) -> Option<ConnPairVec> {
+        let conn_pair = await!(self.mux_connector.transform(address.clone()))?;
+        let conn_pair = await!(self.keepalive_transform.transform(conn_pair));
+
+        let (open_sender, incoming_open_requests) = mpsc::channel(0);
+        let session_fut = mux_client_loop(conn_pair, incoming_open_requests, self.spawner.clone())
+            .map_err(|e| warn!("mux_client_loop() error: {:?}", e))
+            .map(|_| ());
+        self.spawner.spawn(session_fut).ok()?;
+
+        // Replaces any previous session that is not usable anymore:
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(address, open_sender.clone());
+
+        await!(open_tunnel(open_sender))
+    }
+
+    /// Should the relay at `address` be connected using the legacy connector?
+    fn use_legacy(&self, address: &A) -> bool {
+        let mut legacy_relays = self.legacy_relays.lock().unwrap();
+        if let Some(conns_left) = legacy_relays.get_mut(address) {
+            if *conns_left > 0 {
+                *conns_left -= 1;
+                return true;
+            }
+        }
+        // Time to attempt a multiplexed session again:
+        legacy_relays.remove(address);
+        false
+    }
+
+    async fn connect(&mut self, address: A) -> Option<ConnPairVec> {
+        if self.use_legacy(&address) {
+            return await!(self.opt_legacy_connector.as_mut()?.transform(address));
+        }
+
+        let opt_open_sender = self.sessions.lock().unwrap().get(&address).cloned();
+        if let Some(open_sender) = opt_open_sender {
+            if let Some(conn_pair) = await!(open_tunnel(open_sender)) {
+                return Some(conn_pair);
+            }
+        }
+
+        if let Some(conn_pair) = await!(self.mux_connect(address.clone())) {
+            return Some(conn_pair);
+        }
+
+        let legacy_connector = self.opt_legacy_connector.as_mut()?;
+        info!("MuxConnector: Falling back to a non multiplexed connection");
+        let conn_pair = await!(legacy_connector.transform(address.clone()))?;
+        // The relay is reachable, but does not support multiplexing:
+        self.legacy_relays
+            .lock()
+            .unwrap()
+            .insert(address, LEGACY_CONNS_BEFORE_RETRY);
+        Some(conn_pair)
+    }
+}
+
+impl<A, MC, LC, KT, S> FutTransform for MuxConnector<A, MC, LC, KT, S>
+where
+    A: Hash + Eq + Clone + Send + Sync + 'static,
+    MC: FutTransform<Input = A, Output = Option<ConnPairVec>> + Send,
+    LC: FutTransform<Input = A, Output = Option<ConnPairVec>> + Send,
+    KT: FutTransform<Input = ConnPairVec, Output = ConnPairVec> + Send,
+    S: Spawn + Clone + Send + 'static,
+{
+    type Input = A;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, address: A) -> BoxFuture<'_, Self::Output> {
+        Box::pin(self.connect(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::ThreadPool;
+    use futures::{future, StreamExt};
+
+    use common::conn::FuncFutTransform;
+    use common::dummy_connector::DummyConnector;
+
+    use crate::mux::mux_server_loop;
+
+    async fn task_mux_connector_basic<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + Sync + 'static,
+    {
+        let (mux_req_sender, mut mux_req_receiver) = mpsc::channel(0);
+        let mux_connector = DummyConnector::new(mux_req_sender);
+        let (legacy_req_sender, _legacy_req_receiver) = mpsc::channel(0);
+        let legacy_connector = DummyConnector::new(legacy_req_sender);
+
+        // keepalive_transform does nothing:
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let mut mux_connector = MuxConnector::new(
+            mux_connector,
+            Some(legacy_connector),
+            keepalive_transform,
+            spawner.clone(),
+        );
+
+        let mut c_mux_connector = mux_connector.clone();
+        let fut_conn_pair = spawner
+            .spawn_with_handle(async move { await!(c_mux_connector.transform(15u32)) })
+            .unwrap();
+
+        // A single connection is opened to the relay:
+        let req = await!(mux_req_receiver.next()).unwrap();
+        assert_eq!(req.address, 15u32);
+        let (local_sender, relay_receiver) = mpsc::channel(0);
+        let (relay_sender, local_receiver) = mpsc::channel(0);
+        req.reply(Some((local_sender, local_receiver)));
+
+        let (accepted_sender, mut accepted_receiver) = mpsc::channel(0);
+        spawner
+            .spawn(
+                mux_server_loop(
+                    (relay_sender, relay_receiver),
+                    accepted_sender,
+                    8,
+                    spawner.clone(),
+                )
+                .map(|_| ()),
+            )
+            .unwrap();
+
+        let (mut a_sender, _a_receiver) = await!(fut_conn_pair).unwrap();
+        let (_b_sender, mut b_receiver) = await!(accepted_receiver.next()).unwrap();
+        await!(a_sender.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(b_receiver.next()).unwrap(), vec![1, 2, 3]);
+
+        // The second connection reuses the same session:
+        let (_c_sender, mut c_receiver) = await!(mux_connector.transform(15u32)).unwrap();
+        let (mut d_sender, _d_receiver) = await!(accepted_receiver.next()).unwrap();
+        await!(d_sender.send(vec![4, 5])).unwrap();
+        assert_eq!(await!(c_receiver.next()).unwrap(), vec![4, 5]);
+    }
+
+    #[test]
+    fn test_mux_connector_basic() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_mux_connector_basic(thread_pool.clone()));
+    }
+
+    async fn task_mux_connector_fallback<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + Sync + 'static,
+    {
+        let (mux_req_sender, mut mux_req_receiver) = mpsc::channel(0);
+        let mux_connector = DummyConnector::new(mux_req_sender);
+        let (legacy_req_sender, mut legacy_req_receiver) = mpsc::channel(0);
+        let legacy_connector = DummyConnector::new(legacy_req_sender);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let mux_connector = MuxConnector::new(
+            mux_connector,
+            Some(legacy_connector),
+            keepalive_transform,
+            spawner.clone(),
+        );
+
+        let mut c_mux_connector = mux_connector.clone();
+        let fut_conn_pair = spawner
+            .spawn_with_handle(async move { await!(c_mux_connector.transform(15u32)) })
+            .unwrap();
+
+        // The relay does not support multiplexing:
+        let req = await!(mux_req_receiver.next()).unwrap();
+        req.reply(None);
+
+        let req = await!(legacy_req_receiver.next()).unwrap();
+        assert_eq!(req.address, 15u32);
+        let (local_sender, _relay_receiver) = mpsc::channel(0);
+        let (mut relay_sender, local_receiver) = mpsc::channel(0);
+        req.reply(Some((local_sender, local_receiver)));
+
+        let (_sender, mut receiver) = await!(fut_conn_pair).unwrap();
+        await!(relay_sender.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(receiver.next()).unwrap(), vec![1, 2, 3]);
+
+        // We remember that the relay does not support multiplexing:
+        for _ in 0..LEGACY_CONNS_BEFORE_RETRY {
+            let mut c_mux_connector = mux_connector.clone();
+            let fut_conn_pair = spawner
+                .spawn_with_handle(async move { await!(c_mux_connector.transform(15u32)) })
+                .unwrap();
+
+            let req = await!(legacy_req_receiver.next()).unwrap();
+            assert_eq!(req.address, 15u32);
+            let (local_sender, _relay_receiver) = mpsc::channel(0);
+            let (_relay_sender, local_receiver) = mpsc::channel(0);
+            req.reply(Some((local_sender, local_receiver)));
+            assert!(await!(fut_conn_pair).is_some());
+        }
+
+        // A multiplexed session is attempted again:
+        let mut c_mux_connector = mux_connector.clone();
+        let fut_conn_pair = spawner
+            .spawn_with_handle(async move { await!(c_mux_connector.transform(15u32)) })
+            .unwrap();
+
+        let req = await!(mux_req_receiver.next()).unwrap();
+        assert_eq!(req.address, 15u32);
+        req.reply(None);
+
+        let req = await!(legacy_req_receiver.next()).unwrap();
+        let (local_sender, _relay_receiver) = mpsc::channel(0);
+        let (_relay_sender, local_receiver) = mpsc::channel(0);
+        req.reply(Some((local_sender, local_receiver)));
+        assert!(await!(fut_conn_pair).is_some());
+    }
+
+    #[test]
+    fn test_mux_connector_fallback() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_mux_connector_fallback(thread_pool.clone()));
+    }
+
+    async fn task_mux_connector_no_legacy<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + Sync + 'static,
+    {
+        let (mux_req_sender, mut mux_req_receiver) = mpsc::channel(0);
+        let mux_connector = DummyConnector::new(mux_req_sender);
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let opt_legacy_connector: Option<DummyConnector<u32, Option<ConnPairVec>>> = None;
+        let mut mux_connector = MuxConnector::new(
+            mux_connector,
+            opt_legacy_connector,
+            keepalive_transform,
+            spawner.clone(),
+        );
+
+        let fut_conn_pair = spawner
+            .spawn_with_handle(async move { await!(mux_connector.transform(15u32)) })
+            .unwrap();
+
+        // The relay does not support multiplexing, and we don't fall back:
+        let req = await!(mux_req_receiver.next()).unwrap();
+        req.reply(None);
+        assert!(await!(fut_conn_pair).is_none());
+    }
+
+    #[test]
+    fn test_mux_connector_no_legacy() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_mux_connector_no_legacy(thread_pool.clone()));
+    }
+}
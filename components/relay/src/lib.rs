@@ -20,8 +20,10 @@ extern crate log;
 extern crate common;
 
 mod client;
+mod mux;
 mod server;
 
 pub use self::client::client_connector::ClientConnector;
 pub use self::client::client_listener::ClientListener;
-pub use self::server::net_server::{net_relay_server, NetRelayServerError};
+pub use self::client::mux_connector::MuxConnector;
+pub use self::server::net_server::{net_relay_server, NetRelayServerError, RelayAcl, RelayQuotas};
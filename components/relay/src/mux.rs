@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::marker::Unpin;
+
+use futures::channel::{mpsc, oneshot};
+use futures::task::{Spawn, SpawnExt};
+use futures::{future, stream, SinkExt, Stream, StreamExt};
+
+use common::conn::ConnPairVec;
+use common::int_convert::{u32_to_usize, usize_to_u32};
+use common::select_streams::{select_streams, BoxStream};
+
+use proto::consts::RELAY_MUX_WINDOW;
+use proto::relay::messages::{MuxCredit, MuxData, MuxMessage};
+use proto::relay::serialize::{deserialize_mux_message, serialize_mux_message};
+
+#[derive(Debug)]
+pub enum MuxError {
+    SendToRemoteError,
+    SpawnError,
+}
+
+enum MuxEvent {
+    RemoteMessage(MuxMessage),
+    RemoteClosed,
+    /// A request to open a new tunnel
+    OpenRequest(oneshot::Sender<ConnPairVec>),
+    /// A message sent by the user through a tunnel
+    UserMessage((u32, Vec<u8>)),
+    /// The user consumed messages received through a tunnel. The remote side may send more.
+    UserCredit((u32, u32)),
+    /// The user closed a tunnel
+    UserClosed(u32),
+}
+
+struct MuxTunnel {
+    /// Messages received from the remote side, waiting to be consumed by the user
+    from_remote_sender: mpsc::Sender<Vec<u8>>,
+    /// Credit granted by the remote side
+    credit_sender: mpsc::Sender<usize>,
+}
+
+/// Forward messages sent by the user to the remote side, as long as we have credit.
+async fn tunnel_send_loop(
+    tunnel_id: u32,
+    mut from_user: mpsc::Receiver<Vec<u8>>,
+    mut credit_receiver: mpsc::Receiver<usize>,
+    mut event_sender: mpsc::Sender<MuxEvent>,
+) {
+    let mut credit = RELAY_MUX_WINDOW;
+    while let Some(data) = await!(from_user.next()) {
+        while credit == 0 {
+            match await!(credit_receiver.next()) {
+                Some(amount) => credit = credit.saturating_add(amount),
+                // The tunnel was closed:
+                None => return,
+            }
+        }
+        credit -= 1;
+        if await!(event_sender.send(MuxEvent::UserMessage((tunnel_id, data)))).is_err() {
+            return;
+        }
+    }
+    let _ = await!(event_sender.send(MuxEvent::UserClosed(tunnel_id)));
+}
+
+/// Forward messages received from the remote side to the user, and grant the remote side more
+/// credit as the user consumes messages.
+async fn tunnel_recv_loop(
+    tunnel_id: u32,
+    mut from_remote: mpsc::Receiver<Vec<u8>>,
+    mut to_user: mpsc::Sender<Vec<u8>>,
+    mut event_sender: mpsc::Sender<MuxEvent>,
+) {
+    let mut consumed: usize = 0;
+    while let Some(data) = await!(from_remote.next()) {
+        if await!(to_user.send(data)).is_err() {
+            let _ = await!(event_sender.send(MuxEvent::UserClosed(tunnel_id)));
+            return;
+        }
+        consumed += 1;
+        if consumed >= RELAY_MUX_WINDOW / 2 {
+            let amount = usize_to_u32(consumed).unwrap();
+            if await!(event_sender.send(MuxEvent::UserCredit((tunnel_id, amount)))).is_err() {
+                return;
+            }
+            consumed = 0;
+        }
+    }
+}
+
+struct Mux<S> {
+    tunnels: HashMap<u32, MuxTunnel>,
+    next_tunnel_id: u32,
+    to_remote: mpsc::Sender<Vec<u8>>,
+    event_sender: mpsc::Sender<MuxEvent>,
+    spawner: S,
+}
+
+impl<S> Mux<S>
+where
+    S: Spawn,
+{
+    async fn send_to_remote(&mut self, mux_message: MuxMessage) -> Result<(), MuxError> {
+        await!(self.to_remote.send(serialize_mux_message(&mux_message)))
+            .map_err(|_| MuxError::SendToRemoteError)
+    }
+
+    /// Create a new tunnel, and return its user side.
+    fn create_tunnel(&mut self, tunnel_id: u32) -> Result<ConnPairVec, MuxError> {
+        let (user_sender, from_user) = mpsc::channel(0);
+        let (to_user, user_receiver) = mpsc::channel(0);
+        // An honest remote side never exceeds the amount of credit it was granted:
+        let (from_remote_sender, from_remote) = mpsc::channel(RELAY_MUX_WINDOW);
+        let (credit_sender, credit_receiver) = mpsc::channel(RELAY_MUX_WINDOW);
+
+        self.spawner
+            .spawn(tunnel_send_loop(
+                tunnel_id,
+                from_user,
+                credit_receiver,
+                self.event_sender.clone(),
+            ))
+            .map_err(|_| MuxError::SpawnError)?;
+        self.spawner
+            .spawn(tunnel_recv_loop(
+                tunnel_id,
+                from_remote,
+                to_user,
+                self.event_sender.clone(),
+            ))
+            .map_err(|_| MuxError::SpawnError)?;
+
+        self.tunnels.insert(
+            tunnel_id,
+            MuxTunnel {
+                from_remote_sender,
+                credit_sender,
+            },
+        );
+        Ok((user_sender, user_receiver))
+    }
+
+    /// Allocate an id for a new tunnel opened by the local side.
+    fn alloc_tunnel_id(&mut self) -> u32 {
+        let mut tunnel_id = self.next_tunnel_id;
+        while self.tunnels.contains_key(&tunnel_id) {
+            tunnel_id = tunnel_id.wrapping_add(1);
+        }
+        self.next_tunnel_id = tunnel_id.wrapping_add(1);
+        tunnel_id
+    }
+
+    /// Close a tunnel, and let the remote side know about it.
+    async fn close_tunnel(&mut self, tunnel_id: u32) -> Result<(), MuxError> {
+        if self.tunnels.remove(&tunnel_id).is_some() {
+            await!(self.send_to_remote(MuxMessage::Close(tunnel_id)))?;
+        }
+        Ok(())
+    }
+}
+
+async fn mux_loop<OR, S>(
+    conn_pair: ConnPairVec,
+    incoming_open_requests: OR,
+    mut opt_accepted_sender: Option<mpsc::Sender<ConnPairVec>>,
+    max_tunnels: usize,
+    close_when_idle: bool,
+    spawner: S,
+) -> Result<(), MuxError>
+where
+    OR: Stream<Item = oneshot::Sender<ConnPairVec>> + Unpin + Send + 'static,
+    S: Spawn,
+{
+    let (to_remote, from_remote) = conn_pair;
+    let (event_sender, event_receiver) = mpsc::channel(0);
+
+    let mut mux = Mux {
+        tunnels: HashMap::new(),
+        next_tunnel_id: 0,
+        to_remote,
+        event_sender,
+        spawner,
+    };
+
+    let from_remote = from_remote
+        .map(|data| match deserialize_mux_message(&data) {
+            Ok(mux_message) => Some(MuxEvent::RemoteMessage(mux_message)),
+            Err(e) => {
+                error!("mux_loop(): Error deserializing mux message: {:?}", e);
+                None
+            }
+        })
+        .take_while(|opt_event| future::ready(opt_event.is_some()))
+        .map(Option::unwrap)
+        .chain(stream::once(future::ready(MuxEvent::RemoteClosed)));
+
+    let incoming_open_requests = incoming_open_requests.map(MuxEvent::OpenRequest);
+
+    let mut events = select_streams![event_receiver, from_remote, incoming_open_requests];
+
+    while let Some(event) = await!(events.next()) {
+        match event {
+            MuxEvent::RemoteMessage(MuxMessage::Open(tunnel_id)) => {
+                if opt_accepted_sender.is_none()
+                    || mux.tunnels.contains_key(&tunnel_id)
+                    || mux.tunnels.len() >= max_tunnels
+                {
+                    warn!("mux_loop(): Refusing to open tunnel {}", tunnel_id);
+                    await!(mux.send_to_remote(MuxMessage::Close(tunnel_id)))?;
+                    continue;
+                }
+                let conn_pair = mux.create_tunnel(tunnel_id)?;
+                // We never wait for the user to take the tunnel, to avoid blocking the other
+                // tunnels of the session:
+                let is_accepted = match &mut opt_accepted_sender {
+                    Some(accepted_sender) => match accepted_sender.try_send(conn_pair) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            if e.is_disconnected() {
+                                // We do not accept new tunnels anymore:
+                                opt_accepted_sender = None;
+                            } else {
+                                warn!(
+                                    "mux_loop(): Too many pending tunnels. Closing {}",
+                                    tunnel_id
+                                );
+                            }
+                            false
+                        }
+                    },
+                    None => false,
+                };
+                if !is_accepted {
+                    await!(mux.close_tunnel(tunnel_id))?;
+                }
+            }
+            MuxEvent::RemoteMessage(MuxMessage::Data(mux_data)) => {
+                let MuxData { tunnel_id, data } = mux_data;
+                let is_sent = match mux.tunnels.get_mut(&tunnel_id) {
+                    Some(tunnel) => tunnel.from_remote_sender.try_send(data).is_ok(),
+                    // The tunnel might have been closed recently:
+                    None => continue,
+                };
+                if !is_sent {
+                    // The user is gone, or the remote side exceeded its credit:
+                    await!(mux.close_tunnel(tunnel_id))?;
+                }
+            }
+            MuxEvent::RemoteMessage(MuxMessage::Credit(mux_credit)) => {
+                let MuxCredit { tunnel_id, amount } = mux_credit;
+                let is_sent = match mux.tunnels.get_mut(&tunnel_id) {
+                    Some(tunnel) => tunnel
+                        .credit_sender
+                        .try_send(u32_to_usize(amount).unwrap())
+                        .is_ok(),
+                    None => continue,
+                };
+                if !is_sent {
+                    await!(mux.close_tunnel(tunnel_id))?;
+                }
+            }
+            MuxEvent::RemoteMessage(MuxMessage::Close(tunnel_id)) => {
+                mux.tunnels.remove(&tunnel_id);
+            }
+            MuxEvent::RemoteClosed => break,
+            MuxEvent::OpenRequest(conn_sender) => {
+                let tunnel_id = mux.alloc_tunnel_id();
+                let conn_pair = mux.create_tunnel(tunnel_id)?;
+                await!(mux.send_to_remote(MuxMessage::Open(tunnel_id)))?;
+                // If the requester is gone, the tunnel will be closed by the tunnel tasks:
+                let _ = conn_sender.send(conn_pair);
+            }
+            MuxEvent::UserMessage((tunnel_id, data)) => {
+                if mux.tunnels.contains_key(&tunnel_id) {
+                    await!(mux.send_to_remote(MuxMessage::Data(MuxData { tunnel_id, data })))?;
+                }
+            }
+            MuxEvent::UserCredit((tunnel_id, amount)) => {
+                if mux.tunnels.contains_key(&tunnel_id) {
+                    let mux_credit = MuxCredit { tunnel_id, amount };
+                    await!(mux.send_to_remote(MuxMessage::Credit(mux_credit)))?;
+                }
+            }
+            MuxEvent::UserClosed(tunnel_id) => await!(mux.close_tunnel(tunnel_id))?,
+        }
+
+        if close_when_idle && mux.tunnels.is_empty() && mux.next_tunnel_id > 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Run the client side of a multiplexed relay connection.
+/// Every open request is answered with a new tunnel. Returns when the connection is closed, or
+/// when the last tunnel is closed.
+pub async fn mux_client_loop<OR, S>(
+    conn_pair: ConnPairVec,
+    incoming_open_requests: OR,
+    spawner: S,
+) -> Result<(), MuxError>
+where
+    OR: Stream<Item = oneshot::Sender<ConnPairVec>> + Unpin + Send + 'static,
+    S: Spawn,
+{
+    await!(mux_loop(
+        conn_pair,
+        incoming_open_requests,
+        None,
+        usize::max_value(),
+        true,
+        spawner
+    ))
+}
+
+/// Run the relay side of a multiplexed relay connection.
+/// Tunnels opened by the client are sent through `accepted_sender`. At most `max_tunnels` tunnels
+/// may be open at the same time.
+/// Tunnels are handed over without waiting: If `accepted_sender` is full, the new tunnel is
+/// closed.
+pub async fn mux_server_loop<S>(
+    conn_pair: ConnPairVec,
+    accepted_sender: mpsc::Sender<ConnPairVec>,
+    max_tunnels: usize,
+    spawner: S,
+) -> Result<(), MuxError>
+where
+    S: Spawn,
+{
+    await!(mux_loop(
+        conn_pair,
+        stream::empty(),
+        Some(accepted_sender),
+        max_tunnels,
+        false,
+        spawner
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::ThreadPool;
+    use futures::FutureExt;
+
+    /// Spawn a client and a server mux loops over a pair of channels.
+    /// Returns a sender of open requests, and a receiver of tunnels accepted by the server.
+    fn spawn_mux_pair<S>(
+        max_tunnels: usize,
+        mut spawner: S,
+    ) -> (
+        mpsc::Sender<oneshot::Sender<ConnPairVec>>,
+        mpsc::Receiver<ConnPairVec>,
+    )
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (client_sender, server_receiver) = mpsc::channel(0);
+        let (server_sender, client_receiver) = mpsc::channel(0);
+
+        let (open_sender, incoming_open_requests) = mpsc::channel(0);
+        let client_fut = mux_client_loop(
+            (client_sender, client_receiver),
+            incoming_open_requests,
+            spawner.clone(),
+        );
+        spawner.spawn(client_fut.map(|_| ())).unwrap();
+
+        let (accepted_sender, accepted_receiver) = mpsc::channel(0);
+        let server_fut = mux_server_loop(
+            (server_sender, server_receiver),
+            accepted_sender,
+            max_tunnels,
+            spawner.clone(),
+        );
+        spawner.spawn(server_fut.map(|_| ())).unwrap();
+
+        (open_sender, accepted_receiver)
+    }
+
+    async fn open_tunnel(
+        open_sender: &mut mpsc::Sender<oneshot::Sender<ConnPairVec>>,
+    ) -> ConnPairVec {
+        let (conn_sender, conn_receiver) = oneshot::channel();
+        await!(open_sender.send(conn_sender)).unwrap();
+        await!(conn_receiver).unwrap()
+    }
+
+    async fn task_mux_basic<S>(spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (mut open_sender, mut accepted_receiver) = spawn_mux_pair(8, spawner);
+
+        let (mut a_sender, mut a_receiver) = await!(open_tunnel(&mut open_sender));
+        let (mut b_sender, mut b_receiver) = await!(accepted_receiver.next()).unwrap();
+
+        let (mut c_sender, mut c_receiver) = await!(open_tunnel(&mut open_sender));
+        let (mut d_sender, mut d_receiver) = await!(accepted_receiver.next()).unwrap();
+
+        // More messages than the window size go through, as long as they are consumed:
+        for i in 0..3 * RELAY_MUX_WINDOW {
+            let i = i as u8;
+            await!(a_sender.send(vec![i])).unwrap();
+            assert_eq!(await!(b_receiver.next()).unwrap(), vec![i]);
+
+            await!(d_sender.send(vec![i, i])).unwrap();
+            assert_eq!(await!(c_receiver.next()).unwrap(), vec![i, i]);
+        }
+
+        await!(b_sender.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(a_receiver.next()).unwrap(), vec![1, 2, 3]);
+
+        // Closing a tunnel does not affect the other tunnel:
+        drop(a_sender);
+        assert!(await!(b_receiver.next()).is_none());
+        drop(a_receiver);
+
+        await!(c_sender.send(vec![4, 5])).unwrap();
+        assert_eq!(await!(d_receiver.next()).unwrap(), vec![4, 5]);
+    }
+
+    #[test]
+    fn test_mux_basic() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_mux_basic(thread_pool.clone()));
+    }
+
+    async fn task_mux_max_tunnels<S>(spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (mut open_sender, mut accepted_receiver) = spawn_mux_pair(1, spawner);
+
+        let (_a_sender, _a_receiver) = await!(open_tunnel(&mut open_sender));
+        let (_b_sender, _b_receiver) = await!(accepted_receiver.next()).unwrap();
+
+        // The second tunnel is refused by the server:
+        let (_c_sender, mut c_receiver) = await!(open_tunnel(&mut open_sender));
+        assert!(await!(c_receiver.next()).is_none());
+    }
+
+    #[test]
+    fn test_mux_max_tunnels() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_mux_max_tunnels(thread_pool.clone()));
+    }
+
+    async fn task_mux_pending_tunnels<S>(spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (mut open_sender, mut accepted_receiver) = spawn_mux_pair(8, spawner);
+
+        let (mut a_sender, _a_receiver) = await!(open_tunnel(&mut open_sender));
+        let (_b_sender, mut b_receiver) = await!(accepted_receiver.next()).unwrap();
+
+        // The server side does not take new tunnels. The first one waits in the channel:
+        let (_c_sender, _c_receiver) = await!(open_tunnel(&mut open_sender));
+
+        // The channel is full, so the next tunnel is refused:
+        let (_e_sender, mut e_receiver) = await!(open_tunnel(&mut open_sender));
+        assert!(await!(e_receiver.next()).is_none());
+
+        // Existing tunnels keep working:
+        await!(a_sender.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(b_receiver.next()).unwrap(), vec![1, 2, 3]);
+
+        // The pending tunnel can still be taken:
+        let (_d_sender, _d_receiver) = await!(accepted_receiver.next()).unwrap();
+    }
+
+    #[test]
+    fn test_mux_pending_tunnels() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_mux_pending_tunnels(thread_pool.clone()));
+    }
+}
@@ -0,0 +1,70 @@
+use common::access_control::AccessControl;
+use crypto::identity::PublicKey;
+
+type AccessControlPk = AccessControl<PublicKey>;
+
+/// Public keys allowed to use the relay.
+/// A missing access control list means that any public key is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct RelayAcl {
+    /// Public keys allowed to listen (And accept connections) on the relay
+    pub opt_listen: Option<AccessControlPk>,
+    /// Public keys allowed to connect to listening public keys
+    pub opt_connect: Option<AccessControlPk>,
+}
+
+impl RelayAcl {
+    pub fn is_listen_allowed(&self, public_key: &PublicKey) -> bool {
+        match &self.opt_listen {
+            Some(listen_acl) => listen_acl.is_allowed(public_key),
+            None => true,
+        }
+    }
+
+    pub fn is_connect_allowed(&self, public_key: &PublicKey) -> bool {
+        match &self.opt_connect {
+            Some(connect_acl) => connect_acl.is_allowed(public_key),
+            None => true,
+        }
+    }
+
+    /// Is this public key allowed to perform any operation on the relay?
+    pub fn is_any_allowed(&self, public_key: &PublicKey) -> bool {
+        self.is_listen_allowed(public_key) || self.is_connect_allowed(public_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::access_control::AccessControlOp;
+    use crypto::identity::PUBLIC_KEY_LEN;
+
+    #[test]
+    fn test_relay_acl() {
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let relay_acl = RelayAcl::default();
+        assert!(relay_acl.is_listen_allowed(&pk_a));
+        assert!(relay_acl.is_connect_allowed(&pk_a));
+
+        let mut listen_acl = AccessControl::new();
+        listen_acl.apply_op(AccessControlOp::Add(pk_a.clone()));
+        let relay_acl = RelayAcl {
+            opt_listen: Some(listen_acl),
+            opt_connect: None,
+        };
+        assert!(relay_acl.is_listen_allowed(&pk_a));
+        assert!(!relay_acl.is_listen_allowed(&pk_b));
+        assert!(relay_acl.is_connect_allowed(&pk_b));
+        assert!(relay_acl.is_any_allowed(&pk_b));
+
+        let relay_acl = RelayAcl {
+            opt_listen: relay_acl.opt_listen,
+            opt_connect: Some(AccessControl::new()),
+        };
+        assert!(relay_acl.is_any_allowed(&pk_a));
+        assert!(!relay_acl.is_any_allowed(&pk_b));
+    }
+}
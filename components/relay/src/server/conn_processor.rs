@@ -6,6 +6,7 @@ use futures::{future, Sink, SinkExt, Stream, StreamExt};
 use common::conn::{ConnPairVec, FutTransform};
 
 use crypto::identity::PublicKey;
+use proto::net::messages::NetAddress;
 use timer::utils::future_timeout;
 use timer::TimerClient;
 
@@ -21,6 +22,7 @@ async fn dispatch_conn<FT>(
     sender: mpsc::Sender<Vec<u8>>,
     receiver: mpsc::Receiver<Vec<u8>>,
     public_key: PublicKey,
+    opt_address: Option<NetAddress>,
     first_msg: Vec<u8>,
     mut keepalive_transform: FT,
 ) -> Option<
@@ -51,23 +53,46 @@ where
             receiver,
             sender,
             accept_public_key,
+            punch: false,
         }),
         InitConnection::Connect(connect_public_key) => {
             IncomingConnInner::Connect(IncomingConnect {
                 receiver,
                 sender,
                 connect_public_key,
+                punch: false,
+            })
+        }
+        InitConnection::AcceptPunch(accept_public_key) => {
+            IncomingConnInner::Accept(IncomingAccept {
+                receiver,
+                sender,
+                accept_public_key,
+                punch: true,
+            })
+        }
+        InitConnection::ConnectPunch(connect_public_key) => {
+            IncomingConnInner::Connect(IncomingConnect {
+                receiver,
+                sender,
+                connect_public_key,
+                punch: true,
             })
         }
     };
 
-    Some(IncomingConn { public_key, inner })
+    Some(IncomingConn {
+        public_key,
+        opt_address,
+        inner,
+    })
 }
 
 async fn process_conn<FT>(
     sender: mpsc::Sender<Vec<u8>>,
     mut receiver: mpsc::Receiver<Vec<u8>>,
     public_key: PublicKey,
+    opt_address: Option<NetAddress>,
     keepalive_transform: FT,
     mut timer_client: TimerClient,
     conn_timeout_ticks: usize,
@@ -91,6 +116,7 @@ where
                     sender,
                     receiver,
                     public_key,
+                    opt_address,
                     first_msg,
                     keepalive_transform
                 ));
@@ -120,11 +146,16 @@ where
 /// For each connection obtain the first message, and prepare the correct type according to this
 /// first messages.
 /// If waiting for the first message takes too long, discard the connection.
+/// Up to `max_concurrent` connections may wait for their first message at the same time, so that
+/// a slow connection does not delay the connections that arrive after it.
+/// Every incoming connection comes together with the address of the remote side, as observed by
+/// the relay (If known).
 pub fn conn_processor<T, FT>(
     incoming_conns: T,
     keepalive_transform: FT,
     timer_client: TimerClient,
     conn_timeout_ticks: usize,
+    max_concurrent: usize,
 ) -> impl Stream<
     Item = IncomingConn<
         impl Stream<Item = RejectConnection>,
@@ -136,21 +167,23 @@ pub fn conn_processor<T, FT>(
     >,
 >
 where
-    T: Stream<Item = (PublicKey, ConnPairVec)> + Unpin,
+    T: Stream<Item = (PublicKey, Option<NetAddress>, ConnPairVec)> + Unpin,
     FT: FutTransform<Input = ConnPairVec, Output = ConnPairVec> + Clone,
 {
     incoming_conns
-        .map(move |(public_key, (sender, receiver))| {
+        .map(move |(public_key, opt_address, (sender, receiver))| {
             process_conn(
                 sender,
                 receiver,
                 public_key,
+                opt_address,
                 keepalive_transform.clone(),
                 timer_client.clone(),
                 conn_timeout_ticks,
             )
         })
-        .filter_map(|opt_conn| opt_conn)
+        .buffer_unordered(max_concurrent)
+        .filter_map(future::ready)
 }
 
 #[cfg(test)]
@@ -161,6 +194,7 @@ mod tests {
     use futures::executor::ThreadPool;
     use futures::task::{Spawn, SpawnExt};
     use futures::{stream, FutureExt};
+    use std::convert::TryInto;
 
     use common::async_test_utils::receive;
     use common::conn::FuncFutTransform;
@@ -183,6 +217,7 @@ mod tests {
             sender,
             receiver,
             public_key.clone(),
+            None,
             ser_first_msg,
             keepalive_transform
         ))
@@ -204,6 +239,7 @@ mod tests {
             sender,
             receiver,
             public_key.clone(),
+            None,
             ser_first_msg,
             keepalive_transform
         ))
@@ -227,6 +263,7 @@ mod tests {
             sender,
             receiver,
             public_key.clone(),
+            None,
             ser_first_msg,
             keepalive_transform
         ))
@@ -235,7 +272,35 @@ mod tests {
         assert_eq!(incoming_conn.public_key, public_key);
         match incoming_conn.inner {
             IncomingConnInner::Connect(incoming_connect) => {
-                assert_eq!(incoming_connect.connect_public_key, connect_public_key)
+                assert_eq!(incoming_connect.connect_public_key, connect_public_key);
+                assert!(!incoming_connect.punch);
+            }
+            _ => panic!("Wrong IncomingConnInner"),
+        };
+
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>(0);
+        let connect_public_key = PublicKey::from(&[0x44; PUBLIC_KEY_LEN]);
+        let first_msg = InitConnection::ConnectPunch(connect_public_key.clone());
+        let ser_first_msg = serialize_init_connection(&first_msg);
+        let public_key = PublicKey::from(&[0x77; PUBLIC_KEY_LEN]);
+        let address: NetAddress = "1.2.3.4:1337".to_owned().try_into().unwrap();
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+        let incoming_conn = await!(dispatch_conn(
+            sender,
+            receiver,
+            public_key.clone(),
+            Some(address.clone()),
+            ser_first_msg,
+            keepalive_transform
+        ))
+        .unwrap();
+
+        assert_eq!(incoming_conn.public_key, public_key);
+        assert_eq!(incoming_conn.opt_address, Some(address));
+        match incoming_conn.inner {
+            IncomingConnInner::Connect(incoming_connect) => {
+                assert_eq!(incoming_connect.connect_public_key, connect_public_key);
+                assert!(incoming_connect.punch);
             }
             _ => panic!("Wrong IncomingConnInner"),
         };
@@ -260,6 +325,7 @@ mod tests {
             sender,
             receiver,
             public_key.clone(),
+            None,
             ser_first_msg,
             keepalive_transform
         ));
@@ -284,8 +350,11 @@ mod tests {
         let (local_sender, _remote_receiver) = mpsc::channel::<Vec<u8>>(0);
         let (mut remote_sender, local_receiver) = mpsc::channel::<Vec<u8>>(0);
 
-        let incoming_conns =
-            stream::iter::<_>(vec![(public_key.clone(), (local_sender, local_receiver))]);
+        let incoming_conns = stream::iter::<_>(vec![(
+            public_key.clone(),
+            None,
+            (local_sender, local_receiver),
+        )]);
 
         let conn_timeout_ticks = 16;
         let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
@@ -295,6 +364,7 @@ mod tests {
             keepalive_transform,
             timer_client,
             conn_timeout_ticks,
+            8,
         );
 
         let processed_conns = Box::pin(processed_conns);
@@ -323,4 +393,62 @@ mod tests {
 
         assert!(thread_pool.run(receive(processed_conns)).is_none());
     }
+
+    #[test]
+    fn test_conn_processor_concurrent() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+
+        // Create a mock time service:
+        let (_tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, thread_pool.clone()).unwrap();
+
+        // The first connection never sends its first message:
+        let public_key_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let (local_sender_a, _remote_receiver_a) = mpsc::channel::<Vec<u8>>(0);
+        let (_remote_sender_a, local_receiver_a) = mpsc::channel::<Vec<u8>>(0);
+
+        let public_key_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let (local_sender_b, _remote_receiver_b) = mpsc::channel::<Vec<u8>>(0);
+        let (mut remote_sender_b, local_receiver_b) = mpsc::channel::<Vec<u8>>(0);
+
+        let incoming_conns = stream::iter::<_>(vec![
+            (
+                public_key_a.clone(),
+                None,
+                (local_sender_a, local_receiver_a),
+            ),
+            (
+                public_key_b.clone(),
+                None,
+                (local_sender_b, local_receiver_b),
+            ),
+        ]);
+
+        let conn_timeout_ticks = 16;
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        let processed_conns = conn_processor(
+            incoming_conns,
+            keepalive_transform,
+            timer_client,
+            conn_timeout_ticks,
+            8,
+        );
+
+        let processed_conns = Box::pin(processed_conns);
+
+        let first_msg = InitConnection::Listen;
+        let ser_first_msg = serialize_init_connection(&first_msg);
+        thread_pool
+            .spawn(
+                async move {
+                    let _ = await!(remote_sender_b.send(ser_first_msg));
+                },
+            )
+            .unwrap();
+
+        // The second connection is not delayed by the first one:
+        let (conn, _processed_conns) = thread_pool.run(receive(processed_conns)).unwrap();
+        assert_eq!(conn.public_key, public_key_b);
+    }
 }
@@ -1,5 +1,9 @@
+mod acl;
 mod conn_limiter;
 mod conn_processor;
+mod mux_sessions;
 pub mod net_server;
+mod quota;
 mod server;
+mod stats;
 mod types;
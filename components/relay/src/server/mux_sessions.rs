@@ -0,0 +1,212 @@
+use std::marker::Unpin;
+
+use futures::channel::mpsc;
+use futures::task::{Spawn, SpawnExt};
+use futures::{future, stream, FutureExt, SinkExt, Stream, StreamExt, TryFutureExt};
+
+use common::conn::{ConnPairVec, FutTransform};
+use common::select_streams::{select_streams, BoxStream};
+
+use crypto::identity::PublicKey;
+
+use proto::consts::RELAY_MUX_PROTOCOL_VERSION;
+use proto::net::messages::NetAddress;
+
+use crate::mux::mux_server_loop;
+
+#[derive(Debug)]
+pub enum MuxSessionsError {
+    SpawnError,
+}
+
+enum MuxSessionsEvent {
+    Conn((u32, PublicKey, Option<NetAddress>, ConnPairVec)),
+    ConnsClosed,
+    SessionTunnel((PublicKey, Option<NetAddress>, ConnPairVec)),
+}
+
+/// Split incoming multiplexed sessions into separate connections.
+///
+/// Every incoming connection comes together with the protocol version declared by the client,
+/// and the address of the client as observed by the relay (If known).
+/// Connections using `RELAY_MUX_PROTOCOL_VERSION` are multiplexed sessions: Every tunnel opened
+/// by the client inside the session is forwarded to `conns_sender` as a separate connection.
+/// Other connections are forwarded as is.
+/// A single session may open at most `max_session_tunnels` tunnels at the same time.
+///
+/// Returns once `incoming_conns` is closed. Running sessions are not affected.
+pub async fn mux_sessions_loop<IC, KT, S>(
+    incoming_conns: IC,
+    mut conns_sender: mpsc::Sender<(PublicKey, Option<NetAddress>, ConnPairVec)>,
+    mut keepalive_transform: KT,
+    max_session_tunnels: usize,
+    mut spawner: S,
+) -> Result<(), MuxSessionsError>
+where
+    IC: Stream<Item = (u32, PublicKey, Option<NetAddress>, ConnPairVec)> + Unpin + Send + 'static,
+    KT: FutTransform<Input = ConnPairVec, Output = ConnPairVec>,
+    S: Spawn + Clone + Send + 'static,
+{
+    let (session_tunnels_sender, session_tunnels_receiver) = mpsc::channel(0);
+
+    let incoming_conns = incoming_conns
+        .map(MuxSessionsEvent::Conn)
+        .chain(stream::once(future::ready(MuxSessionsEvent::ConnsClosed)));
+    let session_tunnels_receiver = session_tunnels_receiver.map(MuxSessionsEvent::SessionTunnel);
+
+    let mut events = select_streams![incoming_conns, session_tunnels_receiver];
+
+    while let Some(event) = await!(events.next()) {
+        match event {
+            MuxSessionsEvent::Conn((version, public_key, opt_address, conn_pair)) => {
+                if version != RELAY_MUX_PROTOCOL_VERSION {
+                    if await!(conns_sender.send((public_key, opt_address, conn_pair))).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+
+                let conn_pair = await!(keepalive_transform.transform(conn_pair));
+                // Room for all the tunnels of the session, so that opening a tunnel never blocks
+                // the session:
+                let (accepted_sender, accepted_receiver) = mpsc::channel(max_session_tunnels);
+                let session_fut = mux_server_loop(
+                    conn_pair,
+                    accepted_sender,
+                    max_session_tunnels,
+                    spawner.clone(),
+                )
+                .map_err(|e| warn!("mux_server_loop() error: {:?}", e))
+                .map(|_| ());
+                spawner
+                    .spawn(session_fut)
+                    .map_err(|_| MuxSessionsError::SpawnError)?;
+
+                // Attach the public key and address of the session to every tunnel:
+                let mut session_tunnels = accepted_receiver
+                    .map(move |conn_pair| (public_key.clone(), opt_address.clone(), conn_pair));
+                let mut c_session_tunnels_sender = session_tunnels_sender.clone();
+                let forward_fut = async move {
+                    let _ = await!(c_session_tunnels_sender.send_all(&mut session_tunnels));
+                };
+                spawner
+                    .spawn(forward_fut)
+                    .map_err(|_| MuxSessionsError::SpawnError)?;
+            }
+            MuxSessionsEvent::ConnsClosed => break,
+            MuxSessionsEvent::SessionTunnel(conn) => {
+                if await!(conns_sender.send(conn)).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor::ThreadPool;
+    use std::convert::TryInto;
+
+    use common::conn::FuncFutTransform;
+    use crypto::identity::PUBLIC_KEY_LEN;
+    use proto::consts::PROTOCOL_VERSION;
+
+    use crate::mux::mux_client_loop;
+
+    async fn task_mux_sessions_loop_basic<S>(mut spawner: S)
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (mut incoming_sender, incoming_conns) = mpsc::channel(0);
+        let (conns_sender, mut conns_receiver) = mpsc::channel(0);
+        // keepalive_transform does nothing:
+        let keepalive_transform = FuncFutTransform::new(|x| Box::pin(future::ready(x)));
+
+        spawner
+            .spawn(
+                mux_sessions_loop(
+                    incoming_conns,
+                    conns_sender,
+                    keepalive_transform,
+                    8,
+                    spawner.clone(),
+                )
+                .map(|_| ()),
+            )
+            .unwrap();
+
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let address_b: NetAddress = "1.2.3.4:1337".to_owned().try_into().unwrap();
+
+        // A legacy connection is forwarded as is:
+        let (mut a_sender, relay_a_receiver) = mpsc::channel(0);
+        let (relay_a_sender, _a_receiver) = mpsc::channel(0);
+        await!(incoming_sender.send((
+            PROTOCOL_VERSION,
+            pk_a.clone(),
+            None,
+            (relay_a_sender, relay_a_receiver)
+        )))
+        .unwrap();
+        let (public_key, opt_address, (_sender, mut receiver)) =
+            await!(conns_receiver.next()).unwrap();
+        assert_eq!(public_key, pk_a);
+        assert_eq!(opt_address, None);
+        await!(a_sender.send(vec![1])).unwrap();
+        assert_eq!(await!(receiver.next()).unwrap(), vec![1]);
+
+        // Tunnels of a multiplexed session are forwarded as separate connections:
+        let (b_sender, relay_b_receiver) = mpsc::channel(0);
+        let (relay_b_sender, b_receiver) = mpsc::channel(0);
+        await!(incoming_sender.send((
+            RELAY_MUX_PROTOCOL_VERSION,
+            pk_b.clone(),
+            Some(address_b.clone()),
+            (relay_b_sender, relay_b_receiver)
+        )))
+        .unwrap();
+
+        let (mut open_sender, incoming_open_requests) = mpsc::channel(0);
+        spawner
+            .spawn(
+                mux_client_loop(
+                    (b_sender, b_receiver),
+                    incoming_open_requests,
+                    spawner.clone(),
+                )
+                .map(|_| ()),
+            )
+            .unwrap();
+
+        // Keep the tunnels open, so that the session is not closed:
+        let mut tunnels = Vec::new();
+        for i in 0..2u8 {
+            let (conn_sender, conn_receiver) = oneshot::channel();
+            await!(open_sender.send(conn_sender)).unwrap();
+            let (mut tunnel_sender, tunnel_receiver) = await!(conn_receiver).unwrap();
+
+            let (public_key, opt_address, (sender, mut receiver)) =
+                await!(conns_receiver.next()).unwrap();
+            assert_eq!(public_key, pk_b);
+            assert_eq!(opt_address, Some(address_b.clone()));
+            await!(tunnel_sender.send(vec![i])).unwrap();
+            assert_eq!(await!(receiver.next()).unwrap(), vec![i]);
+            tunnels.push((tunnel_sender, tunnel_receiver, sender, receiver));
+        }
+
+        // Closing incoming connections stops the loop:
+        drop(incoming_sender);
+        assert!(await!(conns_receiver.next()).is_none());
+    }
+
+    #[test]
+    fn test_mux_sessions_loop_basic() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_mux_sessions_loop_basic(thread_pool.clone()));
+    }
+}
@@ -1,15 +1,21 @@
 use std::marker::Unpin;
 
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::task::{Spawn, SpawnExt};
-use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
+use futures::{FutureExt, SinkExt, Stream, StreamExt, TryFutureExt};
 
 use derive_more::*;
 
 use common::conn::{BoxFuture, ConnPairVec, FutTransform};
-use common::transform_pool::transform_pool_loop;
+use common::shutdown::until_shutdown;
+use common::transform_pool::report_transform_pool_loop;
 
-use proto::consts::{CONN_TIMEOUT_TICKS, KEEPALIVE_TICKS, PROTOCOL_VERSION, TICKS_TO_REKEY};
+use proto::net::messages::NetAddress;
+
+use proto::consts::{
+    CONN_TIMEOUT_TICKS, HYBRID_VERSION_FLAG, KEEPALIVE_TICKS, PROTOCOL_VERSION,
+    RELAY_MUX_PROTOCOL_VERSION, TICKS_TO_REKEY,
+};
 
 use crypto::crypto_rand::CryptoRandom;
 use crypto::identity::PublicKey;
@@ -18,30 +24,49 @@ use identity::IdentityClient;
 use keepalive::KeepAliveChannel;
 use timer::TimerClient;
 
-use secure_channel::SecureChannel;
-use version::VersionPrefix;
+use secure_channel::{HandshakeMode, SecureChannel};
+use version::VersionAccept;
 
+pub use super::acl::RelayAcl;
 use super::conn_processor::conn_processor;
+use super::mux_sessions::mux_sessions_loop;
+pub use super::quota::RelayQuotas;
 use super::server::relay_server_loop;
 pub use super::server::RelayServerError;
 
+/// Maximum amount of connections waiting for their first message at the same time
+const MAX_CONCURRENT_PROCESS: usize = 0x100;
+
 /// A relay server loop. Incoming connections should contain both (sender, receiver) and a
-/// public_key of the remote side (Should be obtained after authentication).
+/// public_key of the remote side (Should be obtained after authentication), together with the
+/// address of the remote side, if known.
 ///
 /// `conn_timeout_ticks` is the amount of time we are willing to wait for a connection to identify
 /// its purpose.
 /// `keepalive_ticks` is the amount of time we are willing to let the remote side to be idle before
 /// we disconnect. It is also used to timeout open half tunnels that were not claimed.
-async fn relay_server<IC, S>(
+/// `quotas` are the limits enforced on every client of the relay, and `acl` determines which
+/// public keys may listen or connect.
+/// If `opt_stats_ticks` is provided, relay statistics are logged every `stats_ticks` ticks.
+/// Connections rejected before reaching the relay server are reported through
+/// `incoming_rejections`, and are counted in the statistics.
+/// When `incoming_conns` is closed, we wait up to `drain_ticks` for open tunnels to close.
+async fn relay_server<IC, IR, S>(
     incoming_conns: IC,
+    incoming_rejections: IR,
     timer_client: TimerClient,
     conn_timeout_ticks: usize,
     keepalive_ticks: usize,
+    quotas: RelayQuotas,
+    acl: RelayAcl,
+    opt_stats_ticks: Option<usize>,
+    drain_ticks: usize,
     spawner: S,
 ) -> Result<(), RelayServerError>
 where
     S: Spawn + Clone + Send + 'static,
-    IC: Stream<Item = (PublicKey, ConnPairVec)> + Unpin + Send + 'static,
+    IC: Stream<Item = (PublicKey, Option<NetAddress>, ConnPairVec)> + Unpin + Send + 'static,
+    IR: Stream<Item = ()> + Unpin + Send,
 {
     let keepalive_transform =
         KeepAliveChannel::new(timer_client.clone(), keepalive_ticks, spawner.clone());
@@ -52,6 +77,7 @@ where
         keepalive_transform,
         timer_client.clone(),
         conn_timeout_ticks,
+        MAX_CONCURRENT_PROCESS,
     ));
 
     // TODO:
@@ -65,7 +91,13 @@ where
     await!(relay_server_loop(
         timer_client,
         processed_conns,
+        incoming_rejections,
         half_tunnel_ticks,
+        quotas,
+        acl,
+        opt_stats_ticks,
+        None,
+        drain_ticks,
         spawner
     ))
 }
@@ -76,73 +108,144 @@ pub enum NetRelayServerError {
     SpawnError,
 }
 
-/// Start a secure channel without knowing the identity of the remote
-/// side ahead of time.
+/// Accept the protocol version of the remote side, and then start a secure channel without
+/// knowing the identity of the remote side ahead of time.
+/// If the declared version has `HYBRID_VERSION_FLAG` set, `hybrid_encrypt_transform` is used
+/// instead of `encrypt_transform`.
+/// Returns the protocol version declared by the remote side (Without the hybrid flag) together
+/// with the connection. The observed address of the remote side is passed through as is.
 #[derive(Clone)]
 struct AnonSecureChannel<ET> {
+    version_accept: VersionAccept,
     encrypt_transform: ET,
+    hybrid_encrypt_transform: ET,
 }
 
 impl<ET> AnonSecureChannel<ET> {
-    pub fn new(encrypt_transform: ET) -> Self {
-        AnonSecureChannel { encrypt_transform }
+    pub fn new(
+        version_accept: VersionAccept,
+        encrypt_transform: ET,
+        hybrid_encrypt_transform: ET,
+    ) -> Self {
+        AnonSecureChannel {
+            version_accept,
+            encrypt_transform,
+            hybrid_encrypt_transform,
+        }
     }
 }
 
 impl<ET> FutTransform for AnonSecureChannel<ET>
 where
     ET: FutTransform<
-        Input = (Option<PublicKey>, ConnPairVec),
-        Output = Option<(PublicKey, ConnPairVec)>,
-    >,
+            Input = (Option<PublicKey>, ConnPairVec),
+            Output = Option<(PublicKey, ConnPairVec)>,
+        > + Send,
 {
-    type Input = ConnPairVec;
-    type Output = Option<(PublicKey, ConnPairVec)>;
+    type Input = (Option<NetAddress>, ConnPairVec);
+    type Output = Option<(u32, PublicKey, Option<NetAddress>, ConnPairVec)>;
 
-    fn transform(&mut self, conn_pair: Self::Input) -> BoxFuture<'_, Self::Output> {
-        self.encrypt_transform.transform((None, conn_pair))
+    fn transform(&mut self, input: Self::Input) -> BoxFuture<'_, Self::Output> {
+        let (opt_address, conn_pair) = input;
+        Box::pin(
+            async move {
+                let (version, conn_pair) = await!(self.version_accept.transform(conn_pair))?;
+                let (public_key, conn_pair) = if version & HYBRID_VERSION_FLAG != 0 {
+                    await!(self.hybrid_encrypt_transform.transform((None, conn_pair)))?
+                } else {
+                    await!(self.encrypt_transform.transform((None, conn_pair)))?
+                };
+                Some((
+                    version & !HYBRID_VERSION_FLAG,
+                    public_key,
+                    opt_address,
+                    conn_pair,
+                ))
+            },
+        )
     }
 }
 
+/// Run a relay server over raw network connections.
+///
+/// Once a shutdown is requested through `shutdown_receiver`, we stop accepting new connections,
+/// disconnect all listeners and wait up to `drain_ticks` ticks for open tunnels to close.
+///
+/// Clients may declare either `PROTOCOL_VERSION` or `RELAY_MUX_PROTOCOL_VERSION`. In the second
+/// case, the connection is a multiplexed session carrying many connections to the relay.
+/// Any of the two versions may be combined with `HYBRID_VERSION_FLAG` to request the hybrid
+/// (post-quantum) secure channel handshake.
+///
+/// Every incoming raw connection may come together with the address of the remote side, as
+/// observed by the relay. Clients asking to punch through NATs will be told the address of the
+/// other side of their tunnel.
+///
+/// The secure channel with every client is rekeyed after `bytes_to_rekey` bytes or
+/// `messages_to_rekey` messages were encrypted using the same key, whichever comes first.
 pub async fn net_relay_server<IRC, R, S>(
     incoming_raw_conns: IRC,
     identity_client: IdentityClient,
     timer_client: TimerClient,
     rng: R,
     max_concurrent_encrypt: usize,
+    bytes_to_rekey: u64,
+    messages_to_rekey: u64,
+    quotas: RelayQuotas,
+    acl: RelayAcl,
+    opt_stats_ticks: Option<usize>,
+    shutdown_receiver: oneshot::Receiver<()>,
+    drain_ticks: usize,
     mut spawner: S,
 ) -> Result<(), NetRelayServerError>
 where
-    IRC: Stream<Item = ConnPairVec> + Unpin + Send + 'static,
+    IRC: Stream<Item = (Option<NetAddress>, ConnPairVec)> + Unpin + Send + 'static,
     R: CryptoRandom + Clone + 'static,
     S: Spawn + Clone + Send + Sync + 'static,
 {
-    let version_transform = VersionPrefix::new(PROTOCOL_VERSION, spawner.clone());
+    let version_accept = VersionAccept::new(vec![
+        PROTOCOL_VERSION,
+        RELAY_MUX_PROTOCOL_VERSION,
+        PROTOCOL_VERSION | HYBRID_VERSION_FLAG,
+        RELAY_MUX_PROTOCOL_VERSION | HYBRID_VERSION_FLAG,
+    ]);
 
     let encrypt_transform = SecureChannel::new(
+        identity_client.clone(),
+        rng.clone(),
+        timer_client.clone(),
+        TICKS_TO_REKEY,
+        bytes_to_rekey,
+        messages_to_rekey,
+        HandshakeMode::Classic,
+        spawner.clone(),
+    );
+
+    let hybrid_encrypt_transform = SecureChannel::new(
         identity_client,
         rng,
         timer_client.clone(),
         TICKS_TO_REKEY,
+        bytes_to_rekey,
+        messages_to_rekey,
+        HandshakeMode::Hybrid,
         spawner.clone(),
     );
 
-    // TODO; How to get rid of Box::pin() here?
-    let incoming_ver_conns = Box::pin(incoming_raw_conns.then(move |raw_conn| {
-        // TODO: A more efficient way to do this?
-        // We seem to have to clone version_transform for every connection
-        // to make the borrow checker happy.
-        let mut c_version_transform = version_transform.clone();
-        async move { await!(c_version_transform.transform(raw_conn)) }
-    }));
+    // Stop accepting new connections once a shutdown is requested:
+    let incoming_raw_conns = until_shutdown(incoming_raw_conns, shutdown_receiver);
+
+    let (enc_conns_sender, incoming_enc_conns) =
+        mpsc::channel::<(u32, PublicKey, Option<NetAddress>, ConnPairVec)>(0);
 
-    let (enc_conns_sender, incoming_enc_conns) = mpsc::channel::<(PublicKey, ConnPairVec)>(0);
+    // Connections dropped before reaching the relay server are counted as rejected:
+    let (rejections_sender, incoming_rejections) = mpsc::channel::<()>(0);
 
-    let enc_pool_fut = transform_pool_loop(
-        incoming_ver_conns,
+    let enc_pool_fut = report_transform_pool_loop(
+        incoming_raw_conns,
         enc_conns_sender,
-        AnonSecureChannel::new(encrypt_transform),
+        AnonSecureChannel::new(version_accept, encrypt_transform, hybrid_encrypt_transform),
         max_concurrent_encrypt,
+        Some(rejections_sender.clone()),
         spawner.clone(),
     )
     .map_err(|e| error!("transform_pool_loop() error: {:?}", e))
@@ -152,11 +255,49 @@ where
         .spawn(enc_pool_fut)
         .map_err(|_| NetRelayServerError::SpawnError)?;
 
-    await!(relay_server(
+    // Reject public keys that are not allowed to use the relay right after the handshake:
+    let c_acl = acl.clone();
+    let incoming_enc_conns =
+        incoming_enc_conns.filter(move |(_version, public_key, _opt_address, _conn_pair)| {
+            let is_allowed = c_acl.is_any_allowed(public_key);
+            let mut c_rejections_sender = rejections_sender.clone();
+            Box::pin(
+                async move {
+                    if !is_allowed {
+                        let _ = await!(c_rejections_sender.send(()));
+                    }
+                    is_allowed
+                },
+            )
+        });
+
+    // Split multiplexed sessions into separate connections.
+    // A session may carry one listen connection, in addition to its tunnels:
+    let (conns_sender, incoming_conns) = mpsc::channel(0);
+    let mux_sessions_fut = mux_sessions_loop(
         incoming_enc_conns,
+        conns_sender,
+        KeepAliveChannel::new(timer_client.clone(), KEEPALIVE_TICKS, spawner.clone()),
+        quotas.max_tunnels.saturating_add(1),
+        spawner.clone(),
+    )
+    .map_err(|e| error!("mux_sessions_loop() error: {:?}", e))
+    .map(|_| ());
+
+    spawner
+        .spawn(mux_sessions_fut)
+        .map_err(|_| NetRelayServerError::SpawnError)?;
+
+    await!(relay_server(
+        incoming_conns,
+        incoming_rejections,
         timer_client,
         CONN_TIMEOUT_TICKS,
         KEEPALIVE_TICKS,
+        quotas,
+        acl,
+        opt_stats_ticks,
+        drain_ticks,
         spawner.clone()
     ))?;
     Ok(())
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::marker::Unpin;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use crypto::identity::PublicKey;
+
+/// Per client limits enforced by the relay server.
+/// Used to make sure that a single client can not monopolize the relay.
+#[derive(Debug, Clone)]
+pub struct RelayQuotas {
+    /// Maximum amount of concurrent tunnels a client may take part in, either as the initiator or
+    /// as the listener. Pending half tunnels are only counted for their initiator.
+    pub max_tunnels: usize,
+    /// Maximum amount of listen registrations a client may perform every tick.
+    pub max_listens_per_tick: usize,
+    /// Maximum amount of bytes a client may send through its tunnels every tick.
+    pub max_bytes_per_tick: usize,
+}
+
+#[derive(Debug)]
+struct ByteBudgetInner {
+    max_bytes_per_tick: usize,
+    /// Amount of bytes sent that were not yet covered by a refill.
+    /// May exceed `max_bytes_per_tick` if a large message was sent.
+    used: usize,
+    /// Amount of bytes spent since the last refill
+    spent: usize,
+    /// Tunnels waiting for the next refill
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+/// Amount of bytes a client may send through its tunnels.
+/// Shared between all the tunnels of the client, and refilled every tick by the relay server.
+#[derive(Debug, Clone)]
+pub struct ByteBudget {
+    arc_mutex_inner: Arc<Mutex<ByteBudgetInner>>,
+}
+
+impl ByteBudget {
+    pub fn new(max_bytes_per_tick: usize) -> Self {
+        let inner = ByteBudgetInner {
+            max_bytes_per_tick,
+            used: 0,
+            spent: 0,
+            waiters: Vec::new(),
+        };
+        ByteBudget {
+            arc_mutex_inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Attempt to spend `num_bytes` of the budget.
+    /// If the budget is exhausted, returns a receiver that is notified on the next refill.
+    fn try_spend(&self, num_bytes: usize) -> Result<(), oneshot::Receiver<()>> {
+        let mut inner = self.arc_mutex_inner.lock().unwrap();
+        if inner.used < inner.max_bytes_per_tick {
+            inner.used = inner.used.saturating_add(num_bytes);
+            inner.spent = inner.spent.saturating_add(num_bytes);
+            return Ok(());
+        }
+        let (sender, receiver) = oneshot::channel();
+        inner.waiters.push(sender);
+        Err(receiver)
+    }
+
+    /// Refill the budget (Should be called once every tick), and wake up waiting tunnels.
+    /// Returns the amount of bytes spent since the last refill.
+    pub fn refill(&self) -> usize {
+        let mut inner = self.arc_mutex_inner.lock().unwrap();
+        inner.used = inner.used.saturating_sub(inner.max_bytes_per_tick);
+        for waiter in inner.waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+        let spent = inner.spent;
+        inner.spent = 0;
+        spent
+    }
+
+    /// Is this budget fully refilled, and not used by any tunnel?
+    fn is_idle(&self) -> bool {
+        Arc::strong_count(&self.arc_mutex_inner) == 1
+            && self.arc_mutex_inner.lock().unwrap().used == 0
+    }
+}
+
+/// Forward messages from `receiver` to `sender`, spending `byte_budget` for every message.
+/// If the budget is exhausted, waits until the budget is refilled.
+pub async fn forward_throttled<M, K>(
+    mut receiver: M,
+    mut sender: K,
+    byte_budget: ByteBudget,
+) -> Result<(), ()>
+where
+    M: Stream<Item = Vec<u8>> + Unpin,
+    K: Sink<SinkItem = Vec<u8>, SinkError = ()> + Unpin,
+{
+    while let Some(data) = await!(receiver.next()) {
+        while let Err(refill_receiver) = byte_budget.try_spend(data.len()) {
+            await!(refill_receiver).map_err(|_| ())?;
+        }
+        await!(sender.send(data))?;
+    }
+    Ok(())
+}
+
+/// Resources used by a single client of the relay
+#[derive(Debug)]
+struct ClientUsage {
+    /// Amount of tunnels the client takes part in (Including pending half tunnels it initiated)
+    num_tunnels: usize,
+    /// Amount of listen registrations done by the client during the current tick
+    num_listens: usize,
+    byte_budget: ByteBudget,
+}
+
+impl ClientUsage {
+    fn new(max_bytes_per_tick: usize) -> Self {
+        ClientUsage {
+            num_tunnels: 0,
+            num_listens: 0,
+            byte_budget: ByteBudget::new(max_bytes_per_tick),
+        }
+    }
+}
+
+/// Keeps track of the resources used by every client of the relay, and enforces the relay
+/// quotas.
+#[derive(Debug)]
+pub struct ClientsUsage {
+    quotas: RelayQuotas,
+    clients: HashMap<PublicKey, ClientUsage>,
+}
+
+impl ClientsUsage {
+    pub fn new(quotas: RelayQuotas) -> Self {
+        ClientsUsage {
+            quotas,
+            clients: HashMap::new(),
+        }
+    }
+
+    fn get_usage(&mut self, public_key: &PublicKey) -> &mut ClientUsage {
+        let max_bytes_per_tick = self.quotas.max_bytes_per_tick;
+        self.clients
+            .entry(public_key.clone())
+            .or_insert_with(|| ClientUsage::new(max_bytes_per_tick))
+    }
+
+    /// Attempt to register a listen by a client.
+    /// Returns false if the client exceeded its listen quota.
+    pub fn try_add_listen(&mut self, public_key: &PublicKey) -> bool {
+        let max_listens_per_tick = self.quotas.max_listens_per_tick;
+        let usage = self.get_usage(public_key);
+        if usage.num_listens >= max_listens_per_tick {
+            return false;
+        }
+        usage.num_listens += 1;
+        true
+    }
+
+    /// Attempt to register a tunnel for a client.
+    /// A half tunnel is registered only for its initiator. The listener is charged once it
+    /// accepts the tunnel, so that pending connections can not exhaust the quota of the listener.
+    /// Returns false if the client exceeded its tunnels quota.
+    pub fn try_add_tunnel(&mut self, public_key: &PublicKey) -> bool {
+        let max_tunnels = self.quotas.max_tunnels;
+        let usage = self.get_usage(public_key);
+        if usage.num_tunnels >= max_tunnels {
+            return false;
+        }
+        usage.num_tunnels += 1;
+        true
+    }
+
+    /// Unregister a tunnel of a client.
+    pub fn remove_tunnel(&mut self, public_key: &PublicKey) {
+        if let Some(usage) = self.clients.get_mut(public_key) {
+            usage.num_tunnels = usage.num_tunnels.saturating_sub(1);
+        }
+    }
+
+    /// Get the byte budget shared by all the tunnels of a client.
+    pub fn byte_budget(&mut self, public_key: &PublicKey) -> ByteBudget {
+        self.get_usage(public_key).byte_budget.clone()
+    }
+
+    /// Advance time by one tick: Refill byte budgets, reset listen counters and forget idle
+    /// clients.
+    /// Returns the total amount of bytes sent by all clients since the last tick.
+    pub fn tick(&mut self) -> usize {
+        let mut total_spent: usize = 0;
+        self.clients.retain(|_public_key, usage| {
+            usage.num_listens = 0;
+            total_spent = total_spent.saturating_add(usage.byte_budget.refill());
+            usage.num_tunnels > 0 || !usage.byte_budget.is_idle()
+        });
+        total_spent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::ThreadPool;
+    use futures::task::{Spawn, SpawnExt};
+    use futures::FutureExt;
+
+    use crypto::identity::PUBLIC_KEY_LEN;
+
+    fn example_quotas() -> RelayQuotas {
+        RelayQuotas {
+            max_tunnels: 2,
+            max_listens_per_tick: 1,
+            max_bytes_per_tick: 4,
+        }
+    }
+
+    #[test]
+    fn test_clients_usage_tunnels() {
+        let mut clients_usage = ClientsUsage::new(example_quotas());
+
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let pk_b = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        assert!(clients_usage.try_add_tunnel(&pk_a));
+        assert!(clients_usage.try_add_tunnel(&pk_a));
+        // pk_a is already at its limit:
+        assert!(!clients_usage.try_add_tunnel(&pk_a));
+        // Other clients are not affected:
+        assert!(clients_usage.try_add_tunnel(&pk_b));
+
+        clients_usage.remove_tunnel(&pk_a);
+        assert!(clients_usage.try_add_tunnel(&pk_a));
+        assert!(!clients_usage.try_add_tunnel(&pk_a));
+
+        // Clients with tunnels are not forgotten:
+        clients_usage.tick();
+        assert_eq!(clients_usage.clients.len(), 2);
+    }
+
+    #[test]
+    fn test_clients_usage_listens() {
+        let mut clients_usage = ClientsUsage::new(example_quotas());
+        let pk_a = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+
+        assert!(clients_usage.try_add_listen(&pk_a));
+        assert!(!clients_usage.try_add_listen(&pk_a));
+        clients_usage.tick();
+        assert!(clients_usage.try_add_listen(&pk_a));
+        assert!(!clients_usage.try_add_listen(&pk_a));
+
+        // Idle clients are forgotten:
+        clients_usage.tick();
+        assert!(clients_usage.clients.is_empty());
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let byte_budget = ByteBudget::new(4);
+        assert!(byte_budget.try_spend(3).is_ok());
+        // Budget may be exceeded by a single message:
+        assert!(byte_budget.try_spend(6).is_ok());
+        assert!(byte_budget.try_spend(1).is_err());
+
+        // Used 9 bytes, this refill is not enough:
+        assert_eq!(byte_budget.refill(), 9);
+        assert!(byte_budget.try_spend(1).is_err());
+        assert_eq!(byte_budget.refill(), 0);
+        assert!(byte_budget.try_spend(1).is_ok());
+    }
+
+    async fn task_forward_throttled(mut spawner: impl Spawn) {
+        let byte_budget = ByteBudget::new(4);
+
+        let (mut a_sender, a_receiver) = mpsc::channel::<Vec<u8>>(0);
+        let (b_sender, mut b_receiver) = mpsc::channel::<Vec<u8>>(8);
+        spawner
+            .spawn(
+                forward_throttled(
+                    a_receiver,
+                    b_sender.sink_map_err(|_| ()),
+                    byte_budget.clone(),
+                )
+                .map(|_| ()),
+            )
+            .unwrap();
+
+        await!(a_sender.send(vec![1, 2, 3, 4])).unwrap();
+        await!(a_sender.send(vec![5])).unwrap();
+        assert_eq!(await!(b_receiver.next()).unwrap(), vec![1, 2, 3, 4]);
+
+        // The second message waits for a refill:
+        byte_budget.refill();
+        assert_eq!(await!(b_receiver.next()).unwrap(), vec![5]);
+
+        drop(a_sender);
+        assert!(await!(b_receiver.next()).is_none());
+    }
+
+    #[test]
+    fn test_forward_throttled() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_forward_throttled(thread_pool.clone()));
+    }
+}
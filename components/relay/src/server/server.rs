@@ -1,17 +1,24 @@
 use futures::channel::mpsc;
+use futures::future::{self, AbortHandle};
 use futures::task::{Spawn, SpawnExt};
-use futures::{future, stream, FutureExt, Sink, SinkExt, Stream, StreamExt, TryFutureExt};
-use std::collections::{HashMap, HashSet};
+use futures::{stream, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
 use std::fmt;
 use std::marker::Unpin;
 
 use common::futures_compat::send_to_sink;
+use common::int_convert::usize_to_u64;
 use common::select_streams::{select_streams, BoxStream};
 use crypto::identity::PublicKey;
 use timer::TimerClient;
 
-use proto::relay::messages::{IncomingConnection, RejectConnection};
+use proto::net::messages::NetAddress;
+use proto::relay::messages::{IncomingConnection, PeerEndpoint, RejectConnection};
+use proto::relay::serialize::serialize_peer_endpoint;
 
+use super::acl::RelayAcl;
+use super::quota::{forward_throttled, ClientsUsage, RelayQuotas};
+use super::stats::RelayStats;
 use super::types::{IncomingAccept, IncomingConn, IncomingConnInner};
 
 struct ConnPair<M, K> {
@@ -27,12 +34,30 @@ impl<M, K> ConnPair<M, K> {
 
 struct HalfTunnel<MT, KT> {
     conn_pair: ConnPair<MT, KT>,
+    /// Observed address of the connecting side
+    opt_address: Option<NetAddress>,
+    /// Did the connecting side ask for the address of the accepting side?
+    punch: bool,
     ticks_to_close: usize,
 }
 
+/// An open tunnel. Used to close the tunnel from the server side.
+struct Tunnel {
+    /// Abort handles for the forwarding of the two directions of the tunnel
+    abort_handles: [AbortHandle; 2],
+}
+
+impl Tunnel {
+    fn close(&self) {
+        for abort_handle in &self.abort_handles {
+            abort_handle.abort();
+        }
+    }
+}
+
 struct Listener<MT, KT> {
     half_tunnels: HashMap<PublicKey, HalfTunnel<MT, KT>>,
-    tunnels: HashSet<PublicKey>,
+    tunnels: HashMap<PublicKey, Tunnel>,
     opt_sender: Option<mpsc::Sender<IncomingConnection>>,
 }
 
@@ -40,12 +65,21 @@ impl<MT, KT> Listener<MT, KT> {
     fn new(sender: mpsc::Sender<IncomingConnection>) -> Self {
         Listener {
             half_tunnels: HashMap::new(),
-            tunnels: HashSet::new(),
+            tunnels: HashMap::new(),
             opt_sender: Some(sender),
         }
     }
 }
 
+/// Stop listening: Disconnect the listener and drop all its pending half tunnels.
+fn close_listener<MT, KT>(listener: &mut Listener<MT, KT>, clients_usage: &mut ClientsUsage) {
+    listener.opt_sender = None;
+    for init_public_key in listener.half_tunnels.keys() {
+        clients_usage.remove_tunnel(init_public_key);
+    }
+    listener.half_tunnels = HashMap::new();
+}
+
 struct TunnelClosed {
     init_public_key: PublicKey,
     listen_public_key: PublicKey,
@@ -57,6 +91,8 @@ enum RelayServerEvent<ML, KL, MA, KA, MC, KC> {
     TunnelClosed(TunnelClosed),
     ListenerMessage((PublicKey, RejectConnection)),
     ListenerClosed(PublicKey),
+    /// A connection was rejected before reaching the server loop
+    Rejected,
     TimerTick,
     TimerClosed,
 }
@@ -71,6 +107,7 @@ impl<ML, KL, MA, KA, MC, KC> fmt::Debug for RelayServerEvent<ML, KL, MA, KA, MC,
             RelayServerEvent::TunnelClosed(_) => write!(f, "RelayServerEvent::TunnelClosed"),
             RelayServerEvent::ListenerMessage(_) => write!(f, "RelayServerEvent::ListenerMessage"),
             RelayServerEvent::ListenerClosed(_) => write!(f, "RelayServerEvent::ListenerClosed"),
+            RelayServerEvent::Rejected => write!(f, "RelayServerEvent::Rejected"),
             RelayServerEvent::TimerTick => write!(f, "RelayServerEvent::TimerTick"),
             RelayServerEvent::TimerClosed => write!(f, "RelayServerEvent::TimerClosed"),
         }
@@ -85,13 +122,49 @@ pub enum RelayServerError {
     // TimerClosedError,
     ListeningNotInProgress,
     NoPendingHalfTunnel,
+    TunnelsQuotaExceeded,
     AlreadyListening,
     EventReceiverError,
 }
 
+/// The first messages sent to the initiator and to the acceptor of a tunnel, if they asked to
+/// punch through NATs.
+/// The addresses are only revealed if both sides asked to punch, and the addresses of both sides
+/// are known. This way either both sides attempt to punch, or none of them does.
+fn peer_endpoint_msgs(
+    init_punch: bool,
+    init_opt_address: Option<NetAddress>,
+    acceptor_punch: bool,
+    acceptor_opt_address: Option<NetAddress>,
+) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    let (init_opt_address, acceptor_opt_address) = match (init_opt_address, acceptor_opt_address) {
+        (Some(init_address), Some(acceptor_address)) if init_punch && acceptor_punch => {
+            (Some(init_address), Some(acceptor_address))
+        }
+        _ => (None, None),
+    };
+    let init_endpoint_msg = if init_punch {
+        Some(serialize_peer_endpoint(&PeerEndpoint {
+            opt_address: acceptor_opt_address,
+        }))
+    } else {
+        None
+    };
+    let acceptor_endpoint_msg = if acceptor_punch {
+        Some(serialize_peer_endpoint(&PeerEndpoint {
+            opt_address: init_opt_address,
+        }))
+    } else {
+        None
+    };
+    (init_endpoint_msg, acceptor_endpoint_msg)
+}
+
 fn handle_accept<MT, KT, MA, KA, TCL>(
     listeners: &mut HashMap<PublicKey, Listener<MT, KT>>,
+    clients_usage: &mut ClientsUsage,
     acceptor_public_key: PublicKey,
+    acceptor_opt_address: Option<NetAddress>,
     incoming_accept: IncomingAccept<MA, KA>,
     // TODO: This should be a oneshot:
     tunnel_closed_sender: TCL,
@@ -109,39 +182,81 @@ where
         None => return Err(RelayServerError::ListeningNotInProgress),
     };
     let IncomingAccept {
-        mut receiver,
-        mut sender,
+        receiver,
+        sender,
         accept_public_key,
+        punch: acceptor_punch,
     } = incoming_accept;
-    let conn_pair = match listener.half_tunnels.remove(&accept_public_key) {
-        Some(HalfTunnel { conn_pair, .. }) => conn_pair,
+    let half_tunnel = match listener.half_tunnels.remove(&accept_public_key) {
+        Some(half_tunnel) => half_tunnel,
         None => return Err(RelayServerError::NoPendingHalfTunnel),
     };
+    // The listener is charged for the tunnel only when it accepts it:
+    if !clients_usage.try_add_tunnel(&acceptor_public_key) {
+        // Dropping the half tunnel closes the connection of the initiator:
+        clients_usage.remove_tunnel(&accept_public_key);
+        return Err(RelayServerError::TunnelsQuotaExceeded);
+    }
+    let HalfTunnel {
+        conn_pair,
+        opt_address: init_opt_address,
+        punch: init_punch,
+        ..
+    } = half_tunnel;
     let c_accept_public_key = accept_public_key.clone();
 
     let ConnPair {
-        sender: mut remote_sender,
-        receiver: mut remote_receiver,
+        sender: remote_sender,
+        receiver: remote_receiver,
     } = conn_pair;
 
-    let send_fut1 = async move {
-        await!(remote_sender
-            .send_all(&mut receiver)
-            .map_err(|e| error!("send_fut1 error: {:?}", e))
-            .then(|_| future::ready(())))
-    };
-    let send_fut2 = async move {
-        await!(sender
-            .send_all(&mut remote_receiver)
-            .map_err(|e| error!("send_fut2 error: {:?}", e))
-            .then(move |_| {
-                let tunnel_closed = TunnelClosed {
-                    init_public_key: c_accept_public_key,
-                    listen_public_key: acceptor_public_key,
-                };
-                send_to_sink(tunnel_closed_sender, tunnel_closed).then(|_| future::ready(()))
-            }))
-    };
+    // Sides that asked to punch through NATs first receive the address of the other side:
+    let (init_endpoint_msg, acceptor_endpoint_msg) = peer_endpoint_msgs(
+        init_punch,
+        init_opt_address,
+        acceptor_punch,
+        acceptor_opt_address,
+    );
+    let receiver = stream::iter(init_endpoint_msg).chain(receiver);
+    let remote_receiver = stream::iter(acceptor_endpoint_msg).chain(remote_receiver);
+
+    // Every side of the tunnel spends its own byte budget:
+    let acceptor_byte_budget = clients_usage.byte_budget(&acceptor_public_key);
+    let init_byte_budget = clients_usage.byte_budget(&accept_public_key);
+
+    // The forwarding may be aborted by the server, for example when draining times out:
+    let (send_fut1, abort_handle1) = future::abortable(forward_throttled(
+        receiver,
+        remote_sender,
+        acceptor_byte_budget,
+    ));
+    let (send_fut2, abort_handle2) =
+        future::abortable(forward_throttled(remote_receiver, sender, init_byte_budget));
+    listener.tunnels.insert(
+        accept_public_key.clone(),
+        Tunnel {
+            abort_handles: [abort_handle1, abort_handle2],
+        },
+    );
+
+    let send_fut1 = send_fut1.map(|res| {
+        if let Ok(Err(e)) = res {
+            error!("send_fut1 error: {:?}", e);
+        }
+    });
+    let send_fut2 = send_fut2
+        .map(|res| {
+            if let Ok(Err(e)) = res {
+                error!("send_fut2 error: {:?}", e);
+            }
+        })
+        .then(move |_| {
+            let tunnel_closed = TunnelClosed {
+                init_public_key: c_accept_public_key,
+                listen_public_key: acceptor_public_key,
+            };
+            send_to_sink(tunnel_closed_sender, tunnel_closed).then(|_| future::ready(()))
+        });
 
     spawner.spawn(send_fut1).unwrap();
     spawner.spawn(send_fut2).unwrap();
@@ -149,10 +264,25 @@ where
     Ok(())
 }
 
-pub async fn relay_server_loop<ML, KL, MA, KA, MC, KC, S>(
+/// Relay connections between listening clients and connecting clients.
+///
+/// When `incoming_conns` is closed the server drains: All listeners are disconnected, and open
+/// tunnels may keep working until they are closed, or until `drain_ticks` ticks have passed.
+///
+/// `incoming_rejections` reports connections that were rejected before reaching the server loop
+/// (For example, by access control right after the handshake). These are counted in the relay
+/// statistics, which are logged every `stats_ticks` ticks (If `opt_stats_ticks` is provided),
+/// and also sent through `opt_stats_sender`.
+pub async fn relay_server_loop<ML, KL, MA, KA, MC, KC, S, R>(
     mut timer_client: TimerClient,
     incoming_conns: S,
+    incoming_rejections: R,
     half_tunnel_ticks: usize,
+    quotas: RelayQuotas,
+    acl: RelayAcl,
+    opt_stats_ticks: Option<usize>,
+    mut opt_stats_sender: Option<mpsc::Sender<RelayStats>>,
+    drain_ticks: usize,
     mut spawner: impl Spawn + Clone,
 ) -> Result<(), RelayServerError>
 where
@@ -163,6 +293,7 @@ where
     MC: Stream<Item = Vec<u8>> + Unpin + Send + 'static,
     KC: Sink<SinkItem = Vec<u8>, SinkError = ()> + Unpin + Send + 'static,
     S: Stream<Item = IncomingConn<ML, KL, MA, KA, MC, KC>> + Unpin + Send,
+    R: Stream<Item = ()> + Unpin + Send,
 {
     let timer_stream = await!(timer_client.request_timer_stream())
         .map_err(|_| RelayServerError::RequestTimerStreamError)?;
@@ -176,21 +307,48 @@ where
             RelayServerEvent::IncomingConnsClosed,
         )));
 
+    let incoming_rejections = incoming_rejections.map(|()| RelayServerEvent::Rejected);
+
     let (event_sender, event_receiver) = mpsc::channel::<RelayServerEvent<_, _, _, _, _, _>>(0);
 
-    let mut relay_server_events = select_streams![timer_stream, incoming_conns, event_receiver];
+    let mut relay_server_events = select_streams![
+        timer_stream,
+        incoming_conns,
+        incoming_rejections,
+        event_receiver
+    ];
 
     let mut incoming_conns_closed = false;
     let mut listeners: HashMap<PublicKey, Listener<_, _>> = HashMap::new();
+    let mut clients_usage = ClientsUsage::new(quotas);
+    let mut relay_stats = RelayStats::default();
+    let mut ticks_since_stats: usize = 0;
+    // Ticks left until we stop waiting for open tunnels to close
+    let mut opt_drain_ticks_left: Option<usize> = None;
 
     while let Some(relay_server_event) = await!(relay_server_events.next()) {
         let c_event_sender = event_sender.clone().sink_map_err(|_| ());
         match relay_server_event {
             RelayServerEvent::IncomingConn(incoming_conn) => {
-                let IncomingConn { public_key, inner } = incoming_conn;
+                let IncomingConn {
+                    public_key,
+                    opt_address,
+                    inner,
+                } = incoming_conn;
                 match inner {
                     IncomingConnInner::Listen(incoming_listen) => {
+                        if !acl.is_listen_allowed(&public_key) {
+                            warn!("{:?} is not allowed to listen", public_key);
+                            relay_stats.num_rejected += 1;
+                            continue; // Discard Listen connection
+                        }
                         if listeners.contains_key(&public_key) {
+                            relay_stats.num_rejected += 1;
+                            continue; // Discard Listen connection
+                        }
+                        if !clients_usage.try_add_listen(&public_key) {
+                            warn!("Listen quota exceeded by {:?}", public_key);
+                            relay_stats.num_rejected += 1;
                             continue; // Discard Listen connection
                         }
 
@@ -236,37 +394,65 @@ where
                             .unwrap();
                     }
                     IncomingConnInner::Accept(incoming_accept) => {
+                        if !acl.is_listen_allowed(&public_key) {
+                            relay_stats.num_rejected += 1;
+                            continue; // Discard Accept connection
+                        }
                         let tunnel_closed_sender = c_event_sender.with(|tunnel_closed| {
                             future::ready(Ok(RelayServerEvent::TunnelClosed(tunnel_closed)))
                         });
-                        let _ = handle_accept(
+                        match handle_accept(
                             &mut listeners,
+                            &mut clients_usage,
                             public_key.clone(),
+                            opt_address,
                             incoming_accept,
                             tunnel_closed_sender,
                             spawner.clone(),
-                        )
-                        .map_err(|e| warn!("handle_accept() error: {:?}", e));
+                        ) {
+                            Ok(()) => relay_stats.num_tunnels += 1,
+                            Err(e) => {
+                                warn!("handle_accept() error: {:?}", e);
+                                relay_stats.num_rejected += 1;
+                            }
+                        }
                     }
                     IncomingConnInner::Connect(incoming_connect) => {
+                        if !acl.is_connect_allowed(&public_key) {
+                            warn!("{:?} is not allowed to connect", public_key);
+                            relay_stats.num_rejected += 1;
+                            continue; // Discard Connect connection
+                        }
                         let listener = match listeners.get_mut(&incoming_connect.connect_public_key)
                         {
                             Some(listener) => listener,
-                            None => continue, // Discard Connect connection
+                            None => {
+                                relay_stats.num_rejected += 1;
+                                continue; // Discard Connect connection
+                            }
                         };
                         if listener.half_tunnels.contains_key(&public_key)
-                            || listener.tunnels.contains(&public_key)
+                            || listener.tunnels.contains_key(&public_key)
                         {
+                            relay_stats.num_rejected += 1;
                             continue;
                         }
+                        if !clients_usage.try_add_tunnel(&public_key) {
+                            warn!("Tunnels quota exceeded by {:?}", public_key);
+                            relay_stats.num_rejected += 1;
+                            continue; // Discard Connect connection
+                        }
 
                         let half_tunnel = HalfTunnel {
                             conn_pair: ConnPair::new(
                                 incoming_connect.receiver,
                                 incoming_connect.sender,
                             ),
+                            opt_address,
+                            punch: incoming_connect.punch,
                             ticks_to_close: half_tunnel_ticks,
                         };
+                        let mut is_notified = false;
                         if let Some(sender) = &mut listener.opt_sender {
                             // Try to send a message to listener about new pending connection:
                             if let Ok(()) = sender.try_send(IncomingConnection {
@@ -275,13 +461,29 @@ where
                                 listener
                                     .half_tunnels
                                     .insert(public_key.clone(), half_tunnel);
+                                is_notified = true;
                             }
                         }
+                        if !is_notified {
+                            clients_usage.remove_tunnel(&public_key);
+                            relay_stats.num_rejected += 1;
+                        }
                     }
                 }
             }
-            RelayServerEvent::IncomingConnsClosed => incoming_conns_closed = true,
+            RelayServerEvent::IncomingConnsClosed => {
+                info!("Relay server: Incoming connections closed. Draining...");
+                incoming_conns_closed = true;
+                opt_drain_ticks_left = Some(drain_ticks);
+                for listener in listeners.values_mut() {
+                    close_listener(listener, &mut clients_usage);
+                }
+                listeners.retain(|_listen_public_key, listener| !listener.tunnels.is_empty());
+            }
             RelayServerEvent::TunnelClosed(tunnel_closed) => {
+                relay_stats.num_tunnels = relay_stats.num_tunnels.saturating_sub(1);
+                clients_usage.remove_tunnel(&tunnel_closed.init_public_key);
+                clients_usage.remove_tunnel(&tunnel_closed.listen_public_key);
                 let listener = match listeners.get_mut(&tunnel_closed.listen_public_key) {
                     Some(listener) => listener,
                     None => continue,
@@ -301,34 +503,77 @@ where
                     Some(listener) => listener,
                     None => continue,
                 };
-                let _ = listener.half_tunnels.remove(&rejected_public_key);
+                if listener.half_tunnels.remove(&rejected_public_key).is_some() {
+                    clients_usage.remove_tunnel(&rejected_public_key);
+                }
             }
             RelayServerEvent::ListenerClosed(public_key) => {
                 let listener = match listeners.get_mut(&public_key) {
                     Some(listener) => listener,
                     None => continue,
                 };
-                listener.opt_sender = None;
-                listener.half_tunnels = HashMap::new();
+                close_listener(listener, &mut clients_usage);
                 if listener.tunnels.is_empty() {
                     listeners.remove(&public_key);
                 }
             }
+            RelayServerEvent::Rejected => relay_stats.num_rejected += 1,
             RelayServerEvent::TimerTick => {
                 // Remove old half tunnels:
                 for listener in listeners.values_mut() {
                     listener
                         .half_tunnels
-                        .retain(|_init_public_key, half_tunnel| {
+                        .retain(|init_public_key, half_tunnel| {
                             half_tunnel.ticks_to_close =
                                 half_tunnel.ticks_to_close.saturating_sub(1);
+                            if half_tunnel.ticks_to_close == 0 {
+                                clients_usage.remove_tunnel(init_public_key);
+                            }
                             half_tunnel.ticks_to_close > 0
                         });
                 }
+                let bytes_forwarded = usize_to_u64(clients_usage.tick()).unwrap();
+                relay_stats.bytes_forwarded =
+                    relay_stats.bytes_forwarded.saturating_add(bytes_forwarded);
+
+                if let Some(stats_ticks) = opt_stats_ticks {
+                    ticks_since_stats += 1;
+                    if ticks_since_stats >= stats_ticks {
+                        ticks_since_stats = 0;
+                        relay_stats.num_listeners = listeners
+                            .values()
+                            .filter(|listener| listener.opt_sender.is_some())
+                            .count();
+                        relay_stats.num_half_tunnels = listeners
+                            .values()
+                            .map(|listener| listener.half_tunnels.len())
+                            .sum();
+                        info!("Relay stats: {}", relay_stats);
+                        if let Some(stats_sender) = &mut opt_stats_sender {
+                            let _ = stats_sender.try_send(relay_stats.clone());
+                        }
+                    }
+                }
+
+                if let Some(drain_ticks_left) = &mut opt_drain_ticks_left {
+                    *drain_ticks_left = drain_ticks_left.saturating_sub(1);
+                    if *drain_ticks_left == 0 {
+                        warn!(
+                            "Relay server: Drain timeout. Closing {} open tunnels.",
+                            relay_stats.num_tunnels
+                        );
+                        for listener in listeners.values() {
+                            for tunnel in listener.tunnels.values() {
+                                tunnel.close();
+                            }
+                        }
+                        break;
+                    }
+                }
             }
             RelayServerEvent::TimerClosed => break,
         }
-        if incoming_conns_closed && listeners.is_empty() {
+        if incoming_conns_closed && listeners.is_empty() && relay_stats.num_tunnels == 0 {
             break;
         }
     }
@@ -338,14 +583,27 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::channel::mpsc;
+    use futures::channel::{mpsc, oneshot};
     use futures::executor::ThreadPool;
     use futures::task::{Spawn, SpawnExt};
+    use futures::TryFutureExt;
+    use std::convert::TryInto;
 
     use super::super::types::{IncomingAccept, IncomingConnect, IncomingListen};
+    use common::access_control::{AccessControl, AccessControlOp};
     use crypto::identity::{PublicKey, PUBLIC_KEY_LEN};
     use timer::create_timer_incoming;
 
+    const DRAIN_TICKS: usize = 8;
+
+    fn test_quotas() -> RelayQuotas {
+        RelayQuotas {
+            max_tunnels: 1,
+            max_listens_per_tick: 1,
+            max_bytes_per_tick: 0x100,
+        }
+    }
+
     async fn task_relay_server_connect(
         mut spawner: impl Spawn + Clone + Send + 'static,
     ) -> Result<(), ()> {
@@ -360,7 +618,13 @@ mod tests {
         let fut_relay_server = relay_server_loop(
             timer_client,
             incoming_conns,
+            stream::empty(),
             half_tunnel_ticks,
+            test_quotas(),
+            RelayAcl::default(),
+            None,
+            None,
+            DRAIN_TICKS,
             spawner.clone(),
         );
 
@@ -395,6 +659,7 @@ mod tests {
         };
         let incoming_conn_a = IncomingConn {
             public_key: a_public_key.clone(),
+            opt_address: None,
             inner: IncomingConnInner::Listen(incoming_listen_a),
         };
 
@@ -404,9 +669,11 @@ mod tests {
             receiver: c_bc,
             sender: c_cb.sink_map_err(|_| ()),
             connect_public_key: a_public_key.clone(),
+            punch: false,
         };
         let incoming_conn_b = IncomingConn {
             public_key: b_public_key.clone(),
+            opt_address: None,
             inner: IncomingConnInner::Connect(incoming_connect_b),
         };
 
@@ -428,9 +695,11 @@ mod tests {
             receiver: c_ac1,
             sender: c_ca1.sink_map_err(|_| ()),
             accept_public_key: b_public_key.clone(),
+            punch: false,
         };
         let incoming_conn_accept_a = IncomingConn {
             public_key: a_public_key.clone(),
+            opt_address: None,
             inner: IncomingConnInner::Accept(incoming_accept_a),
         };
 
@@ -463,6 +732,161 @@ mod tests {
             .unwrap();
     }
 
+    async fn task_relay_server_punch(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) -> Result<(), ()> {
+        // Create a mock time service:
+        let (_tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut outgoing_conns, incoming_conns) = mpsc::channel::<_>(0);
+
+        let fut_relay_server = relay_server_loop(
+            timer_client,
+            incoming_conns,
+            stream::empty(),
+            16,
+            test_quotas(),
+            RelayAcl::default(),
+            None,
+            None,
+            DRAIN_TICKS,
+            spawner.clone(),
+        );
+
+        spawner
+            .spawn(fut_relay_server.map_err(|_e| ()).map(|_| ()))
+            .unwrap();
+
+        let a_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let b_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let a_address: NetAddress = "10.0.0.1:1111".to_owned().try_into().unwrap();
+        let b_address: NetAddress = "10.0.0.2:2222".to_owned().try_into().unwrap();
+
+        // a listens:
+        let (_a_ac, c_ac) = mpsc::channel::<RejectConnection>(0);
+        let (c_ca, mut a_ca) = mpsc::channel::<IncomingConnection>(0);
+        let incoming_conn_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: Some(a_address.clone()),
+            inner: IncomingConnInner::Listen(IncomingListen {
+                receiver: c_ac,
+                sender: c_ca.sink_map_err(|_| ()),
+            }),
+        };
+        await!(outgoing_conns.send(incoming_conn_a)).unwrap();
+
+        // b connects to a, asking to punch:
+        let (mut b_bc, c_bc) = mpsc::channel::<Vec<u8>>(0);
+        let (c_cb, mut b_cb) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_conn_b = IncomingConn {
+            public_key: b_public_key.clone(),
+            opt_address: Some(b_address.clone()),
+            inner: IncomingConnInner::Connect(IncomingConnect {
+                receiver: c_bc,
+                sender: c_cb.sink_map_err(|_| ()),
+                connect_public_key: a_public_key.clone(),
+                punch: true,
+            }),
+        };
+        await!(outgoing_conns.send(incoming_conn_b)).unwrap();
+
+        let msg = await!(a_ca.next()).unwrap();
+        assert_eq!(msg.public_key, b_public_key);
+
+        // a accepts, asking to punch:
+        let (mut a_ac1, c_ac1) = mpsc::channel::<Vec<u8>>(0);
+        let (c_ca1, mut a_ca1) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_conn_accept_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: Some(a_address.clone()),
+            inner: IncomingConnInner::Accept(IncomingAccept {
+                receiver: c_ac1,
+                sender: c_ca1.sink_map_err(|_| ()),
+                accept_public_key: b_public_key.clone(),
+                punch: true,
+            }),
+        };
+        await!(outgoing_conns.send(incoming_conn_accept_a)).unwrap();
+
+        // Both sides first learn the address of the other side:
+        let msg = await!(b_cb.next()).unwrap();
+        assert_eq!(
+            msg,
+            serialize_peer_endpoint(&PeerEndpoint {
+                opt_address: Some(a_address.clone())
+            })
+        );
+        let msg = await!(a_ca1.next()).unwrap();
+        assert_eq!(
+            msg,
+            serialize_peer_endpoint(&PeerEndpoint {
+                opt_address: Some(b_address.clone())
+            })
+        );
+
+        // The tunnel works as usual afterwards:
+        await!(a_ac1.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(b_cb.next()).unwrap(), vec![1, 2, 3]);
+        await!(b_bc.send(vec![4, 3, 2, 1])).unwrap();
+        assert_eq!(await!(a_ca1.next()).unwrap(), vec![4, 3, 2, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay_server_punch() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool
+            .run(task_relay_server_punch(thread_pool.clone()))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_peer_endpoint_msgs() {
+        let a_address: NetAddress = "10.0.0.1:1111".to_owned().try_into().unwrap();
+        let b_address: NetAddress = "10.0.0.2:2222".to_owned().try_into().unwrap();
+        let no_address_msg = Some(serialize_peer_endpoint(&PeerEndpoint { opt_address: None }));
+
+        // Only sides that asked to punch get a message:
+        assert_eq!(
+            peer_endpoint_msgs(
+                false,
+                Some(a_address.clone()),
+                false,
+                Some(b_address.clone())
+            ),
+            (None, None)
+        );
+        assert_eq!(
+            peer_endpoint_msgs(
+                true,
+                Some(a_address.clone()),
+                false,
+                Some(b_address.clone())
+            ),
+            (no_address_msg.clone(), None)
+        );
+
+        // The address of one side is unknown. No side is told the address of the other side:
+        assert_eq!(
+            peer_endpoint_msgs(true, None, true, Some(b_address.clone())),
+            (no_address_msg.clone(), no_address_msg.clone())
+        );
+
+        assert_eq!(
+            peer_endpoint_msgs(true, Some(a_address.clone()), true, Some(b_address.clone())),
+            (
+                Some(serialize_peer_endpoint(&PeerEndpoint {
+                    opt_address: Some(b_address.clone())
+                })),
+                Some(serialize_peer_endpoint(&PeerEndpoint {
+                    opt_address: Some(a_address.clone())
+                })),
+            )
+        );
+    }
+
     async fn task_relay_server_reject(
         mut spawner: impl Spawn + Clone + Send + 'static,
     ) -> Result<(), ()> {
@@ -477,7 +901,13 @@ mod tests {
         let fut_relay_server = relay_server_loop(
             timer_client,
             incoming_conns,
+            stream::empty(),
             half_tunnel_ticks,
+            test_quotas(),
+            RelayAcl::default(),
+            None,
+            None,
+            DRAIN_TICKS,
             spawner.clone(),
         );
 
@@ -512,6 +942,7 @@ mod tests {
         };
         let incoming_conn_a = IncomingConn {
             public_key: a_public_key.clone(),
+            opt_address: None,
             inner: IncomingConnInner::Listen(incoming_listen_a),
         };
 
@@ -521,9 +952,11 @@ mod tests {
             receiver: c_bc,
             sender: c_cb.sink_map_err(|_| ()),
             connect_public_key: a_public_key.clone(),
+            punch: false,
         };
         let incoming_conn_b = IncomingConn {
             public_key: b_public_key.clone(),
+            opt_address: None,
             inner: IncomingConnInner::Connect(incoming_connect_b),
         };
 
@@ -548,9 +981,11 @@ mod tests {
                 receiver: c_ac1,
                 sender: c_ca1.sink_map_err(|_| ()),
                 accept_public_key: b_public_key.clone(),
+                punch: false,
             };
             let incoming_conn_accept_a = IncomingConn {
                 public_key: a_public_key.clone(),
+                opt_address: None,
                 inner: IncomingConnInner::Accept(incoming_accept_a),
             };
             await!(outgoing_conns.send(incoming_conn_accept_a)).unwrap();
@@ -582,10 +1017,549 @@ mod tests {
             .unwrap();
     }
 
+    async fn task_relay_server_tunnels_quota(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) -> Result<(), ()> {
+        // Create a mock time service:
+        let (_tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut outgoing_conns, incoming_conns) = mpsc::channel::<_>(0);
+
+        let half_tunnel_ticks: usize = 16;
+
+        let fut_relay_server = relay_server_loop(
+            timer_client,
+            incoming_conns,
+            stream::empty(),
+            half_tunnel_ticks,
+            test_quotas(),
+            RelayAcl::default(),
+            None,
+            None,
+            DRAIN_TICKS,
+            spawner.clone(),
+        );
+
+        spawner
+            .spawn(fut_relay_server.map_err(|_e| ()).map(|_| ()))
+            .unwrap();
+
+        let (a_ac, c_ac) = mpsc::channel::<RejectConnection>(0);
+        let (c_ca, mut a_ca) = mpsc::channel::<IncomingConnection>(0);
+
+        let a_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let b_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let d_public_key = PublicKey::from(&[0xdd; PUBLIC_KEY_LEN]);
+
+        let incoming_listen_a = IncomingListen {
+            receiver: c_ac,
+            sender: c_ca.sink_map_err(|_| ()),
+        };
+        let incoming_conn_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Listen(incoming_listen_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_a)).unwrap();
+
+        // B connects to A:
+        let (b_bc, c_bc) = mpsc::channel::<Vec<u8>>(0);
+        let (c_cb, mut b_cb) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_connect_b = IncomingConnect {
+            receiver: c_bc,
+            sender: c_cb.sink_map_err(|_| ()),
+            connect_public_key: a_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_b = IncomingConn {
+            public_key: b_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Connect(incoming_connect_b),
+        };
+        await!(outgoing_conns.send(incoming_conn_b)).unwrap();
+
+        let msg = await!(a_ca.next()).unwrap();
+        assert_eq!(
+            msg,
+            IncomingConnection {
+                public_key: b_public_key.clone()
+            }
+        );
+
+        // D connects to A. Pending connections do not count against the quota of A:
+        let (d_dc, c_dc) = mpsc::channel::<Vec<u8>>(0);
+        let (c_cd, mut d_cd) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_connect_d = IncomingConnect {
+            receiver: c_dc,
+            sender: c_cd.sink_map_err(|_| ()),
+            connect_public_key: a_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_d = IncomingConn {
+            public_key: d_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Connect(incoming_connect_d),
+        };
+        await!(outgoing_conns.send(incoming_conn_d)).unwrap();
+
+        let msg = await!(a_ca.next()).unwrap();
+        assert_eq!(
+            msg,
+            IncomingConnection {
+                public_key: d_public_key.clone()
+            }
+        );
+
+        // A accepts B's connection:
+        let (mut a_ac1, c_ac1) = mpsc::channel::<Vec<u8>>(0);
+        let (c_ca1, _a_ca1) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_accept_a = IncomingAccept {
+            receiver: c_ac1,
+            sender: c_ca1.sink_map_err(|_| ()),
+            accept_public_key: b_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_accept_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Accept(incoming_accept_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_accept_a)).unwrap();
+
+        await!(a_ac1.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(b_cb.next()).unwrap(), vec![1, 2, 3]);
+
+        // A attempts to accept D's connection, but A already takes part in the maximum amount of
+        // tunnels:
+        let (a_ac2, c_ac2) = mpsc::channel::<Vec<u8>>(0);
+        let (c_ca2, mut a_ca2) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_accept_a = IncomingAccept {
+            receiver: c_ac2,
+            sender: c_ca2.sink_map_err(|_| ()),
+            accept_public_key: d_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_accept_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Accept(incoming_accept_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_accept_a)).unwrap();
+
+        // Both A's accept connection and D's connection are closed:
+        assert!(await!(a_ca2.next()).is_none());
+        assert!(await!(d_cd.next()).is_none());
+
+        // Drop here, to make sure values are not automatically dropped earlier:
+        drop(a_ac);
+        drop(a_ca);
+        drop(a_ac1);
+        drop(a_ac2);
+        drop(b_bc);
+        drop(d_dc);
+        drop(outgoing_conns);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay_server_tunnels_quota() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool
+            .run(task_relay_server_tunnels_quota(thread_pool.clone()))
+            .unwrap();
+    }
+
+    async fn task_relay_server_acl(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) -> Result<(), ()> {
+        // Create a mock time service:
+        let (mut tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut outgoing_conns, incoming_conns) = mpsc::channel::<_>(0);
+
+        let half_tunnel_ticks: usize = 16;
+
+        let a_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let b_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+        let c_public_key = PublicKey::from(&[0xcc; PUBLIC_KEY_LEN]);
+
+        // Only B is allowed to listen, and only C is allowed to connect:
+        let mut listen_acl = AccessControl::new();
+        listen_acl.apply_op(AccessControlOp::Add(b_public_key.clone()));
+        let mut connect_acl = AccessControl::new();
+        connect_acl.apply_op(AccessControlOp::Add(c_public_key.clone()));
+        let acl = RelayAcl {
+            opt_listen: Some(listen_acl),
+            opt_connect: Some(connect_acl),
+        };
+
+        let (mut rejections_sender, incoming_rejections) = mpsc::channel::<()>(0);
+        let (stats_sender, mut stats_receiver) = mpsc::channel::<RelayStats>(0);
+
+        let fut_relay_server = relay_server_loop(
+            timer_client,
+            incoming_conns,
+            incoming_rejections,
+            half_tunnel_ticks,
+            test_quotas(),
+            acl,
+            Some(1),
+            Some(stats_sender),
+            DRAIN_TICKS,
+            spawner.clone(),
+        );
+
+        spawner
+            .spawn(fut_relay_server.map_err(|_e| ()).map(|_| ()))
+            .unwrap();
+
+        // A connection was rejected before reaching the server loop:
+        await!(rejections_sender.send(())).unwrap();
+
+        // A attempts to listen:
+        let (a_ac, c_ac) = mpsc::channel::<RejectConnection>(0);
+        let (c_ca, mut a_ca) = mpsc::channel::<IncomingConnection>(0);
+        let incoming_listen_a = IncomingListen {
+            receiver: c_ac,
+            sender: c_ca.sink_map_err(|_| ()),
+        };
+        let incoming_conn_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Listen(incoming_listen_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_a)).unwrap();
+
+        // A's listen connection is closed:
+        assert!(await!(a_ca.next()).is_none());
+
+        // B listens:
+        let (b_bc, c_bc) = mpsc::channel::<RejectConnection>(0);
+        let (c_cb, mut b_cb) = mpsc::channel::<IncomingConnection>(0);
+        let incoming_listen_b = IncomingListen {
+            receiver: c_bc,
+            sender: c_cb.sink_map_err(|_| ()),
+        };
+        let incoming_conn_b = IncomingConn {
+            public_key: b_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Listen(incoming_listen_b),
+        };
+        await!(outgoing_conns.send(incoming_conn_b)).unwrap();
+
+        // A attempts to connect to B:
+        let (_a_ac1, c_ac1) = mpsc::channel::<Vec<u8>>(0);
+        let (c_ca1, mut a_ca1) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_connect_a = IncomingConnect {
+            receiver: c_ac1,
+            sender: c_ca1.sink_map_err(|_| ()),
+            connect_public_key: b_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_connect_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Connect(incoming_connect_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_connect_a)).unwrap();
+
+        // A's connect connection is closed:
+        assert!(await!(a_ca1.next()).is_none());
+
+        // C connects to B:
+        let (_c_cb1, b_cb1) = mpsc::channel::<Vec<u8>>(0);
+        let (b_bc1, _c_bc1) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_connect_c = IncomingConnect {
+            receiver: b_cb1,
+            sender: b_bc1.sink_map_err(|_| ()),
+            connect_public_key: b_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_connect_c = IncomingConn {
+            public_key: c_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Connect(incoming_connect_c),
+        };
+        await!(outgoing_conns.send(incoming_conn_connect_c)).unwrap();
+
+        // B is notified only about C's connection. A's connection was never forwarded:
+        let msg = await!(b_cb.next()).unwrap();
+        assert_eq!(
+            msg,
+            IncomingConnection {
+                public_key: c_public_key.clone()
+            }
+        );
+
+        // All rejections are counted:
+        await!(tick_sender.send(())).unwrap();
+        let relay_stats = await!(stats_receiver.next()).unwrap();
+        assert_eq!(relay_stats.num_rejected, 3);
+        assert_eq!(relay_stats.num_listeners, 1);
+        assert_eq!(relay_stats.num_half_tunnels, 1);
+
+        // This is done to help the compiler deduce the types for
+        // IncomingConn:
+        if false {
+            let (_a_ac2, c_ac2) = mpsc::channel::<Vec<u8>>(0);
+            let (c_ca2, _a_ca2) = mpsc::channel::<Vec<u8>>(0);
+            let incoming_accept_a = IncomingAccept {
+                receiver: c_ac2,
+                sender: c_ca2.sink_map_err(|_| ()),
+                accept_public_key: b_public_key.clone(),
+                punch: false,
+            };
+            let incoming_conn_accept_a = IncomingConn {
+                public_key: a_public_key.clone(),
+                opt_address: None,
+                inner: IncomingConnInner::Accept(incoming_accept_a),
+            };
+            await!(outgoing_conns.send(incoming_conn_accept_a)).unwrap();
+        }
+
+        // Drop here, to make sure values are not automatically dropped earlier:
+        drop(a_ac);
+        drop(b_bc);
+        drop(outgoing_conns);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay_server_acl() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool
+            .run(task_relay_server_acl(thread_pool.clone()))
+            .unwrap();
+    }
+
+    async fn task_relay_server_drain(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) -> Result<(), ()> {
+        // Create a mock time service:
+        let (_tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut outgoing_conns, incoming_conns) = mpsc::channel::<_>(0);
+
+        let half_tunnel_ticks: usize = 16;
+
+        let fut_relay_server = relay_server_loop(
+            timer_client,
+            incoming_conns,
+            stream::empty(),
+            half_tunnel_ticks,
+            test_quotas(),
+            RelayAcl::default(),
+            None,
+            None,
+            DRAIN_TICKS,
+            spawner.clone(),
+        );
+
+        let (loop_done_sender, loop_done_receiver) = oneshot::channel::<bool>();
+        spawner
+            .spawn(fut_relay_server.map(move |res| {
+                let _ = loop_done_sender.send(res.is_ok());
+            }))
+            .unwrap();
+
+        let a_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let b_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let (a_ac, c_ac) = mpsc::channel::<RejectConnection>(0);
+        let (c_ca, mut a_ca) = mpsc::channel::<IncomingConnection>(0);
+        let incoming_listen_a = IncomingListen {
+            receiver: c_ac,
+            sender: c_ca.sink_map_err(|_| ()),
+        };
+        let incoming_conn_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Listen(incoming_listen_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_a)).unwrap();
+
+        // This is done to help the compiler deduce the types for
+        // IncomingConn:
+        if false {
+            let (_a_ac1, c_ac1) = mpsc::channel::<Vec<u8>>(0);
+            let (c_ca1, _a_ca1) = mpsc::channel::<Vec<u8>>(0);
+            let incoming_accept_a = IncomingAccept {
+                receiver: c_ac1,
+                sender: c_ca1.sink_map_err(|_| ()),
+                accept_public_key: b_public_key.clone(),
+                punch: false,
+            };
+            let incoming_conn_accept_a = IncomingConn {
+                public_key: a_public_key.clone(),
+                opt_address: None,
+                inner: IncomingConnInner::Accept(incoming_accept_a),
+            };
+            await!(outgoing_conns.send(incoming_conn_accept_a)).unwrap();
+
+            let (_b_bc, c_bc) = mpsc::channel::<Vec<u8>>(0);
+            let (c_cb, _b_cb) = mpsc::channel::<Vec<u8>>(0);
+            let incoming_connect_b = IncomingConnect {
+                receiver: c_bc,
+                sender: c_cb.sink_map_err(|_| ()),
+                connect_public_key: a_public_key.clone(),
+                punch: false,
+            };
+            let incoming_conn_b = IncomingConn {
+                public_key: b_public_key.clone(),
+                opt_address: None,
+                inner: IncomingConnInner::Connect(incoming_connect_b),
+            };
+            await!(outgoing_conns.send(incoming_conn_b)).unwrap();
+        }
+
+        // Stop accepting connections. The relay server should start draining:
+        drop(outgoing_conns);
+
+        // The listener is disconnected:
+        assert!(await!(a_ca.next()).is_none());
+
+        // There are no open tunnels, so the server loop exits:
+        assert!(await!(loop_done_receiver).unwrap());
+
+        drop(a_ac);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay_server_drain() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool
+            .run(task_relay_server_drain(thread_pool.clone()))
+            .unwrap();
+    }
+
+    async fn task_relay_server_drain_timeout(
+        mut spawner: impl Spawn + Clone + Send + 'static,
+    ) -> Result<(), ()> {
+        // Create a mock time service:
+        let (mut tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, spawner.clone()).unwrap();
+
+        let (mut outgoing_conns, incoming_conns) = mpsc::channel::<_>(0);
+
+        let half_tunnel_ticks: usize = 16;
+
+        let fut_relay_server = relay_server_loop(
+            timer_client,
+            incoming_conns,
+            stream::empty(),
+            half_tunnel_ticks,
+            test_quotas(),
+            RelayAcl::default(),
+            None,
+            None,
+            DRAIN_TICKS,
+            spawner.clone(),
+        );
+
+        let (loop_done_sender, loop_done_receiver) = oneshot::channel::<bool>();
+        spawner
+            .spawn(fut_relay_server.map(move |res| {
+                let _ = loop_done_sender.send(res.is_ok());
+            }))
+            .unwrap();
+
+        let a_public_key = PublicKey::from(&[0xaa; PUBLIC_KEY_LEN]);
+        let b_public_key = PublicKey::from(&[0xbb; PUBLIC_KEY_LEN]);
+
+        let (a_ac, c_ac) = mpsc::channel::<RejectConnection>(0);
+        let (c_ca, mut a_ca) = mpsc::channel::<IncomingConnection>(0);
+        let incoming_listen_a = IncomingListen {
+            receiver: c_ac,
+            sender: c_ca.sink_map_err(|_| ()),
+        };
+        let incoming_conn_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Listen(incoming_listen_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_a)).unwrap();
+
+        // B connects to A:
+        let (b_bc, c_bc) = mpsc::channel::<Vec<u8>>(0);
+        let (c_cb, mut b_cb) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_connect_b = IncomingConnect {
+            receiver: c_bc,
+            sender: c_cb.sink_map_err(|_| ()),
+            connect_public_key: a_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_b = IncomingConn {
+            public_key: b_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Connect(incoming_connect_b),
+        };
+        await!(outgoing_conns.send(incoming_conn_b)).unwrap();
+
+        let msg = await!(a_ca.next()).unwrap();
+        assert_eq!(
+            msg,
+            IncomingConnection {
+                public_key: b_public_key.clone()
+            }
+        );
+
+        // A accepts B's connection:
+        let (mut a_ac1, c_ac1) = mpsc::channel::<Vec<u8>>(0);
+        let (c_ca1, mut a_ca1) = mpsc::channel::<Vec<u8>>(0);
+        let incoming_accept_a = IncomingAccept {
+            receiver: c_ac1,
+            sender: c_ca1.sink_map_err(|_| ()),
+            accept_public_key: b_public_key.clone(),
+            punch: false,
+        };
+        let incoming_conn_accept_a = IncomingConn {
+            public_key: a_public_key.clone(),
+            opt_address: None,
+            inner: IncomingConnInner::Accept(incoming_accept_a),
+        };
+        await!(outgoing_conns.send(incoming_conn_accept_a)).unwrap();
+
+        // Stop accepting connections. The relay server should start draining:
+        drop(outgoing_conns);
+
+        // The listener is disconnected:
+        assert!(await!(a_ca.next()).is_none());
+
+        // The open tunnel keeps working while draining:
+        await!(a_ac1.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(b_cb.next()).unwrap(), vec![1, 2, 3]);
+
+        // Drain times out:
+        for _ in 0..DRAIN_TICKS {
+            await!(tick_sender.send(())).unwrap();
+        }
+
+        // The tunnel is closed, although both sides are still connected:
+        assert!(await!(b_cb.next()).is_none());
+        assert!(await!(a_ca1.next()).is_none());
+        assert!(await!(loop_done_receiver).unwrap());
+
+        drop(a_ac);
+        drop(a_ac1);
+        drop(b_bc);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay_server_drain_timeout() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool
+            .run(task_relay_server_drain_timeout(thread_pool.clone()))
+            .unwrap();
+    }
+
     // TODO: Add tests:
     // - Timeout of half tunnels
     //      (Do some action first, to make sure timer_stream was already obtained).
-    // - Graceful shutdown if incoming_conns is closed.
     // - Duplicate connections should be denied. (Same (initiator_pk, listener_pk) pair).
     // - Tunnel keeps working even if listener is disconnected.
 }
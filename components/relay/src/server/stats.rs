@@ -0,0 +1,52 @@
+use std::fmt;
+
+/// Counters describing the activity of a relay server.
+/// Logged periodically, to allow operators to capacity-plan their relays.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayStats {
+    /// Amount of clients currently listening
+    pub num_listeners: usize,
+    /// Amount of half tunnels waiting to be accepted
+    pub num_half_tunnels: usize,
+    /// Amount of open tunnels
+    pub num_tunnels: usize,
+    /// Total amount of bytes forwarded through tunnels
+    pub bytes_forwarded: u64,
+    /// Total amount of rejected connections (Due to access control, quotas, too many concurrent
+    /// handshakes or invalid requests)
+    pub num_rejected: u64,
+}
+
+impl fmt::Display for RelayStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "listeners={} half_tunnels={} tunnels={} bytes_forwarded={} rejected={}",
+            self.num_listeners,
+            self.num_half_tunnels,
+            self.num_tunnels,
+            self.bytes_forwarded,
+            self.num_rejected
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_stats_display() {
+        let relay_stats = RelayStats {
+            num_listeners: 3,
+            num_half_tunnels: 1,
+            num_tunnels: 2,
+            bytes_forwarded: 1000,
+            num_rejected: 4,
+        };
+        assert_eq!(
+            relay_stats.to_string(),
+            "listeners=3 half_tunnels=1 tunnels=2 bytes_forwarded=1000 rejected=4"
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crypto::identity::PublicKey;
+use proto::net::messages::NetAddress;
 
 pub struct IncomingListen<M, K> {
     pub receiver: M,
@@ -9,12 +10,16 @@ pub struct IncomingAccept<M, K> {
     pub receiver: M,
     pub sender: K,
     pub accept_public_key: PublicKey,
+    /// Did the accepting side ask for the address of the connecting side?
+    pub punch: bool,
 }
 
 pub struct IncomingConnect<M, K> {
     pub receiver: M,
     pub sender: K,
     pub connect_public_key: PublicKey,
+    /// Did the connecting side ask for the address of the accepting side?
+    pub punch: bool,
 }
 
 pub enum IncomingConnInner<ML, KL, MA, KA, MC, KC> {
@@ -25,5 +30,7 @@ pub enum IncomingConnInner<ML, KL, MA, KA, MC, KC> {
 
 pub struct IncomingConn<ML, KL, MA, KA, MC, KC> {
     pub public_key: PublicKey,
+    /// Address of the remote side, as observed by the relay (If known)
+    pub opt_address: Option<NetAddress>,
     pub inner: IncomingConnInner<ML, KL, MA, KA, MC, KC>,
 }
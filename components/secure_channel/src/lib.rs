@@ -23,3 +23,4 @@ mod secure_channel;
 mod state;
 
 pub use self::secure_channel::SecureChannel;
+pub use self::state::HandshakeMode;
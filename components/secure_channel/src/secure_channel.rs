@@ -12,7 +12,7 @@ use crypto::identity::PublicKey;
 use identity::IdentityClient;
 use timer::TimerClient;
 
-use crate::state::{ScState, ScStateError, ScStateInitial};
+use crate::state::{HandshakeMode, ScState, ScStateError, ScStateInitial};
 use proto::secure_channel::messages::{EncryptedData, PlainData};
 use proto::secure_channel::serialize::{
     deserialize_exchange_dh, deserialize_exchange_rand_nonce, serialize_exchange_dh,
@@ -22,6 +22,7 @@ use proto::secure_channel::serialize::{
 #[derive(Debug)]
 enum SecureChannelError {
     IdentityFailure,
+    CreateInitialStateError(ScStateError),
     WriterError,
     ReaderClosed,
     DeserializeRandNonceError,
@@ -39,6 +40,7 @@ async fn initial_exchange<EK, M: 'static, K: 'static, R: CryptoRandom + 'static>
     mut reader: M,
     identity_client: IdentityClient,
     opt_expected_remote: Option<PublicKey>,
+    handshake_mode: HandshakeMode,
     rng: R,
 ) -> Result<(ScState, K, M), SecureChannelError>
 where
@@ -49,7 +51,9 @@ where
     let local_public_key = await!(identity_client.request_public_key())
         .map_err(|_| SecureChannelError::IdentityFailure)?;
 
-    let (dh_state_initial, exchange_rand_nonce) = ScStateInitial::new(&local_public_key, &rng);
+    let (dh_state_initial, exchange_rand_nonce) =
+        ScStateInitial::new(&local_public_key, handshake_mode, &rng)
+            .map_err(SecureChannelError::CreateInitialStateError)?;
     let ser_exchange_rand_nonce = serialize_exchange_rand_nonce(&exchange_rand_nonce);
     await!(writer.send(ser_exchange_rand_nonce)).map_err(|_| SecureChannelError::WriterError)?;
 
@@ -99,6 +103,8 @@ async fn secure_channel_loop<EK, M: 'static, K: 'static, R: CryptoRandom + 'stat
     mut to_user: mpsc::Sender<Vec<u8>>,
     rng: R,
     ticks_to_rekey: usize,
+    bytes_to_rekey: u64,
+    messages_to_rekey: u64,
     mut timer_client: TimerClient,
 ) -> Result<(), SecureChannelError>
 where
@@ -152,6 +158,17 @@ where
             SecureChannelEvent::User(data) => {
                 let enc_data = dh_state.create_outgoing(&PlainData(data), &rng);
                 await!(writer.send(enc_data.0)).map_err(|_| SecureChannelError::WriterError)?;
+
+                if !dh_state.traffic_requires_rekey(bytes_to_rekey, messages_to_rekey) {
+                    continue;
+                }
+                let enc_data = match dh_state.create_rekey(&rng) {
+                    Ok(enc_data) => enc_data,
+                    Err(ScStateError::RekeyInProgress) => continue,
+                    Err(_) => unreachable!(),
+                };
+                await!(writer.send(enc_data.0)).map_err(|_| SecureChannelError::WriterError)?;
+                cur_ticks_to_rekey = ticks_to_rekey;
             }
             SecureChannelEvent::TimerTick => {
                 if let Some(new_cur_ticks_to_rekey) = cur_ticks_to_rekey.checked_sub(1) {
@@ -183,7 +200,12 @@ where
 /// identity is permitted. `Some(public_key)` means that only the identity `public_key` is allowed.
 ///
 /// `ticks_to_rekey` is the amount of time ticks it takes to issue a rekey, changing the symmetric
-/// key used for the encryption.
+/// key used for the encryption. A rekey is also issued after `bytes_to_rekey` bytes or
+/// `messages_to_rekey` messages were encrypted using the same key, whichever comes first.
+///
+/// `handshake_mode` determines whether a post-quantum KEM is combined with Diffie Hellman during
+/// the initial key exchange. A side using `HandshakeMode::Negotiate` can communicate with a remote
+/// side using any mode. Otherwise both sides must use the same mode.
 async fn create_secure_channel<EK, M, K, R, S>(
     writer: K,
     reader: M,
@@ -192,6 +214,9 @@ async fn create_secure_channel<EK, M, K, R, S>(
     rng: R,
     timer_client: TimerClient,
     ticks_to_rekey: usize,
+    bytes_to_rekey: u64,
+    messages_to_rekey: u64,
+    handshake_mode: HandshakeMode,
     mut spawner: S,
 ) -> Result<(PublicKey, ConnPairVec), SecureChannelError>
 where
@@ -206,6 +231,7 @@ where
         reader,
         identity_client,
         opt_expected_remote,
+        handshake_mode,
         rng.clone()
     ))?;
 
@@ -222,6 +248,8 @@ where
         to_user,
         rng.clone(),
         ticks_to_rekey,
+        bytes_to_rekey,
+        messages_to_rekey,
         timer_client,
     );
 
@@ -243,6 +271,9 @@ pub struct SecureChannel<R, S> {
     rng: R,
     timer_client: TimerClient,
     ticks_to_rekey: usize,
+    bytes_to_rekey: u64,
+    messages_to_rekey: u64,
+    handshake_mode: HandshakeMode,
     spawner: S,
 }
 
@@ -252,6 +283,9 @@ impl<R, S> SecureChannel<R, S> {
         rng: R,
         timer_client: TimerClient,
         ticks_to_rekey: usize,
+        bytes_to_rekey: u64,
+        messages_to_rekey: u64,
+        handshake_mode: HandshakeMode,
         spawner: S,
     ) -> SecureChannel<R, S> {
         SecureChannel {
@@ -259,6 +293,9 @@ impl<R, S> SecureChannel<R, S> {
             rng,
             timer_client,
             ticks_to_rekey,
+            bytes_to_rekey,
+            messages_to_rekey,
+            handshake_mode,
             spawner,
         }
     }
@@ -296,6 +333,9 @@ where
                     self.rng.clone(),
                     self.timer_client.clone(),
                     self.ticks_to_rekey,
+                    self.bytes_to_rekey,
+                    self.messages_to_rekey,
+                    self.handshake_mode,
                     self.spawner.clone()
                 ))
                 .ok()
@@ -396,6 +436,9 @@ mod tests {
             rng1.clone(),
             timer_client.clone(),
             ticks_to_rekey,
+            u64::max_value(),
+            u64::max_value(),
+            HandshakeMode::Classic,
             thread_pool.clone(),
         );
 
@@ -407,6 +450,9 @@ mod tests {
             rng2.clone(),
             timer_client.clone(),
             ticks_to_rekey,
+            u64::max_value(),
+            u64::max_value(),
+            HandshakeMode::Classic,
             thread_pool.clone(),
         );
 
@@ -431,4 +477,99 @@ mod tests {
         assert_eq!(true, thread_pool.run(output_receiver1).unwrap());
         assert_eq!(true, thread_pool.run(output_receiver2).unwrap());
     }
+
+    #[test]
+    fn test_secure_channel_rekey_by_messages() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+
+        // Time never advances, so only traffic can cause rekeying:
+        let (_tick_sender, tick_receiver) = mpsc::channel::<()>(0);
+        let timer_client = create_timer_incoming(tick_receiver, thread_pool.clone()).unwrap();
+
+        let rng1 = DummyRandom::new(&[1u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng1);
+        let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+        let (requests_sender1, identity_server1) = create_identity(identity1);
+        let identity_client1 = IdentityClient::new(requests_sender1);
+
+        let rng2 = DummyRandom::new(&[2u8]);
+        let pkcs8 = generate_pkcs8_key_pair(&rng2);
+        let identity2 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
+        let (requests_sender2, identity_server2) = create_identity(identity2);
+        let identity_client2 = IdentityClient::new(requests_sender2);
+
+        thread_pool
+            .spawn(identity_server1.then(|_| future::ready(())))
+            .unwrap();
+        thread_pool
+            .spawn(identity_server2.then(|_| future::ready(())))
+            .unwrap();
+
+        let (sender1, receiver2) = mpsc::channel::<Vec<u8>>(0);
+        let (sender2, receiver1) = mpsc::channel::<Vec<u8>>(0);
+
+        // The first side rekeys after every 2 messages:
+        let fut_sc1 = create_secure_channel(
+            sender1.sink_map_err(|_| ()),
+            receiver1,
+            identity_client1,
+            None,
+            rng1.clone(),
+            timer_client.clone(),
+            usize::max_value(),
+            u64::max_value(),
+            2,
+            HandshakeMode::Classic,
+            thread_pool.clone(),
+        );
+
+        // The second side is driven manually, to observe the rekey messages:
+        let fut_exchange2 = initial_exchange(
+            sender2.sink_map_err(|_| ()),
+            receiver2,
+            identity_client2,
+            None,
+            HandshakeMode::Classic,
+            rng2.clone(),
+        );
+
+        thread_pool.run(
+            async move {
+                let (res1, res2) = await!(future::join(fut_sc1, fut_exchange2));
+                let (_public_key, (mut sender1, mut receiver1)) = res1.unwrap();
+                let (mut dh_state2, mut writer2, mut reader2) = res2.unwrap();
+
+                let mut num_rekeys: usize = 0;
+                for i in 0..10u8 {
+                    await!(sender1.send(vec![i])).unwrap();
+
+                    // Rekey messages are answered until the user message arrives:
+                    loop {
+                        let data = await!(reader2.next()).unwrap();
+                        let hi_output = dh_state2
+                            .handle_incoming(&EncryptedData(data), &rng2)
+                            .unwrap();
+                        if hi_output.rekey_occurred {
+                            num_rekeys += 1;
+                        }
+                        if let Some(send_message) = hi_output.opt_send_message {
+                            await!(writer2.send(send_message.0)).unwrap();
+                        }
+                        if let Some(incoming_message) = hi_output.opt_incoming_message {
+                            assert_eq!(incoming_message.0, vec![i]);
+                            break;
+                        }
+                    }
+
+                    // The first side can read messages encrypted using the new keys:
+                    let enc_data = dh_state2.create_outgoing(&PlainData(vec![i]), &rng2);
+                    await!(writer2.send(enc_data.0)).unwrap();
+                    assert_eq!(await!(receiver1.next()).unwrap(), vec![i]);
+                }
+
+                // Time never advanced, so all the rekeys were caused by the amount of messages:
+                assert!(num_rekeys > 0);
+            },
+        );
+    }
 }
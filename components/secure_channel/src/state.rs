@@ -1,9 +1,12 @@
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
 
+use common::int_convert::usize_to_u64;
+
 use crypto::crypto_rand::{CryptoRandom, RandValue};
 use crypto::dh::{DhPrivateKey, Salt};
 use crypto::identity::{verify_signature, PublicKey, Signature};
+use crypto::kem::{kem_encapsulate, KemPrivateKey, KemPublicKey, KemSharedSecret};
 use crypto::sym_encrypt::{Decryptor, Encryptor};
 use identity::IdentityClient;
 use proto::secure_channel::messages::{
@@ -26,11 +29,39 @@ pub enum ScStateError {
     DecryptionFailure,
     DeserializeError,
     RekeyInProgress,
+    MissingKemPublicKey,
+    MissingKemCiphertext,
+    KemKeyGenFailure,
+    KemEncapsulateFailure,
+    KemDecapsulateFailure,
+}
+
+/// The kind of key exchange used when setting up a secure channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeMode {
+    /// Diffie Hellman (X25519) only
+    Classic,
+    /// Diffie Hellman (X25519) combined with a post-quantum KEM.
+    /// The symmetric keys are derived from both shared secrets, so the channel remains secure as
+    /// long as at least one of the two key exchanges is not broken.
+    /// Note that rekeying is done using Diffie Hellman only.
+    /// The remote side must use the hybrid handshake too.
+    Hybrid,
+    /// Offer the hybrid handshake. The hybrid handshake is used if the remote side offers it too,
+    /// otherwise the classic handshake is used.
+    /// The offers are signed by both sides, so an active attacker can not strip them to make both
+    /// sides fall back to the classic handshake.
+    Negotiate,
 }
 
 pub struct ScStateInitial {
     local_public_key: PublicKey,
     local_rand_nonce: RandValue,
+    handshake_mode: HandshakeMode,
+    /// Only used in the hybrid handshake
+    opt_kem_private_key: Option<KemPrivateKey>,
+    /// The KEM public key we sent to the remote side (If we offered the hybrid handshake)
+    opt_kem_public_key: Option<KemPublicKey>,
 }
 
 pub struct ScStateHalf {
@@ -39,6 +70,13 @@ pub struct ScStateHalf {
     local_rand_nonce: RandValue,
     dh_private_key: DhPrivateKey,
     local_salt: Salt,
+    /// Only used in the hybrid handshake:
+    /// Our KEM private key, and the secret we encapsulated for the remote side.
+    opt_kem_pair: Option<(KemPrivateKey, KemSharedSecret)>,
+    /// The KEM public key we sent to the remote side (If we offered the hybrid handshake)
+    opt_kem_public_key: Option<KemPublicKey>,
+    /// Did the remote side offer the hybrid handshake?
+    remote_kem_offer: bool,
 }
 
 struct PendingRekey {
@@ -57,24 +95,42 @@ pub struct ScState {
     /// messages for the new receiver.
     opt_old_receiver: Option<Decryptor>,
     opt_pending_rekey: Option<PendingRekey>,
+    /// Amount of bytes encrypted using the current sender
+    bytes_since_rekey: u64,
+    /// Amount of messages encrypted using the current sender
+    messages_since_rekey: u64,
 }
 
 impl ScStateInitial {
     pub fn new<R: CryptoRandom>(
         local_public_key: &PublicKey,
+        handshake_mode: HandshakeMode,
         rng: &R,
-    ) -> (ScStateInitial, ExchangeRandNonce) {
+    ) -> Result<(ScStateInitial, ExchangeRandNonce), ScStateError> {
         let local_rand_nonce = RandValue::new(rng);
 
+        let (opt_kem_private_key, opt_kem_public_key) = match handshake_mode {
+            HandshakeMode::Classic => (None, None),
+            HandshakeMode::Hybrid | HandshakeMode::Negotiate => {
+                let (kem_private_key, kem_public_key) =
+                    KemPrivateKey::generate(rng).map_err(|_| ScStateError::KemKeyGenFailure)?;
+                (Some(kem_private_key), Some(kem_public_key))
+            }
+        };
+
         let sc_state_initial = ScStateInitial {
             local_public_key: local_public_key.clone(),
             local_rand_nonce: local_rand_nonce.clone(),
+            handshake_mode,
+            opt_kem_private_key,
+            opt_kem_public_key: opt_kem_public_key.clone(),
         };
         let exchange_rand_nonce = ExchangeRandNonce {
             rand_nonce: local_rand_nonce,
             public_key: local_public_key.clone(),
+            opt_kem_public_key,
         };
-        (sc_state_initial, exchange_rand_nonce)
+        Ok((sc_state_initial, exchange_rand_nonce))
     }
 
     pub async fn handle_exchange_rand_nonce<R: CryptoRandom + 'static>(
@@ -90,12 +146,36 @@ impl ScStateInitial {
             .map_err(|_| ScStateError::DhPublicKeyComputeFailure)?;;
         let local_salt = Salt::new(&rng).map_err(|_| ScStateError::SaltGenFailure)?;
 
+        // In the hybrid handshake, encapsulate a secret for the remote side.
+        // The hybrid handshake is used only if both sides offered it:
+        let (opt_kem_pair, opt_kem_ciphertext) = match (
+            self.opt_kem_private_key,
+            &exchange_rand_nonce.opt_kem_public_key,
+        ) {
+            (Some(kem_private_key), Some(remote_kem_public_key)) => {
+                let (sent_kem_secret, kem_ciphertext) =
+                    kem_encapsulate(remote_kem_public_key, &rng)
+                        .map_err(|_| ScStateError::KemEncapsulateFailure)?;
+                (Some((kem_private_key, sent_kem_secret)), Some(kem_ciphertext))
+            }
+            (Some(_), None) => {
+                if self.handshake_mode == HandshakeMode::Hybrid {
+                    return Err(ScStateError::MissingKemPublicKey);
+                }
+                (None, None)
+            }
+            (None, _) => (None, None),
+        };
+
         let sc_state_half = ScStateHalf {
             remote_public_key: exchange_rand_nonce.public_key,
             local_public_key: self.local_public_key,
             local_rand_nonce: self.local_rand_nonce,
             dh_private_key,
             local_salt: local_salt.clone(),
+            opt_kem_pair,
+            opt_kem_public_key: self.opt_kem_public_key.clone(),
+            remote_kem_offer: exchange_rand_nonce.opt_kem_public_key.is_some(),
         };
 
         let mut exchange_dh = ExchangeDh {
@@ -103,9 +183,14 @@ impl ScStateInitial {
             rand_nonce: exchange_rand_nonce.rand_nonce,
             key_salt: local_salt,
             signature: Signature::zero(),
+            opt_kem_ciphertext,
         };
-        exchange_dh.signature =
-            await!(identity_client.request_signature(exchange_dh.signature_buffer())).unwrap();
+        // The offer of the remote side is signed, so that it can not be stripped unnoticed:
+        let sbuffer = exchange_dh.signature_buffer(
+            exchange_rand_nonce.opt_kem_public_key.as_ref(),
+            self.opt_kem_public_key.is_some(),
+        );
+        exchange_dh.signature = await!(identity_client.request_signature(sbuffer)).unwrap();
 
         Ok((sc_state_half, exchange_dh))
     }
@@ -118,8 +203,11 @@ impl ScStateHalf {
         if self.local_rand_nonce != exchange_dh.rand_nonce {
             return Err(ScStateError::IncorrectRandNonce);
         }
-        // Verify signature:
-        let sbuffer = exchange_dh.signature_buffer();
+        // Verify signature.
+        // The remote side signs the KEM public key it received from us, and whether it offered
+        // the hybrid handshake. If any of the offers was stripped, the signature is invalid:
+        let sbuffer =
+            exchange_dh.signature_buffer(self.opt_kem_public_key.as_ref(), self.remote_kem_offer);
         if !verify_signature(&sbuffer, &self.remote_public_key, &exchange_dh.signature) {
             return Err(ScStateError::InvalidSignature);
         }
@@ -129,14 +217,29 @@ impl ScStateHalf {
     pub fn handle_exchange_dh(self, exchange_dh: ExchangeDh) -> Result<ScState, ScStateError> {
         self.verify_exchange_dh(&exchange_dh)?;
 
-        let (send_key, recv_key) = self
-            .dh_private_key
-            .derive_symmetric_key(
+        let (send_key, recv_key) = match self.opt_kem_pair {
+            None => self.dh_private_key.derive_symmetric_key(
                 exchange_dh.dh_public_key,
                 self.local_salt,
                 exchange_dh.key_salt,
-            )
-            .map_err(|_| ScStateError::KeyDerivationFailure)?;
+            ),
+            Some((kem_private_key, sent_kem_secret)) => {
+                let kem_ciphertext = exchange_dh
+                    .opt_kem_ciphertext
+                    .ok_or(ScStateError::MissingKemCiphertext)?;
+                let recv_kem_secret = kem_private_key
+                    .decapsulate(&kem_ciphertext)
+                    .map_err(|_| ScStateError::KemDecapsulateFailure)?;
+                self.dh_private_key.derive_symmetric_key_hybrid(
+                    exchange_dh.dh_public_key,
+                    self.local_salt,
+                    exchange_dh.key_salt,
+                    &sent_kem_secret,
+                    &recv_kem_secret,
+                )
+            }
+        }
+        .map_err(|_| ScStateError::KeyDerivationFailure)?;
 
         Ok(ScState {
             local_public_key: self.local_public_key,
@@ -146,6 +249,8 @@ impl ScStateHalf {
                 .map_err(|_| ScStateError::CreateDecryptorFailure)?,
             opt_old_receiver: None,
             opt_pending_rekey: None,
+            bytes_since_rekey: 0,
+            messages_since_rekey: 0,
         })
     }
 }
@@ -168,6 +273,10 @@ impl ScState {
         };
         let ser_channel_message = serialize_channel_message(&channel_message);
         let enc_channel_message = self.sender.encrypt(&ser_channel_message).unwrap();
+        self.bytes_since_rekey = self
+            .bytes_since_rekey
+            .saturating_add(usize_to_u64(enc_channel_message.len()).unwrap());
+        self.messages_since_rekey = self.messages_since_rekey.saturating_add(1);
         EncryptedData(enc_channel_message)
     }
 
@@ -252,6 +361,18 @@ impl ScState {
         Ok(self.encrypt_outgoing(ChannelContent::Rekey(rekey), rng))
     }
 
+    /// Replace the sender after a rekey, resetting the traffic counters
+    fn set_sender(&mut self, sender: Encryptor) {
+        self.sender = sender;
+        self.bytes_since_rekey = 0;
+        self.messages_since_rekey = 0;
+    }
+
+    /// Check if enough traffic was encrypted using the current sender to require a rekey.
+    pub fn traffic_requires_rekey(&self, bytes_to_rekey: u64, messages_to_rekey: u64) -> bool {
+        self.bytes_since_rekey >= bytes_to_rekey || self.messages_since_rekey >= messages_to_rekey
+    }
+
     fn handle_incoming_rekey<R: CryptoRandom>(
         &mut self,
         rekey: Rekey,
@@ -281,7 +402,7 @@ impl ScState {
                 };
                 let rekey_data = self.encrypt_outgoing(ChannelContent::Rekey(rekey), rng);
 
-                self.sender = new_sender;
+                self.set_sender(new_sender);
                 Ok(HandleIncomingOutput {
                     rekey_occurred: true,
                     opt_send_message: Some(rekey_data),
@@ -297,8 +418,9 @@ impl ScState {
                         rekey.key_salt,
                     )
                     .map_err(|_| ScStateError::KeyDerivationFailure)?;
-                self.sender =
+                let new_sender =
                     Encryptor::new(&send_key).map_err(|_| ScStateError::CreateEncryptorFailure)?;
+                self.set_sender(new_sender);
                 let new_receiver =
                     Decryptor::new(&recv_key).map_err(|_| ScStateError::CreateDecryptorFailure)?;
                 self.opt_old_receiver = Some(mem::replace(&mut self.receiver, new_receiver));
@@ -348,31 +470,37 @@ mod tests {
     async fn run_basic_sc_state(
         identity_client1: IdentityClient,
         identity_client2: IdentityClient,
-    ) -> Result<(ScState, ScState), ()> {
+        handshake_mode1: HandshakeMode,
+        handshake_mode2: HandshakeMode,
+        strip_kem_offer1: bool,
+    ) -> Result<(ScState, ScState), ScStateError> {
         let rng1 = DummyRandom::new(&[1u8]);
         let rng2 = DummyRandom::new(&[2u8]);
         let local_public_key1 = await!(identity_client1.request_public_key()).unwrap();
         let local_public_key2 = await!(identity_client2.request_public_key()).unwrap();
-        let (sc_state_initial1, exchange_rand_nonce1) =
-            ScStateInitial::new(&local_public_key1, &rng1);
+        let (sc_state_initial1, mut exchange_rand_nonce1) =
+            ScStateInitial::new(&local_public_key1, handshake_mode1, &rng1)?;
         let (sc_state_initial2, exchange_rand_nonce2) =
-            ScStateInitial::new(&local_public_key2, &rng2);
+            ScStateInitial::new(&local_public_key2, handshake_mode2, &rng2)?;
+
+        // An attacker in the middle may strip the hybrid handshake offer of the first side:
+        if strip_kem_offer1 {
+            exchange_rand_nonce1.opt_kem_public_key = None;
+        }
 
         let (sc_state_half1, exchange_dh1) = await!(sc_state_initial1.handle_exchange_rand_nonce(
             exchange_rand_nonce2,
             identity_client1.clone(),
             rng1.clone()
-        ))
-        .unwrap();
+        ))?;
         let (sc_state_half2, exchange_dh2) = await!(sc_state_initial2.handle_exchange_rand_nonce(
             exchange_rand_nonce1,
             identity_client2.clone(),
             rng2.clone()
-        ))
-        .unwrap();
+        ))?;
 
-        let sc_state1 = sc_state_half1.handle_exchange_dh(exchange_dh2).unwrap();
-        let sc_state2 = sc_state_half2.handle_exchange_dh(exchange_dh1).unwrap();
+        let sc_state1 = sc_state_half1.handle_exchange_dh(exchange_dh2)?;
+        let sc_state2 = sc_state_half2.handle_exchange_dh(exchange_dh1)?;
         Ok((sc_state1, sc_state2))
     }
 
@@ -442,7 +570,11 @@ mod tests {
         assert_eq!(incoming_output2.opt_incoming_message, None);
     }
 
-    fn prepare_dh_test() -> (ScState, ScState, DummyRandom, DummyRandom) {
+    fn try_prepare_dh_test(
+        handshake_mode1: HandshakeMode,
+        handshake_mode2: HandshakeMode,
+        strip_kem_offer1: bool,
+    ) -> Result<(ScState, ScState, DummyRandom, DummyRandom), ScStateError> {
         let rng1 = DummyRandom::new(&[1u8]);
         let pkcs8 = generate_pkcs8_key_pair(&rng1);
         let identity1 = SoftwareEd25519Identity::from_pkcs8(&pkcs8).unwrap();
@@ -464,11 +596,19 @@ mod tests {
             .spawn(identity_server2.then(|_| future::ready(())))
             .unwrap();
 
-        let (sc_state1, sc_state2) = thread_pool
-            .run(run_basic_sc_state(identity_client1, identity_client2))
-            .unwrap();
+        let (sc_state1, sc_state2) = thread_pool.run(run_basic_sc_state(
+            identity_client1,
+            identity_client2,
+            handshake_mode1,
+            handshake_mode2,
+            strip_kem_offer1,
+        ))?;
+
+        Ok((sc_state1, sc_state2, rng1, rng2))
+    }
 
-        (sc_state1, sc_state2, rng1, rng2)
+    fn prepare_dh_test() -> (ScState, ScState, DummyRandom, DummyRandom) {
+        try_prepare_dh_test(HandshakeMode::Classic, HandshakeMode::Classic, false).unwrap()
     }
 
     #[test]
@@ -480,6 +620,69 @@ mod tests {
         rekey_simultaneous(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
         send_recv_messages(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
     }
+
+    #[test]
+    fn test_hybrid_sc_state() {
+        let (mut sc_state1, mut sc_state2, rng1, rng2) =
+            try_prepare_dh_test(HandshakeMode::Hybrid, HandshakeMode::Hybrid, false).unwrap();
+        send_recv_messages(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+        rekey_sequential(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+        send_recv_messages(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+    }
+
+    #[test]
+    fn test_negotiate_sc_state() {
+        // Negotiation succeeds with any remote handshake mode:
+        let remote_modes = [
+            HandshakeMode::Classic,
+            HandshakeMode::Hybrid,
+            HandshakeMode::Negotiate,
+        ];
+        for remote_mode in &remote_modes {
+            let (mut sc_state1, mut sc_state2, rng1, rng2) =
+                try_prepare_dh_test(HandshakeMode::Negotiate, *remote_mode, false).unwrap();
+            send_recv_messages(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+            rekey_sequential(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+            send_recv_messages(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+        }
+    }
+
+    #[test]
+    fn test_hybrid_sc_state_mode_mismatch() {
+        // The hybrid side refuses to continue without the remote KEM public key:
+        match try_prepare_dh_test(HandshakeMode::Hybrid, HandshakeMode::Classic, false) {
+            Err(ScStateError::MissingKemPublicKey) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_negotiate_sc_state_stripped_offer() {
+        // Stripping the offer of one side is noticed by that side, even if the other side does
+        // not require the hybrid handshake:
+        match try_prepare_dh_test(HandshakeMode::Negotiate, HandshakeMode::Negotiate, true) {
+            Err(ScStateError::InvalidSignature) => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn test_sc_state_traffic_requires_rekey() {
+        let (mut sc_state1, mut sc_state2, rng1, rng2) = prepare_dh_test();
+        assert!(!sc_state1.traffic_requires_rekey(1, 1));
+
+        // Every side sends 5 messages:
+        send_recv_messages(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+        assert!(sc_state1.traffic_requires_rekey(u64::max_value(), 5));
+        assert!(!sc_state1.traffic_requires_rekey(u64::max_value(), 6));
+        assert!(sc_state1.traffic_requires_rekey(5 * 6, u64::max_value()));
+
+        // Counters are reset after rekeying:
+        rekey_sequential(&mut sc_state1, &mut sc_state2, &rng1, &rng2);
+        assert!(!sc_state1.traffic_requires_rekey(1, 1));
+        assert!(!sc_state2.traffic_requires_rekey(1, 1));
+    }
+
     // TODO: Add tests:
     // - Test the usage of old receiver
     // - Test error cases
@@ -133,6 +133,15 @@ pub struct ResetFriendCmd {
     pub friend_name: String,
 }
 
+/// Set privacy mode for capacities reported to index servers
+#[derive(Clone, Debug, StructOpt)]
+pub struct SetCapacityBucketCmd {
+    /// Round down reported capacities to a multiple of this value.
+    /// If not specified, exact capacities are reported.
+    #[structopt(long = "bucket", short = "b")]
+    pub capacity_bucket: Option<u128>,
+}
+
 #[derive(Clone, Debug, StructOpt)]
 pub enum ConfigCmd {
     /// Add a relay server
@@ -174,6 +183,9 @@ pub enum ConfigCmd {
     /// Reset mutual credit with a friend according to friend's terms
     #[structopt(name = "reset-friend")]
     ResetFriend(ResetFriendCmd),
+    /// Set privacy mode for capacities reported to index servers
+    #[structopt(name = "set-capacity-bucket")]
+    SetCapacityBucket(SetCapacityBucketCmd),
 }
 
 #[derive(Debug)]
@@ -295,6 +307,14 @@ async fn config_remove_index(
         .map_err(|_| ConfigError::AppConfigError)
 }
 
+async fn config_set_capacity_bucket(
+    set_capacity_bucket_cmd: SetCapacityBucketCmd,
+    mut app_config: AppConfig,
+) -> Result<(), ConfigError> {
+    await!(app_config.set_capacity_bucket(set_capacity_bucket_cmd.capacity_bucket))
+        .map_err(|_| ConfigError::AppConfigError)
+}
+
 async fn config_add_friend(
     add_friend_cmd: AddFriendCmd,
     mut app_config: AppConfig,
@@ -547,6 +567,9 @@ pub async fn config(
             app_config,
             node_report
         ))?,
+        ConfigCmd::SetCapacityBucket(set_capacity_bucket_cmd) => await!(
+            config_set_capacity_bucket(set_capacity_bucket_cmd, app_config)
+        )?,
     }
 
     Ok(())
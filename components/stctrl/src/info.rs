@@ -5,10 +5,13 @@ use prettytable::Table;
 use structopt::StructOpt;
 
 use app::report::{
-    ChannelStatusReport, FriendReport, FriendStatusReport, NodeReport, RequestsStatusReport,
+    ChannelStatusReport, FriendConnectionReport, FriendReport, FriendStatusReport, NodeReport,
+    RequestsStatusReport,
+};
+use app::ser_string::{public_key_to_string, string_to_public_key};
+use app::{
+    store_friend_to_file, AppReport, AppRoutes, FriendAddress, NodeConnection, RelayAddress,
 };
-use app::ser_string::public_key_to_string;
-use app::{store_friend_to_file, AppReport, FriendAddress, NodeConnection, RelayAddress};
 
 use crate::file::token::store_token_to_file;
 use crate::utils::friend_public_key_by_name;
@@ -27,7 +30,11 @@ pub struct IndexCmd {}
 
 /// Show all configured friend servers
 #[derive(Clone, Debug, StructOpt)]
-pub struct FriendsCmd {}
+pub struct FriendsCmd {
+    /// Show connection statistics for every friend
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+}
 
 /// Export last obtained token from a friend
 #[derive(Clone, Debug, StructOpt)]
@@ -52,6 +59,20 @@ pub struct ExportTicketCmd {
     pub output_file: PathBuf,
 }
 
+/// Show the nodes that can send funds to a destination
+#[derive(Clone, Debug, StructOpt)]
+pub struct ReachabilityCmd {
+    /// Minimal amount of credits the nodes should be able to send
+    #[structopt(short = "a", long = "amount")]
+    pub amount: u128,
+    /// Destination's public key (Defaults to the local node)
+    #[structopt(short = "d", long = "dest")]
+    pub opt_destination_str: Option<String>,
+    /// Maximum amount of nodes to show (Nodes with the largest capacities are shown first)
+    #[structopt(short = "m", long = "max")]
+    pub opt_max_nodes: Option<u32>,
+}
+
 #[derive(Clone, Debug, StructOpt)]
 pub enum InfoCmd {
     /// Show local public key (Used as address for sending funds)
@@ -75,6 +96,9 @@ pub enum InfoCmd {
     /// Export ticket for this node
     #[structopt(name = "export-ticket")]
     ExportTicket(ExportTicketCmd),
+    /// Show the nodes that can send funds to a destination
+    #[structopt(name = "reachability")]
+    Reachability(ReachabilityCmd),
 }
 
 #[derive(Debug)]
@@ -86,7 +110,11 @@ pub enum InfoError {
     FriendNameNotFound,
     MissingLastIncomingMoveToken,
     StoreLastIncomingMoveTokenError,
+    NoRoutesPermissions,
+    InvalidDestination,
+    AppRoutesError,
     WriteError,
+    EnableConnectionsReportError,
 }
 
 /// Get a most recently known node report:
@@ -230,17 +258,75 @@ fn friend_channel_status(friend_report: &FriendReport) -> String {
     res
 }
 
+/// A user friendly string describing the connection statistics of a friend
+fn friend_connection_status(
+    opt_friend_connection_report: Option<&FriendConnectionReport<RelayAddress>>,
+) -> String {
+    let friend_connection_report = match opt_friend_connection_report {
+        Some(friend_connection_report) => friend_connection_report,
+        None => return "?".to_owned(),
+    };
+
+    let mut res = String::new();
+    match friend_connection_report.opt_connected_since {
+        Some(connected_since) => res += &format!("since={}\n", connected_since),
+        None => res += "disconnected\n",
+    }
+    res += &format!("reconnects={}\n", friend_connection_report.reconnect_count);
+    res += &format!(
+        "sent={}B/{}msg\nrecv={}B/{}msg\n",
+        friend_connection_report.bytes_sent,
+        friend_connection_report.messages_sent,
+        friend_connection_report.bytes_received,
+        friend_connection_report.messages_received
+    );
+    match friend_connection_report.opt_last_rtt_ms {
+        Some(last_rtt_ms) => res += &format!("rtt={}ms\n", last_rtt_ms),
+        None => res += "rtt=?\n",
+    }
+    // Incoming direct connections have no relay address:
+    match (
+        &friend_connection_report.opt_relay,
+        friend_connection_report.opt_connected_since,
+    ) {
+        (Some(relay_address), _) if relay_address.address.is_direct() => {
+            res += &format!("direct={}", relay_address.address)
+        }
+        (Some(relay_address), _) => res += &format!("relay={}", relay_address.address),
+        (None, Some(_)) => res += "direct",
+        (None, None) => res += "relay=?",
+    }
+    for relay_health_report in &friend_connection_report.relays_health {
+        res += &format!(
+            "\n{}: ok={} fail={}",
+            relay_health_report.relay.address,
+            relay_health_report.successes,
+            relay_health_report.failures
+        );
+        if let Some(last_latency_ms) = relay_health_report.opt_last_latency_ms {
+            res += &format!(" latency={}ms", last_latency_ms);
+        }
+    }
+    res
+}
+
 pub async fn info_friends(
+    friends_cmd: FriendsCmd,
     mut app_report: AppReport,
     writer: &mut impl io::Write,
 ) -> Result<(), InfoError> {
+    let FriendsCmd { verbose } = friends_cmd;
     let report = await!(get_report(&mut app_report))?;
 
     let mut table = Table::new();
     // Add titlek:
-    table.set_titles(row!["st", "name", "balance"]);
+    if verbose {
+        table.set_titles(row!["st", "name", "balance", "connection"]);
+    } else {
+        table.set_titles(row!["st", "name", "balance"]);
+    }
 
-    for (_friend_public_key, friend_report) in &report.funder_report.friends {
+    for (friend_public_key, friend_report) in &report.funder_report.friends {
         // Is the friend enabled?
         let status_str = if friend_report.status == FriendStatusReport::Enabled {
             "E"
@@ -258,11 +344,22 @@ pub async fn info_friends(
         status_string += status_str;
         status_string += liveness_str;
 
-        table.add_row(row![
-            status_string,
-            friend_report.name,
-            friend_channel_status(&friend_report),
-        ]);
+        if verbose {
+            let opt_friend_connection_report =
+                report.connections_report.friends.get(friend_public_key);
+            table.add_row(row![
+                status_string,
+                friend_report.name,
+                friend_channel_status(&friend_report),
+                friend_connection_status(opt_friend_connection_report),
+            ]);
+        } else {
+            table.add_row(row![
+                status_string,
+                friend_report.name,
+                friend_channel_status(&friend_report),
+            ]);
+        }
     }
 
     if !table.is_empty() {
@@ -370,6 +467,48 @@ pub async fn info_export_ticket(
     Ok(())
 }
 
+pub async fn info_reachability(
+    reachability_cmd: ReachabilityCmd,
+    mut app_report: AppReport,
+    mut app_routes: AppRoutes,
+    writer: &mut impl io::Write,
+) -> Result<(), InfoError> {
+    let ReachabilityCmd {
+        amount,
+        opt_destination_str,
+        opt_max_nodes,
+    } = reachability_cmd;
+
+    let destination = match opt_destination_str {
+        Some(destination_str) => {
+            string_to_public_key(&destination_str).map_err(|_| InfoError::InvalidDestination)?
+        }
+        None => {
+            let report = await!(get_report(&mut app_report))?;
+            report.funder_report.local_public_key.clone()
+        }
+    };
+
+    let nodes = await!(app_routes.request_reachability(amount, destination, opt_max_nodes))
+        .map_err(|_| InfoError::AppRoutesError)?;
+
+    let mut table = Table::new();
+    // Add title:
+    table.set_titles(row!["public key", "capacity"]);
+
+    for node_with_capacity in &nodes {
+        let pk_string = public_key_to_string(&node_with_capacity.public_key);
+        table.add_row(row![pk_string, node_with_capacity.capacity]);
+    }
+    if !table.is_empty() {
+        table.print(writer).map_err(|_| InfoError::WriteError)?;
+    } else {
+        writeln!(writer, "No nodes can send the requested amount.")
+            .map_err(|_| InfoError::WriteError)?;
+    }
+    Ok(())
+}
+
 pub async fn info(
     info_cmd: InfoCmd,
     mut node_connection: NodeConnection,
@@ -381,7 +520,14 @@ pub async fn info(
         InfoCmd::PublicKey(_public_key_cmd) => await!(info_public_key(app_report, writer))?,
         InfoCmd::Relays(_relays_cmd) => await!(info_relays(app_report, writer))?,
         InfoCmd::Index(_index_cmd) => await!(info_index(app_report, writer))?,
-        InfoCmd::Friends(_friends_cmd) => await!(info_friends(app_report, writer))?,
+        InfoCmd::Friends(friends_cmd) => {
+            if friends_cmd.verbose {
+                // Connection statistics are only reported on request:
+                await!(node_connection.enable_connections_report())
+                    .map_err(|_| InfoError::EnableConnectionsReportError)?;
+            }
+            await!(info_friends(friends_cmd, app_report, writer))?
+        }
         InfoCmd::FriendLastToken(friend_last_token_cmd) => {
             await!(info_friend_last_token(friend_last_token_cmd, app_report))?
         }
@@ -389,6 +535,18 @@ pub async fn info(
         InfoCmd::ExportTicket(export_ticket_cmd) => {
             await!(info_export_ticket(export_ticket_cmd, app_report))?
         }
+        InfoCmd::Reachability(reachability_cmd) => {
+            let app_routes = node_connection
+                .routes()
+                .ok_or(InfoError::NoRoutesPermissions)?
+                .clone();
+            await!(info_reachability(
+                reachability_cmd,
+                app_report,
+                app_routes,
+                writer
+            ))?
+        }
     }
     Ok(())
 }
@@ -1,4 +1,5 @@
 use std::io;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use futures::executor::ThreadPool;
@@ -69,6 +70,9 @@ pub struct StCtrlCmd {
     /// Node ticket file path
     #[structopt(parse(from_os_str), short = "T", long = "ticket")]
     pub node_ticket: PathBuf,
+    /// Connect to the node through a SOCKS5 proxy (For example: 127.0.0.1:9050)
+    #[structopt(long = "proxy")]
+    pub proxy: Option<SocketAddr>,
     #[structopt(flatten)]
     pub subcommand: StCtrlSubcommand,
 }
@@ -79,6 +83,7 @@ pub fn stctrl(st_ctrl_cmd: StCtrlCmd, writer: &mut impl io::Write) -> Result<(),
     let StCtrlCmd {
         idfile,
         node_ticket,
+        proxy,
         subcommand,
     } = st_ctrl_cmd;
 
@@ -108,6 +113,7 @@ pub fn stctrl(st_ctrl_cmd: StCtrlCmd, writer: &mut impl io::Write) -> Result<(),
                 node_address.public_key,
                 node_address.address,
                 app_identity_client,
+                proxy,
                 c_thread_pool.clone()
             ))
             .map_err(|_| StCtrlError::ConnectionError)?;
@@ -1,8 +1,10 @@
-use std::{str, thread, time};
+use std::{fs, str, thread, time};
 
 use tempfile::tempdir;
 
-use bin::stindexlib::{stindex, StIndexCmd};
+use bin::stindexlib::{
+    stindex, AddServerCmd, ExportGraphCmd, RemoveServerCmd, RunCmd, StIndexCmd, StatusCmd,
+};
 use bin::stnodelib::{stnode, StNodeCmd};
 use bin::strelaylib::{strelay, StRelayCmd};
 
@@ -16,6 +18,9 @@ use stctrl::info::{
 };
 use stctrl::stctrllib::{stctrl, StCtrlCmd, StCtrlSubcommand};
 
+use proto::file::index_server::load_index_server_from_file;
+use proto::file::ser_string::public_key_to_string;
+
 use stctrl::stregisterlib::{
     stregister, GenInvoiceCmd, StRegisterCmd, VerifyReceiptCmd, VerifyTokenCmd,
 };
@@ -26,34 +31,68 @@ use crate::cli_tests::stctrl_setup::{create_stctrl_setup, StCtrlSetup};
 /// Spawn relay servers, index servers and nodes as threads
 fn spawn_entities(stctrl_setup: &StCtrlSetup) {
     // Spawn index0:
-    let st_index_cmd = StIndexCmd {
+    let st_index_cmd = StIndexCmd::Run(RunCmd {
         idfile: stctrl_setup
             .temp_dir_path
             .join("index0")
             .join("index0.ident"),
         lclient: stctrl_setup.index0_client_addr.parse().unwrap(),
+        ws_lclient: None,
         lserver: stctrl_setup.index0_server_addr.parse().unwrap(),
+        ws_lserver: None,
         trusted: stctrl_setup.temp_dir_path.join("index0").join("trusted"),
-    };
+        snapshot: None,
+        snapshot_ticks: None,
+        ladmin: Some(stctrl_setup.index0_admin_addr.parse().unwrap()),
+        routes_per_tick: None,
+        routes_burst: None,
+        max_route_queries: None,
+        capacity_bucket: None,
+        ban_threshold: None,
+        ban_ticks: None,
+        discovery: false,
+        max_discovered_servers: None,
+        drain_ticks: None,
+        proxy: None,
+        bytes_to_rekey: None,
+        messages_to_rekey: None,
+    });
     // TODO: How can we close this thread?
     thread::spawn(move || {
-        let res = stindex(st_index_cmd);
+        let res = stindex(st_index_cmd, &mut Vec::new());
         error!("index0 exited with: {:?}", res);
     });
 
     // Spawn index1:
-    let st_index_cmd = StIndexCmd {
+    let st_index_cmd = StIndexCmd::Run(RunCmd {
         idfile: stctrl_setup
             .temp_dir_path
             .join("index1")
             .join("index1.ident"),
         lclient: stctrl_setup.index1_client_addr.parse().unwrap(),
+        ws_lclient: None,
         lserver: stctrl_setup.index1_server_addr.parse().unwrap(),
+        ws_lserver: None,
         trusted: stctrl_setup.temp_dir_path.join("index1").join("trusted"),
-    };
+        snapshot: None,
+        snapshot_ticks: None,
+        ladmin: None,
+        routes_per_tick: None,
+        routes_burst: None,
+        max_route_queries: None,
+        capacity_bucket: None,
+        ban_threshold: None,
+        ban_ticks: None,
+        discovery: false,
+        max_discovered_servers: None,
+        drain_ticks: None,
+        proxy: None,
+        bytes_to_rekey: None,
+        messages_to_rekey: None,
+    });
     // TODO: How can we close this thread?
     thread::spawn(move || {
-        let res = stindex(st_index_cmd);
+        let res = stindex(st_index_cmd, &mut Vec::new());
         error!("index1 exited with: {:?}", res);
     });
 
@@ -64,6 +103,16 @@ fn spawn_entities(stctrl_setup: &StCtrlSetup) {
             .join("relay0")
             .join("relay0.ident"),
         laddr: stctrl_setup.relay0_addr.parse().unwrap(),
+        ws_laddr: None,
+        max_tunnels: None,
+        listens_per_tick: None,
+        bytes_per_tick: None,
+        listen_allow: None,
+        connect_allow: None,
+        stats_ticks: None,
+        drain_ticks: None,
+        bytes_to_rekey: None,
+        messages_to_rekey: None,
     };
     // TODO: How can we close this thread?
     thread::spawn(move || {
@@ -78,6 +127,16 @@ fn spawn_entities(stctrl_setup: &StCtrlSetup) {
             .join("relay1")
             .join("relay1.ident"),
         laddr: stctrl_setup.relay1_addr.parse().unwrap(),
+        ws_laddr: None,
+        max_tunnels: None,
+        listens_per_tick: None,
+        bytes_per_tick: None,
+        listen_allow: None,
+        connect_allow: None,
+        stats_ticks: None,
+        drain_ticks: None,
+        bytes_to_rekey: None,
+        messages_to_rekey: None,
     };
     // TODO: How can we close this thread?
     thread::spawn(move || {
@@ -89,6 +148,14 @@ fn spawn_entities(stctrl_setup: &StCtrlSetup) {
     let st_node_cmd = StNodeCmd {
         idfile: stctrl_setup.temp_dir_path.join("node0").join("node0.ident"),
         laddr: stctrl_setup.node0_addr.clone().parse().unwrap(),
+        ws_laddr: None,
+        direct_laddr: None,
+        proxy: None,
+        // node0 uses the hybrid handshake with the relays, node1 uses the classic handshake:
+        hybrid_handshake: true,
+        require_hybrid: false,
+        bytes_to_rekey: None,
+        messages_to_rekey: None,
         database: stctrl_setup.temp_dir_path.join("node0").join("node0.db"),
         trusted: stctrl_setup.temp_dir_path.join("node0").join("trusted"),
     };
@@ -102,6 +169,13 @@ fn spawn_entities(stctrl_setup: &StCtrlSetup) {
     let st_node_cmd = StNodeCmd {
         idfile: stctrl_setup.temp_dir_path.join("node1").join("node1.ident"),
         laddr: stctrl_setup.node1_addr.clone().parse().unwrap(),
+        ws_laddr: None,
+        direct_laddr: None,
+        proxy: None,
+        hybrid_handshake: false,
+        require_hybrid: false,
+        bytes_to_rekey: None,
+        messages_to_rekey: None,
         database: stctrl_setup.temp_dir_path.join("node1").join("node1.db"),
         trusted: stctrl_setup.temp_dir_path.join("node1").join("trusted"),
     };
@@ -127,6 +201,7 @@ fn get_node_public_key(stctrl_setup: &StCtrlSetup, index: usize) -> String {
             .temp_dir_path
             .join(format!("node{}", index))
             .join(format!("node{}.ticket", index)),
+        proxy: None,
         subcommand,
     };
 
@@ -145,7 +220,7 @@ fn get_node_public_key(stctrl_setup: &StCtrlSetup, index: usize) -> String {
 fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
     // Wait until apps can connect to nodes:
     for j in 0..2 {
-        let friends_cmd = FriendsCmd {};
+        let friends_cmd = FriendsCmd { verbose: false };
         let info_cmd = InfoCmd::Friends(friends_cmd);
         let subcommand = StCtrlSubcommand::Info(info_cmd);
 
@@ -158,6 +233,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
 
@@ -195,6 +271,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -224,6 +301,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -252,6 +330,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -284,6 +363,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -307,6 +387,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -314,7 +395,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
 
     // Wait until friends are seen enabled and online:
     for j in 0..2 {
-        let friends_cmd = FriendsCmd {};
+        let friends_cmd = FriendsCmd { verbose: false };
         let info_cmd = InfoCmd::Friends(friends_cmd);
         let subcommand = StCtrlSubcommand::Info(info_cmd);
 
@@ -327,6 +408,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
 
@@ -360,6 +442,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -367,7 +450,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
 
     // Wait until requests are seen open:
     for j in 0..2 {
-        let friends_cmd = FriendsCmd {};
+        let friends_cmd = FriendsCmd { verbose: false };
         let info_cmd = InfoCmd::Friends(friends_cmd);
         let subcommand = StCtrlSubcommand::Info(info_cmd);
 
@@ -380,6 +463,7 @@ fn configure_mutual_credit(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
 
@@ -412,13 +496,14 @@ fn send_funds(stctrl_setup: &StCtrlSetup) {
             .temp_dir_path
             .join("node0")
             .join("node0.ticket"),
+        proxy: None,
         subcommand,
     };
     stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
 
     // Wait until node1 sees that his local max debt is 200:
     // -----------------------------------------------------
-    let friends_cmd = FriendsCmd {};
+    let friends_cmd = FriendsCmd { verbose: false };
     let info_cmd = InfoCmd::Friends(friends_cmd);
     let subcommand = StCtrlSubcommand::Info(info_cmd);
 
@@ -428,6 +513,7 @@ fn send_funds(stctrl_setup: &StCtrlSetup) {
             .temp_dir_path
             .join("node1")
             .join("node1.ticket"),
+        proxy: None,
         subcommand,
     };
 
@@ -465,6 +551,7 @@ fn send_funds(stctrl_setup: &StCtrlSetup) {
             .temp_dir_path
             .join("node1")
             .join("node1.ticket"),
+        proxy: None,
         subcommand,
     };
     // Attempt to pay. We might need to wait a bit first until the route is registered with the
@@ -492,6 +579,7 @@ fn send_funds(stctrl_setup: &StCtrlSetup) {
             .temp_dir_path
             .join("node1")
             .join("node1.ticket"),
+        proxy: None,
         subcommand,
     };
 
@@ -542,6 +630,7 @@ fn pay_invoice(stctrl_setup: &StCtrlSetup) {
             .temp_dir_path
             .join("node1")
             .join("node1.ticket"),
+        proxy: None,
         subcommand,
     };
     stctrl(st_ctrl_cmd.clone(), &mut Vec::new()).unwrap();
@@ -581,6 +670,7 @@ fn export_token(stctrl_setup: &StCtrlSetup) {
             .temp_dir_path
             .join("node0")
             .join("node0.ticket"),
+        proxy: None,
         subcommand,
     };
     stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -619,6 +709,7 @@ fn close_disable(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -626,7 +717,7 @@ fn close_disable(stctrl_setup: &StCtrlSetup) {
 
     // Wait until requests are seen closed:
     for j in 0..2 {
-        let friends_cmd = FriendsCmd {};
+        let friends_cmd = FriendsCmd { verbose: false };
         let info_cmd = InfoCmd::Friends(friends_cmd);
         let subcommand = StCtrlSubcommand::Info(info_cmd);
 
@@ -639,6 +730,7 @@ fn close_disable(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
 
@@ -671,6 +763,7 @@ fn close_disable(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
         stctrl(st_ctrl_cmd, &mut Vec::new()).unwrap();
@@ -678,7 +771,7 @@ fn close_disable(stctrl_setup: &StCtrlSetup) {
 
     // Wait until friends are seen disabled and offline:
     for j in 0..2 {
-        let friends_cmd = FriendsCmd {};
+        let friends_cmd = FriendsCmd { verbose: false };
         let info_cmd = InfoCmd::Friends(friends_cmd);
         let subcommand = StCtrlSubcommand::Info(info_cmd);
 
@@ -691,6 +784,7 @@ fn close_disable(stctrl_setup: &StCtrlSetup) {
                 .temp_dir_path
                 .join(format!("node{}", j))
                 .join(format!("node{}.ticket", j)),
+            proxy: None,
             subcommand,
         };
 
@@ -707,6 +801,70 @@ fn close_disable(stctrl_setup: &StCtrlSetup) {
     }
 }
 
+/// Wait until index0 is connected to index1
+fn wait_index_servers_connected(stctrl_setup: &StCtrlSetup) {
+    loop {
+        let status_cmd = StatusCmd {
+            admin: stctrl_setup.index0_admin_addr.parse().unwrap(),
+        };
+        let st_index_cmd = StIndexCmd::Status(status_cmd);
+        let mut output = Vec::new();
+        if stindex(st_index_cmd, &mut output).is_ok() {
+            let output_string = str::from_utf8(&output).unwrap();
+            assert!(output_string.contains("Graph: "));
+            if output_string.contains("Connected\n") {
+                break;
+            }
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+/// Query the status of index0 and export its graph through its admin endpoint.
+/// Then remove index1 from the trusted servers of index0 and add it back.
+fn index_admin(stctrl_setup: &StCtrlSetup) {
+    wait_index_servers_connected(stctrl_setup);
+
+    // Export index0's graph:
+    let output = stctrl_setup.temp_dir_path.join("index0").join("graph.dot");
+    let export_graph_cmd = ExportGraphCmd {
+        admin: stctrl_setup.index0_admin_addr.parse().unwrap(),
+        output: output.clone(),
+        graphml: false,
+    };
+    stindex(StIndexCmd::ExportGraph(export_graph_cmd), &mut Vec::new()).unwrap();
+    let graph_string = fs::read_to_string(&output).unwrap();
+    assert!(graph_string.starts_with("digraph index {"));
+
+    let index1_server_file = stctrl_setup
+        .temp_dir_path
+        .join("index0")
+        .join("trusted")
+        .join("index1_server.ticket");
+    let index1_server = load_index_server_from_file(&index1_server_file).unwrap();
+
+    let remove_server_cmd = RemoveServerCmd {
+        admin: stctrl_setup.index0_admin_addr.parse().unwrap(),
+        public_key: public_key_to_string(&index1_server.public_key),
+    };
+    stindex(StIndexCmd::RemoveServer(remove_server_cmd), &mut Vec::new()).unwrap();
+
+    let add_server_cmd = AddServerCmd {
+        admin: stctrl_setup.index0_admin_addr.parse().unwrap(),
+        server: index1_server_file.clone(),
+    };
+    stindex(StIndexCmd::AddServer(add_server_cmd), &mut Vec::new()).unwrap();
+
+    // Adding the same server again fails:
+    let add_server_cmd = AddServerCmd {
+        admin: stctrl_setup.index0_admin_addr.parse().unwrap(),
+        server: index1_server_file,
+    };
+    assert!(stindex(StIndexCmd::AddServer(add_server_cmd), &mut Vec::new()).is_err());
+
+    wait_index_servers_connected(stctrl_setup);
+}
+
 #[test]
 fn basic_cli() {
     let _ = env_logger::init();
@@ -718,6 +876,7 @@ fn basic_cli() {
     let stctrl_setup = create_stctrl_setup(&temp_dir_path);
 
     spawn_entities(&stctrl_setup);
+    index_admin(&stctrl_setup);
     configure_mutual_credit(&stctrl_setup);
     send_funds(&stctrl_setup);
     pay_invoice(&stctrl_setup);
@@ -0,0 +1,90 @@
+use std::{env, fs};
+
+use tempfile::tempdir;
+
+use bin::passphrase::{load_raw_identity_from_file, PASSPHRASE_ENV_VAR, PASSPHRASE_FILE_ENV_VAR};
+use bin::stmgrlib::{
+    stmgr, ChangePassphraseCmd, DecryptIdentCmd, EncryptIdentCmd, GenIdentCmd, StMgrCmd,
+};
+
+use proto::file::identity::is_identity_file_encrypted;
+
+#[test]
+fn identity_cli() {
+    let _ = env_logger::init();
+
+    let temp_dir = tempdir().unwrap();
+    let temp_dir_path = temp_dir.path().to_path_buf();
+
+    let passphrase1_path = temp_dir_path.join("passphrase1");
+    fs::write(&passphrase1_path, "first passphrase\n").unwrap();
+    let passphrase2_path = temp_dir_path.join("passphrase2");
+    fs::write(&passphrase2_path, "second passphrase\n").unwrap();
+
+    // Make sure the passphrase is only taken from PASSPHRASE_FILE_ENV_VAR:
+    env::remove_var(PASSPHRASE_ENV_VAR);
+
+    // Create a plain identity file:
+    let plain_path = temp_dir_path.join("plain.ident");
+    let gen_ident_cmd = GenIdentCmd {
+        output: plain_path.clone(),
+        encrypt: false,
+        new_passphrase_file: None,
+    };
+    stmgr(StMgrCmd::GenIdent(gen_ident_cmd)).unwrap();
+    assert!(!is_identity_file_encrypted(&plain_path).unwrap());
+
+    // Encrypt the identity file:
+    let encrypted1_path = temp_dir_path.join("encrypted1.ident");
+    let encrypt_ident_cmd = EncryptIdentCmd {
+        idfile: plain_path.clone(),
+        output: encrypted1_path.clone(),
+        new_passphrase_file: Some(passphrase1_path.clone()),
+    };
+    stmgr(StMgrCmd::EncryptIdent(encrypt_ident_cmd)).unwrap();
+    assert!(is_identity_file_encrypted(&encrypted1_path).unwrap());
+
+    // A plain identity file can not be decrypted:
+    let decrypt_ident_cmd = DecryptIdentCmd {
+        idfile: plain_path.clone(),
+        output: temp_dir_path.join("bad.ident"),
+    };
+    assert!(stmgr(StMgrCmd::DecryptIdent(decrypt_ident_cmd)).is_err());
+
+    // Change the passphrase:
+    env::set_var(PASSPHRASE_FILE_ENV_VAR, &passphrase1_path);
+    let encrypted2_path = temp_dir_path.join("encrypted2.ident");
+    let change_passphrase_cmd = ChangePassphraseCmd {
+        idfile: encrypted1_path.clone(),
+        output: encrypted2_path.clone(),
+        new_passphrase_file: Some(passphrase2_path.clone()),
+    };
+    stmgr(StMgrCmd::ChangePassphrase(change_passphrase_cmd)).unwrap();
+
+    // The old passphrase does not work for the new file:
+    let decrypt_ident_cmd = DecryptIdentCmd {
+        idfile: encrypted2_path.clone(),
+        output: temp_dir_path.join("bad.ident"),
+    };
+    assert!(stmgr(StMgrCmd::DecryptIdent(decrypt_ident_cmd)).is_err());
+
+    // Decrypt using the new passphrase:
+    env::set_var(PASSPHRASE_FILE_ENV_VAR, &passphrase2_path);
+    let decrypted_path = temp_dir_path.join("decrypted.ident");
+    let decrypt_ident_cmd = DecryptIdentCmd {
+        idfile: encrypted2_path.clone(),
+        output: decrypted_path.clone(),
+    };
+    stmgr(StMgrCmd::DecryptIdent(decrypt_ident_cmd)).unwrap();
+    assert!(!is_identity_file_encrypted(&decrypted_path).unwrap());
+
+    // We should end up with the original identity:
+    let identity = load_raw_identity_from_file(&plain_path).unwrap();
+    let identity2 = load_raw_identity_from_file(&encrypted2_path).unwrap();
+    let identity3 = load_raw_identity_from_file(&decrypted_path).unwrap();
+    // We convert to vec here because [u8; 85] doesn't implement PartialEq
+    assert_eq!(identity.to_vec(), identity2.to_vec());
+    assert_eq!(identity.to_vec(), identity3.to_vec());
+
+    env::remove_var(PASSPHRASE_FILE_ENV_VAR);
+}
@@ -1,2 +1,3 @@
 mod basic_cli;
+mod identity_cli;
 mod stctrl_setup;
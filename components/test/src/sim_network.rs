@@ -3,6 +3,7 @@ use std::convert::TryFrom;
 
 use futures::channel::{mpsc, oneshot};
 use futures::task::{Spawn, SpawnExt};
+use futures::stream::Map;
 use futures::{SinkExt, StreamExt};
 
 use common::conn::{BoxFuture, ConnPairVec, FutTransform};
@@ -17,14 +18,24 @@ pub fn net_address(from: &str) -> NetAddress {
     NetAddress::try_from(from.to_string()).unwrap()
 }
 
+/// An incoming connection, together with the address of the connecting side (If known)
+pub type ObservedConn = (Option<NetAddress>, ConnPairVec);
+
 #[derive(Debug)]
 pub enum SimNetworkRequest {
-    Listen((NetAddress, oneshot::Sender<mpsc::Receiver<ConnPairVec>>)),
-    Connect((NetAddress, oneshot::Sender<ConnPairVec>)),
+    Listen((NetAddress, oneshot::Sender<mpsc::Receiver<ObservedConn>>)),
+    /// Connect from an (optional) source address to a listening address
+    Connect((Option<NetAddress>, NetAddress, oneshot::Sender<ConnPairVec>)),
+    /// Attempt a simultaneous open between a local and a remote address.
+    /// Succeeds once the remote side attempts a simultaneous open to the local address.
+    Punch((NetAddress, NetAddress, oneshot::Sender<ConnPairVec>)),
 }
 
 pub async fn sim_network_loop(mut incoming_requests: mpsc::Receiver<SimNetworkRequest>) {
-    let mut listeners: HashMap<NetAddress, mpsc::Sender<ConnPairVec>> = HashMap::new();
+    let mut listeners: HashMap<NetAddress, mpsc::Sender<ObservedConn>> = HashMap::new();
+    // Punch attempts waiting for the remote side, by (local address, remote address):
+    let mut pending_punches: HashMap<(NetAddress, NetAddress), oneshot::Sender<ConnPairVec>> =
+        HashMap::new();
 
     while let Some(request) = await!(incoming_requests.next()) {
         match request {
@@ -49,13 +60,14 @@ pub async fn sim_network_loop(mut incoming_requests: mpsc::Receiver<SimNetworkRe
                     warn!("SimNetworkRequest::Listen: Request failed");
                 }
             }
-            SimNetworkRequest::Connect((connect_address, oneshot_sender)) => {
+            SimNetworkRequest::Connect((opt_source_address, connect_address, oneshot_sender)) => {
                 info!("SimNetworkRequest::Connect({:?})", connect_address);
                 if let Some(mut conn_sender) = listeners.remove(&connect_address) {
                     let (connect_sender, listen_receiver) = mpsc::channel(CHANNEL_SIZE);
                     let (listen_sender, connect_receiver) = mpsc::channel(CHANNEL_SIZE);
 
-                    if let Err(_) = await!(conn_sender.send((listen_sender, listen_receiver))) {
+                    let observed_conn = (opt_source_address, (listen_sender, listen_receiver));
+                    if let Err(_) = await!(conn_sender.send(observed_conn)) {
                         // Note that we dropped the listener's sender.
                         warn!("SimNetworkRequest::Connect: Connection request failed");
                         continue;
@@ -70,6 +82,31 @@ pub async fn sim_network_loop(mut incoming_requests: mpsc::Receiver<SimNetworkRe
                     warn!("Connection failed: No listeners at: {:?}", connect_address);
                 }
             }
+            SimNetworkRequest::Punch((local_address, remote_address, oneshot_sender)) => {
+                info!(
+                    "SimNetworkRequest::Punch({:?} -> {:?})",
+                    local_address, remote_address
+                );
+                let key = (remote_address.clone(), local_address.clone());
+                match pending_punches.remove(&key) {
+                    Some(remote_oneshot_sender) if !remote_oneshot_sender.is_canceled() => {
+                        let (local_sender, remote_receiver) = mpsc::channel(CHANNEL_SIZE);
+                        let (remote_sender, local_receiver) = mpsc::channel(CHANNEL_SIZE);
+                        if let Err(_) = remote_oneshot_sender.send((remote_sender, remote_receiver))
+                        {
+                            warn!("SimNetworkRequest::Punch: Failure sending pair!");
+                            continue;
+                        }
+                        if let Err(_) = oneshot_sender.send((local_sender, local_receiver)) {
+                            warn!("SimNetworkRequest::Punch: Failure sending pair!");
+                        }
+                    }
+                    _ => {
+                        // Wait for the remote side to punch too:
+                        pending_punches.insert((local_address, remote_address), oneshot_sender);
+                    }
+                }
+            }
         }
     }
     info!("sim_network_loop() closed");
@@ -91,10 +128,11 @@ impl SimNetworkClient {
         SimNetworkClient { sender }
     }
 
-    pub async fn listen(
+    /// Listen for incoming connections, together with the addresses of the connecting sides.
+    pub async fn listen_observed(
         &mut self,
         net_address: NetAddress,
-    ) -> Result<mpsc::Receiver<ConnPairVec>, SimNetworkClientError> {
+    ) -> Result<mpsc::Receiver<ObservedConn>, SimNetworkClientError> {
         let (response_sender, response_receiver) = oneshot::channel();
         await!(self
             .sender
@@ -102,23 +140,118 @@ impl SimNetworkClient {
         .map_err(|_| SimNetworkClientError::SendRequestError)?;
         await!(response_receiver).map_err(|_| SimNetworkClientError::ReceiveResponseError)
     }
+
+    pub async fn listen(
+        &mut self,
+        net_address: NetAddress,
+    ) -> Result<
+        Map<mpsc::Receiver<ObservedConn>, fn(ObservedConn) -> ConnPairVec>,
+        SimNetworkClientError,
+    > {
+        let incoming_conns = await!(self.listen_observed(net_address))?;
+        let strip_address: fn(ObservedConn) -> ConnPairVec = |(_opt_address, conn_pair)| conn_pair;
+        Ok(incoming_conns.map(strip_address))
+    }
+
+    /// Connect to `net_address`. The listener will see the connection as coming from
+    /// `opt_source_address`.
+    pub async fn connect_from(
+        &mut self,
+        opt_source_address: Option<NetAddress>,
+        net_address: NetAddress,
+    ) -> Option<ConnPairVec> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(self.sender.send(SimNetworkRequest::Connect((
+            opt_source_address,
+            net_address,
+            response_sender
+        ))))
+        .ok()?;
+        await!(response_receiver).ok()
+    }
+
+    /// Attempt a simultaneous open from `local_address` to `remote_address`.
+    /// Waits until the remote side attempts a simultaneous open to `local_address`.
+    pub async fn punch(
+        &mut self,
+        local_address: NetAddress,
+        remote_address: NetAddress,
+    ) -> Option<ConnPairVec> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        await!(self.sender.send(SimNetworkRequest::Punch((
+            local_address,
+            remote_address,
+            response_sender
+        ))))
+        .ok()?;
+        await!(response_receiver).ok()
+    }
 }
 
 impl FutTransform for SimNetworkClient {
     type Input = NetAddress;
     type Output = Option<ConnPairVec>;
 
-    #[allow(unused)]
     fn transform(&mut self, net_address: Self::Input) -> BoxFuture<'_, Self::Output> {
-        let (response_sender, response_receiver) = oneshot::channel();
+        Box::pin(self.connect_from(None, net_address))
+    }
+}
+
+/// A stand-in for a NAT, built on the simulated network.
+/// Connections through the NAT are seen by listeners as coming from `public_address`.
+/// Hosts behind NATs can not accept connections. However, two hosts behind NATs may punch a
+/// direct connection, if both of them attempt to connect to the public address of the other side
+/// at the same time.
+#[derive(Clone)]
+pub struct SimNat {
+    sim_network_client: SimNetworkClient,
+    public_address: NetAddress,
+}
+
+impl SimNat {
+    pub fn new(sim_network_client: SimNetworkClient, public_address: NetAddress) -> Self {
+        SimNat {
+            sim_network_client,
+            public_address,
+        }
+    }
+
+    /// A connector that punches through the NAT to the public address of a remote host
+    pub fn punch_connector(&self) -> SimNatPunchConnector {
+        SimNatPunchConnector {
+            sim_nat: self.clone(),
+        }
+    }
+}
+
+impl FutTransform for SimNat {
+    type Input = NetAddress;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, net_address: Self::Input) -> BoxFuture<'_, Self::Output> {
+        let public_address = self.public_address.clone();
         Box::pin(
-            async move {
-                await!(self
-                    .sender
-                    .send(SimNetworkRequest::Connect((net_address, response_sender))))
-                .ok()?;
-                await!(response_receiver).ok()
-            },
+            self.sim_network_client
+                .connect_from(Some(public_address), net_address),
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct SimNatPunchConnector {
+    sim_nat: SimNat,
+}
+
+impl FutTransform for SimNatPunchConnector {
+    type Input = NetAddress;
+    type Output = Option<ConnPairVec>;
+
+    fn transform(&mut self, remote_address: Self::Input) -> BoxFuture<'_, Self::Output> {
+        let public_address = self.sim_nat.public_address.clone();
+        Box::pin(
+            self.sim_nat
+                .sim_network_client
+                .punch(public_address, remote_address),
         )
     }
 }
@@ -141,6 +274,7 @@ where
 mod tests {
     use super::*;
     use futures::executor::ThreadPool;
+    use futures::FutureExt;
 
     async fn task_sim_network_basic<S>(mut spawner: S)
     where
@@ -187,4 +321,44 @@ mod tests {
         let mut thread_pool = ThreadPool::new().unwrap();
         thread_pool.run(task_sim_network_basic(thread_pool.clone()));
     }
+
+    async fn task_sim_nat_punch<S>(mut spawner: S)
+    where
+        S: Spawn,
+    {
+        let mut net_client = create_sim_network(&mut spawner);
+        let mut incoming_server =
+            await!(net_client.listen_observed(net_address("server"))).unwrap();
+
+        let mut sim_nat_a = SimNat::new(net_client.clone(), net_address("nat_a"));
+        let sim_nat_b = SimNat::new(net_client.clone(), net_address("nat_b"));
+
+        // Listeners observe the public address of the NAT:
+        let _conn_pair = await!(sim_nat_a.transform(net_address("server"))).unwrap();
+        let (opt_address, _conn_pair) = await!(incoming_server.next()).unwrap();
+        assert_eq!(opt_address, Some(net_address("nat_a")));
+
+        // Both sides punch at the same time:
+        let mut punch_connector_a = sim_nat_a.punch_connector();
+        let mut punch_connector_b = sim_nat_b.punch_connector();
+        let (fut_a, fut_b) = (
+            punch_connector_a.transform(net_address("nat_b")),
+            punch_connector_b.transform(net_address("nat_a")),
+        );
+        let (opt_conn_a, opt_conn_b) = await!(fut_a.join(fut_b));
+        let (mut sender_a, mut receiver_a) = opt_conn_a.unwrap();
+        let (mut sender_b, mut receiver_b) = opt_conn_b.unwrap();
+
+        await!(sender_a.send(vec![1, 2, 3])).unwrap();
+        assert_eq!(await!(receiver_b.next()), Some(vec![1, 2, 3]));
+
+        await!(sender_b.send(vec![3, 2, 1])).unwrap();
+        assert_eq!(await!(receiver_a.next()), Some(vec![3, 2, 1]));
+    }
+
+    #[test]
+    fn test_sim_nat_punch() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_sim_nat_punch(thread_pool.clone()));
+    }
 }
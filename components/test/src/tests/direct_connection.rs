@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use futures::channel::mpsc;
+
+use tempfile::tempdir;
+
+use common::test_executor::TestExecutor;
+
+use proto::app_server::messages::AppPermissions;
+use timer::create_timer_incoming;
+
+use crate::utils::{
+    advance_time, create_app, create_node, direct_address, named_direct_address, node_public_key,
+    SimDb,
+};
+
+use node::connect::AppReport;
+
+use crate::sim_network::create_sim_network;
+
+const TIMER_CHANNEL_LEN: usize = 0;
+
+/// Checks if a friend is online, connected directly (Without a relay).
+/// panics if the friend does not exist.
+async fn is_friend_online_direct(report: &mut AppReport, index: u8) -> bool {
+    let (node_report, mutations_receiver) = await!(report.incoming_reports()).unwrap();
+    drop(mutations_receiver);
+
+    let friend_report = match node_report
+        .funder_report
+        .friends
+        .get(&node_public_key(index))
+    {
+        None => unreachable!(),
+        Some(friend_report) => friend_report,
+    };
+    if !friend_report.liveness.is_online() {
+        return false;
+    }
+
+    let friend_connection_report = match node_report
+        .connections_report
+        .friends
+        .get(&node_public_key(index))
+    {
+        None => return false,
+        Some(friend_connection_report) => friend_connection_report,
+    };
+    // The connecting side reports the direct address of the friend.
+    // The listening side does not report any relay:
+    match &friend_connection_report.opt_relay {
+        None => friend_connection_report.opt_connected_since.is_some(),
+        Some(relay_address) => relay_address == &direct_address(index),
+    }
+}
+
+async fn task_direct_connection(mut test_executor: TestExecutor) {
+    // Create timer_client:
+    let (mut tick_sender, tick_receiver) = mpsc::channel(TIMER_CHANNEL_LEN);
+    let timer_client = create_timer_incoming(tick_receiver, test_executor.clone()).unwrap();
+
+    // Create a temporary directory.
+    // Should be deleted when gets out of scope:
+    let temp_dir = tempdir().unwrap();
+
+    // Create a database manager at the temporary directory:
+    let sim_db = SimDb::new(temp_dir.path().to_path_buf());
+
+    // A network simulator:
+    let sim_net_client = create_sim_network(&mut test_executor);
+
+    let mut apps = Vec::new();
+    for index in 0..2 {
+        // Create initial database for the node:
+        sim_db.init_db(index);
+
+        let mut trusted_apps = HashMap::new();
+        trusted_apps.insert(
+            index,
+            AppPermissions {
+                routes: true,
+                send_funds: true,
+                config: true,
+            },
+        );
+
+        await!(create_node(
+            index,
+            sim_db.clone(),
+            timer_client.clone(),
+            sim_net_client.clone(),
+            trusted_apps,
+            test_executor.clone()
+        ))
+        .forget();
+
+        let app = await!(create_app(
+            index,
+            sim_net_client.clone(),
+            timer_client.clone(),
+            index,
+            test_executor.clone()
+        ))
+        .unwrap();
+        apps.push(app);
+    }
+    let mut app1 = apps.pop().unwrap();
+    let mut app0 = apps.pop().unwrap();
+
+    let mut config0 = app0.config().unwrap().clone();
+    let mut config1 = app1.config().unwrap().clone();
+
+    let mut report0 = app0.report().clone();
+    let mut report1 = app1.report().clone();
+
+    // Note that there are no relays at all.
+    // Every node advertises its own direct address:
+    await!(config0.add_relay(named_direct_address(0))).unwrap();
+    await!(config1.add_relay(named_direct_address(1))).unwrap();
+
+    // Node0: Add node1 as a friend:
+    await!(config0.add_friend(
+        node_public_key(1),
+        vec![direct_address(1)],
+        String::from("node1"),
+        100
+    ))
+    .unwrap();
+
+    // Node1: Add node0 as a friend:
+    await!(config1.add_friend(
+        node_public_key(0),
+        vec![direct_address(0)],
+        String::from("node0"),
+        -100
+    ))
+    .unwrap();
+
+    await!(config0.enable_friend(node_public_key(1))).unwrap();
+    await!(config1.enable_friend(node_public_key(0))).unwrap();
+
+    await!(advance_time(40, &mut tick_sender, &test_executor));
+
+    assert!(await!(is_friend_online_direct(&mut report0, 1)));
+    assert!(await!(is_friend_online_direct(&mut report1, 0)));
+}
+
+#[test]
+fn test_direct_connection() {
+    // let _ = env_logger::init();
+    let test_executor = TestExecutor::new();
+    let res = test_executor.run(task_direct_connection(test_executor.clone()));
+    assert!(res.is_output());
+}
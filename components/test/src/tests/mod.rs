@@ -1,3 +1,5 @@
+mod direct_connection;
+mod nat_traversal;
 mod nodes_chain;
 mod relay_migration;
 mod resolve_inconsistency;
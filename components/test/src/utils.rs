@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::future::RemoteHandle;
 use futures::task::{Spawn, SpawnExt};
 use futures::{future, FutureExt, SinkExt, TryFutureExt};
@@ -11,10 +11,14 @@ use crypto::identity::{generate_pkcs8_key_pair, Identity, PublicKey, SoftwareEd2
 use crypto::crypto_rand::CryptoRandom;
 use crypto::test_utils::DummyRandom;
 
+use common::conn::{ConnPairVec, FutTransform};
 use common::test_executor::TestExecutor;
 
 use proto::app_server::messages::{AppPermissions, NamedRelayAddress, RelayAddress};
-use proto::consts::{KEEPALIVE_TICKS, MAX_NODE_RELAYS, MAX_OPERATIONS_IN_BATCH, TICKS_TO_REKEY};
+use proto::consts::{
+    BYTES_TO_REKEY, KEEPALIVE_TICKS, MAX_NODE_RELAYS, MAX_OPERATIONS_IN_BATCH, MESSAGES_TO_REKEY,
+    TICKS_TO_REKEY,
+};
 use proto::index_server::messages::NamedIndexServerAddress;
 use proto::net::messages::NetAddress;
 
@@ -25,12 +29,12 @@ use node::{net_node, NodeConfig, NodeState};
 
 use database::file_db::FileDb;
 
-use index_server::net_index_server;
-use relay::net_relay_server;
+use index_server::{net_index_server, BanConfig, RoutesLimit};
+use relay::{net_relay_server, RelayAcl, RelayQuotas};
 
 use timer::TimerClient;
 
-use crate::sim_network::{net_address, SimNetworkClient};
+use crate::sim_network::{net_address, SimNat, SimNatPunchConnector, SimNetworkClient};
 
 /// Memory allocated to a channel in memory (Used to connect two components)
 const CHANNEL_LEN: usize = 0x20;
@@ -46,9 +50,19 @@ const MAX_OPEN_INDEX_CLIENT_REQUESTS: usize = 0x8;
 /// The amount of ticks we are willing to wait until a connection is established (Through
 /// the relay)
 const CONN_TIMEOUT_TICKS: usize = 0x8;
+/// The amount of ticks between reports of friends connection statistics
+const CONN_STATS_TICKS: usize = 0x4;
+/// Maximum amount of ticks servers wait for open connections when shutting down
+const DRAIN_TICKS: usize = 0x8;
 /// Maximum amount of concurrent applications
 /// going through the incoming connection transform at the same time
 const MAX_CONCURRENT_INCOMING_APPS: usize = 0x8;
+/// Per client limits of the relay servers
+const RELAY_QUOTAS: RelayQuotas = RelayQuotas {
+    max_tunnels: 0x20,
+    max_listens_per_tick: 0x10,
+    max_bytes_per_tick: 1 << 24,
+};
 
 /*
 // Based on:
@@ -128,12 +142,20 @@ fn default_node_config() -> NodeConfig {
         keepalive_ticks: KEEPALIVE_TICKS,
         /// Amount of ticks to wait until the next rekeying (Channel encryption)
         ticks_to_rekey: TICKS_TO_REKEY,
+        /// Amount of bytes to encrypt using the same key until the next rekeying
+        bytes_to_rekey: BYTES_TO_REKEY,
+        /// Amount of messages to encrypt using the same key until the next rekeying
+        messages_to_rekey: MESSAGES_TO_REKEY,
+        hybrid_handshake: false,
+        require_hybrid: false,
         /// Maximum amount of encryption set ups (diffie hellman) that we allow to occur at the same
         /// time.
         max_concurrent_encrypt: MAX_CONCURRENT_ENCRYPT,
         /// The amount of ticks we are willing to wait until a connection is established (Through
         /// the relay)
         conn_timeout_ticks: CONN_TIMEOUT_TICKS,
+        /// The amount of ticks between reports of friends connection statistics
+        conn_stats_ticks: CONN_STATS_TICKS,
         /// Maximum amount of operations in one move token message
         max_operations_in_batch: MAX_OPERATIONS_IN_BATCH,
         /// The size we allocate for the user send funds requests queue.
@@ -182,6 +204,15 @@ fn listen_node_address(index: u8) -> NetAddress {
     net_address(&format!("node_{}", index))
 }
 
+fn listen_node_direct_address(index: u8) -> NetAddress {
+    net_address(&format!("direct://node_direct_{}", index))
+}
+
+/// The public address of the NAT a node is behind (If any)
+fn node_nat_address(index: u8) -> NetAddress {
+    net_address(&format!("node_nat_{}", index))
+}
+
 fn listen_index_server_client_address(index: u8) -> NetAddress {
     net_address(&format!("index_server_client_{}", index))
 }
@@ -209,6 +240,22 @@ pub fn relay_address(index: u8) -> RelayAddress {
     }
 }
 
+/// A direct address of a node: Marked by the `direct` scheme of its network address.
+pub fn named_direct_address(index: u8) -> NamedRelayAddress {
+    NamedRelayAddress {
+        public_key: get_node_identity(index).get_public_key(),
+        address: listen_node_direct_address(index),
+        name: format!("named_direct_{}", index),
+    }
+}
+
+pub fn direct_address(index: u8) -> RelayAddress {
+    RelayAddress {
+        public_key: get_node_identity(index).get_public_key(),
+        address: listen_node_direct_address(index),
+    }
+}
+
 pub fn named_index_server_address(index: u8) -> NamedIndexServerAddress {
     NamedIndexServerAddress {
         public_key: get_index_server_identity(index).get_public_key(),
@@ -254,20 +301,92 @@ where
 }
 
 pub async fn create_node<S>(
+    index: u8,
+    sim_db: SimDb,
+    timer_client: TimerClient,
+    sim_network_client: SimNetworkClient,
+    trusted_apps: HashMap<u8, AppPermissions>,
+    spawner: S,
+) -> RemoteHandle<()>
+where
+    S: Spawn + Send + Sync + Clone + 'static,
+{
+    let net_connector = sim_network_client.clone();
+    await!(spawn_node(
+        index,
+        sim_db,
+        timer_client,
+        sim_network_client,
+        net_connector,
+        None::<SimNatPunchConnector>,
+        trusted_apps,
+        spawner
+    ))
+}
+
+/// Create a node behind a NAT.
+/// Connections from the node are seen by others as coming from the public address of the NAT.
+/// If `punch` is set, the node attempts to punch direct connections to its friends through NATs.
+pub async fn create_nat_node<S>(
+    index: u8,
+    sim_db: SimDb,
+    timer_client: TimerClient,
+    sim_network_client: SimNetworkClient,
+    trusted_apps: HashMap<u8, AppPermissions>,
+    punch: bool,
+    spawner: S,
+) -> RemoteHandle<()>
+where
+    S: Spawn + Send + Sync + Clone + 'static,
+{
+    let sim_nat = SimNat::new(sim_network_client.clone(), node_nat_address(index));
+    let opt_punch_connector = if punch {
+        Some(sim_nat.punch_connector())
+    } else {
+        None
+    };
+    await!(spawn_node(
+        index,
+        sim_db,
+        timer_client,
+        sim_network_client,
+        sim_nat,
+        opt_punch_connector,
+        trusted_apps,
+        spawner
+    ))
+}
+
+async fn spawn_node<C, PC, S>(
     index: u8,
     sim_db: SimDb,
     timer_client: TimerClient,
     mut sim_network_client: SimNetworkClient,
+    net_connector: C,
+    opt_punch_connector: Option<PC>,
     trusted_apps: HashMap<u8, AppPermissions>,
     mut spawner: S,
 ) -> RemoteHandle<()>
 where
+    C: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    PC: FutTransform<Input = NetAddress, Output = Option<ConnPairVec>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     S: Spawn + Send + Sync + Clone + 'static,
 {
     let identity = get_node_identity(index);
     let identity_client = create_identity_client(identity, spawner.clone());
     let listen_address = listen_node_address(index);
     let incoming_app_raw_conns = await!(sim_network_client.listen(listen_address)).unwrap();
+    let direct_listen_address = listen_node_direct_address(index);
+    let incoming_direct_raw_conns =
+        await!(sim_network_client.listen(direct_listen_address)).unwrap();
 
     // Translate application index to application public key:
     let trusted_apps = trusted_apps
@@ -281,7 +400,9 @@ where
     // Simulating the passage of time becomes more difficult if our code uses a few different executors.
     let net_node_fut = net_node(
         incoming_app_raw_conns,
-        sim_network_client,
+        incoming_direct_raw_conns,
+        net_connector,
+        opt_punch_connector,
         timer_client,
         identity_client,
         rng,
@@ -329,19 +450,50 @@ pub async fn create_index_server<S>(
         .collect::<HashMap<_, _>>();
 
     let rng = DummyRandom::new(&[0xff, 0x13, 0x38, index]);
-    // We use the same spawner for both required spawners.
+    // We use the same spawner for all required spawners.
     // We do this to make it easier to simulate the passage of time in tests.
+    // Route requests limits are not reached in tests:
+    let routes_limit = RoutesLimit {
+        client_requests_per_tick: 0x100,
+        client_max_burst: 0x100,
+        max_concurrent_queries: 0x100,
+    };
+    let ban_config = BanConfig {
+        violation_threshold: 0x10,
+        ban_ticks: 0x100,
+    };
+
+    // We don't serve admin requests in tests:
+    let (_admin_raw_conns_sender, incoming_admin_raw_conns) = mpsc::channel(0);
+
+    // Servers are never shut down in tests (A canceled shutdown receiver never fires):
+    let (_shutdown_sender, shutdown_receiver) = oneshot::channel();
+
     let net_index_server_fut = net_index_server(
         incoming_client_raw_conns,
         incoming_server_raw_conns,
+        incoming_admin_raw_conns,
         sim_network_client,
         identity_client,
         timer_client,
         rng,
         trusted_servers,
+        None, // opt_trusted_dir
         MAX_CONCURRENT_ENCRYPT,
+        BYTES_TO_REKEY,
+        MESSAGES_TO_REKEY,
         BACKOFF_TICKS,
-        spawner.clone(),
+        routes_limit,
+        None, // opt_capacity_bucket
+        ban_config,
+        false, // discovery
+        0,     // max_discovered_servers
+        None, // opt_snapshot_path
+        0, // snapshot_ticks
+        shutdown_receiver,
+        DRAIN_TICKS,
+        spawner.clone(), // graph_service_spawner
+        spawner.clone(), // file_spawner
         spawner.clone(),
     )
     .map_err(|e| error!("net_index_server()  error: {:?}", e))
@@ -350,33 +502,50 @@ pub async fn create_index_server<S>(
     spawner.spawn(net_index_server_fut).unwrap();
 }
 
+/// Spawn a relay server.
+/// Returns a sender that can be used to shut down the relay. Dropping the sender keeps the relay
+/// running.
 pub async fn create_relay<S>(
     index: u8,
     timer_client: TimerClient,
     mut sim_network_client: SimNetworkClient,
     mut spawner: S,
-) where
+) -> oneshot::Sender<()>
+where
     S: Spawn + Send + Sync + Clone + 'static,
 {
     let identity = get_relay_identity(index);
     let identity_client = create_identity_client(identity, spawner.clone());
 
+    // The relay observes the addresses of connecting nodes:
     let listen_address = listen_relay_address(index);
-    let incoming_raw_conns = await!(sim_network_client.listen(listen_address)).unwrap();
+    let incoming_raw_conns = await!(sim_network_client.listen_observed(listen_address)).unwrap();
 
     let rng = DummyRandom::new(&[0xff, 0x13, 0x39, index]);
+
+    // A canceled shutdown receiver never fires:
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
     let net_relay_server_fut = net_relay_server(
         incoming_raw_conns,
         identity_client,
         timer_client,
         rng,
         MAX_CONCURRENT_ENCRYPT,
+        BYTES_TO_REKEY,
+        MESSAGES_TO_REKEY,
+        RELAY_QUOTAS,
+        RelayAcl::default(),
+        None,
+        shutdown_receiver,
+        DRAIN_TICKS,
         spawner.clone(),
     )
     .map_err(|e| error!("net_relay_server() error: {:?}", e))
     .map(|_| ());
 
     spawner.spawn(net_relay_server_fut).unwrap();
+    shutdown_sender
 }
 
 pub async fn advance_time<'a>(
@@ -16,6 +16,8 @@
 #[macro_use]
 extern crate log;
 
+mod version_accept;
 mod version_prefix;
 
+pub use self::version_accept::VersionAccept;
 pub use self::version_prefix::VersionPrefix;
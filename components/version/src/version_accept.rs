@@ -0,0 +1,115 @@
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use futures::{SinkExt, StreamExt};
+
+use common::conn::{BoxFuture, ConnPairVec, FutTransform};
+
+/// Accept the version prefix of a remote side that may use any of a few supported versions.
+/// The version declared by the remote side is sent back, and returned together with the
+/// connection. Compatible with a remote side using `VersionPrefix`.
+#[derive(Clone)]
+pub struct VersionAccept {
+    supported_versions: Vec<u32>,
+}
+
+impl VersionAccept {
+    pub fn new(supported_versions: Vec<u32>) -> Self {
+        VersionAccept { supported_versions }
+    }
+
+    async fn accept_version(&self, conn_pair: ConnPairVec) -> Option<(u32, ConnPairVec)> {
+        let (mut sender, mut receiver) = conn_pair;
+
+        // Expect version to be the first sent data:
+        let version_data = match await!(receiver.next()) {
+            Some(version_data) => version_data,
+            None => {
+                warn!("Failed to receive version information");
+                return None;
+            }
+        };
+
+        if version_data.len() != 4 {
+            warn!("Invalid version_data length");
+            return None;
+        }
+
+        let remote_version = BigEndian::read_u32(&version_data);
+        if !self.supported_versions.contains(&remote_version) {
+            warn!("Unsupported remote version: {}", remote_version);
+            return None;
+        }
+
+        // Declare the same version back to the remote side:
+        let mut version_data = Vec::new();
+        version_data.write_u32::<BigEndian>(remote_version).unwrap();
+        if await!(sender.send(version_data)).is_err() {
+            warn!("Failed to send version information");
+            return None;
+        }
+
+        Some((remote_version, (sender, receiver)))
+    }
+}
+
+impl FutTransform for VersionAccept {
+    type Input = ConnPairVec;
+    type Output = Option<(u32, ConnPairVec)>;
+
+    fn transform(&mut self, input: Self::Input) -> BoxFuture<'_, Self::Output> {
+        Box::pin(self.accept_version(input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::ThreadPool;
+    use futures::task::{Spawn, SpawnExt};
+
+    use crate::version_prefix::VersionPrefix;
+
+    async fn task_version_accept<S>(mut spawner: S)
+    where
+        S: Spawn + Clone,
+    {
+        let mut version_accept = VersionAccept::new(vec![3u32, 4u32]);
+
+        for &version in &[3u32, 4u32] {
+            let (a_sender, b_receiver) = mpsc::channel(0);
+            let (b_sender, a_receiver) = mpsc::channel(0);
+
+            let mut version_prefix = VersionPrefix::new(version, spawner.clone());
+            let (mut a_sender, mut a_receiver) =
+                version_prefix.spawn_prefix((a_sender, a_receiver));
+
+            let mut c_version_accept = version_accept.clone();
+            let accept_handle = spawner
+                .spawn_with_handle(
+                    async move { await!(c_version_accept.transform((b_sender, b_receiver))) },
+                )
+                .unwrap();
+
+            await!(a_sender.send(vec![1, 2, 3])).unwrap();
+            let (remote_version, (mut b_sender, mut b_receiver)) = await!(accept_handle).unwrap();
+            assert_eq!(remote_version, version);
+
+            assert_eq!(await!(b_receiver.next()).unwrap(), vec![1, 2, 3]);
+            await!(b_sender.send(vec![3, 2, 1])).unwrap();
+            assert_eq!(await!(a_receiver.next()).unwrap(), vec![3, 2, 1]);
+        }
+
+        // Unsupported version:
+        let (a_sender, b_receiver) = mpsc::channel(0);
+        let (b_sender, a_receiver) = mpsc::channel(0);
+        let mut version_prefix = VersionPrefix::new(5u32, spawner.clone());
+        let (_a_sender, _a_receiver) = version_prefix.spawn_prefix((a_sender, a_receiver));
+        assert!(await!(version_accept.transform((b_sender, b_receiver))).is_none());
+    }
+
+    #[test]
+    fn test_version_accept() {
+        let mut thread_pool = ThreadPool::new().unwrap();
+        thread_pool.run(task_version_accept(thread_pool.clone()));
+    }
+}